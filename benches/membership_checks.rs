@@ -0,0 +1,144 @@
+//! `COUNT(*)` against `EXISTS` for boolean membership checks.
+//!
+//! Runs against the database in `DATABASE_URL`, which it migrates and seeds with one group of
+//! `BENCH_MEMBERS` (default 100000) members; the seeded rows are removed afterwards. Use a
+//! scratch database:
+//!
+//! ```text
+//! DATABASE_URL=postgres://localhost/auth_bench cargo bench --bench membership_checks
+//! ```
+//!
+//! A check on the full primary key matches at most one row, so both forms cost about the same.
+//! A check that matches many rows, like "does the group have any member with this role", counts
+//! every one of them with `COUNT(*)` and stops at the first with `EXISTS`.
+
+use std::time::{Duration, Instant};
+
+use sqlx::PgPool;
+use subseq_auth::db::create_user_tables;
+use uuid::Uuid;
+
+const ITERATIONS: u32 = 200;
+const EMAIL_DOMAIN: &str = "membership-bench.invalid";
+
+struct Case {
+    name: &'static str,
+    count: &'static str,
+    exists: &'static str,
+}
+
+const CASES: &[Case] = &[
+    Case {
+        name: "is_member (one row)",
+        count: "SELECT COUNT(*) > 0 FROM auth.group_memberships WHERE group_id = $1 AND user_id = $2",
+        exists: "SELECT EXISTS (SELECT 1 FROM auth.group_memberships WHERE group_id = $1 AND user_id = $2)",
+    },
+    Case {
+        name: "any member with role (every row)",
+        count: "SELECT COUNT(*) > 0 FROM auth.group_memberships WHERE group_id = $1 AND role_name = 'member' AND user_id <> $2",
+        exists: "SELECT EXISTS (SELECT 1 FROM auth.group_memberships WHERE group_id = $1 AND role_name = 'member' AND user_id <> $2)",
+    },
+];
+
+#[tokio::main]
+async fn main() -> Result<(), sqlx::Error> {
+    let Ok(url) = std::env::var("DATABASE_URL") else {
+        eprintln!("Set DATABASE_URL to a scratch database to run this benchmark");
+        return Ok(());
+    };
+    let members: i64 = std::env::var("BENCH_MEMBERS")
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(100_000);
+    let pool = PgPool::connect(&url).await?;
+    create_user_tables(&pool).await?;
+
+    let group_id = Uuid::new_v4();
+    let user_id = seed(&pool, group_id, members).await?;
+    println!("{} members", members);
+    for case in CASES {
+        let count = measure(&pool, case.count, group_id, user_id).await?;
+        let exists = measure(&pool, case.exists, group_id, user_id).await?;
+        println!(
+            "{:<34} COUNT(*) {:>10.1?}  EXISTS {:>10.1?}",
+            case.name, count, exists
+        );
+    }
+    cleanup(&pool, group_id).await
+}
+
+/// Insert the group and its members. Returns the id of one member.
+async fn seed(pool: &PgPool, group_id: Uuid, members: i64) -> Result<Uuid, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    sqlx::query("INSERT INTO auth.groups (id, display_name) VALUES ($1, 'membership bench')")
+        .bind(group_id)
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query(
+        r#"
+        INSERT INTO auth.users (id, email)
+        SELECT gen_random_uuid(), 'user' || n || '@' || $2
+        FROM generate_series(1, $1) AS n
+        "#,
+    )
+    .bind(members)
+    .bind(EMAIL_DOMAIN)
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query(
+        r#"
+        INSERT INTO auth.group_memberships (group_id, user_id, role_name)
+        SELECT $1, id, 'member'
+        FROM auth.users
+        WHERE email LIKE '%@' || $2
+        "#,
+    )
+    .bind(group_id)
+    .bind(EMAIL_DOMAIN)
+    .execute(&mut *tx)
+    .await?;
+    let user_id = sqlx::query_scalar(
+        "SELECT user_id FROM auth.group_memberships WHERE group_id = $1 LIMIT 1",
+    )
+    .bind(group_id)
+    .fetch_one(&mut *tx)
+    .await?;
+    tx.commit().await?;
+    sqlx::query("ANALYZE auth.group_memberships")
+        .execute(pool)
+        .await?;
+    Ok(user_id)
+}
+
+/// Mean time of one check, after a warm-up run.
+async fn measure(
+    pool: &PgPool,
+    sql: &'static str,
+    group_id: Uuid,
+    user_id: Uuid,
+) -> Result<Duration, sqlx::Error> {
+    let check = || {
+        sqlx::query_scalar::<_, bool>(sql)
+            .bind(group_id)
+            .bind(user_id)
+            .fetch_one(pool)
+    };
+    assert!(check().await?);
+    let started = Instant::now();
+    for _ in 0..ITERATIONS {
+        check().await?;
+    }
+    Ok(started.elapsed() / ITERATIONS)
+}
+
+async fn cleanup(pool: &PgPool, group_id: Uuid) -> Result<(), sqlx::Error> {
+    sqlx::query("DELETE FROM auth.groups WHERE id = $1")
+        .bind(group_id)
+        .execute(pool)
+        .await?;
+    sqlx::query("DELETE FROM auth.users WHERE email LIKE '%@' || $1")
+        .bind(EMAIL_DOMAIN)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
@@ -0,0 +1,139 @@
+//! SQL built once against SQL rebuilt with `format!` on every call.
+//!
+//! Runs against the database in `DATABASE_URL`, which it migrates and seeds with one user holding
+//! one role; the seeded rows are removed afterwards. Use a scratch database:
+//!
+//! ```text
+//! DATABASE_URL=postgres://localhost/auth_bench cargo bench --bench static_queries
+//! ```
+//!
+//! Each case runs `BENCH_ITERATIONS` (default 20000) sequential calls. Both forms send the same
+//! statement text, which sqlx prepares once and caches by text, so the round trip dominates and
+//! the difference stays within run-to-run noise.
+
+use std::time::{Duration, Instant};
+
+use sqlx::PgPool;
+use subseq_auth::db::{UserRoleRow, UserRow, create_user_tables};
+use uuid::Uuid;
+
+const EMAIL: &str = "static-queries@bench.invalid";
+
+struct Case {
+    name: &'static str,
+    sql: fn() -> String,
+}
+
+const CASES: &[Case] = &[
+    Case {
+        name: "UserRow::get",
+        sql: get_user_sql,
+    },
+    Case {
+        name: "UserRoleRow::has_role",
+        sql: has_role_sql,
+    },
+];
+
+#[tokio::main]
+async fn main() -> Result<(), sqlx::Error> {
+    let Ok(url) = std::env::var("DATABASE_URL") else {
+        eprintln!("Set DATABASE_URL to a scratch database to run this benchmark");
+        return Ok(());
+    };
+    let iterations: u32 = std::env::var("BENCH_ITERATIONS")
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(20_000);
+    let pool = PgPool::connect(&url).await?;
+    create_user_tables(&pool).await?;
+    let user_id = seed(&pool).await?;
+
+    println!("{} calls each", iterations);
+    for case in CASES {
+        let prepared = (case.sql)();
+        let once = measure(&pool, iterations, user_id, || prepared.as_str()).await?;
+        let per_call = measure(&pool, iterations, user_id, case.sql).await?;
+        println!(
+            "{:<22} static {:>10.1?}  format! {:>10.1?}",
+            case.name, once, per_call
+        );
+    }
+    cleanup(&pool, user_id).await
+}
+
+fn get_user_sql() -> String {
+    format!(
+        "SELECT {} FROM {} WHERE id = $1",
+        UserRow::columns(),
+        UserRow::table_name()
+    )
+}
+
+fn has_role_sql() -> String {
+    format!(
+        "SELECT EXISTS (SELECT 1 FROM {} WHERE user_id = $1 AND scope = 'project' \
+         AND scope_id = 'bench' AND role_name = 'editor')",
+        UserRoleRow::table_name()
+    )
+}
+
+/// Insert the user and its role. Returns the user's id.
+async fn seed(pool: &PgPool) -> Result<Uuid, sqlx::Error> {
+    let user_id = Uuid::new_v4();
+    sqlx::query("INSERT INTO auth.users (id, email) VALUES ($1, $2)")
+        .bind(user_id)
+        .bind(EMAIL)
+        .execute(pool)
+        .await?;
+    sqlx::query(
+        r#"
+        INSERT INTO auth.user_roles (user_id, scope, scope_id, role_name)
+        VALUES ($1, 'project', 'bench', 'editor')
+        "#,
+    )
+    .bind(user_id)
+    .execute(pool)
+    .await?;
+    Ok(user_id)
+}
+
+/// Mean time of one call, after a warm-up call. `sql` runs before every call, so a per-call
+/// `format!` is paid each time.
+async fn measure<F, S>(
+    pool: &PgPool,
+    iterations: u32,
+    user_id: Uuid,
+    sql: F,
+) -> Result<Duration, sqlx::Error>
+where
+    F: Fn() -> S,
+    S: AsRef<str>,
+{
+    let call = || async {
+        let sql = sql();
+        sqlx::query(sql.as_ref())
+            .bind(user_id)
+            .fetch_one(pool)
+            .await
+            .map(drop)
+    };
+    call().await?;
+    let started = Instant::now();
+    for _ in 0..iterations {
+        call().await?;
+    }
+    Ok(started.elapsed() / iterations)
+}
+
+async fn cleanup(pool: &PgPool, user_id: Uuid) -> Result<(), sqlx::Error> {
+    sqlx::query("DELETE FROM auth.user_roles WHERE user_id = $1")
+        .bind(user_id)
+        .execute(pool)
+        .await?;
+    sqlx::query("DELETE FROM auth.users WHERE id = $1")
+        .bind(user_id)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
@@ -0,0 +1,117 @@
+## Error messages, keyed by `error-` plus the error code with `_` replaced by `-`.
+## Top-level string fields of the error details are available as arguments, e.g. `$domain`.
+
+error-bad-request = The request is invalid.
+error-conflict = The request conflicts with the current state of the resource.
+error-forbidden = You do not have permission to do this.
+error-not-found = The requested resource was not found.
+error-precondition-failed = The resource was modified by another request.
+error-unauthorized = You need to sign in.
+error-csrf-mismatch = Your sign-in attempt expired. Please try again.
+error-identity-provider-error = The identity provider could not be reached.
+error-identity-provider-unavailable = Sign-in is temporarily unavailable. Please try again later.
+error-internal-error = Something went wrong.
+error-missing-scope-check = You do not have the role required for this.
+error-insufficient-scope = This application is not allowed to do this.
+error-reauth-required = Please confirm it's you to continue.
+error-suspended = Your account is suspended.
+error-captcha-required = Please complete the CAPTCHA.
+error-captcha-failed = The CAPTCHA could not be verified. Please try again.
+error-captcha-unavailable = The CAPTCHA service is unavailable. Please try again later.
+error-email-invalid = The email address is invalid.
+error-email-domain-disposable = Disposable email addresses from { $domain } are not allowed.
+error-email-domain-denied = Email addresses from { $domain } are not allowed.
+error-email-domain-no-mail-server = { $domain } does not accept email.
+error-password-rejected = The password does not meet the password requirements.
+error-provisioning-disabled = Accounts are not created on sign-in.
+error-pending-approval = Your account is waiting for an administrator to approve it.
+error-invitation-required = An invitation is required to create an account.
+error-invitation-email-mismatch = This invitation was sent to a different email address.
+error-idempotency-key-reused = This Idempotency-Key was already used for a different request.
+
+## Notifications
+
+notification-greeting = Hi,
+notification-greeting-named = Hi { $name },
+notification-unknown = unknown
+
+notification-verification-email-subject = Verify your email address
+notification-verification-email-body =
+    { $greeting }
+
+    Confirm your email address by opening this link:
+
+    { $verify_url }
+
+    The link expires at { $expires_at } UTC.
+
+notification-password-reset-subject = Reset your password
+notification-password-reset-body =
+    { $greeting }
+
+    Someone asked to reset your password. If this was you, open this link:
+
+    { $reset_url }
+
+    The link expires at { $expires_at } UTC. If you did not ask for this, ignore this message.
+
+notification-new-device-login-subject = New sign-in to your account
+notification-new-device-login-body =
+    { $greeting }
+
+    Your account was signed in to from a new device at { $logged_in_at } UTC.
+
+    Device: { $user_agent }
+    IP address: { $ip }
+
+    If this was not you, secure your account.
+
+notification-invitation-received-subject = You're invited to join { $group_name }
+notification-invitation-received-body =
+    { $greeting }
+
+    { $inviter_name } invited you to join { $group_name }. Accept the invitation here:
+
+    { $accept_url }
+
+notification-account-invitation-subject = You're invited to create an account
+notification-account-invitation-body =
+    { $greeting }
+
+    { $inviter_name } invited you to create an account. Accept the invitation here:
+
+    { $accept_url }
+
+    The link expires at { $expires_at } UTC.
+notification-password-changed-subject = Your password was changed
+notification-password-changed-body =
+    { $greeting }
+
+    The password of your account was changed at { $changed_at } UTC.
+
+    If this was not you, reset your password and secure your account.
+
+notification-email-changed-subject = Your email address was changed
+notification-email-changed-body =
+    { $greeting }
+
+    The email address of your account was changed to { $new_email } at { $changed_at } UTC.
+
+    If this was not you, contact support right away.
+
+notification-mfa-disabled-subject = Multi-factor authentication was turned off
+notification-mfa-disabled-body =
+    { $greeting }
+
+    Multi-factor authentication was turned off for your account at { $changed_at } UTC.
+
+    If this was not you, turn it back on and secure your account.
+
+notification-api-key-created-subject = A new API key was created
+notification-api-key-created-body =
+    { $greeting }
+
+    The API key "{ $key_name }" was created for your account at { $created_at } UTC.
+
+    If this was not you, delete the key and secure your account.
+notification-someone = Someone
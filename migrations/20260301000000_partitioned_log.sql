@@ -0,0 +1,73 @@
+ALTER TABLE auth.log RENAME TO log_unpartitioned;
+ALTER INDEX auth.log_pkey RENAME TO log_unpartitioned_pkey;
+ALTER INDEX auth.idx_auth_log_user_id RENAME TO idx_auth_log_unpartitioned_user_id;
+
+CREATE TABLE auth.log (
+    id UUID NOT NULL DEFAULT gen_random_uuid(),
+    user_id UUID REFERENCES auth.users(id) ON DELETE SET NULL,
+    action JSONB NOT NULL,
+    timestamp TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (id, timestamp)
+) PARTITION BY RANGE (timestamp);
+
+CREATE INDEX IF NOT EXISTS idx_auth_log_user_id ON auth.log (user_id, timestamp);
+
+CREATE TABLE IF NOT EXISTS auth.log_default PARTITION OF auth.log DEFAULT;
+
+-- Creates the monthly partition containing `month_start`, moving any rows that
+-- already landed in the default partition for that range. Returns the partition name.
+CREATE OR REPLACE FUNCTION auth.create_log_partition(month_start TIMESTAMP)
+RETURNS TEXT
+LANGUAGE plpgsql
+AS $$
+DECLARE
+    range_start TIMESTAMP := date_trunc('month', month_start);
+    range_end TIMESTAMP := date_trunc('month', month_start) + INTERVAL '1 month';
+    partition_name TEXT := 'log_' || to_char(date_trunc('month', month_start), '"y"YYYY"m"MM');
+BEGIN
+    IF to_regclass('auth.' || partition_name) IS NOT NULL THEN
+        RETURN partition_name;
+    END IF;
+
+    EXECUTE format(
+        'CREATE TABLE auth.%I (LIKE auth.log INCLUDING DEFAULTS INCLUDING CONSTRAINTS)',
+        partition_name
+    );
+    EXECUTE format(
+        'INSERT INTO auth.%I SELECT * FROM auth.log_default WHERE timestamp >= $1 AND timestamp < $2',
+        partition_name
+    ) USING range_start, range_end;
+    DELETE FROM auth.log_default WHERE timestamp >= range_start AND timestamp < range_end;
+    EXECUTE format(
+        'ALTER TABLE auth.log ATTACH PARTITION auth.%I FOR VALUES FROM (%L) TO (%L)',
+        partition_name,
+        range_start,
+        range_end
+    );
+
+    RETURN partition_name;
+END;
+$$;
+
+DO $$
+DECLARE
+    month TIMESTAMP;
+BEGIN
+    FOR month IN
+        SELECT DISTINCT date_trunc('month', COALESCE(timestamp, CURRENT_TIMESTAMP))
+        FROM auth.log_unpartitioned
+        UNION
+        SELECT date_trunc('month', CURRENT_TIMESTAMP::TIMESTAMP)
+        UNION
+        SELECT date_trunc('month', CURRENT_TIMESTAMP::TIMESTAMP) + INTERVAL '1 month'
+    LOOP
+        PERFORM auth.create_log_partition(month);
+    END LOOP;
+END;
+$$;
+
+INSERT INTO auth.log (id, user_id, action, timestamp)
+SELECT id, user_id, action, COALESCE(timestamp, CURRENT_TIMESTAMP)
+FROM auth.log_unpartitioned;
+
+DROP TABLE auth.log_unpartitioned;
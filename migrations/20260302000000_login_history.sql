@@ -0,0 +1,14 @@
+ALTER TABLE auth.users ADD COLUMN IF NOT EXISTS last_login_at TIMESTAMP;
+ALTER TABLE auth.users ADD COLUMN IF NOT EXISTS last_login_ip TEXT;
+
+CREATE TABLE IF NOT EXISTS auth.login_history (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    user_id UUID NOT NULL REFERENCES auth.users(id) ON DELETE CASCADE,
+    ip TEXT,
+    user_agent TEXT,
+    method TEXT NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS idx_auth_login_history_user_created
+    ON auth.login_history (user_id, created_at DESC);
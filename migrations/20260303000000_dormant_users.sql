@@ -0,0 +1,3 @@
+CREATE INDEX IF NOT EXISTS idx_auth_users_last_seen_active
+    ON auth.users ((COALESCE(last_login_at, created_at)))
+    WHERE active = TRUE;
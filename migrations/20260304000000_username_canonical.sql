@@ -0,0 +1,21 @@
+-- Canonical form used for username uniqueness: NFKC, lowercased, with common Cyrillic/Greek/digit
+-- look-alikes folded to Latin. Keep the translate() tables in sync with src/username.rs.
+CREATE OR REPLACE FUNCTION auth.username_canonical(username TEXT)
+RETURNS TEXT
+LANGUAGE sql
+IMMUTABLE
+PARALLEL SAFE
+AS $$
+    SELECT translate(
+        lower(normalize(username, NFKC)),
+        'авекмнорстухіјѕԁԛԝһӏαβεικνορτυχϲ01',
+        'abekmhopctyxijsdqwhlabeikvoptuxcol'
+    )
+$$;
+
+ALTER TABLE auth.users
+    ADD COLUMN IF NOT EXISTS username_canonical TEXT
+    GENERATED ALWAYS AS (auth.username_canonical(username)) STORED;
+
+CREATE UNIQUE INDEX IF NOT EXISTS idx_auth_users_username_canonical
+    ON auth.users (username_canonical);
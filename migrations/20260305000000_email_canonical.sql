@@ -0,0 +1,21 @@
+ALTER TABLE auth.users ADD COLUMN IF NOT EXISTS email_canonical TEXT;
+
+-- Backfill with the default normalization (trim + lowercase). Where existing rows collide, only
+-- the oldest account keeps the canonical value so the unique index can be built.
+WITH ranked AS (
+    SELECT id,
+           lower(btrim(email)) AS canonical,
+           row_number() OVER (
+               PARTITION BY lower(btrim(email))
+               ORDER BY created_at ASC NULLS LAST, id ASC
+           ) AS rank
+    FROM auth.users
+)
+UPDATE auth.users u
+SET email_canonical = ranked.canonical
+FROM ranked
+WHERE u.id = ranked.id
+  AND ranked.rank = 1;
+
+CREATE UNIQUE INDEX IF NOT EXISTS idx_auth_users_email_canonical
+    ON auth.users (email_canonical);
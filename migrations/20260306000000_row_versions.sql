@@ -0,0 +1,3 @@
+-- Incremented on every details update so concurrent edits can be detected (If-Match / ETag).
+ALTER TABLE auth.users ADD COLUMN IF NOT EXISTS version BIGINT NOT NULL DEFAULT 1;
+ALTER TABLE auth.groups ADD COLUMN IF NOT EXISTS version BIGINT NOT NULL DEFAULT 1;
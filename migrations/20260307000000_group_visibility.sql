@@ -0,0 +1,22 @@
+ALTER TABLE auth.groups
+    ADD COLUMN IF NOT EXISTS visibility TEXT NOT NULL DEFAULT 'private'
+    CHECK (visibility IN ('private', 'discoverable', 'open'));
+
+CREATE INDEX IF NOT EXISTS idx_auth_groups_discoverable
+    ON auth.groups (display_name)
+    WHERE active AND visibility <> 'private';
+
+CREATE TABLE IF NOT EXISTS auth.group_join_requests (
+    group_id UUID NOT NULL REFERENCES auth.groups(id) ON DELETE CASCADE,
+    user_id UUID NOT NULL REFERENCES auth.users(id) ON DELETE CASCADE,
+    message TEXT,
+    status TEXT NOT NULL DEFAULT 'pending' CHECK (status IN ('pending', 'approved', 'rejected')),
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    decided_at TIMESTAMP,
+    decided_by UUID REFERENCES auth.users(id) ON DELETE SET NULL,
+    PRIMARY KEY (group_id, user_id)
+);
+
+CREATE INDEX IF NOT EXISTS idx_auth_group_join_requests_pending
+    ON auth.group_join_requests (group_id, created_at)
+    WHERE status = 'pending';
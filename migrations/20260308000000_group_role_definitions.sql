@@ -0,0 +1,10 @@
+-- Custom member roles defined per group. `capabilities` is a bitset of `GroupCapabilities`.
+-- The built-in `group_admin` and `member` roles are not stored here.
+CREATE TABLE IF NOT EXISTS auth.group_role_definitions (
+    group_id UUID NOT NULL REFERENCES auth.groups(id) ON DELETE CASCADE,
+    role_name TEXT NOT NULL,
+    capabilities BIGINT NOT NULL DEFAULT 0,
+    description TEXT,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (group_id, role_name)
+);
@@ -0,0 +1,16 @@
+-- Former usernames. A released handle stays reserved for its previous owner until `released_at`
+-- so links and mentions keep resolving to the same account.
+CREATE TABLE IF NOT EXISTS auth.username_history (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    user_id UUID NOT NULL REFERENCES auth.users(id) ON DELETE CASCADE,
+    username TEXT NOT NULL,
+    username_canonical TEXT NOT NULL,
+    changed_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    released_at TIMESTAMP NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS idx_auth_username_history_canonical
+    ON auth.username_history (username_canonical, changed_at DESC);
+
+CREATE INDEX IF NOT EXISTS idx_auth_username_history_user
+    ON auth.username_history (user_id, changed_at DESC);
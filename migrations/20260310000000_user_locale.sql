@@ -0,0 +1,3 @@
+-- Preferred locale for user-facing messages, as a BCP 47 language tag (e.g. `pt-BR`).
+ALTER TABLE auth.users
+    ADD COLUMN IF NOT EXISTS locale TEXT;
@@ -0,0 +1,7 @@
+-- Local password hashes. The scheme (argon2id, bcrypt, scrypt) is identified by the hash prefix,
+-- so hashes imported from other systems keep working until they are upgraded on login.
+CREATE TABLE IF NOT EXISTS auth.user_passwords (
+    user_id UUID PRIMARY KEY REFERENCES auth.users(id) ON DELETE CASCADE,
+    password_hash TEXT NOT NULL,
+    updated_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
@@ -0,0 +1,20 @@
+-- Progress of bulk imports from legacy systems. `last_record` is the last input record covered by
+-- a committed batch, so an interrupted run resumes after it instead of starting over.
+CREATE TABLE IF NOT EXISTS auth.import_runs (
+    name TEXT PRIMARY KEY,
+    last_record BIGINT NOT NULL DEFAULT 0,
+    imported BIGINT NOT NULL DEFAULT 0,
+    started_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    updated_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    finished_at TIMESTAMP
+);
+
+-- Records an import skipped, with the reason, for review after the run.
+CREATE TABLE IF NOT EXISTS auth.import_conflicts (
+    run_name TEXT NOT NULL REFERENCES auth.import_runs(name) ON DELETE CASCADE,
+    record BIGINT NOT NULL,
+    email TEXT,
+    kind TEXT NOT NULL,
+    message TEXT NOT NULL,
+    PRIMARY KEY (run_name, record)
+);
@@ -0,0 +1,17 @@
+-- A `deny` grant blocks the role in its scope even when it is allowed directly, through a group,
+-- or at the global scope id. Deny always overrides allow.
+ALTER TABLE auth.user_roles
+    ADD COLUMN IF NOT EXISTS effect TEXT NOT NULL DEFAULT 'allow'
+    CHECK (effect IN ('allow', 'deny'));
+
+ALTER TABLE auth.group_roles
+    ADD COLUMN IF NOT EXISTS effect TEXT NOT NULL DEFAULT 'allow'
+    CHECK (effect IN ('allow', 'deny'));
+
+CREATE INDEX IF NOT EXISTS idx_auth_user_roles_deny
+    ON auth.user_roles (user_id, scope)
+    WHERE effect = 'deny';
+
+CREATE INDEX IF NOT EXISTS idx_auth_group_roles_deny
+    ON auth.group_roles (group_id, scope)
+    WHERE effect = 'deny';
@@ -0,0 +1,6 @@
+-- Optional attribute conditions on a grant, e.g. {"ip_cidr": "10.0.0.0/8", "mfa": true}. See
+-- `policy::GrantCondition`. A conditional allow only applies where the request context is known
+-- and satisfies it; a conditional deny applies whenever the context is unknown.
+ALTER TABLE auth.user_roles ADD COLUMN IF NOT EXISTS condition JSONB;
+
+ALTER TABLE auth.group_roles ADD COLUMN IF NOT EXISTS condition JSONB;
@@ -0,0 +1,15 @@
+-- Named sets of role grants applied together, e.g. when onboarding a new hire. A bundle lists
+-- (scope, role_name) pairs; the scope id is chosen when the bundle is applied.
+CREATE TABLE IF NOT EXISTS auth.role_bundles (
+    name TEXT PRIMARY KEY,
+    description TEXT,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    updated_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE TABLE IF NOT EXISTS auth.role_bundle_grants (
+    bundle_name TEXT NOT NULL REFERENCES auth.role_bundles(name) ON DELETE CASCADE,
+    scope TEXT NOT NULL,
+    role_name TEXT NOT NULL,
+    PRIMARY KEY (bundle_name, scope, role_name)
+);
@@ -0,0 +1,19 @@
+-- Grants a group hands to each of its members as direct user grants: applied when a member joins
+-- and revoked when they leave.
+CREATE TABLE IF NOT EXISTS auth.group_default_roles (
+    group_id UUID NOT NULL REFERENCES auth.groups(id) ON DELETE CASCADE,
+    scope TEXT NOT NULL,
+    scope_id TEXT NOT NULL,
+    role_name TEXT NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (group_id, scope, scope_id, role_name)
+);
+
+-- The group whose default put the grant there; NULL for grants made directly. Only these grants
+-- are revoked when the member leaves.
+ALTER TABLE auth.user_roles
+    ADD COLUMN IF NOT EXISTS source_group_id UUID REFERENCES auth.groups(id) ON DELETE CASCADE;
+
+CREATE INDEX IF NOT EXISTS idx_auth_user_roles_source_group
+    ON auth.user_roles (source_group_id, user_id)
+    WHERE source_group_id IS NOT NULL;
@@ -0,0 +1,6 @@
+-- Last start of each maintenance task across all replicas, so a task runs at most once per
+-- interval however many replicas schedule it.
+CREATE TABLE IF NOT EXISTS auth.maintenance_runs (
+    task TEXT PRIMARY KEY,
+    last_run_at TIMESTAMP NOT NULL
+);
@@ -0,0 +1,15 @@
+-- The identity provider mapping that put the grant or membership there; NULL when made directly.
+-- Only these are pruned when a sync-and-prune `ClaimsMapper` no longer maps them.
+ALTER TABLE auth.user_roles
+    ADD COLUMN IF NOT EXISTS source_provider TEXT;
+
+ALTER TABLE auth.group_memberships
+    ADD COLUMN IF NOT EXISTS source_provider TEXT;
+
+CREATE INDEX IF NOT EXISTS idx_auth_user_roles_source_provider
+    ON auth.user_roles (user_id, source_provider)
+    WHERE source_provider IS NOT NULL;
+
+CREATE INDEX IF NOT EXISTS idx_auth_group_memberships_source_provider
+    ON auth.group_memberships (user_id, source_provider)
+    WHERE source_provider IS NOT NULL;
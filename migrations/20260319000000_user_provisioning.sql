@@ -0,0 +1,8 @@
+-- Users created on first sign-in under `ProvisioningMode::RequireApproval`. They get no default
+-- access, and claims are not synced for them, until an admin approves them.
+ALTER TABLE auth.users
+    ADD COLUMN IF NOT EXISTS pending_approval BOOLEAN NOT NULL DEFAULT FALSE;
+
+CREATE INDEX IF NOT EXISTS idx_auth_users_pending_approval
+    ON auth.users (created_at)
+    WHERE pending_approval;
@@ -0,0 +1,22 @@
+-- Admin-issued invitations to create an account. Only a SHA-256 hash of the emailed token is
+-- stored. An invitation is bound to the invited email and can be accepted once before it expires.
+CREATE TABLE IF NOT EXISTS auth.user_invitations (
+    id UUID PRIMARY KEY,
+    email TEXT NOT NULL,
+    email_canonical TEXT NOT NULL,
+    token_hash TEXT NOT NULL UNIQUE,
+    invited_by UUID REFERENCES auth.users(id) ON DELETE SET NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    expires_at TIMESTAMP NOT NULL,
+    accepted_at TIMESTAMP,
+    accepted_by UUID REFERENCES auth.users(id) ON DELETE SET NULL,
+    revoked_at TIMESTAMP
+);
+
+-- At most one outstanding invitation per address; inviting again replaces it.
+CREATE UNIQUE INDEX IF NOT EXISTS idx_auth_user_invitations_outstanding
+    ON auth.user_invitations (email_canonical)
+    WHERE accepted_at IS NULL AND revoked_at IS NULL;
+
+CREATE INDEX IF NOT EXISTS idx_auth_user_invitations_created_at
+    ON auth.user_invitations (created_at);
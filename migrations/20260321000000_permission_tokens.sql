@@ -0,0 +1,19 @@
+-- Permission tokens handed to edge services: signed role snapshots verified without the database.
+-- Only the token id is stored, so a token can be revoked before it expires. `user_id` has no
+-- foreign key so tokens of a deleted user stay on the revocation list until they expire.
+CREATE TABLE IF NOT EXISTS auth.permission_tokens (
+    jti UUID PRIMARY KEY,
+    user_id UUID NOT NULL,
+    issued_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    expires_at TIMESTAMP NOT NULL,
+    revoked_at TIMESTAMP,
+    revoked_by UUID REFERENCES auth.users(id) ON DELETE SET NULL
+);
+
+CREATE INDEX IF NOT EXISTS idx_auth_permission_tokens_user_id
+    ON auth.permission_tokens (user_id);
+
+-- The revocation list only covers tokens that have not expired yet.
+CREATE INDEX IF NOT EXISTS idx_auth_permission_tokens_revoked
+    ON auth.permission_tokens (expires_at)
+    WHERE revoked_at IS NOT NULL;
@@ -0,0 +1,38 @@
+-- First-party applications that sign users in through this service (the `oauth-server` feature).
+-- Only a SHA-256 hash of a confidential client's secret is stored; public clients have none and
+-- rely on PKCE alone. Trusted clients skip the consent step.
+CREATE TABLE IF NOT EXISTS auth.oauth_clients (
+    client_id TEXT PRIMARY KEY,
+    name TEXT NOT NULL,
+    redirect_uris TEXT[] NOT NULL DEFAULT '{}',
+    secret_hash TEXT,
+    trusted BOOLEAN NOT NULL DEFAULT FALSE,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    disabled_at TIMESTAMP
+);
+
+-- Scopes a user agreed to share with an untrusted client.
+CREATE TABLE IF NOT EXISTS auth.oauth_consents (
+    user_id UUID NOT NULL REFERENCES auth.users(id) ON DELETE CASCADE,
+    client_id TEXT NOT NULL REFERENCES auth.oauth_clients(client_id) ON DELETE CASCADE,
+    scopes TEXT[] NOT NULL,
+    granted_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (user_id, client_id)
+);
+
+-- Single-use authorization codes, stored as SHA-256 hashes.
+CREATE TABLE IF NOT EXISTS auth.oauth_authorization_codes (
+    code_hash TEXT PRIMARY KEY,
+    client_id TEXT NOT NULL REFERENCES auth.oauth_clients(client_id) ON DELETE CASCADE,
+    user_id UUID NOT NULL REFERENCES auth.users(id) ON DELETE CASCADE,
+    redirect_uri TEXT NOT NULL,
+    scopes TEXT[] NOT NULL,
+    code_challenge TEXT NOT NULL,
+    nonce TEXT,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    expires_at TIMESTAMP NOT NULL,
+    consumed_at TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS idx_auth_oauth_authorization_codes_expires_at
+    ON auth.oauth_authorization_codes (expires_at);
@@ -0,0 +1,24 @@
+-- Applications registered with this service, for the OAuth provider and any other per-app
+-- credentials. Replaces `auth.oauth_clients`; consents and authorization codes keep pointing at
+-- the renamed table.
+ALTER TABLE IF EXISTS auth.oauth_clients RENAME TO clients;
+
+-- Scopes the client may request. Clients registered before this migration keep every scope the
+-- provider supported.
+ALTER TABLE auth.clients
+    ADD COLUMN IF NOT EXISTS allowed_scopes TEXT[] NOT NULL DEFAULT '{}';
+UPDATE auth.clients SET allowed_scopes = '{openid,profile,email}';
+
+-- Group that owns the application, for attribution.
+ALTER TABLE auth.clients
+    ADD COLUMN IF NOT EXISTS owner_group_id UUID REFERENCES auth.groups(id) ON DELETE SET NULL;
+
+-- Token requests allowed per minute. NULL means unlimited.
+ALTER TABLE auth.clients
+    ADD COLUMN IF NOT EXISTS rate_limit_per_minute INTEGER CHECK (rate_limit_per_minute > 0);
+
+ALTER TABLE auth.clients
+    ADD COLUMN IF NOT EXISTS updated_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP;
+
+CREATE INDEX IF NOT EXISTS idx_auth_clients_owner_group_id
+    ON auth.clients (owner_group_id);
@@ -0,0 +1,18 @@
+-- What each user allowed each client, one row per scope. Replaces `auth.oauth_consents`.
+-- Revoking sets `revoked_at`; access tokens issued before a scope was (re)granted stop working at
+-- the userinfo endpoint, and codes for revoked scopes cannot be redeemed.
+CREATE TABLE IF NOT EXISTS auth.consents (
+    user_id UUID NOT NULL REFERENCES auth.users(id) ON DELETE CASCADE,
+    client_id TEXT NOT NULL REFERENCES auth.clients(client_id) ON DELETE CASCADE,
+    scope TEXT NOT NULL,
+    granted_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    revoked_at TIMESTAMP,
+    PRIMARY KEY (user_id, client_id, scope)
+);
+
+INSERT INTO auth.consents (user_id, client_id, scope, granted_at)
+SELECT user_id, client_id, unnest(scopes), granted_at
+FROM auth.oauth_consents
+ON CONFLICT DO NOTHING;
+
+DROP TABLE IF EXISTS auth.oauth_consents;
@@ -0,0 +1,15 @@
+-- Sessions ended at the identity provider through OIDC back-channel logout. Each instance loads
+-- recent rows into memory; `(issuer, jti)` rejects replayed logout tokens.
+CREATE TABLE IF NOT EXISTS auth.federated_logouts (
+    issuer TEXT NOT NULL,
+    jti TEXT NOT NULL,
+    subject TEXT,
+    session_id TEXT,
+    logged_out_at TIMESTAMP NOT NULL,
+    received_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (issuer, jti),
+    CHECK (subject IS NOT NULL OR session_id IS NOT NULL)
+);
+
+CREATE INDEX IF NOT EXISTS federated_logouts_logged_out_at_idx
+    ON auth.federated_logouts (logged_out_at);
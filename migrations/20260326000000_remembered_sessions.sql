@@ -0,0 +1,18 @@
+-- Long-lived remember-me tokens that re-establish short sessions. The cookie holds
+-- `<id>.<secret>`; `token` is the provider's token set, encrypted under a key derived from the
+-- secret, so it is only usable together with the cookie.
+CREATE TABLE IF NOT EXISTS auth.remembered_sessions (
+    id UUID PRIMARY KEY,
+    user_id UUID NOT NULL REFERENCES auth.users(id) ON DELETE CASCADE,
+    secret_hash TEXT NOT NULL,
+    token TEXT NOT NULL,
+    user_agent TEXT,
+    ip TEXT,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    last_used_at TIMESTAMP,
+    expires_at TIMESTAMP NOT NULL,
+    revoked_at TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS remembered_sessions_user_id_idx
+    ON auth.remembered_sessions (user_id);
@@ -0,0 +1,8 @@
+-- Optional notifications a user turned on or off. Kinds without a row are on.
+CREATE TABLE IF NOT EXISTS auth.notification_preferences (
+    user_id UUID NOT NULL REFERENCES auth.users(id) ON DELETE CASCADE,
+    kind TEXT NOT NULL,
+    enabled BOOLEAN NOT NULL,
+    updated_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (user_id, kind)
+);
@@ -0,0 +1,49 @@
+-- Per-day activity for `/auth/admin/stats`, over the year before the last refresh. Counting
+-- `auth.log` and `auth.login_history` per request would scan both; the view is refreshed with
+-- `stats::refresh_daily_stats`, from the endpoint or a maintenance task.
+CREATE INDEX IF NOT EXISTS idx_auth_log_login_failed
+    ON auth.log (timestamp)
+    WHERE action->>'type' = 'login_failed';
+
+CREATE INDEX IF NOT EXISTS idx_auth_users_created_at ON auth.users (created_at);
+
+CREATE MATERIALIZED VIEW IF NOT EXISTS auth.daily_stats AS
+WITH days AS (
+    SELECT generate_series(
+        (now() AT TIME ZONE 'UTC')::DATE - 364,
+        (now() AT TIME ZONE 'UTC')::DATE,
+        INTERVAL '1 day'
+    )::DATE AS day
+),
+signups AS (
+    SELECT created_at::DATE AS day, count(*) AS n
+    FROM auth.users
+    WHERE created_at >= (SELECT min(day) FROM days)
+    GROUP BY 1
+),
+logins AS (
+    SELECT created_at::DATE AS day, count(*) AS n
+    FROM auth.login_history
+    WHERE created_at >= (SELECT min(day) FROM days)
+    GROUP BY 1
+),
+failed_logins AS (
+    SELECT timestamp::DATE AS day, count(*) AS n
+    FROM auth.log
+    WHERE action->>'type' = 'login_failed'
+      AND timestamp >= (SELECT min(day) FROM days)
+    GROUP BY 1
+)
+SELECT
+    days.day,
+    COALESCE(signups.n, 0)::BIGINT AS signups,
+    COALESCE(logins.n, 0)::BIGINT AS logins,
+    COALESCE(failed_logins.n, 0)::BIGINT AS failed_logins,
+    (now() AT TIME ZONE 'UTC')::TIMESTAMP AS refreshed_at
+FROM days
+LEFT JOIN signups ON signups.day = days.day
+LEFT JOIN logins ON logins.day = days.day
+LEFT JOIN failed_logins ON failed_logins.day = days.day;
+
+-- Required by `REFRESH MATERIALIZED VIEW CONCURRENTLY`.
+CREATE UNIQUE INDEX IF NOT EXISTS idx_auth_daily_stats_day ON auth.daily_stats (day);
@@ -0,0 +1,10 @@
+-- Issuer of the identity provider each login went through, for `reports::logins_by_provider`.
+-- NULL for logins recorded before this column, or without a provider.
+ALTER TABLE auth.login_history ADD COLUMN IF NOT EXISTS provider TEXT;
+
+CREATE INDEX IF NOT EXISTS idx_auth_login_history_created_at
+    ON auth.login_history (created_at);
+
+CREATE INDEX IF NOT EXISTS idx_auth_log_role_changes
+    ON auth.log (timestamp)
+    WHERE action->>'type' IN ('role_grant', 'role_deny', 'role_revoke');
@@ -0,0 +1,9 @@
+-- Identifiers for URLs that do not reveal row UUIDs, e.g. nanoids or slugs. NULL for rows created
+-- before this column until `backfill_external_ids` or `set_external_id` gives them one.
+ALTER TABLE auth.users ADD COLUMN IF NOT EXISTS external_id TEXT;
+ALTER TABLE auth.groups ADD COLUMN IF NOT EXISTS external_id TEXT;
+
+CREATE UNIQUE INDEX IF NOT EXISTS idx_auth_users_external_id
+    ON auth.users (external_id);
+CREATE UNIQUE INDEX IF NOT EXISTS idx_auth_groups_external_id
+    ON auth.groups (external_id);
@@ -0,0 +1,61 @@
+-- Publish changes to a user's roles, groups and profile on the `auth_user_updates` channel, for
+-- `updates::UserUpdates` to stream to `GET /auth/me/updates`. Payloads are
+-- {"kind": "roles" | "groups" | "profile", "user_id" | "group_id": "<uuid>"}; a group_id concerns
+-- every member of the group. Notifications are sent on commit.
+CREATE OR REPLACE FUNCTION auth.notify_user_update()
+RETURNS TRIGGER
+LANGUAGE plpgsql
+AS $$
+DECLARE
+    changed JSONB;
+BEGIN
+    IF TG_OP = 'DELETE' THEN
+        changed := to_jsonb(OLD);
+    ELSE
+        changed := to_jsonb(NEW);
+    END IF;
+    PERFORM pg_notify(
+        'auth_user_updates',
+        jsonb_build_object('kind', TG_ARGV[0], TG_ARGV[2], changed ->> TG_ARGV[1])::TEXT
+    );
+    RETURN NULL;
+END;
+$$;
+
+DROP TRIGGER IF EXISTS notify_user_update ON auth.user_roles;
+CREATE TRIGGER notify_user_update
+    AFTER INSERT OR UPDATE OR DELETE ON auth.user_roles
+    FOR EACH ROW EXECUTE FUNCTION auth.notify_user_update('roles', 'user_id', 'user_id');
+
+DROP TRIGGER IF EXISTS notify_user_update ON auth.group_roles;
+CREATE TRIGGER notify_user_update
+    AFTER INSERT OR UPDATE OR DELETE ON auth.group_roles
+    FOR EACH ROW EXECUTE FUNCTION auth.notify_user_update('roles', 'group_id', 'group_id');
+
+DROP TRIGGER IF EXISTS notify_user_update ON auth.group_memberships;
+CREATE TRIGGER notify_user_update
+    AFTER INSERT OR UPDATE OR DELETE ON auth.group_memberships
+    FOR EACH ROW EXECUTE FUNCTION auth.notify_user_update('groups', 'user_id', 'user_id');
+
+-- Logins touch last_login_at on every sign-in; only the fields `/auth/me` returns count.
+DROP TRIGGER IF EXISTS notify_user_update ON auth.users;
+CREATE TRIGGER notify_user_update
+    AFTER UPDATE ON auth.users
+    FOR EACH ROW
+    WHEN (
+        (OLD.username, OLD.email, OLD.details, OLD.version, OLD.external_id, OLD.locale)
+        IS DISTINCT FROM
+        (NEW.username, NEW.email, NEW.details, NEW.version, NEW.external_id, NEW.locale)
+    )
+    EXECUTE FUNCTION auth.notify_user_update('profile', 'id', 'user_id');
+
+DROP TRIGGER IF EXISTS notify_user_update ON auth.groups;
+CREATE TRIGGER notify_user_update
+    AFTER UPDATE ON auth.groups
+    FOR EACH ROW
+    WHEN (
+        (OLD.display_name, OLD.active, OLD.visibility, OLD.external_id)
+        IS DISTINCT FROM
+        (NEW.display_name, NEW.active, NEW.visibility, NEW.external_id)
+    )
+    EXECUTE FUNCTION auth.notify_user_update('groups', 'id', 'group_id');
@@ -0,0 +1,26 @@
+-- Shared secrets for HMAC-signed requests (`hmac_auth`), for internal services that cannot use
+-- OAuth. A client may hold several keys so a secret can be rotated without downtime. Unlike
+-- client secrets these cannot be hashed, since the service has to sign with them too; `secret`
+-- is written through the app's `cipher::FieldCipher` when it has one.
+CREATE TABLE IF NOT EXISTS auth.hmac_keys (
+    key_id TEXT PRIMARY KEY,
+    client_id TEXT NOT NULL REFERENCES auth.clients(client_id) ON DELETE CASCADE,
+    secret TEXT NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    revoked_at TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS idx_auth_hmac_keys_client_id
+    ON auth.hmac_keys (client_id);
+
+-- Nonces of accepted requests, kept until the request timestamp falls outside the allowed clock
+-- skew; after that the timestamp check alone refuses a replay.
+CREATE TABLE IF NOT EXISTS auth.hmac_nonces (
+    key_id TEXT NOT NULL REFERENCES auth.hmac_keys(key_id) ON DELETE CASCADE,
+    nonce TEXT NOT NULL,
+    expires_at TIMESTAMP NOT NULL,
+    PRIMARY KEY (key_id, nonce)
+);
+
+CREATE INDEX IF NOT EXISTS idx_auth_hmac_nonces_expires_at
+    ON auth.hmac_nonces (expires_at);
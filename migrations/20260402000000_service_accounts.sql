@@ -0,0 +1,13 @@
+-- Services that authenticate with client certificates (`mtls`). A certificate maps to the account
+-- whose `san` it presents: a SPIFFE ID (URI SAN) or a DNS name. `spki_pins` optionally narrows
+-- this to certain keys, as base64url SHA-256 digests of the DER SubjectPublicKeyInfo; empty
+-- accepts any key the TLS layer trusted.
+CREATE TABLE IF NOT EXISTS auth.service_accounts (
+    id UUID PRIMARY KEY,
+    name TEXT NOT NULL UNIQUE,
+    san TEXT NOT NULL UNIQUE,
+    spki_pins TEXT[] NOT NULL DEFAULT '{}',
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    updated_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    disabled_at TIMESTAMP
+);
@@ -0,0 +1,7 @@
+-- Id of the HTTP request that wrote the entry, from `X-Request-Id` or generated by
+-- `RequestIdLayer`. NULL for entries written outside a request, e.g. by maintenance jobs.
+ALTER TABLE auth.log ADD COLUMN IF NOT EXISTS request_id TEXT;
+
+CREATE INDEX IF NOT EXISTS idx_auth_log_request_id
+    ON auth.log (request_id)
+    WHERE request_id IS NOT NULL;
@@ -0,0 +1,15 @@
+-- Why a user or group was deactivated, with an admin note, who did it and when. Reactivation
+-- clears them. All NULL for rows deactivated before this migration or by dormancy sweeps.
+ALTER TABLE auth.users
+    ADD COLUMN IF NOT EXISTS deactivation_reason TEXT
+        CHECK (deactivation_reason IN ('user_requested', 'abuse', 'non_payment', 'security')),
+    ADD COLUMN IF NOT EXISTS deactivation_note TEXT,
+    ADD COLUMN IF NOT EXISTS deactivated_by UUID REFERENCES auth.users(id) ON DELETE SET NULL,
+    ADD COLUMN IF NOT EXISTS deactivated_at TIMESTAMP;
+
+ALTER TABLE auth.groups
+    ADD COLUMN IF NOT EXISTS deactivation_reason TEXT
+        CHECK (deactivation_reason IN ('user_requested', 'abuse', 'non_payment', 'security')),
+    ADD COLUMN IF NOT EXISTS deactivation_note TEXT,
+    ADD COLUMN IF NOT EXISTS deactivated_by UUID REFERENCES auth.users(id) ON DELETE SET NULL,
+    ADD COLUMN IF NOT EXISTS deactivated_at TIMESTAMP;
@@ -0,0 +1,24 @@
+-- Time-boxed or indefinite bans, kept apart from `active` so lifting one restores the account
+-- as it was and the history survives. A suspension is in force from `starts_at` until `ends_at`
+-- (NULL: until lifted) unless `lifted_at` is set.
+CREATE TABLE IF NOT EXISTS auth.suspensions (
+    id UUID PRIMARY KEY,
+    user_id UUID NOT NULL REFERENCES auth.users(id) ON DELETE CASCADE,
+    reason TEXT NOT NULL,
+    note TEXT,
+    starts_at TIMESTAMP NOT NULL,
+    ends_at TIMESTAMP,
+    imposed_by UUID REFERENCES auth.users(id) ON DELETE SET NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    lifted_at TIMESTAMP,
+    lifted_by UUID REFERENCES auth.users(id) ON DELETE SET NULL,
+    lift_note TEXT,
+    CHECK (ends_at IS NULL OR ends_at > starts_at)
+);
+
+CREATE INDEX IF NOT EXISTS idx_auth_suspensions_user_id
+    ON auth.suspensions (user_id, starts_at);
+
+CREATE INDEX IF NOT EXISTS idx_auth_suspensions_in_force
+    ON auth.suspensions (user_id)
+    WHERE lifted_at IS NULL;
@@ -0,0 +1,42 @@
+-- Abuse reports filed by users against users or groups, worked through by moderators:
+-- open -> reviewing -> actioned or dismissed.
+CREATE TABLE IF NOT EXISTS auth.reports (
+    id UUID PRIMARY KEY,
+    reporter_id UUID REFERENCES auth.users(id) ON DELETE SET NULL,
+    subject_type TEXT NOT NULL CHECK (subject_type IN ('user', 'group')),
+    subject_id UUID NOT NULL,
+    category TEXT NOT NULL,
+    message TEXT,
+    status TEXT NOT NULL DEFAULT 'open'
+        CHECK (status IN ('open', 'reviewing', 'actioned', 'dismissed')),
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    updated_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    reviewer_id UUID REFERENCES auth.users(id) ON DELETE SET NULL,
+    resolution_note TEXT,
+    resolved_at TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS idx_auth_reports_queue
+    ON auth.reports (status, created_at);
+
+CREATE INDEX IF NOT EXISTS idx_auth_reports_subject
+    ON auth.reports (subject_type, subject_id);
+
+-- One unresolved report per reporter and subject.
+CREATE UNIQUE INDEX IF NOT EXISTS idx_auth_reports_unresolved
+    ON auth.reports (reporter_id, subject_type, subject_id)
+    WHERE status IN ('open', 'reviewing');
+
+-- Material attached to a report by the reporter, the host app or moderators, e.g. the message
+-- that was reported.
+CREATE TABLE IF NOT EXISTS auth.report_evidence (
+    id UUID PRIMARY KEY,
+    report_id UUID NOT NULL REFERENCES auth.reports(id) ON DELETE CASCADE,
+    kind TEXT NOT NULL,
+    payload JSONB NOT NULL,
+    attached_by UUID REFERENCES auth.users(id) ON DELETE SET NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS idx_auth_report_evidence_report_id
+    ON auth.report_evidence (report_id, created_at);
@@ -0,0 +1,17 @@
+-- Responses to POST requests sent with an `Idempotency-Key` header, replayed when a client
+-- retries with the same key. `status_code` is NULL while the first request is still being
+-- handled. No foreign key on `user_id`: accepting an invitation creates the user it runs as.
+CREATE TABLE IF NOT EXISTS auth.idempotency_keys (
+    user_id UUID NOT NULL,
+    key TEXT NOT NULL,
+    fingerprint TEXT NOT NULL,
+    status_code INTEGER,
+    content_type TEXT,
+    body BYTEA,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    completed_at TIMESTAMP,
+    PRIMARY KEY (user_id, key)
+);
+
+CREATE INDEX IF NOT EXISTS idx_auth_idempotency_keys_created_at
+    ON auth.idempotency_keys (created_at);
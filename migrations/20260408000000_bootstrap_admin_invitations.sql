@@ -0,0 +1,4 @@
+-- Invitations issued by `bootstrap::bootstrap` for the first admin of a deployment. Accepting one
+-- grants `super_admin`, unless someone already holds it by then.
+ALTER TABLE auth.user_invitations
+    ADD COLUMN IF NOT EXISTS bootstrap_admin BOOLEAN NOT NULL DEFAULT FALSE;
@@ -0,0 +1,8 @@
+-- Indexes for lookups that were sequential scans. `auth.user_roles (user_id, scope, scope_id)`,
+-- `auth.group_memberships (user_id)` and `auth.log (user_id, timestamp)` already exist; the last
+-- serves newest-first reads by scanning backwards. `query_plans::explain_queries` checks all of
+-- them.
+CREATE INDEX IF NOT EXISTS idx_auth_users_email_lower ON auth.users (lower(email));
+
+CREATE INDEX IF NOT EXISTS idx_auth_import_conflicts_email_lower
+    ON auth.import_conflicts (lower(email));
@@ -0,0 +1,91 @@
+-- Ordered feed of changes to grants and memberships, for `GET /auth/sync/changes`. Each row is an
+-- upsert of the row as it is now or a delete of the row as it was; an update that changes a
+-- row's key is a delete of the old key followed by an upsert of the new one.
+--
+-- Writers take an advisory lock for the rest of their transaction before touching the tables, so
+-- ids are handed out in commit order and a reader never sees a change before an earlier one.
+CREATE TABLE IF NOT EXISTS auth.permission_changes (
+    id BIGSERIAL PRIMARY KEY,
+    kind TEXT NOT NULL CHECK (kind IN ('user_role', 'group_role', 'membership')),
+    operation TEXT NOT NULL CHECK (operation IN ('upsert', 'delete')),
+    record JSONB NOT NULL,
+    changed_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE OR REPLACE FUNCTION auth.lock_permission_changes()
+RETURNS TRIGGER
+LANGUAGE plpgsql
+AS $$
+BEGIN
+    PERFORM pg_advisory_xact_lock(hashtext('auth.permission_changes'));
+    RETURN NULL;
+END;
+$$;
+
+-- TG_ARGV[0] is the kind; the rest name the key columns.
+CREATE OR REPLACE FUNCTION auth.record_permission_change()
+RETURNS TRIGGER
+LANGUAGE plpgsql
+AS $$
+DECLARE
+    moved BOOLEAN := FALSE;
+BEGIN
+    IF TG_OP = 'UPDATE' THEN
+        FOR i IN 1 .. TG_NARGS - 1 LOOP
+            IF (to_jsonb(OLD) -> TG_ARGV[i]) IS DISTINCT FROM (to_jsonb(NEW) -> TG_ARGV[i]) THEN
+                moved := TRUE;
+            END IF;
+        END LOOP;
+    END IF;
+    IF TG_OP = 'DELETE' OR moved THEN
+        INSERT INTO auth.permission_changes (kind, operation, record)
+        VALUES (TG_ARGV[0], 'delete', to_jsonb(OLD));
+    END IF;
+    IF TG_OP IN ('INSERT', 'UPDATE') THEN
+        INSERT INTO auth.permission_changes (kind, operation, record)
+        VALUES (TG_ARGV[0], 'upsert', to_jsonb(NEW));
+    END IF;
+    RETURN NULL;
+END;
+$$;
+
+DROP TRIGGER IF EXISTS lock_permission_changes ON auth.user_roles;
+CREATE TRIGGER lock_permission_changes
+    BEFORE INSERT OR UPDATE OR DELETE ON auth.user_roles
+    FOR EACH STATEMENT EXECUTE FUNCTION auth.lock_permission_changes();
+
+DROP TRIGGER IF EXISTS record_permission_change ON auth.user_roles;
+CREATE TRIGGER record_permission_change
+    AFTER INSERT OR UPDATE OR DELETE ON auth.user_roles
+    FOR EACH ROW EXECUTE FUNCTION
+        auth.record_permission_change('user_role', 'user_id', 'scope', 'scope_id', 'role_name');
+
+DROP TRIGGER IF EXISTS lock_permission_changes ON auth.group_roles;
+CREATE TRIGGER lock_permission_changes
+    BEFORE INSERT OR UPDATE OR DELETE ON auth.group_roles
+    FOR EACH STATEMENT EXECUTE FUNCTION auth.lock_permission_changes();
+
+DROP TRIGGER IF EXISTS record_permission_change ON auth.group_roles;
+CREATE TRIGGER record_permission_change
+    AFTER INSERT OR UPDATE OR DELETE ON auth.group_roles
+    FOR EACH ROW EXECUTE FUNCTION
+        auth.record_permission_change('group_role', 'group_id', 'scope', 'scope_id', 'role_name');
+
+DROP TRIGGER IF EXISTS lock_permission_changes ON auth.group_memberships;
+CREATE TRIGGER lock_permission_changes
+    BEFORE INSERT OR UPDATE OR DELETE ON auth.group_memberships
+    FOR EACH STATEMENT EXECUTE FUNCTION auth.lock_permission_changes();
+
+DROP TRIGGER IF EXISTS record_permission_change ON auth.group_memberships;
+CREATE TRIGGER record_permission_change
+    AFTER INSERT OR UPDATE OR DELETE ON auth.group_memberships
+    FOR EACH ROW EXECUTE FUNCTION
+        auth.record_permission_change('membership', 'group_id', 'user_id');
+
+-- Start the feed with the current rows, so reading it from the beginning rebuilds everything.
+INSERT INTO auth.permission_changes (kind, operation, record)
+SELECT 'user_role', 'upsert', to_jsonb(r) FROM auth.user_roles r
+UNION ALL
+SELECT 'group_role', 'upsert', to_jsonb(r) FROM auth.group_roles r
+UNION ALL
+SELECT 'membership', 'upsert', to_jsonb(m) FROM auth.group_memberships m;
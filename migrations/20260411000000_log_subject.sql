@@ -0,0 +1,9 @@
+-- Audit events about a user that someone else wrote, for `GET /auth/me/activity`. Membership and
+-- suspension events name the user in `user_id`; role changes name them in `target_id`.
+CREATE INDEX IF NOT EXISTS idx_auth_log_subject_user
+    ON auth.log ((action->>'user_id'), timestamp)
+    WHERE action ? 'user_id';
+
+CREATE INDEX IF NOT EXISTS idx_auth_log_role_target_user
+    ON auth.log ((action->>'target_id'), timestamp)
+    WHERE action->>'target_type' = 'user';
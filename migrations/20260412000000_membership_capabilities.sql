@@ -0,0 +1,3 @@
+-- Capabilities delegated to one member on top of those of their role, as a `GroupCapabilities`
+-- bitset, so an admin can let a member add or remove members without a custom role for it.
+ALTER TABLE auth.group_memberships ADD COLUMN IF NOT EXISTS capabilities BIGINT NOT NULL DEFAULT 0;
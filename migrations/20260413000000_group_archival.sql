@@ -0,0 +1,87 @@
+-- Archived groups are frozen: they stay active and queryable, their grants still apply, but their
+-- details, memberships, grants, role definitions, default roles and join requests can no longer
+-- change. Writes raise SQLSTATE SGA01, which `archival::is_group_archived` recognizes.
+ALTER TABLE auth.groups
+    ADD COLUMN IF NOT EXISTS archived_at TIMESTAMP,
+    ADD COLUMN IF NOT EXISTS archived_by UUID REFERENCES auth.users(id) ON DELETE SET NULL;
+
+CREATE INDEX IF NOT EXISTS idx_auth_groups_archived_at
+    ON auth.groups (archived_at)
+    WHERE archived_at IS NOT NULL;
+
+-- Rows deleted by a cascade, e.g. from deleting a user, are let through.
+CREATE OR REPLACE FUNCTION auth.reject_archived_group_change()
+RETURNS TRIGGER
+LANGUAGE plpgsql
+AS $$
+DECLARE
+    changed JSONB;
+BEGIN
+    IF TG_OP = 'DELETE' THEN
+        changed := to_jsonb(OLD);
+    ELSE
+        changed := to_jsonb(NEW);
+    END IF;
+    IF pg_trigger_depth() = 1 AND EXISTS (
+        SELECT 1
+        FROM auth.groups
+        WHERE id = (changed ->> 'group_id')::UUID
+          AND archived_at IS NOT NULL
+    ) THEN
+        RAISE EXCEPTION 'group % is archived', changed ->> 'group_id'
+            USING ERRCODE = 'SGA01';
+    END IF;
+    IF TG_OP = 'DELETE' THEN
+        RETURN OLD;
+    END IF;
+    RETURN NEW;
+END;
+$$;
+
+DROP TRIGGER IF EXISTS reject_archived_group_change ON auth.group_memberships;
+CREATE TRIGGER reject_archived_group_change
+    BEFORE INSERT OR UPDATE OR DELETE ON auth.group_memberships
+    FOR EACH ROW EXECUTE FUNCTION auth.reject_archived_group_change();
+
+DROP TRIGGER IF EXISTS reject_archived_group_change ON auth.group_roles;
+CREATE TRIGGER reject_archived_group_change
+    BEFORE INSERT OR UPDATE OR DELETE ON auth.group_roles
+    FOR EACH ROW EXECUTE FUNCTION auth.reject_archived_group_change();
+
+DROP TRIGGER IF EXISTS reject_archived_group_change ON auth.group_role_definitions;
+CREATE TRIGGER reject_archived_group_change
+    BEFORE INSERT OR UPDATE OR DELETE ON auth.group_role_definitions
+    FOR EACH ROW EXECUTE FUNCTION auth.reject_archived_group_change();
+
+DROP TRIGGER IF EXISTS reject_archived_group_change ON auth.group_default_roles;
+CREATE TRIGGER reject_archived_group_change
+    BEFORE INSERT OR UPDATE OR DELETE ON auth.group_default_roles
+    FOR EACH ROW EXECUTE FUNCTION auth.reject_archived_group_change();
+
+DROP TRIGGER IF EXISTS reject_archived_group_change ON auth.group_join_requests;
+CREATE TRIGGER reject_archived_group_change
+    BEFORE INSERT OR UPDATE OR DELETE ON auth.group_join_requests
+    FOR EACH ROW EXECUTE FUNCTION auth.reject_archived_group_change();
+
+CREATE OR REPLACE FUNCTION auth.reject_archived_group_update()
+RETURNS TRIGGER
+LANGUAGE plpgsql
+AS $$
+BEGIN
+    RAISE EXCEPTION 'group % is archived', OLD.id USING ERRCODE = 'SGA01';
+END;
+$$;
+
+-- Archiving, unarchiving and deactivating an archived group are allowed.
+DROP TRIGGER IF EXISTS reject_archived_group_update ON auth.groups;
+CREATE TRIGGER reject_archived_group_update
+    BEFORE UPDATE ON auth.groups
+    FOR EACH ROW
+    WHEN (
+        OLD.archived_at IS NOT NULL
+        AND NEW.archived_at IS NOT NULL
+        AND (OLD.display_name, OLD.details, OLD.version, OLD.visibility, OLD.external_id)
+            IS DISTINCT FROM
+            (NEW.display_name, NEW.details, NEW.version, NEW.visibility, NEW.external_id)
+    )
+    EXECUTE FUNCTION auth.reject_archived_group_update();
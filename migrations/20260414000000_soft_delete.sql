@@ -0,0 +1,17 @@
+-- Soft deletion: a deleted user or group is inactive and marked with when and by whom it was
+-- deleted, until it is restored or purged after the retention window.
+ALTER TABLE auth.users
+    ADD COLUMN IF NOT EXISTS deleted_at TIMESTAMP,
+    ADD COLUMN IF NOT EXISTS deleted_by UUID REFERENCES auth.users(id) ON DELETE SET NULL;
+
+ALTER TABLE auth.groups
+    ADD COLUMN IF NOT EXISTS deleted_at TIMESTAMP,
+    ADD COLUMN IF NOT EXISTS deleted_by UUID REFERENCES auth.users(id) ON DELETE SET NULL;
+
+CREATE INDEX IF NOT EXISTS idx_auth_users_deleted_at
+    ON auth.users (deleted_at)
+    WHERE deleted_at IS NOT NULL;
+
+CREATE INDEX IF NOT EXISTS idx_auth_groups_deleted_at
+    ON auth.groups (deleted_at)
+    WHERE deleted_at IS NOT NULL;
@@ -0,0 +1,20 @@
+-- Display name and IANA time zone as columns, so users can be sorted and searched by name without
+-- reading `details`. Values apps kept in `details` under `display_name`, `locale` and `timezone`
+-- are copied over where the column is still empty; `details` itself is left alone.
+ALTER TABLE auth.users
+    ADD COLUMN IF NOT EXISTS display_name TEXT,
+    ADD COLUMN IF NOT EXISTS timezone TEXT;
+
+UPDATE auth.users
+SET display_name = COALESCE(display_name, NULLIF(btrim(details ->> 'display_name'), '')),
+    locale = COALESCE(locale, NULLIF(btrim(details ->> 'locale'), '')),
+    timezone = COALESCE(
+        timezone,
+        (SELECT name FROM pg_timezone_names WHERE name = details ->> 'timezone')
+    )
+WHERE jsonb_typeof(details) = 'object'
+  AND details ?| ARRAY['display_name', 'locale', 'timezone'];
+
+CREATE INDEX IF NOT EXISTS idx_auth_users_display_name
+    ON auth.users (lower(display_name))
+    WHERE display_name IS NOT NULL;
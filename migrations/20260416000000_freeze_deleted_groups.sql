@@ -0,0 +1,62 @@
+-- Soft-deleted groups are frozen like archived ones until they are restored, so restoring brings
+-- back exactly what was deleted. Writes raise SQLSTATE SGD01, which `recycle_bin::is_group_deleted`
+-- recognizes. Rows deleted by a cascade, e.g. from purging the group, are still let through.
+CREATE OR REPLACE FUNCTION auth.reject_archived_group_change()
+RETURNS TRIGGER
+LANGUAGE plpgsql
+AS $$
+DECLARE
+    changed JSONB;
+    archived BOOLEAN;
+    deleted BOOLEAN;
+BEGIN
+    IF TG_OP = 'DELETE' THEN
+        changed := to_jsonb(OLD);
+    ELSE
+        changed := to_jsonb(NEW);
+    END IF;
+    IF pg_trigger_depth() = 1 THEN
+        SELECT archived_at IS NOT NULL, deleted_at IS NOT NULL
+        INTO archived, deleted
+        FROM auth.groups
+        WHERE id = (changed ->> 'group_id')::UUID;
+        IF deleted THEN
+            RAISE EXCEPTION 'group % is deleted', changed ->> 'group_id'
+                USING ERRCODE = 'SGD01';
+        END IF;
+        IF archived THEN
+            RAISE EXCEPTION 'group % is archived', changed ->> 'group_id'
+                USING ERRCODE = 'SGA01';
+        END IF;
+    END IF;
+    IF TG_OP = 'DELETE' THEN
+        RETURN OLD;
+    END IF;
+    RETURN NEW;
+END;
+$$;
+
+CREATE OR REPLACE FUNCTION auth.reject_deleted_group_update()
+RETURNS TRIGGER
+LANGUAGE plpgsql
+AS $$
+BEGIN
+    RAISE EXCEPTION 'group % is deleted', OLD.id USING ERRCODE = 'SGD01';
+END;
+$$;
+
+-- Restoring and purging are allowed.
+DROP TRIGGER IF EXISTS reject_deleted_group_update ON auth.groups;
+CREATE TRIGGER reject_deleted_group_update
+    BEFORE UPDATE ON auth.groups
+    FOR EACH ROW
+    WHEN (
+        OLD.deleted_at IS NOT NULL
+        AND NEW.deleted_at IS NOT NULL
+        AND (OLD.display_name, OLD.details, OLD.version, OLD.visibility, OLD.external_id,
+             OLD.archived_at)
+            IS DISTINCT FROM
+            (NEW.display_name, NEW.details, NEW.version, NEW.visibility, NEW.external_id,
+             NEW.archived_at)
+    )
+    EXECUTE FUNCTION auth.reject_deleted_group_update();
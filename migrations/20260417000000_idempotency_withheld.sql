@@ -0,0 +1,4 @@
+-- Responses that must not be stored, e.g. ones carrying a newly issued secret, complete their key
+-- without a body. A retry learns that the request succeeded but not what it returned.
+ALTER TABLE auth.idempotency_keys
+    ADD COLUMN IF NOT EXISTS withheld BOOLEAN NOT NULL DEFAULT FALSE;
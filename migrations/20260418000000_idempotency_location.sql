@@ -0,0 +1,4 @@
+-- `Location` of stored responses, replayed with them so a retried create still points at what it
+-- created.
+ALTER TABLE auth.idempotency_keys
+    ADD COLUMN IF NOT EXISTS location TEXT;
@@ -0,0 +1,39 @@
+-- Serialize partition creation. Two callers could both see the partition missing and race on
+-- `CREATE TABLE`, or on moving rows out of `auth.log_default`; the lock is held until the caller's
+-- transaction ends, and the existence check runs again once it is taken.
+CREATE OR REPLACE FUNCTION auth.create_log_partition(month_start TIMESTAMP)
+RETURNS TEXT
+LANGUAGE plpgsql
+AS $$
+DECLARE
+    range_start TIMESTAMP := date_trunc('month', month_start);
+    range_end TIMESTAMP := date_trunc('month', month_start) + INTERVAL '1 month';
+    partition_name TEXT := 'log_' || to_char(date_trunc('month', month_start), '"y"YYYY"m"MM');
+BEGIN
+    IF to_regclass('auth.' || partition_name) IS NOT NULL THEN
+        RETURN partition_name;
+    END IF;
+    PERFORM pg_advisory_xact_lock(hashtext('auth.create_log_partition'));
+    IF to_regclass('auth.' || partition_name) IS NOT NULL THEN
+        RETURN partition_name;
+    END IF;
+
+    EXECUTE format(
+        'CREATE TABLE auth.%I (LIKE auth.log INCLUDING DEFAULTS INCLUDING CONSTRAINTS)',
+        partition_name
+    );
+    EXECUTE format(
+        'INSERT INTO auth.%I SELECT * FROM auth.log_default WHERE timestamp >= $1 AND timestamp < $2',
+        partition_name
+    ) USING range_start, range_end;
+    DELETE FROM auth.log_default WHERE timestamp >= range_start AND timestamp < range_end;
+    EXECUTE format(
+        'ALTER TABLE auth.log ATTACH PARTITION auth.%I FOR VALUES FROM (%L) TO (%L)',
+        partition_name,
+        range_start,
+        range_end
+    );
+
+    RETURN partition_name;
+END;
+$$;
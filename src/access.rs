@@ -0,0 +1,249 @@
+use std::collections::BTreeMap;
+
+use chrono::NaiveDateTime;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sqlx::{FromRow, PgPool};
+use uuid::Uuid;
+
+use crate::db::{RoleAssignmentTarget, RoleEffect};
+use crate::group_id::GroupId;
+
+/// A grant held by the principal, directly or inherited through a group.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, FromRow)]
+pub struct SnapshotGrant {
+    /// The group the grant is inherited from; `None` if the principal holds it directly.
+    pub via_group_id: Option<GroupId>,
+    pub scope: String,
+    pub scope_id: String,
+    pub role_name: String,
+    pub effect: RoleEffect,
+    pub condition: Option<Value>,
+}
+
+impl SnapshotGrant {
+    fn key(&self) -> (Option<GroupId>, &str, &str, &str) {
+        (
+            self.via_group_id,
+            &self.scope,
+            &self.scope_id,
+            &self.role_name,
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, FromRow)]
+pub struct SnapshotMembership {
+    pub group_id: GroupId,
+    pub display_name: String,
+    pub role_name: String,
+}
+
+/// Canonical view of everything a user or group can do: group memberships and every grant, in a
+/// stable order so two snapshots of the same access serialize identically.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AccessSnapshot {
+    /// `user` or `group`.
+    pub target_type: String,
+    pub target_id: Uuid,
+    pub taken_at: NaiveDateTime,
+    /// Always empty for groups.
+    pub memberships: Vec<SnapshotMembership>,
+    pub grants: Vec<SnapshotGrant>,
+}
+
+/// Snapshot a user's access, including grants inherited through groups, or a group's own grants.
+pub async fn access_snapshot(
+    pool: &PgPool,
+    principal: RoleAssignmentTarget,
+) -> Result<AccessSnapshot, sqlx::Error> {
+    let (target_type, target_id, memberships, mut grants) = match principal {
+        RoleAssignmentTarget::User(user_id) => {
+            let memberships = sqlx::query_as::<_, SnapshotMembership>(
+                r#"
+                SELECT gm.group_id, g.display_name, gm.role_name
+                FROM auth.group_memberships gm
+                JOIN auth.groups g
+                  ON g.id = gm.group_id
+                 AND g.deleted_at IS NULL
+                WHERE gm.user_id = $1
+                ORDER BY gm.group_id ASC
+                "#,
+            )
+            .bind(user_id)
+            .fetch_all(pool)
+            .await?;
+            let grants = sqlx::query_as::<_, SnapshotGrant>(
+                r#"
+                SELECT NULL::UUID AS via_group_id, scope, scope_id, role_name, effect, condition
+                FROM auth.user_roles
+                WHERE user_id = $1
+                UNION ALL
+                SELECT gr.group_id, gr.scope, gr.scope_id, gr.role_name, gr.effect, gr.condition
+                FROM auth.group_memberships gm
+                JOIN auth.groups g
+                  ON g.id = gm.group_id
+                 AND g.deleted_at IS NULL
+                JOIN auth.group_roles gr
+                  ON gr.group_id = gm.group_id
+                WHERE gm.user_id = $1
+                "#,
+            )
+            .bind(user_id)
+            .fetch_all(pool)
+            .await?;
+            ("user", user_id.0, memberships, grants)
+        }
+        RoleAssignmentTarget::Group(group_id) => {
+            let grants = sqlx::query_as::<_, SnapshotGrant>(
+                r#"
+                SELECT NULL::UUID AS via_group_id, scope, scope_id, role_name, effect, condition
+                FROM auth.group_roles
+                WHERE group_id = $1
+                "#,
+            )
+            .bind(group_id)
+            .fetch_all(pool)
+            .await?;
+            ("group", group_id.0, Vec::new(), grants)
+        }
+    };
+    grants.sort_by(|a, b| a.key().cmp(&b.key()));
+
+    Ok(AccessSnapshot {
+        target_type: target_type.to_string(),
+        target_id,
+        taken_at: chrono::Utc::now().naive_utc(),
+        memberships,
+        grants,
+    })
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Change<T> {
+    pub before: T,
+    pub after: T,
+}
+
+/// What changed from one snapshot to another. Grants are matched by source group, scope, scope
+/// id and role; a changed effect or condition is a change rather than a removal and an addition.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct AccessDiff {
+    pub added_memberships: Vec<SnapshotMembership>,
+    pub removed_memberships: Vec<SnapshotMembership>,
+    pub changed_memberships: Vec<Change<SnapshotMembership>>,
+    pub added_grants: Vec<SnapshotGrant>,
+    pub removed_grants: Vec<SnapshotGrant>,
+    pub changed_grants: Vec<Change<SnapshotGrant>>,
+}
+
+impl AccessDiff {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+/// Compare two snapshots, e.g. of a user before and after a change, or a user against a template
+/// user. Who the snapshots belong to and when they were taken is ignored.
+pub fn diff(before: &AccessSnapshot, after: &AccessSnapshot) -> AccessDiff {
+    let (added_memberships, removed_memberships, changed_memberships) =
+        diff_by(&before.memberships, &after.memberships, |membership| {
+            membership.group_id
+        });
+    let (added_grants, removed_grants, changed_grants) =
+        diff_by(&before.grants, &after.grants, |grant| {
+            let (via, scope, scope_id, role_name) = grant.key();
+            (
+                via,
+                scope.to_string(),
+                scope_id.to_string(),
+                role_name.to_string(),
+            )
+        });
+
+    AccessDiff {
+        added_memberships,
+        removed_memberships,
+        changed_memberships,
+        added_grants,
+        removed_grants,
+        changed_grants,
+    }
+}
+
+type Diffed<T> = (Vec<T>, Vec<T>, Vec<Change<T>>);
+
+fn diff_by<T, K>(before: &[T], after: &[T], key: impl Fn(&T) -> K) -> Diffed<T>
+where
+    T: Clone + PartialEq,
+    K: Ord,
+{
+    let mut remaining: BTreeMap<K, &T> = before.iter().map(|item| (key(item), item)).collect();
+    let mut added = Vec::new();
+    let mut changed = Vec::new();
+    for item in after {
+        match remaining.remove(&key(item)) {
+            None => added.push(item.clone()),
+            Some(previous) if previous != item => changed.push(Change {
+                before: previous.clone(),
+                after: item.clone(),
+            }),
+            Some(_) => {}
+        }
+    }
+    let removed = remaining.into_values().cloned().collect();
+    (added, removed, changed)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use uuid::Uuid;
+
+    use super::{AccessSnapshot, SnapshotGrant, diff};
+    use crate::db::RoleEffect;
+
+    fn grant(scope_id: &str, role_name: &str, effect: RoleEffect) -> SnapshotGrant {
+        SnapshotGrant {
+            via_group_id: None,
+            scope: "proj".to_string(),
+            scope_id: scope_id.to_string(),
+            role_name: role_name.to_string(),
+            effect,
+            condition: None,
+        }
+    }
+
+    fn snapshot(grants: Vec<SnapshotGrant>) -> AccessSnapshot {
+        AccessSnapshot {
+            target_type: "user".to_string(),
+            target_id: Uuid::new_v4(),
+            taken_at: chrono::Utc::now().naive_utc(),
+            memberships: Vec::new(),
+            grants,
+        }
+    }
+
+    #[test]
+    fn diffs_grants_by_key() {
+        let before = snapshot(vec![
+            grant("p1", "viewer", RoleEffect::Allow),
+            grant("p2", "viewer", RoleEffect::Allow),
+            grant("p3", "editor", RoleEffect::Allow),
+        ]);
+        let mut conditional = grant("p3", "editor", RoleEffect::Allow);
+        conditional.condition = Some(json!({"mfa": true}));
+        let after = snapshot(vec![
+            grant("p1", "viewer", RoleEffect::Allow),
+            grant("p2", "viewer", RoleEffect::Deny),
+            conditional,
+            grant("p4", "viewer", RoleEffect::Allow),
+        ]);
+
+        let changes = diff(&before, &after);
+        assert_eq!(changes.added_grants.len(), 1);
+        assert!(changes.removed_grants.is_empty());
+        assert_eq!(changes.changed_grants.len(), 2);
+        assert!(diff(&before, &before).is_empty());
+    }
+}
@@ -0,0 +1,343 @@
+use std::sync::{Arc, RwLock};
+use std::task::{Context, Poll};
+
+use axum::extract::{FromRequestParts, Request};
+use axum::http::request::Parts;
+use axum::response::{IntoResponse, Response};
+use futures_util::future::{self, BoxFuture};
+use hyper::HeaderMap;
+use hyper::header::{AUTHORIZATION, HeaderValue, WWW_AUTHENTICATE};
+use jsonwebtoken::jwk::JwkSet;
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use serde::{Deserialize, Serialize};
+use tower::{Layer, Service};
+use uuid::Uuid;
+
+use crate::keys::KeyError;
+use crate::prelude::{AuthRejectReason, RejectReason};
+
+/// `typ` header of access tokens (RFC 9068).
+pub const ACCESS_TOKEN_TYPE: &str = "at+jwt";
+
+/// Claims of an access token (RFC 9068), signed with `typ` `at+jwt`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AccessTokenClaims {
+    pub iss: String,
+    pub sub: Uuid,
+    pub aud: String,
+    pub client_id: String,
+    /// Space-separated.
+    pub scope: String,
+    pub iat: i64,
+    pub exp: i64,
+    pub jti: Uuid,
+}
+
+impl AccessTokenClaims {
+    pub fn has_scope(&self, scope: &str) -> bool {
+        self.scope
+            .split_whitespace()
+            .any(|granted| granted == scope)
+    }
+}
+
+/// Checks access tokens against published signing keys, e.g. the issuer's
+/// `/auth/.well-known/jwks.json`. Clones share their keys, so `set_jwks` on one updates every
+/// layer built from it.
+#[derive(Debug, Clone)]
+pub struct AccessTokenVerifier {
+    jwks: Arc<RwLock<JwkSet>>,
+    issuer: Option<String>,
+    audience: Option<String>,
+}
+
+impl AccessTokenVerifier {
+    pub fn new(jwks: JwkSet) -> Self {
+        Self {
+            jwks: Arc::new(RwLock::new(jwks)),
+            issuer: None,
+            audience: None,
+        }
+    }
+
+    /// Only accept tokens whose `iss` is `issuer`.
+    pub fn with_issuer<S: Into<String>>(mut self, issuer: S) -> Self {
+        self.issuer = Some(issuer.into());
+        self
+    }
+
+    /// Only accept tokens whose `aud` is `audience`, e.g. the `client_id` of this service.
+    pub fn with_audience<S: Into<String>>(mut self, audience: S) -> Self {
+        self.audience = Some(audience.into());
+        self
+    }
+
+    /// Replace the keys, e.g. after the issuer rotated them.
+    pub fn set_jwks(&self, jwks: JwkSet) {
+        *self.jwks.write().unwrap_or_else(|err| err.into_inner()) = jwks;
+    }
+
+    /// The claims of `token`. ID tokens and other JWTs without the `at+jwt` type are refused.
+    pub fn verify(&self, token: &str) -> Result<AccessTokenClaims, KeyError> {
+        let header =
+            jsonwebtoken::decode_header(token).map_err(|err| KeyError::Invalid(err.to_string()))?;
+        if header.alg != Algorithm::EdDSA {
+            return Err(KeyError::Invalid(format!(
+                "unsupported algorithm {:?}",
+                header.alg
+            )));
+        }
+        let typ = header.typ.as_deref().unwrap_or_default();
+        if !typ.eq_ignore_ascii_case(ACCESS_TOKEN_TYPE)
+            && !typ.eq_ignore_ascii_case("application/at+jwt")
+        {
+            return Err(KeyError::Invalid("not an access token".to_string()));
+        }
+        let kid = header.kid.ok_or(KeyError::UnknownKey(None))?;
+        let key = {
+            let jwks = self.jwks.read().unwrap_or_else(|err| err.into_inner());
+            let jwk = jwks
+                .find(&kid)
+                .ok_or_else(|| KeyError::UnknownKey(Some(kid.clone())))?;
+            DecodingKey::from_jwk(jwk).map_err(|err| KeyError::Invalid(err.to_string()))?
+        };
+        let mut validation = Validation::new(Algorithm::EdDSA);
+        if let Some(issuer) = &self.issuer {
+            validation.set_issuer(&[issuer]);
+        }
+        match &self.audience {
+            Some(audience) => validation.set_audience(&[audience]),
+            None => validation.validate_aud = false,
+        }
+        jsonwebtoken::decode::<AccessTokenClaims>(token, &key, &validation)
+            .map(|data| data.claims)
+            .map_err(|err| KeyError::Invalid(err.to_string()))
+    }
+}
+
+/// Require a bearer access token carrying `scope` before a route runs.
+///
+/// Token scopes are what the user let the client do, independent of the user's roles; combine
+/// with `guard::require_role_layer` where both matter. Requests without a valid token get `401`,
+/// tokens without the scope get `403` with the `insufficient_scope` error body. The verified
+/// claims are inserted into request extensions for the `AccessToken` extractor.
+///
+/// ```ignore
+/// let users = Router::new()
+///     .route("/users", get(list_users))
+///     .layer(require_scope_layer(verifier.clone(), "users:read"));
+/// ```
+pub fn require_scope_layer(verifier: AccessTokenVerifier, scope: &str) -> RequireScopeLayer {
+    RequireScopeLayer::new(verifier, [scope])
+}
+
+/// Like `require_scope_layer`, but every one of `scopes` is needed.
+pub fn require_scopes_layer<I, R>(verifier: AccessTokenVerifier, scopes: I) -> RequireScopeLayer
+where
+    I: IntoIterator<Item = R>,
+    R: AsRef<str>,
+{
+    RequireScopeLayer::new(verifier, scopes)
+}
+
+#[derive(Clone)]
+pub struct RequireScopeLayer {
+    verifier: AccessTokenVerifier,
+    scopes: Arc<Vec<String>>,
+}
+
+impl RequireScopeLayer {
+    pub fn new<I, R>(verifier: AccessTokenVerifier, scopes: I) -> Self
+    where
+        I: IntoIterator<Item = R>,
+        R: AsRef<str>,
+    {
+        Self {
+            verifier,
+            scopes: Arc::new(
+                scopes
+                    .into_iter()
+                    .map(|scope| scope.as_ref().to_string())
+                    .collect(),
+            ),
+        }
+    }
+}
+
+impl<Inner> Layer<Inner> for RequireScopeLayer {
+    type Service = RequireScope<Inner>;
+
+    fn layer(&self, inner: Inner) -> Self::Service {
+        RequireScope {
+            verifier: self.verifier.clone(),
+            scopes: self.scopes.clone(),
+            inner,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct RequireScope<Inner> {
+    verifier: AccessTokenVerifier,
+    scopes: Arc<Vec<String>>,
+    inner: Inner,
+}
+
+impl<Inner, B> Service<Request<B>> for RequireScope<Inner>
+where
+    Inner: Service<Request<B>, Response = Response> + Clone + Send + 'static,
+    Inner::Future: Send + 'static,
+    B: Send + 'static,
+{
+    type Response = Response;
+    type Error = Inner::Error;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: Request<B>) -> Self::Future {
+        let claims = match bearer_token(req.headers()) {
+            Some(token) => self.verifier.verify(token).map_err(Some),
+            None => Err(None),
+        };
+        let claims = match claims {
+            Ok(claims) => claims,
+            Err(err) => {
+                let response = unauthorized(err);
+                return Box::pin(async move { Ok(response) });
+            }
+        };
+        if let Some(missing) = self.scopes.iter().find(|scope| !claims.has_scope(scope)) {
+            tracing::info!(
+                "Rejecting token {} of client {} without scope {}",
+                claims.jti,
+                claims.client_id,
+                missing
+            );
+            let response = insufficient_scope(&self.scopes);
+            return Box::pin(async move { Ok(response) });
+        }
+
+        req.extensions_mut().insert(AccessToken(claims));
+        let clone = self.inner.clone();
+        // https://docs.rs/tower/latest/tower/trait.Service.html#be-careful-when-cloning-inner-services
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        Box::pin(async move { inner.call(req).await })
+    }
+}
+
+/// Claims of the access token accepted by `RequireScopeLayer`.
+#[derive(Debug, Clone)]
+pub struct AccessToken(pub AccessTokenClaims);
+
+impl<S> FromRequestParts<S> for AccessToken
+where
+    S: Send + Sync,
+{
+    type Rejection = RejectReason;
+
+    fn from_request_parts(
+        parts: &mut Parts,
+        _state: &S,
+    ) -> impl Future<Output = Result<Self, Self::Rejection>> + Send {
+        let token = parts
+            .extensions
+            .get::<AccessToken>()
+            .cloned()
+            .ok_or_else(|| RejectReason::auth(AuthRejectReason::no_session_token()));
+        future::ready(token)
+    }
+}
+
+fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Bearer ")
+        .map(str::trim)
+}
+
+/// `401` with the RFC 6750 challenge. `err` is `None` when no token was sent.
+fn unauthorized(err: Option<KeyError>) -> Response {
+    let (reason, challenge) = match err {
+        None => (AuthRejectReason::no_session_token(), "Bearer".to_string()),
+        Some(err) => (
+            AuthRejectReason::invalid_session_token(err.to_string()),
+            r#"Bearer error="invalid_token""#.to_string(),
+        ),
+    };
+    with_challenge(RejectReason::auth(reason).into_response(), &challenge)
+}
+
+fn insufficient_scope(scopes: &[String]) -> Response {
+    let challenge = format!(
+        r#"Bearer error="insufficient_scope", scope="{}""#,
+        scopes.join(" ")
+    );
+    with_challenge(
+        RejectReason::insufficient_scope(scopes.to_vec()).into_response(),
+        &challenge,
+    )
+}
+
+fn with_challenge(mut response: Response, challenge: &str) -> Response {
+    if let Ok(value) = HeaderValue::from_str(challenge) {
+        response.headers_mut().insert(WWW_AUTHENTICATE, value);
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AccessTokenClaims, AccessTokenVerifier};
+    use crate::keys::{KeyError, SigningKey};
+
+    #[test]
+    fn verifies_access_tokens_only() {
+        let pkcs8 =
+            ring::signature::Ed25519KeyPair::generate_pkcs8(&ring::rand::SystemRandom::new())
+                .unwrap();
+        let key = SigningKey::ed25519("k1", pkcs8.as_ref().to_vec()).unwrap();
+        let jwks = jsonwebtoken::jwk::JwkSet {
+            keys: vec![key.public_jwk().unwrap()],
+        };
+        let claims = AccessTokenClaims {
+            iss: "https://app.example.com/auth".to_string(),
+            sub: uuid::Uuid::new_v4(),
+            aud: "reports".to_string(),
+            client_id: "reports".to_string(),
+            scope: "openid users:read".to_string(),
+            iat: chrono::Utc::now().timestamp(),
+            exp: chrono::Utc::now().timestamp() + 60,
+            jti: uuid::Uuid::new_v4(),
+        };
+        let sign = |typ: &str| {
+            let mut header = jsonwebtoken::Header::new(jsonwebtoken::Algorithm::EdDSA);
+            header.typ = Some(typ.to_string());
+            header.kid = Some("k1".to_string());
+            let encoding = jsonwebtoken::EncodingKey::from_ed_der(pkcs8.as_ref());
+            jsonwebtoken::encode(&header, &claims, &encoding).unwrap()
+        };
+
+        let verifier = AccessTokenVerifier::new(jwks)
+            .with_issuer("https://app.example.com/auth")
+            .with_audience("reports");
+        let verified = verifier.verify(&sign("at+jwt")).unwrap();
+        assert!(verified.has_scope("users:read"));
+        assert!(!verified.has_scope("users:write"));
+        assert!(matches!(
+            verifier.verify(&sign("JWT")),
+            Err(KeyError::Invalid(_))
+        ));
+        assert!(matches!(
+            verifier
+                .clone()
+                .with_audience("billing")
+                .verify(&sign("at+jwt")),
+            Err(KeyError::Invalid(_))
+        ));
+    }
+}
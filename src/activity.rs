@@ -0,0 +1,381 @@
+//! The user's own account activity behind `GET /auth/me/activity`: logins, profile and security
+//! changes, and changes to their roles and memberships, newest first.
+//!
+//! Unlike the audit log it reads from, events are picked from an allowlist and rewritten into
+//! typed entries. Actions the user took as an administrator on others are left out, and events
+//! written by an administrator say only that an administrator acted, not who, or why.
+
+use chrono::NaiveDateTime;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sqlx::{FromRow, PgPool};
+use uuid::Uuid;
+
+use crate::group_id::GroupId;
+use crate::user_id::UserId;
+
+/// Events that are always about the user who wrote them.
+const OWN_EVENTS: &[&str] = &[
+    "login_failed",
+    "password_set",
+    "username_changed",
+    "notification_preferences_updated",
+    "remembered_session_revoked",
+    "remembered_sessions_revoked",
+    "oauth_consent_granted",
+    "oauth_consent_revoked",
+    "invitation_accepted",
+    "user_approved",
+];
+
+/// Events about the user named in their `user_id`.
+const SUBJECT_EVENTS: &[&str] = &[
+    "group_join",
+    "group_leave",
+    "group_member_role_changed",
+    "group_member_capabilities_changed",
+    "user_suspended",
+    "user_unsuspended",
+];
+
+/// Events about the user named in their `target_id`.
+const ROLE_EVENTS: &[&str] = &["role_grant", "role_deny", "role_revoke"];
+
+/// Who caused an event, from the user's point of view.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Actor {
+    You,
+    Admin,
+    /// A scheduled job or directory sync.
+    System,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Activity {
+    Login {
+        method: String,
+        ip: Option<String>,
+        user_agent: Option<String>,
+        /// The first login from this user agent, after an earlier one from another.
+        new_device: bool,
+    },
+    LoginFailed {
+        method: Option<String>,
+    },
+    PasswordChanged,
+    UsernameChanged {
+        from: Option<String>,
+        to: Option<String>,
+    },
+    NotificationPreferencesChanged,
+    RoleGranted {
+        scope: String,
+        scope_id: String,
+        role_name: String,
+    },
+    RoleDenied {
+        scope: String,
+        scope_id: String,
+        role_name: String,
+    },
+    RoleRevoked {
+        scope: String,
+        scope_id: String,
+        role_name: String,
+    },
+    GroupJoined {
+        group_id: GroupId,
+        role_name: Option<String>,
+    },
+    GroupLeft {
+        group_id: GroupId,
+    },
+    GroupRoleChanged {
+        group_id: GroupId,
+        role_name: Option<String>,
+    },
+    /// Capabilities delegated to the user on top of their group role.
+    GroupCapabilitiesChanged {
+        group_id: GroupId,
+        capabilities: Vec<String>,
+    },
+    SessionsRevoked {
+        count: i64,
+    },
+    AppAuthorized {
+        client_id: String,
+        scopes: Vec<String>,
+    },
+    AppAuthorizationRevoked {
+        client_id: String,
+        scopes: Vec<String>,
+    },
+    Suspended {
+        ends_at: Option<NaiveDateTime>,
+    },
+    Unsuspended,
+    AccountApproved,
+    InvitationAccepted,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ActivityEvent {
+    pub id: Uuid,
+    pub occurred_at: NaiveDateTime,
+    pub actor: Actor,
+    #[serde(flatten)]
+    pub activity: Activity,
+}
+
+#[derive(FromRow)]
+struct ActivityRow {
+    id: Uuid,
+    user_id: Option<UserId>,
+    action: Value,
+    occurred_at: NaiveDateTime,
+}
+
+/// Up to `limit` of the user's events after skipping `offset`, newest first.
+pub async fn activity_for_user(
+    pool: &PgPool,
+    user_id: UserId,
+    limit: i64,
+    offset: i64,
+) -> Result<Vec<ActivityEvent>, sqlx::Error> {
+    let rows = sqlx::query_as::<_, ActivityRow>(
+        r#"
+        SELECT id, user_id, action, timestamp AS occurred_at
+        FROM auth.log
+        WHERE user_id = $1
+          AND action->>'type' = ANY($2)
+        UNION ALL
+        SELECT id, user_id, action, timestamp
+        FROM auth.log
+        WHERE action ? 'user_id'
+          AND action->>'user_id' = $1::TEXT
+          AND action->>'type' = ANY($3)
+        UNION ALL
+        SELECT id, user_id, action, timestamp
+        FROM auth.log
+        WHERE action->>'target_type' = 'user'
+          AND action->>'target_id' = $1::TEXT
+          AND action->>'type' = ANY($4)
+        UNION ALL
+        SELECT
+            id,
+            user_id,
+            jsonb_build_object(
+                'type', 'login',
+                'method', method,
+                'ip', ip,
+                'user_agent', user_agent,
+                'new_device',
+                    row_number() OVER (ORDER BY created_at, id) > 1
+                    AND row_number() OVER (PARTITION BY user_agent ORDER BY created_at, id) = 1
+            ),
+            created_at
+        FROM auth.login_history
+        WHERE user_id = $1
+        ORDER BY occurred_at DESC, id DESC
+        LIMIT $5 OFFSET $6
+        "#,
+    )
+    .bind(user_id)
+    .bind(OWN_EVENTS)
+    .bind(SUBJECT_EVENTS)
+    .bind(ROLE_EVENTS)
+    .bind(limit)
+    .bind(offset)
+    .fetch_all(pool)
+    .await?;
+    Ok(rows
+        .into_iter()
+        .filter_map(|row| ActivityEvent::from_row(user_id, row))
+        .collect())
+}
+
+impl ActivityEvent {
+    fn from_row(viewer: UserId, row: ActivityRow) -> Option<Self> {
+        let action = &row.action;
+        let text = |key: &str| action.get(key).and_then(Value::as_str).map(str::to_string);
+        let group_id = || text("group_id")?.parse().ok().map(GroupId);
+        let names = |key: &str| {
+            action
+                .get(key)
+                .and_then(Value::as_array)
+                .map(|values| {
+                    values
+                        .iter()
+                        .filter_map(|name| name.as_str().map(str::to_string))
+                        .collect()
+                })
+                .unwrap_or_default()
+        };
+        let activity = match action.get("type").and_then(Value::as_str)? {
+            "login" => Activity::Login {
+                method: text("method")?,
+                ip: text("ip"),
+                user_agent: text("user_agent"),
+                new_device: action
+                    .get("new_device")
+                    .and_then(Value::as_bool)
+                    .unwrap_or(false),
+            },
+            "login_failed" => Activity::LoginFailed {
+                method: text("method"),
+            },
+            "password_set" => Activity::PasswordChanged,
+            "username_changed" => Activity::UsernameChanged {
+                from: text("from"),
+                to: text("to"),
+            },
+            "notification_preferences_updated" => Activity::NotificationPreferencesChanged,
+            kind @ ("role_grant" | "role_deny" | "role_revoke") => {
+                let (scope, scope_id, role_name) =
+                    (text("scope")?, text("scope_id")?, text("role_name")?);
+                match kind {
+                    "role_grant" => Activity::RoleGranted {
+                        scope,
+                        scope_id,
+                        role_name,
+                    },
+                    "role_deny" => Activity::RoleDenied {
+                        scope,
+                        scope_id,
+                        role_name,
+                    },
+                    _ => Activity::RoleRevoked {
+                        scope,
+                        scope_id,
+                        role_name,
+                    },
+                }
+            }
+            "group_join" => Activity::GroupJoined {
+                group_id: group_id()?,
+                role_name: text("role_name"),
+            },
+            "group_leave" => Activity::GroupLeft {
+                group_id: group_id()?,
+            },
+            "group_member_role_changed" => Activity::GroupRoleChanged {
+                group_id: group_id()?,
+                role_name: text("role_name"),
+            },
+            "group_member_capabilities_changed" => Activity::GroupCapabilitiesChanged {
+                group_id: group_id()?,
+                capabilities: names("capabilities"),
+            },
+            "remembered_session_revoked" => Activity::SessionsRevoked { count: 1 },
+            "remembered_sessions_revoked" => Activity::SessionsRevoked {
+                count: action.get("count").and_then(Value::as_i64).unwrap_or(0),
+            },
+            "oauth_consent_granted" => Activity::AppAuthorized {
+                client_id: text("client_id")?,
+                scopes: names("scopes"),
+            },
+            "oauth_consent_revoked" => Activity::AppAuthorizationRevoked {
+                client_id: text("client_id")?,
+                scopes: names("scopes"),
+            },
+            "user_suspended" => Activity::Suspended {
+                ends_at: action
+                    .get("ends_at")
+                    .cloned()
+                    .and_then(|ends_at| serde_json::from_value(ends_at).ok()),
+            },
+            "user_unsuspended" => Activity::Unsuspended,
+            "user_approved" => Activity::AccountApproved,
+            "invitation_accepted" => Activity::InvitationAccepted,
+            _ => return None,
+        };
+
+        // Role and approval events name the actor; the rest were written by them.
+        let actor_id = match action.get("actor_user_id") {
+            Some(actor) => actor.as_str().and_then(|id| id.parse().ok()).map(UserId),
+            None => row.user_id,
+        };
+        let actor = match actor_id {
+            Some(actor_id) if actor_id == viewer => Actor::You,
+            Some(_) => Actor::Admin,
+            None => Actor::System,
+        };
+        let actor = match activity {
+            // Logins and failed logins are the user's, whatever wrote them.
+            Activity::Login { .. } | Activity::LoginFailed { .. } => Actor::You,
+            _ => actor,
+        };
+        Some(Self {
+            id: row.id,
+            occurred_at: row.occurred_at,
+            actor,
+            activity,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::{Activity, ActivityEvent, ActivityRow, Actor};
+    use crate::user_id::UserId;
+
+    fn row(user_id: Option<UserId>, action: serde_json::Value) -> ActivityRow {
+        ActivityRow {
+            id: uuid::Uuid::nil(),
+            user_id,
+            action,
+            occurred_at: chrono::DateTime::UNIX_EPOCH.naive_utc(),
+        }
+    }
+
+    #[test]
+    fn hides_who_acted_and_why() {
+        let viewer = UserId(uuid::Uuid::new_v4());
+        let admin = UserId(uuid::Uuid::new_v4());
+        let suspended = ActivityEvent::from_row(
+            viewer,
+            row(
+                Some(admin),
+                json!({
+                    "type": "user_suspended",
+                    "user_id": viewer.to_string(),
+                    "reason": "spam reports from #4412",
+                    "ends_at": "2026-05-01T00:00:00",
+                }),
+            ),
+        )
+        .unwrap();
+        assert_eq!(suspended.actor, Actor::Admin);
+        let serialized = serde_json::to_value(&suspended).unwrap();
+        assert_eq!(serialized["kind"], "suspended");
+        assert_eq!(serialized["actor"], "admin");
+        assert!(!serialized.to_string().contains("spam"));
+        assert!(!serialized.to_string().contains(&admin.to_string()));
+
+        let granted = ActivityEvent::from_row(
+            viewer,
+            row(
+                Some(admin),
+                json!({
+                    "type": "role_grant",
+                    "actor_user_id": null,
+                    "target_type": "user",
+                    "target_id": viewer.to_string(),
+                    "scope": "project",
+                    "scope_id": "p1",
+                    "role_name": "editor",
+                }),
+            ),
+        )
+        .unwrap();
+        assert_eq!(granted.actor, Actor::System);
+        assert!(matches!(granted.activity, Activity::RoleGranted { .. }));
+
+        let unknown = row(Some(viewer), json!({"type": "client_registered"}));
+        assert!(ActivityEvent::from_row(viewer, unknown).is_none());
+    }
+}
@@ -1,27 +1,208 @@
+pub mod v2;
+
+use std::collections::{BTreeMap, HashMap};
+use std::convert::Infallible;
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
 use std::sync::Arc;
 
+use crate::access::{AccessSnapshot, access_snapshot, diff};
+use crate::activity::activity_for_user;
+use crate::archival::{
+    ArchivedFilter, GroupArchival, archive_group, group_archival, unarchive_group,
+};
+use crate::auth::sync_login_claims;
+use crate::auth_context::AuthContext;
+use crate::bootstrap::accept_bootstrap_invitation;
+use crate::breaker::ProviderHealth;
+use crate::bundle::{
+    BundleGrant, RoleBundle, apply_bundle, delete_bundle, get_bundle, list_bundles, save_bundle,
+};
+use crate::captcha::{CaptchaVerifier, verify_captcha};
+use crate::claims::ClaimsMapper;
+use crate::clients::{
+    Client, ClientError, ClientRateLimiter, ClientUpdate, DEFAULT_CLIENT_RATE_LIMITER, NewClient,
+    register_client,
+};
+use crate::config::{RegistrationMode, SessionConfig};
+use crate::deactivation::{
+    Deactivation, DeactivationReason, ReactivationError, deactivate_group, deactivate_user,
+    group_deactivation, reactivate_group, reactivate_user, user_deactivation,
+};
+use crate::email::{
+    DEFAULT_EMAIL_DOMAIN_POLICY, DEFAULT_EMAIL_NORMALIZER, EmailDomainPolicy, EmailNormalizer,
+};
+use crate::environment::{is_valid_environment_name, pinned_scope, validate_scope};
+use crate::external_id::{DEFAULT_EXTERNAL_ID_GENERATOR, ExternalIdGenerator};
+use crate::fields::{FieldMask, FieldMaskError};
+use crate::guard::{invalidate_all_role_snapshots, invalidate_role_snapshots};
+use crate::hooks::{DeactivationChange, Hooks, MembershipChange, NewUser, NoHooks, RoleChange};
+use crate::i18n::{DEFAULT_TRANSLATIONS, LOCALE_SESSION_KEY, Translations, parse_locale};
+use crate::integrity;
+use crate::invitations::{
+    DEFAULT_INVITATION_TTL, InvitationRow, InvitationStatus, SkippedInvitation, create_invitations,
+};
+use crate::json_patch::{apply_json_patch, apply_merge_patch, parse_json_patch};
+use crate::keys::{KeyRing, log_rotation};
+use crate::membership_sync::{
+    MemberRole, MembershipDiff, MembershipSyncError, preview_membership_sync, sync_group_members,
+};
+use crate::moderation::{
+    AbuseReport, EvidenceCollector, NewEvidence, NewReport, ReportEvidence, ReportStatus,
+    ReportSubject, ReportTransitionError, attach_evidence, file_report, get_report,
+    moderation_queue, report_evidence, transition_report,
+};
+#[cfg(feature = "mtls")]
+use crate::mtls::ClientCertPolicy;
+use crate::notify::{
+    AccountInvitationContext, Notification, Notifier, Recipient, SECURITY_NOTIFICATIONS,
+    notification_preferences, set_notification_preferences,
+};
+#[cfg(feature = "password-hashing")]
+use crate::notify::{SecurityEvent, notify_security_event};
+#[cfg(feature = "oauth-server")]
+use crate::oauth_server::{
+    ProviderMetadata, authorizations_handler, authorize_handler, consent_handler,
+    revoke_authorization_handler, token_handler, userinfo_handler,
+};
+use crate::password::{BreachChecker, DEFAULT_PASSWORD_POLICY, PasswordPolicy};
+#[cfg(feature = "password-hashing")]
+use crate::password::{DEFAULT_PASSWORD_HASHER, PasswordHasher, PasswordStrength};
+use crate::permission_changes::permission_changes;
+use crate::permission_tokens::{
+    DEFAULT_PERMISSION_TOKEN_POLICY, PermissionTokenError, PermissionTokenPolicy, RevokeTarget,
+    issue_permission_token, revoke_permission_tokens, revoked_permission_tokens,
+};
+use crate::policy::{GrantCondition, RequestContext};
+#[cfg(feature = "password-hashing")]
+use crate::prelude::AuthRejectReason;
 use crate::prelude::{AuthenticatedUser, GroupId, RejectReason, UserId, ValidatesIdentity};
-use axum::extract::{Query, State};
-use axum::response::IntoResponse;
-use axum::routing::{get, post};
-use axum::{Json, Router};
-use cookie::SameSite;
-use hyper::StatusCode;
+use crate::provisioning::{
+    DEFAULT_PROVISIONING_POLICY, ProvisioningMode, ProvisioningPolicy, approve_user,
+    provision_invited_user_in, provision_user,
+};
+use crate::recycle_bin::{
+    DeletedGroup, DeletedUser, delete_group, delete_user, deleted_group, deleted_groups,
+    deleted_users, restore_group, restore_user,
+};
+use crate::redact::Sensitive;
+use crate::remember::RememberedSession;
+use crate::reports::{Bucket, Report, ReportRange};
+use crate::stats::{refresh_daily_stats, stats};
+use crate::suspension::{
+    NewSuspension, Suspension, impose_suspension, lift_suspension, suspensions_for_user,
+};
+use crate::updates::{UserUpdate, UserUpdates};
+use crate::username::{DEFAULT_USERNAME_POLICY, UsernamePolicy};
+use axum::extract::{FromRef, FromRequestParts, Path, Query, State};
+use axum::http::request::Parts;
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{delete, get, patch, post, put};
+use axum::{Extension, Json, Router};
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use futures_util::stream::{self, Stream, StreamExt};
+use hyper::header::{CACHE_CONTROL, CONTENT_TYPE, ETAG, IF_MATCH, IF_NONE_MATCH};
+use hyper::{HeaderMap, StatusCode, Uri};
 use serde::{Deserialize, Serialize};
 use serde_json::{Value, json};
-use time::Duration;
-use tower_sessions::{Expiry, MemoryStore, SessionManagerLayer};
+use sha2::{Digest, Sha256};
+use tower_sessions::session::Id;
+use tower_sessions::session_store::SessionStore;
+use tower_sessions::{Expiry, MemoryStore, Session, SessionManagerLayer};
 
+#[cfg(feature = "password-hashing")]
+use crate::db::UserPasswordRow;
 use crate::db::{
-    AccessRoleRow, GLOBAL_SCOPE, GLOBAL_SCOPE_ID, GroupMembershipRow, GroupRoleRow, GroupRow,
-    LogRow, RoleAssignmentTarget, SUPER_ADMIN_ROLE, UserRoleRow, UserRow,
-    can_manage_role_assignment, grant_role_assignment_with_audit, is_super_admin,
-    revoke_role_assignment_with_audit, user_is_group_admin_for_scope,
+    AccessRoleRow, AppliedMigration, BulkReport, DEFAULT_USERNAME_HOLD_DOWN, ExternalIdChangeError,
+    GLOBAL_SCOPE, GLOBAL_SCOPE_ID, GROUP_ADMIN_ROLE, GROUP_MEMBER_ROLE, GroupCapabilities,
+    GroupDefaultRoleRow, GroupJoinRequestRow, GroupMembershipRow, GroupRoleDefinitionRow,
+    GroupRoleRow, GroupRow, GroupVisibility, LastLogin, LogRow, LoginHistoryRow, MigrationInfo,
+    ProfileUpdate, RoleAssignmentTarget, RoleEffect, SUPER_ADMIN_ROLE, UserRoleRow, UserRow,
+    UsernameChangeError, UsernameHistoryRow, VersionConflictError, applied_migrations,
+    can_manage_role_assignment, deactivate_dormant_report, explain_effective_access,
+    group_capabilities, health_check, is_known_timezone, is_super_admin, is_valid_scope_id_pattern,
+    pending_migrations, revoke_role_assignment_with_audit, set_role_assignment_with_audit,
+    user_is_group_admin_for_scope,
 };
+#[cfg(feature = "password-hashing")]
+use crate::step_up::record_reauthentication;
 
 /// Provides access to the database connection pool.
 pub trait HasPool {
+    /// Primary pool. All writes, and reads that must observe them, go here.
     fn pool(&self) -> Arc<sqlx::PgPool>;
+
+    /// Pool for read-only queries, e.g. a read replica. Defaults to the primary.
+    ///
+    /// Reads served from here may lag behind writes by the replication delay. Authorization checks,
+    /// e.g. for super_admin, read from `pool` instead, so revoked access stops working at once.
+    fn reader_pool(&self) -> Arc<sqlx::PgPool> {
+        self.pool()
+    }
+}
+
+/// Reads and writes the auth schema: users, groups, roles and the rows kept with them. Every
+/// `HasPool` has it; handlers that need nothing else are bound on it alone, so they can be
+/// mounted on a state that is not an `AuthApp`.
+pub trait HasStore: HasPool {}
+
+impl<T: HasPool> HasStore for T {}
+
+/// Authenticates requests from bearer tokens and session cookies: what `auth::AuthLayer` needs.
+/// Every `ValidatesIdentity` has it.
+pub trait HasSessions: ValidatesIdentity {}
+
+impl<T: ValidatesIdentity> HasSessions for T {}
+
+/// Signs the tokens this service issues. Every `AuthApp` has it through the hooks of the same
+/// names; a state that is not one implements it directly to serve the key and permission token
+/// handlers.
+pub trait HasKeys {
+    /// See `AuthApp::key_ring`.
+    fn key_ring(&self) -> Option<&KeyRing>;
+
+    /// See `AuthApp::token_issuer`.
+    fn token_issuer(&self) -> Option<&str> {
+        None
+    }
+
+    /// See `AuthApp::permission_token_policy`.
+    fn permission_token_policy(&self) -> &PermissionTokenPolicy {
+        &DEFAULT_PERMISSION_TOKEN_POLICY
+    }
+}
+
+/// Runs the host app's lifecycle callbacks. Every `AuthApp` has it through `AuthApp::hooks`; a
+/// state that is not one implements it, usually as `impl HasHooks for AppState {}`, to serve the
+/// role and group deactivation handlers.
+pub trait HasHooks {
+    /// See `AuthApp::hooks`.
+    fn hooks(&self) -> &dyn Hooks {
+        &NoHooks
+    }
+}
+
+impl<T: AuthApp> HasHooks for T {
+    fn hooks(&self) -> &dyn Hooks {
+        AuthApp::hooks(self)
+    }
+}
+
+impl<T: AuthApp> HasKeys for T {
+    fn key_ring(&self) -> Option<&KeyRing> {
+        AuthApp::key_ring(self)
+    }
+
+    fn token_issuer(&self) -> Option<&str> {
+        AuthApp::token_issuer(self)
+    }
+
+    fn permission_token_policy(&self) -> &PermissionTokenPolicy {
+        AuthApp::permission_token_policy(self)
+    }
 }
 
 /// Announces user-related events to the application.
@@ -38,25 +219,458 @@ pub trait AnnouncesUserEvents {
     fn announce_user_group_leave(&self, user_id: UserId, group_id: GroupId);
 }
 
-pub trait AuthApp: ValidatesIdentity + HasPool + AnnouncesUserEvents {}
+/// Host-app validation of JSON details documents, e.g. against a JSON Schema.
+pub trait DetailsValidator: Send + Sync {
+    /// Return the reason `details` is rejected for the group.
+    fn validate_group_details(&self, group_id: GroupId, details: &Value) -> Result<(), String>;
+}
 
-#[derive(Debug, Clone, Serialize)]
+pub trait AuthApp: ValidatesIdentity + HasPool + AnnouncesUserEvents {
+    /// Username rules applied when user records are created.
+    fn username_policy(&self) -> &UsernamePolicy {
+        &DEFAULT_USERNAME_POLICY
+    }
+
+    /// Email canonicalization used for uniqueness and lookups.
+    fn email_normalizer(&self) -> &EmailNormalizer {
+        &DEFAULT_EMAIL_NORMALIZER
+    }
+
+    /// Generates the `external_id` of user records created by `/auth/me`.
+    fn external_id_generator(&self) -> &ExternalIdGenerator {
+        &DEFAULT_EXTERNAL_ID_GENERATOR
+    }
+
+    /// CAPTCHA checked before `/auth/me` creates a user record and before
+    /// `self_reauthenticate_handler` checks a password. Existing users are not otherwise challenged.
+    fn captcha_verifier(&self) -> Option<&dyn CaptchaVerifier> {
+        None
+    }
+
+    /// Delivery for messages to users, e.g. new-device sign-in alerts. `None` disables them.
+    fn notifier(&self) -> Option<&dyn Notifier> {
+        None
+    }
+
+    /// Localized text for error responses and notifications.
+    fn translations(&self) -> &dyn Translations {
+        &*DEFAULT_TRANSLATIONS
+    }
+
+    /// Email domains allowed to create accounts.
+    fn email_domain_policy(&self) -> &EmailDomainPolicy {
+        &DEFAULT_EMAIL_DOMAIN_POLICY
+    }
+
+    /// Rules for local passwords, enforced by `set_user_password` and `/auth/me/password`.
+    fn password_policy(&self) -> &PasswordPolicy {
+        &DEFAULT_PASSWORD_POLICY
+    }
+
+    /// Breach lookup used alongside `password_policy`. `None` skips the check.
+    fn breach_checker(&self) -> Option<&dyn BreachChecker> {
+        None
+    }
+
+    /// Scheme and parameters for new password hashes. Stored hashes of other schemes are
+    /// upgraded to it on the next successful `UserPasswordRow::verify`.
+    #[cfg(feature = "password-hashing")]
+    fn password_hasher(&self) -> &PasswordHasher {
+        &DEFAULT_PASSWORD_HASHER
+    }
+
+    /// How long a released username stays reserved for its previous owner.
+    fn username_hold_down(&self) -> chrono::Duration {
+        DEFAULT_USERNAME_HOLD_DOWN
+    }
+
+    /// Validation applied to patched group details before they are stored.
+    fn details_validator(&self) -> Option<&dyn DetailsValidator> {
+        None
+    }
+
+    /// Adds the app's own evidence to abuse reports filed through `/auth/reports`.
+    fn evidence_collector(&self) -> Option<&dyn EvidenceCollector> {
+        None
+    }
+
+    /// Callbacks before and after user creation, role changes, group joins and leaves, and
+    /// deactivations, able to veto them.
+    fn hooks(&self) -> &dyn Hooks {
+        &NoHooks
+    }
+
+    /// Signing keys rotated by `/auth/admin/keys/rotate`. `None` disables the endpoint.
+    fn key_ring(&self) -> Option<&KeyRing> {
+        None
+    }
+
+    /// Source of the `/auth/me/updates` event stream. `None` disables the endpoint.
+    fn user_updates(&self) -> Option<&UserUpdates> {
+        None
+    }
+
+    /// Where `mtls::ServicePrincipal` finds client certificates. `None` refuses every request
+    /// that extracts one.
+    #[cfg(feature = "mtls")]
+    fn client_cert_policy(&self) -> Option<&ClientCertPolicy> {
+        None
+    }
+
+    /// Maps identity provider claims into roles and memberships when `/auth/me` creates the user.
+    /// Pass the same mapper to `auth_with_login_tracking` to keep them in sync on every login.
+    fn claims_mapper(&self) -> Option<&dyn ClaimsMapper> {
+        None
+    }
+
+    /// Whether `/auth/me` creates users on first sign-in, for which email domains, and with
+    /// which default roles and groups.
+    fn provisioning_policy(&self) -> &ProvisioningPolicy {
+        &DEFAULT_PROVISIONING_POLICY
+    }
+
+    /// Link emailed with invitations from `/auth/admin/invitations`, e.g.
+    /// `https://app.example.com/invite?token=...`. Without it, or without a `notifier`, tokens are
+    /// returned to the admin to deliver some other way.
+    fn invitation_accept_url(&self, _token: &str) -> Option<String> {
+        None
+    }
+
+    /// Health of the app's identity providers for `/auth/admin/identity-providers`, e.g.
+    /// `vec![self.idp.health()]`.
+    fn identity_provider_health(&self) -> Vec<ProviderHealth> {
+        Vec::new()
+    }
+
+    /// URL this service issues tokens as, e.g. `https://app.example.com/auth` when the routes are
+    /// served from the root of `app.example.com`. Sets `iss` on permission tokens and enables
+    /// `/auth/.well-known/openid-configuration`, which lives under the issuer as discovery
+    /// expects.
+    fn token_issuer(&self) -> Option<&str> {
+        None
+    }
+
+    /// Where `/auth/oauth/authorize` sends signed-out users. They should come back to `return_to`
+    /// once signed in.
+    #[cfg(feature = "oauth-server")]
+    fn oauth_login_url(&self, return_to: &str) -> String {
+        crate::oauth_server::default_login_url(return_to)
+    }
+
+    /// Page that asks the user to approve an untrusted client, given the query of the original
+    /// authorization request. The page posts the answer to `/auth/oauth/authorize`. `None`
+    /// answers `consent_required` to the client instead.
+    #[cfg(feature = "oauth-server")]
+    fn oauth_consent_url(&self, _client: &Client, _request_query: &str) -> Option<String> {
+        None
+    }
+
+    /// Lifetime of access and ID tokens from `/auth/oauth/token`.
+    #[cfg(feature = "oauth-server")]
+    fn oauth_access_token_ttl(&self) -> chrono::Duration {
+        crate::oauth_server::DEFAULT_ACCESS_TOKEN_TTL
+    }
+
+    /// Counts requests against each client's `rate_limit_per_minute`. The default limiter is
+    /// shared by every app in the process.
+    fn client_rate_limiter(&self) -> &ClientRateLimiter {
+        &DEFAULT_CLIENT_RATE_LIMITER
+    }
+
+    /// Lifetimes of the tokens from `/auth/permission-tokens`. Tokens are signed with `key_ring`.
+    fn permission_token_policy(&self) -> &PermissionTokenPolicy {
+        &DEFAULT_PERMISSION_TOKEN_POLICY
+    }
+}
+
+/// Apply the app's username policy to a username from the identity provider.
+///
+/// Usernames that fail the policy, collide with an existing user's canonical username, or were
+/// recently released by another user are dropped rather than failing account creation; the user
+/// can still sign in by id/email.
+pub(crate) async fn accepted_username<S>(
+    app: &S,
+    pool: &sqlx::PgPool,
+    user_id: UserId,
+    username: Option<String>,
+) -> Result<Option<String>, RejectReason>
+where
+    S: AuthApp,
+{
+    let Some(username) = username else {
+        return Ok(None);
+    };
+    let username = match app.username_policy().validate(&username) {
+        Ok(username) => username,
+        Err(err) => {
+            tracing::info!("Dropping username {:?}: {}", Sensitive(&username), err);
+            return Ok(None);
+        }
+    };
+    let existing = UserRow::get_by_username_canonical(pool, &username)
+        .await
+        .map_err(|_| RejectReason::database("Failed to reach database"))?;
+    if existing.is_some() {
+        tracing::info!(
+            "Dropping username {:?}: already taken",
+            Sensitive(&username)
+        );
+        return Ok(None);
+    }
+    let held = UsernameHistoryRow::held_until(pool, &username, user_id)
+        .await
+        .map_err(|_| RejectReason::database("Failed to reach database"))?;
+    if held.is_some() {
+        tracing::info!(
+            "Dropping username {:?}: recently released",
+            Sensitive(&username)
+        );
+        return Ok(None);
+    }
+    Ok(Some(username))
+}
+
+#[derive(Clone, Serialize)]
 pub struct User {
     pub id: UserId,
+    pub external_id: Option<String>,
     pub username: Option<String>,
     pub email: String,
     pub details: Option<Value>,
+    pub version: i64,
+    pub display_name: Option<String>,
+    pub locale: Option<String>,
+    /// IANA time zone name, e.g. `Europe/Lisbon`.
+    pub timezone: Option<String>,
+}
+
+impl fmt::Debug for User {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("User")
+            .field("id", &self.id)
+            .field("external_id", &self.external_id)
+            .field("username", &self.username.as_ref().map(Sensitive))
+            .field("email", &Sensitive(&self.email))
+            .field("details", &self.details.as_ref().map(Sensitive))
+            .field("version", &self.version)
+            .field("display_name", &self.display_name.as_ref().map(Sensitive))
+            .field("locale", &self.locale)
+            .field("timezone", &self.timezone)
+            .finish()
+    }
 }
 
 impl From<UserRow> for User {
     fn from(row: UserRow) -> Self {
         Self {
-            id: UserId(row.id),
+            id: row.id,
+            external_id: row.external_id,
             username: row.username,
             email: row.email,
             details: row.details,
+            version: row.version,
+            display_name: row.display_name,
+            locale: row.locale,
+            timezone: row.timezone,
+        }
+    }
+}
+
+/// Shape of `User` and `Group` responses. Routes from `versioned_routes` serve the version of
+/// their prefix; all other routes serve `V1`. See `v2` for what changed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ApiVersion {
+    #[default]
+    V1,
+    V2,
+}
+
+impl ApiVersion {
+    pub const ALL: [ApiVersion; 2] = [ApiVersion::V1, ApiVersion::V2];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ApiVersion::V1 => "v1",
+            ApiVersion::V2 => "v2",
+        }
+    }
+
+    /// Path prefix of this version's routes, e.g. `/v2`.
+    pub fn prefix(self) -> &'static str {
+        match self {
+            ApiVersion::V1 => "/v1",
+            ApiVersion::V2 => "/v2",
         }
     }
+
+    pub fn user(self, user: User) -> VersionedUser {
+        match self {
+            ApiVersion::V1 => VersionedUser::V1(user),
+            ApiVersion::V2 => VersionedUser::V2(user.into()),
+        }
+    }
+}
+
+impl FromStr for ApiVersion {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        ApiVersion::ALL
+            .into_iter()
+            .find(|version| version.as_str() == value.to_ascii_lowercase())
+            .ok_or_else(|| "expected v1 or v2".to_string())
+    }
+}
+
+impl<S> FromRequestParts<S> for ApiVersion
+where
+    S: Send + Sync,
+{
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        Ok(parts
+            .extensions
+            .get::<ApiVersion>()
+            .copied()
+            .unwrap_or_default())
+    }
+}
+
+/// `?fields=` for endpoints that return users.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct FieldsQuery {
+    #[serde(default)]
+    pub fields: FieldMask,
+}
+
+/// `body` cut down to `fields`.
+fn sparse<T: Serialize>(fields: &FieldMask, body: &T) -> Result<Json<Value>, RejectReason> {
+    fields.apply(body).map(Json).map_err(|err| match err {
+        FieldMaskError::UnknownField(_) => RejectReason::bad_request(err.to_string()),
+        FieldMaskError::Serialize(err) => RejectReason::anyhow(anyhow::Error::new(err)),
+    })
+}
+
+/// A `User` in the shape of the request's `ApiVersion`.
+#[derive(Debug, Clone, Serialize)]
+#[serde(untagged)]
+pub enum VersionedUser {
+    V1(User),
+    V2(v2::User),
+}
+
+fn etag(version: i64) -> String {
+    format!("\"{}\"", version)
+}
+
+/// `Cache-Control` of polled per-user responses: never shared, always revalidated.
+const REVALIDATE_CACHE_CONTROL: &str = "private, no-cache";
+
+/// `Cache-Control` of responses carrying a secret shown once, e.g. a new client secret. Also
+/// keeps `IdempotencyLayer` from storing them.
+const NO_STORE_CACHE_CONTROL: &str = "no-store";
+
+/// Whether `If-None-Match` lists `etag`, or `*`. Compared weakly, so `W/"3"` matches `"3"`.
+fn if_none_match(headers: &HeaderMap, etag: &str) -> bool {
+    let opaque = |tag: &str| tag.trim().trim_start_matches("W/").to_string();
+    headers
+        .get_all(IF_NONE_MATCH)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|tag| tag.trim() == "*" || opaque(tag) == opaque(etag))
+}
+
+fn weak_etag(version: i64) -> String {
+    format!("W/\"{}\"", version)
+}
+
+fn not_modified(etag: String) -> Response {
+    (
+        StatusCode::NOT_MODIFIED,
+        [
+            (ETAG, etag),
+            (CACHE_CONTROL, REVALIDATE_CACHE_CONTROL.to_string()),
+        ],
+    )
+        .into_response()
+}
+
+/// `body` tagged with `etag`, or `304 Not Modified` when the client already has it.
+fn conditional(headers: &HeaderMap, etag: String, body: impl IntoResponse) -> Response {
+    if if_none_match(headers, &etag) {
+        return not_modified(etag);
+    }
+    (
+        [
+            (ETAG, etag),
+            (CACHE_CONTROL, REVALIDATE_CACHE_CONTROL.to_string()),
+        ],
+        body,
+    )
+        .into_response()
+}
+
+/// `conditional` for responses without a version column, tagged with a hash of their content.
+fn conditional_content<T: Serialize>(
+    headers: &HeaderMap,
+    body: &T,
+) -> Result<Response, RejectReason> {
+    let bytes =
+        serde_json::to_vec(body).map_err(|err| RejectReason::anyhow(anyhow::Error::new(err)))?;
+    let etag = format!(
+        "W/\"{}\"",
+        URL_SAFE_NO_PAD.encode(&Sha256::digest(&bytes)[..16])
+    );
+    if if_none_match(headers, &etag) {
+        return Ok(not_modified(etag));
+    }
+    Ok((
+        [
+            (ETAG, etag),
+            (CACHE_CONTROL, REVALIDATE_CACHE_CONTROL.to_string()),
+            (CONTENT_TYPE, "application/json".to_string()),
+        ],
+        bytes,
+    )
+        .into_response())
+}
+
+/// Version required by an `If-Match` header. `None` when the header is absent or `*`.
+fn if_match_version(headers: &HeaderMap) -> Result<Option<i64>, RejectReason> {
+    let Some(value) = headers.get(IF_MATCH) else {
+        return Ok(None);
+    };
+    let value = value
+        .to_str()
+        .map_err(|_| RejectReason::bad_request("Invalid If-Match header"))?
+        .trim();
+    if value == "*" {
+        return Ok(None);
+    }
+    value
+        .trim_start_matches("W/")
+        .trim_matches('"')
+        .parse()
+        .map(Some)
+        .map_err(|_| RejectReason::bad_request("Invalid If-Match header"))
+}
+
+/// The `AuthContext` left in the request by `guard::ResolveRolesLayer`, or a fresh one from the
+/// reader pool.
+async fn auth_context<S: HasPool>(
+    app: &S,
+    user_id: UserId,
+    loaded: Option<Extension<AuthContext>>,
+) -> Result<AuthContext, RejectReason> {
+    match loaded {
+        Some(Extension(context)) if context.user_id == user_id => Ok(context),
+        _ => AuthContext::load(&app.reader_pool(), user_id)
+            .await
+            .map_err(|_| RejectReason::database("Failed to reach database")),
+    }
 }
 
 /// Handler to get or create the authenticated user's record.
@@ -64,110 +678,741 @@ impl From<UserRow> for User {
 /// If the user does not exist in the database, create a new record using the information from the
 /// AuthenticatedUser.
 ///
-/// This serves as a new user insertion point when first seen from the identity provider.
+/// This serves as a new user insertion point when first seen from the identity provider. When the
+/// app has a `captcha_verifier`, creating the record requires a passing challenge response.
+///
+/// `?fields=id,email` returns only those fields of the user. The response carries a weak ETag of
+/// the user's version and a matching `If-None-Match` gets `304 Not Modified`, so clients can poll
+/// cheaply.
 pub async fn self_handler<S>(
     app: State<S>,
     auth_user: AuthenticatedUser,
+    context: Option<Extension<AuthContext>>,
+    version: ApiVersion,
+    Query(query): Query<FieldsQuery>,
+    headers: HeaderMap,
+    uri: Uri,
 ) -> Result<impl IntoResponse, RejectReason>
 where
     S: AuthApp + Clone + Send + Sync + 'static,
 {
     let pool = app.pool();
-    let user = UserRow::get(&pool, auth_user.id())
-        .await
-        .map_err(|_| RejectReason::database("Failed to reach database"))?;
-    if let Some(user) = user {
-        Ok(Json(User::from(user)))
+    let context = auth_context(&*app, auth_user.id(), context).await?;
+    let policy = app.provisioning_policy();
+    if let Some(user) = context.user {
+        if context.pending_approval {
+            return Err(pending_approval_rejection());
+        }
+        let user = User::from(user);
+        let etag = weak_etag(user.version);
+        Ok(conditional(
+            &headers,
+            etag,
+            sparse(&query.fields, &version.user(user))?,
+        ))
     } else {
+        if policy.mode() == ProvisioningMode::Disabled {
+            return Err(RejectReason::forbidden_detailed(
+                "provisioning_disabled",
+                "Accounts are not created on sign-in",
+                None,
+            ));
+        }
         // Must have a valid email from the identity provider.
         let email = auth_user
             .email()
             .ok_or_else(|| RejectReason::bad_request("Email is required"))?;
+        if let Some(verifier) = app.captcha_verifier()
+            && let Err(err) = verify_captcha(verifier, &headers, &uri, None).await
+        {
+            tracing::info!("Rejecting new user {}: {}", auth_user.id(), err);
+            return Err(RejectReason::captcha_rejected(&err));
+        }
+        if let Err(err) = policy.check_email(&email) {
+            tracing::info!("Not provisioning new user {}: {}", auth_user.id(), err);
+            return Err(RejectReason::email_rejected(&err));
+        }
+        if let Err(err) = app.email_domain_policy().validate(&email).await {
+            tracing::info!("Rejecting new user {}: {}", auth_user.id(), err);
+            return Err(RejectReason::email_rejected(&err));
+        }
 
-        // Create a user record if it doesn't exist.
-        let new_user = UserRow::new(auth_user.id(), auth_user.username(), email, None);
-        UserRow::insert(&pool, &new_user)
-            .await
-            .map_err(|_| RejectReason::database("Failed to reach database"))?;
+        // Create a user record if it doesn't exist. Concurrent first requests resolve to the same
+        // row rather than racing on the insert.
+        let username =
+            accepted_username(&*app, &pool, auth_user.id(), auth_user.username()).await?;
+        app.hooks()
+            .before_user_create(&NewUser {
+                id: auth_user.id(),
+                email: &email,
+                username: username.as_deref(),
+                invited: false,
+            })
+            .await?;
+        let defaults = UserRow::new(auth_user.id(), username, email.clone(), None)
+            .with_external_id(app.external_id_generator());
+        let (user, created) = UserRow::get_or_create_by_email_normalized(
+            &pool,
+            &email,
+            defaults,
+            app.email_normalizer(),
+        )
+        .await
+        .map_err(|_| RejectReason::database("Failed to reach database"))?;
+        if user.id != auth_user.id() {
+            return Err(RejectReason::conflict(
+                "Email is already registered to another account",
+            ));
+        }
 
-        let user = User::from(new_user.clone());
-        app.announce_new_user(&user);
+        let user = User::from(user);
+        if created {
+            provision_user(&pool, user.id, policy)
+                .await
+                .map_err(|_| RejectReason::database("Failed to reach database"))?;
+            app.announce_new_user(&user);
+            app.hooks().after_user_create(&user).await;
+            if policy.mode() == ProvisioningMode::RequireApproval {
+                return Err(pending_approval_rejection());
+            }
+            if let Some(mapper) = app.claims_mapper() {
+                sync_login_claims(&pool, user.id, mapper, auth_user.authorization()).await;
+            }
+        } else if UserRow::is_deleted(&pool, user.id)
+            .await
+            .map_err(|_| RejectReason::database("Failed to reach database"))?
+        {
+            return Err(deleted_account_rejection());
+        } else if UserRow::is_pending_approval(&pool, user.id)
+            .await
+            .map_err(|_| RejectReason::database("Failed to reach database"))?
+        {
+            return Err(pending_approval_rejection());
+        }
 
-        Ok(Json(user))
+        let etag = weak_etag(user.version);
+        Ok(conditional(
+            &headers,
+            etag,
+            sparse(&query.fields, &version.user(user))?,
+        ))
     }
 }
 
-/// Handler to update the authenticated user's record.
-///
-/// Stores arbitrary JSON details about the user.
-pub async fn self_update_handler<S>(
+/// `self_handler` for `RegistrationMode::InvitationOnly`. Never creates a record; users without one
+/// get `403` with code `invitation_required` until they accept an invitation.
+pub async fn invitation_only_self_handler<S>(
     app: State<S>,
     auth_user: AuthenticatedUser,
-    Json(payload): Json<Value>,
+    version: ApiVersion,
+    Query(query): Query<FieldsQuery>,
+    headers: HeaderMap,
 ) -> Result<impl IntoResponse, RejectReason>
 where
-    S: AuthApp + Clone + Send + Sync + 'static,
+    S: HasStore + Clone + Send + Sync + 'static,
 {
-    let pool = app.pool();
-    let details = Some(payload);
-    UserRow::set_details(&pool, auth_user.id(), details)
-        .await
-        .map_err(|_| RejectReason::database("Failed to reach database"))?;
-
-    let user_row = UserRow::get(&pool, auth_user.id())
+    let pool = app.reader_pool();
+    let user = UserRow::get(&pool, auth_user.id())
         .await
         .map_err(|_| RejectReason::database("Failed to reach database"))?
-        .ok_or_else(|| RejectReason::not_found("User not found"))?;
-    let user = User::from(user_row);
-
-    app.announce_user_update(&user);
-    Ok(Json(user))
-}
-
-#[derive(Debug, Clone, Serialize)]
-pub struct Group {
-    pub id: GroupId,
-    pub name: String,
+        .ok_or_else(|| {
+            RejectReason::forbidden_detailed(
+                "invitation_required",
+                "An invitation is required to create an account",
+                None,
+            )
+        })?;
+    let pending = UserRow::is_pending_approval(&pool, auth_user.id())
+        .await
+        .map_err(|_| RejectReason::database("Failed to reach database"))?;
+    if pending {
+        return Err(pending_approval_rejection());
+    }
+    let user = User::from(user);
+    let etag = weak_etag(user.version);
+    Ok(conditional(
+        &headers,
+        etag,
+        sparse(&query.fields, &version.user(user))?,
+    ))
 }
 
-impl From<GroupRow> for Group {
-    fn from(row: GroupRow) -> Self {
-        Self {
-            id: GroupId(row.id),
-            name: row.display_name,
-        }
-    }
+#[derive(Clone, Deserialize)]
+pub struct AcceptInvitationContent {
+    pub token: String,
 }
 
-/// Handler to get the authenticated user's groups.
+/// Create the authenticated user's record from an invitation. The invitation must have been
+/// issued for the email the identity provider reports. An invitation revoked or accepted while
+/// this runs gets `404` and no account is created.
 ///
-/// Groups are used as a way to organize users, assign permissions, and manage payments within the
-/// system. Although you could use a group for RBAC purposes, we provide a separate permissions
-/// endpoint to allow for role assignments without the JOIN overhead of groups.
-pub async fn self_groups_handler<S>(
+/// Accepting grants the `provisioning_policy` defaults; its mode and domain list do not apply,
+/// since an admin chose the address. Accepting a `bootstrap::bootstrap` invitation also grants
+/// `super_admin`, unless someone already holds it.
+pub async fn accept_invitation_handler<S>(
     app: State<S>,
     auth_user: AuthenticatedUser,
+    version: ApiVersion,
+    Json(payload): Json<AcceptInvitationContent>,
 ) -> Result<impl IntoResponse, RejectReason>
 where
     S: AuthApp + Clone + Send + Sync + 'static,
 {
     let pool = app.pool();
-    let groups = GroupMembershipRow::groups_for_user(&pool, auth_user.id())
+    let email = auth_user
+        .email()
+        .ok_or_else(|| RejectReason::bad_request("Email is required"))?;
+    let invitation = InvitationRow::get_acceptable(&pool, &payload.token)
         .await
-        .map_err(|_| RejectReason::database("Failed to reach database"))?;
-    Ok(Json(
-        groups.into_iter().map(Group::from).collect::<Vec<_>>(),
-    ))
-}
-
-#[derive(Debug, Clone, Serialize)]
-pub struct Role {
-    pub name: String,
-}
+        .map_err(|_| RejectReason::database("Failed to reach database"))?
+        .ok_or_else(|| RejectReason::not_found("Invitation not found or expired"))?;
+    let normalizer = app.email_normalizer();
+    if normalizer.canonical(&email) != normalizer.canonical(&invitation.email) {
+        return Err(RejectReason::forbidden_detailed(
+            "invitation_email_mismatch",
+            "Invitation was issued for a different email address",
+            None,
+        ));
+    }
 
-impl From<AccessRoleRow> for Role {
-    fn from(row: AccessRoleRow) -> Self {
-        Self {
+    let username = accepted_username(&*app, &pool, auth_user.id(), auth_user.username()).await?;
+    app.hooks()
+        .before_user_create(&NewUser {
+            id: auth_user.id(),
+            email: &email,
+            username: username.as_deref(),
+            invited: true,
+        })
+        .await?;
+    // The invitation is claimed in the transaction that creates the account, so one revoked or
+    // accepted concurrently rolls the account back.
+    let mut tx = pool
+        .begin()
+        .await
+        .map_err(|_| RejectReason::database("Failed to reach database"))?;
+    let defaults = UserRow::new(auth_user.id(), username, email.clone(), None)
+        .with_external_id(app.external_id_generator());
+    let (user, created) = UserRow::get_or_create_by_email_in(&mut tx, &email, defaults, normalizer)
+        .await
+        .map_err(|_| RejectReason::database("Failed to reach database"))?;
+    if user.id != auth_user.id() {
+        return Err(RejectReason::conflict(
+            "Email is already registered to another account",
+        ));
+    }
+    if !created {
+        return Err(RejectReason::conflict("Account already exists"));
+    }
+    let accepted = InvitationRow::mark_accepted_in(&mut tx, invitation.id, auth_user.id())
+        .await
+        .map_err(|_| RejectReason::database("Failed to reach database"))?;
+    if !accepted {
+        return Err(RejectReason::not_found("Invitation not found or expired"));
+    }
+
+    if invitation.bootstrap_admin
+        && !accept_bootstrap_invitation(&mut tx, invitation.id, auth_user.id())
+            .await
+            .map_err(|_| RejectReason::database("Failed to reach database"))?
+    {
+        tracing::warn!(
+            "Bootstrap invitation {} accepted after a super admin already existed",
+            invitation.id
+        );
+    }
+    provision_invited_user_in(
+        &mut tx,
+        invitation.invited_by.map(UserId),
+        auth_user.id(),
+        app.provisioning_policy(),
+    )
+    .await
+    .map_err(|_| RejectReason::database("Failed to reach database"))?;
+    tx.commit()
+        .await
+        .map_err(|_| RejectReason::database("Failed to reach database"))?;
+
+    let user = User::from(user);
+    app.announce_new_user(&user);
+    app.hooks().after_user_create(&user).await;
+    if let Some(mapper) = app.claims_mapper() {
+        sync_login_claims(&pool, user.id, mapper, auth_user.authorization()).await;
+    }
+    Ok((
+        StatusCode::CREATED,
+        [(ETAG, etag(user.version))],
+        Json(version.user(user)),
+    ))
+}
+
+pub(crate) fn pending_approval_rejection() -> RejectReason {
+    RejectReason::forbidden_detailed(
+        "pending_approval",
+        "Account is waiting for an administrator to approve it",
+        None,
+    )
+}
+
+pub(crate) fn deleted_account_rejection() -> RejectReason {
+    RejectReason::forbidden_detailed("account_deleted", "Account has been deleted", None)
+}
+
+/// Handler to update the authenticated user's record.
+///
+/// Stores arbitrary JSON details about the user. Send the `ETag` from `GET /auth/me` as
+/// `If-Match` to reject the write with `412` if the record changed in the meantime.
+pub async fn self_update_handler<S>(
+    app: State<S>,
+    auth_user: AuthenticatedUser,
+    version: ApiVersion,
+    headers: HeaderMap,
+    Json(payload): Json<Value>,
+) -> Result<impl IntoResponse, RejectReason>
+where
+    S: AuthApp + Clone + Send + Sync + 'static,
+{
+    let pool = app.pool();
+    let details = Some(payload);
+    match if_match_version(&headers)? {
+        Some(expected_version) => {
+            UserRow::set_details_if_version(&pool, auth_user.id(), details, expected_version)
+                .await
+                .map_err(|err| match err {
+                    VersionConflictError::Conflict { .. } => {
+                        RejectReason::precondition_failed("User was modified by another request")
+                    }
+                    VersionConflictError::NotFound => RejectReason::not_found("User not found"),
+                    VersionConflictError::Database(_) => {
+                        RejectReason::database("Failed to reach database")
+                    }
+                })?;
+        }
+        None => {
+            UserRow::set_details(&pool, auth_user.id(), details)
+                .await
+                .map_err(|_| RejectReason::database("Failed to reach database"))?;
+        }
+    }
+
+    let user_row = UserRow::get(&pool, auth_user.id())
+        .await
+        .map_err(|_| RejectReason::database("Failed to reach database"))?
+        .ok_or_else(|| RejectReason::not_found("User not found"))?;
+    let user = User::from(user_row);
+
+    app.announce_user_update(&user);
+    Ok(([(ETAG, etag(user.version))], Json(version.user(user))))
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct UsernameContent {
+    pub username: Option<String>,
+}
+
+/// Change or clear the authenticated user's username.
+///
+/// The previous username keeps resolving to this user and cannot be claimed by anyone else until
+/// the app's `username_hold_down` has passed.
+pub async fn self_username_handler<S>(
+    app: State<S>,
+    auth_user: AuthenticatedUser,
+    version: ApiVersion,
+    Json(payload): Json<UsernameContent>,
+) -> Result<impl IntoResponse, RejectReason>
+where
+    S: AuthApp + Clone + Send + Sync + 'static,
+{
+    let username = payload
+        .username
+        .map(|username| app.username_policy().validate(&username))
+        .transpose()
+        .map_err(|err| RejectReason::bad_request(err.to_string()))?;
+
+    let pool = app.pool();
+    let user_row = UserRow::set_username(
+        &pool,
+        auth_user.id(),
+        username.as_deref(),
+        app.username_hold_down(),
+    )
+    .await
+    .map_err(|err| match err {
+        UsernameChangeError::Taken | UsernameChangeError::HeldDown { .. } => {
+            RejectReason::conflict(err.to_string())
+        }
+        UsernameChangeError::NotFound => RejectReason::not_found("User not found"),
+        UsernameChangeError::Database(_) => RejectReason::database("Failed to reach database"),
+    })?;
+    let user = User::from(user_row);
+
+    app.announce_user_update(&user);
+    Ok(([(ETAG, etag(user.version))], Json(version.user(user))))
+}
+
+/// Longest display name `PATCH /auth/me` accepts, in characters.
+const MAX_DISPLAY_NAME_CHARS: usize = 100;
+
+/// Profile fields for `PATCH /auth/me`. A field left out is unchanged; `null` clears it.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ProfileContent {
+    #[serde(default, deserialize_with = "nullable")]
+    pub display_name: Option<Option<String>>,
+    /// BCP 47 tag such as `pt-BR`.
+    #[serde(default, deserialize_with = "nullable")]
+    pub locale: Option<Option<String>>,
+    /// IANA time zone name such as `America/Sao_Paulo`.
+    #[serde(default, deserialize_with = "nullable")]
+    pub timezone: Option<Option<String>>,
+}
+
+/// Tells a `null` field (`Some(None)`) apart from a missing one (`None`, via `#[serde(default)]`).
+fn nullable<'de, D>(deserializer: D) -> Result<Option<Option<String>>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    Option::<String>::deserialize(deserializer).map(Some)
+}
+
+/// Update the authenticated user's display name, locale or time zone.
+///
+/// The locale also applies to this session, as with `PUT /auth/me/locale`. Send the `ETag` from
+/// `GET /auth/me` as `If-Match` to reject the write with `412` if the record changed meanwhile.
+pub async fn self_profile_handler<S>(
+    app: State<S>,
+    auth_user: AuthenticatedUser,
+    version: ApiVersion,
+    headers: HeaderMap,
+    session: Session,
+    Json(payload): Json<ProfileContent>,
+) -> Result<impl IntoResponse, RejectReason>
+where
+    S: AuthApp + Clone + Send + Sync + 'static,
+{
+    let expected_version = if_match_version(&headers)?;
+    let pool = app.pool();
+    let update = validate_profile(&pool, payload).await?;
+    if update.is_empty() {
+        return Err(RejectReason::bad_request("No profile fields to update"));
+    }
+
+    let user_row = UserRow::set_profile(&pool, auth_user.id(), &update, expected_version)
+        .await
+        .map_err(|err| match err {
+            VersionConflictError::Conflict { .. } => {
+                RejectReason::precondition_failed("User was modified by another request")
+            }
+            VersionConflictError::NotFound => RejectReason::not_found("User not found"),
+            VersionConflictError::Database(_) => RejectReason::database("Failed to reach database"),
+        })?;
+    if let Some(locale) = &update.locale {
+        let stored = match locale {
+            Some(locale) => session.insert(LOCALE_SESSION_KEY, locale).await.map(|_| ()),
+            None => session
+                .remove::<String>(LOCALE_SESSION_KEY)
+                .await
+                .map(|_| ()),
+        };
+        stored.map_err(|_| RejectReason::Session)?;
+    }
+    let user = User::from(user_row);
+
+    app.announce_user_update(&user);
+    Ok(([(ETAG, etag(user.version))], Json(version.user(user))))
+}
+
+/// Normalize the fields of a profile update, rejecting invalid ones. A blank display name clears
+/// it.
+async fn validate_profile(
+    pool: &sqlx::PgPool,
+    payload: ProfileContent,
+) -> Result<ProfileUpdate, RejectReason> {
+    let display_name = payload
+        .display_name
+        .map(|name| {
+            let name = name
+                .map(|name| name.trim().to_string())
+                .filter(|name| !name.is_empty());
+            match &name {
+                Some(name) if name.chars().count() > MAX_DISPLAY_NAME_CHARS => {
+                    Err(RejectReason::bad_request(format!(
+                        "Display names are at most {} characters",
+                        MAX_DISPLAY_NAME_CHARS
+                    )))
+                }
+                Some(name) if name.chars().any(char::is_control) => Err(RejectReason::bad_request(
+                    "Display names cannot contain control characters",
+                )),
+                _ => Ok(name),
+            }
+        })
+        .transpose()?;
+    let locale = payload
+        .locale
+        .map(|tag| {
+            tag.map(|tag| {
+                parse_locale(&tag)
+                    .map(|locale| locale.to_string())
+                    .ok_or_else(|| RejectReason::bad_request(format!("Invalid locale {:?}", tag)))
+            })
+            .transpose()
+        })
+        .transpose()?;
+    let timezone = match payload.timezone {
+        Some(Some(timezone)) => {
+            let known = is_known_timezone(pool, &timezone)
+                .await
+                .map_err(|_| RejectReason::database("Failed to reach database"))?;
+            if !known {
+                return Err(RejectReason::bad_request(format!(
+                    "Unknown time zone {:?}",
+                    timezone
+                )));
+            }
+            Some(Some(timezone))
+        }
+        other => other,
+    };
+    Ok(ProfileUpdate {
+        display_name,
+        locale,
+        timezone,
+    })
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct LocaleContent {
+    pub locale: Option<String>,
+}
+
+/// Set or clear the authenticated user's preferred locale, for this session and for messages
+/// sent to them later. The locale must be a valid BCP 47 tag such as `pt-BR`.
+pub async fn self_locale_handler<S>(
+    app: State<S>,
+    auth_user: AuthenticatedUser,
+    session: Session,
+    Json(payload): Json<LocaleContent>,
+) -> Result<impl IntoResponse, RejectReason>
+where
+    S: HasStore + Clone + Send + Sync + 'static,
+{
+    let locale = payload
+        .locale
+        .map(|tag| {
+            parse_locale(&tag)
+                .map(|locale| locale.to_string())
+                .ok_or_else(|| RejectReason::bad_request(format!("Invalid locale {:?}", tag)))
+        })
+        .transpose()?;
+
+    let pool = app.pool();
+    let updated = UserRow::set_locale(&pool, auth_user.id(), locale.as_deref())
+        .await
+        .map_err(|_| RejectReason::database("Failed to reach database"))?;
+    if !updated {
+        return Err(RejectReason::not_found("User not found"));
+    }
+
+    let stored = match &locale {
+        Some(locale) => session.insert(LOCALE_SESSION_KEY, locale).await.map(|_| ()),
+        None => session
+            .remove::<String>(LOCALE_SESSION_KEY)
+            .await
+            .map(|_| ()),
+    };
+    stored.map_err(|_| RejectReason::Session)?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Group {
+    pub id: GroupId,
+    pub external_id: Option<String>,
+    pub name: String,
+}
+
+impl From<GroupRow> for Group {
+    fn from(row: GroupRow) -> Self {
+        Self {
+            id: row.id,
+            external_id: row.external_id,
+            name: row.display_name,
+        }
+    }
+}
+
+/// A group in a path: its UUID or its `external_id`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(from = "String")]
+pub enum GroupRef {
+    Id(GroupId),
+    External(String),
+}
+
+impl From<String> for GroupRef {
+    fn from(value: String) -> Self {
+        match value.parse() {
+            Ok(group_id) => Self::Id(group_id),
+            Err(_) => Self::External(value),
+        }
+    }
+}
+
+impl GroupRef {
+    /// The group's id. UUIDs are taken as they are; whether that group exists is up to the
+    /// caller. Soft-deleted groups are not found by `external_id`.
+    pub async fn resolve(&self, pool: &sqlx::PgPool) -> Result<GroupId, RejectReason> {
+        self.resolve_in(pool, false).await
+    }
+
+    /// `resolve`, also finding soft-deleted groups by `external_id`.
+    pub(crate) async fn resolve_including_deleted(
+        &self,
+        pool: &sqlx::PgPool,
+    ) -> Result<GroupId, RejectReason> {
+        self.resolve_in(pool, true).await
+    }
+
+    async fn resolve_in(
+        &self,
+        pool: &sqlx::PgPool,
+        include_deleted: bool,
+    ) -> Result<GroupId, RejectReason> {
+        let external_id = match self {
+            Self::Id(group_id) => return Ok(*group_id),
+            Self::External(external_id) => external_id,
+        };
+        let group = if include_deleted {
+            GroupRow::get_by_external_id_including_deleted(pool, external_id).await
+        } else {
+            GroupRow::get_by_external_id(pool, external_id).await
+        };
+        group
+            .map_err(|_| RejectReason::database("Failed to reach database"))?
+            .map(|group| group.id)
+            .ok_or_else(|| RejectReason::not_found("Group not found"))
+    }
+}
+
+/// A user in a path: their UUID or their `external_id`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(from = "String")]
+pub enum UserRef {
+    Id(UserId),
+    External(String),
+}
+
+impl From<String> for UserRef {
+    fn from(value: String) -> Self {
+        match value.parse() {
+            Ok(user_id) => Self::Id(user_id),
+            Err(_) => Self::External(value),
+        }
+    }
+}
+
+impl UserRef {
+    /// The user's id. UUIDs are taken as they are; whether that user exists is up to the caller.
+    /// Soft-deleted users are not found by `external_id`.
+    pub async fn resolve(&self, pool: &sqlx::PgPool) -> Result<UserId, RejectReason> {
+        self.resolve_in(pool, false).await
+    }
+
+    /// `resolve`, also finding soft-deleted users by `external_id`.
+    pub(crate) async fn resolve_including_deleted(
+        &self,
+        pool: &sqlx::PgPool,
+    ) -> Result<UserId, RejectReason> {
+        self.resolve_in(pool, true).await
+    }
+
+    async fn resolve_in(
+        &self,
+        pool: &sqlx::PgPool,
+        include_deleted: bool,
+    ) -> Result<UserId, RejectReason> {
+        let external_id = match self {
+            Self::Id(user_id) => return Ok(*user_id),
+            Self::External(external_id) => external_id,
+        };
+        let user = if include_deleted {
+            UserRow::get_by_external_id_including_deleted(pool, external_id).await
+        } else {
+            UserRow::get_by_external_id(pool, external_id).await
+        };
+        user.map_err(|_| RejectReason::database("Failed to reach database"))?
+            .map(|user| user.id)
+            .ok_or_else(|| RejectReason::not_found("User not found"))
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ExternalIdContent {
+    pub external_id: String,
+}
+
+fn external_id_rejection(err: ExternalIdChangeError, resource: &str) -> RejectReason {
+    match err {
+        ExternalIdChangeError::Invalid => RejectReason::bad_request(
+            "External ids are 1 to 64 letters, digits, '-' or '_', and not a UUID",
+        ),
+        ExternalIdChangeError::Taken => RejectReason::conflict("External id is already taken"),
+        ExternalIdChangeError::NotFound => RejectReason::not_found(format!("{resource} not found")),
+        ExternalIdChangeError::Database(_) => RejectReason::database("Failed to reach database"),
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct GroupListQuery {
+    pub archived: Option<ArchivedFilter>,
+}
+
+/// Handler to get the authenticated user's groups.
+///
+/// Groups are used as a way to organize users, assign permissions, and manage payments within the
+/// system. Although you could use a group for RBAC purposes, we provide a separate permissions
+/// endpoint to allow for role assignments without the JOIN overhead of groups.
+///
+/// Archived groups are listed too unless `archived` is `exclude`; `only` lists just those.
+///
+/// Answers `If-None-Match` like `self_handler`, with an ETag over the response content.
+pub async fn self_groups_handler<S>(
+    app: State<S>,
+    auth_user: AuthenticatedUser,
+    context: Option<Extension<AuthContext>>,
+    version: ApiVersion,
+    headers: HeaderMap,
+    Query(query): Query<GroupListQuery>,
+) -> Result<impl IntoResponse, RejectReason>
+where
+    S: HasStore + Clone + Send + Sync + 'static,
+{
+    let archived = query.archived.unwrap_or(ArchivedFilter::Include);
+    let context = auth_context(&*app, auth_user.id(), context).await?;
+    let groups = context
+        .groups
+        .into_iter()
+        .map(|membership| membership.group)
+        .filter(|group| archived.matches(group.archived_at.is_some()));
+    match version {
+        ApiVersion::V1 => {
+            conditional_content(&headers, &groups.map(Group::from).collect::<Vec<_>>())
+        }
+        ApiVersion::V2 => {
+            conditional_content(&headers, &groups.map(v2::Group::from).collect::<Vec<_>>())
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Role {
+    pub name: String,
+}
+
+impl From<AccessRoleRow> for Role {
+    fn from(row: AccessRoleRow) -> Self {
+        Self {
             name: row.role_name,
         }
     }
@@ -183,18 +1428,76 @@ impl From<AccessRoleRow> for Role {
 ///
 /// None of the endpoints here assume any specific roles; it's up to the application to interpret
 /// them and add additional endpoints as necessary to perform actions based on these roles.
+///
+/// Answers `If-None-Match` like `self_groups_handler`.
 pub async fn self_permissions_handler<S>(
     app: State<S>,
     auth_user: AuthenticatedUser,
+    context: Option<Extension<AuthContext>>,
+    headers: HeaderMap,
+) -> Result<impl IntoResponse, RejectReason>
+where
+    S: HasStore + Clone + Send + Sync + 'static,
+{
+    let context = auth_context(&*app, auth_user.id(), context).await?;
+    let roles: Vec<Role> = context
+        .global_roles
+        .into_iter()
+        .map(|name| Role { name })
+        .collect();
+    conditional_content(&headers, &roles)
+}
+
+/// Server-sent events announcing changes to the authenticated user's roles, groups and profile,
+/// so clients fetch those endpoints again instead of polling them. Each event is named by its
+/// `UpdateKind`, e.g. `event: roles`, with the `UserUpdate` as data. The stream opens with a
+/// `resync`. `404` unless the app has `user_updates`.
+pub async fn self_updates_handler<S>(
+    app: State<S>,
+    auth_user: AuthenticatedUser,
 ) -> Result<impl IntoResponse, RejectReason>
 where
     S: AuthApp + Clone + Send + Sync + 'static,
 {
-    let pool = app.pool();
-    let roles = AccessRoleRow::roles(&pool, auth_user.id())
-        .await
-        .map_err(|_| RejectReason::database("Failed to reach database"))?;
-    Ok(Json(roles.into_iter().map(Role::from).collect::<Vec<_>>()))
+    let Some(updates) = app.user_updates() else {
+        return Err(RejectReason::not_found("user updates"));
+    };
+    let events = user_update_events(app.pool(), updates.subscribe(), auth_user.id());
+    Ok(Sse::new(events).keep_alive(KeepAlive::default()))
+}
+
+fn user_update_events(
+    pool: Arc<sqlx::PgPool>,
+    rx: tokio::sync::broadcast::Receiver<UserUpdate>,
+    user_id: UserId,
+) -> impl Stream<Item = Result<Event, Infallible>> {
+    use tokio::sync::broadcast::error::RecvError;
+
+    let updates = stream::unfold((pool, rx), move |(pool, mut rx)| async move {
+        loop {
+            let update = match rx.recv().await {
+                Ok(update) => update,
+                Err(RecvError::Lagged(_)) => UserUpdate::resync(),
+                Err(RecvError::Closed) => return None,
+            };
+            match update.concerns(&pool, user_id).await {
+                Ok(true) => return Some((update, (pool, rx))),
+                Ok(false) => continue,
+                Err(err) => {
+                    tracing::warn!("Failed to check update for {}: {}", user_id, err);
+                    return Some((UserUpdate::resync(), (pool, rx)));
+                }
+            }
+        }
+    });
+    stream::once(async { UserUpdate::resync() })
+        .chain(updates)
+        .map(|update| {
+            let event = Event::default().event(update.kind.as_str());
+            Ok(event
+                .json_data(update)
+                .unwrap_or_else(|_| Event::default().event(update.kind.as_str())))
+        })
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -227,6 +1530,13 @@ pub struct RoleChangeContent {
     pub scope: String,
     pub scope_id: String,
     pub role_name: String,
+    /// A `policy::GrantCondition` for grants and denies; ignored when revoking.
+    #[serde(default)]
+    pub condition: Option<Value>,
+    /// Pin the grant to one environment, e.g. `staging`, instead of sharing it with all. See
+    /// `environment`.
+    #[serde(default)]
+    pub environment: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize)]
@@ -239,6 +1549,9 @@ pub struct ScopedRole {
     pub scope: String,
     pub scope_id: String,
     pub name: String,
+    pub effect: RoleEffect,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub condition: Option<Value>,
 }
 
 #[derive(Debug, Clone, Serialize)]
@@ -259,6 +1572,7 @@ pub struct RolesQuery {
 
 enum RoleMutationKind {
     Grant,
+    Deny,
     Revoke,
 }
 
@@ -269,7 +1583,7 @@ async fn mutate_role_assignment<S>(
     kind: RoleMutationKind,
 ) -> Result<Json<RoleChangeResult>, RejectReason>
 where
-    S: AuthApp + Clone + Send + Sync + 'static,
+    S: HasStore + HasHooks + Clone + Send + Sync + 'static,
 {
     let pool = app.pool();
     let actor_user_id = auth_user.id();
@@ -282,6 +1596,32 @@ where
             "scope, scope_id, and role_name are required",
         ));
     }
+    if !is_valid_scope_id_pattern(scope_id) {
+        return Err(RejectReason::bad_request(
+            "scope_id may only use * as its last character",
+        ));
+    }
+    if validate_scope(scope).is_err() {
+        return Err(RejectReason::bad_request(
+            "scope may not contain @; pin grants with environment",
+        ));
+    }
+    let stored_scope = match payload.environment.as_deref().map(str::trim) {
+        Some(environment) if !is_valid_environment_name(environment) => {
+            return Err(RejectReason::bad_request(
+                "environment may only use letters, digits, - and _",
+            ));
+        }
+        Some(environment) => pinned_scope(scope, environment),
+        None => scope.to_string(),
+    };
+    let condition = match (&kind, &payload.condition) {
+        (RoleMutationKind::Grant | RoleMutationKind::Deny, Some(condition)) => Some(
+            GrantCondition::parse(condition)
+                .map_err(|err| RejectReason::bad_request(err.to_string()))?,
+        ),
+        _ => None,
+    };
     if role_name == SUPER_ADMIN_ROLE && (scope != GLOBAL_SCOPE || scope_id != GLOBAL_SCOPE_ID) {
         return Err(RejectReason::bad_request(
             "super_admin can only be assigned at scope=global and scope_id=global",
@@ -315,30 +1655,61 @@ where
         ));
     }
 
-    let changed = match kind {
-        RoleMutationKind::Grant => grant_role_assignment_with_audit(
-            &pool,
-            actor_user_id,
-            payload.target.assignment_target(),
-            scope,
-            scope_id,
-            role_name,
-        )
-        .await
-        .map_err(|_| RejectReason::database("Failed to reach database"))?,
-        RoleMutationKind::Revoke => revoke_role_assignment_with_audit(
-            &pool,
-            actor_user_id,
-            payload.target.assignment_target(),
-            scope,
-            scope_id,
-            role_name,
-        )
-        .await
-        .map_err(|_| RejectReason::database("Failed to reach database"))?,
-    };
+    if let RoleAssignmentTarget::Group(group_id) = payload.target.assignment_target() {
+        require_group_not_deleted(&pool, group_id).await?;
+        require_group_unarchived(&pool, group_id).await?;
+    }
 
-    Ok(Json(RoleChangeResult { changed }))
+    let change = RoleChange {
+        actor_user_id,
+        target: payload.target.assignment_target(),
+        scope: &stored_scope,
+        scope_id,
+        role_name,
+    };
+    let effect = match kind {
+        RoleMutationKind::Deny => RoleEffect::Deny,
+        _ => RoleEffect::Allow,
+    };
+    let changed = match kind {
+        RoleMutationKind::Grant | RoleMutationKind::Deny => {
+            app.hooks().before_role_grant(&change, effect).await?;
+            set_role_assignment_with_audit(
+                &pool,
+                actor_user_id,
+                change.target,
+                &stored_scope,
+                scope_id,
+                role_name,
+                effect,
+                condition.as_ref(),
+            )
+            .await
+            .map_err(|_| RejectReason::database("Failed to reach database"))?
+        }
+        RoleMutationKind::Revoke => {
+            app.hooks().before_role_revoke(&change).await?;
+            revoke_role_assignment_with_audit(
+                &pool,
+                actor_user_id,
+                change.target,
+                &stored_scope,
+                scope_id,
+                role_name,
+            )
+            .await
+            .map_err(|_| RejectReason::database("Failed to reach database"))?
+        }
+    };
+
+    if changed {
+        match kind {
+            RoleMutationKind::Revoke => app.hooks().after_role_revoke(&change).await,
+            _ => app.hooks().after_role_grant(&change, effect).await,
+        }
+    }
+
+    Ok(Json(RoleChangeResult { changed }))
 }
 
 pub async fn role_grant_handler<S>(
@@ -347,29 +1718,107 @@ pub async fn role_grant_handler<S>(
     Json(payload): Json<RoleChangeContent>,
 ) -> Result<impl IntoResponse, RejectReason>
 where
-    S: AuthApp + Clone + Send + Sync + 'static,
+    S: HasStore + HasHooks + Clone + Send + Sync + 'static,
 {
     mutate_role_assignment(app, auth_user, payload, RoleMutationKind::Grant).await
 }
 
+/// Block a role for a user or group even where it is allowed, e.g. through a group membership.
+pub async fn role_deny_handler<S>(
+    app: State<S>,
+    auth_user: AuthenticatedUser,
+    Json(payload): Json<RoleChangeContent>,
+) -> Result<impl IntoResponse, RejectReason>
+where
+    S: HasStore + HasHooks + Clone + Send + Sync + 'static,
+{
+    mutate_role_assignment(app, auth_user, payload, RoleMutationKind::Deny).await
+}
+
 pub async fn role_revoke_handler<S>(
     app: State<S>,
     auth_user: AuthenticatedUser,
     Json(payload): Json<RoleChangeContent>,
 ) -> Result<impl IntoResponse, RejectReason>
 where
-    S: AuthApp + Clone + Send + Sync + 'static,
+    S: HasStore + HasHooks + Clone + Send + Sync + 'static,
 {
     mutate_role_assignment(app, auth_user, payload, RoleMutationKind::Revoke).await
 }
 
+#[derive(Debug, Clone, Deserialize)]
+pub struct AccessCheckContent {
+    /// Whose access to explain; defaults to the caller.
+    #[serde(default)]
+    pub user_id: Option<UserId>,
+    pub scope: String,
+    pub scope_id: String,
+    pub role_name: String,
+    /// Evaluate conditional grants as if the request came from this address instead of the
+    /// caller's.
+    #[serde(default)]
+    pub client_ip: Option<IpAddr>,
+    /// Evaluate conditional grants as if the session did (or did not) use MFA.
+    #[serde(default)]
+    pub mfa: Option<bool>,
+}
+
+/// Explain a role check: the decision, the rule that settled it and every grant considered,
+/// including which group each inherited grant came from.
+///
+/// Anyone can explain their own access; explaining another user's requires super_admin.
+/// Conditional grants are evaluated against the caller's request unless overridden.
+pub async fn check_explain_handler<S>(
+    app: State<S>,
+    auth_user: AuthenticatedUser,
+    context: RequestContext,
+    Json(payload): Json<AccessCheckContent>,
+) -> Result<impl IntoResponse, RejectReason>
+where
+    S: HasStore + Clone + Send + Sync + 'static,
+{
+    let pool = app.reader_pool();
+    let actor_user_id = auth_user.id();
+    let user_id = payload.user_id.unwrap_or(actor_user_id);
+    if user_id != actor_user_id {
+        let actor_is_super_admin = is_super_admin(&app.pool(), actor_user_id)
+            .await
+            .map_err(|_| RejectReason::database("Failed to reach database"))?;
+        if !actor_is_super_admin {
+            return Err(RejectReason::forbidden(
+                actor_user_id,
+                "Only super_admin can explain another user's access",
+            ));
+        }
+    }
+
+    let mut context = context;
+    if let Some(client_ip) = payload.client_ip {
+        context = context.with_client_ip(client_ip);
+    }
+    if let Some(mfa) = payload.mfa {
+        context = context.with_mfa(mfa);
+    }
+    let explanation = explain_effective_access(
+        &pool,
+        user_id,
+        payload.scope.trim(),
+        payload.scope_id.trim(),
+        payload.role_name.trim(),
+        Some(&context),
+    )
+    .await
+    .map_err(|_| RejectReason::database("Failed to reach database"))?;
+    Ok(Json(explanation))
+}
+
 pub async fn roles_handler<S>(
     app: State<S>,
     auth_user: AuthenticatedUser,
     Query(query): Query<RolesQuery>,
 ) -> Result<impl IntoResponse, RejectReason>
 where
-    S: AuthApp + Clone + Send + Sync + 'static,
+    S: HasStore + Clone + Send + Sync + 'static,
 {
     if query.scope.is_some() != query.scope_id.is_some() {
         return Err(RejectReason::bad_request(
@@ -377,9 +1826,9 @@ where
         ));
     }
 
-    let pool = app.pool();
+    let pool = app.reader_pool();
     let actor_user_id = auth_user.id();
-    let actor_is_super_admin = is_super_admin(&pool, actor_user_id)
+    let actor_is_super_admin = is_super_admin(&app.pool(), actor_user_id)
         .await
         .map_err(|_| RejectReason::database("Failed to reach database"))?;
     let scope = query.scope.as_deref();
@@ -417,6 +1866,8 @@ where
                     scope: row.scope,
                     scope_id: row.scope_id,
                     name: row.role_name,
+                    effect: row.effect,
+                    condition: row.condition,
                 })
                 .collect::<Vec<_>>();
 
@@ -466,6 +1917,8 @@ where
                     scope: row.scope,
                     scope_id: row.scope_id,
                     name: row.role_name,
+                    effect: row.effect,
+                    condition: row.condition,
                 })
                 .collect::<Vec<_>>();
 
@@ -491,13 +1944,465 @@ where
     S: AuthApp + Clone + Send + Sync + 'static,
 {
     let pool = app.pool();
-    UserRow::deactivate(&pool, auth_user.id())
+    let change = DeactivationChange {
+        actor_user_id: auth_user.id(),
+        subject: auth_user.id(),
+        reason: Some(DeactivationReason::UserRequested),
+    };
+    app.hooks().before_user_deactivate(&change).await?;
+    let deactivated = deactivate_user(
+        &pool,
+        auth_user.id(),
+        auth_user.id(),
+        DeactivationReason::UserRequested,
+        None,
+    )
+    .await
+    .map_err(|_| RejectReason::database("Failed to reach database"))?;
+    if deactivated {
+        app.announce_user_deactivation(auth_user.id());
+        app.hooks().after_user_deactivate(&change).await;
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+const DEFAULT_PAGE_LIMIT: i64 = 50;
+const MAX_PAGE_LIMIT: i64 = 500;
+const MAX_INVITATIONS_PER_REQUEST: usize = 500;
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct PageQuery {
+    #[serde(default, deserialize_with = "query_number")]
+    pub limit: Option<i64>,
+    #[serde(default, deserialize_with = "query_number")]
+    pub offset: Option<i64>,
+}
+
+/// An optional number that may arrive as a string, as every value does once `PageQuery` is
+/// `#[serde(flatten)]`ed into another query.
+fn query_number<'de, D>(deserializer: D) -> Result<Option<i64>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Number {
+        Int(i64),
+        Text(String),
+    }
+
+    match Option::<Number>::deserialize(deserializer)? {
+        None => Ok(None),
+        Some(Number::Int(value)) => Ok(Some(value)),
+        Some(Number::Text(value)) => value
+            .trim()
+            .parse()
+            .map(Some)
+            .map_err(serde::de::Error::custom),
+    }
+}
+
+impl PageQuery {
+    fn page(&self) -> (i64, i64) {
+        (
+            self.limit
+                .unwrap_or(DEFAULT_PAGE_LIMIT)
+                .clamp(1, MAX_PAGE_LIMIT),
+            self.offset.unwrap_or(0).max(0),
+        )
+    }
+}
+
+#[derive(Clone, Serialize)]
+pub struct LoginRecord {
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+    pub method: String,
+    pub provider: Option<String>,
+    pub at: chrono::NaiveDateTime,
+}
+
+impl fmt::Debug for LoginRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LoginRecord")
+            .field("ip", &self.ip.as_ref().map(Sensitive))
+            .field("user_agent", &self.user_agent.as_ref().map(Sensitive))
+            .field("method", &self.method)
+            .field("provider", &self.provider)
+            .field("at", &self.at)
+            .finish()
+    }
+}
+
+impl From<LoginHistoryRow> for LoginRecord {
+    fn from(row: LoginHistoryRow) -> Self {
+        Self {
+            ip: row.ip,
+            user_agent: row.user_agent,
+            method: row.method,
+            provider: row.provider,
+            at: row.created_at,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct LoginsResponse {
+    pub user_id: UserId,
+    pub last_login_at: Option<chrono::NaiveDateTime>,
+    pub last_login_ip: Option<String>,
+    pub logins: Vec<LoginRecord>,
+}
+
+async fn logins_for_user(
+    pool: &sqlx::PgPool,
+    user_id: UserId,
+    page: &PageQuery,
+) -> Result<LoginsResponse, RejectReason> {
+    let last_login = LastLogin::get(pool, user_id)
+        .await
+        .map_err(|_| RejectReason::database("Failed to reach database"))?
+        .ok_or_else(|| RejectReason::not_found("User not found"))?;
+    let logins = LoginHistoryRow::for_user(pool, user_id, Some(page.page()))
+        .await
+        .map_err(|_| RejectReason::database("Failed to reach database"))?;
+    Ok(LoginsResponse {
+        user_id,
+        last_login_at: last_login.last_login_at,
+        last_login_ip: last_login.last_login_ip,
+        logins: logins.into_iter().map(LoginRecord::from).collect(),
+    })
+}
+
+/// Login history for the authenticated user, newest first.
+pub async fn self_logins_handler<S>(
+    app: State<S>,
+    auth_user: AuthenticatedUser,
+    Query(page): Query<PageQuery>,
+) -> Result<impl IntoResponse, RejectReason>
+where
+    S: HasStore + Clone + Send + Sync + 'static,
+{
+    let pool = app.reader_pool();
+    Ok(Json(logins_for_user(&pool, auth_user.id(), &page).await?))
+}
+
+/// The authenticated user's account activity, newest first; see `activity`.
+pub async fn self_activity_handler<S>(
+    app: State<S>,
+    auth_user: AuthenticatedUser,
+    Query(page): Query<PageQuery>,
+) -> Result<impl IntoResponse, RejectReason>
+where
+    S: HasStore + Clone + Send + Sync + 'static,
+{
+    let pool = app.reader_pool();
+    let (limit, offset) = page.page();
+    let events = activity_for_user(&pool, auth_user.id(), limit, offset)
+        .await
+        .map_err(|_| RejectReason::database("Failed to reach database"))?;
+    Ok(Json(events))
+}
+
+/// Which security notifications the authenticated user gets, by notification kind.
+pub async fn self_notifications_handler<S>(
+    app: State<S>,
+    auth_user: AuthenticatedUser,
+) -> Result<impl IntoResponse, RejectReason>
+where
+    S: HasStore + Clone + Send + Sync + 'static,
+{
+    let pool = app.reader_pool();
+    let preferences = notification_preferences(&pool, auth_user.id())
+        .await
+        .map_err(|_| RejectReason::database("Failed to reach database"))?;
+    Ok(Json(preferences))
+}
+
+/// Turn security notifications on or off, e.g. `{"new_device_login": false}`. Kinds left out
+/// keep their setting.
+pub async fn self_notifications_update_handler<S>(
+    app: State<S>,
+    auth_user: AuthenticatedUser,
+    Json(payload): Json<BTreeMap<String, bool>>,
+) -> Result<impl IntoResponse, RejectReason>
+where
+    S: HasStore + Clone + Send + Sync + 'static,
+{
+    if let Some(kind) = payload
+        .keys()
+        .find(|kind| !SECURITY_NOTIFICATIONS.contains(&kind.as_str()))
+    {
+        return Err(RejectReason::bad_request(format!(
+            "Unknown notification kind: {}",
+            kind
+        )));
+    }
+    let pool = app.pool();
+    set_notification_preferences(&pool, auth_user.id(), &payload)
+        .await
+        .map_err(|_| RejectReason::database("Failed to reach database"))?;
+    let preferences = notification_preferences(&pool, auth_user.id())
+        .await
+        .map_err(|_| RejectReason::database("Failed to reach database"))?;
+    Ok(Json(preferences))
+}
+
+/// Devices the authenticated user stays signed in on through remember-me tokens.
+pub async fn self_sessions_handler<S>(
+    app: State<S>,
+    auth_user: AuthenticatedUser,
+) -> Result<impl IntoResponse, RejectReason>
+where
+    S: HasStore + Clone + Send + Sync + 'static,
+{
+    let pool = app.reader_pool();
+    let sessions = RememberedSession::list(&pool, auth_user.id())
+        .await
+        .map_err(|_| RejectReason::database("Failed to reach database"))?;
+    Ok(Json(sessions))
+}
+
+/// Revoke one of the authenticated user's remember-me tokens. That device has to sign in again
+/// once its current session ends.
+pub async fn revoke_self_session_handler<S>(
+    app: State<S>,
+    auth_user: AuthenticatedUser,
+    Path(session_id): Path<uuid::Uuid>,
+) -> Result<impl IntoResponse, RejectReason>
+where
+    S: HasStore + Clone + Send + Sync + 'static,
+{
+    let pool = app.pool();
+    let revoked = RememberedSession::revoke(&pool, auth_user.id(), session_id)
         .await
         .map_err(|_| RejectReason::database("Failed to reach database"))?;
-    app.announce_user_deactivation(auth_user.id());
+    if !revoked {
+        return Err(RejectReason::not_found("Session not found"));
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(feature = "password-hashing")]
+#[derive(Deserialize)]
+pub struct ReauthenticatePayload {
+    pub password: String,
+}
+
+#[cfg(feature = "password-hashing")]
+impl fmt::Debug for ReauthenticatePayload {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ReauthenticatePayload")
+            .finish_non_exhaustive()
+    }
+}
+
+/// Confirm the authenticated user's local password, so `step_up::RequireFreshAuth` accepts the
+/// session again without a new sign-in. Does not satisfy requirements for MFA. Challenged with
+/// `AuthApp::captcha_verifier` when one is configured, as the password can be guessed here.
+#[cfg(feature = "password-hashing")]
+pub async fn self_reauthenticate_handler<S>(
+    app: State<S>,
+    auth_user: AuthenticatedUser,
+    session: Session,
+    headers: HeaderMap,
+    uri: Uri,
+    Json(payload): Json<ReauthenticatePayload>,
+) -> Result<impl IntoResponse, RejectReason>
+where
+    S: AuthApp + Clone + Send + Sync + 'static,
+{
+    if let Some(verifier) = app.captcha_verifier()
+        && let Err(err) = verify_captcha(verifier, &headers, &uri, None).await
+    {
+        tracing::info!("Rejecting reauthentication of {}: {}", auth_user.id(), err);
+        return Err(RejectReason::captcha_rejected(&err));
+    }
+    let pool = app.pool();
+    let valid = UserPasswordRow::verify(
+        &pool,
+        auth_user.id(),
+        &payload.password,
+        app.password_hasher(),
+    )
+    .await
+    .map_err(|err| {
+        tracing::error!("Failed to check password of {}: {}", auth_user.id(), err);
+        RejectReason::database("Failed to reach database")
+    })?;
+    if !valid {
+        tracing::info!("Reauthentication of {} failed", auth_user.id());
+        return Err(RejectReason::auth(AuthRejectReason::invalid_credentials()));
+    }
+    record_reauthentication(&session, auth_user.id()).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Check `password` against `AuthApp::password_policy` and `AuthApp::breach_checker`, then hash
+/// and store it. Call it from the app's registration and password reset flows.
+#[cfg(feature = "password-hashing")]
+pub async fn set_user_password<S>(
+    app: &S,
+    user: &UserRow,
+    password: &str,
+) -> Result<PasswordStrength, RejectReason>
+where
+    S: AuthApp,
+{
+    let mut user_inputs = vec![user.email.as_str()];
+    user_inputs.extend(user.username.as_deref());
+    let strength = app
+        .password_policy()
+        .validate(password, &user_inputs, app.breach_checker())
+        .await
+        .map_err(|rejection| {
+            tracing::info!("Rejecting new password of {}: {}", user.id, rejection);
+            RejectReason::password_rejected(&rejection)
+        })?;
+    let password_hash = {
+        let hasher = app.password_hasher().clone();
+        let password = password.to_string();
+        tokio::task::spawn_blocking(move || hasher.hash(&password))
+            .await
+            .map_err(|err| RejectReason::anyhow(anyhow::Error::new(err)))?
+            .map_err(|err| RejectReason::anyhow(anyhow::Error::new(err)))?
+    };
+    UserPasswordRow::set(&app.pool(), user.id, &password_hash)
+        .await
+        .map_err(|err| {
+            tracing::error!("Failed to store password of {}: {}", user.id, err);
+            RejectReason::database("Failed to store password")
+        })?;
+    Ok(strength)
+}
+
+#[cfg(feature = "password-hashing")]
+#[derive(Deserialize)]
+pub struct ChangePasswordPayload {
+    pub current_password: String,
+    pub new_password: String,
+}
+
+#[cfg(feature = "password-hashing")]
+impl fmt::Debug for ChangePasswordPayload {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ChangePasswordPayload")
+            .finish_non_exhaustive()
+    }
+}
+
+/// Replace the authenticated user's local password after confirming the current one. The new
+/// password must pass `AuthApp::password_policy`; violations are listed in the error details.
+#[cfg(feature = "password-hashing")]
+pub async fn self_password_handler<S>(
+    app: State<S>,
+    auth_user: AuthenticatedUser,
+    Json(payload): Json<ChangePasswordPayload>,
+) -> Result<impl IntoResponse, RejectReason>
+where
+    S: AuthApp + Clone + Send + Sync + 'static,
+{
+    let pool = app.pool();
+    let user = UserRow::get(&pool, auth_user.id())
+        .await
+        .map_err(|_| RejectReason::database("Failed to reach database"))?
+        .ok_or_else(|| RejectReason::not_found("User not found"))?;
+    let valid = UserPasswordRow::verify(
+        &pool,
+        auth_user.id(),
+        &payload.current_password,
+        app.password_hasher(),
+    )
+    .await
+    .map_err(|err| {
+        tracing::error!("Failed to check password of {}: {}", auth_user.id(), err);
+        RejectReason::database("Failed to reach database")
+    })?;
+    if !valid {
+        tracing::info!("Password change of {} failed", auth_user.id());
+        return Err(RejectReason::auth(AuthRejectReason::invalid_credentials()));
+    }
+    set_user_password(&*app, &user, &payload.new_password).await?;
+    if let Some(notifier) = app.notifier() {
+        notify_security_event(&pool, notifier, user.id, SecurityEvent::PasswordChanged).await;
+    }
     Ok(StatusCode::NO_CONTENT)
 }
 
+#[derive(Debug, Clone, Deserialize)]
+pub struct UserLoginsQuery {
+    pub user_id: UserId,
+    #[serde(flatten)]
+    pub page: PageQuery,
+}
+
+/// Login history for any user. Restricted to super_admin.
+pub async fn user_logins_handler<S>(
+    app: State<S>,
+    auth_user: AuthenticatedUser,
+    Query(query): Query<UserLoginsQuery>,
+) -> Result<impl IntoResponse, RejectReason>
+where
+    S: HasStore + Clone + Send + Sync + 'static,
+{
+    let pool = app.reader_pool();
+    let actor_is_super_admin = is_super_admin(&app.pool(), auth_user.id())
+        .await
+        .map_err(|_| RejectReason::database("Failed to reach database"))?;
+    if !actor_is_super_admin {
+        return Err(RejectReason::forbidden(
+            auth_user.id(),
+            "Only super_admin can read another user's login history",
+        ));
+    }
+    Ok(Json(
+        logins_for_user(&pool, query.user_id, &query.page).await?,
+    ))
+}
+
+/// Maintenance task: deactivate users with no login since `cutoff` and announce each
+/// deactivation.
+///
+/// Run this on a schedule, e.g. with `cutoff = now - 90 days`.
+pub async fn deactivate_dormant_users<S>(
+    app: &S,
+    cutoff: chrono::NaiveDateTime,
+) -> Result<Vec<UserId>, sqlx::Error>
+where
+    S: AuthApp,
+{
+    Ok(deactivate_dormant_users_report(app, cutoff, false)
+        .await?
+        .effects)
+}
+
+/// `deactivate_dormant_users`, optionally as a dry run. Nothing is announced for a dry run.
+pub async fn deactivate_dormant_users_report<S>(
+    app: &S,
+    cutoff: chrono::NaiveDateTime,
+    dry_run: bool,
+) -> Result<BulkReport<Vec<UserId>>, sqlx::Error>
+where
+    S: AuthApp,
+{
+    let pool = app.pool();
+    let report = deactivate_dormant_report(&pool, cutoff, dry_run).await?;
+    if !report.dry_run {
+        for user_id in &report.effects {
+            app.announce_user_deactivation(*user_id);
+        }
+    }
+    Ok(report)
+}
+
+/// Query parameters accepted by bulk admin endpoints.
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+pub struct DryRunQuery {
+    /// Compute the effects and roll them back instead of applying them.
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
 #[derive(Debug, Clone, Deserialize)]
 pub struct LeaveGroupContent {
     pub group_id: GroupId,
@@ -505,9 +2410,6 @@ pub struct LeaveGroupContent {
 }
 
 /// Allow the user to leave a group they are a member of.
-///
-/// We don't have a handler for adding users to groups because an application may want to enforce
-/// invitations or other rules that keep both the group and users safe from abuse.
 pub async fn self_leave_group_handler<S>(
     app: State<S>,
     auth_user: AuthenticatedUser,
@@ -540,6 +2442,14 @@ where
     if !was_member {
         return Ok(StatusCode::NO_CONTENT);
     }
+    require_group_not_deleted(&pool, payload.group_id).await?;
+    require_group_unarchived(&pool, payload.group_id).await?;
+    let change = MembershipChange {
+        actor_user_id: auth_user.id(),
+        group_id: payload.group_id,
+        user_id: auth_user.id(),
+    };
+    app.hooks().before_group_leave(&change).await?;
 
     let inherited_admin = GroupMembershipRow::remove_member_with_inheritance(
         &pool,
@@ -578,36 +2488,3952 @@ where
     }
 
     app.announce_user_group_leave(auth_user.id(), payload.group_id);
+    app.hooks().after_group_leave(&change).await;
     Ok(StatusCode::NO_CONTENT)
 }
 
-pub fn routes<S>(store: MemoryStore) -> Router<S>
-where
-    S: AuthApp + Clone + Send + Sync + 'static,
-{
-    tracing::info!("Registering route /auth/me [GET,PUT]");
-    tracing::info!("Registering route /auth/me/groups [GET]");
-    tracing::info!("Registering route /auth/me/permissions [GET]");
-    tracing::info!("Registering route /auth/me/deactivate [POST]");
-    tracing::info!("Registering route /auth/me/leave [POST]");
-    tracing::info!("Registering route /auth/roles [GET]");
-    tracing::info!("Registering route /auth/roles/grant [POST]");
-    tracing::info!("Registering route /auth/roles/revoke [POST]");
+/// Reject unless the actor is super_admin or an admin of the group.
+/// Capabilities the actor holds in a group. super_admin holds all of them.
+async fn actor_group_capabilities(
+    pool: &sqlx::PgPool,
+    actor_user_id: UserId,
+    group_id: GroupId,
+) -> Result<GroupCapabilities, RejectReason> {
+    if is_super_admin(pool, actor_user_id)
+        .await
+        .map_err(|_| RejectReason::database("Failed to reach database"))?
+    {
+        return Ok(GroupCapabilities::ALL);
+    }
+    group_capabilities(pool, group_id, actor_user_id)
+        .await
+        .map_err(|_| RejectReason::database("Failed to reach database"))
+}
+
+async fn require_group_capability(
+    pool: &sqlx::PgPool,
+    actor_user_id: UserId,
+    group_id: GroupId,
+    capability: GroupCapabilities,
+    reason: &str,
+) -> Result<GroupCapabilities, RejectReason> {
+    let held = actor_group_capabilities(pool, actor_user_id, group_id).await?;
+    if held.contains(capability) {
+        Ok(held)
+    } else {
+        Err(RejectReason::forbidden(actor_user_id, reason))
+    }
+}
+
+/// Reject writes to a soft-deleted group before the schema does. Deleted groups respond `404`
+/// like missing ones.
+async fn require_group_not_deleted(
+    pool: &sqlx::PgPool,
+    group_id: GroupId,
+) -> Result<(), RejectReason> {
+    let deleted = deleted_group(pool, group_id)
+        .await
+        .map_err(|_| RejectReason::database("Failed to reach database"))?;
+    if deleted.is_some() {
+        return Err(RejectReason::not_found("Group not found"));
+    }
+    Ok(())
+}
+
+/// Reject writes to an archived group before the schema does.
+async fn require_group_unarchived(
+    pool: &sqlx::PgPool,
+    group_id: GroupId,
+) -> Result<(), RejectReason> {
+    match group_archival(pool, group_id)
+        .await
+        .map_err(|_| RejectReason::database("Failed to reach database"))?
+    {
+        Some(archival) => Err(RejectReason::group_archived(archival.archived_at)),
+        None => Ok(()),
+    }
+}
+
+const JSON_PATCH_CONTENT_TYPE: &str = "application/json-patch+json";
+const GROUP_DETAILS_PATCH_ATTEMPTS: usize = 3;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct GroupDetailsResponse {
+    pub group_id: GroupId,
+    pub details: Option<Value>,
+    pub version: i64,
+}
+
+/// Patch a group's details. Requires the `edit_details` group capability.
+///
+/// `Content-Type: application/json-patch+json` applies an RFC 6902 patch; any other JSON body is
+/// an RFC 7386 merge patch. With `If-Match` the patch is rejected with `412` if the group changed
+/// since that version; without it, the patch is re-applied to the latest details on conflict.
+pub async fn group_details_patch_handler<S>(
+    app: State<S>,
+    auth_user: AuthenticatedUser,
+    Path(group): Path<GroupRef>,
+    headers: HeaderMap,
+    Json(patch): Json<Value>,
+) -> Result<impl IntoResponse, RejectReason>
+where
+    S: AuthApp + Clone + Send + Sync + 'static,
+{
+    let pool = app.pool();
+    let group_id = group.resolve(&pool).await?;
+    let actor_user_id = auth_user.id();
+    require_group_capability(
+        &pool,
+        actor_user_id,
+        group_id,
+        GroupCapabilities::EDIT_DETAILS,
+        "Only group admins can update group details",
+    )
+    .await?;
+    require_group_not_deleted(&pool, group_id).await?;
+    require_group_unarchived(&pool, group_id).await?;
+
+    let is_json_patch = headers
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with(JSON_PATCH_CONTENT_TYPE));
+    let operations = if is_json_patch {
+        Some(
+            parse_json_patch(patch.clone())
+                .map_err(|err| RejectReason::bad_request(err.to_string()))?,
+        )
+    } else {
+        None
+    };
+    let expected_version = if_match_version(&headers)?;
+
+    for _ in 0..GROUP_DETAILS_PATCH_ATTEMPTS {
+        let group = GroupRow::get(&pool, group_id)
+            .await
+            .map_err(|_| RejectReason::database("Failed to reach database"))?
+            .ok_or_else(|| RejectReason::not_found("Group not found"))?;
+        if expected_version.is_some_and(|expected| expected != group.version) {
+            return Err(RejectReason::precondition_failed(
+                "Group was modified by another request",
+            ));
+        }
+
+        let mut details = group.details.unwrap_or_else(|| json!({}));
+        match &operations {
+            Some(operations) => apply_json_patch(&mut details, operations)
+                .map_err(|err| RejectReason::bad_request(err.to_string()))?,
+            None => apply_merge_patch(&mut details, &patch),
+        }
+        if let Some(validator) = app.details_validator() {
+            validator
+                .validate_group_details(group_id, &details)
+                .map_err(RejectReason::bad_request)?;
+        }
+
+        let version = match GroupRow::set_details_if_version(
+            &pool,
+            group_id,
+            Some(details.clone()),
+            group.version,
+        )
+        .await
+        {
+            Ok(version) => version,
+            Err(VersionConflictError::Conflict { .. }) if expected_version.is_none() => continue,
+            Err(VersionConflictError::Conflict { .. }) => {
+                return Err(RejectReason::precondition_failed(
+                    "Group was modified by another request",
+                ));
+            }
+            Err(VersionConflictError::NotFound) => {
+                return Err(RejectReason::not_found("Group not found"));
+            }
+            Err(VersionConflictError::Database(_)) => {
+                return Err(RejectReason::database("Failed to reach database"));
+            }
+        };
+
+        let update_log = LogRow::new(
+            actor_user_id,
+            json!({
+                "type": "group_details_updated",
+                "group_id": group_id.to_string(),
+                "version": version,
+            }),
+        );
+        LogRow::insert(&pool, &update_log)
+            .await
+            .map_err(|_| RejectReason::database("Failed to reach database"))?;
+
+        return Ok((
+            [(ETAG, etag(version))],
+            Json(GroupDetailsResponse {
+                group_id,
+                details: Some(details),
+                version,
+            }),
+        ));
+    }
+
+    Err(RejectReason::conflict(
+        "Group details are being modified concurrently",
+    ))
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DiscoverableGroup {
+    pub id: GroupId,
+    pub external_id: Option<String>,
+    pub name: String,
+    pub visibility: GroupVisibility,
+    pub archived: bool,
+}
+
+impl From<GroupRow> for DiscoverableGroup {
+    fn from(row: GroupRow) -> Self {
+        Self {
+            id: row.id,
+            external_id: row.external_id,
+            name: row.display_name,
+            visibility: row.visibility,
+            archived: row.archived_at.is_some(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct DiscoverQuery {
+    pub q: Option<String>,
+    /// `exclude` (the default), `include` or `only`.
+    #[serde(default)]
+    pub archived: ArchivedFilter,
+    #[serde(flatten)]
+    pub page: PageQuery,
+}
+
+/// List groups that are open or discoverable, optionally filtered by name. Archived groups, which
+/// cannot be joined, are left out unless `archived` asks for them.
+pub async fn group_discover_handler<S>(
+    app: State<S>,
+    _auth_user: AuthenticatedUser,
+    version: ApiVersion,
+    Query(query): Query<DiscoverQuery>,
+) -> Result<impl IntoResponse, RejectReason>
+where
+    S: HasStore + Clone + Send + Sync + 'static,
+{
+    let pool = app.reader_pool();
+    let search = query
+        .q
+        .as_deref()
+        .map(str::trim)
+        .filter(|search| !search.is_empty());
+    let groups = GroupRow::discoverable(&pool, search, query.archived, Some(query.page.page()))
+        .await
+        .map_err(|_| RejectReason::database("Failed to reach database"))?;
+    let body = match version {
+        ApiVersion::V1 => Json(
+            groups
+                .into_iter()
+                .map(DiscoverableGroup::from)
+                .collect::<Vec<_>>(),
+        )
+        .into_response(),
+        ApiVersion::V2 => {
+            Json(groups.into_iter().map(v2::Group::from).collect::<Vec<_>>()).into_response()
+        }
+    };
+    Ok(body)
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct JoinGroupContent {
+    pub message: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JoinGroupStatus {
+    /// Already a member; nothing changed.
+    Member,
+    /// Joined an open group.
+    Joined,
+    /// A join request is waiting for a group admin.
+    Pending,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct JoinGroupResponse {
+    pub group_id: GroupId,
+    pub status: JoinGroupStatus,
+}
+
+/// Join an open group immediately, or request to join a discoverable one.
+///
+/// Private and inactive groups respond `404` so their existence is not revealed.
+pub async fn group_join_handler<S>(
+    app: State<S>,
+    auth_user: AuthenticatedUser,
+    Path(group): Path<GroupRef>,
+    payload: Option<Json<JoinGroupContent>>,
+) -> Result<impl IntoResponse, RejectReason>
+where
+    S: AuthApp + Clone + Send + Sync + 'static,
+{
+    let pool = app.pool();
+    let group_id = group.resolve(&pool).await?;
+    let user_id = auth_user.id();
+    let group = GroupRow::get_joinable(&pool, group_id)
+        .await
+        .map_err(|_| RejectReason::database("Failed to reach database"))?
+        .ok_or_else(|| RejectReason::not_found("Group not found"))?;
+
+    let is_member = GroupMembershipRow::is_member(&pool, group_id, user_id)
+        .await
+        .map_err(|_| RejectReason::database("Failed to reach database"))?;
+    let (code, status) = if is_member {
+        (StatusCode::OK, JoinGroupStatus::Member)
+    } else if let Some(archived_at) = group.archived_at {
+        return Err(RejectReason::group_archived(archived_at));
+    } else if group.visibility == GroupVisibility::Open {
+        let change = MembershipChange {
+            actor_user_id: user_id,
+            group_id,
+            user_id,
+        };
+        app.hooks().before_group_join(&change).await?;
+        GroupMembershipRow::add_member(
+            &pool,
+            &GroupMembershipRow::new(group_id, user_id, GROUP_MEMBER_ROLE),
+        )
+        .await
+        .map_err(|_| RejectReason::database("Failed to reach database"))?;
+
+        let join_log = LogRow::new(
+            user_id,
+            json!({
+                "type": "group_join",
+                "group_id": group_id.to_string(),
+                "user_id": user_id.to_string(),
+            }),
+        );
+        LogRow::insert(&pool, &join_log)
+            .await
+            .map_err(|_| RejectReason::database("Failed to reach database"))?;
+
+        app.announce_user_group_join(user_id, group_id);
+        app.hooks().after_group_join(&change).await;
+        (StatusCode::OK, JoinGroupStatus::Joined)
+    } else {
+        let message = payload.and_then(|Json(payload)| payload.message);
+        GroupJoinRequestRow::request(&pool, group_id, user_id, message.as_deref())
+            .await
+            .map_err(|_| RejectReason::database("Failed to reach database"))?;
+        (StatusCode::ACCEPTED, JoinGroupStatus::Pending)
+    };
+
+    Ok((code, Json(JoinGroupResponse { group_id, status })))
+}
+
+/// Pending join requests for a group. Requires the `approve_join_requests` group capability.
+pub async fn group_join_requests_handler<S>(
+    app: State<S>,
+    auth_user: AuthenticatedUser,
+    Path(group): Path<GroupRef>,
+    Query(page): Query<PageQuery>,
+) -> Result<impl IntoResponse, RejectReason>
+where
+    S: HasStore + Clone + Send + Sync + 'static,
+{
+    let pool = app.pool();
+    let group_id = group.resolve(&pool).await?;
+    require_group_capability(
+        &pool,
+        auth_user.id(),
+        group_id,
+        GroupCapabilities::APPROVE_JOIN_REQUESTS,
+        "Only group admins can view join requests",
+    )
+    .await?;
+    let requests =
+        GroupJoinRequestRow::pending_for_group(&app.reader_pool(), group_id, Some(page.page()))
+            .await
+            .map_err(|_| RejectReason::database("Failed to reach database"))?;
+    Ok(Json(requests))
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct JoinRequestDecisionContent {
+    pub approve: bool,
+}
+
+/// Approve or reject a pending join request. Requires the `approve_join_requests` group
+/// capability.
+pub async fn group_join_request_decision_handler<S>(
+    app: State<S>,
+    auth_user: AuthenticatedUser,
+    Path((group, user)): Path<(GroupRef, UserRef)>,
+    Json(payload): Json<JoinRequestDecisionContent>,
+) -> Result<impl IntoResponse, RejectReason>
+where
+    S: AuthApp + Clone + Send + Sync + 'static,
+{
+    let pool = app.pool();
+    let group_id = group.resolve(&pool).await?;
+    let user_id = user.resolve(&pool).await?;
+    require_group_capability(
+        &pool,
+        auth_user.id(),
+        group_id,
+        GroupCapabilities::APPROVE_JOIN_REQUESTS,
+        "Only group admins can decide join requests",
+    )
+    .await?;
+    require_group_not_deleted(&pool, group_id).await?;
+    require_group_unarchived(&pool, group_id).await?;
+    let change = MembershipChange {
+        actor_user_id: auth_user.id(),
+        group_id,
+        user_id,
+    };
+    if payload.approve {
+        app.hooks().before_group_join(&change).await?;
+    }
+
+    let decided =
+        GroupJoinRequestRow::decide(&pool, group_id, user_id, payload.approve, auth_user.id())
+            .await
+            .map_err(|_| RejectReason::database("Failed to reach database"))?;
+    if !decided {
+        return Err(RejectReason::not_found("Pending join request not found"));
+    }
+
+    if payload.approve {
+        invalidate_role_snapshots(user_id);
+        app.announce_user_group_join(user_id, group_id);
+        app.hooks().after_group_join(&change).await;
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct GroupVisibilityContent {
+    pub visibility: GroupVisibility,
+}
+
+/// Change whether a group can be discovered and joined. Requires the `edit_details` group
+/// capability.
+pub async fn group_visibility_handler<S>(
+    app: State<S>,
+    auth_user: AuthenticatedUser,
+    Path(group): Path<GroupRef>,
+    Json(payload): Json<GroupVisibilityContent>,
+) -> Result<impl IntoResponse, RejectReason>
+where
+    S: HasStore + Clone + Send + Sync + 'static,
+{
+    let pool = app.pool();
+    let group_id = group.resolve(&pool).await?;
+    require_group_capability(
+        &pool,
+        auth_user.id(),
+        group_id,
+        GroupCapabilities::EDIT_DETAILS,
+        "Only group admins can change group visibility",
+    )
+    .await?;
+    require_group_not_deleted(&pool, group_id).await?;
+    require_group_unarchived(&pool, group_id).await?;
+
+    let updated = GroupRow::set_visibility(&pool, group_id, payload.visibility)
+        .await
+        .map_err(|_| RejectReason::database("Failed to reach database"))?;
+    if !updated {
+        return Err(RejectReason::not_found("Group not found"));
+    }
+
+    let visibility_log = LogRow::new(
+        auth_user.id(),
+        json!({
+            "type": "group_visibility_changed",
+            "group_id": group_id.to_string(),
+            "visibility": payload.visibility,
+        }),
+    );
+    LogRow::insert(&pool, &visibility_log)
+        .await
+        .map_err(|_| RejectReason::database("Failed to reach database"))?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Change the id a group is addressed by in URLs. Requires the `edit_details` group capability.
+/// The previous external id stops resolving immediately.
+pub async fn group_external_id_handler<S>(
+    app: State<S>,
+    auth_user: AuthenticatedUser,
+    Path(group): Path<GroupRef>,
+    Json(payload): Json<ExternalIdContent>,
+) -> Result<impl IntoResponse, RejectReason>
+where
+    S: HasStore + Clone + Send + Sync + 'static,
+{
+    let pool = app.pool();
+    let group_id = group.resolve(&pool).await?;
+    require_group_capability(
+        &pool,
+        auth_user.id(),
+        group_id,
+        GroupCapabilities::EDIT_DETAILS,
+        "Only group admins can change a group's external id",
+    )
+    .await?;
+    require_group_not_deleted(&pool, group_id).await?;
+    require_group_unarchived(&pool, group_id).await?;
+
+    let previous = GroupRow::set_external_id(&pool, group_id, &payload.external_id)
+        .await
+        .map_err(|err| external_id_rejection(err, "Group"))?;
+    let log = LogRow::new(
+        auth_user.id(),
+        json!({
+            "type": "external_id_changed",
+            "group_id": group_id.to_string(),
+            "from": previous,
+            "to": payload.external_id,
+        }),
+    );
+    LogRow::insert(&pool, &log)
+        .await
+        .map_err(|_| RejectReason::database("Failed to reach database"))?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct GroupRoleDefinition {
+    pub role_name: String,
+    pub capabilities: Vec<&'static str>,
+    pub description: Option<String>,
+    pub builtin: bool,
+}
+
+impl GroupRoleDefinition {
+    fn builtin(role_name: &str, description: &str) -> Self {
+        Self {
+            role_name: role_name.to_string(),
+            capabilities: GroupCapabilities::builtin(role_name)
+                .unwrap_or_default()
+                .names(),
+            description: Some(description.to_string()),
+            builtin: true,
+        }
+    }
+}
+
+impl From<GroupRoleDefinitionRow> for GroupRoleDefinition {
+    fn from(row: GroupRoleDefinitionRow) -> Self {
+        Self {
+            capabilities: row.capabilities().names(),
+            role_name: row.role_name,
+            description: row.description,
+            builtin: false,
+        }
+    }
+}
+
+/// Roles members of a group can hold: the built-in roles followed by the group's custom roles.
+/// Requires the `view_members` group capability.
+pub async fn group_roles_handler<S>(
+    app: State<S>,
+    auth_user: AuthenticatedUser,
+    Path(group): Path<GroupRef>,
+) -> Result<impl IntoResponse, RejectReason>
+where
+    S: HasStore + Clone + Send + Sync + 'static,
+{
+    let pool = app.reader_pool();
+    let group_id = group.resolve(&pool).await?;
+    require_group_capability(
+        &app.pool(),
+        auth_user.id(),
+        group_id,
+        GroupCapabilities::VIEW_MEMBERS,
+        "Only group members can view group roles",
+    )
+    .await?;
+
+    let custom = GroupRoleDefinitionRow::for_group(&pool, group_id)
+        .await
+        .map_err(|_| RejectReason::database("Failed to reach database"))?;
+    let mut roles = vec![
+        GroupRoleDefinition::builtin(GROUP_ADMIN_ROLE, "Full control of the group"),
+        GroupRoleDefinition::builtin(GROUP_MEMBER_ROLE, "Default role for new members"),
+    ];
+    roles.extend(custom.into_iter().map(GroupRoleDefinition::from));
+    Ok(Json(roles))
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct GroupRoleDefinitionContent {
+    pub capabilities: Vec<String>,
+    #[serde(default)]
+    pub description: Option<String>,
+}
+
+/// Create or update a custom group role. Requires the `manage_roles` group capability; an actor
+/// cannot define a role with capabilities they do not hold themselves.
+pub async fn group_role_define_handler<S>(
+    app: State<S>,
+    auth_user: AuthenticatedUser,
+    Path((group, role_name)): Path<(GroupRef, String)>,
+    Json(payload): Json<GroupRoleDefinitionContent>,
+) -> Result<impl IntoResponse, RejectReason>
+where
+    S: HasStore + Clone + Send + Sync + 'static,
+{
+    let pool = app.pool();
+    let group_id = group.resolve(&pool).await?;
+    let actor_user_id = auth_user.id();
+    let held = require_group_capability(
+        &pool,
+        actor_user_id,
+        group_id,
+        GroupCapabilities::MANAGE_ROLES,
+        "Only group admins can manage group roles",
+    )
+    .await?;
+    require_group_not_deleted(&pool, group_id).await?;
+    require_group_unarchived(&pool, group_id).await?;
+
+    let role_name = role_name.trim();
+    if role_name.is_empty() {
+        return Err(RejectReason::bad_request("Role name must not be empty"));
+    }
+    if GroupCapabilities::builtin(role_name).is_some() {
+        return Err(RejectReason::bad_request(format!(
+            "{} is a built-in role and cannot be redefined",
+            role_name
+        )));
+    }
+    let capabilities = GroupCapabilities::from_names(&payload.capabilities)
+        .map_err(|name| RejectReason::bad_request(format!("Unknown capability {}", name)))?;
+    if !held.contains(capabilities) {
+        return Err(RejectReason::forbidden(
+            actor_user_id,
+            "Cannot grant capabilities you do not hold",
+        ));
+    }
+
+    let row = GroupRoleDefinitionRow::new(
+        group_id,
+        role_name,
+        capabilities,
+        payload.description.clone(),
+    );
+    GroupRoleDefinitionRow::upsert(&pool, &row)
+        .await
+        .map_err(|_| RejectReason::database("Failed to reach database"))?;
+
+    let role_log = LogRow::new(
+        actor_user_id,
+        json!({
+            "type": "group_role_defined",
+            "group_id": group_id.to_string(),
+            "role_name": role_name,
+            "capabilities": capabilities.names(),
+        }),
+    );
+    LogRow::insert(&pool, &role_log)
+        .await
+        .map_err(|_| RejectReason::database("Failed to reach database"))?;
+
+    Ok(Json(GroupRoleDefinition::from(row)))
+}
+
+/// Delete a custom group role. Fails with `409` while any member still holds it. Requires the
+/// `manage_roles` group capability.
+pub async fn group_role_delete_handler<S>(
+    app: State<S>,
+    auth_user: AuthenticatedUser,
+    Path((group, role_name)): Path<(GroupRef, String)>,
+) -> Result<impl IntoResponse, RejectReason>
+where
+    S: HasStore + Clone + Send + Sync + 'static,
+{
+    let pool = app.pool();
+    let group_id = group.resolve(&pool).await?;
+    let actor_user_id = auth_user.id();
+    require_group_capability(
+        &pool,
+        actor_user_id,
+        group_id,
+        GroupCapabilities::MANAGE_ROLES,
+        "Only group admins can manage group roles",
+    )
+    .await?;
+    require_group_not_deleted(&pool, group_id).await?;
+    require_group_unarchived(&pool, group_id).await?;
+
+    if GroupCapabilities::builtin(&role_name).is_some() {
+        return Err(RejectReason::bad_request(format!(
+            "{} is a built-in role and cannot be deleted",
+            role_name
+        )));
+    }
+    let deleted = GroupRoleDefinitionRow::delete_unused(&pool, group_id, &role_name)
+        .await
+        .map_err(|_| RejectReason::database("Failed to reach database"))?;
+    if !deleted {
+        let exists = GroupRoleDefinitionRow::get(&pool, group_id, &role_name)
+            .await
+            .map_err(|_| RejectReason::database("Failed to reach database"))?
+            .is_some();
+        return Err(if exists {
+            RejectReason::conflict("Role is still assigned to members")
+        } else {
+            RejectReason::not_found("Group role not found")
+        });
+    }
+
+    let role_log = LogRow::new(
+        actor_user_id,
+        json!({
+            "type": "group_role_deleted",
+            "group_id": group_id.to_string(),
+            "role_name": role_name,
+        }),
+    );
+    LogRow::insert(&pool, &role_log)
+        .await
+        .map_err(|_| RejectReason::database("Failed to reach database"))?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct MemberRoleContent {
+    pub role_name: String,
+}
+
+/// Change a member's role within a group. Requires the `manage_roles` group capability, and the
+/// actor must hold every capability of both the member's current role and the new one.
+pub async fn group_member_role_handler<S>(
+    app: State<S>,
+    auth_user: AuthenticatedUser,
+    Path((group, user)): Path<(GroupRef, UserRef)>,
+    Json(payload): Json<MemberRoleContent>,
+) -> Result<impl IntoResponse, RejectReason>
+where
+    S: HasStore + Clone + Send + Sync + 'static,
+{
+    let pool = app.pool();
+    let group_id = group.resolve(&pool).await?;
+    let user_id = user.resolve(&pool).await?;
+    let actor_user_id = auth_user.id();
+    let held = require_group_capability(
+        &pool,
+        actor_user_id,
+        group_id,
+        GroupCapabilities::MANAGE_ROLES,
+        "Only group admins can change member roles",
+    )
+    .await?;
+    require_group_not_deleted(&pool, group_id).await?;
+    require_group_unarchived(&pool, group_id).await?;
+
+    let new_capabilities = match GroupCapabilities::builtin(&payload.role_name) {
+        Some(capabilities) => capabilities,
+        None => GroupRoleDefinitionRow::get(&pool, group_id, &payload.role_name)
+            .await
+            .map_err(|_| RejectReason::database("Failed to reach database"))?
+            .ok_or_else(|| RejectReason::bad_request("Role is not defined for this group"))?
+            .capabilities(),
+    };
+    let current_capabilities = group_capabilities(&pool, group_id, user_id)
+        .await
+        .map_err(|_| RejectReason::database("Failed to reach database"))?;
+    if !held.contains(new_capabilities.union(current_capabilities)) {
+        return Err(RejectReason::forbidden(
+            actor_user_id,
+            "Cannot change roles with capabilities you do not hold",
+        ));
+    }
+
+    let updated = GroupMembershipRow::set_role(&pool, group_id, user_id, &payload.role_name)
+        .await
+        .map_err(|_| RejectReason::database("Failed to reach database"))?;
+    if !updated {
+        return Err(RejectReason::not_found("Group member not found"));
+    }
+
+    let role_log = LogRow::new(
+        actor_user_id,
+        json!({
+            "type": "group_member_role_changed",
+            "group_id": group_id.to_string(),
+            "user_id": user_id.to_string(),
+            "role_name": payload.role_name,
+        }),
+    );
+    LogRow::insert(&pool, &role_log)
+        .await
+        .map_err(|_| RejectReason::database("Failed to reach database"))?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct AddMemberContent {
+    pub user_id: UserRef,
+    /// Defaults to `member`.
+    pub role_name: Option<String>,
+}
+
+/// Add a user to a group. Requires the `invite_members` group capability; a role other than
+/// `member` also needs `manage_roles` and every capability of the role. `AuthApp::hooks` can veto
+/// the join.
+pub async fn group_member_add_handler<S>(
+    app: State<S>,
+    auth_user: AuthenticatedUser,
+    Path(group): Path<GroupRef>,
+    Json(payload): Json<AddMemberContent>,
+) -> Result<impl IntoResponse, RejectReason>
+where
+    S: AuthApp + Clone + Send + Sync + 'static,
+{
+    let pool = app.pool();
+    let group_id = group.resolve(&pool).await?;
+    let user_id = payload.user_id.resolve(&pool).await?;
+    let actor_user_id = auth_user.id();
+    let held = require_group_capability(
+        &pool,
+        actor_user_id,
+        group_id,
+        GroupCapabilities::INVITE_MEMBERS,
+        "Only group admins and members with invite_members can add members",
+    )
+    .await?;
+    require_group_not_deleted(&pool, group_id).await?;
+    require_group_unarchived(&pool, group_id).await?;
+
+    let role_name = payload
+        .role_name
+        .as_deref()
+        .map(str::trim)
+        .unwrap_or(GROUP_MEMBER_ROLE)
+        .to_string();
+    if role_name != GROUP_MEMBER_ROLE {
+        let role_capabilities = match GroupCapabilities::builtin(&role_name) {
+            Some(capabilities) => capabilities,
+            None => GroupRoleDefinitionRow::get(&pool, group_id, &role_name)
+                .await
+                .map_err(|_| RejectReason::database("Failed to reach database"))?
+                .ok_or_else(|| RejectReason::bad_request("Role is not defined for this group"))?
+                .capabilities(),
+        };
+        if !held.contains(GroupCapabilities::MANAGE_ROLES.union(role_capabilities)) {
+            return Err(RejectReason::forbidden(
+                actor_user_id,
+                "Cannot add members with roles you cannot grant",
+            ));
+        }
+    }
+
+    UserRow::get(&pool, user_id)
+        .await
+        .map_err(|_| RejectReason::database("Failed to reach database"))?
+        .ok_or_else(|| RejectReason::not_found("User not found"))?;
+    let is_member = GroupMembershipRow::is_member(&pool, group_id, user_id)
+        .await
+        .map_err(|_| RejectReason::database("Failed to reach database"))?;
+    if is_member {
+        return Err(RejectReason::conflict("User is already a group member"));
+    }
+
+    let change = MembershipChange {
+        actor_user_id,
+        group_id,
+        user_id,
+    };
+    app.hooks().before_group_join(&change).await?;
+    GroupMembershipRow::add_member(
+        &pool,
+        &GroupMembershipRow::new(group_id, user_id, &role_name),
+    )
+    .await
+    .map_err(|_| RejectReason::database("Failed to reach database"))?;
+
+    let join_log = LogRow::new(
+        actor_user_id,
+        json!({
+            "type": "group_join",
+            "group_id": group_id.to_string(),
+            "user_id": user_id.to_string(),
+            "role_name": role_name,
+        }),
+    );
+    LogRow::insert(&pool, &join_log)
+        .await
+        .map_err(|_| RejectReason::database("Failed to reach database"))?;
+
+    app.announce_user_group_join(user_id, group_id);
+    app.hooks().after_group_join(&change).await;
+    Ok(StatusCode::CREATED)
+}
+
+/// Remove a member from a group. Requires the `remove_members` group capability and every
+/// capability the member holds, so a delegate cannot remove an admin. Members leave on their own
+/// through `self_leave_group_handler`.
+pub async fn group_member_remove_handler<S>(
+    app: State<S>,
+    auth_user: AuthenticatedUser,
+    Path((group, user)): Path<(GroupRef, UserRef)>,
+) -> Result<impl IntoResponse, RejectReason>
+where
+    S: AuthApp + Clone + Send + Sync + 'static,
+{
+    let pool = app.pool();
+    let group_id = group.resolve(&pool).await?;
+    let user_id = user.resolve(&pool).await?;
+    let actor_user_id = auth_user.id();
+    let held = require_group_capability(
+        &pool,
+        actor_user_id,
+        group_id,
+        GroupCapabilities::REMOVE_MEMBERS,
+        "Only group admins and members with remove_members can remove members",
+    )
+    .await?;
+    require_group_not_deleted(&pool, group_id).await?;
+    require_group_unarchived(&pool, group_id).await?;
+    if user_id == actor_user_id {
+        return Err(RejectReason::bad_request(
+            "Use /auth/me/leave to leave a group",
+        ));
+    }
+
+    let is_member = GroupMembershipRow::is_member(&pool, group_id, user_id)
+        .await
+        .map_err(|_| RejectReason::database("Failed to reach database"))?;
+    if !is_member {
+        return Err(RejectReason::not_found("Group member not found"));
+    }
+    let member_capabilities = group_capabilities(&pool, group_id, user_id)
+        .await
+        .map_err(|_| RejectReason::database("Failed to reach database"))?;
+    if !held.contains(member_capabilities) {
+        return Err(RejectReason::forbidden(
+            actor_user_id,
+            "Cannot remove members with capabilities you do not hold",
+        ));
+    }
+
+    let change = MembershipChange {
+        actor_user_id,
+        group_id,
+        user_id,
+    };
+    app.hooks().before_group_leave(&change).await?;
+    let inherited_admin =
+        GroupMembershipRow::remove_member_with_inheritance(&pool, group_id, user_id, None)
+            .await
+            .map_err(|_| RejectReason::database("Failed to reach database"))?;
+
+    let leave_log = LogRow::new(
+        actor_user_id,
+        json!({
+            "type": "group_leave",
+            "group_id": group_id.to_string(),
+            "user_id": user_id.to_string(),
+        }),
+    );
+    LogRow::insert(&pool, &leave_log)
+        .await
+        .map_err(|_| RejectReason::database("Failed to reach database"))?;
+    if let Some(inherited_admin_user_id) = inherited_admin {
+        let inheritance_log = LogRow::new(
+            actor_user_id,
+            json!({
+                "type": "group_admin_inherited",
+                "group_id": group_id.to_string(),
+                "from_user_id": user_id.to_string(),
+                "to_user_id": inherited_admin_user_id.to_string(),
+            }),
+        );
+        LogRow::insert(&pool, &inheritance_log)
+            .await
+            .map_err(|_| RejectReason::database("Failed to reach database"))?;
+    }
+
+    app.announce_user_group_leave(user_id, group_id);
+    app.hooks().after_group_leave(&change).await;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct MemberCapabilitiesContent {
+    pub capabilities: Vec<String>,
+}
+
+/// Delegate capabilities to a member on top of their role's, e.g. `["invite_members"]` for a
+/// member who may add others but not remove them. Replaces what was delegated before; an empty
+/// list takes it all back. Requires the `manage_roles` group capability, and the actor must hold
+/// every capability given or taken away.
+pub async fn group_member_capabilities_handler<S>(
+    app: State<S>,
+    auth_user: AuthenticatedUser,
+    Path((group, user)): Path<(GroupRef, UserRef)>,
+    Json(payload): Json<MemberCapabilitiesContent>,
+) -> Result<impl IntoResponse, RejectReason>
+where
+    S: HasStore + Clone + Send + Sync + 'static,
+{
+    let pool = app.pool();
+    let group_id = group.resolve(&pool).await?;
+    let user_id = user.resolve(&pool).await?;
+    let actor_user_id = auth_user.id();
+    let held = require_group_capability(
+        &pool,
+        actor_user_id,
+        group_id,
+        GroupCapabilities::MANAGE_ROLES,
+        "Only group admins can delegate capabilities",
+    )
+    .await?;
+    require_group_not_deleted(&pool, group_id).await?;
+    require_group_unarchived(&pool, group_id).await?;
+
+    let capabilities = GroupCapabilities::from_names(&payload.capabilities)
+        .map_err(|name| RejectReason::bad_request(format!("Unknown capability {}", name)))?;
+    let current = GroupMembershipRow::delegated_capabilities(&pool, group_id, user_id)
+        .await
+        .map_err(|_| RejectReason::database("Failed to reach database"))?
+        .ok_or_else(|| RejectReason::not_found("Group member not found"))?;
+    if !held.contains(capabilities.union(current)) {
+        return Err(RejectReason::forbidden(
+            actor_user_id,
+            "Cannot delegate capabilities you do not hold",
+        ));
+    }
+
+    let updated = GroupMembershipRow::set_capabilities(&pool, group_id, user_id, capabilities)
+        .await
+        .map_err(|_| RejectReason::database("Failed to reach database"))?;
+    if !updated {
+        return Err(RejectReason::not_found("Group member not found"));
+    }
+
+    let capabilities_log = LogRow::new(
+        actor_user_id,
+        json!({
+            "type": "group_member_capabilities_changed",
+            "group_id": group_id.to_string(),
+            "user_id": user_id.to_string(),
+            "capabilities": capabilities.names(),
+        }),
+    );
+    LogRow::insert(&pool, &capabilities_log)
+        .await
+        .map_err(|_| RejectReason::database("Failed to reach database"))?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct SyncMemberContent {
+    pub user_id: UserRef,
+    pub role_name: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct MembersSyncContent {
+    /// Every member the group should have, with their role. Must include an admin.
+    pub members: Vec<SyncMemberContent>,
+}
+
+/// Make a group's members exactly the given list, adding, removing and changing roles in one
+/// transaction, and return what changed. `?dry_run=true` returns the changes without making them.
+///
+/// Requires the `invite_members`, `remove_members` and `manage_roles` group capabilities, and the
+/// actor must hold every capability of each role given or taken away. `AuthApp::hooks` can veto
+/// each join and leave; they are checked against the changes as previewed before the transaction.
+pub async fn group_members_sync_handler<S>(
+    app: State<S>,
+    auth_user: AuthenticatedUser,
+    Path(group): Path<GroupRef>,
+    Query(query): Query<DryRunQuery>,
+    Json(payload): Json<MembersSyncContent>,
+) -> Result<Json<MembershipDiff>, RejectReason>
+where
+    S: AuthApp + Clone + Send + Sync + 'static,
+{
+    let pool = app.pool();
+    let group_id = group.resolve(&pool).await?;
+    let actor_user_id = auth_user.id();
+    let held = require_group_capability(
+        &pool,
+        actor_user_id,
+        group_id,
+        GroupCapabilities::INVITE_MEMBERS
+            .union(GroupCapabilities::REMOVE_MEMBERS)
+            .union(GroupCapabilities::MANAGE_ROLES),
+        "Only group admins can sync members",
+    )
+    .await?;
+    require_group_not_deleted(&pool, group_id).await?;
+    require_group_unarchived(&pool, group_id).await?;
+
+    let mut desired = Vec::with_capacity(payload.members.len());
+    for member in payload.members {
+        desired.push(MemberRole {
+            user_id: member.user_id.resolve(&pool).await?,
+            role_name: member.role_name.trim().to_string(),
+        });
+    }
+    let preview = preview_membership_sync(&pool, group_id, &desired)
+        .await
+        .map_err(membership_sync_rejection)?;
+
+    let mut role_capabilities: HashMap<&str, GroupCapabilities> = HashMap::new();
+    let touched_roles = preview
+        .added
+        .iter()
+        .chain(&preview.removed)
+        .map(|member| member.role_name.as_str())
+        .chain(
+            preview
+                .role_changes
+                .iter()
+                .flat_map(|change| [change.from.as_str(), change.to.as_str()]),
+        );
+    for role_name in touched_roles {
+        if role_capabilities.contains_key(role_name) {
+            continue;
+        }
+        let capabilities = match GroupCapabilities::builtin(role_name) {
+            Some(capabilities) => capabilities,
+            None => GroupRoleDefinitionRow::get(&pool, group_id, role_name)
+                .await
+                .map_err(|_| RejectReason::database("Failed to reach database"))?
+                .ok_or_else(|| {
+                    RejectReason::bad_request(format!(
+                        "Role {role_name} is not defined for this group"
+                    ))
+                })?
+                .capabilities(),
+        };
+        role_capabilities.insert(role_name, capabilities);
+    }
+    let needed = role_capabilities
+        .values()
+        .fold(GroupCapabilities::NONE, |needed, capabilities| {
+            needed.union(*capabilities)
+        });
+    if !held.contains(needed) {
+        return Err(RejectReason::forbidden(
+            actor_user_id,
+            "Cannot change roles with capabilities you do not hold",
+        ));
+    }
+    if query.dry_run {
+        return Ok(Json(preview));
+    }
+
+    let membership_change = |user_id| MembershipChange {
+        actor_user_id,
+        group_id,
+        user_id,
+    };
+    for member in &preview.added {
+        app.hooks()
+            .before_group_join(&membership_change(member.user_id))
+            .await?;
+    }
+    for member in &preview.removed {
+        app.hooks()
+            .before_group_leave(&membership_change(member.user_id))
+            .await?;
+    }
+
+    let diff = sync_group_members(&pool, actor_user_id, group_id, &desired)
+        .await
+        .map_err(membership_sync_rejection)?;
+    for user_id in diff.affected_users() {
+        invalidate_role_snapshots(user_id);
+    }
+    for member in &diff.added {
+        app.announce_user_group_join(member.user_id, group_id);
+        app.hooks()
+            .after_group_join(&membership_change(member.user_id))
+            .await;
+    }
+    for member in &diff.removed {
+        app.announce_user_group_leave(member.user_id, group_id);
+        app.hooks()
+            .after_group_leave(&membership_change(member.user_id))
+            .await;
+    }
+    Ok(Json(diff))
+}
+
+fn membership_sync_rejection(err: MembershipSyncError) -> RejectReason {
+    match err {
+        MembershipSyncError::GroupNotFound => RejectReason::not_found("Group not found"),
+        MembershipSyncError::Archived(archived_at) => RejectReason::group_archived(archived_at),
+        MembershipSyncError::Database(_) => RejectReason::database("Failed to reach database"),
+        err => RejectReason::bad_request(err.to_string()),
+    }
+}
+
+/// Grants the group hands to its members. Requires the `view_members` group capability.
+pub async fn group_default_roles_handler<S>(
+    app: State<S>,
+    auth_user: AuthenticatedUser,
+    Path(group): Path<GroupRef>,
+) -> Result<impl IntoResponse, RejectReason>
+where
+    S: HasStore + Clone + Send + Sync + 'static,
+{
+    let pool = app.reader_pool();
+    let group_id = group.resolve(&pool).await?;
+    require_group_capability(
+        &app.pool(),
+        auth_user.id(),
+        group_id,
+        GroupCapabilities::VIEW_MEMBERS,
+        "Only group members can view default roles",
+    )
+    .await?;
+
+    let defaults = GroupDefaultRoleRow::for_group(&pool, group_id)
+        .await
+        .map_err(|_| RejectReason::database("Failed to reach database"))?;
+    Ok(Json(defaults))
+}
+
+/// Checks shared by adding and removing a group default role: the actor needs the
+/// `manage_roles` group capability and permission to manage the role itself.
+async fn authorize_group_default_role(
+    pool: &sqlx::PgPool,
+    actor_user_id: UserId,
+    group_id: GroupId,
+    scope: &str,
+    scope_id: &str,
+    role_name: &str,
+) -> Result<(), RejectReason> {
+    require_group_capability(
+        pool,
+        actor_user_id,
+        group_id,
+        GroupCapabilities::MANAGE_ROLES,
+        "Only group admins can manage default roles",
+    )
+    .await?;
+    if !is_valid_scope_id_pattern(scope_id) {
+        return Err(RejectReason::bad_request(
+            "scope_id may only use * as its last character",
+        ));
+    }
+    if role_name == SUPER_ADMIN_ROLE {
+        return Err(RejectReason::bad_request(
+            "super_admin cannot be a group default role",
+        ));
+    }
+
+    let actor_is_super_admin = is_super_admin(pool, actor_user_id)
+        .await
+        .map_err(|_| RejectReason::database("Failed to reach database"))?;
+    let can_manage = actor_is_super_admin
+        || can_manage_role_assignment(pool, actor_user_id, scope, scope_id, role_name)
+            .await
+            .map_err(|_| RejectReason::database("Failed to reach database"))?;
+    if !can_manage {
+        return Err(RejectReason::forbidden(
+            actor_user_id,
+            "Missing delegated role-management permission for this scope",
+        ));
+    }
+    Ok(())
+}
+
+/// Add a default role to a group and grant it to every current member. Requires the
+/// `manage_roles` group capability and permission to grant the role.
+pub async fn group_default_role_add_handler<S>(
+    app: State<S>,
+    auth_user: AuthenticatedUser,
+    Path((group, scope, scope_id, role_name)): Path<(GroupRef, String, String, String)>,
+) -> Result<impl IntoResponse, RejectReason>
+where
+    S: HasStore + Clone + Send + Sync + 'static,
+{
+    validate_scope(&scope).map_err(RejectReason::bad_request)?;
+    let pool = app.pool();
+    let group_id = group.resolve(&pool).await?;
+    let actor_user_id = auth_user.id();
+    authorize_group_default_role(
+        &pool,
+        actor_user_id,
+        group_id,
+        &scope,
+        &scope_id,
+        &role_name,
+    )
+    .await?;
+    require_group_not_deleted(&pool, group_id).await?;
+    require_group_unarchived(&pool, group_id).await?;
+
+    let row = GroupDefaultRoleRow::new(group_id, &scope, &scope_id, &role_name);
+    let changed = GroupDefaultRoleRow::add(&pool, actor_user_id, &row)
+        .await
+        .map_err(|_| RejectReason::database("Failed to reach database"))?;
+    Ok(Json(RoleChangeResult { changed }))
+}
+
+/// Remove a default role from a group and revoke it from members who hold it only because of the
+/// group. Requires the `manage_roles` group capability and permission to manage the role.
+pub async fn group_default_role_remove_handler<S>(
+    app: State<S>,
+    auth_user: AuthenticatedUser,
+    Path((group, scope, scope_id, role_name)): Path<(GroupRef, String, String, String)>,
+) -> Result<impl IntoResponse, RejectReason>
+where
+    S: HasStore + Clone + Send + Sync + 'static,
+{
+    let pool = app.pool();
+    let group_id = group.resolve(&pool).await?;
+    let actor_user_id = auth_user.id();
+    authorize_group_default_role(
+        &pool,
+        actor_user_id,
+        group_id,
+        &scope,
+        &scope_id,
+        &role_name,
+    )
+    .await?;
+    require_group_not_deleted(&pool, group_id).await?;
+    require_group_unarchived(&pool, group_id).await?;
+
+    let row = GroupDefaultRoleRow::new(group_id, &scope, &scope_id, &role_name);
+    let changed = GroupDefaultRoleRow::remove(&pool, actor_user_id, &row)
+        .await
+        .map_err(|_| RejectReason::database("Failed to reach database"))?;
+    Ok(Json(RoleChangeResult { changed }))
+}
+
+/// Cheap liveness probe; does not touch the database.
+pub async fn health_handler() -> impl IntoResponse {
+    Json(json!({"status": "ok"}))
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ReadinessResponse {
+    pub ready: bool,
+    pub database: bool,
+    pub pending_migrations: Option<usize>,
+    pub session_store: bool,
+}
+
+/// Readiness probe: checks database connectivity, pending migrations, and the session store.
+///
+/// Responds with 503 if any check fails.
+pub async fn ready_handler<S, Store>(app: State<S>, store: Store) -> impl IntoResponse
+where
+    S: HasStore + Clone + Send + Sync + 'static,
+    Store: SessionStore,
+{
+    let pool = app.pool();
+    let health = health_check(&pool).await;
+    let session_store = match store.load(&Id::default()).await {
+        Ok(_) => true,
+        Err(err) => {
+            tracing::warn!("Session store unreachable: {}", err);
+            false
+        }
+    };
+    let ready = health.is_ready() && session_store;
+    let status = if ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (
+        status,
+        Json(ReadinessResponse {
+            ready,
+            database: health.database,
+            pending_migrations: health.pending_migrations,
+            session_store,
+        }),
+    )
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SchemaStatusResponse {
+    /// Highest successfully applied migration version, if any.
+    pub version: Option<i64>,
+    pub applied: Vec<AppliedMigration>,
+    pub pending: Vec<MigrationInfo>,
+}
+
+/// Report the auth schema version and any pending migrations. Restricted to super_admin.
+///
+/// Migrations are never run from here; use `create_user_tables` or `migrate_to` from an
+/// operator-controlled process.
+pub async fn schema_status_handler<S>(
+    app: State<S>,
+    auth_user: AuthenticatedUser,
+) -> Result<impl IntoResponse, RejectReason>
+where
+    S: HasStore + Clone + Send + Sync + 'static,
+{
+    let pool = app.pool();
+    let actor_is_super_admin = is_super_admin(&pool, auth_user.id())
+        .await
+        .map_err(|_| RejectReason::database("Failed to reach database"))?;
+    if !actor_is_super_admin {
+        return Err(RejectReason::forbidden(
+            auth_user.id(),
+            "Only super_admin can read schema status",
+        ));
+    }
+
+    let applied = applied_migrations(&pool)
+        .await
+        .map_err(|_| RejectReason::database("Failed to reach database"))?;
+    let pending = pending_migrations(&pool)
+        .await
+        .map_err(|_| RejectReason::database("Failed to reach database"))?;
+    let version = applied
+        .iter()
+        .filter(|m| m.success)
+        .map(|m| m.version)
+        .max();
+
+    Ok(Json(SchemaStatusResponse {
+        version,
+        applied,
+        pending,
+    }))
+}
+
+/// Snapshot a user's or group's access. Restricted to super_admin.
+pub async fn access_snapshot_handler<S>(
+    app: State<S>,
+    auth_user: AuthenticatedUser,
+    Query(principal): Query<RoleTargetContent>,
+) -> Result<impl IntoResponse, RejectReason>
+where
+    S: HasStore + Clone + Send + Sync + 'static,
+{
+    let pool = app.reader_pool();
+    let actor_is_super_admin = is_super_admin(&app.pool(), auth_user.id())
+        .await
+        .map_err(|_| RejectReason::database("Failed to reach database"))?;
+    if !actor_is_super_admin {
+        return Err(RejectReason::forbidden(
+            auth_user.id(),
+            "Only super_admin can read access snapshots",
+        ));
+    }
+
+    let snapshot = access_snapshot(&pool, principal.assignment_target())
+        .await
+        .map_err(|_| RejectReason::database("Failed to reach database"))?;
+    Ok(Json(snapshot))
+}
+
+/// One side of an access comparison: a live principal, or a snapshot saved earlier.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum AccessDiffSide {
+    Snapshot { snapshot: AccessSnapshot },
+    Principal(RoleTargetContent),
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct AccessDiffContent {
+    pub before: AccessDiffSide,
+    pub after: AccessDiffSide,
+}
+
+/// Compare two principals' access, or a principal against an earlier snapshot. Restricted to
+/// super_admin.
+pub async fn access_diff_handler<S>(
+    app: State<S>,
+    auth_user: AuthenticatedUser,
+    Json(payload): Json<AccessDiffContent>,
+) -> Result<impl IntoResponse, RejectReason>
+where
+    S: HasStore + Clone + Send + Sync + 'static,
+{
+    let pool = app.reader_pool();
+    let actor_is_super_admin = is_super_admin(&app.pool(), auth_user.id())
+        .await
+        .map_err(|_| RejectReason::database("Failed to reach database"))?;
+    if !actor_is_super_admin {
+        return Err(RejectReason::forbidden(
+            auth_user.id(),
+            "Only super_admin can compare access",
+        ));
+    }
+
+    let mut snapshots = Vec::with_capacity(2);
+    for side in [payload.before, payload.after] {
+        snapshots.push(match side {
+            AccessDiffSide::Snapshot { snapshot } => snapshot,
+            AccessDiffSide::Principal(principal) => {
+                access_snapshot(&pool, principal.assignment_target())
+                    .await
+                    .map_err(|_| RejectReason::database("Failed to reach database"))?
+            }
+        });
+    }
+    Ok(Json(diff(&snapshots[0], &snapshots[1])))
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct SyncChangesQuery {
+    /// The `cursor` of the last page read; 0 or absent to read from the beginning.
+    #[serde(default, deserialize_with = "query_number")]
+    pub since: Option<i64>,
+    #[serde(default, deserialize_with = "query_number")]
+    pub limit: Option<i64>,
+}
+
+/// Grant and membership changes after the `since` cursor, oldest first, for downstream services
+/// keeping their own permission caches. Restricted to super_admin.
+pub async fn sync_changes_handler<S>(
+    app: State<S>,
+    auth_user: AuthenticatedUser,
+    Query(query): Query<SyncChangesQuery>,
+) -> Result<impl IntoResponse, RejectReason>
+where
+    S: HasStore + Clone + Send + Sync + 'static,
+{
+    let pool = app.reader_pool();
+    let actor_is_super_admin = is_super_admin(&app.pool(), auth_user.id())
+        .await
+        .map_err(|_| RejectReason::database("Failed to reach database"))?;
+    if !actor_is_super_admin {
+        return Err(RejectReason::forbidden(
+            auth_user.id(),
+            "Only super_admin can read permission changes",
+        ));
+    }
+
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_PAGE_LIMIT)
+        .clamp(1, MAX_PAGE_LIMIT);
+    let page = permission_changes(&pool, query.since.unwrap_or(0).max(0), limit)
+        .await
+        .map_err(|_| RejectReason::database("Failed to reach database"))?;
+    Ok(Json(page))
+}
+
+/// Report orphaned or inconsistent auth rows. Restricted to super_admin.
+pub async fn integrity_check_handler<S>(
+    app: State<S>,
+    auth_user: AuthenticatedUser,
+) -> Result<impl IntoResponse, RejectReason>
+where
+    S: HasStore + Clone + Send + Sync + 'static,
+{
+    let pool = app.pool();
+    let actor_is_super_admin = is_super_admin(&pool, auth_user.id())
+        .await
+        .map_err(|_| RejectReason::database("Failed to reach database"))?;
+    if !actor_is_super_admin {
+        return Err(RejectReason::forbidden(
+            auth_user.id(),
+            "Only super_admin can run integrity checks",
+        ));
+    }
+
+    let report = integrity::check(&pool)
+        .await
+        .map_err(|_| RejectReason::database("Failed to reach database"))?;
+    Ok(Json(report))
+}
+
+/// Fix what `integrity_check_handler` reports. Restricted to super_admin.
+///
+/// With `?dry_run=true` this only reports, like the check.
+pub async fn integrity_repair_handler<S>(
+    app: State<S>,
+    auth_user: AuthenticatedUser,
+    Query(query): Query<DryRunQuery>,
+) -> Result<impl IntoResponse, RejectReason>
+where
+    S: HasStore + Clone + Send + Sync + 'static,
+{
+    let pool = app.pool();
+    let actor_is_super_admin = is_super_admin(&pool, auth_user.id())
+        .await
+        .map_err(|_| RejectReason::database("Failed to reach database"))?;
+    if !actor_is_super_admin {
+        return Err(RejectReason::forbidden(
+            auth_user.id(),
+            "Only super_admin can repair integrity issues",
+        ));
+    }
+
+    let report = if query.dry_run {
+        integrity::check(&pool).await
+    } else {
+        integrity::repair(&pool, Some(auth_user.id())).await
+    }
+    .map_err(|_| RejectReason::database("Failed to reach database"))?;
+    if report.repaired && !report.is_clean() {
+        invalidate_all_role_snapshots();
+    }
+    Ok(Json(report))
+}
+
+async fn require_super_admin(
+    pool: &sqlx::PgPool,
+    actor_user_id: UserId,
+    message: &str,
+) -> Result<(), RejectReason> {
+    let actor_is_super_admin = is_super_admin(pool, actor_user_id)
+        .await
+        .map_err(|_| RejectReason::database("Failed to reach database"))?;
+    if !actor_is_super_admin {
+        return Err(RejectReason::forbidden(actor_user_id, message));
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct AdminStatsQuery {
+    /// Days of daily activity to return, at most `stats::MAX_STATS_DAYS`.
+    #[serde(default = "default_stats_days")]
+    pub days: u32,
+    /// Refresh the daily activity first instead of returning it as of the last refresh.
+    #[serde(default)]
+    pub refresh: bool,
+}
+
+fn default_stats_days() -> u32 {
+    30
+}
+
+/// Aggregate user, session, login, group and grant counts for dashboards. Restricted to
+/// super_admin.
+pub async fn admin_stats_handler<S>(
+    app: State<S>,
+    auth_user: AuthenticatedUser,
+    Query(query): Query<AdminStatsQuery>,
+) -> Result<impl IntoResponse, RejectReason>
+where
+    S: HasStore + Clone + Send + Sync + 'static,
+{
+    let pool = app.pool();
+    require_super_admin(&pool, auth_user.id(), "Only super_admin can read stats").await?;
+
+    // Read back from the primary after a refresh, since replicas may not have it yet.
+    let pool = if query.refresh {
+        refresh_daily_stats(&pool).await.map_err(|err| {
+            tracing::error!("Failed to refresh daily stats: {}", err);
+            RejectReason::database("Failed to reach database")
+        })?;
+        pool
+    } else {
+        app.reader_pool()
+    };
+    let stats = stats(&pool, query.days)
+        .await
+        .map_err(|_| RejectReason::database("Failed to reach database"))?;
+    Ok(Json(stats))
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct ReportQuery {
+    /// Defaults to 30 days before `to`.
+    pub from: Option<chrono::DateTime<chrono::Utc>>,
+    /// Defaults to now.
+    pub to: Option<chrono::DateTime<chrono::Utc>>,
+    #[serde(default)]
+    pub bucket: Bucket,
+}
+
+/// Run one of `reports::Report` over `[from, to)`. Restricted to super_admin.
+///
+/// E.g. `/auth/admin/reports/active_users?from=${__from:date:iso}&to=${__to:date:iso}` from a
+/// Grafana JSON data source.
+pub async fn admin_report_handler<S>(
+    app: State<S>,
+    auth_user: AuthenticatedUser,
+    Path(report): Path<String>,
+    Query(query): Query<ReportQuery>,
+) -> Result<impl IntoResponse, RejectReason>
+where
+    S: HasStore + Clone + Send + Sync + 'static,
+{
+    let pool = app.reader_pool();
+    require_super_admin(
+        &app.pool(),
+        auth_user.id(),
+        "Only super_admin can read reports",
+    )
+    .await?;
+    let report: Report = report
+        .parse()
+        .map_err(|_| RejectReason::not_found("Report not found"))?;
+
+    let to = query.to.unwrap_or_else(chrono::Utc::now);
+    let from = query.from.unwrap_or(to - chrono::Duration::days(30));
+    if from >= to {
+        return Err(RejectReason::bad_request("from must be before to"));
+    }
+    let points = report
+        .run(&pool, &ReportRange::new(from, to, query.bucket))
+        .await
+        .map_err(|err| {
+            tracing::error!("Failed to run report {}: {}", report.as_str(), err);
+            RejectReason::database("Failed to reach database")
+        })?;
+    Ok(Json(points))
+}
+
+/// Reload the app's signing keys so the source's newest key signs from now on. Tokens signed by
+/// the previous key keep verifying for the ring's grace window. Restricted to super_admin.
+pub async fn rotate_keys_handler<S>(
+    app: State<S>,
+    auth_user: AuthenticatedUser,
+) -> Result<impl IntoResponse, RejectReason>
+where
+    S: HasStore + HasKeys + Clone + Send + Sync + 'static,
+{
+    let pool = app.pool();
+    require_super_admin(
+        &pool,
+        auth_user.id(),
+        "Only super_admin can rotate signing keys",
+    )
+    .await?;
+    let Some(key_ring) = app.key_ring() else {
+        return Err(RejectReason::not_found("signing keys"));
+    };
+
+    let previous_kid = key_ring.status().active_kid;
+    let status = key_ring
+        .rotate()
+        .await
+        .map_err(|err| RejectReason::anyhow(anyhow::Error::new(err)))?;
+    log_rotation(&pool, auth_user.id(), &previous_kid, &status)
+        .await
+        .map_err(|_| RejectReason::database("Failed to reach database"))?;
+    Ok(Json(status))
+}
+
+/// Public keys of the tokens this service signs, as a JWK set. Retired keys stay listed for their
+/// grace window, so consumers that cache the set keep verifying older tokens.
+pub async fn jwks_handler<S>(app: State<S>) -> Result<impl IntoResponse, RejectReason>
+where
+    S: HasKeys + Clone + Send + Sync + 'static,
+{
+    let Some(key_ring) = app.key_ring() else {
+        return Err(RejectReason::not_found("signing keys"));
+    };
+    Ok((
+        [(CACHE_CONTROL, JWKS_CACHE_CONTROL)],
+        Json(key_ring.public_jwks()),
+    ))
+}
+
+/// Short enough that a rotation reaches consumers well inside the grace window.
+const JWKS_CACHE_CONTROL: &str = "public, max-age=300";
+
+/// Discovery metadata for consumers of the tokens this service signs. The provider endpoints are
+/// only listed with the `oauth-server` feature.
+#[derive(Debug, Clone, Serialize)]
+pub struct IssuerMetadata {
+    pub issuer: String,
+    pub jwks_uri: String,
+    #[cfg(feature = "oauth-server")]
+    #[serde(flatten)]
+    pub provider: ProviderMetadata,
+    pub subject_types_supported: Vec<&'static str>,
+    pub id_token_signing_alg_values_supported: Vec<String>,
+    pub claims_supported: Vec<&'static str>,
+}
+
+/// OpenID discovery document for `AuthApp::token_issuer`. Not found unless the app sets an issuer
+/// and a key ring.
+pub async fn issuer_metadata_handler<S>(app: State<S>) -> Result<impl IntoResponse, RejectReason>
+where
+    S: HasKeys + Clone + Send + Sync + 'static,
+{
+    let (Some(issuer), Some(key_ring)) = (app.token_issuer(), app.key_ring()) else {
+        return Err(RejectReason::not_found("issuer metadata"));
+    };
+    let mut algorithms: Vec<String> = key_ring
+        .public_jwks()
+        .keys
+        .iter()
+        .filter_map(|jwk| jwk.common.key_algorithm)
+        .map(|algorithm| algorithm.to_string())
+        .collect();
+    algorithms.sort();
+    algorithms.dedup();
+    let base = issuer.trim_end_matches('/');
+    #[cfg(feature = "oauth-server")]
+    let provider_claims = crate::oauth_server::PROVIDER_CLAIMS;
+    #[cfg(not(feature = "oauth-server"))]
+    let provider_claims: &[&str] = &[];
+    let claims_supported = ["iss", "sub", "jti", "iat", "exp", "groups", "grants"]
+        .iter()
+        .chain(provider_claims)
+        .copied()
+        .collect();
+    Ok((
+        [(CACHE_CONTROL, JWKS_CACHE_CONTROL)],
+        Json(IssuerMetadata {
+            issuer: issuer.to_string(),
+            jwks_uri: format!("{}/.well-known/jwks.json", base),
+            #[cfg(feature = "oauth-server")]
+            provider: ProviderMetadata::for_issuer(base),
+            subject_types_supported: vec!["public"],
+            id_token_signing_alg_values_supported: algorithms,
+            claims_supported,
+        }),
+    ))
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct PermissionTokenRequest {
+    /// Capped at the policy's maximum; the policy default applies when omitted.
+    #[serde(default)]
+    pub ttl_secs: Option<i64>,
+}
+
+/// Issue a signed snapshot of the caller's roles that edge services verify without the
+/// database.
+pub async fn issue_permission_token_handler<S>(
+    app: State<S>,
+    auth_user: AuthenticatedUser,
+    Json(payload): Json<PermissionTokenRequest>,
+) -> Result<impl IntoResponse, RejectReason>
+where
+    S: HasStore + HasKeys + Clone + Send + Sync + 'static,
+{
+    let Some(key_ring) = app.key_ring() else {
+        return Err(RejectReason::not_found("signing keys"));
+    };
+    let ttl = app
+        .permission_token_policy()
+        .ttl_for(payload.ttl_secs.map(chrono::Duration::seconds));
+    let issued = issue_permission_token(
+        &app.pool(),
+        key_ring,
+        app.token_issuer(),
+        auth_user.id(),
+        ttl,
+    )
+    .await
+    .map_err(|err| match err {
+        PermissionTokenError::Database(_) => RejectReason::database("Failed to reach database"),
+        PermissionTokenError::Signing(err) => RejectReason::anyhow(anyhow::Error::new(err)),
+    })?;
+    Ok((
+        StatusCode::CREATED,
+        [(CACHE_CONTROL, NO_STORE_CACHE_CONTROL)],
+        Json(issued),
+    ))
+}
+
+/// Ids of revoked permission tokens that have not expired yet.
+pub async fn permission_token_revocations_handler<S>(
+    app: State<S>,
+) -> Result<impl IntoResponse, RejectReason>
+where
+    S: HasStore + Clone + Send + Sync + 'static,
+{
+    let revoked = revoked_permission_tokens(&app.reader_pool())
+        .await
+        .map_err(|_| RejectReason::database("Failed to reach database"))?;
+    Ok(Json(json!({
+        "generated_at": chrono::Utc::now().naive_utc(),
+        "revoked": revoked,
+    })))
+}
+
+/// Revoke one permission token by `jti`, or every token of `user_id`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct RevokePermissionTokensPayload {
+    #[serde(default)]
+    pub jti: Option<uuid::Uuid>,
+    #[serde(default)]
+    pub user_id: Option<UserId>,
+}
+
+/// Revoke permission tokens before they expire. Restricted to super_admin.
+pub async fn revoke_permission_tokens_handler<S>(
+    app: State<S>,
+    auth_user: AuthenticatedUser,
+    Json(payload): Json<RevokePermissionTokensPayload>,
+) -> Result<impl IntoResponse, RejectReason>
+where
+    S: HasStore + Clone + Send + Sync + 'static,
+{
+    let pool = app.pool();
+    require_super_admin(
+        &pool,
+        auth_user.id(),
+        "Only super_admin can revoke permission tokens",
+    )
+    .await?;
+    let target = match (payload.jti, payload.user_id) {
+        (Some(jti), None) => RevokeTarget::Token(jti),
+        (None, Some(user_id)) => RevokeTarget::User(user_id),
+        _ => {
+            return Err(RejectReason::bad_request(
+                "Expected exactly one of jti or user_id",
+            ));
+        }
+    };
+    let revoked = revoke_permission_tokens(&pool, auth_user.id(), target)
+        .await
+        .map_err(|_| RejectReason::database("Failed to reach database"))?;
+    Ok(Json(json!({ "revoked": revoked })))
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ClientSettings {
+    pub name: String,
+    #[serde(default)]
+    pub redirect_uris: Vec<String>,
+    #[serde(default)]
+    pub allowed_scopes: Vec<String>,
+    #[serde(default)]
+    pub trusted: bool,
+    #[serde(default)]
+    pub owner_group_id: Option<GroupId>,
+    /// Token requests allowed per minute; omit for no limit.
+    #[serde(default)]
+    pub rate_limit_per_minute: Option<i32>,
+}
+
+impl From<ClientSettings> for ClientUpdate {
+    fn from(settings: ClientSettings) -> Self {
+        ClientUpdate {
+            name: settings.name,
+            redirect_uris: settings.redirect_uris,
+            allowed_scopes: settings.allowed_scopes,
+            trusted: settings.trusted,
+            owner_group_id: settings.owner_group_id,
+            rate_limit_per_minute: settings.rate_limit_per_minute,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct NewClientPayload {
+    pub client_id: String,
+    /// Issue a client secret, returned once in the response.
+    #[serde(default)]
+    pub confidential: bool,
+    #[serde(flatten)]
+    pub settings: ClientSettings,
+}
+
+fn client_rejection(err: ClientError) -> RejectReason {
+    match err {
+        ClientError::Invalid(reason) => RejectReason::bad_request(reason),
+        ClientError::Conflict => RejectReason::conflict("client"),
+        ClientError::UnknownGroup => RejectReason::bad_request("Owner group not found"),
+        ClientError::Database(_) => RejectReason::database("Failed to reach database"),
+    }
+}
+
+/// Every registered client application, including disabled ones. Restricted to super_admin.
+pub async fn clients_handler<S>(
+    app: State<S>,
+    auth_user: AuthenticatedUser,
+) -> Result<impl IntoResponse, RejectReason>
+where
+    S: HasStore + Clone + Send + Sync + 'static,
+{
+    let pool = app.reader_pool();
+    require_super_admin(
+        &app.pool(),
+        auth_user.id(),
+        "Only super_admin can view clients",
+    )
+    .await?;
+    let clients = Client::list(&pool)
+        .await
+        .map_err(|_| RejectReason::database("Failed to reach database"))?;
+    Ok(Json(clients))
+}
+
+/// Register a client application. Confidential clients get their secret in the response, once.
+/// Restricted to super_admin.
+pub async fn create_client_handler<S>(
+    app: State<S>,
+    auth_user: AuthenticatedUser,
+    Json(payload): Json<NewClientPayload>,
+) -> Result<impl IntoResponse, RejectReason>
+where
+    S: HasStore + Clone + Send + Sync + 'static,
+{
+    let pool = app.pool();
+    require_super_admin(&pool, auth_user.id(), "Only super_admin can manage clients").await?;
+    let settings = ClientUpdate::from(payload.settings);
+    let new = NewClient {
+        client_id: payload.client_id,
+        name: settings.name,
+        redirect_uris: settings.redirect_uris,
+        allowed_scopes: settings.allowed_scopes,
+        confidential: payload.confidential,
+        trusted: settings.trusted,
+        owner_group_id: settings.owner_group_id,
+        rate_limit_per_minute: settings.rate_limit_per_minute,
+    };
+    let registered = register_client(&pool, auth_user.id(), &new)
+        .await
+        .map_err(client_rejection)?;
+    Ok((
+        StatusCode::CREATED,
+        [(CACHE_CONTROL, NO_STORE_CACHE_CONTROL)],
+        Json(registered),
+    ))
+}
+
+/// One client application. Restricted to super_admin.
+pub async fn client_handler<S>(
+    app: State<S>,
+    auth_user: AuthenticatedUser,
+    Path(client_id): Path<String>,
+) -> Result<impl IntoResponse, RejectReason>
+where
+    S: HasStore + Clone + Send + Sync + 'static,
+{
+    let pool = app.reader_pool();
+    require_super_admin(
+        &app.pool(),
+        auth_user.id(),
+        "Only super_admin can view clients",
+    )
+    .await?;
+    let client = Client::get(&pool, &client_id)
+        .await
+        .map_err(|_| RejectReason::database("Failed to reach database"))?
+        .ok_or_else(|| RejectReason::not_found("client"))?;
+    Ok(Json(client))
+}
+
+/// Replace a client's settings. Restricted to super_admin.
+pub async fn update_client_handler<S>(
+    app: State<S>,
+    auth_user: AuthenticatedUser,
+    Path(client_id): Path<String>,
+    Json(payload): Json<ClientSettings>,
+) -> Result<impl IntoResponse, RejectReason>
+where
+    S: HasStore + Clone + Send + Sync + 'static,
+{
+    let pool = app.pool();
+    require_super_admin(&pool, auth_user.id(), "Only super_admin can manage clients").await?;
+    let client = Client::update(&pool, auth_user.id(), &client_id, &payload.into())
+        .await
+        .map_err(client_rejection)?
+        .ok_or_else(|| RejectReason::not_found("client"))?;
+    Ok(Json(client))
+}
+
+/// Disable a client. Its tokens stay valid until they expire. Restricted to super_admin.
+pub async fn disable_client_handler<S>(
+    app: State<S>,
+    auth_user: AuthenticatedUser,
+    Path(client_id): Path<String>,
+) -> Result<impl IntoResponse, RejectReason>
+where
+    S: HasStore + Clone + Send + Sync + 'static,
+{
+    let pool = app.pool();
+    require_super_admin(&pool, auth_user.id(), "Only super_admin can manage clients").await?;
+    let disabled = Client::disable(&pool, auth_user.id(), &client_id)
+        .await
+        .map_err(|_| RejectReason::database("Failed to reach database"))?;
+    if !disabled {
+        return Err(RejectReason::not_found("client"));
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Issue a new secret to a confidential client; the old one stops working. Restricted to
+/// super_admin.
+pub async fn rotate_client_secret_handler<S>(
+    app: State<S>,
+    auth_user: AuthenticatedUser,
+    Path(client_id): Path<String>,
+) -> Result<impl IntoResponse, RejectReason>
+where
+    S: HasStore + Clone + Send + Sync + 'static,
+{
+    let pool = app.pool();
+    require_super_admin(&pool, auth_user.id(), "Only super_admin can manage clients").await?;
+    let rotated = Client::rotate_secret(&pool, auth_user.id(), &client_id)
+        .await
+        .map_err(|_| RejectReason::database("Failed to reach database"))?
+        .ok_or_else(|| RejectReason::not_found("confidential client"))?;
+    Ok(([(CACHE_CONTROL, NO_STORE_CACHE_CONTROL)], Json(rotated)))
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct UserListQuery {
+    #[serde(flatten)]
+    pub page: PageQuery,
+    #[serde(default)]
+    pub fields: FieldMask,
+}
+
+/// Users created on sign-in that are waiting for approval. Restricted to super_admin. Takes
+/// `?fields=` like `self_handler`.
+pub async fn pending_users_handler<S>(
+    app: State<S>,
+    auth_user: AuthenticatedUser,
+    version: ApiVersion,
+    Query(query): Query<UserListQuery>,
+) -> Result<impl IntoResponse, RejectReason>
+where
+    S: HasStore + Clone + Send + Sync + 'static,
+{
+    let pool = app.reader_pool();
+    require_super_admin(
+        &app.pool(),
+        auth_user.id(),
+        "Only super_admin can view pending users",
+    )
+    .await?;
+
+    let users = UserRow::pending_approval(&pool, Some(query.page.page()))
+        .await
+        .map_err(|_| RejectReason::database("Failed to reach database"))?;
+    sparse(
+        &query.fields,
+        &users
+            .into_iter()
+            .map(|user| version.user(user.into()))
+            .collect::<Vec<_>>(),
+    )
+}
+
+/// Approve a pending user and grant the provisioning defaults. Restricted to super_admin.
+pub async fn approve_pending_user_handler<S>(
+    app: State<S>,
+    auth_user: AuthenticatedUser,
+    Path(user): Path<UserRef>,
+) -> Result<impl IntoResponse, RejectReason>
+where
+    S: AuthApp + Clone + Send + Sync + 'static,
+{
+    let pool = app.pool();
+    let user_id = user.resolve(&pool).await?;
+    require_super_admin(
+        &pool,
+        auth_user.id(),
+        "Only super_admin can approve pending users",
+    )
+    .await?;
+
+    let approved = approve_user(&pool, auth_user.id(), user_id, app.provisioning_policy())
+        .await
+        .map_err(|_| RejectReason::database("Failed to reach database"))?;
+    if !approved {
+        return Err(RejectReason::not_found("Pending user not found"));
+    }
+    invalidate_role_snapshots(user_id);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Change the id a user is addressed by in URLs. Restricted to super_admin.
+pub async fn user_external_id_handler<S>(
+    app: State<S>,
+    auth_user: AuthenticatedUser,
+    Path(user): Path<UserRef>,
+    Json(payload): Json<ExternalIdContent>,
+) -> Result<impl IntoResponse, RejectReason>
+where
+    S: HasStore + Clone + Send + Sync + 'static,
+{
+    let pool = app.pool();
+    require_super_admin(
+        &pool,
+        auth_user.id(),
+        "Only super_admin can change a user's external id",
+    )
+    .await?;
+    let user_id = user.resolve(&pool).await?;
+
+    let previous = UserRow::set_external_id(&pool, user_id, &payload.external_id)
+        .await
+        .map_err(|err| external_id_rejection(err, "User"))?;
+    let log = LogRow::new(
+        auth_user.id(),
+        json!({
+            "type": "external_id_changed",
+            "user_id": user_id.to_string(),
+            "from": previous,
+            "to": payload.external_id,
+        }),
+    );
+    LogRow::insert(&pool, &log)
+        .await
+        .map_err(|_| RejectReason::database("Failed to reach database"))?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct DeactivateContent {
+    pub reason: DeactivationReason,
+    pub note: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ReactivateContent {
+    /// Must be the recorded reason; omitted or null for a deactivation without one.
+    #[serde(default)]
+    pub acknowledge_reason: Option<DeactivationReason>,
+    pub note: Option<String>,
+}
+
+fn reactivation_rejection(err: ReactivationError, resource: &str) -> RejectReason {
+    match err {
+        ReactivationError::NotFound => RejectReason::not_found(format!("{resource} not found")),
+        ReactivationError::NotDeactivated => {
+            RejectReason::conflict(format!("{resource} is not deactivated"))
+        }
+        err @ ReactivationError::Unacknowledged { .. } => {
+            RejectReason::precondition_failed(err.to_string())
+        }
+        ReactivationError::Database(_) => RejectReason::database("Failed to reach database"),
+    }
+}
+
+/// Why a deactivated user was deactivated. Restricted to super_admin.
+pub async fn user_deactivation_handler<S>(
+    app: State<S>,
+    auth_user: AuthenticatedUser,
+    Path(user): Path<UserRef>,
+) -> Result<Json<Deactivation>, RejectReason>
+where
+    S: HasStore + Clone + Send + Sync + 'static,
+{
+    let pool = app.reader_pool();
+    require_super_admin(
+        &app.pool(),
+        auth_user.id(),
+        "Only super_admin can view deactivations",
+    )
+    .await?;
+    let user_id = user.resolve(&pool).await?;
+
+    user_deactivation(&pool, user_id)
+        .await
+        .map_err(|_| RejectReason::database("Failed to reach database"))?
+        .map(Json)
+        .ok_or_else(|| RejectReason::not_found("Deactivated user"))
+}
+
+/// Deactivate a user with a reason and note. Restricted to super_admin.
+pub async fn deactivate_user_handler<S>(
+    app: State<S>,
+    auth_user: AuthenticatedUser,
+    Path(user): Path<UserRef>,
+    Json(payload): Json<DeactivateContent>,
+) -> Result<impl IntoResponse, RejectReason>
+where
+    S: AuthApp + Clone + Send + Sync + 'static,
+{
+    let pool = app.pool();
+    require_super_admin(
+        &pool,
+        auth_user.id(),
+        "Only super_admin can deactivate users",
+    )
+    .await?;
+    let user_id = user.resolve(&pool).await?;
+    let change = DeactivationChange {
+        actor_user_id: auth_user.id(),
+        subject: user_id,
+        reason: Some(payload.reason),
+    };
+    app.hooks().before_user_deactivate(&change).await?;
+
+    let deactivated = deactivate_user(
+        &pool,
+        auth_user.id(),
+        user_id,
+        payload.reason,
+        payload.note.as_deref(),
+    )
+    .await
+    .map_err(|_| RejectReason::database("Failed to reach database"))?;
+    if !deactivated {
+        return Err(already_deactivated(
+            user_deactivation(&pool, user_id).await,
+            "User",
+        ));
+    }
+    app.announce_user_deactivation(user_id);
+    app.hooks().after_user_deactivate(&change).await;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Reactivate a user, acknowledging the recorded deactivation reason. Restricted to super_admin.
+pub async fn reactivate_user_handler<S>(
+    app: State<S>,
+    auth_user: AuthenticatedUser,
+    Path(user): Path<UserRef>,
+    Json(payload): Json<ReactivateContent>,
+) -> Result<impl IntoResponse, RejectReason>
+where
+    S: HasStore + Clone + Send + Sync + 'static,
+{
+    let pool = app.pool();
+    require_super_admin(
+        &pool,
+        auth_user.id(),
+        "Only super_admin can reactivate users",
+    )
+    .await?;
+    let user_id = user.resolve(&pool).await?;
+
+    reactivate_user(
+        &pool,
+        auth_user.id(),
+        user_id,
+        payload.acknowledge_reason,
+        payload.note.as_deref(),
+    )
+    .await
+    .map_err(|err| reactivation_rejection(err, "User"))?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Why a deactivated group was deactivated. Restricted to super_admin.
+pub async fn group_deactivation_handler<S>(
+    app: State<S>,
+    auth_user: AuthenticatedUser,
+    Path(group): Path<GroupRef>,
+) -> Result<Json<Deactivation>, RejectReason>
+where
+    S: HasStore + Clone + Send + Sync + 'static,
+{
+    let pool = app.reader_pool();
+    require_super_admin(
+        &app.pool(),
+        auth_user.id(),
+        "Only super_admin can view deactivations",
+    )
+    .await?;
+    let group_id = group.resolve(&pool).await?;
+
+    group_deactivation(&pool, group_id)
+        .await
+        .map_err(|_| RejectReason::database("Failed to reach database"))?
+        .map(Json)
+        .ok_or_else(|| RejectReason::not_found("Deactivated group"))
+}
+
+/// Deactivate a group with a reason and note. Restricted to super_admin.
+pub async fn deactivate_group_handler<S>(
+    app: State<S>,
+    auth_user: AuthenticatedUser,
+    Path(group): Path<GroupRef>,
+    Json(payload): Json<DeactivateContent>,
+) -> Result<impl IntoResponse, RejectReason>
+where
+    S: HasStore + HasHooks + Clone + Send + Sync + 'static,
+{
+    let pool = app.pool();
+    require_super_admin(
+        &pool,
+        auth_user.id(),
+        "Only super_admin can deactivate groups",
+    )
+    .await?;
+    let group_id = group.resolve(&pool).await?;
+    let change = DeactivationChange {
+        actor_user_id: auth_user.id(),
+        subject: group_id,
+        reason: Some(payload.reason),
+    };
+    app.hooks().before_group_deactivate(&change).await?;
+
+    let deactivated = deactivate_group(
+        &pool,
+        auth_user.id(),
+        group_id,
+        payload.reason,
+        payload.note.as_deref(),
+    )
+    .await
+    .map_err(|_| RejectReason::database("Failed to reach database"))?;
+    if !deactivated {
+        return Err(already_deactivated(
+            group_deactivation(&pool, group_id).await,
+            "Group",
+        ));
+    }
+    app.hooks().after_group_deactivate(&change).await;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Reactivate a group, acknowledging the recorded deactivation reason. Restricted to super_admin.
+pub async fn reactivate_group_handler<S>(
+    app: State<S>,
+    auth_user: AuthenticatedUser,
+    Path(group): Path<GroupRef>,
+    Json(payload): Json<ReactivateContent>,
+) -> Result<impl IntoResponse, RejectReason>
+where
+    S: HasStore + Clone + Send + Sync + 'static,
+{
+    let pool = app.pool();
+    require_super_admin(
+        &pool,
+        auth_user.id(),
+        "Only super_admin can reactivate groups",
+    )
+    .await?;
+    let group_id = group.resolve(&pool).await?;
+
+    reactivate_group(
+        &pool,
+        auth_user.id(),
+        group_id,
+        payload.acknowledge_reason,
+        payload.note.as_deref(),
+    )
+    .await
+    .map_err(|err| reactivation_rejection(err, "Group"))?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ArchiveContent {
+    pub note: Option<String>,
+}
+
+/// When and by whom an archived group was archived. Restricted to super_admin.
+pub async fn group_archival_handler<S>(
+    app: State<S>,
+    auth_user: AuthenticatedUser,
+    Path(group): Path<GroupRef>,
+) -> Result<Json<GroupArchival>, RejectReason>
+where
+    S: HasStore + Clone + Send + Sync + 'static,
+{
+    let pool = app.reader_pool();
+    require_super_admin(
+        &app.pool(),
+        auth_user.id(),
+        "Only super_admin can view archivals",
+    )
+    .await?;
+    let group_id = group.resolve(&pool).await?;
+
+    group_archival(&pool, group_id)
+        .await
+        .map_err(|_| RejectReason::database("Failed to reach database"))?
+        .map(Json)
+        .ok_or_else(|| RejectReason::not_found("Archived group"))
+}
+
+/// Freeze a group read-only; see `archival`. Restricted to super_admin.
+pub async fn archive_group_handler<S>(
+    app: State<S>,
+    auth_user: AuthenticatedUser,
+    Path(group): Path<GroupRef>,
+    payload: Option<Json<ArchiveContent>>,
+) -> Result<impl IntoResponse, RejectReason>
+where
+    S: HasStore + Clone + Send + Sync + 'static,
+{
+    let pool = app.pool();
+    require_super_admin(&pool, auth_user.id(), "Only super_admin can archive groups").await?;
+    let group_id = group.resolve(&pool).await?;
+    require_group_not_deleted(&pool, group_id).await?;
+    let note = payload.and_then(|Json(payload)| payload.note);
+
+    let archived = archive_group(&pool, auth_user.id(), group_id, note.as_deref())
+        .await
+        .map_err(|_| RejectReason::database("Failed to reach database"))?;
+    if !archived {
+        return Err(archival_unchanged(&pool, group_id, "Group is already archived").await);
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Lift an archival, letting the group change again. Restricted to super_admin.
+pub async fn unarchive_group_handler<S>(
+    app: State<S>,
+    auth_user: AuthenticatedUser,
+    Path(group): Path<GroupRef>,
+    payload: Option<Json<ArchiveContent>>,
+) -> Result<impl IntoResponse, RejectReason>
+where
+    S: HasStore + Clone + Send + Sync + 'static,
+{
+    let pool = app.pool();
+    require_super_admin(
+        &pool,
+        auth_user.id(),
+        "Only super_admin can unarchive groups",
+    )
+    .await?;
+    let group_id = group.resolve(&pool).await?;
+    require_group_not_deleted(&pool, group_id).await?;
+    let note = payload.and_then(|Json(payload)| payload.note);
+
+    let unarchived = unarchive_group(&pool, auth_user.id(), group_id, note.as_deref())
+        .await
+        .map_err(|_| RejectReason::database("Failed to reach database"))?;
+    if !unarchived {
+        return Err(archival_unchanged(&pool, group_id, "Group is not archived").await);
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Why `archive_group` or `unarchive_group` changed nothing: the group is missing, or `conflict`.
+async fn archival_unchanged(
+    pool: &sqlx::PgPool,
+    group_id: GroupId,
+    conflict: &str,
+) -> RejectReason {
+    match GroupRow::get(pool, group_id).await {
+        Ok(Some(_)) => RejectReason::conflict(conflict),
+        Ok(None) => RejectReason::not_found("Group not found"),
+        Err(_) => RejectReason::database("Failed to reach database"),
+    }
+}
+
+/// Why `deactivate_user` or `deactivate_group` changed nothing: the row is missing or inactive.
+fn already_deactivated(
+    existing: Result<Option<Deactivation>, sqlx::Error>,
+    resource: &str,
+) -> RejectReason {
+    match existing {
+        Ok(Some(_)) => RejectReason::conflict(format!("{resource} is already deactivated")),
+        Ok(None) => RejectReason::not_found(format!("{resource} not found")),
+        Err(_) => RejectReason::database("Failed to reach database"),
+    }
+}
+
+/// Soft-delete a user; see `recycle_bin`. Restricted to super_admin.
+pub async fn delete_user_handler<S>(
+    app: State<S>,
+    auth_user: AuthenticatedUser,
+    Path(user): Path<UserRef>,
+) -> Result<impl IntoResponse, RejectReason>
+where
+    S: AuthApp + Clone + Send + Sync + 'static,
+{
+    let pool = app.pool();
+    require_super_admin(&pool, auth_user.id(), "Only super_admin can delete users").await?;
+    let user_id = user.resolve(&pool).await?;
+    let change = DeactivationChange {
+        actor_user_id: auth_user.id(),
+        subject: user_id,
+        reason: None,
+    };
+    app.hooks().before_user_deactivate(&change).await?;
+
+    let deleted = delete_user(&pool, auth_user.id(), user_id)
+        .await
+        .map_err(|_| RejectReason::database("Failed to reach database"))?;
+    if !deleted {
+        let exists = UserRow::get_including_deleted(&pool, user_id)
+            .await
+            .map(|user| user.is_some());
+        return Err(recycle_unchanged(exists, "User", "User is already deleted"));
+    }
+    app.announce_user_deactivation(user_id);
+    app.hooks().after_user_deactivate(&change).await;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Restore a soft-deleted user. It stays inactive if it was deactivated before it was deleted.
+/// Restricted to super_admin.
+pub async fn restore_user_handler<S>(
+    app: State<S>,
+    auth_user: AuthenticatedUser,
+    Path(user): Path<UserRef>,
+) -> Result<impl IntoResponse, RejectReason>
+where
+    S: HasStore + Clone + Send + Sync + 'static,
+{
+    let pool = app.pool();
+    require_super_admin(&pool, auth_user.id(), "Only super_admin can restore users").await?;
+    let user_id = user.resolve_including_deleted(&pool).await?;
+
+    let restored = restore_user(&pool, auth_user.id(), user_id)
+        .await
+        .map_err(|_| RejectReason::database("Failed to reach database"))?;
+    if !restored {
+        let exists = UserRow::get_including_deleted(&pool, user_id)
+            .await
+            .map(|user| user.is_some());
+        return Err(recycle_unchanged(exists, "User", "User is not deleted"));
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Soft-delete a group; see `recycle_bin`. Restricted to super_admin.
+pub async fn delete_group_handler<S>(
+    app: State<S>,
+    auth_user: AuthenticatedUser,
+    Path(group): Path<GroupRef>,
+) -> Result<impl IntoResponse, RejectReason>
+where
+    S: HasStore + HasHooks + Clone + Send + Sync + 'static,
+{
+    let pool = app.pool();
+    require_super_admin(&pool, auth_user.id(), "Only super_admin can delete groups").await?;
+    let group_id = group.resolve(&pool).await?;
+    let change = DeactivationChange {
+        actor_user_id: auth_user.id(),
+        subject: group_id,
+        reason: None,
+    };
+    app.hooks().before_group_deactivate(&change).await?;
+
+    let deleted = delete_group(&pool, auth_user.id(), group_id)
+        .await
+        .map_err(|_| RejectReason::database("Failed to reach database"))?;
+    if !deleted {
+        let exists = GroupRow::get_including_deleted(&pool, group_id)
+            .await
+            .map(|group| group.is_some());
+        return Err(recycle_unchanged(
+            exists,
+            "Group",
+            "Group is already deleted",
+        ));
+    }
+    app.hooks().after_group_deactivate(&change).await;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Restore a soft-deleted group with its memberships and grants. Restricted to super_admin.
+pub async fn restore_group_handler<S>(
+    app: State<S>,
+    auth_user: AuthenticatedUser,
+    Path(group): Path<GroupRef>,
+) -> Result<impl IntoResponse, RejectReason>
+where
+    S: HasStore + Clone + Send + Sync + 'static,
+{
+    let pool = app.pool();
+    require_super_admin(&pool, auth_user.id(), "Only super_admin can restore groups").await?;
+    let group_id = group.resolve_including_deleted(&pool).await?;
+
+    let restored = restore_group(&pool, auth_user.id(), group_id)
+        .await
+        .map_err(|_| RejectReason::database("Failed to reach database"))?;
+    if !restored {
+        let exists = GroupRow::get_including_deleted(&pool, group_id)
+            .await
+            .map(|group| group.is_some());
+        return Err(recycle_unchanged(exists, "Group", "Group is not deleted"));
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Soft-deleted users, most recently deleted first. Restricted to super_admin.
+pub async fn deleted_users_handler<S>(
+    app: State<S>,
+    auth_user: AuthenticatedUser,
+    Query(page): Query<PageQuery>,
+) -> Result<Json<Vec<DeletedUser>>, RejectReason>
+where
+    S: HasStore + Clone + Send + Sync + 'static,
+{
+    let pool = app.reader_pool();
+    require_super_admin(
+        &app.pool(),
+        auth_user.id(),
+        "Only super_admin can view deleted users",
+    )
+    .await?;
+
+    deleted_users(&pool, Some(page.page()))
+        .await
+        .map(Json)
+        .map_err(|_| RejectReason::database("Failed to reach database"))
+}
+
+/// Soft-deleted groups, most recently deleted first. Restricted to super_admin.
+pub async fn deleted_groups_handler<S>(
+    app: State<S>,
+    auth_user: AuthenticatedUser,
+    Query(page): Query<PageQuery>,
+) -> Result<Json<Vec<DeletedGroup>>, RejectReason>
+where
+    S: HasStore + Clone + Send + Sync + 'static,
+{
+    let pool = app.reader_pool();
+    require_super_admin(
+        &app.pool(),
+        auth_user.id(),
+        "Only super_admin can view deleted groups",
+    )
+    .await?;
+
+    deleted_groups(&pool, Some(page.page()))
+        .await
+        .map(Json)
+        .map_err(|_| RejectReason::database("Failed to reach database"))
+}
+
+/// Why a soft delete or restore changed nothing: the row is missing, or `conflict`.
+fn recycle_unchanged(
+    exists: Result<bool, sqlx::Error>,
+    resource: &str,
+    conflict: &str,
+) -> RejectReason {
+    match exists {
+        Ok(true) => RejectReason::conflict(conflict),
+        Ok(false) => RejectReason::not_found(format!("{resource} not found")),
+        Err(_) => RejectReason::database("Failed to reach database"),
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct SuspendContent {
+    pub reason: String,
+    pub note: Option<String>,
+    /// Defaults to now.
+    pub starts_at: Option<chrono::DateTime<chrono::Utc>>,
+    /// Omit to suspend until lifted.
+    pub ends_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct LiftSuspensionContent {
+    pub note: Option<String>,
+}
+
+/// A user's suspensions, including ended and lifted ones. Restricted to super_admin.
+pub async fn user_suspensions_handler<S>(
+    app: State<S>,
+    auth_user: AuthenticatedUser,
+    Path(user): Path<UserRef>,
+) -> Result<Json<Vec<Suspension>>, RejectReason>
+where
+    S: HasStore + Clone + Send + Sync + 'static,
+{
+    let pool = app.reader_pool();
+    require_super_admin(
+        &app.pool(),
+        auth_user.id(),
+        "Only super_admin can view suspensions",
+    )
+    .await?;
+    let user_id = user.resolve(&pool).await?;
+
+    suspensions_for_user(&pool, user_id)
+        .await
+        .map(Json)
+        .map_err(|_| RejectReason::database("Failed to reach database"))
+}
+
+/// Suspend a user from now or `starts_at`, until `ends_at` or until lifted. Restricted to
+/// super_admin.
+pub async fn suspend_user_handler<S>(
+    app: State<S>,
+    auth_user: AuthenticatedUser,
+    Path(user): Path<UserRef>,
+    Json(payload): Json<SuspendContent>,
+) -> Result<impl IntoResponse, RejectReason>
+where
+    S: HasStore + Clone + Send + Sync + 'static,
+{
+    let pool = app.pool();
+    require_super_admin(&pool, auth_user.id(), "Only super_admin can suspend users").await?;
+    let user_id = user.resolve(&pool).await?;
+
+    let reason = payload.reason.trim();
+    if reason.is_empty() {
+        return Err(RejectReason::bad_request("A suspension needs a reason"));
+    }
+    let starts_at = payload.starts_at.unwrap_or_else(chrono::Utc::now);
+    let mut suspension = NewSuspension::new(reason).starting_at(starts_at.naive_utc());
+    if let Some(ends_at) = payload.ends_at {
+        if ends_at <= starts_at {
+            return Err(RejectReason::bad_request(
+                "ends_at must be after the start of the suspension",
+            ));
+        }
+        suspension = suspension.until(ends_at.naive_utc());
+    }
+    if let Some(note) = payload.note {
+        suspension = suspension.with_note(note);
+    }
+    if UserRow::get(&pool, user_id)
+        .await
+        .map_err(|_| RejectReason::database("Failed to reach database"))?
+        .is_none()
+    {
+        return Err(RejectReason::not_found("User not found"));
+    }
+
+    let imposed = impose_suspension(&pool, auth_user.id(), user_id, &suspension)
+        .await
+        .map_err(|_| RejectReason::database("Failed to reach database"))?;
+    Ok((StatusCode::CREATED, Json(imposed)))
+}
+
+/// End a suspension early. Restricted to super_admin.
+pub async fn lift_suspension_handler<S>(
+    app: State<S>,
+    auth_user: AuthenticatedUser,
+    Path(suspension_id): Path<uuid::Uuid>,
+    payload: Option<Json<LiftSuspensionContent>>,
+) -> Result<Json<Suspension>, RejectReason>
+where
+    S: HasStore + Clone + Send + Sync + 'static,
+{
+    let pool = app.pool();
+    require_super_admin(
+        &pool,
+        auth_user.id(),
+        "Only super_admin can lift suspensions",
+    )
+    .await?;
+    let note = payload.and_then(|Json(payload)| payload.note);
+
+    lift_suspension(&pool, auth_user.id(), suspension_id, note.as_deref())
+        .await
+        .map_err(|_| RejectReason::database("Failed to reach database"))?
+        .map(Json)
+        .ok_or_else(|| RejectReason::not_found("Unlifted suspension"))
+}
+
+const MAX_REPORT_CATEGORY_LEN: usize = 64;
+const MAX_REPORT_MESSAGE_LEN: usize = 4000;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ReportContent {
+    /// `user` or `group`.
+    pub subject_type: String,
+    /// UUID or external id of the subject.
+    pub subject_id: String,
+    /// e.g. `spam` or `harassment`.
+    pub category: String,
+    pub message: Option<String>,
+    /// Kept as `reporter` evidence.
+    pub evidence: Option<Value>,
+}
+
+/// Report a user or group for moderation. Evidence from `AuthApp::evidence_collector` is
+/// attached after the report is filed; failing to collect it does not fail the report.
+pub async fn file_report_handler<S>(
+    app: State<S>,
+    auth_user: AuthenticatedUser,
+    Json(payload): Json<ReportContent>,
+) -> Result<impl IntoResponse, RejectReason>
+where
+    S: AuthApp + Clone + Send + Sync + 'static,
+{
+    let pool = app.pool();
+    let category = payload.category.trim();
+    if category.is_empty() || category.len() > MAX_REPORT_CATEGORY_LEN {
+        return Err(RejectReason::bad_request(format!(
+            "category must be 1 to {MAX_REPORT_CATEGORY_LEN} bytes"
+        )));
+    }
+    if payload
+        .message
+        .as_ref()
+        .is_some_and(|message| message.len() > MAX_REPORT_MESSAGE_LEN)
+    {
+        return Err(RejectReason::bad_request(format!(
+            "message must be at most {MAX_REPORT_MESSAGE_LEN} bytes"
+        )));
+    }
+
+    let subject = match payload.subject_type.as_str() {
+        "user" => {
+            let user_id = UserRef::from(payload.subject_id).resolve(&pool).await?;
+            if user_id == auth_user.id() {
+                return Err(RejectReason::bad_request("You cannot report yourself"));
+            }
+            UserRow::get(&pool, user_id)
+                .await
+                .map_err(|_| RejectReason::database("Failed to reach database"))?
+                .ok_or_else(|| RejectReason::not_found("User not found"))?;
+            ReportSubject::User(user_id)
+        }
+        "group" => {
+            let group_id = GroupRef::from(payload.subject_id).resolve(&pool).await?;
+            GroupRow::get(&pool, group_id)
+                .await
+                .map_err(|_| RejectReason::database("Failed to reach database"))?
+                .ok_or_else(|| RejectReason::not_found("Group not found"))?;
+            ReportSubject::Group(group_id)
+        }
+        _ => {
+            return Err(RejectReason::bad_request(
+                "subject_type must be either `user` or `group`",
+            ));
+        }
+    };
+    let report = NewReport {
+        subject,
+        category: category.to_string(),
+        message: payload.message,
+        evidence: payload
+            .evidence
+            .map(|payload| NewEvidence {
+                kind: "reporter".to_string(),
+                payload,
+            })
+            .into_iter()
+            .collect(),
+    };
+
+    let filed = file_report(&pool, auth_user.id(), &report)
+        .await
+        .map_err(|_| RejectReason::database("Failed to reach database"))?
+        .ok_or_else(|| RejectReason::conflict("You already reported this"))?;
+    if let Some(collector) = app.evidence_collector() {
+        for evidence in collector.collect(&filed).await {
+            if let Err(err) = attach_evidence(&pool, filed.id, None, &evidence).await {
+                tracing::warn!("Failed to attach evidence to report {}: {}", filed.id, err);
+            }
+        }
+    }
+    Ok((StatusCode::CREATED, Json(filed)))
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ModerationQueueQuery {
+    #[serde(flatten)]
+    pub page: PageQuery,
+    /// Defaults to every unresolved report.
+    pub status: Option<ReportStatus>,
+}
+
+/// Abuse reports waiting for moderation, oldest first. Restricted to super_admin.
+pub async fn moderation_queue_handler<S>(
+    app: State<S>,
+    auth_user: AuthenticatedUser,
+    Query(query): Query<ModerationQueueQuery>,
+) -> Result<Json<Vec<AbuseReport>>, RejectReason>
+where
+    S: HasStore + Clone + Send + Sync + 'static,
+{
+    let pool = app.reader_pool();
+    require_super_admin(&app.pool(), auth_user.id(), "Only super_admin can moderate").await?;
+
+    moderation_queue(&pool, query.status, query.page.page())
+        .await
+        .map(Json)
+        .map_err(|_| RejectReason::database("Failed to reach database"))
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ModerationReport {
+    #[serde(flatten)]
+    pub report: AbuseReport,
+    pub evidence: Vec<ReportEvidence>,
+}
+
+/// An abuse report with its evidence. Restricted to super_admin.
+pub async fn moderation_report_handler<S>(
+    app: State<S>,
+    auth_user: AuthenticatedUser,
+    Path(report_id): Path<uuid::Uuid>,
+) -> Result<Json<ModerationReport>, RejectReason>
+where
+    S: HasStore + Clone + Send + Sync + 'static,
+{
+    let pool = app.reader_pool();
+    require_super_admin(&app.pool(), auth_user.id(), "Only super_admin can moderate").await?;
+
+    let report = get_report(&pool, report_id)
+        .await
+        .map_err(|_| RejectReason::database("Failed to reach database"))?
+        .ok_or_else(|| RejectReason::not_found("Report not found"))?;
+    let evidence = report_evidence(&pool, report_id)
+        .await
+        .map_err(|_| RejectReason::database("Failed to reach database"))?;
+    Ok(Json(ModerationReport { report, evidence }))
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ReportStatusContent {
+    pub status: ReportStatus,
+    /// Kept as the resolution when the report is actioned or dismissed.
+    pub note: Option<String>,
+}
+
+/// Move an abuse report through `open` → `reviewing` → `actioned` or `dismissed`. Restricted to
+/// super_admin.
+pub async fn moderation_report_status_handler<S>(
+    app: State<S>,
+    auth_user: AuthenticatedUser,
+    Path(report_id): Path<uuid::Uuid>,
+    Json(payload): Json<ReportStatusContent>,
+) -> Result<Json<AbuseReport>, RejectReason>
+where
+    S: HasStore + Clone + Send + Sync + 'static,
+{
+    let pool = app.pool();
+    require_super_admin(&pool, auth_user.id(), "Only super_admin can moderate").await?;
+
+    transition_report(
+        &pool,
+        auth_user.id(),
+        report_id,
+        payload.status,
+        payload.note.as_deref(),
+    )
+    .await
+    .map(Json)
+    .map_err(|err| match err {
+        ReportTransitionError::NotFound => RejectReason::not_found("Report not found"),
+        err @ ReportTransitionError::Invalid { .. } => RejectReason::conflict(err.to_string()),
+        ReportTransitionError::Database(_) => RejectReason::database("Failed to reach database"),
+    })
+}
+
+/// Attach evidence to an abuse report as a moderator. Restricted to super_admin.
+pub async fn moderation_evidence_handler<S>(
+    app: State<S>,
+    auth_user: AuthenticatedUser,
+    Path(report_id): Path<uuid::Uuid>,
+    Json(payload): Json<NewEvidence>,
+) -> Result<impl IntoResponse, RejectReason>
+where
+    S: HasStore + Clone + Send + Sync + 'static,
+{
+    let pool = app.pool();
+    require_super_admin(&pool, auth_user.id(), "Only super_admin can moderate").await?;
+    if payload.kind.trim().is_empty() {
+        return Err(RejectReason::bad_request("Evidence needs a kind"));
+    }
+
+    let attached = attach_evidence(&pool, report_id, Some(auth_user.id()), &payload)
+        .await
+        .map_err(|_| RejectReason::database("Failed to reach database"))?
+        .ok_or_else(|| RejectReason::not_found("Report not found"))?;
+    Ok((StatusCode::CREATED, Json(attached)))
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct CreateInvitationsContent {
+    pub emails: Vec<String>,
+    /// Defaults to `DEFAULT_INVITATION_TTL`.
+    pub expires_in_hours: Option<i64>,
+}
+
+#[derive(Clone, Serialize)]
+pub struct CreatedInvitation {
+    #[serde(flatten)]
+    pub invitation: InvitationRow,
+    pub emailed: bool,
+    /// Only returned when the invitation was not emailed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+}
+
+#[derive(Clone, Serialize)]
+pub struct CreateInvitationsResponse {
+    pub invitations: Vec<CreatedInvitation>,
+    pub skipped: Vec<SkippedInvitation>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct InvitationSummary {
+    #[serde(flatten)]
+    pub invitation: InvitationRow,
+    pub status: InvitationStatus,
+}
+
+/// Invite up to 500 email addresses to create an account, emailing each a link from
+/// `AuthApp::invitation_accept_url`. Restricted to super_admin.
+pub async fn create_invitations_handler<S>(
+    app: State<S>,
+    auth_user: AuthenticatedUser,
+    Json(payload): Json<CreateInvitationsContent>,
+) -> Result<impl IntoResponse, RejectReason>
+where
+    S: AuthApp + Clone + Send + Sync + 'static,
+{
+    let pool = app.pool();
+    require_super_admin(&pool, auth_user.id(), "Only super_admin can invite users").await?;
+    if payload.emails.len() > MAX_INVITATIONS_PER_REQUEST {
+        return Err(RejectReason::bad_request(format!(
+            "At most {} invitations per request",
+            MAX_INVITATIONS_PER_REQUEST
+        )));
+    }
+    let ttl = match payload.expires_in_hours {
+        Some(hours) if hours > 0 => chrono::Duration::hours(hours),
+        Some(_) => {
+            return Err(RejectReason::bad_request(
+                "expires_in_hours must be positive",
+            ));
+        }
+        None => DEFAULT_INVITATION_TTL,
+    };
+
+    let batch = create_invitations(
+        &pool,
+        auth_user.id(),
+        &payload.emails,
+        ttl,
+        app.email_normalizer(),
+    )
+    .await
+    .map_err(|_| RejectReason::database("Failed to reach database"))?;
+
+    let inviter_name = UserRow::get(&pool, auth_user.id())
+        .await
+        .map_err(|_| RejectReason::database("Failed to reach database"))?
+        .and_then(|user| user.display_name.or(user.username));
+    let mut invitations = Vec::with_capacity(batch.issued.len());
+    for issued in batch.issued {
+        let mut emailed = false;
+        if let (Some(notifier), Some(accept_url)) =
+            (app.notifier(), app.invitation_accept_url(&issued.token))
+        {
+            let notification = Notification::AccountInvitation(AccountInvitationContext {
+                recipient: Recipient::new(issued.invitation.email.clone()),
+                inviter_name: inviter_name.clone(),
+                accept_url,
+                expires_at: issued.invitation.expires_at,
+            });
+            match notifier.notify(&notification).await {
+                Ok(()) => emailed = true,
+                Err(err) => tracing::warn!(
+                    "Failed to email invitation {}: {}",
+                    issued.invitation.id,
+                    err
+                ),
+            }
+        }
+        invitations.push(CreatedInvitation {
+            token: (!emailed).then_some(issued.token),
+            invitation: issued.invitation,
+            emailed,
+        });
+    }
+    Ok((
+        [(CACHE_CONTROL, NO_STORE_CACHE_CONTROL)],
+        Json(CreateInvitationsResponse {
+            invitations,
+            skipped: batch.skipped,
+        }),
+    ))
+}
+
+/// Every invitation with its status, newest first. Restricted to super_admin.
+pub async fn invitations_handler<S>(
+    app: State<S>,
+    auth_user: AuthenticatedUser,
+    Query(page): Query<PageQuery>,
+) -> Result<impl IntoResponse, RejectReason>
+where
+    S: HasStore + Clone + Send + Sync + 'static,
+{
+    let pool = app.reader_pool();
+    require_super_admin(
+        &app.pool(),
+        auth_user.id(),
+        "Only super_admin can view invitations",
+    )
+    .await?;
+
+    let now = chrono::Utc::now().naive_utc();
+    let invitations = InvitationRow::list(&pool, Some(page.page()))
+        .await
+        .map_err(|_| RejectReason::database("Failed to reach database"))?
+        .into_iter()
+        .map(|invitation| InvitationSummary {
+            status: invitation.status(now),
+            invitation,
+        })
+        .collect::<Vec<_>>();
+    Ok(Json(invitations))
+}
+
+/// Revoke an invitation that has not been accepted. Restricted to super_admin.
+pub async fn revoke_invitation_handler<S>(
+    app: State<S>,
+    auth_user: AuthenticatedUser,
+    Path(invitation_id): Path<uuid::Uuid>,
+) -> Result<impl IntoResponse, RejectReason>
+where
+    S: HasStore + Clone + Send + Sync + 'static,
+{
+    let pool = app.pool();
+    require_super_admin(
+        &pool,
+        auth_user.id(),
+        "Only super_admin can revoke invitations",
+    )
+    .await?;
+
+    let revoked = InvitationRow::revoke(&pool, auth_user.id(), invitation_id)
+        .await
+        .map_err(|_| RejectReason::database("Failed to reach database"))?;
+    if !revoked {
+        return Err(RejectReason::not_found("Outstanding invitation not found"));
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Error rates, latencies and circuit breaker state of the identity providers. Restricted to
+/// super_admin.
+pub async fn identity_provider_health_handler<S>(
+    app: State<S>,
+    auth_user: AuthenticatedUser,
+) -> Result<impl IntoResponse, RejectReason>
+where
+    S: AuthApp + Clone + Send + Sync + 'static,
+{
+    require_super_admin(
+        &app.pool(),
+        auth_user.id(),
+        "Only super_admin can view identity provider health",
+    )
+    .await?;
+    Ok(Json(app.identity_provider_health()))
+}
+
+/// Every role bundle with its grants. Restricted to super_admin.
+pub async fn bundles_handler<S>(
+    app: State<S>,
+    auth_user: AuthenticatedUser,
+) -> Result<impl IntoResponse, RejectReason>
+where
+    S: HasStore + Clone + Send + Sync + 'static,
+{
+    let pool = app.reader_pool();
+    require_super_admin(
+        &app.pool(),
+        auth_user.id(),
+        "Only super_admin can view role bundles",
+    )
+    .await?;
+
+    let bundles = list_bundles(&pool)
+        .await
+        .map_err(|_| RejectReason::database("Failed to reach database"))?;
+    Ok(Json(bundles))
+}
+
+/// A single role bundle. Restricted to super_admin.
+pub async fn bundle_handler<S>(
+    app: State<S>,
+    auth_user: AuthenticatedUser,
+    Path(name): Path<String>,
+) -> Result<impl IntoResponse, RejectReason>
+where
+    S: HasStore + Clone + Send + Sync + 'static,
+{
+    let pool = app.reader_pool();
+    require_super_admin(
+        &app.pool(),
+        auth_user.id(),
+        "Only super_admin can view role bundles",
+    )
+    .await?;
+
+    let bundle = get_bundle(&pool, &name)
+        .await
+        .map_err(|_| RejectReason::database("Failed to reach database"))?
+        .ok_or_else(|| RejectReason::not_found("Role bundle not found"))?;
+    Ok(Json(bundle))
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct RoleBundleContent {
+    #[serde(default)]
+    pub description: Option<String>,
+    pub grants: Vec<BundleGrant>,
+}
+
+/// Create or replace a role bundle. Restricted to super_admin; bundles cannot include
+/// super_admin.
+pub async fn bundle_save_handler<S>(
+    app: State<S>,
+    auth_user: AuthenticatedUser,
+    Path(name): Path<String>,
+    Json(payload): Json<RoleBundleContent>,
+) -> Result<impl IntoResponse, RejectReason>
+where
+    S: HasStore + Clone + Send + Sync + 'static,
+{
+    let pool = app.pool();
+    require_super_admin(
+        &pool,
+        auth_user.id(),
+        "Only super_admin can manage role bundles",
+    )
+    .await?;
+
+    let name = name.trim();
+    if name.is_empty() {
+        return Err(RejectReason::bad_request("Bundle name must not be empty"));
+    }
+    let mut grants = Vec::with_capacity(payload.grants.len());
+    for grant in payload.grants {
+        let scope = grant.scope.trim();
+        let role_name = grant.role_name.trim();
+        if scope.is_empty() || role_name.is_empty() {
+            return Err(RejectReason::bad_request(
+                "Every grant needs a scope and role_name",
+            ));
+        }
+        validate_scope(scope).map_err(RejectReason::bad_request)?;
+        if role_name == SUPER_ADMIN_ROLE {
+            return Err(RejectReason::bad_request(
+                "Role bundles cannot grant super_admin",
+            ));
+        }
+        grants.push(BundleGrant {
+            scope: scope.to_string(),
+            role_name: role_name.to_string(),
+        });
+    }
+    grants.sort();
+    grants.dedup();
+
+    let bundle = RoleBundle {
+        name: name.to_string(),
+        description: payload.description,
+        grants,
+    };
+    save_bundle(&pool, auth_user.id(), &bundle)
+        .await
+        .map_err(|_| RejectReason::database("Failed to reach database"))?;
+    Ok(Json(bundle))
+}
+
+/// Delete a role bundle; grants it already handed out are kept. Restricted to super_admin.
+pub async fn bundle_delete_handler<S>(
+    app: State<S>,
+    auth_user: AuthenticatedUser,
+    Path(name): Path<String>,
+) -> Result<impl IntoResponse, RejectReason>
+where
+    S: HasStore + Clone + Send + Sync + 'static,
+{
+    let pool = app.pool();
+    require_super_admin(
+        &pool,
+        auth_user.id(),
+        "Only super_admin can manage role bundles",
+    )
+    .await?;
+
+    let deleted = delete_bundle(&pool, auth_user.id(), &name)
+        .await
+        .map_err(|_| RejectReason::database("Failed to reach database"))?;
+    if !deleted {
+        return Err(RejectReason::not_found("Role bundle not found"));
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct BundleApplyContent {
+    pub user_id: UserId,
+    pub scope_id: String,
+}
+
+/// Grant every role in a bundle to a user at one scope id. Restricted to super_admin.
+pub async fn bundle_apply_handler<S>(
+    app: State<S>,
+    auth_user: AuthenticatedUser,
+    Path(name): Path<String>,
+    Json(payload): Json<BundleApplyContent>,
+) -> Result<impl IntoResponse, RejectReason>
+where
+    S: HasStore + Clone + Send + Sync + 'static,
+{
+    let pool = app.pool();
+    require_super_admin(
+        &pool,
+        auth_user.id(),
+        "Only super_admin can apply role bundles",
+    )
+    .await?;
+
+    let scope_id = payload.scope_id.trim();
+    if scope_id.is_empty() {
+        return Err(RejectReason::bad_request("scope_id is required"));
+    }
+    if !is_valid_scope_id_pattern(scope_id) {
+        return Err(RejectReason::bad_request(
+            "scope_id may only use * as its last character",
+        ));
+    }
+    let target_exists = UserRow::get(&pool, payload.user_id)
+        .await
+        .map_err(|_| RejectReason::database("Failed to reach database"))?
+        .is_some();
+    if !target_exists {
+        return Err(RejectReason::not_found("User not found"));
+    }
+
+    let application = apply_bundle(&pool, auth_user.id(), payload.user_id, &name, scope_id)
+        .await
+        .map_err(|_| RejectReason::database("Failed to reach database"))?
+        .ok_or_else(|| RejectReason::not_found("Role bundle not found"))?;
+    if !application.granted.is_empty() {
+        invalidate_role_snapshots(payload.user_id);
+    }
+    Ok(Json(application))
+}
+
+pub fn routes<S>(store: MemoryStore) -> Router<S>
+where
+    S: AuthApp + Clone + Send + Sync + 'static,
+{
+    routes_with_config(store, &SessionConfig::default())
+}
+
+/// `routes` with the session cookie configured by `session`, e.g. from `AuthConfig::session`.
+pub fn routes_with_config<S>(store: MemoryStore, session: &SessionConfig) -> Router<S>
+where
+    S: AuthApp + Clone + Send + Sync + 'static,
+{
+    routes_with_registration(store, session, RegistrationMode::Open)
+}
+
+/// `routes_with_config` where `registration` decides whether `GET /auth/me` creates accounts. With
+/// `RegistrationMode::InvitationOnly` it serves `invitation_only_self_handler` instead, so the
+/// only way to an account is `POST /auth/invitations/accept`.
+pub fn routes_with_registration<S>(
+    store: MemoryStore,
+    session: &SessionConfig,
+    registration: RegistrationMode,
+) -> Router<S>
+where
+    S: AuthApp + Clone + Send + Sync + 'static,
+{
+    routes_from_ref::<S, S>(store, session, registration)
+}
+
+/// `routes_with_registration` for a router whose state `O` only contains the auth state `S`,
+/// e.g. a `Router<AppState>` with `AuthState: FromRef<AppState>`. The handlers extract `S` from
+/// it, so `O` needs none of the auth traits; `auth::AuthLayer` still takes the `S` itself.
+///
+/// ```ignore
+/// let app: Router<AppState> = Router::new()
+///     .merge(routes_from_ref::<AuthState, AppState>(store, &config.session, config.registration))
+///     .route("/reports", get(list_reports))
+///     .layer(AuthLayer::new(app_state.auth.clone()));
+/// ```
+pub fn routes_from_ref<S, O>(
+    store: MemoryStore,
+    session: &SessionConfig,
+    registration: RegistrationMode,
+) -> Router<O>
+where
+    S: AuthApp + FromRef<O> + Clone + Send + Sync + 'static,
+    O: Clone + Send + Sync + 'static,
+{
+    let layer = session_layer(store.clone(), session);
+    api_routes::<S, O>(store, registration).layer(layer)
+}
+
+/// `routes_with_registration` once for each of `versions`, under its prefix, e.g.
+/// `/v2/auth/me`. Each copy answers with the `User` and `Group` shapes of its version.
+///
+/// The unprefixed `/auth/...` paths are not included; merge in `routes_with_registration` to keep
+/// serving them, as v1, to clients that predate versioning.
+pub fn versioned_routes<S>(
+    store: MemoryStore,
+    session: &SessionConfig,
+    registration: RegistrationMode,
+    versions: &[ApiVersion],
+) -> Router<S>
+where
+    S: AuthApp + Clone + Send + Sync + 'static,
+{
+    let layer = session_layer(store.clone(), session);
+    let mut router = Router::new();
+    for version in versions {
+        tracing::info!(
+            "Registering API {} under {}",
+            version.as_str(),
+            version.prefix()
+        );
+        router = router.nest(
+            version.prefix(),
+            api_routes::<S, S>(store.clone(), registration).layer(Extension(*version)),
+        );
+    }
+    router.layer(layer)
+}
+
+fn session_layer(store: MemoryStore, session: &SessionConfig) -> SessionManagerLayer<MemoryStore> {
     let layer = SessionManagerLayer::new(store)
-        .with_secure(false)
-        .with_same_site(SameSite::Lax) // Ensure we send the cookie from the OAuth redirect.
-        .with_expiry(Expiry::OnInactivity(Duration::days(1)));
-    Router::new()
+        .with_secure(session.secure)
+        .with_same_site(session.same_site.into())
+        .with_expiry(Expiry::OnInactivity(session.inactivity()));
+    match &session.cookie_name {
+        Some(cookie_name) => layer.with_name(cookie_name.clone()),
+        None => layer,
+    }
+}
+
+/// Every route, without the session layer.
+fn api_routes<S, O>(store: MemoryStore, registration: RegistrationMode) -> Router<O>
+where
+    S: AuthApp + FromRef<O> + Clone + Send + Sync + 'static,
+    O: Clone + Send + Sync + 'static,
+{
+    tracing::info!("Registering route /auth/me [GET,PUT,PATCH]");
+    tracing::info!("Registering route /auth/me/username [PUT]");
+    tracing::info!("Registering route /auth/me/locale [PUT]");
+    tracing::info!("Registering route /auth/me/groups [GET]");
+    tracing::info!("Registering route /auth/me/permissions [GET]");
+    tracing::info!("Registering route /auth/me/updates [GET]");
+    tracing::info!("Registering route /auth/me/deactivate [POST]");
+    tracing::info!("Registering route /auth/me/leave [POST]");
+    tracing::info!("Registering route /auth/me/logins [GET]");
+    tracing::info!("Registering route /auth/me/activity [GET]");
+    tracing::info!("Registering route /auth/me/notifications [GET,PUT]");
+    tracing::info!("Registering route /auth/me/sessions [GET]");
+    tracing::info!("Registering route /auth/me/sessions/{{session_id}} [DELETE]");
+    tracing::info!("Registering route /auth/logins [GET]");
+    tracing::info!("Registering route /auth/roles [GET]");
+    tracing::info!("Registering route /auth/roles/grant [POST]");
+    tracing::info!("Registering route /auth/roles/deny [POST]");
+    tracing::info!("Registering route /auth/roles/revoke [POST]");
+    tracing::info!("Registering route /auth/check/explain [POST]");
+    tracing::info!("Registering route /auth/health [GET]");
+    tracing::info!("Registering route /auth/ready [GET]");
+    tracing::info!("Registering route /auth/admin/schema [GET]");
+    tracing::info!("Registering route /auth/admin/integrity [GET]");
+    tracing::info!("Registering route /auth/admin/integrity/repair [POST]");
+    tracing::info!("Registering route /auth/admin/stats [GET]");
+    tracing::info!("Registering route /auth/admin/reports/{{report}} [GET]");
+    tracing::info!("Registering route /auth/access/snapshot [GET]");
+    tracing::info!("Registering route /auth/access/diff [POST]");
+    tracing::info!("Registering route /auth/sync/changes [GET]");
+    tracing::info!("Registering route /auth/admin/keys/rotate [POST]");
+    tracing::info!("Registering route /auth/permission-tokens [POST]");
+    tracing::info!("Registering route /auth/.well-known/jwks.json [GET]");
+    tracing::info!("Registering route /auth/.well-known/openid-configuration [GET]");
+    tracing::info!("Registering route /auth/permission-tokens/revocations [GET]");
+    tracing::info!("Registering route /auth/admin/permission-tokens/revoke [POST]");
+    tracing::info!("Registering route /auth/admin/clients [GET,POST]");
+    tracing::info!("Registering route /auth/admin/clients/{{client_id}} [GET,PUT,DELETE]");
+    tracing::info!("Registering route /auth/admin/clients/{{client_id}}/secret [POST]");
+    tracing::info!("Registering route /auth/invitations/accept [POST]");
+    tracing::info!("Registering route /auth/admin/invitations [GET,POST]");
+    tracing::info!("Registering route /auth/admin/invitations/{{invitation_id}} [DELETE]");
+    tracing::info!("Registering route /auth/admin/identity-providers [GET]");
+    tracing::info!("Registering route /auth/admin/pending-users [GET]");
+    tracing::info!("Registering route /auth/admin/pending-users/{{user_id}}/approve [POST]");
+    tracing::info!("Registering route /auth/admin/users/{{user_id}}/external-id [PUT]");
+    tracing::info!("Registering route /auth/admin/users/{{user_id}}/deactivation [GET]");
+    tracing::info!("Registering route /auth/admin/users/{{user_id}}/deactivate [POST]");
+    tracing::info!("Registering route /auth/admin/users/{{user_id}}/reactivate [POST]");
+    tracing::info!("Registering route /auth/admin/groups/{{group_id}}/deactivation [GET]");
+    tracing::info!("Registering route /auth/admin/groups/{{group_id}}/deactivate [POST]");
+    tracing::info!("Registering route /auth/admin/groups/{{group_id}}/reactivate [POST]");
+    tracing::info!("Registering route /auth/admin/groups/{{group_id}}/archival [GET]");
+    tracing::info!("Registering route /auth/admin/groups/{{group_id}}/archive [POST]");
+    tracing::info!("Registering route /auth/admin/groups/{{group_id}}/unarchive [POST]");
+    tracing::info!("Registering route /auth/admin/users/{{user_id}} [DELETE]");
+    tracing::info!("Registering route /auth/admin/users/{{user_id}}/restore [POST]");
+    tracing::info!("Registering route /auth/admin/groups/{{group_id}} [DELETE]");
+    tracing::info!("Registering route /auth/admin/groups/{{group_id}}/restore [POST]");
+    tracing::info!("Registering route /auth/admin/deleted/users [GET]");
+    tracing::info!("Registering route /auth/admin/deleted/groups [GET]");
+    tracing::info!("Registering route /auth/admin/users/{{user_id}}/suspensions [GET,POST]");
+    tracing::info!("Registering route /auth/admin/suspensions/{{suspension_id}}/lift [POST]");
+    tracing::info!("Registering route /auth/reports [POST]");
+    tracing::info!("Registering route /auth/admin/moderation/reports [GET]");
+    tracing::info!("Registering route /auth/admin/moderation/reports/{{report_id}} [GET]");
+    tracing::info!("Registering route /auth/admin/moderation/reports/{{report_id}}/status [POST]");
+    tracing::info!(
+        "Registering route /auth/admin/moderation/reports/{{report_id}}/evidence [POST]"
+    );
+    tracing::info!("Registering route /auth/admin/bundles [GET]");
+    tracing::info!("Registering route /auth/admin/bundles/{{name}} [GET,PUT,DELETE]");
+    tracing::info!("Registering route /auth/admin/bundles/{{name}}/apply [POST]");
+    tracing::info!("Registering route /auth/groups/{{group_id}}/details [PATCH]");
+    tracing::info!("Registering route /auth/groups/discover [GET]");
+    tracing::info!("Registering route /auth/groups/{{group_id}}/join [POST]");
+    tracing::info!("Registering route /auth/groups/{{group_id}}/join-requests [GET]");
+    tracing::info!("Registering route /auth/groups/{{group_id}}/join-requests/{{user_id}} [POST]");
+    tracing::info!("Registering route /auth/groups/{{group_id}}/visibility [PUT]");
+    tracing::info!("Registering route /auth/groups/{{group_id}}/external-id [PUT]");
+    tracing::info!("Registering route /auth/groups/{{group_id}}/roles [GET]");
+    tracing::info!("Registering route /auth/groups/{{group_id}}/roles/{{role_name}} [PUT,DELETE]");
+    tracing::info!("Registering route /auth/groups/{{group_id}}/members [POST]");
+    tracing::info!("Registering route /auth/groups/{{group_id}}/members/{{user_id}} [DELETE]");
+    tracing::info!("Registering route /auth/groups/{{group_id}}/members/{{user_id}}/role [PUT]");
+    tracing::info!(
+        "Registering route /auth/groups/{{group_id}}/members/{{user_id}}/capabilities [PUT]"
+    );
+    tracing::info!("Registering route /auth/groups/{{group_id}}/members:sync [PUT]");
+    tracing::info!("Registering route /auth/groups/{{group_id}}/default-roles [GET]");
+    tracing::info!(
+        "Registering route /auth/groups/{{group_id}}/default-roles/{{scope}}/{{scope_id}}/{{role_name}} [PUT,DELETE]"
+    );
+    #[cfg(feature = "password-hashing")]
+    {
+        tracing::info!("Registering route /auth/me/reauthenticate [POST]");
+        tracing::info!("Registering route /auth/me/password [PUT]");
+    }
+    #[cfg(feature = "oauth-server")]
+    {
+        tracing::info!("Registering route /auth/oauth/authorize [GET,POST]");
+        tracing::info!("Registering route /auth/oauth/token [POST]");
+        tracing::info!("Registering route /auth/oauth/userinfo [GET]");
+        tracing::info!("Registering route /auth/me/authorizations [GET]");
+        tracing::info!("Registering route /auth/me/authorizations/{{client_id}} [DELETE]");
+    }
+    let self_get = match registration {
+        RegistrationMode::Open => get(self_handler::<S>),
+        RegistrationMode::InvitationOnly => get(invitation_only_self_handler::<S>),
+    };
+    let router = Router::new()
         .route(
             "/auth/me",
-            get(self_handler::<S>).put(self_update_handler::<S>),
+            self_get
+                .put(self_update_handler::<S>)
+                .patch(self_profile_handler::<S>),
         )
+        .route("/auth/me/username", put(self_username_handler::<S>))
+        .route("/auth/me/locale", put(self_locale_handler::<S>))
         .route("/auth/me/groups", get(self_groups_handler::<S>))
         .route("/auth/me/permissions", get(self_permissions_handler::<S>))
+        .route("/auth/me/updates", get(self_updates_handler::<S>))
         .route("/auth/me/deactivate", post(self_deactivate_handler::<S>))
         .route("/auth/me/leave", post(self_leave_group_handler::<S>))
+        .route("/auth/me/logins", get(self_logins_handler::<S>))
+        .route("/auth/me/activity", get(self_activity_handler::<S>))
+        .route(
+            "/auth/me/notifications",
+            get(self_notifications_handler::<S>).put(self_notifications_update_handler::<S>),
+        )
+        .route("/auth/me/sessions", get(self_sessions_handler::<S>))
+        .route(
+            "/auth/me/sessions/{session_id}",
+            delete(revoke_self_session_handler::<S>),
+        )
+        .route("/auth/logins", get(user_logins_handler::<S>))
         .route("/auth/roles", get(roles_handler::<S>))
         .route("/auth/roles/grant", post(role_grant_handler::<S>))
+        .route("/auth/roles/deny", post(role_deny_handler::<S>))
         .route("/auth/roles/revoke", post(role_revoke_handler::<S>))
-        .layer(layer)
+        .route("/auth/check/explain", post(check_explain_handler::<S>))
+        .route("/auth/health", get(health_handler))
+        .route(
+            "/auth/ready",
+            get(move |app: State<S>| ready_handler(app, store.clone())),
+        )
+        .route("/auth/admin/schema", get(schema_status_handler::<S>))
+        .route("/auth/admin/integrity", get(integrity_check_handler::<S>))
+        .route(
+            "/auth/admin/integrity/repair",
+            post(integrity_repair_handler::<S>),
+        )
+        .route("/auth/admin/stats", get(admin_stats_handler::<S>))
+        .route(
+            "/auth/admin/reports/{report}",
+            get(admin_report_handler::<S>),
+        )
+        .route("/auth/access/snapshot", get(access_snapshot_handler::<S>))
+        .route("/auth/access/diff", post(access_diff_handler::<S>))
+        .route("/auth/sync/changes", get(sync_changes_handler::<S>))
+        .route("/auth/admin/keys/rotate", post(rotate_keys_handler::<S>))
+        .route(
+            "/auth/permission-tokens",
+            post(issue_permission_token_handler::<S>),
+        )
+        .route("/auth/.well-known/jwks.json", get(jwks_handler::<S>))
+        .route(
+            "/auth/.well-known/openid-configuration",
+            get(issuer_metadata_handler::<S>),
+        )
+        .route(
+            "/auth/permission-tokens/revocations",
+            get(permission_token_revocations_handler::<S>),
+        )
+        .route(
+            "/auth/admin/permission-tokens/revoke",
+            post(revoke_permission_tokens_handler::<S>),
+        )
+        .route(
+            "/auth/admin/clients",
+            get(clients_handler::<S>).post(create_client_handler::<S>),
+        )
+        .route(
+            "/auth/admin/clients/{client_id}",
+            get(client_handler::<S>)
+                .put(update_client_handler::<S>)
+                .delete(disable_client_handler::<S>),
+        )
+        .route(
+            "/auth/admin/clients/{client_id}/secret",
+            post(rotate_client_secret_handler::<S>),
+        )
+        .route(
+            "/auth/invitations/accept",
+            post(accept_invitation_handler::<S>),
+        )
+        .route(
+            "/auth/admin/invitations",
+            get(invitations_handler::<S>).post(create_invitations_handler::<S>),
+        )
+        .route(
+            "/auth/admin/invitations/{invitation_id}",
+            delete(revoke_invitation_handler::<S>),
+        )
+        .route(
+            "/auth/admin/identity-providers",
+            get(identity_provider_health_handler::<S>),
+        )
+        .route("/auth/admin/pending-users", get(pending_users_handler::<S>))
+        .route(
+            "/auth/admin/pending-users/{user_id}/approve",
+            post(approve_pending_user_handler::<S>),
+        )
+        .route(
+            "/auth/admin/users/{user_id}/external-id",
+            put(user_external_id_handler::<S>),
+        )
+        .route(
+            "/auth/admin/users/{user_id}/deactivation",
+            get(user_deactivation_handler::<S>),
+        )
+        .route(
+            "/auth/admin/users/{user_id}/deactivate",
+            post(deactivate_user_handler::<S>),
+        )
+        .route(
+            "/auth/admin/users/{user_id}/reactivate",
+            post(reactivate_user_handler::<S>),
+        )
+        .route(
+            "/auth/admin/groups/{group_id}/deactivation",
+            get(group_deactivation_handler::<S>),
+        )
+        .route(
+            "/auth/admin/groups/{group_id}/deactivate",
+            post(deactivate_group_handler::<S>),
+        )
+        .route(
+            "/auth/admin/groups/{group_id}/reactivate",
+            post(reactivate_group_handler::<S>),
+        )
+        .route(
+            "/auth/admin/groups/{group_id}/archival",
+            get(group_archival_handler::<S>),
+        )
+        .route(
+            "/auth/admin/groups/{group_id}/archive",
+            post(archive_group_handler::<S>),
+        )
+        .route(
+            "/auth/admin/groups/{group_id}/unarchive",
+            post(unarchive_group_handler::<S>),
+        )
+        .route(
+            "/auth/admin/users/{user_id}",
+            delete(delete_user_handler::<S>),
+        )
+        .route(
+            "/auth/admin/users/{user_id}/restore",
+            post(restore_user_handler::<S>),
+        )
+        .route(
+            "/auth/admin/groups/{group_id}",
+            delete(delete_group_handler::<S>),
+        )
+        .route(
+            "/auth/admin/groups/{group_id}/restore",
+            post(restore_group_handler::<S>),
+        )
+        .route("/auth/admin/deleted/users", get(deleted_users_handler::<S>))
+        .route(
+            "/auth/admin/deleted/groups",
+            get(deleted_groups_handler::<S>),
+        )
+        .route(
+            "/auth/admin/users/{user_id}/suspensions",
+            get(user_suspensions_handler::<S>).post(suspend_user_handler::<S>),
+        )
+        .route(
+            "/auth/admin/suspensions/{suspension_id}/lift",
+            post(lift_suspension_handler::<S>),
+        )
+        .route("/auth/reports", post(file_report_handler::<S>))
+        .route(
+            "/auth/admin/moderation/reports",
+            get(moderation_queue_handler::<S>),
+        )
+        .route(
+            "/auth/admin/moderation/reports/{report_id}",
+            get(moderation_report_handler::<S>),
+        )
+        .route(
+            "/auth/admin/moderation/reports/{report_id}/status",
+            post(moderation_report_status_handler::<S>),
+        )
+        .route(
+            "/auth/admin/moderation/reports/{report_id}/evidence",
+            post(moderation_evidence_handler::<S>),
+        )
+        .route("/auth/admin/bundles", get(bundles_handler::<S>))
+        .route(
+            "/auth/admin/bundles/{name}",
+            get(bundle_handler::<S>)
+                .put(bundle_save_handler::<S>)
+                .delete(bundle_delete_handler::<S>),
+        )
+        .route(
+            "/auth/admin/bundles/{name}/apply",
+            post(bundle_apply_handler::<S>),
+        )
+        .route(
+            "/auth/groups/{group_id}/details",
+            patch(group_details_patch_handler::<S>),
+        )
+        .route("/auth/groups/discover", get(group_discover_handler::<S>))
+        .route(
+            "/auth/groups/{group_id}/join",
+            post(group_join_handler::<S>),
+        )
+        .route(
+            "/auth/groups/{group_id}/join-requests",
+            get(group_join_requests_handler::<S>),
+        )
+        .route(
+            "/auth/groups/{group_id}/join-requests/{user_id}",
+            post(group_join_request_decision_handler::<S>),
+        )
+        .route(
+            "/auth/groups/{group_id}/visibility",
+            put(group_visibility_handler::<S>),
+        )
+        .route(
+            "/auth/groups/{group_id}/external-id",
+            put(group_external_id_handler::<S>),
+        )
+        .route(
+            "/auth/groups/{group_id}/roles",
+            get(group_roles_handler::<S>),
+        )
+        .route(
+            "/auth/groups/{group_id}/roles/{role_name}",
+            put(group_role_define_handler::<S>).delete(group_role_delete_handler::<S>),
+        )
+        .route(
+            "/auth/groups/{group_id}/members",
+            post(group_member_add_handler::<S>),
+        )
+        .route(
+            "/auth/groups/{group_id}/members/{user_id}",
+            delete(group_member_remove_handler::<S>),
+        )
+        .route(
+            "/auth/groups/{group_id}/members/{user_id}/role",
+            put(group_member_role_handler::<S>),
+        )
+        .route(
+            "/auth/groups/{group_id}/members/{user_id}/capabilities",
+            put(group_member_capabilities_handler::<S>),
+        )
+        .route(
+            "/auth/groups/{group_id}/members:sync",
+            put(group_members_sync_handler::<S>),
+        )
+        .route(
+            "/auth/groups/{group_id}/default-roles",
+            get(group_default_roles_handler::<S>),
+        )
+        .route(
+            "/auth/groups/{group_id}/default-roles/{scope}/{scope_id}/{role_name}",
+            put(group_default_role_add_handler::<S>).delete(group_default_role_remove_handler::<S>),
+        );
+    #[cfg(feature = "oauth-server")]
+    let router = router
+        .route(
+            "/auth/oauth/authorize",
+            get(authorize_handler::<S>).post(consent_handler::<S>),
+        )
+        .route("/auth/oauth/token", post(token_handler::<S>))
+        .route("/auth/oauth/userinfo", get(userinfo_handler::<S>))
+        .route("/auth/me/authorizations", get(authorizations_handler::<S>))
+        .route(
+            "/auth/me/authorizations/{client_id}",
+            delete(revoke_authorization_handler::<S>),
+        );
+    #[cfg(feature = "password-hashing")]
+    let router = router
+        .route(
+            "/auth/me/reauthenticate",
+            post(self_reauthenticate_handler::<S>),
+        )
+        .route("/auth/me/password", put(self_password_handler::<S>));
+    router
+}
+
+#[cfg(test)]
+mod tests {
+    use hyper::header::IF_NONE_MATCH;
+    use hyper::{HeaderMap, StatusCode};
+
+    use super::{ProfileContent, conditional_content, if_none_match};
+
+    #[test]
+    fn matches_if_none_match_weakly() {
+        let mut headers = HeaderMap::new();
+        assert!(!if_none_match(&headers, "W/\"3\""));
+        headers.insert(IF_NONE_MATCH, "\"2\", W/\"3\"".parse().unwrap());
+        assert!(if_none_match(&headers, "W/\"3\""));
+        assert!(if_none_match(&headers, "\"2\""));
+        assert!(!if_none_match(&headers, "W/\"4\""));
+        headers.insert(IF_NONE_MATCH, "*".parse().unwrap());
+        assert!(if_none_match(&headers, "W/\"4\""));
+    }
+
+    #[test]
+    fn answers_not_modified_for_unchanged_content() {
+        let body = vec!["admin", "member"];
+        let response = conditional_content(&HeaderMap::new(), &body).unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let etag = response.headers()["etag"].clone();
+
+        let mut headers = HeaderMap::new();
+        headers.insert(IF_NONE_MATCH, etag);
+        let response = conditional_content(&headers, &body).unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        let response = conditional_content(&headers, &vec!["member"]).unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn profile_content_tells_null_from_missing() {
+        let content: ProfileContent =
+            serde_json::from_value(serde_json::json!({"display_name": null, "locale": "pt-BR"}))
+                .unwrap();
+        assert_eq!(content.display_name, Some(None));
+        assert_eq!(content.locale, Some(Some("pt-BR".to_string())));
+        assert_eq!(content.timezone, None);
+        assert!(
+            serde_json::from_value::<ProfileContent>(serde_json::json!({"name": "Ada"})).is_err()
+        );
+    }
 }
@@ -0,0 +1,175 @@
+//! Response shapes of `ApiVersion::V2`.
+//!
+//! Changes from v1:
+//! - `User::details` is `{}` rather than `null` when the user has none.
+//! - `Group` carries `display_name` instead of `name`, and its `visibility`. Group discovery
+//!   returns it too, in place of `DiscoverableGroup`.
+
+use std::fmt;
+
+use serde::Serialize;
+use serde_json::{Map, Value};
+
+use crate::db::{GroupRow, GroupVisibility, UserRow};
+use crate::prelude::{GroupId, UserId};
+use crate::redact::Sensitive;
+
+#[derive(Clone, Serialize)]
+pub struct User {
+    pub id: UserId,
+    pub external_id: Option<String>,
+    pub username: Option<String>,
+    pub email: String,
+    pub details: Value,
+    pub version: i64,
+    pub display_name: Option<String>,
+    pub locale: Option<String>,
+    /// IANA time zone name, e.g. `Europe/Lisbon`.
+    pub timezone: Option<String>,
+}
+
+impl fmt::Debug for User {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("User")
+            .field("id", &self.id)
+            .field("external_id", &self.external_id)
+            .field("username", &self.username.as_ref().map(Sensitive))
+            .field("email", &Sensitive(&self.email))
+            .field("details", &Sensitive(&self.details))
+            .field("version", &self.version)
+            .field("display_name", &self.display_name.as_ref().map(Sensitive))
+            .field("locale", &self.locale)
+            .field("timezone", &self.timezone)
+            .finish()
+    }
+}
+
+impl From<UserRow> for User {
+    fn from(row: UserRow) -> Self {
+        super::User::from(row).into()
+    }
+}
+
+impl From<super::User> for User {
+    fn from(user: super::User) -> Self {
+        Self {
+            id: user.id,
+            external_id: user.external_id,
+            username: user.username,
+            email: user.email,
+            details: user.details.unwrap_or_else(|| Value::Object(Map::new())),
+            version: user.version,
+            display_name: user.display_name,
+            locale: user.locale,
+            timezone: user.timezone,
+        }
+    }
+}
+
+/// Empty details become `null` again.
+impl From<User> for super::User {
+    fn from(user: User) -> Self {
+        let details = match user.details {
+            Value::Object(map) if map.is_empty() => None,
+            Value::Null => None,
+            details => Some(details),
+        };
+        Self {
+            id: user.id,
+            external_id: user.external_id,
+            username: user.username,
+            email: user.email,
+            details,
+            version: user.version,
+            display_name: user.display_name,
+            locale: user.locale,
+            timezone: user.timezone,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Group {
+    pub id: GroupId,
+    pub external_id: Option<String>,
+    pub display_name: String,
+    pub visibility: GroupVisibility,
+    /// Frozen read-only; see `archival`.
+    pub archived: bool,
+}
+
+impl From<GroupRow> for Group {
+    fn from(row: GroupRow) -> Self {
+        Self {
+            id: row.id,
+            external_id: row.external_id,
+            display_name: row.display_name,
+            visibility: row.visibility,
+            archived: row.archived_at.is_some(),
+        }
+    }
+}
+
+impl From<super::DiscoverableGroup> for Group {
+    fn from(group: super::DiscoverableGroup) -> Self {
+        Self {
+            id: group.id,
+            external_id: group.external_id,
+            display_name: group.name,
+            visibility: group.visibility,
+            archived: group.archived,
+        }
+    }
+}
+
+impl From<Group> for super::Group {
+    fn from(group: Group) -> Self {
+        Self {
+            id: group.id,
+            external_id: group.external_id,
+            name: group.display_name,
+        }
+    }
+}
+
+impl From<Group> for super::DiscoverableGroup {
+    fn from(group: Group) -> Self {
+        Self {
+            id: group.id,
+            external_id: group.external_id,
+            name: group.display_name,
+            visibility: group.visibility,
+            archived: group.archived,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::User;
+    use crate::api;
+    use crate::prelude::UserId;
+
+    #[test]
+    fn converts_users_between_versions() {
+        let v1 = api::User {
+            id: UserId(uuid::Uuid::new_v4()),
+            external_id: Some("alice".to_string()),
+            username: None,
+            email: "alice@example.com".to_string(),
+            details: None,
+            version: 3,
+            display_name: Some("Alice".to_string()),
+            locale: None,
+            timezone: Some("Europe/Lisbon".to_string()),
+        };
+        let v2 = User::from(v1.clone());
+        assert_eq!(v2.details, json!({}));
+        assert_eq!(
+            serde_json::to_value(api::User::from(v2)).unwrap(),
+            serde_json::to_value(v1).unwrap()
+        );
+    }
+}
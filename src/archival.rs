@@ -0,0 +1,179 @@
+//! Archived groups: a read-only freeze, unlike deactivation.
+//!
+//! An archived group stays active, listed and queryable, and its grants still apply to its
+//! members, but nothing about it changes until it is unarchived: not its details, memberships,
+//! grants, role definitions, default roles or join requests. The schema enforces it, so a write
+//! that slips past the handlers' checks fails with an error `is_group_archived` recognizes.
+//! Archiving and unarchiving are logged to `auth.log`.
+
+use chrono::NaiveDateTime;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::{FromRow, PgPool};
+
+use crate::db::insert_audit_log;
+use crate::group_id::GroupId;
+use crate::user_id::UserId;
+
+/// Raised by the schema for a write to an archived group.
+pub const GROUP_ARCHIVED_SQLSTATE: &str = "SGA01";
+
+/// When and by whom a group was archived.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, FromRow)]
+pub struct GroupArchival {
+    pub archived_at: NaiveDateTime,
+    pub archived_by: Option<UserId>,
+}
+
+/// Which groups a listing returns by their archival.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ArchivedFilter {
+    #[default]
+    Exclude,
+    Include,
+    Only,
+}
+
+impl ArchivedFilter {
+    pub fn matches(self, archived: bool) -> bool {
+        match self {
+            Self::Exclude => !archived,
+            Self::Include => true,
+            Self::Only => archived,
+        }
+    }
+
+    /// Whether matching groups are archived, or `None` for either; bound as a query parameter.
+    pub(crate) fn archived(self) -> Option<bool> {
+        match self {
+            Self::Exclude => Some(false),
+            Self::Include => None,
+            Self::Only => Some(true),
+        }
+    }
+}
+
+/// The write failed because its group is archived.
+pub fn is_group_archived(err: &sqlx::Error) -> bool {
+    matches!(
+        err,
+        sqlx::Error::Database(err) if err.code().as_deref() == Some(GROUP_ARCHIVED_SQLSTATE)
+    )
+}
+
+/// Archive a group. Returns false if the group does not exist, is soft-deleted or is already
+/// archived.
+pub async fn archive_group(
+    pool: &PgPool,
+    actor_user_id: UserId,
+    group_id: GroupId,
+    note: Option<&str>,
+) -> Result<bool, sqlx::Error> {
+    let now = chrono::Utc::now().naive_utc();
+    let mut tx = pool.begin().await?;
+    let updated = sqlx::query(
+        r#"
+        UPDATE auth.groups
+        SET archived_at = $2,
+            archived_by = $3
+        WHERE id = $1
+          AND archived_at IS NULL
+          AND deleted_at IS NULL
+        "#,
+    )
+    .bind(group_id)
+    .bind(now)
+    .bind(actor_user_id)
+    .execute(&mut *tx)
+    .await?
+    .rows_affected();
+    if updated == 0 {
+        return Ok(false);
+    }
+    insert_audit_log(
+        &mut *tx,
+        Some(actor_user_id),
+        json!({
+            "type": "group_archived",
+            "group_id": group_id.to_string(),
+            "note": note,
+        }),
+    )
+    .await?;
+    tx.commit().await?;
+    Ok(true)
+}
+
+/// Unarchive a group. Returns false if the group does not exist, is soft-deleted or is not
+/// archived.
+pub async fn unarchive_group(
+    pool: &PgPool,
+    actor_user_id: UserId,
+    group_id: GroupId,
+    note: Option<&str>,
+) -> Result<bool, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    let updated = sqlx::query(
+        r#"
+        UPDATE auth.groups
+        SET archived_at = NULL,
+            archived_by = NULL
+        WHERE id = $1
+          AND archived_at IS NOT NULL
+          AND deleted_at IS NULL
+        "#,
+    )
+    .bind(group_id)
+    .execute(&mut *tx)
+    .await?
+    .rows_affected();
+    if updated == 0 {
+        return Ok(false);
+    }
+    insert_audit_log(
+        &mut *tx,
+        Some(actor_user_id),
+        json!({
+            "type": "group_unarchived",
+            "group_id": group_id.to_string(),
+            "note": note,
+        }),
+    )
+    .await?;
+    tx.commit().await?;
+    Ok(true)
+}
+
+/// How a group was archived. `None` if it is not archived or does not exist.
+pub async fn group_archival(
+    pool: &PgPool,
+    group_id: GroupId,
+) -> Result<Option<GroupArchival>, sqlx::Error> {
+    sqlx::query_as::<_, GroupArchival>(
+        r#"
+        SELECT archived_at, archived_by
+        FROM auth.groups
+        WHERE id = $1
+          AND archived_at IS NOT NULL
+        "#,
+    )
+    .bind(group_id)
+    .fetch_optional(pool)
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ArchivedFilter;
+
+    #[test]
+    fn filters_by_archival() {
+        let filter: ArchivedFilter = serde_json::from_value(serde_json::json!("only")).unwrap();
+        assert_eq!(filter, ArchivedFilter::Only);
+        assert!(filter.matches(true) && !filter.matches(false));
+        assert!(ArchivedFilter::default().matches(false));
+        assert!(!ArchivedFilter::default().matches(true));
+        assert_eq!(ArchivedFilter::Include.archived(), None);
+    }
+}
@@ -0,0 +1,190 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use sqlx::PgPool;
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+
+use crate::db::{LOG_BATCH_ROWS, LogRow};
+
+#[derive(Debug, Clone)]
+pub struct AuditWriterConfig {
+    /// Maximum number of queued entries before `write` applies backpressure.
+    pub capacity: usize,
+    /// Maximum number of rows written per `INSERT`, at most `db::LOG_BATCH_ROWS`.
+    pub max_batch: usize,
+    /// How long a partial batch may wait before it is written. Zero is treated as one millisecond.
+    pub flush_interval: Duration,
+}
+
+impl Default for AuditWriterConfig {
+    fn default() -> Self {
+        Self {
+            capacity: 4096,
+            max_batch: 256,
+            flush_interval: Duration::from_millis(250),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuditWriterError {
+    /// The writer task has shut down.
+    Closed,
+    /// The queue is full; only returned by `try_write`.
+    Full,
+}
+
+enum AuditCommand {
+    Entry(Box<LogRow>),
+    Flush(oneshot::Sender<()>),
+}
+
+/// Cloneable handle to a background task that batches `auth.log` inserts.
+///
+/// ```ignore
+/// let (audit, task) = AuditWriter::spawn(pool.clone(), AuditWriterConfig::default());
+/// audit.write(LogRow::new(user_id, json!({"type": "login"}))).await?;
+/// // On shutdown
+/// audit.shutdown().await;
+/// task.await.ok();
+/// ```
+#[derive(Clone)]
+pub struct AuditWriter {
+    tx: mpsc::Sender<AuditCommand>,
+    cancel: CancellationToken,
+    stopped: CancellationToken,
+}
+
+impl AuditWriter {
+    pub fn spawn(pool: Arc<PgPool>, config: AuditWriterConfig) -> (Self, JoinHandle<()>) {
+        Self::spawn_with_cancellation(pool, config, CancellationToken::new())
+    }
+
+    /// Like `spawn`, but the writer also shuts down, writing everything queued, once `cancel` is
+    /// cancelled. Share the host app's shutdown token to stop it with everything else.
+    pub fn spawn_with_cancellation(
+        pool: Arc<PgPool>,
+        config: AuditWriterConfig,
+        cancel: CancellationToken,
+    ) -> (Self, JoinHandle<()>) {
+        let (tx, rx) = mpsc::channel(config.capacity.max(1));
+        let cancel = cancel.child_token();
+        let stopped = CancellationToken::new();
+        let handle = tokio::spawn(run_writer(
+            pool,
+            config,
+            rx,
+            cancel.clone(),
+            stopped.clone(),
+        ));
+        (
+            Self {
+                tx,
+                cancel,
+                stopped,
+            },
+            handle,
+        )
+    }
+
+    /// Queue an entry, waiting for capacity if the queue is full.
+    pub async fn write(&self, row: LogRow) -> Result<(), AuditWriterError> {
+        self.tx
+            .send(AuditCommand::Entry(Box::new(row)))
+            .await
+            .map_err(|_| AuditWriterError::Closed)
+    }
+
+    /// Queue an entry without waiting.
+    pub fn try_write(&self, row: LogRow) -> Result<(), AuditWriterError> {
+        self.tx
+            .try_send(AuditCommand::Entry(Box::new(row)))
+            .map_err(|err| match err {
+                mpsc::error::TrySendError::Full(_) => AuditWriterError::Full,
+                mpsc::error::TrySendError::Closed(_) => AuditWriterError::Closed,
+            })
+    }
+
+    /// Wait until everything queued before this call has been written.
+    pub async fn flush(&self) -> Result<(), AuditWriterError> {
+        let (done, wait) = oneshot::channel();
+        self.tx
+            .send(AuditCommand::Flush(done))
+            .await
+            .map_err(|_| AuditWriterError::Closed)?;
+        wait.await.map_err(|_| AuditWriterError::Closed)
+    }
+
+    /// Stop accepting entries, write everything already queued, and wait for the task to stop.
+    pub async fn shutdown(&self) {
+        self.cancel.cancel();
+        self.stopped.cancelled().await;
+    }
+}
+
+async fn write_batch(pool: &PgPool, batch: &mut Vec<LogRow>) {
+    if batch.is_empty() {
+        return;
+    }
+    if let Err(err) = LogRow::insert_batch(pool, batch).await {
+        tracing::error!("Failed to write {} audit log entries: {}", batch.len(), err);
+    }
+    batch.clear();
+}
+
+async fn run_writer(
+    pool: Arc<PgPool>,
+    config: AuditWriterConfig,
+    mut rx: mpsc::Receiver<AuditCommand>,
+    cancel: CancellationToken,
+    stopped: CancellationToken,
+) {
+    // Cancelled however the task ends, so `shutdown` cannot hang.
+    let _stopped_guard = stopped.drop_guard();
+    let max_batch = config.max_batch.clamp(1, LOG_BATCH_ROWS);
+    let mut batch: Vec<LogRow> = Vec::with_capacity(max_batch);
+    let mut ticker = tokio::time::interval(config.flush_interval.max(Duration::from_millis(1)));
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+    loop {
+        tokio::select! {
+            command = rx.recv() => match command {
+                Some(command) => handle_command(&pool, &mut batch, max_batch, command).await,
+                None => break,
+            },
+            _ = ticker.tick() => {
+                write_batch(&pool, &mut batch).await;
+            }
+            _ = cancel.cancelled() => {
+                rx.close();
+                while let Some(command) = rx.recv().await {
+                    handle_command(&pool, &mut batch, max_batch, command).await;
+                }
+                break;
+            }
+        }
+    }
+    write_batch(&pool, &mut batch).await;
+}
+
+async fn handle_command(
+    pool: &PgPool,
+    batch: &mut Vec<LogRow>,
+    max_batch: usize,
+    command: AuditCommand,
+) {
+    match command {
+        AuditCommand::Entry(row) => {
+            batch.push(*row);
+            if batch.len() >= max_batch {
+                write_batch(pool, batch).await;
+            }
+        }
+        AuditCommand::Flush(done) => {
+            write_batch(pool, batch).await;
+            done.send(()).ok();
+        }
+    }
+}
@@ -2,6 +2,8 @@ use std::convert::Infallible;
 use std::future;
 use std::task::{Context, Poll};
 
+#[cfg(feature = "sqlx")]
+use axum::{Form, Json};
 use axum::{
     extract::{FromRequestParts, Query, Request},
     http::{
@@ -14,21 +16,49 @@ use axum::{
 use axum_extra::extract::CookieJar as AxumCookieJar;
 use cookie::{Cookie, CookieJar, SameSite};
 use futures_util::future::BoxFuture;
-use hyper::body::Incoming;
-use openidconnect::{AuthorizationCode, Nonce, PkceCodeVerifier};
+use openidconnect::{AuthorizationCode, CsrfToken, Nonce, PkceCodeVerifier};
 use serde::Deserialize;
-use tower::Service;
+#[cfg(feature = "sqlx")]
+use serde_json::json;
+#[cfg(feature = "sqlx")]
+use sqlx::PgPool;
+use tower::{Layer, Service};
 use tower_sessions::Session;
+use url::Url;
 use urlencoding::decode;
-
+#[cfg(feature = "sqlx")]
+use uuid::Uuid;
+
+use crate::breaker::ProviderUnavailable;
+#[cfg(feature = "sqlx")]
+use crate::claims::{ClaimsMapper, sync_claims};
+#[cfg(feature = "sqlx")]
+use crate::db::{UserRow, is_new_device, record_provider_login};
+use crate::logout::session_logged_out;
+#[cfg(feature = "sqlx")]
+use crate::logout::{LogoutTokenError, record_federated_logout, store_federated_logout};
+#[cfg(feature = "sqlx")]
+use crate::notify::{NewDeviceLoginContext, Notification, Notifier, Recipient, notify_user};
 use crate::oidc::{IdentityProvider, OidcToken};
 use crate::prelude::{
     AuthRejectReason, AuthenticatedUser, MaybeAuthenticatedUser, RejectReason, ValidatesIdentity,
     validate_bearer,
 };
+#[cfg(feature = "sqlx")]
+use crate::prelude::{CoreIdToken, validated_token_claims};
+#[cfg(feature = "sqlx")]
+use crate::remember::{DEFAULT_REMEMBER_FOR, remember_session};
+#[cfg(feature = "sqlx")]
+use crate::user_id::UserId;
 
 pub const AUTH_COOKIE: &str = "access_token";
 
+/// Cookie holding a remember-me token; see `remember::remember_me_layer`.
+pub const REMEMBER_COOKIE: &str = "remember_me";
+
+/// Session key for the `remember` flag between `login` and the code exchange.
+const REMEMBER_ME_KEY: &str = "remember_me";
+
 /// Validate a bearer token from an Authorization header
 ///
 /// Implement like this in your application state:
@@ -38,7 +68,7 @@ pub const AUTH_COOKIE: &str = "access_token";
 ///    .await
 ///    .expect("TLS conf");
 /// let addr = SocketAddr::from(([0, 0, 0, 0], PORT));
-
+///
 /// axum_server::bind_rustls(addr, config)
 ///     .serve(authed.into_make_service())
 ///     .await
@@ -108,6 +138,14 @@ where
             }
         };
 
+        if session_logged_out(&auth_user.authorization, &auth_user.claims) {
+            tracing::debug!(
+                "Session of {} was ended by the identity provider",
+                auth_user.id
+            );
+            return None;
+        }
+
         if let Some(reset_token) = token {
             tracing::trace!("Reset token");
             cookies.add(auth_cookie(reset_token));
@@ -115,18 +153,9 @@ where
         Some(auth_user)
     }
 
-    fn cookies_from_request(headers: &HeaderMap) -> impl Iterator<Item = Cookie<'static>> + '_ {
-        headers
-            .get_all(COOKIE)
-            .into_iter()
-            .filter_map(|value| value.to_str().ok())
-            .flat_map(|value| value.split(';'))
-            .filter_map(|cookie| Cookie::parse_encoded(cookie.to_owned()).ok())
-    }
-
     fn cookies(headers: &HeaderMap) -> CookieJar {
         let mut jar = CookieJar::new();
-        for cookie in Self::cookies_from_request(headers) {
+        for cookie in cookies_from_request(headers) {
             jar.add_original(cookie);
         }
         jar
@@ -141,11 +170,12 @@ where
     }
 }
 
-impl<State, Wrapped> Service<Request<Incoming>> for AuthService<State, Wrapped>
+impl<State, Wrapped, B> Service<Request<B>> for AuthService<State, Wrapped>
 where
     State: Clone + Send + Sync + ValidatesIdentity + 'static,
-    Wrapped: Service<Request<Incoming>, Response = Response> + Clone + Send + 'static,
+    Wrapped: Service<Request<B>, Response = Response> + Clone + Send + 'static,
     Wrapped::Future: Send + 'static,
+    B: Send + 'static,
 {
     type Response = Wrapped::Response;
     type Error = Wrapped::Error;
@@ -155,7 +185,7 @@ where
         self.inner.poll_ready(cx)
     }
 
-    fn call(&mut self, mut req: Request<Incoming>) -> Self::Future {
+    fn call(&mut self, mut req: Request<B>) -> Self::Future {
         let state = self.state.clone();
         let clone = self.inner.clone();
         // https://docs.rs/tower/latest/tower/trait.Service.html#be-careful-when-cloning-inner-services
@@ -163,7 +193,7 @@ where
         Box::pin(async move {
             let headers = req.headers();
             let authorization = headers.get(AUTHORIZATION);
-            let mut cookies = Self::cookies(&headers);
+            let mut cookies = Self::cookies(headers);
             let auth_parts = Self::authorize(&state, authorization, &mut cookies).await;
             if let Some(auth_user) = auth_parts {
                 req.extensions_mut().insert(auth_user);
@@ -176,9 +206,59 @@ where
     }
 }
 
+pub(crate) fn cookies_from_request(
+    headers: &HeaderMap,
+) -> impl Iterator<Item = Cookie<'static>> + '_ {
+    headers
+        .get_all(COOKIE)
+        .into_iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|cookie| Cookie::parse_encoded(cookie.trim().to_owned()).ok())
+}
+
+/// `AuthService` as a layer, for attaching authentication to a `Router` instead of wrapping the
+/// whole server.
+///
+/// Requests with a valid bearer token or session cookie get `AuthenticatedUser` inserted into
+/// their extensions, so any downstream service or middleware can read the identity.
+///
+/// ```ignore
+/// let app = Router::new()
+///     .route("/reports", get(list_reports))
+///     .layer(resolve_roles_layer(state.clone()))
+///     .layer(AuthLayer::new(state));
+/// ```
+#[derive(Clone)]
+pub struct AuthLayer<State> {
+    state: State,
+}
+
+impl<State> AuthLayer<State>
+where
+    State: ValidatesIdentity,
+{
+    pub fn new(state: State) -> Self {
+        AuthLayer { state }
+    }
+}
+
+impl<State, Wrapped> Layer<Wrapped> for AuthLayer<State>
+where
+    State: Clone + ValidatesIdentity,
+{
+    type Service = AuthService<State, Wrapped>;
+
+    fn layer(&self, inner: Wrapped) -> Self::Service {
+        AuthService::new(self.state.clone(), inner)
+    }
+}
+
+// `AuthLayer` has already validated the request, so any router state will do, including one
+// that only holds the auth state for `FromRef`.
 impl<S> FromRequestParts<S> for AuthenticatedUser
 where
-    S: Send + Sync + ValidatesIdentity,
+    S: Send + Sync,
 {
     type Rejection = StatusCode;
     fn from_request_parts(
@@ -214,6 +294,9 @@ where
 #[derive(Deserialize)]
 pub struct RedirectQuery {
     pub origin: Option<String>,
+    /// Ask for a remember-me token; see `auth_with_login_tracking`.
+    #[serde(default)]
+    pub remember: bool,
 }
 
 pub async fn login(
@@ -221,10 +304,39 @@ pub async fn login(
     idp: &IdentityProvider,
     Query(query): Query<RedirectQuery>,
 ) -> Result<impl IntoResponse, RejectReason> {
-    let RedirectQuery { origin } = query;
+    let RedirectQuery { origin, remember } = query;
     let redirect_uri = origin.as_deref().unwrap_or("/");
-    let (auth_url, csrf_token, verifier, nonce) = idp.login_oidc(vec![String::from("email")]);
+    let login = idp.login_oidc(vec![String::from("email")]);
+    start_login(session, login, redirect_uri, remember).await
+}
+
+#[derive(Deserialize)]
+pub struct ReauthQuery {
+    pub origin: Option<String>,
+    /// Ask the provider for MFA; see `IdentityProvider::with_mfa_acr`.
+    #[serde(default)]
+    pub mfa: bool,
+}
 
+/// Send the signed-in user to the identity provider to enter their credentials again, for
+/// `step_up::RequireFreshAuth`. The callback is the usual `auth` route, which replaces the
+/// session cookie and keeps everything stored in the session.
+pub async fn reauthenticate(
+    session: &mut Session,
+    idp: &IdentityProvider,
+    Query(query): Query<ReauthQuery>,
+) -> Result<impl IntoResponse, RejectReason> {
+    let redirect_uri = query.origin.as_deref().unwrap_or("/");
+    let login = idp.reauth_oidc(vec![String::from("email")], query.mfa);
+    start_login(session, login, redirect_uri, false).await
+}
+
+async fn start_login(
+    session: &mut Session,
+    (auth_url, csrf_token, verifier, nonce): (Url, CsrfToken, PkceCodeVerifier, Nonce),
+    redirect_uri: &str,
+    remember: bool,
+) -> Result<Redirect, RejectReason> {
     session
         .insert("csrf_token", csrf_token.secret().clone())
         .await
@@ -241,6 +353,10 @@ pub async fn login(
         .insert("redirect_uri", redirect_uri)
         .await
         .map_err(|_| RejectReason::Session)?;
+    session
+        .insert(REMEMBER_ME_KEY, remember)
+        .await
+        .map_err(|_| RejectReason::Session)?;
 
     Ok(Redirect::to(auth_url.as_str()))
 }
@@ -251,12 +367,19 @@ pub struct AuthQuery {
     pub state: String,
 }
 
-pub async fn auth(
+enum CodeExchange {
+    Token {
+        token: Box<OidcToken>,
+        redirect_uri: String,
+    },
+    Restart(Response),
+}
+
+async fn exchange_code(
     session: &mut Session,
     idp: &IdentityProvider,
-    jar: AxumCookieJar,
-    Query(query): Query<AuthQuery>,
-) -> Result<(AxumCookieJar, Response), AuthRejectReason> {
+    query: AuthQuery,
+) -> Result<CodeExchange, AuthRejectReason> {
     let AuthQuery { code, state } = query;
     let code = AuthorizationCode::new(code);
 
@@ -264,7 +387,9 @@ pub async fn auth(
         Ok(Some(csrf_token)) => csrf_token,
         Err(_) | Ok(None) => {
             tracing::warn!("Missing csrf token");
-            return Ok((jar, Redirect::to("/auth/login").into_response()));
+            return Ok(CodeExchange::Restart(
+                Redirect::to("/auth/login").into_response(),
+            ));
         }
     };
 
@@ -272,7 +397,9 @@ pub async fn auth(
         Ok(Some(pkce_verifier)) => PkceCodeVerifier::new(pkce_verifier),
         Err(_) | Ok(None) => {
             tracing::warn!("Missing PKCE verifier");
-            return Ok((jar, Redirect::to("/auth/login").into_response()));
+            return Ok(CodeExchange::Restart(
+                Redirect::to("/auth/login").into_response(),
+            ));
         }
     };
 
@@ -280,38 +407,225 @@ pub async fn auth(
         Ok(Some(nonce)) => Nonce::new(nonce),
         Err(_) | Ok(None) => {
             tracing::warn!("Missing nonce");
-            return Ok((jar, Redirect::to("/auth/login").into_response()));
+            return Ok(CodeExchange::Restart(
+                Redirect::to("/auth/login").into_response(),
+            ));
         }
     };
 
     let redirect_uri = match session.get::<String>("redirect_uri").await {
         Ok(Some(redirect_uri)) => decode(&redirect_uri)
-            .map(|s| s.to_owned().to_string())
+            .map(|s| s.into_owned())
             .unwrap_or_else(|_| String::from("/")),
         Err(_) | Ok(None) => String::from("/"),
     };
 
     if state != csrf_token {
         tracing::warn!("CSRF token mismatch! This is a possible attack!");
-        return Ok((jar, Redirect::to("auth/login").into_response()));
+        return Ok(CodeExchange::Restart(
+            Redirect::to("auth/login").into_response(),
+        ));
     }
 
     let token = match idp.token_oidc(code, verifier, nonce).await {
         Ok(token) => token,
-        Err(err) => return Err(AuthRejectReason::token_transfer_failed(err.to_string())),
+        Err(err) => {
+            if let Some(unavailable) = err.downcast_ref::<ProviderUnavailable>() {
+                tracing::warn!("{}", unavailable);
+                return Err(AuthRejectReason::identity_provider_unavailable(unavailable));
+            }
+            return Err(AuthRejectReason::token_transfer_failed(err.to_string()));
+        }
     };
 
+    Ok(CodeExchange::Token {
+        token: Box::new(token),
+        redirect_uri,
+    })
+}
+
+fn finish_auth(
+    jar: AxumCookieJar,
+    token: OidcToken,
+    redirect_uri: &str,
+) -> (AxumCookieJar, Response) {
     let redirect = format!(
         "<html><head><meta http-equiv=\"refresh\" content=\"0; URL='{}'\"/></head></html>",
         redirect_uri
     );
-    Ok((
+    (
         jar.add(auth_cookie(token)),
         axum::response::Html(redirect).into_response(),
-    ))
+    )
+}
+
+pub async fn auth(
+    session: &mut Session,
+    idp: &IdentityProvider,
+    jar: AxumCookieJar,
+    Query(query): Query<AuthQuery>,
+) -> Result<(AxumCookieJar, Response), AuthRejectReason> {
+    match exchange_code(session, idp, query).await? {
+        CodeExchange::Restart(response) => Ok((jar, response)),
+        CodeExchange::Token {
+            token,
+            redirect_uri,
+        } => Ok(finish_auth(jar, *token, &redirect_uri)),
+    }
+}
+
+/// Same as `auth`, but also records the login in `auth.login_history` and updates the user's
+/// `last_login_at`/`last_login_ip`. With a `notifier`, the user is sent a `NewDeviceLogin`
+/// notification when the login comes from a user agent they have not signed in with before. With
+/// a `claims_mapper`, the ID token's claims are synced into roles and memberships.
+///
+/// When the login asked for `remember`, a remember-me token lasting `DEFAULT_REMEMBER_FOR` is
+/// created as well; see `remember::remember_me_layer`.
+///
+/// Failing to record the login, to notify, to sync claims or to remember the session is logged
+/// and does not fail the sign-in. Bearer-token requests are not logins and are never recorded.
+#[cfg(feature = "sqlx")]
+#[allow(clippy::too_many_arguments)]
+pub async fn auth_with_login_tracking(
+    session: &mut Session,
+    idp: &IdentityProvider,
+    pool: &PgPool,
+    mut jar: AxumCookieJar,
+    client_ip: Option<&str>,
+    user_agent: Option<&str>,
+    notifier: Option<&dyn Notifier>,
+    claims_mapper: Option<&dyn ClaimsMapper>,
+    Query(query): Query<AuthQuery>,
+) -> Result<(AxumCookieJar, Response), AuthRejectReason> {
+    match exchange_code(session, idp, query).await? {
+        CodeExchange::Restart(response) => Ok((jar, response)),
+        CodeExchange::Token {
+            token,
+            redirect_uri,
+        } => {
+            let validated = idp
+                .validate_token(&token)
+                .ok()
+                .and_then(|(id_token, claims)| {
+                    Uuid::parse_str(claims.subject().as_str())
+                        .ok()
+                        .map(|user_id| (UserId(user_id), id_token))
+                });
+            let remember = session
+                .remove::<bool>(REMEMBER_ME_KEY)
+                .await
+                .ok()
+                .flatten()
+                .unwrap_or(false);
+            match validated {
+                Some((user_id, id_token)) => {
+                    if remember {
+                        jar = match remember_session(
+                            pool,
+                            jar.clone(),
+                            user_id,
+                            &token,
+                            user_agent,
+                            client_ip,
+                            DEFAULT_REMEMBER_FOR,
+                        )
+                        .await
+                        {
+                            Ok(jar) => jar,
+                            Err(err) => {
+                                tracing::warn!("Failed to remember session: {}", err);
+                                jar
+                            }
+                        };
+                    }
+                    if let Some(mapper) = claims_mapper {
+                        sync_login_claims(pool, user_id, mapper, &id_token).await;
+                    }
+                    let new_device = match notifier {
+                        Some(_) => is_new_device(pool, user_id, user_agent)
+                            .await
+                            .unwrap_or_else(|err| {
+                                tracing::warn!("Failed to check login device: {}", err);
+                                false
+                            }),
+                        None => false,
+                    };
+                    if let Err(err) = record_provider_login(
+                        pool,
+                        user_id,
+                        Some(idp.issuer()),
+                        client_ip,
+                        user_agent,
+                        "oidc",
+                    )
+                    .await
+                    {
+                        tracing::warn!("Failed to record login: {}", err);
+                    }
+                    if let Some(notifier) = notifier
+                        && new_device
+                    {
+                        notify_new_device(pool, notifier, user_id, client_ip, user_agent).await;
+                    }
+                }
+                None => tracing::warn!("Failed to resolve user id for login tracking"),
+            }
+            Ok(finish_auth(jar, *token, &redirect_uri))
+        }
+    }
 }
 
-fn auth_cookie<'a>(token: OidcToken) -> Cookie<'a> {
+/// Sync a verified ID token's claims through `mapper`, logging instead of failing the caller.
+#[cfg(feature = "sqlx")]
+pub async fn sync_login_claims(
+    pool: &PgPool,
+    user_id: UserId,
+    mapper: &dyn ClaimsMapper,
+    id_token: &CoreIdToken,
+) {
+    let Some(claims) = validated_token_claims(id_token) else {
+        tracing::warn!("Failed to read ID token claims for {}", user_id);
+        return;
+    };
+    match sync_claims(pool, user_id, mapper, &claims).await {
+        #[cfg(feature = "api")]
+        Ok(sync) if !sync.is_empty() => crate::guard::invalidate_role_snapshots(user_id),
+        Ok(_) => {}
+        Err(err) => tracing::warn!(
+            "Failed to sync {} claims for {}: {}",
+            mapper.source(),
+            user_id,
+            err
+        ),
+    }
+}
+
+#[cfg(feature = "sqlx")]
+async fn notify_new_device(
+    pool: &PgPool,
+    notifier: &dyn Notifier,
+    user_id: UserId,
+    client_ip: Option<&str>,
+    user_agent: Option<&str>,
+) {
+    let user = match UserRow::get(pool, user_id).await {
+        Ok(Some(user)) => user,
+        Ok(None) => return,
+        Err(err) => {
+            tracing::warn!("Failed to load user for new device notification: {}", err);
+            return;
+        }
+    };
+    let notification = Notification::NewDeviceLogin(NewDeviceLoginContext {
+        recipient: Recipient::for_user(&user),
+        logged_in_at: chrono::Utc::now().naive_utc(),
+        ip: client_ip.map(str::to_string),
+        user_agent: user_agent.map(str::to_string),
+    });
+    notify_user(pool, notifier, &notification).await;
+}
+
+pub(crate) fn auth_cookie<'a>(token: OidcToken) -> Cookie<'a> {
     Cookie::build((
         AUTH_COOKIE,
         serde_json::to_string(&token).expect("serialize token"),
@@ -341,17 +655,119 @@ pub async fn logout(
         let oidc_token =
             parse_auth_cookie(token.value()).map_err(|_| StatusCode::UNPROCESSABLE_ENTITY)?;
         let logout_url = idp.logout_oidc("/", &oidc_token);
-        let uri = logout_url.as_str();
-        let mut response = Redirect::to(uri).into_response();
-        {
-            let headers = response.headers_mut();
-            headers.insert(CACHE_CONTROL, "no-store, must-revalidate".parse().unwrap());
-            headers.insert(EXPIRES, "0".parse().unwrap());
-            let cookie = format!("{}=; Max-Age=0; Path=/; HttpOnly; Secure", AUTH_COOKIE);
-            headers.insert(SET_COOKIE, cookie.parse().unwrap());
-        }
-        Ok(response)
+        Ok(logged_out(logout_url.as_str()))
     } else {
         Err(StatusCode::UNAUTHORIZED)
     }
 }
+
+/// What `logout_with` does besides ending the local session.
+#[derive(Debug, Clone)]
+pub struct LogoutOptions {
+    /// Also end the session at the identity provider (RP-initiated logout), so signing in again
+    /// asks for credentials.
+    pub end_idp_session: bool,
+    /// Path the user lands on afterwards. With `end_idp_session`, the provider redirects here
+    /// and it must be registered there as a post-logout redirect URI.
+    pub post_logout_redirect: String,
+}
+
+impl Default for LogoutOptions {
+    fn default() -> Self {
+        Self {
+            end_idp_session: true,
+            post_logout_redirect: "/".to_string(),
+        }
+    }
+}
+
+/// Like `logout`, but always clears the local session, even without or with an unreadable
+/// session cookie, and only ends the provider's session when `options` ask for it. The
+/// remember-me cookie is removed too; `remember::forget_session` also revokes its token.
+pub async fn logout_with(
+    session: &mut Session,
+    idp: &IdentityProvider,
+    jar: &AxumCookieJar,
+    options: &LogoutOptions,
+) -> Response {
+    session.delete().await.ok();
+    let token = jar
+        .get(AUTH_COOKIE)
+        .and_then(|cookie| parse_auth_cookie(cookie.value()).ok());
+    match token {
+        Some(token) if options.end_idp_session => {
+            let logout_url = idp.logout_oidc(&options.post_logout_redirect, &token);
+            logged_out(logout_url.as_str())
+        }
+        _ => logged_out(&options.post_logout_redirect),
+    }
+}
+
+fn logged_out(uri: &str) -> Response {
+    let mut response = Redirect::to(uri).into_response();
+    let headers = response.headers_mut();
+    headers.insert(CACHE_CONTROL, "no-store, must-revalidate".parse().unwrap());
+    headers.insert(EXPIRES, "0".parse().unwrap());
+    for name in [AUTH_COOKIE, REMEMBER_COOKIE] {
+        let cookie = format!("{}=; Max-Age=0; Path=/; HttpOnly; Secure", name);
+        headers.append(SET_COOKIE, cookie.parse().unwrap());
+    }
+    response
+}
+
+#[derive(Debug, Deserialize)]
+pub struct BackchannelLogoutForm {
+    pub logout_token: String,
+}
+
+/// OIDC back-channel logout endpoint: the identity provider posts a logout token here when a
+/// user signs out there, and the matching sessions stop being accepted.
+///
+/// Route it as `POST` and register its URL with the provider, e.g. as Okta's "Logout request
+/// URL". The logout is stored in `auth.federated_logouts`; replicas the provider does not reach
+/// pick it up through `logout::sync_federated_logouts`.
+#[cfg(feature = "sqlx")]
+pub async fn backchannel_logout(
+    idp: &IdentityProvider,
+    pool: &PgPool,
+    Form(form): Form<BackchannelLogoutForm>,
+) -> Response {
+    let logout = match idp.verify_logout_token(&form.logout_token) {
+        Ok(logout) => logout,
+        Err(err) => {
+            tracing::warn!("Refusing back-channel logout: {}", err);
+            return backchannel_response(
+                StatusCode::BAD_REQUEST,
+                json!({
+                    "error": "invalid_request",
+                    "error_description": err.to_string(),
+                }),
+            );
+        }
+    };
+    record_federated_logout(&logout);
+    match store_federated_logout(pool, &logout).await {
+        // A retry of a logout already handled.
+        Ok(()) | Err(LogoutTokenError::Replayed) => {
+            tracing::info!(
+                "Identity provider ended session {:?} of {:?}",
+                logout.session_id,
+                logout.subject
+            );
+            backchannel_response(StatusCode::OK, json!({}))
+        }
+        Err(err) => backchannel_response(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            json!({ "error": "server_error", "error_description": err.to_string() }),
+        ),
+    }
+}
+
+#[cfg(feature = "sqlx")]
+fn backchannel_response(status: StatusCode, body: serde_json::Value) -> Response {
+    let mut response = (status, Json(body)).into_response();
+    response
+        .headers_mut()
+        .insert(CACHE_CONTROL, "no-store".parse().unwrap());
+    response
+}
@@ -0,0 +1,216 @@
+//! Everything the `/auth/me` endpoints and role extractors need about the authenticated user,
+//! fetched in one round trip.
+
+use serde::Deserialize;
+use serde_json::Value;
+use sqlx::types::Json;
+use sqlx::{FromRow, PgPool};
+
+use crate::db::{
+    EffectiveRole, GLOBAL_SCOPE, GLOBAL_SCOPE_ID, GroupRow, GroupVisibility, UserRow,
+    resolve_environment,
+};
+use crate::environment::lookup_scopes;
+use crate::group_id::GroupId;
+use crate::user_id::UserId;
+
+/// An active group the user belongs to.
+#[derive(Debug, Clone)]
+pub struct AuthContextGroup {
+    pub group: GroupRow,
+    /// The user's role in the group, e.g. `member`.
+    pub role_name: String,
+}
+
+/// The user row, account flags, memberships and grants of one user.
+///
+/// Handlers get it as an extractor; `guard::ResolveRolesLayer` leaves the one it loads in the
+/// request extensions, so a handler behind it does not query again.
+#[derive(Debug, Clone)]
+pub struct AuthContext {
+    pub user_id: UserId,
+    /// `None` until the user is provisioned, e.g. by their first `/auth/me`.
+    pub user: Option<UserRow>,
+    /// False for deactivated users and ones that do not exist.
+    pub active: bool,
+    pub pending_approval: bool,
+    /// Unconditional allow grants held directly at the global scope id that count in the current
+    /// environment, as `AccessRoleRow::roles`.
+    pub global_roles: Vec<String>,
+    /// As `GroupMembershipRow::groups_for_user`, with the user's role in each.
+    pub groups: Vec<AuthContextGroup>,
+    /// As `db::effective_roles`.
+    pub roles: Vec<EffectiveRole>,
+}
+
+#[derive(Deserialize)]
+struct ContextGroup {
+    id: GroupId,
+    display_name: String,
+    details: Option<Value>,
+    version: i64,
+    visibility: GroupVisibility,
+    external_id: Option<String>,
+    archived_at: Option<chrono::NaiveDateTime>,
+    role_name: String,
+}
+
+#[derive(FromRow)]
+struct ContextRow {
+    id: Option<UserId>,
+    username: Option<String>,
+    email: Option<String>,
+    details: Option<Value>,
+    email_canonical: Option<String>,
+    version: Option<i64>,
+    locale: Option<String>,
+    external_id: Option<String>,
+    display_name: Option<String>,
+    timezone: Option<String>,
+    active: Option<bool>,
+    pending_approval: Option<bool>,
+    global_roles: Vec<String>,
+    groups: Json<Vec<ContextGroup>>,
+    roles: Json<Vec<EffectiveRole>>,
+}
+
+impl AuthContext {
+    pub async fn load(pool: &PgPool, user_id: UserId) -> Result<Self, sqlx::Error> {
+        let row = sqlx::query_as::<_, ContextRow>(
+            r#"
+            SELECT u.id, u.username, u.email, u.details, u.email_canonical, u.version, u.locale,
+                   u.external_id, u.display_name, u.timezone, u.active, u.pending_approval,
+                   ARRAY(
+                       SELECT DISTINCT role_name
+                       FROM auth.user_roles
+                       WHERE user_id = me.id
+                         AND scope = ANY($2)
+                         AND scope_id = $3
+                         AND effect = 'allow'
+                         AND condition IS NULL
+                       ORDER BY role_name ASC
+                   ) AS global_roles,
+                   COALESCE((
+                       SELECT jsonb_agg(jsonb_build_object(
+                           'id', g.id,
+                           'display_name', g.display_name,
+                           'details', g.details,
+                           'version', g.version,
+                           'visibility', g.visibility,
+                           'external_id', g.external_id,
+                           'archived_at', g.archived_at,
+                           'role_name', gm.role_name
+                       ))
+                       FROM auth.group_memberships gm
+                       JOIN auth.groups g
+                         ON g.id = gm.group_id
+                       WHERE gm.user_id = me.id
+                         AND g.active = TRUE
+                   ), '[]'::JSONB) AS groups,
+                   COALESCE((
+                       SELECT jsonb_agg(to_jsonb(grants)
+                                        ORDER BY grants.scope, grants.scope_id, grants.role_name)
+                       FROM (
+                           SELECT scope, scope_id, role_name, effect, condition
+                           FROM auth.user_roles
+                           WHERE user_id = me.id
+                           UNION
+                           SELECT gr.scope, gr.scope_id, gr.role_name, gr.effect, gr.condition
+                           FROM auth.group_memberships gm
+                           JOIN auth.groups g
+                             ON g.id = gm.group_id
+                            AND g.deleted_at IS NULL
+                           JOIN auth.group_roles gr
+                             ON gr.group_id = gm.group_id
+                           WHERE gm.user_id = me.id
+                       ) AS grants
+                   ), '[]'::JSONB) AS roles
+            FROM (SELECT $1::UUID AS id) AS me
+            LEFT JOIN auth.users u
+              ON u.id = me.id
+             AND u.deleted_at IS NULL
+            "#,
+        )
+        .bind(user_id)
+        .bind(lookup_scopes(GLOBAL_SCOPE))
+        .bind(GLOBAL_SCOPE_ID)
+        .fetch_one(pool)
+        .await?;
+
+        let user = match (row.id, row.email, row.version) {
+            (Some(id), Some(email), Some(version)) => Some(UserRow {
+                id,
+                username: row.username,
+                email,
+                details: row.details,
+                email_canonical: row.email_canonical,
+                version,
+                locale: row.locale,
+                external_id: row.external_id,
+                display_name: row.display_name,
+                timezone: row.timezone,
+            }),
+            _ => None,
+        };
+        Ok(Self {
+            user_id,
+            active: user.is_some() && row.active != Some(false),
+            user,
+            pending_approval: row.pending_approval.unwrap_or(false),
+            global_roles: row.global_roles,
+            groups: row
+                .groups
+                .0
+                .into_iter()
+                .map(|group| AuthContextGroup {
+                    group: GroupRow {
+                        id: group.id,
+                        display_name: group.display_name,
+                        details: group.details,
+                        version: group.version,
+                        visibility: group.visibility,
+                        external_id: group.external_id,
+                        archived_at: group.archived_at,
+                    },
+                    role_name: group.role_name,
+                })
+                .collect(),
+            roles: resolve_environment(row.roles.0),
+        })
+    }
+}
+
+#[cfg(feature = "api")]
+mod extract {
+    use axum::extract::FromRequestParts;
+    use axum::http::request::Parts;
+
+    use super::AuthContext;
+    use crate::api::HasPool;
+    use crate::prelude::{AuthRejectReason, AuthenticatedUser, RejectReason};
+
+    /// Loads the context of the `AuthenticatedUser` from `HasPool::reader_pool`, unless the
+    /// request already carries one, and keeps it in the extensions for later extractors.
+    impl<S> FromRequestParts<S> for AuthContext
+    where
+        S: HasPool + Send + Sync,
+    {
+        type Rejection = RejectReason;
+
+        async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+            if let Some(context) = parts.extensions.get::<AuthContext>() {
+                return Ok(context.clone());
+            }
+            let user_id = parts
+                .extensions
+                .get::<AuthenticatedUser>()
+                .map(AuthenticatedUser::id)
+                .ok_or_else(|| RejectReason::auth(AuthRejectReason::no_session_token()))?;
+            let context = AuthContext::load(&state.reader_pool(), user_id)
+                .await
+                .map_err(|_| RejectReason::database("Failed to reach database"))?;
+            parts.extensions.insert(context.clone());
+            Ok(context)
+        }
+    }
+}
@@ -0,0 +1,380 @@
+//! Request-scoped batching of user and role lookups.
+//!
+//! Resolvers rendering one response, e.g. the fields of a GraphQL list, tend to ask for one user
+//! or one role check at a time. `AuthzLoader` answers lookups issued together, e.g. from
+//! `join_all`, with one query per kind, and remembers every answer for the rest of the request,
+//! so repeated lookups of the same key cost nothing.
+//!
+//! ```ignore
+//! async fn handler(loader: AuthzLoader, Json(ids): Json<Vec<UserId>>) -> Json<Vec<bool>> {
+//!     let checks = ids
+//!         .iter()
+//!         .map(|id| loader.has_role(*id, "project", "p1", "editor"));
+//!     Json(join_all(checks).await.into_iter().map(|r| r.unwrap_or(false)).collect())
+//! }
+//! ```
+
+use std::collections::{HashMap, HashSet};
+use std::hash::Hash;
+use std::sync::{Arc, Mutex};
+
+use futures_util::FutureExt;
+use futures_util::future::{BoxFuture, Shared};
+use sqlx::{FromRow, PgPool};
+use uuid::Uuid;
+
+use crate::db::{
+    EffectiveRole, GLOBAL_SCOPE, GLOBAL_SCOPE_ID, GROUP_ADMIN_ROLE, RoleEffect, SUPER_ADMIN_ROLE,
+    UserRow, resolve_environment, roles_allow, roles_deny,
+};
+use crate::policy::RequestContext;
+use crate::user_id::UserId;
+
+/// A failed batch, shared by every lookup that was waiting on it.
+pub type LoadError = Arc<sqlx::Error>;
+
+type Batch = Shared<BoxFuture<'static, Result<(), LoadError>>>;
+
+/// Keys waiting for the next batch, lookups that joined it, the batch that will answer them, if
+/// one has been started, batches that are querying, by the keys they took, and answers so far.
+struct Loads<K, V> {
+    pending: HashSet<K>,
+    joined: usize,
+    batch: Option<Batch>,
+    in_flight: HashMap<K, Batch>,
+    loaded: HashMap<K, V>,
+}
+
+impl<K, V> Default for Loads<K, V> {
+    fn default() -> Self {
+        Self {
+            pending: HashSet::new(),
+            joined: 0,
+            batch: None,
+            in_flight: HashMap::new(),
+            loaded: HashMap::new(),
+        }
+    }
+}
+
+/// A user's grants and the groups they administer, for role checks.
+#[derive(Debug, Clone, Default)]
+struct Access {
+    roles: Vec<EffectiveRole>,
+    admin_of: HashSet<Uuid>,
+}
+
+#[derive(Default)]
+struct LoaderState {
+    users: Loads<UserId, Option<UserRow>>,
+    access: Loads<UserId, Access>,
+}
+
+/// Batches and caches lookups for one request. Clones share the batches and the cache, which is
+/// never invalidated, so a handler that changes grants should check them again with `db`.
+///
+/// As an extractor it is created on first use and kept in the request extensions, so every
+/// extractor and handler of the request gets the same one.
+#[derive(Clone)]
+pub struct AuthzLoader {
+    pool: Arc<PgPool>,
+    state: Arc<Mutex<LoaderState>>,
+}
+
+impl AuthzLoader {
+    pub fn new(pool: Arc<PgPool>) -> Self {
+        Self {
+            pool,
+            state: Arc::new(Mutex::new(LoaderState::default())),
+        }
+    }
+
+    pub fn pool(&self) -> &Arc<PgPool> {
+        &self.pool
+    }
+
+    /// The user, or `None` if it does not exist.
+    pub async fn user(&self, user_id: UserId) -> Result<Option<UserRow>, LoadError> {
+        load(
+            self,
+            user_id,
+            |state| &mut state.users,
+            |loader, user_ids| loader.load_users(user_ids).boxed(),
+        )
+        .await
+    }
+
+    /// `db::user_has_effective_access`, from the user's batched grants.
+    pub async fn has_role(
+        &self,
+        user_id: UserId,
+        scope: &str,
+        scope_id: &str,
+        role_name: &str,
+    ) -> Result<bool, LoadError> {
+        let access = self.access(user_id).await?;
+        Ok(access.allows(scope, scope_id, role_name, None))
+    }
+
+    /// `db::user_has_effective_access_in_context`, from the user's batched grants.
+    pub async fn has_role_in_context(
+        &self,
+        user_id: UserId,
+        scope: &str,
+        scope_id: &str,
+        role_name: &str,
+        context: &RequestContext,
+    ) -> Result<bool, LoadError> {
+        let access = self.access(user_id).await?;
+        Ok(access.allows(scope, scope_id, role_name, Some(context)))
+    }
+
+    /// The user's grants, as `db::effective_roles`.
+    pub async fn roles(&self, user_id: UserId) -> Result<Vec<EffectiveRole>, LoadError> {
+        Ok(self.access(user_id).await?.roles)
+    }
+
+    async fn access(&self, user_id: UserId) -> Result<Access, LoadError> {
+        load(
+            self,
+            user_id,
+            |state| &mut state.access,
+            |loader, user_ids| loader.load_access(user_ids).boxed(),
+        )
+        .await
+    }
+
+    async fn load_users(self, user_ids: Vec<UserId>) -> Result<(), LoadError> {
+        let rows = UserRow::get_many(&self.pool, &user_ids).await?;
+        let mut found: HashMap<UserId, UserRow> =
+            rows.into_iter().map(|row| (row.id, row)).collect();
+        let mut state = self.lock();
+        for user_id in user_ids {
+            let user = found.remove(&user_id);
+            state.users.loaded.insert(user_id, user);
+        }
+        Ok(())
+    }
+
+    async fn load_access(self, user_ids: Vec<UserId>) -> Result<(), LoadError> {
+        let grants = sqlx::query_as::<_, UserGrant>(
+            r#"
+            SELECT user_id, scope, scope_id, role_name, effect, condition
+            FROM auth.user_roles
+            WHERE user_id = ANY($1)
+            UNION
+            SELECT gm.user_id, gr.scope, gr.scope_id, gr.role_name, gr.effect, gr.condition
+            FROM auth.group_memberships gm
+            JOIN auth.groups g
+              ON g.id = gm.group_id
+             AND g.deleted_at IS NULL
+            JOIN auth.group_roles gr
+              ON gr.group_id = gm.group_id
+            WHERE gm.user_id = ANY($1)
+            ORDER BY scope ASC, scope_id ASC, role_name ASC
+            "#,
+        )
+        .bind(&user_ids)
+        .fetch_all(&*self.pool)
+        .await?;
+        let admin_of: Vec<(UserId, Uuid)> = sqlx::query_as(
+            r#"
+            SELECT gm.user_id, gm.group_id
+            FROM auth.group_memberships gm
+            JOIN auth.groups g
+              ON g.id = gm.group_id
+             AND g.deleted_at IS NULL
+            WHERE gm.user_id = ANY($1)
+              AND gm.role_name = $2
+            "#,
+        )
+        .bind(&user_ids)
+        .bind(GROUP_ADMIN_ROLE)
+        .fetch_all(&*self.pool)
+        .await?;
+
+        let mut access: HashMap<UserId, Access> = user_ids
+            .iter()
+            .map(|user_id| (*user_id, Access::default()))
+            .collect();
+        for grant in grants {
+            if let Some(access) = access.get_mut(&grant.user_id) {
+                access.roles.push(grant.into());
+            }
+        }
+        for (user_id, group_id) in admin_of {
+            if let Some(access) = access.get_mut(&user_id) {
+                access.admin_of.insert(group_id);
+            }
+        }
+        let mut state = self.lock();
+        for (user_id, mut access) in access {
+            access.roles = resolve_environment(access.roles);
+            state.access.loaded.insert(user_id, access);
+        }
+        Ok(())
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, LoaderState> {
+        self.state.lock().unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+/// Answer `key` from the cache or from the batch already querying it, or queue it for the next
+/// batch, starting one with `dispatch` if none is waiting, and wait for it.
+///
+/// A new batch yields to the runtime until no more keys join it, so lookups issued alongside it,
+/// e.g. by `join_all`, share it.
+async fn load<K, V, S, D>(
+    loader: &AuthzLoader,
+    key: K,
+    select: S,
+    dispatch: D,
+) -> Result<V, LoadError>
+where
+    K: Copy + Eq + Hash + Send + 'static,
+    V: Clone + Send + 'static,
+    S: Fn(&mut LoaderState) -> &mut Loads<K, V> + Copy + Send + 'static,
+    D: FnOnce(AuthzLoader, Vec<K>) -> BoxFuture<'static, Result<(), LoadError>> + Send + 'static,
+{
+    let batch = {
+        let mut state = loader.lock();
+        let loads = select(&mut state);
+        if let Some(value) = loads.loaded.get(&key) {
+            return Ok(value.clone());
+        }
+        if let Some(batch) = loads.in_flight.get(&key) {
+            batch.clone()
+        } else {
+            loads.pending.insert(key);
+            loads.joined += 1;
+            match &loads.batch {
+                Some(batch) => batch.clone(),
+                None => {
+                    let batch_loader = loader.clone();
+                    let run = async move {
+                        // Every waiter polls the batch, so wait until a round of polls adds no
+                        // lookups rather than for one yield.
+                        let mut joined = 0;
+                        loop {
+                            let now = select(&mut batch_loader.lock()).joined;
+                            if now == joined {
+                                break;
+                            }
+                            joined = now;
+                            tokio::task::yield_now().await;
+                        }
+                        let keys: Vec<K> = {
+                            let mut state = batch_loader.lock();
+                            let loads = select(&mut state);
+                            let batch = loads.batch.take().expect("waiting batch is registered");
+                            let keys: Vec<K> = loads.pending.drain().collect();
+                            loads.joined = 0;
+                            for key in &keys {
+                                loads.in_flight.insert(*key, batch.clone());
+                            }
+                            keys
+                        };
+                        let result = dispatch(batch_loader.clone(), keys.clone()).await;
+                        let mut state = batch_loader.lock();
+                        let loads = select(&mut state);
+                        for key in &keys {
+                            loads.in_flight.remove(key);
+                        }
+                        result
+                    }
+                    .boxed()
+                    .shared();
+                    loads.batch = Some(run.clone());
+                    run
+                }
+            }
+        }
+    };
+    batch.await?;
+    let mut state = loader.lock();
+    Ok(select(&mut state)
+        .loaded
+        .get(&key)
+        .cloned()
+        .expect("batch answers every key it took"))
+}
+
+#[derive(FromRow)]
+struct UserGrant {
+    user_id: UserId,
+    scope: String,
+    scope_id: String,
+    role_name: String,
+    effect: RoleEffect,
+    condition: Option<serde_json::Value>,
+}
+
+impl From<UserGrant> for EffectiveRole {
+    fn from(grant: UserGrant) -> Self {
+        Self {
+            scope: grant.scope,
+            scope_id: grant.scope_id,
+            role_name: grant.role_name,
+            effect: grant.effect,
+            condition: grant.condition,
+        }
+    }
+}
+
+impl Access {
+    /// The decision of `db::user_has_effective_access_in_context`.
+    fn allows(
+        &self,
+        scope: &str,
+        scope_id: &str,
+        role_name: &str,
+        context: Option<&RequestContext>,
+    ) -> bool {
+        if roles_allow(
+            &self.roles,
+            GLOBAL_SCOPE,
+            GLOBAL_SCOPE_ID,
+            SUPER_ADMIN_ROLE,
+            context,
+        ) {
+            return true;
+        }
+        if roles_deny(&self.roles, scope, scope_id, role_name, context) {
+            return false;
+        }
+        if scope != GLOBAL_SCOPE
+            && scope_id != GLOBAL_SCOPE_ID
+            && Uuid::parse_str(scope_id).is_ok_and(|group_id| self.admin_of.contains(&group_id))
+        {
+            return true;
+        }
+        roles_allow(&self.roles, scope, scope_id, role_name, context)
+    }
+}
+
+#[cfg(feature = "api")]
+mod extract {
+    use axum::extract::FromRequestParts;
+    use axum::http::request::Parts;
+
+    use super::AuthzLoader;
+    use crate::api::HasPool;
+
+    /// The request's loader over `HasPool::reader_pool`, created on first use.
+    impl<S> FromRequestParts<S> for AuthzLoader
+    where
+        S: HasPool + Send + Sync,
+    {
+        type Rejection = std::convert::Infallible;
+
+        async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+            if let Some(loader) = parts.extensions.get::<AuthzLoader>() {
+                return Ok(loader.clone());
+            }
+            let loader = AuthzLoader::new(state.reader_pool());
+            parts.extensions.insert(loader.clone());
+            Ok(loader)
+        }
+    }
+}
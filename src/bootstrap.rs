@@ -0,0 +1,318 @@
+//! The first admin of a fresh deployment, from `AuthConfig::bootstrap_admin`.
+//!
+//! Call `bootstrap` at startup, after migrations. While no user holds `super_admin` it makes the
+//! configured admin one: a user already registered under the email, or under the configured
+//! `user_id`, is granted the role; a configured `user_id` that does not exist yet is created with
+//! the email; otherwise a one-time invitation is issued, and accepting it through
+//! `/auth/invitations/accept` grants the role. Once anyone is super admin it does nothing, so it
+//! can run on every start.
+//!
+//! Both respect the process-wide `environment`: in an isolated environment the role is granted
+//! pinned to it, and only a super admin who counts there makes the bootstrap unnecessary.
+//!
+//! ```ignore
+//! match bootstrap(&pool, &config).await? {
+//!     BootstrapOutcome::Invited(issued) => println!(
+//!         "Sign in as {} and accept https://app.example.com/invite?token={}",
+//!         issued.invitation.email, issued.token,
+//!     ),
+//!     outcome => tracing::info!("Admin bootstrap: {:?}", outcome),
+//! }
+//! ```
+
+use std::fmt;
+
+use chrono::NaiveDateTime;
+use serde_json::json;
+use sqlx::{PgConnection, PgPool};
+use uuid::Uuid;
+
+use crate::config::{AuthConfig, BootstrapAdminConfig};
+use crate::db::{
+    GLOBAL_SCOPE, GLOBAL_SCOPE_ID, RoleAssignmentTarget, SUPER_ADMIN_ROLE, UserRow,
+    insert_audit_log, insert_role_audit_log,
+};
+use crate::email::DEFAULT_EMAIL_NORMALIZER;
+use crate::environment::{grant_scope, lookup_scopes};
+use crate::invitations::{IssuedInvitation, issue_invitation};
+use crate::user_id::UserId;
+
+/// Serializes bootstrap runs and bootstrap invitation acceptance across replicas.
+const BOOTSTRAP_LOCK: &str = "auth.bootstrap_admin";
+
+#[derive(Debug, Clone)]
+pub enum BootstrapOutcome {
+    /// `AuthConfig::bootstrap_admin` is not set.
+    NotConfigured,
+    /// A user already holds `super_admin`; nothing was changed.
+    AlreadyBootstrapped,
+    /// The admin was granted `super_admin`, after being created if `created`.
+    Granted { user_id: UserId, created: bool },
+    /// Nobody is registered under the email yet. Deliver the token; the user who signs in with
+    /// that email and accepts it becomes super admin.
+    Invited(IssuedInvitation),
+    /// A bootstrap invitation from an earlier run is still outstanding. Its token cannot be
+    /// recovered; set `reissue_invitation` for a new one.
+    Pending {
+        invitation_id: Uuid,
+        expires_at: NaiveDateTime,
+    },
+}
+
+#[derive(Debug)]
+pub enum BootstrapError {
+    /// The configured email belongs to a different user than the configured `user_id`.
+    EmailTaken(UserId),
+    Database(sqlx::Error),
+}
+
+impl fmt::Display for BootstrapError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::EmailTaken(user_id) => write!(
+                f,
+                "Bootstrap admin email is already registered to user {}",
+                user_id
+            ),
+            Self::Database(err) => write!(f, "Database error: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for BootstrapError {}
+
+impl From<sqlx::Error> for BootstrapError {
+    fn from(err: sqlx::Error) -> Self {
+        Self::Database(err)
+    }
+}
+
+/// Make the configured admin super admin if nobody is yet. See the module docs.
+///
+/// A user created here does not go through the app's `Hooks::before_user_create` and
+/// `Hooks::after_user_create`; on `BootstrapOutcome::Granted { created: true, .. }` an app that
+/// provisions per-user resources in them should do so itself.
+pub async fn bootstrap(
+    pool: &PgPool,
+    config: &AuthConfig,
+) -> Result<BootstrapOutcome, BootstrapError> {
+    let Some(config) = &config.bootstrap_admin else {
+        return Ok(BootstrapOutcome::NotConfigured);
+    };
+    let mut tx = pool.begin().await?;
+    let outcome = bootstrap_admin(&mut tx, config).await?;
+    tx.commit().await?;
+    match &outcome {
+        BootstrapOutcome::Granted { user_id, created } => tracing::info!(
+            "Bootstrapped super admin {}{}",
+            user_id,
+            if *created { " (created)" } else { "" }
+        ),
+        BootstrapOutcome::Invited(issued) => tracing::info!(
+            "Issued bootstrap admin invitation {}, expiring at {}",
+            issued.invitation.id,
+            issued.invitation.expires_at
+        ),
+        _ => {}
+    }
+    Ok(outcome)
+}
+
+async fn bootstrap_admin(
+    conn: &mut PgConnection,
+    config: &BootstrapAdminConfig,
+) -> Result<BootstrapOutcome, BootstrapError> {
+    lock(conn).await?;
+    if has_super_admin(conn).await? {
+        return Ok(BootstrapOutcome::AlreadyBootstrapped);
+    }
+
+    let email = config.email.trim();
+    let email_canonical = DEFAULT_EMAIL_NORMALIZER.canonical(email);
+    let registered: Option<UserId> = sqlx::query_scalar(
+        r#"
+        SELECT id
+        FROM auth.users
+        WHERE email_canonical = $1
+           OR email = $2
+        LIMIT 1
+        "#,
+    )
+    .bind(&email_canonical)
+    .bind(email)
+    .fetch_optional(&mut *conn)
+    .await?;
+
+    let (user_id, created) = match (registered, config.user_id) {
+        (Some(registered), Some(user_id)) if registered != user_id => {
+            return Err(BootstrapError::EmailTaken(registered));
+        }
+        (Some(user_id), _) => (user_id, false),
+        (None, Some(user_id)) => {
+            let exists: bool =
+                sqlx::query_scalar("SELECT EXISTS (SELECT 1 FROM auth.users WHERE id = $1)")
+                    .bind(user_id)
+                    .fetch_one(&mut *conn)
+                    .await?;
+            if !exists {
+                UserRow::insert_in(conn, &UserRow::new(user_id, None, email.to_string(), None))
+                    .await?;
+            }
+            (user_id, !exists)
+        }
+        (None, None) => return invite(conn, config, email, &email_canonical).await,
+    };
+
+    grant_super_admin(conn, user_id, json!({ "created": created })).await?;
+    Ok(BootstrapOutcome::Granted { user_id, created })
+}
+
+async fn invite(
+    conn: &mut PgConnection,
+    config: &BootstrapAdminConfig,
+    email: &str,
+    email_canonical: &str,
+) -> Result<BootstrapOutcome, BootstrapError> {
+    let now = chrono::Utc::now().naive_utc();
+    if !config.reissue_invitation {
+        let pending: Option<(Uuid, NaiveDateTime)> = sqlx::query_as(
+            r#"
+            SELECT id, expires_at
+            FROM auth.user_invitations
+            WHERE bootstrap_admin
+              AND email_canonical = $1
+              AND accepted_at IS NULL
+              AND revoked_at IS NULL
+              AND expires_at > $2
+            "#,
+        )
+        .bind(email_canonical)
+        .bind(now)
+        .fetch_optional(&mut *conn)
+        .await?;
+        if let Some((invitation_id, expires_at)) = pending {
+            return Ok(BootstrapOutcome::Pending {
+                invitation_id,
+                expires_at,
+            });
+        }
+    }
+
+    // Replaces expired bootstrap invitations, and ones for an email configured earlier.
+    sqlx::query(
+        r#"
+        UPDATE auth.user_invitations
+        SET revoked_at = $2
+        WHERE (email_canonical = $1 OR bootstrap_admin)
+          AND accepted_at IS NULL
+          AND revoked_at IS NULL
+        "#,
+    )
+    .bind(email_canonical)
+    .bind(now)
+    .execute(&mut *conn)
+    .await?;
+    let expires_at = now
+        .checked_add_signed(config.invitation_ttl())
+        .unwrap_or(NaiveDateTime::MAX);
+    let issued = issue_invitation(conn, email, email_canonical, None, expires_at, true).await?;
+    insert_audit_log(
+        conn,
+        None,
+        json!({
+            "type": "bootstrap_admin_invited",
+            "invitation_id": issued.invitation.id,
+            "expires_at": expires_at,
+        }),
+    )
+    .await?;
+    Ok(BootstrapOutcome::Invited(issued))
+}
+
+/// Grant `super_admin` to `user_id`, who is accepting bootstrap invitation `invitation_id` in the
+/// transaction on `conn`, unless someone became super admin since it was issued. Returns whether
+/// it was granted.
+pub(crate) async fn accept_bootstrap_invitation(
+    conn: &mut PgConnection,
+    invitation_id: Uuid,
+    user_id: UserId,
+) -> Result<bool, sqlx::Error> {
+    lock(conn).await?;
+    if has_super_admin(conn).await? {
+        return Ok(false);
+    }
+    grant_super_admin(conn, user_id, json!({ "invitation_id": invitation_id })).await?;
+    tracing::info!(
+        "Bootstrapped super admin {} from invitation {}",
+        user_id,
+        invitation_id
+    );
+    Ok(true)
+}
+
+async fn lock(conn: &mut PgConnection) -> Result<(), sqlx::Error> {
+    sqlx::query("SELECT pg_advisory_xact_lock(hashtext($1))")
+        .bind(BOOTSTRAP_LOCK)
+        .execute(&mut *conn)
+        .await?;
+    Ok(())
+}
+
+async fn has_super_admin(conn: &mut PgConnection) -> Result<bool, sqlx::Error> {
+    sqlx::query_scalar(
+        r#"
+        SELECT EXISTS (
+            SELECT 1
+            FROM auth.user_roles
+            WHERE scope = ANY($1)
+              AND scope_id = $2
+              AND role_name = $3
+              AND effect = 'allow'
+        )
+        "#,
+    )
+    .bind(lookup_scopes(GLOBAL_SCOPE))
+    .bind(GLOBAL_SCOPE_ID)
+    .bind(SUPER_ADMIN_ROLE)
+    .fetch_one(&mut *conn)
+    .await
+}
+
+/// Grant the role with a `role_grant` entry, then log `admin_bootstrapped` with `details`.
+async fn grant_super_admin(
+    conn: &mut PgConnection,
+    user_id: UserId,
+    mut details: serde_json::Value,
+) -> Result<(), sqlx::Error> {
+    let scope = grant_scope(GLOBAL_SCOPE);
+    sqlx::query(
+        r#"
+        INSERT INTO auth.user_roles (user_id, scope, scope_id, role_name, effect)
+        VALUES ($1, $2, $3, $4, 'allow')
+        ON CONFLICT (user_id, scope, scope_id, role_name) DO UPDATE
+        SET effect = EXCLUDED.effect,
+            condition = NULL,
+            source_group_id = NULL,
+            source_provider = NULL
+        "#,
+    )
+    .bind(user_id)
+    .bind(&scope)
+    .bind(GLOBAL_SCOPE_ID)
+    .bind(SUPER_ADMIN_ROLE)
+    .execute(&mut *conn)
+    .await?;
+    insert_role_audit_log(
+        conn,
+        None,
+        "role_grant",
+        RoleAssignmentTarget::User(user_id),
+        &scope,
+        GLOBAL_SCOPE_ID,
+        SUPER_ADMIN_ROLE,
+        None,
+    )
+    .await?;
+    details["type"] = json!("admin_bootstrapped");
+    insert_audit_log(conn, Some(user_id), details).await
+}
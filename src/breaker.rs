@@ -0,0 +1,356 @@
+use std::fmt;
+use std::future::Future;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use chrono::NaiveDateTime;
+use serde::Serialize;
+
+/// Weight of the newest call in `ProviderHealth::error_rate` and `avg_latency_ms`.
+const EWMA_WEIGHT: f64 = 0.2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CircuitBreakerConfig {
+    /// Consecutive failures that open the circuit.
+    pub failure_threshold: u32,
+    /// How long an open circuit rejects calls before letting a trial call through.
+    pub open_for: Duration,
+    /// Calls still running after this long are abandoned and count as failures.
+    pub call_timeout: Duration,
+}
+
+impl Default for CircuitBreakerConfig {
+    fn default() -> Self {
+        Self {
+            failure_threshold: 5,
+            open_for: Duration::from_secs(30),
+            call_timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CircuitState {
+    Closed,
+    /// Calls fail immediately without reaching the provider.
+    Open,
+    /// One trial call is let through; its outcome closes or reopens the circuit.
+    HalfOpen,
+}
+
+/// Call statistics for one provider, e.g. for an admin dashboard or metrics export.
+#[derive(Debug, Clone, Serialize)]
+pub struct ProviderHealth {
+    pub name: String,
+    pub state: CircuitState,
+    pub calls: u64,
+    /// Includes timeouts.
+    pub failures: u64,
+    pub timeouts: u64,
+    /// Calls refused while the circuit was open.
+    pub rejected: u64,
+    pub consecutive_failures: u32,
+    /// Exponentially weighted share of recent calls that failed, from 0 to 1.
+    pub error_rate: f64,
+    /// Exponentially weighted latency of recent calls.
+    pub avg_latency_ms: f64,
+    pub last_latency_ms: Option<u64>,
+    pub last_failure_at: Option<NaiveDateTime>,
+    /// Seconds until an open circuit lets a trial call through.
+    pub retry_after_secs: Option<u64>,
+}
+
+#[derive(Debug)]
+pub enum CallError<E> {
+    /// The circuit is open; the provider was not called.
+    Open {
+        retry_after: Duration,
+    },
+    TimedOut,
+    Failed(E),
+}
+
+impl<E: fmt::Display> fmt::Display for CallError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CallError::Open { retry_after } => write!(
+                f,
+                "Circuit open, retry in {}s",
+                retry_after.as_secs().max(1)
+            ),
+            CallError::TimedOut => write!(f, "Call timed out"),
+            CallError::Failed(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl<E: fmt::Debug + fmt::Display> std::error::Error for CallError<E> {}
+
+/// An external provider is failing or not answering in time. `retry_after` is set when the
+/// circuit is open.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProviderUnavailable {
+    pub provider: String,
+    pub retry_after: Option<Duration>,
+}
+
+impl fmt::Display for ProviderUnavailable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Identity provider {} is unavailable", self.provider)
+    }
+}
+
+impl std::error::Error for ProviderUnavailable {}
+
+#[derive(Debug, Default)]
+struct Stats {
+    calls: u64,
+    failures: u64,
+    timeouts: u64,
+    rejected: u64,
+    consecutive_failures: u32,
+    error_rate: f64,
+    avg_latency_ms: f64,
+    last_latency_ms: Option<u64>,
+    last_failure_at: Option<NaiveDateTime>,
+    /// Set while the circuit is open or half-open.
+    opened_at: Option<Instant>,
+    /// Start of the half-open trial call, if one is running.
+    trial_started_at: Option<Instant>,
+}
+
+/// Fails calls to an external provider fast once it keeps failing, so requests do not pile up
+/// waiting on an outage. Also tracks the provider's error rate and latency.
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    name: String,
+    config: CircuitBreakerConfig,
+    stats: Mutex<Stats>,
+}
+
+impl CircuitBreaker {
+    pub fn new<S: Into<String>>(name: S, config: CircuitBreakerConfig) -> Self {
+        Self {
+            name: name.into(),
+            config,
+            stats: Mutex::new(Stats::default()),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Run `call` unless the circuit is open. Errors for which `is_outage` is false, e.g. a
+    /// provider rejecting an expired code, count as successful calls.
+    pub async fn call<T, E, F>(
+        &self,
+        call: F,
+        is_outage: impl Fn(&E) -> bool,
+    ) -> Result<T, CallError<E>>
+    where
+        F: Future<Output = Result<T, E>>,
+    {
+        self.admit(Instant::now())?;
+        let started = Instant::now();
+        let result = tokio::time::timeout(self.config.call_timeout, call).await;
+        let latency = started.elapsed();
+        match result {
+            Ok(Ok(value)) => {
+                self.record(latency, Outcome::Success);
+                Ok(value)
+            }
+            Ok(Err(err)) => {
+                let outcome = if is_outage(&err) {
+                    Outcome::Failure
+                } else {
+                    Outcome::Success
+                };
+                self.record(latency, outcome);
+                Err(CallError::Failed(err))
+            }
+            Err(_) => {
+                self.record(latency, Outcome::TimedOut);
+                Err(CallError::TimedOut)
+            }
+        }
+    }
+
+    pub fn health(&self) -> ProviderHealth {
+        let stats = self.stats.lock().unwrap_or_else(|err| err.into_inner());
+        let now = Instant::now();
+        ProviderHealth {
+            name: self.name.clone(),
+            state: self.state(&stats, now),
+            calls: stats.calls,
+            failures: stats.failures,
+            timeouts: stats.timeouts,
+            rejected: stats.rejected,
+            consecutive_failures: stats.consecutive_failures,
+            error_rate: stats.error_rate,
+            avg_latency_ms: stats.avg_latency_ms,
+            last_latency_ms: stats.last_latency_ms,
+            last_failure_at: stats.last_failure_at,
+            retry_after_secs: self
+                .retry_after(&stats, now)
+                .map(|retry_after| retry_after.as_secs().max(1)),
+        }
+    }
+
+    fn state(&self, stats: &Stats, now: Instant) -> CircuitState {
+        match stats.opened_at {
+            None => CircuitState::Closed,
+            Some(opened_at) if now.duration_since(opened_at) < self.config.open_for => {
+                CircuitState::Open
+            }
+            Some(_) => CircuitState::HalfOpen,
+        }
+    }
+
+    fn retry_after(&self, stats: &Stats, now: Instant) -> Option<Duration> {
+        let opened_at = stats.opened_at?;
+        let retry_after = self
+            .config
+            .open_for
+            .checked_sub(now.duration_since(opened_at))?;
+        (!retry_after.is_zero()).then_some(retry_after)
+    }
+
+    fn admit<E>(&self, now: Instant) -> Result<(), CallError<E>> {
+        let mut stats = self.stats.lock().unwrap_or_else(|err| err.into_inner());
+        let admitted = match self.state(&stats, now) {
+            CircuitState::Closed => true,
+            CircuitState::Open => false,
+            // A trial that outlived the call timeout was cancelled by its caller.
+            CircuitState::HalfOpen => stats
+                .trial_started_at
+                .is_none_or(|started| now.duration_since(started) >= self.config.call_timeout),
+        };
+        if !admitted {
+            stats.rejected += 1;
+            return Err(CallError::Open {
+                retry_after: self
+                    .retry_after(&stats, now)
+                    .unwrap_or(self.config.call_timeout),
+            });
+        }
+        if stats.opened_at.is_some() {
+            stats.trial_started_at = Some(now);
+        }
+        Ok(())
+    }
+
+    fn record(&self, latency: Duration, outcome: Outcome) {
+        let mut stats = self.stats.lock().unwrap_or_else(|err| err.into_inner());
+        let latency_ms = latency.as_millis() as f64;
+        stats.avg_latency_ms = if stats.calls == 0 {
+            latency_ms
+        } else {
+            stats.avg_latency_ms + EWMA_WEIGHT * (latency_ms - stats.avg_latency_ms)
+        };
+        stats.calls += 1;
+        stats.last_latency_ms = Some(latency.as_millis() as u64);
+        stats.trial_started_at = None;
+
+        let failed = outcome != Outcome::Success;
+        stats.error_rate += EWMA_WEIGHT * (f64::from(u8::from(failed)) - stats.error_rate);
+        if !failed {
+            stats.consecutive_failures = 0;
+            if stats.opened_at.take().is_some() {
+                tracing::info!("Circuit for {} closed", self.name);
+            }
+            return;
+        }
+
+        stats.failures += 1;
+        if outcome == Outcome::TimedOut {
+            stats.timeouts += 1;
+        }
+        stats.consecutive_failures = stats.consecutive_failures.saturating_add(1);
+        stats.last_failure_at = Some(chrono::Utc::now().naive_utc());
+        // A failed trial reopens the circuit for another full interval.
+        if stats.opened_at.is_some() || stats.consecutive_failures >= self.config.failure_threshold
+        {
+            if stats.opened_at.is_none() {
+                tracing::warn!(
+                    "Circuit for {} opened after {} consecutive failures",
+                    self.name,
+                    stats.consecutive_failures
+                );
+            }
+            stats.opened_at = Some(Instant::now());
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Outcome {
+    Success,
+    Failure,
+    TimedOut,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{CallError, CircuitBreaker, CircuitBreakerConfig, CircuitState};
+
+    #[tokio::test]
+    async fn opens_after_failures_and_recovers() {
+        let breaker = CircuitBreaker::new(
+            "idp",
+            CircuitBreakerConfig {
+                failure_threshold: 2,
+                open_for: Duration::from_millis(50),
+                call_timeout: Duration::from_millis(20),
+            },
+        );
+        let outage = |_: &&str| true;
+
+        let rejected = breaker.call(async { Err::<(), _>("bad code") }, |_| false);
+        assert!(matches!(rejected.await, Err(CallError::Failed("bad code"))));
+        assert!(
+            breaker
+                .call(async { Err::<(), _>("down") }, outage)
+                .await
+                .is_err()
+        );
+        let slow = async {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            Ok::<_, &str>(())
+        };
+        assert!(matches!(
+            breaker.call(slow, outage).await,
+            Err(CallError::TimedOut)
+        ));
+        assert_eq!(breaker.health().state, CircuitState::Open);
+        assert!(matches!(
+            breaker.call(async { Ok::<_, &str>(()) }, outage).await,
+            Err(CallError::Open { .. })
+        ));
+
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        assert_eq!(breaker.health().state, CircuitState::HalfOpen);
+        assert!(
+            breaker
+                .call(async { Ok::<_, &str>(()) }, outage)
+                .await
+                .is_ok()
+        );
+
+        let health = breaker.health();
+        assert_eq!(health.state, CircuitState::Closed);
+        assert_eq!(
+            (
+                health.calls,
+                health.failures,
+                health.timeouts,
+                health.rejected
+            ),
+            (4, 2, 1, 1)
+        );
+        assert_eq!(health.consecutive_failures, 0);
+    }
+}
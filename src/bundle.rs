@@ -0,0 +1,285 @@
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::{FromRow, PgConnection, PgPool};
+
+use crate::db::{RoleAssignmentTarget, insert_audit_log, insert_role_audit_log};
+use crate::user_id::UserId;
+
+/// A role granted by a bundle. The scope id comes from `apply_bundle`.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, FromRow)]
+pub struct BundleGrant {
+    pub scope: String,
+    pub role_name: String,
+}
+
+/// A named set of grants, e.g. "Engineer", applied to a user in one step.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct RoleBundle {
+    pub name: String,
+    pub description: Option<String>,
+    pub grants: Vec<BundleGrant>,
+}
+
+#[derive(Debug, Clone, FromRow)]
+struct RoleBundleRow {
+    name: String,
+    description: Option<String>,
+}
+
+#[derive(Debug, Clone, FromRow)]
+struct RoleBundleGrantRow {
+    bundle_name: String,
+    scope: String,
+    role_name: String,
+}
+
+pub async fn list_bundles(pool: &PgPool) -> Result<Vec<RoleBundle>, sqlx::Error> {
+    let rows = sqlx::query_as::<_, RoleBundleRow>(
+        r#"
+        SELECT name, description
+        FROM auth.role_bundles
+        ORDER BY name ASC
+        "#,
+    )
+    .fetch_all(pool)
+    .await?;
+    let grants = sqlx::query_as::<_, RoleBundleGrantRow>(
+        r#"
+        SELECT bundle_name, scope, role_name
+        FROM auth.role_bundle_grants
+        ORDER BY bundle_name ASC, scope ASC, role_name ASC
+        "#,
+    )
+    .fetch_all(pool)
+    .await?;
+
+    Ok(rows
+        .into_iter()
+        .map(|row| RoleBundle {
+            grants: grants
+                .iter()
+                .filter(|grant| grant.bundle_name == row.name)
+                .map(|grant| BundleGrant {
+                    scope: grant.scope.clone(),
+                    role_name: grant.role_name.clone(),
+                })
+                .collect(),
+            name: row.name,
+            description: row.description,
+        })
+        .collect())
+}
+
+pub async fn get_bundle(pool: &PgPool, name: &str) -> Result<Option<RoleBundle>, sqlx::Error> {
+    let Some(row) = sqlx::query_as::<_, RoleBundleRow>(
+        r#"
+        SELECT name, description
+        FROM auth.role_bundles
+        WHERE name = $1
+        "#,
+    )
+    .bind(name)
+    .fetch_optional(pool)
+    .await?
+    else {
+        return Ok(None);
+    };
+    let grants = sqlx::query_as::<_, BundleGrant>(
+        r#"
+        SELECT scope, role_name
+        FROM auth.role_bundle_grants
+        WHERE bundle_name = $1
+        ORDER BY scope ASC, role_name ASC
+        "#,
+    )
+    .bind(name)
+    .fetch_all(pool)
+    .await?;
+
+    Ok(Some(RoleBundle {
+        name: row.name,
+        description: row.description,
+        grants,
+    }))
+}
+
+/// Create a bundle or replace its description and grants, and log a `role_bundle_saved` entry.
+///
+/// Users who already received the bundle keep the grants it had when it was applied.
+pub async fn save_bundle(
+    pool: &PgPool,
+    actor_user_id: UserId,
+    bundle: &RoleBundle,
+) -> Result<(), sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    write_bundle(&mut tx, Some(actor_user_id), bundle).await?;
+    tx.commit().await
+}
+
+/// `save_bundle` on an open transaction. `actor_user_id` is `None` for changes made outside a
+/// request, e.g. by `sync::apply`.
+pub(crate) async fn write_bundle(
+    conn: &mut PgConnection,
+    actor_user_id: Option<UserId>,
+    bundle: &RoleBundle,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        r#"
+        INSERT INTO auth.role_bundles (name, description)
+        VALUES ($1, $2)
+        ON CONFLICT (name) DO UPDATE
+        SET description = EXCLUDED.description,
+            updated_at = CURRENT_TIMESTAMP
+        "#,
+    )
+    .bind(&bundle.name)
+    .bind(&bundle.description)
+    .execute(&mut *conn)
+    .await?;
+    sqlx::query("DELETE FROM auth.role_bundle_grants WHERE bundle_name = $1")
+        .bind(&bundle.name)
+        .execute(&mut *conn)
+        .await?;
+    for grant in &bundle.grants {
+        sqlx::query(
+            r#"
+            INSERT INTO auth.role_bundle_grants (bundle_name, scope, role_name)
+            VALUES ($1, $2, $3)
+            ON CONFLICT DO NOTHING
+            "#,
+        )
+        .bind(&bundle.name)
+        .bind(&grant.scope)
+        .bind(&grant.role_name)
+        .execute(&mut *conn)
+        .await?;
+    }
+
+    insert_audit_log(
+        &mut *conn,
+        actor_user_id,
+        json!({
+            "type": "role_bundle_saved",
+            "bundle": bundle.name,
+            "grants": bundle.grants,
+        }),
+    )
+    .await?;
+    Ok(())
+}
+
+/// Delete a bundle and log a `role_bundle_deleted` entry. Grants it already handed out are kept.
+/// Returns `false` if the bundle does not exist.
+pub async fn delete_bundle(
+    pool: &PgPool,
+    actor_user_id: UserId,
+    name: &str,
+) -> Result<bool, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    let deleted = sqlx::query("DELETE FROM auth.role_bundles WHERE name = $1")
+        .bind(name)
+        .execute(&mut *tx)
+        .await?
+        .rows_affected()
+        > 0;
+    if deleted {
+        insert_audit_log(
+            &mut *tx,
+            Some(actor_user_id),
+            json!({
+                "type": "role_bundle_deleted",
+                "bundle": name,
+            }),
+        )
+        .await?;
+    }
+    tx.commit().await?;
+    Ok(deleted)
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct BundleApplication {
+    pub bundle: String,
+    pub user_id: UserId,
+    pub scope_id: String,
+    /// Grants the user did not hold before.
+    pub granted: Vec<BundleGrant>,
+    /// Grants left alone because the user already holds them, or is denied them.
+    pub unchanged: Vec<BundleGrant>,
+}
+
+/// Grant every role in the bundle to the user at `scope_id`, in one transaction.
+///
+/// Each new grant gets a `role_grant` audit entry, followed by one
+/// `role_bundle_applied` entry. Existing grants for the same role, including denies and
+/// conditional grants, are left as they are. Returns `None` if the bundle does not exist.
+pub async fn apply_bundle(
+    pool: &PgPool,
+    actor_user_id: UserId,
+    user_id: UserId,
+    bundle_name: &str,
+    scope_id: &str,
+) -> Result<Option<BundleApplication>, sqlx::Error> {
+    let Some(bundle) = get_bundle(pool, bundle_name).await? else {
+        return Ok(None);
+    };
+
+    let mut tx = pool.begin().await?;
+    let mut granted = Vec::new();
+    let mut unchanged = Vec::new();
+    for grant in bundle.grants {
+        let inserted = sqlx::query(
+            r#"
+            INSERT INTO auth.user_roles (user_id, scope, scope_id, role_name)
+            VALUES ($1, $2, $3, $4)
+            ON CONFLICT DO NOTHING
+            "#,
+        )
+        .bind(user_id)
+        .bind(&grant.scope)
+        .bind(scope_id)
+        .bind(&grant.role_name)
+        .execute(&mut *tx)
+        .await?
+        .rows_affected()
+            > 0;
+        if inserted {
+            insert_role_audit_log(
+                &mut tx,
+                Some(actor_user_id),
+                "role_grant",
+                RoleAssignmentTarget::User(user_id),
+                &grant.scope,
+                scope_id,
+                &grant.role_name,
+                None,
+            )
+            .await?;
+            granted.push(grant);
+        } else {
+            unchanged.push(grant);
+        }
+    }
+
+    insert_audit_log(
+        &mut *tx,
+        Some(actor_user_id),
+        json!({
+            "type": "role_bundle_applied",
+            "bundle": bundle.name,
+            "target_user_id": user_id.to_string(),
+            "scope_id": scope_id,
+            "granted": granted,
+        }),
+    )
+    .await?;
+    tx.commit().await?;
+
+    Ok(Some(BundleApplication {
+        bundle: bundle.name,
+        user_id,
+        scope_id: scope_id.to_string(),
+        granted,
+        unchanged,
+    }))
+}
@@ -0,0 +1,342 @@
+//! CAPTCHA verification for routes open to abuse.
+//!
+//! The crate checks `AuthApp::captcha_verifier` itself only where it accepts credentials or
+//! creates records: user provisioning in `/auth/me` and password reauthentication. Login and
+//! password reset are served by the host app, so it wraps those routes in [`CaptchaLayer`].
+
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use axum::extract::{ConnectInfo, Request};
+use axum::http::{HeaderMap, Uri};
+use axum::response::{IntoResponse, Response};
+use futures_util::future::BoxFuture;
+use tower::{Layer, Service};
+
+use crate::prelude::RejectReason;
+
+/// Header carrying the challenge response token.
+pub const CAPTCHA_TOKEN_HEADER: &str = "x-captcha-token";
+/// Query parameter accepted instead of the header, for browser redirects such as login.
+pub const CAPTCHA_TOKEN_QUERY: &str = "captcha_token";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CaptchaError {
+    /// The request carried no challenge response.
+    Missing,
+    /// The provider rejected the challenge response.
+    Failed { error_codes: Vec<String> },
+    /// The provider could not be reached or answered with garbage.
+    Unavailable(String),
+}
+
+impl CaptchaError {
+    /// Stable machine-readable code for API responses.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::Missing => "captcha_required",
+            Self::Failed { .. } => "captcha_failed",
+            Self::Unavailable(_) => "captcha_unavailable",
+        }
+    }
+}
+
+impl fmt::Display for CaptchaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Missing => write!(f, "CAPTCHA response is required"),
+            Self::Failed { .. } => write!(f, "CAPTCHA verification failed"),
+            Self::Unavailable(msg) => write!(f, "CAPTCHA provider unavailable: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for CaptchaError {}
+
+/// Verifies a CAPTCHA challenge response with its provider.
+pub trait CaptchaVerifier: Send + Sync {
+    fn verify<'a>(
+        &'a self,
+        token: &'a str,
+        remote_ip: Option<IpAddr>,
+    ) -> BoxFuture<'a, Result<(), CaptchaError>>;
+}
+
+/// Challenge response from the `x-captcha-token` header, or the `captcha_token` query parameter.
+pub fn captcha_token(headers: &HeaderMap, uri: &Uri) -> Option<String> {
+    if let Some(token) = headers
+        .get(CAPTCHA_TOKEN_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .filter(|token| !token.is_empty())
+    {
+        return Some(token.to_string());
+    }
+    url::form_urlencoded::parse(uri.query()?.as_bytes())
+        .find(|(key, value)| key == CAPTCHA_TOKEN_QUERY && !value.is_empty())
+        .map(|(_, value)| value.into_owned())
+}
+
+/// Verify the request's challenge response. Missing tokens fail without calling the provider.
+pub async fn verify_captcha(
+    verifier: &dyn CaptchaVerifier,
+    headers: &HeaderMap,
+    uri: &Uri,
+    remote_ip: Option<IpAddr>,
+) -> Result<(), CaptchaError> {
+    let token = captcha_token(headers, uri).ok_or(CaptchaError::Missing)?;
+    verifier.verify(&token, remote_ip).await
+}
+
+/// Require a passing CAPTCHA before the wrapped routes run, e.g. the app's login route.
+///
+/// Failures are rejected with a `captcha_required`, `captcha_failed` or `captcha_unavailable`
+/// error code. The client address is forwarded to the provider when the server is run with
+/// `into_make_service_with_connect_info::<SocketAddr>()`.
+///
+/// ```ignore
+/// let login = Router::new()
+///     .route("/auth/login", get(login_handler))
+///     .layer(CaptchaLayer::new(Arc::new(TurnstileVerifier::new(secret))));
+/// ```
+#[derive(Clone)]
+pub struct CaptchaLayer {
+    verifier: Arc<dyn CaptchaVerifier>,
+}
+
+impl CaptchaLayer {
+    pub fn new(verifier: Arc<dyn CaptchaVerifier>) -> Self {
+        Self { verifier }
+    }
+}
+
+impl<Inner> Layer<Inner> for CaptchaLayer {
+    type Service = RequireCaptcha<Inner>;
+
+    fn layer(&self, inner: Inner) -> Self::Service {
+        RequireCaptcha {
+            verifier: self.verifier.clone(),
+            inner,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct RequireCaptcha<Inner> {
+    verifier: Arc<dyn CaptchaVerifier>,
+    inner: Inner,
+}
+
+impl<Inner, B> Service<Request<B>> for RequireCaptcha<Inner>
+where
+    Inner: Service<Request<B>, Response = Response> + Clone + Send + 'static,
+    Inner::Future: Send + 'static,
+    B: Send + 'static,
+{
+    type Response = Response;
+    type Error = Inner::Error;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<B>) -> Self::Future {
+        let verifier = self.verifier.clone();
+        let clone = self.inner.clone();
+        // https://docs.rs/tower/latest/tower/trait.Service.html#be-careful-when-cloning-inner-services
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        Box::pin(async move {
+            let remote_ip = req
+                .extensions()
+                .get::<ConnectInfo<SocketAddr>>()
+                .map(|ConnectInfo(addr)| addr.ip());
+            match verify_captcha(&*verifier, req.headers(), req.uri(), remote_ip).await {
+                Ok(()) => inner.call(req).await,
+                Err(err) => {
+                    tracing::info!("Rejecting {} {}: {}", req.method(), req.uri().path(), err);
+                    Ok(RejectReason::captcha_rejected(&err).into_response())
+                }
+            }
+        })
+    }
+}
+
+#[cfg(any(feature = "hcaptcha", feature = "turnstile"))]
+#[derive(serde::Deserialize)]
+struct SiteVerifyResponse {
+    success: bool,
+    #[serde(rename = "error-codes", default)]
+    error_codes: Vec<String>,
+}
+
+/// Shared `siteverify` call; hCaptcha and Turnstile use the same form fields and response shape.
+#[cfg(any(feature = "hcaptcha", feature = "turnstile"))]
+async fn site_verify(
+    client: &reqwest::Client,
+    url: &str,
+    secret: &str,
+    sitekey: Option<&str>,
+    token: &str,
+    remote_ip: Option<IpAddr>,
+) -> Result<(), CaptchaError> {
+    let remote_ip = remote_ip.map(|ip| ip.to_string());
+    let mut form = vec![("secret", secret), ("response", token)];
+    if let Some(remote_ip) = remote_ip.as_deref() {
+        form.push(("remoteip", remote_ip));
+    }
+    if let Some(sitekey) = sitekey {
+        form.push(("sitekey", sitekey));
+    }
+
+    let response: SiteVerifyResponse = client
+        .post(url)
+        .form(&form)
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(|err| CaptchaError::Unavailable(err.to_string()))?
+        .json()
+        .await
+        .map_err(|err| CaptchaError::Unavailable(err.to_string()))?;
+    if response.success {
+        Ok(())
+    } else {
+        Err(CaptchaError::Failed {
+            error_codes: response.error_codes,
+        })
+    }
+}
+
+#[cfg(feature = "hcaptcha")]
+pub const HCAPTCHA_VERIFY_URL: &str = "https://api.hcaptcha.com/siteverify";
+
+/// hCaptcha `siteverify` client.
+#[cfg(feature = "hcaptcha")]
+#[derive(Debug, Clone)]
+pub struct HCaptchaVerifier {
+    client: reqwest::Client,
+    secret: String,
+    sitekey: Option<String>,
+    verify_url: String,
+}
+
+#[cfg(feature = "hcaptcha")]
+impl HCaptchaVerifier {
+    pub fn new<S: Into<String>>(secret: S) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            secret: secret.into(),
+            sitekey: None,
+            verify_url: HCAPTCHA_VERIFY_URL.to_string(),
+        }
+    }
+
+    /// Also require the response to have been issued for this sitekey.
+    pub fn with_sitekey<S: Into<String>>(mut self, sitekey: S) -> Self {
+        self.sitekey = Some(sitekey.into());
+        self
+    }
+
+    pub fn with_verify_url<S: Into<String>>(mut self, verify_url: S) -> Self {
+        self.verify_url = verify_url.into();
+        self
+    }
+}
+
+#[cfg(feature = "hcaptcha")]
+impl CaptchaVerifier for HCaptchaVerifier {
+    fn verify<'a>(
+        &'a self,
+        token: &'a str,
+        remote_ip: Option<IpAddr>,
+    ) -> BoxFuture<'a, Result<(), CaptchaError>> {
+        Box::pin(site_verify(
+            &self.client,
+            &self.verify_url,
+            &self.secret,
+            self.sitekey.as_deref(),
+            token,
+            remote_ip,
+        ))
+    }
+}
+
+#[cfg(feature = "turnstile")]
+pub const TURNSTILE_VERIFY_URL: &str = "https://challenges.cloudflare.com/turnstile/v0/siteverify";
+
+/// Cloudflare Turnstile `siteverify` client.
+#[cfg(feature = "turnstile")]
+#[derive(Debug, Clone)]
+pub struct TurnstileVerifier {
+    client: reqwest::Client,
+    secret: String,
+    verify_url: String,
+}
+
+#[cfg(feature = "turnstile")]
+impl TurnstileVerifier {
+    pub fn new<S: Into<String>>(secret: S) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            secret: secret.into(),
+            verify_url: TURNSTILE_VERIFY_URL.to_string(),
+        }
+    }
+
+    pub fn with_verify_url<S: Into<String>>(mut self, verify_url: S) -> Self {
+        self.verify_url = verify_url.into();
+        self
+    }
+}
+
+#[cfg(feature = "turnstile")]
+impl CaptchaVerifier for TurnstileVerifier {
+    fn verify<'a>(
+        &'a self,
+        token: &'a str,
+        remote_ip: Option<IpAddr>,
+    ) -> BoxFuture<'a, Result<(), CaptchaError>> {
+        Box::pin(site_verify(
+            &self.client,
+            &self.verify_url,
+            &self.secret,
+            None,
+            token,
+            remote_ip,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::{HeaderMap, HeaderValue, Uri};
+
+    use super::{CAPTCHA_TOKEN_HEADER, captcha_token};
+
+    #[test]
+    fn token_prefers_header_over_query() {
+        let uri: Uri = "/auth/login?origin=%2F&captcha_token=from-query"
+            .parse()
+            .unwrap();
+        let mut headers = HeaderMap::new();
+        assert_eq!(
+            captcha_token(&headers, &uri),
+            Some("from-query".to_string())
+        );
+
+        headers.insert(
+            CAPTCHA_TOKEN_HEADER,
+            HeaderValue::from_static("from-header"),
+        );
+        assert_eq!(
+            captcha_token(&headers, &uri),
+            Some("from-header".to_string())
+        );
+        assert_eq!(
+            captcha_token(&HeaderMap::new(), &"/auth/login".parse().unwrap()),
+            None
+        );
+    }
+}
@@ -0,0 +1,390 @@
+//! Casbin policy export, for services that enforce with Casbin against this crate's grants.
+//!
+//! `export_policy` renders the grants and group memberships as policy CSV for `CASBIN_MODEL`:
+//!
+//! ```text
+//! p, user:<uuid>, project, p1, editor, allow
+//! p, group:<uuid>, project, proj-*, viewer, allow
+//! p, user:<uuid>, project, p2, editor, deny
+//! g, user:<uuid>, group:<uuid>
+//! ```
+//!
+//! Requests are `sub, scope, scope_id, role` with `sub` as `user:<uuid>`. The export holds the
+//! grants that count in the current environment (see `environment`), with pinned scopes
+//! resolved. Conditional grants need a request to evaluate, so conditional allows are left out and
+//! conditional denies are exported as unconditional ones, as `EffectiveRole::applies` does without
+//! a context. Super admins and group admins are exported as wildcard rules; unlike
+//! `db::user_has_effective_access`, a deny still wins over them.
+//!
+//! `CasbinPolicyWatcher` exports again whenever `UserUpdates` reports a role or membership
+//! change, for enforcers that reload their policy from a file or a channel.
+
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde_json::Value;
+use sqlx::{FromRow, PgPool};
+use tokio::sync::{broadcast, watch};
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+
+use crate::db::{
+    EffectiveRole, GLOBAL_SCOPE, GLOBAL_SCOPE_ID, GROUP_ADMIN_ROLE, RoleEffect, SUPER_ADMIN_ROLE,
+};
+use crate::environment::resolve_scope;
+use crate::updates::{UpdateKind, UserUpdate, UserUpdates};
+
+/// The Casbin model `export_policy` is written for.
+pub const CASBIN_MODEL: &str = r#"[request_definition]
+r = sub, scope, scope_id, role
+
+[policy_definition]
+p = sub, scope, scope_id, role, eft
+
+[role_definition]
+g = _, _
+
+[policy_effect]
+e = some(where (p.eft == allow)) && !some(where (p.eft == deny))
+
+[matchers]
+m = g(r.sub, p.sub) && keyMatch(r.scope, p.scope) && keyMatch(r.role, p.role) && (keyMatch(r.scope_id, p.scope_id) || p.scope_id == "global")
+"#;
+
+const WILDCARD: &str = "*";
+const SUPER_ADMIN_SUBJECT: &str = "role:super_admin";
+
+/// One policy line: `p` with `sub, scope, scope_id, role, eft`, or `g` with `member, group`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CasbinRule {
+    pub ptype: &'static str,
+    pub values: Vec<String>,
+}
+
+impl CasbinRule {
+    fn policy(
+        subject: String,
+        scope: &str,
+        scope_id: &str,
+        role: &str,
+        effect: RoleEffect,
+    ) -> Self {
+        let effect = match effect {
+            RoleEffect::Allow => "allow",
+            RoleEffect::Deny => "deny",
+        };
+        Self {
+            ptype: "p",
+            values: vec![
+                subject,
+                scope.to_string(),
+                scope_id.to_string(),
+                role.to_string(),
+                effect.to_string(),
+            ],
+        }
+    }
+
+    fn grouping(member: String, group: String) -> Self {
+        Self {
+            ptype: "g",
+            values: vec![member, group],
+        }
+    }
+
+    /// The rule as a line of Casbin policy CSV.
+    pub fn to_csv_line(&self) -> String {
+        std::iter::once(self.ptype)
+            .chain(self.values.iter().map(String::as_str))
+            .map(csv_field)
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) || value.trim() != value {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+#[derive(FromRow)]
+struct GrantRow {
+    subject: String,
+    scope: String,
+    scope_id: String,
+    role_name: String,
+    effect: RoleEffect,
+    condition: Option<Value>,
+}
+
+/// The current grants and memberships as Casbin rules, policies first. Soft-deleted groups are
+/// left out.
+pub async fn export_rules(pool: &PgPool) -> Result<Vec<CasbinRule>, sqlx::Error> {
+    let grants = sqlx::query_as::<_, GrantRow>(
+        r#"
+        SELECT 'user:' || user_id AS subject, scope, scope_id, role_name, effect, condition
+        FROM auth.user_roles
+        UNION ALL
+        SELECT 'group:' || gr.group_id AS subject, gr.scope, gr.scope_id, gr.role_name,
+               gr.effect, gr.condition
+        FROM auth.group_roles gr
+        JOIN auth.groups g
+          ON g.id = gr.group_id
+         AND g.deleted_at IS NULL
+        ORDER BY subject ASC, scope ASC, scope_id ASC, role_name ASC, effect ASC
+        "#,
+    )
+    .fetch_all(pool)
+    .await?;
+    let memberships: Vec<(String, String, String)> = sqlx::query_as(
+        r#"
+        SELECT 'user:' || gm.user_id, gm.group_id::TEXT, gm.role_name
+        FROM auth.group_memberships gm
+        JOIN auth.groups g
+          ON g.id = gm.group_id
+         AND g.deleted_at IS NULL
+        ORDER BY gm.user_id ASC, gm.group_id ASC
+        "#,
+    )
+    .fetch_all(pool)
+    .await?;
+
+    let mut rules = Vec::new();
+    let mut super_admins = Vec::new();
+    for grant in grants {
+        let role = EffectiveRole {
+            scope: grant.scope,
+            scope_id: grant.scope_id,
+            role_name: grant.role_name,
+            effect: grant.effect,
+            condition: grant.condition,
+        };
+        if !role.applies(None) {
+            continue;
+        }
+        let Some(scope) = resolve_scope(&role.scope) else {
+            continue;
+        };
+        if scope == GLOBAL_SCOPE
+            && role.scope_id == GLOBAL_SCOPE_ID
+            && role.role_name == SUPER_ADMIN_ROLE
+            && role.effect == RoleEffect::Allow
+        {
+            super_admins.push(grant.subject);
+            continue;
+        }
+        rules.push(CasbinRule::policy(
+            grant.subject,
+            scope,
+            &role.scope_id,
+            &role.role_name,
+            role.effect,
+        ));
+    }
+
+    // Group admins hold every role wherever the scope id is their group's id.
+    let mut admin_groups: Vec<&str> = memberships
+        .iter()
+        .filter(|(_, _, role_name)| role_name == GROUP_ADMIN_ROLE)
+        .map(|(_, group_id, _)| group_id.as_str())
+        .collect();
+    admin_groups.sort_unstable();
+    admin_groups.dedup();
+    for group_id in admin_groups {
+        rules.push(CasbinRule::policy(
+            format!("group_admin:{}", group_id),
+            WILDCARD,
+            group_id,
+            WILDCARD,
+            RoleEffect::Allow,
+        ));
+    }
+    if !super_admins.is_empty() {
+        rules.push(CasbinRule::policy(
+            SUPER_ADMIN_SUBJECT.to_string(),
+            WILDCARD,
+            WILDCARD,
+            WILDCARD,
+            RoleEffect::Allow,
+        ));
+    }
+
+    rules.extend(
+        super_admins
+            .into_iter()
+            .map(|subject| CasbinRule::grouping(subject, SUPER_ADMIN_SUBJECT.to_string())),
+    );
+    for (subject, group_id, role_name) in memberships {
+        if role_name == GROUP_ADMIN_ROLE {
+            rules.push(CasbinRule::grouping(
+                subject.clone(),
+                format!("group_admin:{}", group_id),
+            ));
+        }
+        rules.push(CasbinRule::grouping(subject, format!("group:{}", group_id)));
+    }
+    Ok(rules)
+}
+
+/// The current grants and memberships as Casbin policy CSV for `CASBIN_MODEL`.
+pub async fn export_policy(pool: &PgPool) -> Result<String, sqlx::Error> {
+    Ok(render_policy(&export_rules(pool).await?))
+}
+
+fn render_policy(rules: &[CasbinRule]) -> String {
+    rules.iter().map(|rule| rule.to_csv_line() + "\n").collect()
+}
+
+/// Replace the file at `path` with `policy` without readers seeing a partial write.
+pub fn write_policy_file(path: &Path, policy: &str) -> std::io::Result<()> {
+    let mut staging = path.as_os_str().to_owned();
+    staging.push(".tmp");
+    let staging = PathBuf::from(staging);
+    std::fs::write(&staging, policy)?;
+    std::fs::rename(&staging, path)
+}
+
+#[derive(Debug, Clone)]
+pub struct CasbinWatchConfig {
+    /// Wait this long after a change before exporting, so a burst of changes, e.g. a group
+    /// sync, is exported once.
+    pub debounce: Duration,
+    /// Also write every export here, with `write_policy_file`.
+    pub policy_file: Option<PathBuf>,
+}
+
+impl Default for CasbinWatchConfig {
+    fn default() -> Self {
+        Self {
+            debounce: Duration::from_secs(1),
+            policy_file: None,
+        }
+    }
+}
+
+/// Cloneable handle to a background task that keeps a Casbin export current.
+///
+/// ```ignore
+/// let (updates, _) = UserUpdates::spawn(pool.clone());
+/// let config = CasbinWatchConfig {
+///     policy_file: Some("/var/run/casbin/policy.csv".into()),
+///     ..CasbinWatchConfig::default()
+/// };
+/// let (policy, task) = CasbinPolicyWatcher::spawn(pool, &updates, config).await?;
+/// ```
+#[derive(Clone)]
+pub struct CasbinPolicyWatcher {
+    rx: watch::Receiver<Arc<String>>,
+}
+
+impl CasbinPolicyWatcher {
+    /// Export once, then again after every role or membership change `updates` reports.
+    pub async fn spawn(
+        pool: Arc<PgPool>,
+        updates: &UserUpdates,
+        config: CasbinWatchConfig,
+    ) -> Result<(Self, JoinHandle<()>), sqlx::Error> {
+        Self::spawn_with_cancellation(pool, updates, config, CancellationToken::new()).await
+    }
+
+    /// Like `spawn`, but the task stops once `cancel` is cancelled.
+    pub async fn spawn_with_cancellation(
+        pool: Arc<PgPool>,
+        updates: &UserUpdates,
+        config: CasbinWatchConfig,
+        cancel: CancellationToken,
+    ) -> Result<(Self, JoinHandle<()>), sqlx::Error> {
+        let changes = updates.subscribe();
+        let policy = export_policy(&pool).await?;
+        publish_file(&config, &policy).await;
+        let (tx, rx) = watch::channel(Arc::new(policy));
+        let handle = tokio::spawn(run_watcher(pool, changes, config, tx, cancel));
+        Ok((Self { rx }, handle))
+    }
+
+    /// The latest export.
+    pub fn policy(&self) -> Arc<String> {
+        self.rx.borrow().clone()
+    }
+
+    /// Notified whenever the export changes.
+    pub fn subscribe(&self) -> watch::Receiver<Arc<String>> {
+        self.rx.clone()
+    }
+}
+
+async fn run_watcher(
+    pool: Arc<PgPool>,
+    mut changes: broadcast::Receiver<UserUpdate>,
+    config: CasbinWatchConfig,
+    tx: watch::Sender<Arc<String>>,
+    cancel: CancellationToken,
+) {
+    loop {
+        let changed = tokio::select! {
+            _ = cancel.cancelled() => return,
+            changed = changes.recv() => changed,
+        };
+        match changed {
+            Ok(update) if update.kind == UpdateKind::Profile => continue,
+            // Missed updates may have been relevant.
+            Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => {}
+            Err(broadcast::error::RecvError::Closed) => return,
+        }
+        tokio::select! {
+            _ = cancel.cancelled() => return,
+            _ = tokio::time::sleep(config.debounce) => {}
+        }
+        // Everything up to now is covered by the export below.
+        while !matches!(
+            changes.try_recv(),
+            Err(broadcast::error::TryRecvError::Empty | broadcast::error::TryRecvError::Closed)
+        ) {}
+        match export_policy(&pool).await {
+            Ok(policy) => {
+                if **tx.borrow() != policy {
+                    publish_file(&config, &policy).await;
+                    tx.send_replace(Arc::new(policy));
+                }
+            }
+            Err(err) => tracing::error!("Exporting the Casbin policy failed: {}", err),
+        }
+    }
+}
+
+async fn publish_file(config: &CasbinWatchConfig, policy: &str) {
+    let Some(path) = config.policy_file.clone() else {
+        return;
+    };
+    let policy = policy.to_string();
+    let written = tokio::task::spawn_blocking(move || write_policy_file(&path, &policy)).await;
+    match written {
+        Ok(Ok(())) => {}
+        Ok(Err(err)) => tracing::error!("Writing the Casbin policy file failed: {}", err),
+        Err(err) => tracing::error!("Writing the Casbin policy file failed: {}", err),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CasbinRule, RoleEffect};
+
+    #[test]
+    fn quotes_fields_that_need_it() {
+        let rule = CasbinRule::policy(
+            "user:1".to_string(),
+            "project",
+            "a, b",
+            "say \"hi\"",
+            RoleEffect::Deny,
+        );
+        assert_eq!(
+            rule.to_csv_line(),
+            r#"p, user:1, project, "a, b", "say ""hi""", deny"#
+        );
+    }
+}
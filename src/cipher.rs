@@ -0,0 +1,213 @@
+use std::fmt;
+
+/// Prefix of every value written by a `FieldCipher`, so columns can hold a mix of plaintext and
+/// ciphertext while they are being migrated.
+pub const ENCRYPTED_PREFIX: &str = "enc:v";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CipherError {
+    /// Not a value written by a `FieldCipher`.
+    Malformed,
+    /// Encrypted under a key version the cipher no longer has.
+    UnknownVersion(u32),
+    /// Wrong key, tampered ciphertext, or associated data that does not match.
+    Failed,
+}
+
+impl fmt::Display for CipherError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CipherError::Malformed => write!(f, "Malformed encrypted value"),
+            CipherError::UnknownVersion(version) => {
+                write!(f, "Unknown encryption key version: {}", version)
+            }
+            CipherError::Failed => write!(f, "Failed to encrypt or decrypt value"),
+        }
+    }
+}
+
+impl std::error::Error for CipherError {}
+
+/// Application-level encryption for sensitive columns.
+///
+/// Values are stored as `enc:v<version>:<base64>`, where the version names the key that
+/// encrypted them. `aad` binds a value to where it is stored, e.g. the table, column and row id,
+/// so a ciphertext copied to another row does not decrypt.
+pub trait FieldCipher: Send + Sync {
+    /// Version of the key new values are encrypted with.
+    fn current_version(&self) -> u32;
+    fn encrypt(&self, plaintext: &[u8], aad: &[u8]) -> Result<String, CipherError>;
+    fn decrypt(&self, stored: &str, aad: &[u8]) -> Result<Vec<u8>, CipherError>;
+}
+
+/// Key version of a stored value, or `None` if it is not encrypted.
+pub fn stored_version(stored: &str) -> Option<u32> {
+    split_stored(stored).map(|(version, _)| version)
+}
+
+pub fn is_encrypted(stored: &str) -> bool {
+    stored_version(stored).is_some()
+}
+
+/// Re-encrypt a value under the current key. Plaintext values are encrypted as they are.
+/// Returns `None` if the value is already under the current key, so a migration only has to
+/// write rows that changed.
+pub fn reencrypt(
+    cipher: &dyn FieldCipher,
+    stored: &str,
+    aad: &[u8],
+) -> Result<Option<String>, CipherError> {
+    match stored_version(stored) {
+        Some(version) if version == cipher.current_version() => Ok(None),
+        Some(_) => cipher.encrypt(&cipher.decrypt(stored, aad)?, aad).map(Some),
+        None => cipher.encrypt(stored.as_bytes(), aad).map(Some),
+    }
+}
+
+fn split_stored(stored: &str) -> Option<(u32, &str)> {
+    let (version, payload) = stored.strip_prefix(ENCRYPTED_PREFIX)?.split_once(':')?;
+    Some((version.parse().ok()?, payload))
+}
+
+#[cfg(feature = "field-encryption")]
+pub use aes_gcm_cipher::AesGcmCipher;
+
+#[cfg(feature = "field-encryption")]
+mod aes_gcm_cipher {
+    use std::collections::BTreeMap;
+    use std::fmt;
+
+    use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+    use aes_gcm::{Aes256Gcm, Key, Nonce};
+    use base64::Engine;
+    use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+
+    use super::{CipherError, ENCRYPTED_PREFIX, FieldCipher, split_stored};
+
+    const NONCE_LEN: usize = 12;
+
+    /// AES-256-GCM with a random nonce per value. Keep retired keys registered until every row
+    /// has been re-encrypted with `reencrypt`.
+    #[derive(Clone)]
+    pub struct AesGcmCipher {
+        current_version: u32,
+        keys: BTreeMap<u32, Aes256Gcm>,
+    }
+
+    impl fmt::Debug for AesGcmCipher {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.debug_struct("AesGcmCipher")
+                .field("current_version", &self.current_version)
+                .field("versions", &self.keys.keys().collect::<Vec<_>>())
+                .finish()
+        }
+    }
+
+    impl AesGcmCipher {
+        pub fn new(version: u32, key: &[u8; 32]) -> Self {
+            Self {
+                current_version: version,
+                keys: BTreeMap::from([(
+                    version,
+                    Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key)),
+                )]),
+            }
+        }
+
+        /// Keep decrypting values written under an older key.
+        pub fn with_retired_key(mut self, version: u32, key: &[u8; 32]) -> Self {
+            if version != self.current_version {
+                self.keys
+                    .insert(version, Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key)));
+            }
+            self
+        }
+    }
+
+    impl FieldCipher for AesGcmCipher {
+        fn current_version(&self) -> u32 {
+            self.current_version
+        }
+
+        fn encrypt(&self, plaintext: &[u8], aad: &[u8]) -> Result<String, CipherError> {
+            let cipher = &self.keys[&self.current_version];
+            let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+            let ciphertext = cipher
+                .encrypt(
+                    &nonce,
+                    Payload {
+                        msg: plaintext,
+                        aad,
+                    },
+                )
+                .map_err(|_| CipherError::Failed)?;
+            let mut payload = nonce.to_vec();
+            payload.extend_from_slice(&ciphertext);
+            Ok(format!(
+                "{}{}:{}",
+                ENCRYPTED_PREFIX,
+                self.current_version,
+                URL_SAFE_NO_PAD.encode(payload)
+            ))
+        }
+
+        fn decrypt(&self, stored: &str, aad: &[u8]) -> Result<Vec<u8>, CipherError> {
+            let (version, payload) = split_stored(stored).ok_or(CipherError::Malformed)?;
+            let cipher = self
+                .keys
+                .get(&version)
+                .ok_or(CipherError::UnknownVersion(version))?;
+            let payload = URL_SAFE_NO_PAD
+                .decode(payload)
+                .map_err(|_| CipherError::Malformed)?;
+            if payload.len() < NONCE_LEN {
+                return Err(CipherError::Malformed);
+            }
+            let (nonce, ciphertext) = payload.split_at(NONCE_LEN);
+            cipher
+                .decrypt(
+                    Nonce::from_slice(nonce),
+                    Payload {
+                        msg: ciphertext,
+                        aad,
+                    },
+                )
+                .map_err(|_| CipherError::Failed)
+        }
+    }
+}
+
+#[cfg(all(test, feature = "field-encryption"))]
+mod tests {
+    use super::{AesGcmCipher, CipherError, FieldCipher, reencrypt, stored_version};
+
+    #[test]
+    fn round_trips_and_reencrypts() {
+        let old = AesGcmCipher::new(1, &[1; 32]);
+        let stored = old.encrypt(b"secret", b"api_keys.secret:1").unwrap();
+        assert_eq!(stored_version(&stored), Some(1));
+        assert_eq!(
+            old.decrypt(&stored, b"api_keys.secret:2"),
+            Err(CipherError::Failed)
+        );
+
+        let current = AesGcmCipher::new(2, &[2; 32]).with_retired_key(1, &[1; 32]);
+        let migrated = reencrypt(&current, &stored, b"api_keys.secret:1")
+            .unwrap()
+            .unwrap();
+        assert_eq!(stored_version(&migrated), Some(2));
+        assert_eq!(
+            current.decrypt(&migrated, b"api_keys.secret:1").unwrap(),
+            b"secret"
+        );
+        assert_eq!(
+            reencrypt(&current, &migrated, b"api_keys.secret:1"),
+            Ok(None)
+        );
+        assert_eq!(
+            AesGcmCipher::new(2, &[2; 32]).decrypt(&stored, b"api_keys.secret:1"),
+            Err(CipherError::UnknownVersion(1))
+        );
+        assert!(reencrypt(&current, "plaintext", b"").unwrap().is_some());
+    }
+}
@@ -0,0 +1,437 @@
+use std::collections::BTreeMap;
+
+use serde::Serialize;
+use serde_json::{Map, Value, json};
+use sqlx::{PgConnection, PgPool};
+use uuid::Uuid;
+
+use crate::db::{
+    GROUP_ADMIN_ROLE, RoleAssignmentTarget, apply_group_default_roles, insert_audit_log,
+    insert_role_audit_log, release_group_default_roles,
+};
+use crate::group_id::GroupId;
+use crate::user_id::UserId;
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+pub struct MappedRole {
+    pub scope: String,
+    pub scope_id: String,
+    pub role_name: String,
+}
+
+impl MappedRole {
+    pub fn new(scope: &str, scope_id: &str, role_name: &str) -> Self {
+        Self {
+            scope: scope.to_string(),
+            scope_id: scope_id.to_string(),
+            role_name: role_name.to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+pub struct MappedMembership {
+    pub group_id: GroupId,
+    pub role_name: String,
+}
+
+impl MappedMembership {
+    pub fn new(group_id: GroupId, role_name: &str) -> Self {
+        Self {
+            group_id,
+            role_name: role_name.to_string(),
+        }
+    }
+}
+
+/// Direct grants and group memberships an identity provider says the user should have.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MappedAccess {
+    pub roles: Vec<MappedRole>,
+    pub memberships: Vec<MappedMembership>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ClaimsSyncMode {
+    /// Only add what the claims map to.
+    #[default]
+    Additive,
+    /// Also remove grants and memberships this mapper added earlier that the claims no longer
+    /// map to. Access granted any other way is never touched.
+    SyncAndPrune,
+}
+
+/// Translates identity provider claims, e.g. directory groups, into roles and memberships.
+///
+/// Called on every OIDC login with the ID token's claims, and when `/auth/me` creates the user.
+/// Other providers can call `sync_claims` with their own attributes as a claims map.
+pub trait ClaimsMapper: Send + Sync {
+    /// Stable name recorded on everything the mapper adds, e.g. `okta`. Pruning only considers
+    /// rows with this name.
+    fn source(&self) -> &str;
+
+    fn mode(&self) -> ClaimsSyncMode {
+        ClaimsSyncMode::Additive
+    }
+
+    fn map_claims(&self, claims: &Map<String, Value>) -> MappedAccess;
+}
+
+/// Maps the values of one claim, a string or an array of strings such as `groups`, through a
+/// fixed table.
+#[derive(Debug, Clone)]
+pub struct ClaimValueMapper {
+    source: String,
+    claim: String,
+    mode: ClaimsSyncMode,
+    roles: BTreeMap<String, Vec<MappedRole>>,
+    memberships: BTreeMap<String, Vec<MappedMembership>>,
+}
+
+impl ClaimValueMapper {
+    pub fn new<A: Into<String>, B: Into<String>>(source: A, claim: B) -> Self {
+        Self {
+            source: source.into(),
+            claim: claim.into(),
+            mode: ClaimsSyncMode::Additive,
+            roles: BTreeMap::new(),
+            memberships: BTreeMap::new(),
+        }
+    }
+
+    pub fn with_mode(mut self, mode: ClaimsSyncMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    pub fn with_role<S: Into<String>>(mut self, value: S, role: MappedRole) -> Self {
+        self.roles.entry(value.into()).or_default().push(role);
+        self
+    }
+
+    pub fn with_membership<S: Into<String>>(
+        mut self,
+        value: S,
+        membership: MappedMembership,
+    ) -> Self {
+        self.memberships
+            .entry(value.into())
+            .or_default()
+            .push(membership);
+        self
+    }
+}
+
+impl ClaimsMapper for ClaimValueMapper {
+    fn source(&self) -> &str {
+        &self.source
+    }
+
+    fn mode(&self) -> ClaimsSyncMode {
+        self.mode
+    }
+
+    fn map_claims(&self, claims: &Map<String, Value>) -> MappedAccess {
+        let values: Vec<&str> = match claims.get(&self.claim) {
+            Some(Value::String(value)) => vec![value.as_str()],
+            Some(Value::Array(values)) => values.iter().filter_map(Value::as_str).collect(),
+            _ => Vec::new(),
+        };
+        let mut access = MappedAccess::default();
+        for value in values {
+            if let Some(roles) = self.roles.get(value) {
+                access.roles.extend(roles.iter().cloned());
+            }
+            if let Some(memberships) = self.memberships.get(value) {
+                access.memberships.extend(memberships.iter().cloned());
+            }
+        }
+        access
+    }
+}
+
+/// What `sync_claims` changed.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct ClaimsSync {
+    pub granted: Vec<MappedRole>,
+    pub revoked: Vec<MappedRole>,
+    pub joined: Vec<MappedMembership>,
+    pub left: Vec<GroupId>,
+}
+
+impl ClaimsSync {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+/// Apply what `mapper` maps `claims` to, in one transaction.
+///
+/// New grants are audited as `role_grant`/`role_revoke` and the whole change as one
+/// `claims_synced` entry. Joining a group applies its default roles and leaving releases them.
+/// Grants and memberships the user already has are left as they are, mappings to groups that do
+/// not exist are skipped, and a pruned membership is kept if the user is the group's last admin.
+/// Memberships of archived or soft-deleted groups are neither added nor pruned.
+/// Nothing happens until the user record exists and, if it awaits approval, is approved.
+pub async fn sync_claims(
+    pool: &PgPool,
+    user_id: UserId,
+    mapper: &dyn ClaimsMapper,
+    claims: &Map<String, Value>,
+) -> Result<ClaimsSync, sqlx::Error> {
+    let mut access = mapper.map_claims(claims);
+    access.roles.sort();
+    access.roles.dedup();
+    access.memberships.sort();
+    access.memberships.dedup_by(|a, b| a.group_id == b.group_id);
+    let source = mapper.source();
+
+    let mut tx = pool.begin().await?;
+    let user_exists: Option<(Uuid,)> = sqlx::query_as(
+        "SELECT id FROM auth.users WHERE id = $1 AND NOT pending_approval AND deleted_at IS NULL \
+         FOR SHARE",
+    )
+    .bind(user_id)
+    .fetch_optional(&mut *tx)
+    .await?;
+    if user_exists.is_none() {
+        return Ok(ClaimsSync::default());
+    }
+
+    let (granted, joined) = grant_access(&mut tx, None, user_id, &access, Some(source)).await?;
+    let mut sync = ClaimsSync {
+        granted,
+        joined,
+        ..ClaimsSync::default()
+    };
+
+    if mapper.mode() == ClaimsSyncMode::SyncAndPrune {
+        let revoked = sqlx::query_as::<_, (String, String, String)>(
+            r#"
+            DELETE FROM auth.user_roles
+            WHERE user_id = $1
+              AND source_provider = $2
+              AND (scope, scope_id, role_name) NOT IN (
+                  SELECT * FROM UNNEST($3::TEXT[], $4::TEXT[], $5::TEXT[])
+              )
+            RETURNING scope, scope_id, role_name
+            "#,
+        )
+        .bind(user_id)
+        .bind(source)
+        .bind(
+            access
+                .roles
+                .iter()
+                .map(|role| role.scope.as_str())
+                .collect::<Vec<_>>(),
+        )
+        .bind(
+            access
+                .roles
+                .iter()
+                .map(|role| role.scope_id.as_str())
+                .collect::<Vec<_>>(),
+        )
+        .bind(
+            access
+                .roles
+                .iter()
+                .map(|role| role.role_name.as_str())
+                .collect::<Vec<_>>(),
+        )
+        .fetch_all(&mut *tx)
+        .await?;
+        for (scope, scope_id, role_name) in revoked {
+            insert_role_audit_log(
+                &mut tx,
+                None,
+                "role_revoke",
+                RoleAssignmentTarget::User(user_id),
+                &scope,
+                &scope_id,
+                &role_name,
+                None,
+            )
+            .await?;
+            sync.revoked.push(MappedRole {
+                scope,
+                scope_id,
+                role_name,
+            });
+        }
+
+        let left = sqlx::query_as::<_, (GroupId,)>(
+            r#"
+            DELETE FROM auth.group_memberships gm
+            WHERE gm.user_id = $1
+              AND gm.source_provider = $2
+              AND gm.group_id <> ALL($3::UUID[])
+              AND NOT EXISTS (
+                  SELECT 1
+                  FROM auth.groups g
+                  WHERE g.id = gm.group_id
+                    AND (g.archived_at IS NOT NULL OR g.deleted_at IS NOT NULL)
+              )
+              AND NOT (
+                  gm.role_name = $4
+                  AND NOT EXISTS (
+                      SELECT 1
+                      FROM auth.group_memberships other
+                      WHERE other.group_id = gm.group_id
+                        AND other.user_id <> gm.user_id
+                        AND other.role_name = $4
+                  )
+              )
+            RETURNING gm.group_id
+            "#,
+        )
+        .bind(user_id)
+        .bind(source)
+        .bind(
+            access
+                .memberships
+                .iter()
+                .map(|membership| membership.group_id)
+                .collect::<Vec<_>>(),
+        )
+        .bind(GROUP_ADMIN_ROLE)
+        .fetch_all(&mut *tx)
+        .await?;
+        for (group_id,) in left {
+            release_group_default_roles(&mut tx, None, group_id, Some(user_id), None).await?;
+            sync.left.push(group_id);
+        }
+    }
+
+    if !sync.is_empty() {
+        insert_audit_log(
+            &mut *tx,
+            Some(user_id),
+            json!({
+                "type": "claims_synced",
+                "source": source,
+                "granted": sync.granted,
+                "revoked": sync.revoked,
+                "joined": sync.joined,
+                "left": sync.left,
+            }),
+        )
+        .await?;
+    }
+    tx.commit().await?;
+    Ok(sync)
+}
+
+/// Grant `access` to the user, marking new rows with `source`. Grants and memberships the user
+/// already has are left alone and groups that do not exist, are archived or are deleted are
+/// skipped. Returns what was added.
+pub(crate) async fn grant_access(
+    conn: &mut PgConnection,
+    actor_user_id: Option<UserId>,
+    user_id: UserId,
+    access: &MappedAccess,
+    source: Option<&str>,
+) -> Result<(Vec<MappedRole>, Vec<MappedMembership>), sqlx::Error> {
+    let mut granted = Vec::new();
+    let mut joined = Vec::new();
+    for role in access.roles.iter() {
+        let inserted = sqlx::query(
+            r#"
+            INSERT INTO auth.user_roles (user_id, scope, scope_id, role_name, source_provider)
+            VALUES ($1, $2, $3, $4, $5)
+            ON CONFLICT DO NOTHING
+            "#,
+        )
+        .bind(user_id)
+        .bind(&role.scope)
+        .bind(&role.scope_id)
+        .bind(&role.role_name)
+        .bind(source)
+        .execute(&mut *conn)
+        .await?
+        .rows_affected()
+            > 0;
+        if inserted {
+            insert_role_audit_log(
+                &mut *conn,
+                actor_user_id,
+                "role_grant",
+                RoleAssignmentTarget::User(user_id),
+                &role.scope,
+                &role.scope_id,
+                &role.role_name,
+                None,
+            )
+            .await?;
+            granted.push(role.clone());
+        }
+    }
+
+    for membership in access.memberships.iter() {
+        let inserted = sqlx::query(
+            r#"
+            INSERT INTO auth.group_memberships (group_id, user_id, role_name, source_provider)
+            SELECT g.id, $2, $3, $4
+            FROM auth.groups g
+            WHERE g.id = $1
+              AND g.archived_at IS NULL
+              AND g.deleted_at IS NULL
+            ON CONFLICT DO NOTHING
+            "#,
+        )
+        .bind(membership.group_id)
+        .bind(user_id)
+        .bind(&membership.role_name)
+        .bind(source)
+        .execute(&mut *conn)
+        .await?
+        .rows_affected()
+            > 0;
+        if inserted {
+            apply_group_default_roles(
+                &mut *conn,
+                actor_user_id,
+                membership.group_id,
+                Some(user_id),
+                None,
+            )
+            .await?;
+            joined.push(membership.clone());
+        }
+    }
+    Ok((granted, joined))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use uuid::Uuid;
+
+    use super::{ClaimValueMapper, ClaimsMapper, MappedMembership, MappedRole};
+    use crate::group_id::GroupId;
+
+    #[test]
+    fn maps_claim_values() {
+        let engineering = GroupId(Uuid::new_v4());
+        let mapper = ClaimValueMapper::new("okta", "groups")
+            .with_role(
+                "apollo-editors",
+                MappedRole::new("project", "apollo", "editor"),
+            )
+            .with_membership("eng", MappedMembership::new(engineering, "member"));
+
+        let claims = json!({"groups": ["eng", "sales"]});
+        let access = mapper.map_claims(claims.as_object().unwrap());
+        assert!(access.roles.is_empty());
+        assert_eq!(
+            access.memberships,
+            vec![MappedMembership::new(engineering, "member")]
+        );
+
+        let claims = json!({"groups": "apollo-editors"});
+        let access = mapper.map_claims(claims.as_object().unwrap());
+        assert_eq!(access.roles.len(), 1);
+        assert!(access.memberships.is_empty());
+    }
+}
@@ -0,0 +1,553 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use chrono::NaiveDateTime;
+use once_cell::sync::Lazy;
+use openidconnect::CsrfToken;
+use serde::Serialize;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use sqlx::{FromRow, PgPool};
+use subtle::ConstantTimeEq;
+use url::Url;
+
+use crate::db::insert_audit_log;
+use crate::group_id::GroupId;
+use crate::user_id::UserId;
+
+/// Random bytes in client secrets, before base64 encoding.
+const SECRET_BYTES: u32 = 32;
+
+/// Length of a `ClientRateLimiter` window.
+const RATE_LIMIT_WINDOW: Duration = Duration::from_secs(60);
+
+/// Limiter used by `AuthApp::client_rate_limiter` unless the app overrides it.
+pub static DEFAULT_CLIENT_RATE_LIMITER: Lazy<ClientRateLimiter> =
+    Lazy::new(ClientRateLimiter::default);
+
+macro_rules! client_columns {
+    () => {
+        "client_id, name, redirect_uris, allowed_scopes, secret_hash IS NOT NULL AS confidential, trusted, owner_group_id, rate_limit_per_minute, created_at, updated_at, disabled_at"
+    };
+}
+
+/// An application registered with this service. Tokens issued to it carry its `client_id`.
+#[derive(Debug, Clone, PartialEq, Eq, FromRow, Serialize)]
+pub struct Client {
+    pub client_id: String,
+    pub name: String,
+    /// Redirect URIs must match one of these exactly.
+    pub redirect_uris: Vec<String>,
+    /// Scopes the client may request; anything else is refused.
+    pub allowed_scopes: Vec<String>,
+    /// Whether the client has a secret. Public clients authenticate with PKCE alone.
+    pub confidential: bool,
+    /// Trusted clients skip the consent step.
+    pub trusted: bool,
+    /// Group responsible for the application.
+    pub owner_group_id: Option<GroupId>,
+    /// Token requests allowed per minute. `None` is unlimited.
+    pub rate_limit_per_minute: Option<i32>,
+    pub created_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+    pub disabled_at: Option<NaiveDateTime>,
+}
+
+impl Client {
+    /// A client, whether or not it is disabled.
+    pub async fn get(pool: &PgPool, client_id: &str) -> Result<Option<Self>, sqlx::Error> {
+        sqlx::query_as::<_, Client>(concat!(
+            "SELECT ",
+            client_columns!(),
+            r#"
+            FROM auth.clients
+            WHERE client_id = $1
+            "#,
+        ))
+        .bind(client_id)
+        .fetch_optional(pool)
+        .await
+    }
+
+    /// An enabled client.
+    pub async fn get_enabled(pool: &PgPool, client_id: &str) -> Result<Option<Self>, sqlx::Error> {
+        Ok(Self::get(pool, client_id)
+            .await?
+            .filter(|client| client.disabled_at.is_none()))
+    }
+
+    pub async fn list(pool: &PgPool) -> Result<Vec<Self>, sqlx::Error> {
+        sqlx::query_as::<_, Client>(concat!(
+            "SELECT ",
+            client_columns!(),
+            r#"
+            FROM auth.clients
+            ORDER BY client_id ASC
+            "#,
+        ))
+        .fetch_all(pool)
+        .await
+    }
+
+    pub fn allows_redirect(&self, redirect_uri: &str) -> bool {
+        self.redirect_uris.iter().any(|uri| uri == redirect_uri)
+    }
+
+    pub fn allows_scope(&self, scope: &str) -> bool {
+        self.allowed_scopes.iter().any(|allowed| allowed == scope)
+    }
+
+    /// Replace the client's settings and log `client_updated`. Returns `None` if there is no
+    /// such client.
+    pub async fn update(
+        pool: &PgPool,
+        actor_user_id: UserId,
+        client_id: &str,
+        update: &ClientUpdate,
+    ) -> Result<Option<Self>, ClientError> {
+        let fields = ClientFields::validate(
+            &update.name,
+            &update.redirect_uris,
+            &update.allowed_scopes,
+            update.rate_limit_per_minute,
+        )?;
+        let mut tx = pool.begin().await?;
+        let client = sqlx::query_as::<_, Client>(concat!(
+            r#"
+            UPDATE auth.clients
+            SET name = $2,
+                redirect_uris = $3,
+                allowed_scopes = $4,
+                trusted = $5,
+                owner_group_id = $6,
+                rate_limit_per_minute = $7,
+                updated_at = $8
+            WHERE client_id = $1
+            RETURNING "#,
+            client_columns!(),
+        ))
+        .bind(client_id)
+        .bind(&fields.name)
+        .bind(&fields.redirect_uris)
+        .bind(&fields.allowed_scopes)
+        .bind(update.trusted)
+        .bind(update.owner_group_id)
+        .bind(update.rate_limit_per_minute)
+        .bind(chrono::Utc::now().naive_utc())
+        .fetch_optional(&mut *tx)
+        .await
+        .map_err(ClientError::from_write)?;
+        if let Some(client) = &client {
+            insert_audit_log(
+                &mut *tx,
+                Some(actor_user_id),
+                json!({
+                    "type": "client_updated",
+                    "client_id": client.client_id,
+                    "allowed_scopes": client.allowed_scopes,
+                    "trusted": client.trusted,
+                    "rate_limit_per_minute": client.rate_limit_per_minute,
+                }),
+            )
+            .await?;
+        }
+        tx.commit().await?;
+        Ok(client)
+    }
+
+    /// Replace a confidential client's secret and log `client_secret_rotated`. The old secret
+    /// stops working at once. Returns `None` for unknown, disabled or public clients.
+    pub async fn rotate_secret(
+        pool: &PgPool,
+        actor_user_id: UserId,
+        client_id: &str,
+    ) -> Result<Option<RegisteredClient>, sqlx::Error> {
+        let client_secret = random_token();
+        let mut tx = pool.begin().await?;
+        let client = sqlx::query_as::<_, Client>(concat!(
+            r#"
+            UPDATE auth.clients
+            SET secret_hash = $2,
+                updated_at = $3
+            WHERE client_id = $1
+              AND secret_hash IS NOT NULL
+              AND disabled_at IS NULL
+            RETURNING "#,
+            client_columns!(),
+        ))
+        .bind(client_id)
+        .bind(secret_hash(&client_secret))
+        .bind(chrono::Utc::now().naive_utc())
+        .fetch_optional(&mut *tx)
+        .await?;
+        if client.is_some() {
+            insert_audit_log(
+                &mut *tx,
+                Some(actor_user_id),
+                json!({
+                    "type": "client_secret_rotated",
+                    "client_id": client_id,
+                }),
+            )
+            .await?;
+        }
+        tx.commit().await?;
+        Ok(client.map(|client| RegisteredClient {
+            client,
+            client_secret: Some(client_secret),
+        }))
+    }
+
+    /// Stop issuing codes and tokens to the client. Tokens already issued stay valid until they
+    /// expire. Returns `false` if there was no enabled client.
+    pub async fn disable(
+        pool: &PgPool,
+        actor_user_id: UserId,
+        client_id: &str,
+    ) -> Result<bool, sqlx::Error> {
+        let mut tx = pool.begin().await?;
+        let now = chrono::Utc::now().naive_utc();
+        let disabled = sqlx::query(
+            r#"
+            UPDATE auth.clients
+            SET disabled_at = $2,
+                updated_at = $2
+            WHERE client_id = $1
+              AND disabled_at IS NULL
+            "#,
+        )
+        .bind(client_id)
+        .bind(now)
+        .execute(&mut *tx)
+        .await?
+        .rows_affected()
+            > 0;
+        if disabled {
+            insert_audit_log(
+                &mut *tx,
+                Some(actor_user_id),
+                json!({
+                    "type": "client_disabled",
+                    "client_id": client_id,
+                }),
+            )
+            .await?;
+        }
+        tx.commit().await?;
+        Ok(disabled)
+    }
+
+    /// The enabled client, if `client_secret` is its secret. Public clients need no secret and
+    /// must prove themselves some other way, e.g. with PKCE.
+    pub async fn authenticate(
+        pool: &PgPool,
+        client_id: &str,
+        client_secret: Option<&str>,
+    ) -> Result<Option<Self>, sqlx::Error> {
+        let stored: Option<(Option<String>,)> = sqlx::query_as(
+            r#"
+            SELECT secret_hash
+            FROM auth.clients
+            WHERE client_id = $1
+              AND disabled_at IS NULL
+            "#,
+        )
+        .bind(client_id)
+        .fetch_optional(pool)
+        .await?;
+        let authenticated = match stored {
+            None => false,
+            Some((None,)) => true,
+            Some((Some(hash),)) => {
+                client_secret.is_some_and(|secret| secret_matches(secret, &hash))
+            }
+        };
+        if !authenticated {
+            return Ok(None);
+        }
+        Self::get_enabled(pool, client_id).await
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NewClient {
+    pub client_id: String,
+    pub name: String,
+    pub redirect_uris: Vec<String>,
+    pub allowed_scopes: Vec<String>,
+    /// Issue a client secret. Leave off for apps that cannot keep one, e.g. single-page apps.
+    pub confidential: bool,
+    pub trusted: bool,
+    pub owner_group_id: Option<GroupId>,
+    pub rate_limit_per_minute: Option<i32>,
+}
+
+/// New settings for a client. Whether it is confidential cannot change.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientUpdate {
+    pub name: String,
+    pub redirect_uris: Vec<String>,
+    pub allowed_scopes: Vec<String>,
+    pub trusted: bool,
+    pub owner_group_id: Option<GroupId>,
+    pub rate_limit_per_minute: Option<i32>,
+}
+
+/// A client with its new secret. The secret is not stored and cannot be recovered.
+#[derive(Clone, Serialize)]
+pub struct RegisteredClient {
+    pub client: Client,
+    pub client_secret: Option<String>,
+}
+
+impl fmt::Debug for RegisteredClient {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RegisteredClient")
+            .field("client", &self.client)
+            .field(
+                "client_secret",
+                &self.client_secret.as_ref().map(|_| "[redacted]"),
+            )
+            .finish()
+    }
+}
+
+#[derive(Debug)]
+pub enum ClientError {
+    /// The settings are unusable; the message says why.
+    Invalid(String),
+    /// A client with the same `client_id` is already registered.
+    Conflict,
+    /// `owner_group_id` does not name a group.
+    UnknownGroup,
+    Database(sqlx::Error),
+}
+
+impl ClientError {
+    fn from_write(err: sqlx::Error) -> Self {
+        match err {
+            sqlx::Error::Database(db_err) if db_err.is_unique_violation() => Self::Conflict,
+            sqlx::Error::Database(db_err) if db_err.is_foreign_key_violation() => {
+                Self::UnknownGroup
+            }
+            err => Self::Database(err),
+        }
+    }
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Invalid(reason) => write!(f, "{}", reason),
+            Self::Conflict => write!(f, "Client already exists"),
+            Self::UnknownGroup => write!(f, "Owner group not found"),
+            Self::Database(err) => write!(f, "Database error: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {}
+
+impl From<sqlx::Error> for ClientError {
+    fn from(err: sqlx::Error) -> Self {
+        Self::Database(err)
+    }
+}
+
+/// Trimmed settings shared by registration and updates.
+struct ClientFields {
+    name: String,
+    redirect_uris: Vec<String>,
+    allowed_scopes: Vec<String>,
+}
+
+impl ClientFields {
+    fn validate(
+        name: &str,
+        redirect_uris: &[String],
+        allowed_scopes: &[String],
+        rate_limit_per_minute: Option<i32>,
+    ) -> Result<Self, ClientError> {
+        let invalid = |reason: &str| ClientError::Invalid(reason.to_string());
+        let name = name.trim();
+        if name.is_empty() {
+            return Err(invalid("Client name must not be empty"));
+        }
+        if redirect_uris.iter().any(|uri| Url::parse(uri).is_err()) {
+            return Err(invalid("Redirect URIs must be absolute URLs"));
+        }
+        let mut scopes: Vec<String> = allowed_scopes
+            .iter()
+            .map(|scope| scope.trim().to_string())
+            .collect();
+        if scopes
+            .iter()
+            .any(|scope| scope.is_empty() || scope.contains(char::is_whitespace))
+        {
+            return Err(invalid("Scopes must be single words"));
+        }
+        scopes.sort();
+        scopes.dedup();
+        if rate_limit_per_minute.is_some_and(|limit| limit <= 0) {
+            return Err(invalid("Rate limit must be positive"));
+        }
+        Ok(Self {
+            name: name.to_string(),
+            redirect_uris: redirect_uris.to_vec(),
+            allowed_scopes: scopes,
+        })
+    }
+}
+
+/// Register a client and log `client_registered`.
+pub async fn register_client(
+    pool: &PgPool,
+    actor_user_id: UserId,
+    new: &NewClient,
+) -> Result<RegisteredClient, ClientError> {
+    let client_id = new.client_id.trim();
+    if client_id.is_empty() || client_id.contains(char::is_whitespace) {
+        return Err(ClientError::Invalid(
+            "Client ID must be a single word".to_string(),
+        ));
+    }
+    let fields = ClientFields::validate(
+        &new.name,
+        &new.redirect_uris,
+        &new.allowed_scopes,
+        new.rate_limit_per_minute,
+    )?;
+    let client_secret = new.confidential.then(random_token);
+    let mut tx = pool.begin().await?;
+    let client = sqlx::query_as::<_, Client>(concat!(
+        r#"
+        INSERT INTO auth.clients
+            (client_id, name, redirect_uris, allowed_scopes, secret_hash, trusted, owner_group_id,
+             rate_limit_per_minute)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+        RETURNING "#,
+        client_columns!(),
+    ))
+    .bind(client_id)
+    .bind(&fields.name)
+    .bind(&fields.redirect_uris)
+    .bind(&fields.allowed_scopes)
+    .bind(client_secret.as_deref().map(secret_hash))
+    .bind(new.trusted)
+    .bind(new.owner_group_id)
+    .bind(new.rate_limit_per_minute)
+    .fetch_one(&mut *tx)
+    .await
+    .map_err(ClientError::from_write)?;
+    insert_audit_log(
+        &mut *tx,
+        Some(actor_user_id),
+        json!({
+            "type": "client_registered",
+            "client_id": client.client_id,
+            "allowed_scopes": client.allowed_scopes,
+            "trusted": client.trusted,
+            "owner_group_id": client.owner_group_id,
+        }),
+    )
+    .await?;
+    tx.commit().await?;
+    Ok(RegisteredClient {
+        client,
+        client_secret,
+    })
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Window {
+    started: Instant,
+    count: u32,
+}
+
+/// Per-client request counts in one-minute windows, checked against
+/// `Client::rate_limit_per_minute`. Counts live in memory, so each instance of the service
+/// enforces the limit on its own.
+#[derive(Debug, Default)]
+pub struct ClientRateLimiter {
+    windows: Mutex<HashMap<String, Window>>,
+}
+
+impl ClientRateLimiter {
+    /// Count a request by `client`. Fails with the time until the client may try again once it
+    /// is over its limit.
+    pub fn check(&self, client: &Client) -> Result<(), Duration> {
+        match client.rate_limit_per_minute {
+            Some(limit) => self.check_at(&client.client_id, limit.max(0) as u32, Instant::now()),
+            None => Ok(()),
+        }
+    }
+
+    fn check_at(&self, client_id: &str, limit: u32, now: Instant) -> Result<(), Duration> {
+        let mut windows = self.windows.lock().unwrap_or_else(|err| err.into_inner());
+        let window = windows.entry(client_id.to_string()).or_insert(Window {
+            started: now,
+            count: 0,
+        });
+        let elapsed = now.saturating_duration_since(window.started);
+        if elapsed >= RATE_LIMIT_WINDOW {
+            *window = Window {
+                started: now,
+                count: 0,
+            };
+        } else if window.count >= limit {
+            return Err(RATE_LIMIT_WINDOW - elapsed);
+        }
+        window.count += 1;
+        Ok(())
+    }
+}
+
+pub(crate) fn random_token() -> String {
+    CsrfToken::new_random_len(SECRET_BYTES).secret().clone()
+}
+
+pub(crate) fn secret_hash(secret: &str) -> String {
+    URL_SAFE_NO_PAD.encode(Sha256::digest(secret.as_bytes()))
+}
+
+/// Whether `secret` hashes to `hash`, compared in constant time.
+pub(crate) fn secret_matches(secret: &str, hash: &str) -> bool {
+    secret_hash(secret).as_bytes().ct_eq(hash.as_bytes()).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::{ClientRateLimiter, secret_hash, secret_matches};
+
+    #[test]
+    fn limits_each_client_per_window() {
+        let limiter = ClientRateLimiter::default();
+        let start = Instant::now();
+        assert!(limiter.check_at("web", 2, start).is_ok());
+        assert!(limiter.check_at("web", 2, start).is_ok());
+        assert_eq!(
+            limiter.check_at("web", 2, start + Duration::from_secs(15)),
+            Err(Duration::from_secs(45))
+        );
+        assert!(limiter.check_at("cli", 2, start).is_ok());
+        assert!(
+            limiter
+                .check_at("web", 2, start + Duration::from_secs(60))
+                .is_ok()
+        );
+    }
+
+    #[test]
+    fn matches_secrets_against_their_hash() {
+        let hash = secret_hash("s3cret");
+        assert!(secret_matches("s3cret", &hash));
+        assert!(!secret_matches("s3cret!", &hash));
+        assert!(!secret_matches("s3cret", ""));
+    }
+}
@@ -0,0 +1,852 @@
+use std::fmt;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+use anyhow::Result as AnyResult;
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+use crate::environment::{Environment, is_valid_environment_name};
+use crate::ids::UuidVersion;
+use crate::oidc::{AllowedOtherAudiences, Any, IdentityProvider, OidcCredentials};
+use crate::redact::RedactionMode;
+use crate::user_id::UserId;
+use crate::workload::WorkloadJwtValidator;
+
+/// Prefix of every environment variable read by `AuthConfig::from_env`.
+pub const ENV_PREFIX: &str = "AUTH_";
+
+/// Everything needed to stand up the auth service, in one place.
+///
+/// Load it with `from_env`, or deserialize it from whatever the app already uses (TOML, figment,
+/// JSON); every field except the database URL has a default. Call `validate` before use.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    pub database: DatabaseConfig,
+    pub session: SessionConfig,
+    /// Browser login. Without it only workload tokens and sessions can authenticate.
+    pub oidc: Option<OidcConfig>,
+    /// Service-to-service JWTs.
+    pub workload: Option<WorkloadConfig>,
+    /// Applied with `redact::set_redaction_mode` at startup.
+    pub redaction: RedactionMode,
+    /// Applied with `ids::set_uuid_version` at startup.
+    pub uuid_version: UuidVersion,
+    /// Passed to `api::routes_with_registration`.
+    pub registration: RegistrationMode,
+    /// How requests are authenticated, for `identity::IdentityValidator::from_config`.
+    pub identity: IdentityConfig,
+    /// First admin of a fresh deployment, for `bootstrap::bootstrap`.
+    pub bootstrap_admin: Option<BootstrapAdminConfig>,
+    /// Applied with `environment::set_environment` at startup.
+    pub environment: Option<Environment>,
+}
+
+/// Pool settings. Unset fields keep the `db::DbConfig` defaults.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DatabaseConfig {
+    pub url: String,
+    pub application_name: Option<String>,
+    pub max_connections: Option<u32>,
+    pub min_connections: Option<u32>,
+    pub acquire_timeout_secs: Option<u64>,
+    pub statement_timeout_ms: Option<u64>,
+    pub ssl_root_cert: Option<PathBuf>,
+    pub required_extensions: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SameSitePolicy {
+    Strict,
+    /// Needed for the session cookie to survive the redirect back from the identity provider.
+    #[default]
+    Lax,
+    None,
+}
+
+impl From<SameSitePolicy> for cookie::SameSite {
+    fn from(policy: SameSitePolicy) -> Self {
+        match policy {
+            SameSitePolicy::Strict => cookie::SameSite::Strict,
+            SameSitePolicy::Lax => cookie::SameSite::Lax,
+            SameSitePolicy::None => cookie::SameSite::None,
+        }
+    }
+}
+
+impl FromStr for SameSitePolicy {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_ascii_lowercase().as_str() {
+            "strict" => Ok(SameSitePolicy::Strict),
+            "lax" => Ok(SameSitePolicy::Lax),
+            "none" => Ok(SameSitePolicy::None),
+            _ => Err("expected strict, lax or none".to_string()),
+        }
+    }
+}
+
+/// Who can create an account.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RegistrationMode {
+    /// Anyone who signs in gets an account, subject to `AuthApp::provisioning_policy`.
+    #[default]
+    Open,
+    /// Accounts are only created by accepting an invitation from `/auth/admin/invitations`.
+    InvitationOnly,
+}
+
+impl FromStr for RegistrationMode {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_ascii_lowercase().as_str() {
+            "open" => Ok(RegistrationMode::Open),
+            "invitation_only" => Ok(RegistrationMode::InvitationOnly),
+            _ => Err("expected open or invitation_only".to_string()),
+        }
+    }
+}
+
+/// Which `identity` validator authenticates requests.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IdentityMode {
+    /// OIDC ID tokens as bearer tokens and sessions from the browser login.
+    #[default]
+    Oidc,
+    /// Sessions from the browser login; bearer tokens are refused.
+    SessionOnly,
+    /// Bearer JWTs signed with the keys in `jwt_keys_file`.
+    Jwt,
+    /// Identity headers from an authenticating proxy.
+    TrustedHeader,
+    /// Bearer API keys listed in `api_keys_file`.
+    ApiKey,
+}
+
+impl fmt::Display for IdentityMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            IdentityMode::Oidc => "oidc",
+            IdentityMode::SessionOnly => "session_only",
+            IdentityMode::Jwt => "jwt",
+            IdentityMode::TrustedHeader => "trusted_header",
+            IdentityMode::ApiKey => "api_key",
+        };
+        f.write_str(name)
+    }
+}
+
+impl FromStr for IdentityMode {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_ascii_lowercase().as_str() {
+            "oidc" => Ok(IdentityMode::Oidc),
+            "session_only" => Ok(IdentityMode::SessionOnly),
+            "jwt" => Ok(IdentityMode::Jwt),
+            "trusted_header" => Ok(IdentityMode::TrustedHeader),
+            "api_key" => Ok(IdentityMode::ApiKey),
+            _ => Err("expected oidc, session_only, jwt, trusted_header or api_key".to_string()),
+        }
+    }
+}
+
+/// The identity mode and the settings of the non-OIDC modes. The OIDC modes use the `oidc`
+/// section, which is only required once the validator is built, so configs for workload-only
+/// services stay valid.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct IdentityConfig {
+    pub mode: IdentityMode,
+    /// `iss` of accepted JWTs.
+    pub jwt_issuer: Option<String>,
+    /// `aud` accepted JWTs must include.
+    pub jwt_audience: Option<String>,
+    /// Keys in the `keys::parse_keys` format; the first one signs.
+    pub jwt_keys_file: Option<PathBuf>,
+    /// Keys in the `identity::ApiKeyIdentity::parse` format.
+    pub api_keys_file: Option<PathBuf>,
+    /// Defaults to `trusted_proxy::DEFAULT_EMAIL_HEADER`.
+    pub proxy_email_header: Option<String>,
+    /// Header the proxy puts `proxy_secret` in.
+    pub proxy_secret_header: Option<String>,
+    pub proxy_secret: Option<String>,
+    /// Addresses the proxy connects from, as CIDRs.
+    pub proxy_peers: Vec<String>,
+}
+
+/// Who `bootstrap::bootstrap` makes super admin while nobody is.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BootstrapAdminConfig {
+    pub email: String,
+    /// The admin's identity provider subject. With it the user is created right away; without
+    /// it, and with no user registered under `email`, a one-time invitation link is issued.
+    pub user_id: Option<UserId>,
+    pub invitation_ttl_secs: u64,
+    /// Replace an outstanding bootstrap invitation with a new one, e.g. when its link was lost.
+    pub reissue_invitation: bool,
+}
+
+impl Default for BootstrapAdminConfig {
+    fn default() -> Self {
+        Self {
+            email: String::new(),
+            user_id: None,
+            invitation_ttl_secs: 24 * 60 * 60,
+            reissue_invitation: false,
+        }
+    }
+}
+
+/// Session cookie settings used by `api::routes_with_config`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SessionConfig {
+    /// Cookie name; `None` keeps the tower-sessions default.
+    pub cookie_name: Option<String>,
+    pub secure: bool,
+    pub same_site: SameSitePolicy,
+    /// Sessions expire after this long without a request.
+    pub inactivity_secs: u64,
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        Self {
+            cookie_name: None,
+            secure: false,
+            same_site: SameSitePolicy::Lax,
+            inactivity_secs: 24 * 60 * 60,
+        }
+    }
+}
+
+/// Identity provider and client registration. Without a client secret the client is a
+/// verification-only public client.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OidcConfig {
+    pub client_id: String,
+    pub client_secret: Option<String>,
+    pub base_url: String,
+    pub redirect_url: String,
+    pub idp_url: String,
+    /// Extra audiences accepted on ID tokens; `["*"]` accepts any.
+    pub allowed_audiences: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WorkloadConfig {
+    pub allowed_issuers: Vec<String>,
+    pub clock_skew_secs: u64,
+    pub jwks_cache_secs: u64,
+}
+
+impl Default for WorkloadConfig {
+    fn default() -> Self {
+        Self {
+            allowed_issuers: Vec::new(),
+            clock_skew_secs: 60,
+            jwks_cache_secs: 300,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigError {
+    /// A required setting is absent.
+    Missing(String),
+    Invalid {
+        key: String,
+        message: String,
+    },
+}
+
+impl ConfigError {
+    fn invalid(key: &str, message: impl Into<String>) -> Self {
+        ConfigError::Invalid {
+            key: key.to_string(),
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Missing(key) => write!(f, "Missing setting: {}", key),
+            ConfigError::Invalid { key, message } => {
+                write!(f, "Invalid setting {}: {}", key, message)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl AuthConfig {
+    /// Read `AUTH_*` environment variables and validate the result.
+    ///
+    /// The database URL falls back to `DATABASE_URL`. The OIDC section is read when
+    /// `AUTH_OIDC_CLIENT_ID` is set, the workload section when `AUTH_WORKLOAD_ISSUERS` is and the
+    /// bootstrap admin when `AUTH_BOOTSTRAP_ADMIN_EMAIL` is and the environment when
+    /// `AUTH_ENVIRONMENT` is; lists are comma-separated.
+    pub fn from_env() -> Result<Self, ConfigError> {
+        Self::from_lookup(|name| std::env::var(name).ok())
+    }
+
+    /// `from_env` against any source of variables, e.g. a map in tests.
+    pub fn from_lookup<F>(lookup: F) -> Result<Self, ConfigError>
+    where
+        F: Fn(&str) -> Option<String>,
+    {
+        let env = Env { lookup };
+        let database = DatabaseConfig {
+            url: env
+                .string("DATABASE_URL")
+                .or_else(|| (env.lookup)("DATABASE_URL"))
+                .unwrap_or_default(),
+            application_name: env.string("DATABASE_APPLICATION_NAME"),
+            max_connections: env.parse("DATABASE_MAX_CONNECTIONS")?,
+            min_connections: env.parse("DATABASE_MIN_CONNECTIONS")?,
+            acquire_timeout_secs: env.parse("DATABASE_ACQUIRE_TIMEOUT_SECS")?,
+            statement_timeout_ms: env.parse("DATABASE_STATEMENT_TIMEOUT_MS")?,
+            ssl_root_cert: env.string("DATABASE_SSL_ROOT_CERT").map(PathBuf::from),
+            required_extensions: env.list("DATABASE_REQUIRED_EXTENSIONS"),
+        };
+
+        let defaults = SessionConfig::default();
+        let session = SessionConfig {
+            cookie_name: env.string("SESSION_COOKIE_NAME"),
+            secure: env.parse("SESSION_SECURE")?.unwrap_or(defaults.secure),
+            same_site: env
+                .parse("SESSION_SAME_SITE")?
+                .unwrap_or(defaults.same_site),
+            inactivity_secs: env
+                .parse("SESSION_INACTIVITY_SECS")?
+                .unwrap_or(defaults.inactivity_secs),
+        };
+
+        let oidc = match env.string("OIDC_CLIENT_ID") {
+            Some(client_id) => Some(OidcConfig {
+                client_id,
+                client_secret: env.string("OIDC_CLIENT_SECRET"),
+                base_url: env.required("OIDC_BASE_URL")?,
+                redirect_url: env.required("OIDC_REDIRECT_URL")?,
+                idp_url: env.required("OIDC_IDP_URL")?,
+                allowed_audiences: env.list("OIDC_ALLOWED_AUDIENCES"),
+            }),
+            None => None,
+        };
+
+        let workload = match env.string("WORKLOAD_ISSUERS") {
+            Some(_) => {
+                let defaults = WorkloadConfig::default();
+                Some(WorkloadConfig {
+                    allowed_issuers: env.list("WORKLOAD_ISSUERS"),
+                    clock_skew_secs: env
+                        .parse("WORKLOAD_CLOCK_SKEW_SECS")?
+                        .unwrap_or(defaults.clock_skew_secs),
+                    jwks_cache_secs: env
+                        .parse("WORKLOAD_JWKS_CACHE_SECS")?
+                        .unwrap_or(defaults.jwks_cache_secs),
+                })
+            }
+            None => None,
+        };
+
+        let bootstrap_admin = match env.string("BOOTSTRAP_ADMIN_EMAIL") {
+            Some(email) => {
+                let defaults = BootstrapAdminConfig::default();
+                Some(BootstrapAdminConfig {
+                    email,
+                    user_id: env.parse("BOOTSTRAP_ADMIN_USER_ID")?,
+                    invitation_ttl_secs: env
+                        .parse("BOOTSTRAP_ADMIN_INVITATION_TTL_SECS")?
+                        .unwrap_or(defaults.invitation_ttl_secs),
+                    reissue_invitation: env
+                        .parse("BOOTSTRAP_ADMIN_REISSUE_INVITATION")?
+                        .unwrap_or(defaults.reissue_invitation),
+                })
+            }
+            None => None,
+        };
+
+        let environment = match env.string("ENVIRONMENT") {
+            Some(name) => Some(Environment {
+                name,
+                mode: env.parse("ENVIRONMENT_MODE")?.unwrap_or_default(),
+            }),
+            None => None,
+        };
+
+        let config = AuthConfig {
+            database,
+            session,
+            oidc,
+            workload,
+            redaction: env.parse("REDACTION")?.unwrap_or_default(),
+            uuid_version: env.parse("UUID_VERSION")?.unwrap_or_default(),
+            registration: env.parse("REGISTRATION")?.unwrap_or_default(),
+            identity: IdentityConfig {
+                mode: env.parse("IDENTITY")?.unwrap_or_default(),
+                jwt_issuer: env.string("JWT_ISSUER"),
+                jwt_audience: env.string("JWT_AUDIENCE"),
+                jwt_keys_file: env.string("JWT_KEYS_FILE").map(PathBuf::from),
+                api_keys_file: env.string("API_KEYS_FILE").map(PathBuf::from),
+                proxy_email_header: env.string("PROXY_EMAIL_HEADER"),
+                proxy_secret_header: env.string("PROXY_SECRET_HEADER"),
+                proxy_secret: env.string("PROXY_SECRET"),
+                proxy_peers: env.list("PROXY_PEERS"),
+            },
+            bootstrap_admin,
+            environment,
+        };
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Check settings that would otherwise fail later, at connect time or in the browser.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        self.database.validate()?;
+        self.session.validate()?;
+        if let Some(oidc) = &self.oidc {
+            oidc.validate()?;
+        }
+        if let Some(workload) = &self.workload {
+            workload.validate()?;
+        }
+        self.identity.validate()?;
+        if let Some(bootstrap_admin) = &self.bootstrap_admin {
+            bootstrap_admin.validate()?;
+        }
+        if let Some(environment) = &self.environment
+            && !is_valid_environment_name(&environment.name)
+        {
+            return Err(ConfigError::invalid(
+                "environment.name",
+                "may only use letters, digits, - and _",
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl DatabaseConfig {
+    fn validate(&self) -> Result<(), ConfigError> {
+        if self.url.trim().is_empty() {
+            return Err(ConfigError::Missing("database.url".to_string()));
+        }
+        if self.max_connections == Some(0) {
+            return Err(ConfigError::invalid(
+                "database.max_connections",
+                "must be at least 1",
+            ));
+        }
+        if let (Some(min), Some(max)) = (self.min_connections, self.max_connections)
+            && min > max
+        {
+            return Err(ConfigError::invalid(
+                "database.min_connections",
+                "must not exceed max_connections",
+            ));
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "sqlx")]
+    pub fn db_config(&self) -> crate::db::DbConfig {
+        let mut config =
+            crate::db::DbConfig::new(&self.url).with_required_extensions(&self.required_extensions);
+        if let Some(application_name) = &self.application_name {
+            config = config.with_application_name(application_name);
+        }
+        if let Some(max_connections) = self.max_connections {
+            config = config.with_max_connections(max_connections);
+        }
+        if let Some(min_connections) = self.min_connections {
+            config = config.with_min_connections(min_connections);
+        }
+        if let Some(secs) = self.acquire_timeout_secs {
+            config = config.with_acquire_timeout(Duration::from_secs(secs));
+        }
+        if let Some(ms) = self.statement_timeout_ms {
+            config = config.with_statement_timeout(Duration::from_millis(ms));
+        }
+        if let Some(path) = &self.ssl_root_cert {
+            config = config.with_ssl_root_cert(path);
+        }
+        config
+    }
+}
+
+impl BootstrapAdminConfig {
+    fn validate(&self) -> Result<(), ConfigError> {
+        if !email_address::EmailAddress::is_valid(self.email.trim()) {
+            return Err(ConfigError::invalid(
+                "bootstrap_admin.email",
+                "must be an email address",
+            ));
+        }
+        if self.invitation_ttl_secs == 0 {
+            return Err(ConfigError::invalid(
+                "bootstrap_admin.invitation_ttl_secs",
+                "must be at least 1",
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn invitation_ttl(&self) -> chrono::Duration {
+        i64::try_from(self.invitation_ttl_secs)
+            .ok()
+            .and_then(chrono::Duration::try_seconds)
+            .unwrap_or(chrono::Duration::MAX)
+    }
+}
+
+impl SessionConfig {
+    fn validate(&self) -> Result<(), ConfigError> {
+        if self.inactivity_secs == 0 {
+            return Err(ConfigError::invalid(
+                "session.inactivity_secs",
+                "must be at least 1",
+            ));
+        }
+        // Browsers drop `SameSite=None` cookies that are not also `Secure`.
+        if self.same_site == SameSitePolicy::None && !self.secure {
+            return Err(ConfigError::invalid(
+                "session.same_site",
+                "none requires session.secure",
+            ));
+        }
+        if self
+            .cookie_name
+            .as_ref()
+            .is_some_and(|name| name.is_empty() || name.contains([';', '=', ' ']))
+        {
+            return Err(ConfigError::invalid(
+                "session.cookie_name",
+                "must be a non-empty cookie token",
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn inactivity(&self) -> time::Duration {
+        time::Duration::seconds(self.inactivity_secs.min(i64::MAX as u64) as i64)
+    }
+}
+
+impl OidcConfig {
+    fn validate(&self) -> Result<(), ConfigError> {
+        if self.client_id.trim().is_empty() {
+            return Err(ConfigError::Missing("oidc.client_id".to_string()));
+        }
+        for (key, value) in [
+            ("oidc.base_url", &self.base_url),
+            ("oidc.redirect_url", &self.redirect_url),
+            ("oidc.idp_url", &self.idp_url),
+        ] {
+            if value.trim().is_empty() {
+                return Err(ConfigError::Missing(key.to_string()));
+            }
+            Url::parse(value).map_err(|err| ConfigError::invalid(key, err.to_string()))?;
+        }
+        Ok(())
+    }
+
+    pub fn credentials(&self) -> AnyResult<OidcCredentials> {
+        match &self.client_secret {
+            Some(secret) => {
+                OidcCredentials::new(&self.client_id, secret, &self.base_url, &self.redirect_url)
+            }
+            None => {
+                OidcCredentials::verification(&self.client_id, &self.base_url, &self.redirect_url)
+            }
+        }
+    }
+
+    pub fn allowed_other_audiences(&self) -> Option<AllowedOtherAudiences> {
+        if self.allowed_audiences.is_empty() {
+            None
+        } else if self
+            .allowed_audiences
+            .iter()
+            .any(|audience| audience == "*")
+        {
+            Some(AllowedOtherAudiences::Any(Any::Any))
+        } else {
+            Some(AllowedOtherAudiences::List(self.allowed_audiences.clone()))
+        }
+    }
+
+    /// Fetch the provider metadata and build the `IdentityProvider`.
+    pub async fn identity_provider(&self) -> AnyResult<IdentityProvider> {
+        let idp_url = Url::parse(&self.idp_url)?;
+        IdentityProvider::new(
+            &self.credentials()?,
+            self.allowed_other_audiences(),
+            &idp_url,
+        )
+        .await
+    }
+}
+
+impl WorkloadConfig {
+    fn validate(&self) -> Result<(), ConfigError> {
+        if self.allowed_issuers.is_empty() {
+            return Err(ConfigError::Missing("workload.allowed_issuers".to_string()));
+        }
+        Ok(())
+    }
+
+    pub fn validator(&self) -> AnyResult<WorkloadJwtValidator> {
+        Ok(WorkloadJwtValidator::new(
+            self.allowed_issuers.clone(),
+            self.clock_skew_secs,
+            Duration::from_secs(self.jwks_cache_secs),
+        )?)
+    }
+}
+
+impl IdentityConfig {
+    fn validate(&self) -> Result<(), ConfigError> {
+        let required = |key: &str, present: bool| {
+            if present {
+                Ok(())
+            } else {
+                Err(ConfigError::Missing(format!("identity.{}", key)))
+            }
+        };
+        match self.mode {
+            IdentityMode::Oidc | IdentityMode::SessionOnly => {}
+            IdentityMode::Jwt => {
+                required("jwt_issuer", self.jwt_issuer.is_some())?;
+                required("jwt_audience", self.jwt_audience.is_some())?;
+                required("jwt_keys_file", self.jwt_keys_file.is_some())?;
+            }
+            IdentityMode::ApiKey => required("api_keys_file", self.api_keys_file.is_some())?,
+            IdentityMode::TrustedHeader => {
+                // A proxy that proves nothing would let any client name any user.
+                required(
+                    "proxy_secret",
+                    self.proxy_secret.is_some() || !self.proxy_peers.is_empty(),
+                )?;
+                if self.proxy_secret.is_some() {
+                    required("proxy_secret_header", self.proxy_secret_header.is_some())?;
+                }
+                for (key, header) in [
+                    ("identity.proxy_email_header", &self.proxy_email_header),
+                    ("identity.proxy_secret_header", &self.proxy_secret_header),
+                ] {
+                    if let Some(header) = header {
+                        axum::http::HeaderName::from_str(header)
+                            .map_err(|err| ConfigError::invalid(key, err.to_string()))?;
+                    }
+                }
+                if let Some(peer) = self
+                    .proxy_peers
+                    .iter()
+                    .find(|peer| crate::policy::parse_cidr(peer).is_none())
+                {
+                    return Err(ConfigError::invalid(
+                        "identity.proxy_peers",
+                        format!("invalid CIDR {}", peer),
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The `trusted_proxy` settings of the `trusted_header` mode.
+    #[cfg(feature = "api")]
+    pub fn trusted_proxy_config(&self) -> AnyResult<crate::trusted_proxy::TrustedProxyConfig> {
+        use axum::http::header::HeaderName;
+
+        let mut config = crate::trusted_proxy::TrustedProxyConfig::new();
+        if let Some(header) = &self.proxy_email_header {
+            config = config.with_email_header(HeaderName::from_str(header)?);
+        }
+        if let (Some(header), Some(secret)) = (&self.proxy_secret_header, &self.proxy_secret) {
+            config = config.with_shared_secret(HeaderName::from_str(header)?, secret);
+        }
+        if !self.proxy_peers.is_empty() {
+            config = config
+                .with_allowed_peers(&self.proxy_peers)
+                .map_err(anyhow::Error::msg)?;
+        }
+        Ok(config)
+    }
+}
+
+struct Env<F> {
+    lookup: F,
+}
+
+impl<F> Env<F>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    fn string(&self, name: &str) -> Option<String> {
+        (self.lookup)(&format!("{}{}", ENV_PREFIX, name))
+            .map(|value| value.trim().to_string())
+            .filter(|value| !value.is_empty())
+    }
+
+    fn required(&self, name: &str) -> Result<String, ConfigError> {
+        self.string(name)
+            .ok_or_else(|| ConfigError::Missing(format!("{}{}", ENV_PREFIX, name)))
+    }
+
+    fn parse<T>(&self, name: &str) -> Result<Option<T>, ConfigError>
+    where
+        T: FromStr,
+        T::Err: fmt::Display,
+    {
+        self.string(name)
+            .map(|value| {
+                value.parse().map_err(|err| {
+                    ConfigError::invalid(&format!("{}{}", ENV_PREFIX, name), format!("{}", err))
+                })
+            })
+            .transpose()
+    }
+
+    fn list(&self, name: &str) -> Vec<String> {
+        self.string(name)
+            .map(|value| {
+                value
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|item| !item.is_empty())
+                    .map(str::to_string)
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::{AuthConfig, ConfigError, RegistrationMode, SameSitePolicy};
+    use crate::environment::{Environment, EnvironmentMode};
+    use crate::ids::UuidVersion;
+
+    fn load(vars: &[(&str, &str)]) -> Result<AuthConfig, ConfigError> {
+        let vars: HashMap<String, String> = vars
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        AuthConfig::from_lookup(|name| vars.get(name).cloned())
+    }
+
+    #[test]
+    fn loads_defaults_and_sections() {
+        let config = load(&[("DATABASE_URL", "postgres://localhost/auth")]).unwrap();
+        assert_eq!(config.database.url, "postgres://localhost/auth");
+        assert_eq!(config.session.same_site, SameSitePolicy::Lax);
+        assert!(config.oidc.is_none() && config.workload.is_none());
+        assert_eq!(config.registration, RegistrationMode::Open);
+
+        let config = load(&[
+            ("AUTH_DATABASE_URL", "postgres://db/auth"),
+            ("AUTH_SESSION_SECURE", "true"),
+            ("AUTH_SESSION_SAME_SITE", "None"),
+            ("AUTH_REGISTRATION", "invitation_only"),
+            ("AUTH_UUID_VERSION", "v7"),
+            ("AUTH_OIDC_CLIENT_ID", "app"),
+            ("AUTH_OIDC_BASE_URL", "https://app.example.com"),
+            ("AUTH_OIDC_REDIRECT_URL", "https://app.example.com/auth"),
+            ("AUTH_OIDC_IDP_URL", "https://idp.example.com/realms/main"),
+            (
+                "AUTH_WORKLOAD_ISSUERS",
+                "https://a.example.com, https://b.example.com",
+            ),
+        ])
+        .unwrap();
+        assert_eq!(config.database.url, "postgres://db/auth");
+        assert_eq!(config.session.same_site, SameSitePolicy::None);
+        assert_eq!(config.registration, RegistrationMode::InvitationOnly);
+        assert_eq!(config.uuid_version, UuidVersion::V7);
+        assert!(config.oidc.unwrap().client_secret.is_none());
+        assert_eq!(config.workload.unwrap().allowed_issuers.len(), 2);
+        assert!(config.bootstrap_admin.is_none());
+
+        let config = load(&[
+            ("DATABASE_URL", "postgres://localhost/auth"),
+            ("AUTH_BOOTSTRAP_ADMIN_EMAIL", "root@example.com"),
+            ("AUTH_BOOTSTRAP_ADMIN_REISSUE_INVITATION", "true"),
+        ])
+        .unwrap();
+        let bootstrap_admin = config.bootstrap_admin.unwrap();
+        assert_eq!(bootstrap_admin.email, "root@example.com");
+        assert!(bootstrap_admin.user_id.is_none() && bootstrap_admin.reissue_invitation);
+        assert!(config.environment.is_none());
+
+        let config = load(&[
+            ("DATABASE_URL", "postgres://localhost/auth"),
+            ("AUTH_ENVIRONMENT", "staging"),
+            ("AUTH_ENVIRONMENT_MODE", "isolated"),
+        ])
+        .unwrap();
+        assert_eq!(
+            config.environment,
+            Some(Environment::new("staging", EnvironmentMode::Isolated))
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_settings() {
+        assert_eq!(
+            load(&[]),
+            Err(ConfigError::Missing("database.url".to_string()))
+        );
+        let url = ("DATABASE_URL", "postgres://localhost/auth");
+        assert!(matches!(
+            load(&[url, ("AUTH_DATABASE_MAX_CONNECTIONS", "many")]),
+            Err(ConfigError::Invalid { .. })
+        ));
+        assert!(matches!(
+            load(&[url, ("AUTH_SESSION_SAME_SITE", "none")]),
+            Err(ConfigError::Invalid { .. })
+        ));
+        assert_eq!(
+            load(&[url, ("AUTH_OIDC_CLIENT_ID", "app")]),
+            Err(ConfigError::Missing("AUTH_OIDC_BASE_URL".to_string()))
+        );
+        assert_eq!(
+            load(&[url, ("AUTH_IDENTITY", "jwt"), ("AUTH_JWT_ISSUER", "app")]),
+            Err(ConfigError::Missing("identity.jwt_audience".to_string()))
+        );
+        assert!(matches!(
+            load(&[
+                url,
+                ("AUTH_IDENTITY", "trusted_header"),
+                ("AUTH_PROXY_PEERS", "10.0.0.0/33"),
+            ]),
+            Err(ConfigError::Invalid { .. })
+        ));
+        assert!(matches!(
+            load(&[url, ("AUTH_BOOTSTRAP_ADMIN_EMAIL", "root")]),
+            Err(ConfigError::Invalid { .. })
+        ));
+        assert!(matches!(
+            load(&[url, ("AUTH_ENVIRONMENT", "staging@eu")]),
+            Err(ConfigError::Invalid { .. })
+        ));
+    }
+}
@@ -1,11 +1,163 @@
+use std::fmt;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
 use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
 use serde_json::{Value, json};
-use sqlx::migrate::{MigrateError, Migrator};
-use sqlx::{FromRow, PgPool};
+use sqlx::migrate::{Migrate, MigrateError, Migrator};
+use sqlx::postgres::{PgConnectOptions, PgPoolOptions, PgSslMode};
+use sqlx::{FromRow, PgConnection, PgPool};
 use uuid::Uuid;
 
+use crate::archival::ArchivedFilter;
+use crate::email::{DEFAULT_EMAIL_NORMALIZER, EmailNormalizer};
+use crate::environment::{environment, lookup_scopes, resolve_scope_for};
+use crate::external_id::{
+    DEFAULT_EXTERNAL_ID_GENERATOR, ExternalIdGenerator, is_valid_external_id,
+};
 use crate::group_id::GroupId;
+use crate::ids::new_uuid;
+use crate::password::HashScheme;
+#[cfg(feature = "password-hashing")]
+use crate::password::{PasswordHashError, PasswordHasher, PasswordVerification};
+use crate::policy::{GrantCondition, RequestContext};
+use crate::redact::Sensitive;
+use crate::remember::RememberedSession;
+use crate::request_id::current_request_id;
 use crate::user_id::UserId;
+use crate::username::canonical_username;
+
+// Column lists shared by `columns()` and the queries. Macros rather than consts so they can be
+// spliced into SQL with `concat!`, keeping every query a `&'static str` sqlx can cache.
+macro_rules! user_columns {
+    () => {
+        "id, username, email, details, email_canonical, version, locale, external_id, display_name, \
+         timezone"
+    };
+}
+macro_rules! user_role_columns {
+    () => {
+        "user_id, scope, scope_id, role_name, effect, condition"
+    };
+}
+macro_rules! group_role_columns {
+    () => {
+        "group_id, scope, scope_id, role_name, effect, condition"
+    };
+}
+macro_rules! role_delegation_policy_columns {
+    () => {
+        "scope, scope_id, admin_role, grantable_role"
+    };
+}
+macro_rules! group_columns {
+    () => {
+        "id, display_name, details, version, visibility, external_id, archived_at"
+    };
+}
+macro_rules! group_join_request_columns {
+    () => {
+        "group_id, user_id, message, status, created_at, decided_at, decided_by"
+    };
+}
+macro_rules! username_history_columns {
+    () => {
+        "id, user_id, username, changed_at, released_at"
+    };
+}
+/// SQL condition: the granted `$column` matches the requested `$param`, exactly or as a trailing
+/// `*` wildcard (see `scope_id_matches`).
+macro_rules! scope_id_matches {
+    ($column:literal, $param:literal) => {
+        concat!(
+            "(",
+            $column,
+            " = ",
+            $param,
+            " OR (right(",
+            $column,
+            ", 1) = '*' AND starts_with(",
+            $param,
+            ", left(",
+            $column,
+            ", -1))))"
+        )
+    };
+}
+/// SQL aggregate over role grant rows with `effect`, `condition` and `scope_id` columns: true if
+/// one unconditionally allows the role in `$3` and none denies it there or at the global id `$5`.
+/// Conditional denies apply, since there is no request context to evaluate them against.
+macro_rules! grant_decision {
+    () => {
+        concat!(
+            "COALESCE(bool_or(effect = 'allow' AND condition IS NULL AND ",
+            scope_id_matches!("scope_id", "$3"),
+            "), FALSE) AND NOT COALESCE(bool_or(effect = 'deny' AND (scope_id = $5 OR ",
+            scope_id_matches!("scope_id", "$3"),
+            ")), FALSE)"
+        )
+    };
+}
+/// A user's role grants for role `$4` in the scopes `$2` (see `environment::lookup_scopes`),
+/// direct and through groups.
+macro_rules! effective_grants {
+    () => {
+        r#"
+        SELECT effect, condition, scope_id
+        FROM auth.user_roles
+        WHERE user_id = $1
+          AND scope = ANY($2)
+          AND role_name = $4
+        UNION ALL
+        SELECT gr.effect, gr.condition, gr.scope_id
+        FROM auth.group_memberships gm
+        JOIN auth.groups g
+          ON g.id = gm.group_id
+         AND g.deleted_at IS NULL
+        JOIN auth.group_roles gr
+          ON gr.group_id = gm.group_id
+        WHERE gm.user_id = $1
+          AND gr.scope = ANY($2)
+          AND gr.role_name = $4
+        "#
+    };
+}
+macro_rules! user_password_columns {
+    () => {
+        "user_id, password_hash, updated_at"
+    };
+}
+macro_rules! group_role_definition_columns {
+    () => {
+        "group_id, role_name, capabilities, description"
+    };
+}
+macro_rules! group_default_role_columns {
+    () => {
+        "group_id, scope, scope_id, role_name"
+    };
+}
+macro_rules! group_membership_columns {
+    () => {
+        "group_id, user_id, role_name"
+    };
+}
+macro_rules! login_history_columns {
+    () => {
+        "id, user_id, ip, user_agent, method, provider, created_at"
+    };
+}
+macro_rules! log_columns {
+    () => {
+        "id, user_id, action, timestamp, request_id"
+    };
+}
+
+/// Most `auth.log` rows one `INSERT` can carry: Postgres allows 65535 bind parameters, and each
+/// row binds one per column.
+pub const LOG_BATCH_ROWS: usize = u16::MAX as usize / 5;
 
 pub static MIGRATOR: Lazy<Migrator> = Lazy::new(|| {
     let mut m = sqlx::migrate!("./migrations");
@@ -17,74 +169,726 @@ pub const GLOBAL_SCOPE: &str = "global";
 pub const GLOBAL_SCOPE_ID: &str = "global";
 pub const SUPER_ADMIN_ROLE: &str = "super_admin";
 pub const GROUP_ADMIN_ROLE: &str = "group_admin";
+/// Role given to users who join a group through discovery.
+pub const GROUP_MEMBER_ROLE: &str = "member";
+/// Trailing wildcard in a granted scope id: `*` covers every id in the scope and
+/// `org:123/project:*` every id starting with `org:123/project:`.
+pub const SCOPE_ID_WILDCARD: char = '*';
+
+/// Whether a grant for `granted` scope id covers `requested`.
+///
+/// Only a trailing `*` is a wildcard; the requested id is always taken literally, so a grant for
+/// `org:1/project:5` does not cover a request for `org:1/project:*`.
+pub fn scope_id_matches(granted: &str, requested: &str) -> bool {
+    match granted.strip_suffix(SCOPE_ID_WILDCARD) {
+        Some(prefix) => requested.starts_with(prefix),
+        None => granted == requested,
+    }
+}
+
+/// Wildcards are only meaningful as the last character of a granted scope id.
+pub fn is_valid_scope_id_pattern(scope_id: &str) -> bool {
+    scope_id
+        .find(SCOPE_ID_WILDCARD)
+        .is_none_or(|index| index == scope_id.len() - 1)
+}
 
 pub async fn create_user_tables(pool: &PgPool) -> Result<(), MigrateError> {
     MIGRATOR.run(pool).await
 }
 
-#[derive(Debug, Clone, FromRow)]
+/// Extensions commonly expected alongside the auth schema. Not checked unless passed to
+/// `DbConfig::with_required_extensions`.
+pub const DEFAULT_REQUIRED_EXTENSIONS: &[&str] = &["pgcrypto", "pg_trgm"];
+
+/// Connection settings for `connect`. Defaults are tuned for an auth workload: many short
+/// queries, a small pool, and a tight statement timeout so a slow query fails the request
+/// instead of stalling logins.
+#[derive(Debug, Clone)]
+pub struct DbConfig {
+    url: String,
+    application_name: String,
+    max_connections: u32,
+    min_connections: u32,
+    acquire_timeout: Duration,
+    statement_timeout: Duration,
+    ssl_mode: Option<PgSslMode>,
+    ssl_root_cert: Option<PathBuf>,
+    required_extensions: Vec<String>,
+}
+
+impl DbConfig {
+    pub fn new<S: Into<String>>(url: S) -> Self {
+        Self {
+            url: url.into(),
+            application_name: "subseq_auth".to_string(),
+            max_connections: 10,
+            min_connections: 1,
+            acquire_timeout: Duration::from_secs(5),
+            statement_timeout: Duration::from_secs(5),
+            ssl_mode: None,
+            ssl_root_cert: None,
+            required_extensions: Vec::new(),
+        }
+    }
+
+    pub fn with_application_name(mut self, application_name: &str) -> Self {
+        self.application_name = application_name.to_string();
+        self
+    }
+
+    pub fn with_max_connections(mut self, max_connections: u32) -> Self {
+        self.max_connections = max_connections;
+        self
+    }
+
+    pub fn with_min_connections(mut self, min_connections: u32) -> Self {
+        self.min_connections = min_connections;
+        self
+    }
+
+    pub fn with_acquire_timeout(mut self, acquire_timeout: Duration) -> Self {
+        self.acquire_timeout = acquire_timeout;
+        self
+    }
+
+    /// Server-side `statement_timeout` for every connection in the pool.
+    pub fn with_statement_timeout(mut self, statement_timeout: Duration) -> Self {
+        self.statement_timeout = statement_timeout;
+        self
+    }
+
+    /// Override the TLS mode. Otherwise the URL's `sslmode` (or sqlx's `prefer`) applies.
+    pub fn with_ssl_mode(mut self, ssl_mode: PgSslMode) -> Self {
+        self.ssl_mode = Some(ssl_mode);
+        self
+    }
+
+    pub fn with_ssl_root_cert<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.ssl_root_cert = Some(path.into());
+        self
+    }
+
+    /// Fail `connect` unless these extensions are installed in the database.
+    pub fn with_required_extensions<I, S>(mut self, extensions: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        self.required_extensions = extensions
+            .into_iter()
+            .map(|extension| extension.as_ref().to_string())
+            .collect();
+        self
+    }
+}
+
+/// Build a connection pool from `config`, verifying required extensions if any were configured.
+pub async fn connect(config: DbConfig) -> Result<PgPool, sqlx::Error> {
+    let mut options = PgConnectOptions::from_str(&config.url)?
+        .application_name(&config.application_name)
+        .options([(
+            "statement_timeout",
+            config.statement_timeout.as_millis().to_string(),
+        )]);
+    if let Some(ssl_mode) = config.ssl_mode {
+        options = options.ssl_mode(ssl_mode);
+    }
+    if let Some(ssl_root_cert) = &config.ssl_root_cert {
+        options = options.ssl_root_cert(ssl_root_cert);
+    }
+
+    let pool = PgPoolOptions::new()
+        .max_connections(config.max_connections)
+        .min_connections(config.min_connections)
+        .acquire_timeout(config.acquire_timeout)
+        .connect_with(options)
+        .await?;
+
+    if !config.required_extensions.is_empty() {
+        let installed: Vec<(String,)> =
+            sqlx::query_as("SELECT extname::TEXT FROM pg_extension WHERE extname = ANY($1)")
+                .bind(&config.required_extensions)
+                .fetch_all(&pool)
+                .await?;
+        let missing: Vec<&str> = config
+            .required_extensions
+            .iter()
+            .filter(|required| !installed.iter().any(|(name,)| name == *required))
+            .map(String::as_str)
+            .collect();
+        if !missing.is_empty() {
+            pool.close().await;
+            return Err(sqlx::Error::Configuration(
+                format!("missing required extensions: {}", missing.join(", ")).into(),
+            ));
+        }
+    }
+
+    Ok(pool)
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct HealthStatus {
+    pub database: bool,
+    /// Number of bundled migrations not yet applied; `None` if the database could not be queried.
+    pub pending_migrations: Option<usize>,
+}
+
+impl HealthStatus {
+    pub fn is_ready(&self) -> bool {
+        self.database && self.pending_migrations == Some(0)
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct MigrationInfo {
+    pub version: i64,
+    pub description: String,
+}
+
+#[derive(Debug, Clone, Serialize, FromRow)]
+pub struct AppliedMigration {
+    pub version: i64,
+    pub description: String,
+    pub installed_on: chrono::DateTime<chrono::Utc>,
+    pub success: bool,
+    /// Execution time in nanoseconds, as recorded by sqlx.
+    pub execution_time: i64,
+}
+
+fn is_undefined_table(err: &sqlx::Error) -> bool {
+    matches!(err, sqlx::Error::Database(err) if err.code().as_deref() == Some("42P01"))
+}
+
+/// Migrations recorded in `_sqlx_migrations`, oldest first.
+///
+/// Returns an empty list if migrations have never been run against this database.
+pub async fn applied_migrations(pool: &PgPool) -> Result<Vec<AppliedMigration>, sqlx::Error> {
+    let rows = sqlx::query_as::<_, AppliedMigration>(
+        r#"
+        SELECT version, description, installed_on, success, execution_time
+        FROM _sqlx_migrations
+        ORDER BY version ASC
+        "#,
+    )
+    .fetch_all(pool)
+    .await;
+
+    match rows {
+        Ok(rows) => Ok(rows),
+        Err(err) if is_undefined_table(&err) => Ok(Vec::new()),
+        Err(err) => Err(err),
+    }
+}
+
+/// Bundled migrations that have not been successfully applied, in the order they would run.
+///
+/// This is a dry run of `create_user_tables`; nothing is executed.
+pub async fn pending_migrations(pool: &PgPool) -> Result<Vec<MigrationInfo>, sqlx::Error> {
+    let applied = applied_migrations(pool).await?;
+    Ok(MIGRATOR
+        .iter()
+        .filter(|m| m.migration_type.is_up_migration())
+        .filter(|m| !applied.iter().any(|a| a.success && a.version == m.version))
+        .map(|m| MigrationInfo {
+            version: m.version,
+            description: m.description.to_string(),
+        })
+        .collect())
+}
+
+/// Apply pending migrations up to and including `target_version`.
+///
+/// Use this instead of `create_user_tables` for staged rollouts. Returns the migrations that
+/// were applied.
+pub async fn migrate_to(
+    pool: &PgPool,
+    target_version: i64,
+) -> Result<Vec<MigrationInfo>, MigrateError> {
+    if !MIGRATOR.version_exists(target_version) {
+        return Err(MigrateError::VersionMissing(target_version));
+    }
+
+    let mut conn = pool.acquire().await?;
+    conn.lock().await?;
+    conn.ensure_migrations_table().await?;
+    if let Some(version) = conn.dirty_version().await? {
+        conn.unlock().await?;
+        return Err(MigrateError::Dirty(version));
+    }
+
+    let applied = conn.list_applied_migrations().await?;
+    let mut ran = Vec::new();
+    for migration in MIGRATOR.iter() {
+        if !migration.migration_type.is_up_migration() || migration.version > target_version {
+            continue;
+        }
+        match applied.iter().find(|a| a.version == migration.version) {
+            Some(applied) if applied.checksum != migration.checksum => {
+                conn.unlock().await?;
+                return Err(MigrateError::VersionMismatch(migration.version));
+            }
+            Some(_) => {}
+            None => {
+                conn.apply(migration).await?;
+                ran.push(MigrationInfo {
+                    version: migration.version,
+                    description: migration.description.to_string(),
+                });
+            }
+        }
+    }
+
+    conn.unlock().await?;
+    Ok(ran)
+}
+
+/// Check database connectivity and whether the auth schema is fully migrated.
+///
+/// Suitable for calling from the host application's own readiness probes.
+pub async fn health_check(pool: &PgPool) -> HealthStatus {
+    if let Err(err) = sqlx::query("SELECT 1").execute(pool).await {
+        tracing::warn!("Auth health check failed to reach database: {}", err);
+        return HealthStatus {
+            database: false,
+            pending_migrations: None,
+        };
+    }
+
+    let pending_migrations = match pending_migrations(pool).await {
+        Ok(pending) => Some(pending.len()),
+        Err(err) => {
+            tracing::warn!("Auth health check failed to read migrations: {}", err);
+            None
+        }
+    };
+
+    HealthStatus {
+        database: true,
+        pending_migrations,
+    }
+}
+
+/// A conditional update did not apply.
+#[derive(Debug)]
+pub enum VersionConflictError {
+    /// The row exists but has moved past the expected version.
+    Conflict {
+        current_version: i64,
+    },
+    NotFound,
+    Database(sqlx::Error),
+}
+
+impl fmt::Display for VersionConflictError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Conflict { current_version } => {
+                write!(f, "Row was modified (current version {})", current_version)
+            }
+            Self::NotFound => write!(f, "Row not found"),
+            Self::Database(err) => write!(f, "Database error: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for VersionConflictError {}
+
+impl From<sqlx::Error> for VersionConflictError {
+    fn from(err: sqlx::Error) -> Self {
+        Self::Database(err)
+    }
+}
+
+/// How long a released username stays reserved for its previous owner.
+pub const DEFAULT_USERNAME_HOLD_DOWN: chrono::Duration = chrono::Duration::days(30);
+
+/// A username change did not apply.
+#[derive(Debug)]
+pub enum UsernameChangeError {
+    /// Another user currently holds an equivalent username.
+    Taken,
+    /// Another user released an equivalent username that is still in its hold-down period.
+    HeldDown {
+        until: chrono::NaiveDateTime,
+    },
+    NotFound,
+    Database(sqlx::Error),
+}
+
+impl fmt::Display for UsernameChangeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Taken => write!(f, "Username is already taken"),
+            Self::HeldDown { until } => write!(f, "Username is unavailable until {}", until),
+            Self::NotFound => write!(f, "User not found"),
+            Self::Database(err) => write!(f, "Database error: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for UsernameChangeError {}
+
+impl From<sqlx::Error> for UsernameChangeError {
+    fn from(err: sqlx::Error) -> Self {
+        Self::Database(err)
+    }
+}
+
+/// An external id change did not apply.
+#[derive(Debug)]
+pub enum ExternalIdChangeError {
+    /// Not allowed by `is_valid_external_id`.
+    Invalid,
+    /// Another user or group of the same kind holds it.
+    Taken,
+    NotFound,
+    Database(sqlx::Error),
+}
+
+impl fmt::Display for ExternalIdChangeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Invalid => write!(f, "External id is not valid"),
+            Self::Taken => write!(f, "External id is already taken"),
+            Self::NotFound => write!(f, "Not found"),
+            Self::Database(err) => write!(f, "Database error: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for ExternalIdChangeError {}
+
+impl From<sqlx::Error> for ExternalIdChangeError {
+    fn from(err: sqlx::Error) -> Self {
+        Self::Database(err)
+    }
+}
+
+/// Sets `external_id` to `$1` on the row with id `$2` of a table, returning the previous one. For
+/// `set_external_id_in`.
+macro_rules! set_external_id_query {
+    ($table:literal) => {
+        concat!(
+            "UPDATE ",
+            $table,
+            r#" updated
+            SET external_id = $1
+            FROM "#,
+            $table,
+            r#" previous
+            WHERE updated.id = $2
+              AND previous.id = updated.id
+            RETURNING previous.external_id
+            "#
+        )
+    };
+}
+
+/// The ids of a table's rows without an external id, and the `UPDATE` giving row `$2` the
+/// external id `$1` if it still has none. For `backfill_external_ids`.
+macro_rules! backfill_external_id_queries {
+    ($table:literal) => {
+        (
+            concat!("SELECT id FROM ", $table, " WHERE external_id IS NULL"),
+            concat!(
+                "UPDATE ",
+                $table,
+                " SET external_id = $1 WHERE id = $2 AND external_id IS NULL"
+            ),
+        )
+    };
+}
+
+/// Shared body of `UserRow`/`GroupRow::set_external_id`, with the table's
+/// `set_external_id_query!`. Returns the previous external id.
+async fn set_external_id_in(
+    pool: &PgPool,
+    query: &'static str,
+    id: Uuid,
+    external_id: &str,
+) -> Result<Option<String>, ExternalIdChangeError> {
+    if !is_valid_external_id(external_id) {
+        return Err(ExternalIdChangeError::Invalid);
+    }
+    let previous: Option<(Option<String>,)> = sqlx::query_as(query)
+        .bind(external_id)
+        .bind(id)
+        .fetch_optional(pool)
+        .await
+        .map_err(|err| match err {
+            sqlx::Error::Database(db_err) if db_err.is_unique_violation() => {
+                ExternalIdChangeError::Taken
+            }
+            err => ExternalIdChangeError::Database(err),
+        })?;
+    previous
+        .map(|(previous,)| previous)
+        .ok_or(ExternalIdChangeError::NotFound)
+}
+
+/// Give every user and group without an external id one from `generator`, e.g. after adding
+/// external ids to an existing deployment. Returns the number of rows updated.
+pub async fn backfill_external_ids(
+    pool: &PgPool,
+    generator: &ExternalIdGenerator,
+) -> Result<u64, sqlx::Error> {
+    let mut updated = 0;
+    for (select, update) in [
+        backfill_external_id_queries!("auth.users"),
+        backfill_external_id_queries!("auth.groups"),
+    ] {
+        let ids: Vec<(Uuid,)> = sqlx::query_as(select).fetch_all(pool).await?;
+        for (id,) in ids {
+            // Retry the rare collision with a fresh id.
+            for _ in 0..3 {
+                let result = sqlx::query(update)
+                    .bind(generator.generate())
+                    .bind(id)
+                    .execute(pool)
+                    .await;
+                match result {
+                    Ok(result) => {
+                        updated += result.rows_affected();
+                        break;
+                    }
+                    Err(sqlx::Error::Database(db_err)) if db_err.is_unique_violation() => {}
+                    Err(err) => return Err(err),
+                }
+            }
+        }
+    }
+    Ok(updated)
+}
+
+/// Shared body of `UserRow`/`GroupRow::set_details_if_version` for a table with `id`, `details`
+/// and `version` columns.
+macro_rules! set_details_if_version {
+    ($table:literal, $pool:expr, $id:expr, $details:expr, $expected_version:expr) => {{
+        let updated: Option<(i64,)> = sqlx::query_as(concat!(
+            "UPDATE ",
+            $table,
+            r#"
+            SET details = $1,
+                version = version + 1
+            WHERE id = $2
+              AND version = $3
+            RETURNING version
+            "#,
+        ))
+        .bind($details)
+        .bind($id)
+        .bind($expected_version)
+        .fetch_optional($pool)
+        .await?;
+        match updated {
+            Some((version,)) => Ok(version),
+            None => {
+                let current: Option<(i64,)> =
+                    sqlx::query_as(concat!("SELECT version FROM ", $table, " WHERE id = $1"))
+                        .bind($id)
+                        .fetch_optional($pool)
+                        .await?;
+                match current {
+                    Some((current_version,)) => {
+                        Err(VersionConflictError::Conflict { current_version })
+                    }
+                    None => Err(VersionConflictError::NotFound),
+                }
+            }
+        }
+    }};
+}
+
+/// Changes for `UserRow::set_profile`. `None` leaves a field alone and `Some(None)` clears it.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ProfileUpdate {
+    pub display_name: Option<Option<String>>,
+    pub locale: Option<Option<String>>,
+    pub timezone: Option<Option<String>>,
+}
+
+impl ProfileUpdate {
+    pub fn is_empty(&self) -> bool {
+        self.display_name.is_none() && self.locale.is_none() && self.timezone.is_none()
+    }
+}
+
+/// Whether Postgres knows `name` as a time zone, e.g. `America/Sao_Paulo`.
+pub async fn is_known_timezone(pool: &PgPool, name: &str) -> Result<bool, sqlx::Error> {
+    sqlx::query_scalar("SELECT EXISTS (SELECT 1 FROM pg_timezone_names WHERE name = $1)")
+        .bind(name)
+        .fetch_one(pool)
+        .await
+}
+
+#[derive(Clone, FromRow)]
 pub struct UserRow {
-    pub id: Uuid,
+    pub id: UserId,
     pub username: Option<String>,
     pub email: String,
     pub details: Option<Value>,
+    pub email_canonical: Option<String>,
+    pub version: i64,
+    pub locale: Option<String>,
+    pub external_id: Option<String>,
+    pub display_name: Option<String>,
+    /// IANA time zone name, e.g. `Europe/Lisbon`.
+    pub timezone: Option<String>,
+}
+
+impl fmt::Debug for UserRow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("UserRow")
+            .field("id", &self.id)
+            .field("username", &self.username.as_ref().map(Sensitive))
+            .field("email", &Sensitive(&self.email))
+            .field("details", &self.details.as_ref().map(Sensitive))
+            .field(
+                "email_canonical",
+                &self.email_canonical.as_ref().map(Sensitive),
+            )
+            .field("version", &self.version)
+            .field("locale", &self.locale)
+            .field("external_id", &self.external_id)
+            .field("display_name", &self.display_name.as_ref().map(Sensitive))
+            .field("timezone", &self.timezone)
+            .finish()
+    }
 }
 
 impl UserRow {
+    /// Build a new user row. `email_canonical` uses the default `EmailNormalizer` and
+    /// `external_id` the default `ExternalIdGenerator`; use `with_email_canonical` and
+    /// `with_external_id` to apply deployment-specific rules.
     pub fn new(
         id: UserId,
         username: Option<String>,
         email: String,
         details: Option<Value>,
     ) -> Self {
+        let email_canonical = Some(DEFAULT_EMAIL_NORMALIZER.canonical(&email));
         Self {
-            id: id.0,
+            id,
             username,
             email,
             details,
+            email_canonical,
+            version: 1,
+            locale: None,
+            external_id: Some(DEFAULT_EXTERNAL_ID_GENERATOR.generate()),
+            display_name: None,
+            timezone: None,
         }
     }
 
+    pub fn with_locale(mut self, locale: Option<String>) -> Self {
+        self.locale = locale;
+        self
+    }
+
+    pub fn with_email_canonical(mut self, normalizer: &EmailNormalizer) -> Self {
+        self.email_canonical = Some(normalizer.canonical(&self.email));
+        self
+    }
+
+    pub fn with_external_id(mut self, generator: &ExternalIdGenerator) -> Self {
+        self.external_id = Some(generator.generate());
+        self
+    }
+
     pub fn table_name() -> &'static str {
         "auth.users"
     }
 
     pub fn columns() -> &'static str {
-        "id, username, email, details"
+        user_columns!()
     }
 
     pub async fn insert(pool: &PgPool, row: &UserRow) -> Result<(), sqlx::Error> {
-        sqlx::query(&format!(
+        let mut conn = pool.acquire().await?;
+        Self::insert_in(&mut conn, row).await
+    }
+
+    pub(crate) async fn insert_in(
+        conn: &mut PgConnection,
+        row: &UserRow,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query(concat!(
+            "INSERT INTO auth.users (",
+            user_columns!(),
+            ")",
             r#"
-            INSERT INTO {} ({})
-            VALUES ($1, $2, $3, $4)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)
             "#,
-            Self::table_name(),
-            Self::columns()
         ))
         .bind(row.id)
         .bind(&row.username)
         .bind(&row.email)
         .bind(&row.details)
-        .execute(pool)
+        .bind(&row.email_canonical)
+        .bind(row.version)
+        .bind(&row.locale)
+        .bind(&row.external_id)
+        .bind(&row.display_name)
+        .bind(&row.timezone)
+        .execute(&mut *conn)
         .await?;
 
         Ok(())
     }
 
+    /// The user, unless they are soft-deleted; see `recycle_bin`.
     pub async fn get(pool: &PgPool, user_id: UserId) -> Result<Option<Self>, sqlx::Error> {
-        sqlx::query_as::<_, UserRow>(&format!(
+        Self::lookup(pool, user_id, false).await
+    }
+
+    /// `get`, including soft-deleted users.
+    pub(crate) async fn get_including_deleted(
+        pool: &PgPool,
+        user_id: UserId,
+    ) -> Result<Option<Self>, sqlx::Error> {
+        Self::lookup(pool, user_id, true).await
+    }
+
+    async fn lookup<'e, E>(
+        executor: E,
+        user_id: UserId,
+        include_deleted: bool,
+    ) -> Result<Option<Self>, sqlx::Error>
+    where
+        E: sqlx::PgExecutor<'e>,
+    {
+        sqlx::query_as::<_, UserRow>(concat!(
+            "SELECT ",
+            user_columns!(),
             r#"
-            SELECT {}
-            FROM {}
+            FROM auth.users
             WHERE id = $1
+              AND ($2 OR deleted_at IS NULL)
             LIMIT 1
             "#,
-            Self::columns(),
-            Self::table_name()
         ))
-        .bind(user_id.0)
-        .fetch_optional(pool)
+        .bind(user_id)
+        .bind(include_deleted)
+        .fetch_optional(executor)
+        .await
+    }
+
+    /// The users among `user_ids` that exist and are not soft-deleted, in no particular order.
+    pub async fn get_many(pool: &PgPool, user_ids: &[UserId]) -> Result<Vec<Self>, sqlx::Error> {
+        sqlx::query_as::<_, UserRow>(concat!(
+            "SELECT ",
+            user_columns!(),
+            r#"
+            FROM auth.users
+            WHERE id = ANY($1)
+              AND deleted_at IS NULL
+            "#,
+        ))
+        .bind(user_ids)
+        .fetch_all(pool)
         .await
     }
 
@@ -92,157 +896,752 @@ impl UserRow {
         pool: &PgPool,
         username: &str,
     ) -> Result<Option<Self>, sqlx::Error> {
-        sqlx::query_as::<_, UserRow>(&format!(
+        sqlx::query_as::<_, UserRow>(concat!(
+            "SELECT ",
+            user_columns!(),
             r#"
-            SELECT {}
-            FROM {}
+            FROM auth.users
             WHERE username = $1
+              AND deleted_at IS NULL
             LIMIT 1
             "#,
-            Self::columns(),
-            Self::table_name()
         ))
         .bind(username)
         .fetch_optional(pool)
         .await
     }
 
-    pub async fn get_by_email(pool: &PgPool, email: &str) -> Result<Option<Self>, sqlx::Error> {
-        sqlx::query_as::<_, UserRow>(&format!(
+    /// Look up a user whose username is equivalent to `username` under
+    /// `auth.username_canonical` (case, width, and look-alike folding).
+    pub async fn get_by_username_canonical(
+        pool: &PgPool,
+        username: &str,
+    ) -> Result<Option<Self>, sqlx::Error> {
+        sqlx::query_as::<_, UserRow>(concat!(
+            "SELECT ",
+            user_columns!(),
             r#"
-            SELECT {}
-            FROM {}
-            WHERE email = $1
+            FROM auth.users
+            WHERE username_canonical = auth.username_canonical($1)
+              AND deleted_at IS NULL
             LIMIT 1
             "#,
-            Self::columns(),
-            Self::table_name()
         ))
-        .bind(email)
+        .bind(username)
         .fetch_optional(pool)
         .await
     }
 
-    pub async fn set_details(
+    /// Look up a user by current username, falling back to the most recent user who held an
+    /// equivalent username. The bool is `true` when the match is a former username, so callers
+    /// can redirect to the current handle.
+    pub async fn get_by_any_username(
         pool: &PgPool,
-        user_id: UserId,
-        details: Option<Value>,
-    ) -> Result<(), sqlx::Error> {
-        sqlx::query(&format!(
+        username: &str,
+    ) -> Result<Option<(Self, bool)>, sqlx::Error> {
+        if let Some(user) = Self::get_by_username_canonical(pool, username).await? {
+            return Ok(Some((user, false)));
+        }
+        let former = sqlx::query_as::<_, UserRow>(concat!(
+            "SELECT ",
+            user_columns!(),
             r#"
-            UPDATE {}
-            SET details = $1
-            WHERE id = $2
+            FROM auth.users
+            WHERE id = (
+                SELECT user_id
+                FROM auth.username_history
+                WHERE username_canonical = auth.username_canonical($1)
+                ORDER BY changed_at DESC
+                LIMIT 1
+            )
+              AND deleted_at IS NULL
             "#,
-            Self::table_name()
         ))
-        .bind(details)
-        .bind(user_id.0)
-        .execute(pool)
-        .await?;
+        .bind(username)
+        .fetch_optional(pool)
+        .await?;
+        Ok(former.map(|user| (user, true)))
+    }
+
+    /// Look up a user by email using the default `EmailNormalizer`.
+    pub async fn get_by_email(pool: &PgPool, email: &str) -> Result<Option<Self>, sqlx::Error> {
+        Self::get_by_email_normalized(pool, email, &DEFAULT_EMAIL_NORMALIZER).await
+    }
+
+    /// Look up a user by canonical email, falling back to an exact match for rows without one.
+    /// Soft-deleted users are not found.
+    pub async fn get_by_email_normalized(
+        pool: &PgPool,
+        email: &str,
+        normalizer: &EmailNormalizer,
+    ) -> Result<Option<Self>, sqlx::Error> {
+        Self::lookup_by_email(pool, email, normalizer, false).await
+    }
+
+    /// `get_by_email_normalized`, including soft-deleted users.
+    pub(crate) async fn get_by_email_normalized_including_deleted(
+        pool: &PgPool,
+        email: &str,
+        normalizer: &EmailNormalizer,
+    ) -> Result<Option<Self>, sqlx::Error> {
+        Self::lookup_by_email(pool, email, normalizer, true).await
+    }
+
+    async fn lookup_by_email<'e, E>(
+        executor: E,
+        email: &str,
+        normalizer: &EmailNormalizer,
+        include_deleted: bool,
+    ) -> Result<Option<Self>, sqlx::Error>
+    where
+        E: sqlx::PgExecutor<'e>,
+    {
+        sqlx::query_as::<_, UserRow>(concat!(
+            "SELECT ",
+            user_columns!(),
+            r#"
+            FROM auth.users
+            WHERE (email_canonical = $1 OR email = $2)
+              AND ($3 OR deleted_at IS NULL)
+            ORDER BY (email_canonical = $1) DESC NULLS LAST
+            LIMIT 1
+            "#,
+        ))
+        .bind(normalizer.canonical(email))
+        .bind(email)
+        .bind(include_deleted)
+        .fetch_optional(executor)
+        .await
+    }
+
+    pub async fn get_or_create_by_email(
+        pool: &PgPool,
+        email: &str,
+        defaults: UserRow,
+    ) -> Result<(Self, bool), sqlx::Error> {
+        Self::get_or_create_by_email_normalized(pool, email, defaults, &DEFAULT_EMAIL_NORMALIZER)
+            .await
+    }
+
+    /// Return the user owning `email`, inserting `defaults` if there is none. The bool is `true`
+    /// when the row was created by this call.
+    ///
+    /// Safe under concurrent first logins: losing an insert race returns the winner's row instead
+    /// of a unique violation. A row with the same id is returned even if its email differs, and a
+    /// username or external id already taken by another account is dropped rather than failing
+    /// the insert. A soft-deleted owner is returned as well, since the email is still theirs;
+    /// callers check `is_deleted` before letting them in.
+    pub async fn get_or_create_by_email_normalized(
+        pool: &PgPool,
+        email: &str,
+        defaults: UserRow,
+        normalizer: &EmailNormalizer,
+    ) -> Result<(Self, bool), sqlx::Error> {
+        let mut conn = pool.acquire().await?;
+        Self::get_or_create_by_email_in(&mut conn, email, defaults, normalizer).await
+    }
+
+    /// `get_or_create_by_email_normalized` on `conn`, e.g. inside a transaction.
+    pub(crate) async fn get_or_create_by_email_in(
+        conn: &mut PgConnection,
+        email: &str,
+        defaults: UserRow,
+        normalizer: &EmailNormalizer,
+    ) -> Result<(Self, bool), sqlx::Error> {
+        let mut row = UserRow {
+            email: email.to_string(),
+            ..defaults
+        }
+        .with_email_canonical(normalizer);
+
+        loop {
+            let inserted = sqlx::query_as::<_, UserRow>(concat!(
+                "INSERT INTO auth.users (",
+                user_columns!(),
+                ")",
+                r#"
+                VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)
+                ON CONFLICT DO NOTHING
+                RETURNING "#,
+                user_columns!(),
+            ))
+            .bind(row.id)
+            .bind(&row.username)
+            .bind(&row.email)
+            .bind(&row.details)
+            .bind(&row.email_canonical)
+            .bind(row.version)
+            .bind(&row.locale)
+            .bind(&row.external_id)
+            .bind(&row.display_name)
+            .bind(&row.timezone)
+            .fetch_optional(&mut *conn)
+            .await?;
+            if let Some(user) = inserted {
+                return Ok((user, true));
+            }
+
+            if let Some(user) = Self::lookup_by_email(&mut *conn, email, normalizer, true).await? {
+                return Ok((user, false));
+            }
+            if let Some(user) = Self::lookup(&mut *conn, row.id, true).await? {
+                return Ok((user, false));
+            }
+            // The only remaining conflicts are the username and, very rarely, the external id.
+            if row.username.take().is_none() && row.external_id.take().is_none() {
+                return Err(sqlx::Error::RowNotFound);
+            }
+        }
+    }
+
+    /// Change a user's username, recording the old one in `auth.username_history`. The old
+    /// username stays reserved for this user for `hold_down`; `None` clears the username.
+    pub async fn set_username(
+        pool: &PgPool,
+        user_id: UserId,
+        username: Option<&str>,
+        hold_down: chrono::Duration,
+    ) -> Result<Self, UsernameChangeError> {
+        let mut tx = pool.begin().await?;
+        let current = sqlx::query_as::<_, UserRow>(concat!(
+            "SELECT ",
+            user_columns!(),
+            r#"
+            FROM auth.users
+            WHERE id = $1
+            FOR UPDATE
+            "#,
+        ))
+        .bind(user_id)
+        .fetch_optional(&mut *tx)
+        .await?
+        .ok_or(UsernameChangeError::NotFound)?;
+        if current.username.as_deref() == username {
+            return Ok(current);
+        }
+
+        if let Some(username) = username
+            && let Some(until) = UsernameHistoryRow::held_until(&mut *tx, username, user_id).await?
+        {
+            return Err(UsernameChangeError::HeldDown { until });
+        }
+
+        let updated = sqlx::query_as::<_, UserRow>(concat!(
+            r#"
+            UPDATE auth.users
+            SET username = $1,
+                version = version + 1
+            WHERE id = $2
+            RETURNING "#,
+            user_columns!(),
+        ))
+        .bind(username)
+        .bind(user_id)
+        .fetch_one(&mut *tx)
+        .await
+        .map_err(|err| match err {
+            sqlx::Error::Database(db_err) if db_err.is_unique_violation() => {
+                UsernameChangeError::Taken
+            }
+            err => UsernameChangeError::Database(err),
+        })?;
+
+        let now = chrono::Utc::now().naive_utc();
+        // Reclaiming a former username ends its earlier hold-down; the new release below decides
+        // how long it stays reserved.
+        if let Some(username) = username {
+            sqlx::query(
+                r#"
+                UPDATE auth.username_history
+                SET released_at = LEAST(released_at, $3)
+                WHERE user_id = $1
+                  AND username_canonical = auth.username_canonical($2)
+                "#,
+            )
+            .bind(user_id)
+            .bind(username)
+            .bind(now)
+            .execute(&mut *tx)
+            .await?;
+        }
+
+        // Case-only changes keep the same canonical handle, so there is nothing to release.
+        if let Some(old) = current.username.as_deref()
+            && username.map(canonical_username) != Some(canonical_username(old))
+        {
+            sqlx::query(
+                r#"
+                INSERT INTO auth.username_history
+                    (user_id, username, username_canonical, changed_at, released_at)
+                VALUES ($1, $2, auth.username_canonical($2), $3, $4)
+                "#,
+            )
+            .bind(user_id)
+            .bind(old)
+            .bind(now)
+            .bind(now.checked_add_signed(hold_down).unwrap_or(now))
+            .execute(&mut *tx)
+            .await?;
+        }
+
+        insert_audit_log(
+            &mut *tx,
+            Some(user_id),
+            json!({
+                "type": "username_changed",
+                "from": current.username,
+                "to": username,
+            }),
+        )
+        .await?;
+
+        tx.commit().await?;
+        Ok(updated)
+    }
+
+    pub async fn get_by_external_id(
+        pool: &PgPool,
+        external_id: &str,
+    ) -> Result<Option<Self>, sqlx::Error> {
+        Self::lookup_by_external_id(pool, external_id, false).await
+    }
+
+    /// `get_by_external_id`, including soft-deleted users.
+    pub(crate) async fn get_by_external_id_including_deleted(
+        pool: &PgPool,
+        external_id: &str,
+    ) -> Result<Option<Self>, sqlx::Error> {
+        Self::lookup_by_external_id(pool, external_id, true).await
+    }
+
+    async fn lookup_by_external_id(
+        pool: &PgPool,
+        external_id: &str,
+        include_deleted: bool,
+    ) -> Result<Option<Self>, sqlx::Error> {
+        sqlx::query_as::<_, UserRow>(concat!(
+            "SELECT ",
+            user_columns!(),
+            r#"
+            FROM auth.users
+            WHERE external_id = $1
+              AND ($2 OR deleted_at IS NULL)
+            LIMIT 1
+            "#,
+        ))
+        .bind(external_id)
+        .bind(include_deleted)
+        .fetch_optional(pool)
+        .await
+    }
+
+    /// Replace the user's external id. Returns the previous one.
+    pub async fn set_external_id(
+        pool: &PgPool,
+        user_id: UserId,
+        external_id: &str,
+    ) -> Result<Option<String>, ExternalIdChangeError> {
+        set_external_id_in(
+            pool,
+            set_external_id_query!("auth.users"),
+            user_id.0,
+            external_id,
+        )
+        .await
+    }
+
+    /// Set or clear the user's preferred locale. Returns `false` if the user does not exist.
+    pub async fn set_locale(
+        pool: &PgPool,
+        user_id: UserId,
+        locale: Option<&str>,
+    ) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query(
+            r#"
+            UPDATE auth.users
+            SET locale = $1,
+                version = version + 1
+            WHERE id = $2
+            "#,
+        )
+        .bind(locale)
+        .bind(user_id)
+        .execute(pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Apply `update` to the user's profile columns, optionally only at `expected_version`, and
+    /// return the updated row. Values are stored as given; validate them first.
+    pub async fn set_profile(
+        pool: &PgPool,
+        user_id: UserId,
+        update: &ProfileUpdate,
+        expected_version: Option<i64>,
+    ) -> Result<Self, VersionConflictError> {
+        let updated = sqlx::query_as::<_, UserRow>(concat!(
+            r#"
+            UPDATE auth.users
+            SET display_name = CASE WHEN $3 THEN $4 ELSE display_name END,
+                locale = CASE WHEN $5 THEN $6 ELSE locale END,
+                timezone = CASE WHEN $7 THEN $8 ELSE timezone END,
+                version = version + 1
+            WHERE id = $1
+              AND ($2::BIGINT IS NULL OR version = $2)
+            RETURNING "#,
+            user_columns!(),
+        ))
+        .bind(user_id)
+        .bind(expected_version)
+        .bind(update.display_name.is_some())
+        .bind(update.display_name.clone().flatten())
+        .bind(update.locale.is_some())
+        .bind(update.locale.clone().flatten())
+        .bind(update.timezone.is_some())
+        .bind(update.timezone.clone().flatten())
+        .fetch_optional(pool)
+        .await?;
+        if let Some(user) = updated {
+            return Ok(user);
+        }
+        match Self::get(pool, user_id).await? {
+            Some(user) => Err(VersionConflictError::Conflict {
+                current_version: user.version,
+            }),
+            None => Err(VersionConflictError::NotFound),
+        }
+    }
+
+    pub async fn set_details(
+        pool: &PgPool,
+        user_id: UserId,
+        details: Option<Value>,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            r#"
+            UPDATE auth.users
+            SET details = $1,
+                version = version + 1
+            WHERE id = $2
+            "#,
+        )
+        .bind(details)
+        .bind(user_id)
+        .execute(pool)
+        .await?;
 
         Ok(())
     }
 
+    /// Replace `details` only if the row is still at `expected_version`. Returns the new version.
+    pub async fn set_details_if_version(
+        pool: &PgPool,
+        user_id: UserId,
+        details: Option<Value>,
+        expected_version: i64,
+    ) -> Result<i64, VersionConflictError> {
+        set_details_if_version!("auth.users", pool, user_id, details, expected_version)
+    }
+
+    /// Deactivate the user and revoke their remember-me tokens.
     pub async fn deactivate(pool: &PgPool, user_id: UserId) -> Result<(), sqlx::Error> {
-        sqlx::query(&format!(
+        let mut tx = pool.begin().await?;
+        sqlx::query(
             r#"
-            UPDATE {}
+            UPDATE auth.users
             SET active = FALSE
             WHERE id = $1
             "#,
-            Self::table_name()
-        ))
-        .bind(user_id.0)
-        .execute(pool)
+        )
+        .bind(user_id)
+        .execute(&mut *tx)
         .await?;
+        RememberedSession::revoke_all_in(&mut tx, user_id).await?;
+        tx.commit().await?;
 
         Ok(())
     }
 
+    /// Active users whose last login (or creation, if they never logged in) is before `cutoff`.
+    pub async fn inactive_since(
+        pool: &PgPool,
+        cutoff: chrono::NaiveDateTime,
+    ) -> Result<Vec<Self>, sqlx::Error> {
+        sqlx::query_as::<_, UserRow>(concat!(
+            "SELECT ",
+            user_columns!(),
+            r#"
+            FROM auth.users
+            WHERE active = TRUE
+              AND COALESCE(last_login_at, created_at) < $1
+            ORDER BY COALESCE(last_login_at, created_at) ASC
+            "#,
+        ))
+        .bind(cutoff)
+        .fetch_all(pool)
+        .await
+    }
+
+    /// Users created under `ProvisioningMode::RequireApproval` that no admin has approved yet,
+    /// oldest first.
+    pub async fn pending_approval(
+        pool: &PgPool,
+        page: Option<(i64, i64)>,
+    ) -> Result<Vec<Self>, sqlx::Error> {
+        let (limit, offset) = match page {
+            Some((limit, offset)) => (Some(limit), offset),
+            None => (None, 0),
+        };
+        sqlx::query_as::<_, UserRow>(concat!(
+            "SELECT ",
+            user_columns!(),
+            r#"
+            FROM auth.users
+            WHERE pending_approval
+              AND deleted_at IS NULL
+            ORDER BY created_at ASC
+            LIMIT $1 OFFSET $2
+            "#,
+        ))
+        .bind(limit)
+        .bind(offset)
+        .fetch_all(pool)
+        .await
+    }
+
+    pub async fn is_pending_approval(pool: &PgPool, user_id: UserId) -> Result<bool, sqlx::Error> {
+        let pending: Option<(bool,)> =
+            sqlx::query_as("SELECT pending_approval FROM auth.users WHERE id = $1")
+                .bind(user_id)
+                .fetch_optional(pool)
+                .await?;
+        Ok(pending.is_some_and(|(pending,)| pending))
+    }
+
+    /// Whether the user is soft-deleted; see `recycle_bin`.
+    pub async fn is_deleted(pool: &PgPool, user_id: UserId) -> Result<bool, sqlx::Error> {
+        let deleted: Option<(bool,)> =
+            sqlx::query_as("SELECT deleted_at IS NOT NULL FROM auth.users WHERE id = $1")
+                .bind(user_id)
+                .fetch_optional(pool)
+                .await?;
+        Ok(deleted.is_some_and(|(deleted,)| deleted))
+    }
+
+    #[cfg(feature = "hard-delete")]
     pub async fn delete(pool: &PgPool, user_id: UserId) -> Result<(), sqlx::Error> {
-        sqlx::query(&format!(
+        sqlx::query(
             r#"
-            DELETE FROM {}
+            DELETE FROM auth.users
             WHERE id = $1
             "#,
-            Self::table_name()
-        ))
-        .bind(user_id.0)
+        )
+        .bind(user_id)
         .execute(pool)
         .await?;
 
         Ok(())
     }
+
+    /// Delete a user and everything tied to them in one transaction. Returns false if the user
+    /// does not exist.
+    ///
+    /// Roles, memberships, join requests, password and login/username history go with the
+    /// `auth.users` row through `ON DELETE CASCADE`. Audit log rows are kept but anonymized: they
+    /// are detached from the user, references to the user id are replaced with a pseudonym that
+    /// is not linked to anything else, and usernames and emails are dropped from the action.
+    #[cfg(feature = "hard-delete")]
+    pub async fn delete_cascade(pool: &PgPool, user_id: UserId) -> Result<bool, sqlx::Error> {
+        let mut tx = pool.begin().await?;
+        let email: Option<String> = sqlx::query_scalar(
+            r#"
+            SELECT email
+            FROM auth.users
+            WHERE id = $1
+            FOR UPDATE
+            "#,
+        )
+        .bind(user_id)
+        .fetch_optional(&mut *tx)
+        .await?;
+        let Some(email) = email else {
+            return Ok(false);
+        };
+
+        // Uuids need no escaping in JSON, so a textual replace keeps the action valid.
+        let pseudonym = Uuid::new_v4().to_string();
+        sqlx::query(
+            r#"
+            UPDATE auth.log
+            SET user_id = NULLIF(user_id, $1),
+                action = replace(
+                    (
+                        CASE WHEN action->>'type' = 'username_changed'
+                            THEN action - 'from' - 'to'
+                            ELSE action
+                        END - 'email' - 'username'
+                    )::TEXT,
+                    $2,
+                    $3
+                )::JSONB
+            WHERE user_id = $1
+               OR action::TEXT LIKE '%' || $2 || '%'
+            "#,
+        )
+        .bind(user_id)
+        .bind(user_id.to_string())
+        .bind(&pseudonym)
+        .execute(&mut *tx)
+        .await?;
+
+        sqlx::query(
+            r#"
+            UPDATE auth.import_conflicts
+            SET email = NULL
+            WHERE lower(email) = lower($1)
+            "#,
+        )
+        .bind(&email)
+        .execute(&mut *tx)
+        .await?;
+
+        sqlx::query(
+            r#"
+            DELETE FROM auth.users
+            WHERE id = $1
+            "#,
+        )
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await?;
+
+        insert_audit_log(
+            &mut *tx,
+            None,
+            json!({
+                "type": "user_deleted",
+                "user_id": pseudonym,
+            }),
+        )
+        .await?;
+
+        tx.commit().await?;
+        Ok(true)
+    }
+}
+
+/// Whether a role grant allows the role or blocks it. A deny covering the requested scope id, or
+/// at the global id of the same scope, overrides any allow.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize, sqlx::Type)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(type_name = "TEXT", rename_all = "lowercase")]
+pub enum RoleEffect {
+    #[default]
+    Allow,
+    Deny,
 }
 
 #[derive(Debug, Clone, FromRow)]
 pub struct UserRoleRow {
-    pub user_id: Uuid,
+    pub user_id: UserId,
     pub scope: String,
     pub scope_id: String,
     pub role_name: String,
+    pub effect: RoleEffect,
+    /// A `policy::GrantCondition` limiting when the grant applies.
+    pub condition: Option<Value>,
 }
 
 impl UserRoleRow {
     pub fn new(user_id: UserId, scope: &str, scope_id: &str, role_name: &str) -> Self {
         Self {
-            user_id: user_id.0,
+            user_id,
             scope: scope.to_string(),
             scope_id: scope_id.to_string(),
             role_name: role_name.to_string(),
+            effect: RoleEffect::default(),
+            condition: None,
         }
     }
 
+    pub fn with_condition(mut self, condition: &GrantCondition) -> Self {
+        self.condition = Some(condition.to_value());
+        self
+    }
+
     pub fn table_name() -> &'static str {
         "auth.user_roles"
     }
 
     pub fn columns() -> &'static str {
-        "user_id, scope, scope_id, role_name"
+        user_role_columns!()
     }
 
+    /// Grant the role, replacing a deny for the same role and scope id. Ignores `row.effect`.
     pub async fn allow(pool: &PgPool, row: &UserRoleRow) -> Result<(), sqlx::Error> {
-        sqlx::query(&format!(
+        Self::set_effect(pool, row, RoleEffect::Allow).await
+    }
+
+    /// Block the role in the scope id even if it is allowed elsewhere. Ignores `row.effect`.
+    pub async fn deny(pool: &PgPool, row: &UserRoleRow) -> Result<(), sqlx::Error> {
+        Self::set_effect(pool, row, RoleEffect::Deny).await
+    }
+
+    async fn set_effect(
+        pool: &PgPool,
+        row: &UserRoleRow,
+        effect: RoleEffect,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query(concat!(
+            "INSERT INTO auth.user_roles (",
+            user_role_columns!(),
+            ")",
             r#"
-            INSERT INTO {} ({})
-            VALUES ($1, $2, $3, $4)
-            ON CONFLICT (user_id, scope, scope_id, role_name) DO NOTHING
+            VALUES ($1, $2, $3, $4, $5, $6)
+            ON CONFLICT (user_id, scope, scope_id, role_name) DO UPDATE
+            SET effect = EXCLUDED.effect,
+                condition = EXCLUDED.condition,
+                source_group_id = NULL,
+                source_provider = NULL
             "#,
-            Self::table_name(),
-            Self::columns()
         ))
         .bind(row.user_id)
         .bind(&row.scope)
         .bind(&row.scope_id)
         .bind(&row.role_name)
+        .bind(effect)
+        .bind(&row.condition)
         .execute(pool)
         .await?;
+        RoleAssignmentTarget::User(row.user_id).invalidate_role_snapshots();
 
         Ok(())
     }
 
     pub async fn revoke(pool: &PgPool, row: &UserRoleRow) -> Result<(), sqlx::Error> {
-        sqlx::query(&format!(
+        sqlx::query(
             r#"
-            DELETE FROM {}
+            DELETE FROM auth.user_roles
             WHERE user_id = $1
               AND scope = $2
               AND scope_id = $3
               AND role_name = $4
             "#,
-            Self::table_name()
-        ))
+        )
         .bind(row.user_id)
         .bind(&row.scope)
         .bind(&row.scope_id)
         .bind(&row.role_name)
         .execute(pool)
         .await?;
+        RoleAssignmentTarget::User(row.user_id).invalidate_role_snapshots();
 
         Ok(())
     }
 
+    /// Whether the user's own grants allow the role in `scope_id`, including through a wildcard,
+    /// and none deny it.
     pub async fn has_role(
         pool: &PgPool,
         user_id: UserId,
@@ -250,39 +1649,36 @@ impl UserRoleRow {
         scope_id: &str,
         role_name: &str,
     ) -> Result<bool, sqlx::Error> {
-        let count: (i64,) = sqlx::query_as(&format!(
+        sqlx::query_scalar(concat!(
+            "SELECT ",
+            grant_decision!(),
             r#"
-            SELECT COUNT(*)
-            FROM {}
+            FROM auth.user_roles
             WHERE user_id = $1
-              AND scope = $2
-              AND scope_id = $3
+              AND scope = ANY($2)
               AND role_name = $4
             "#,
-            Self::table_name()
         ))
-        .bind(user_id.0)
-        .bind(scope)
+        .bind(user_id)
+        .bind(lookup_scopes(scope))
         .bind(scope_id)
         .bind(role_name)
+        .bind(GLOBAL_SCOPE_ID)
         .fetch_one(pool)
-        .await?;
-
-        Ok(count.0 > 0)
+        .await
     }
 
     pub async fn roles(pool: &PgPool, user_id: UserId) -> Result<Vec<Self>, sqlx::Error> {
-        sqlx::query_as::<_, UserRoleRow>(&format!(
+        sqlx::query_as::<_, UserRoleRow>(concat!(
+            "SELECT ",
+            user_role_columns!(),
             r#"
-            SELECT {}
-            FROM {}
+            FROM auth.user_roles
             WHERE user_id = $1
             ORDER BY scope ASC, scope_id ASC, role_name ASC
             "#,
-            Self::columns(),
-            Self::table_name()
         ))
-        .bind(user_id.0)
+        .bind(user_id)
         .fetch_all(pool)
         .await
     }
@@ -293,37 +1689,71 @@ impl UserRoleRow {
         scope: &str,
         scope_id: &str,
     ) -> Result<Vec<Self>, sqlx::Error> {
-        sqlx::query_as::<_, UserRoleRow>(&format!(
+        sqlx::query_as::<_, UserRoleRow>(concat!(
+            "SELECT ",
+            user_role_columns!(),
             r#"
-            SELECT {}
-            FROM {}
+            FROM auth.user_roles
             WHERE user_id = $1
               AND scope = $2
               AND scope_id = $3
             ORDER BY role_name ASC
             "#,
-            Self::columns(),
-            Self::table_name()
         ))
-        .bind(user_id.0)
+        .bind(user_id)
         .bind(scope)
         .bind(scope_id)
         .fetch_all(pool)
         .await
     }
-}
 
-/// Backward-compatible global user roles view on top of scoped user_roles.
-#[derive(Debug, Clone, FromRow)]
-pub struct AccessRoleRow {
-    pub user_id: Uuid,
-    pub role_name: String,
-}
+    /// Revoke every role the user holds in one scope. Returns the revoked grants.
+    pub async fn revoke_all_in_scope(
+        pool: &PgPool,
+        user_id: UserId,
+        scope: &str,
+        scope_id: &str,
+    ) -> Result<Vec<Self>, sqlx::Error> {
+        let report =
+            revoke_user_roles_report(pool, None, Some(user_id), Some((scope, scope_id)), false)
+                .await?;
+        Ok(report.effects)
+    }
+
+    /// Revoke every role the user holds, in any scope, e.g. when offboarding them. Returns the
+    /// revoked grants.
+    pub async fn revoke_all_for_user(
+        pool: &PgPool,
+        user_id: UserId,
+    ) -> Result<Vec<Self>, sqlx::Error> {
+        let report = revoke_user_roles_report(pool, None, Some(user_id), None, false).await?;
+        Ok(report.effects)
+    }
+
+    /// Revoke every user's roles in one scope, e.g. when the scope is deleted. Returns the
+    /// revoked grants.
+    pub async fn revoke_all_for_scope(
+        pool: &PgPool,
+        scope: &str,
+        scope_id: &str,
+    ) -> Result<Vec<Self>, sqlx::Error> {
+        let report =
+            revoke_user_roles_report(pool, None, None, Some((scope, scope_id)), false).await?;
+        Ok(report.effects)
+    }
+}
+
+/// Backward-compatible global user roles view on top of scoped user_roles.
+#[derive(Debug, Clone, FromRow)]
+pub struct AccessRoleRow {
+    pub user_id: UserId,
+    pub role_name: String,
+}
 
 impl AccessRoleRow {
     pub fn new(user_id: UserId, role_name: &str) -> Self {
         Self {
-            user_id: user_id.0,
+            user_id,
             role_name: role_name.to_string(),
         }
     }
@@ -342,6 +1772,8 @@ impl AccessRoleRow {
             scope: GLOBAL_SCOPE.to_string(),
             scope_id: GLOBAL_SCOPE_ID.to_string(),
             role_name: row.role_name.clone(),
+            effect: RoleEffect::Allow,
+            condition: None,
         };
         UserRoleRow::allow(pool, &scoped).await
     }
@@ -352,6 +1784,8 @@ impl AccessRoleRow {
             scope: GLOBAL_SCOPE.to_string(),
             scope_id: GLOBAL_SCOPE_ID.to_string(),
             role_name: row.role_name.clone(),
+            effect: RoleEffect::Allow,
+            condition: None,
         };
         UserRoleRow::revoke(pool, &scoped).await
     }
@@ -364,86 +1798,126 @@ impl AccessRoleRow {
         UserRoleRow::has_role(pool, user_id, GLOBAL_SCOPE, GLOBAL_SCOPE_ID, role_name).await
     }
 
+    /// Unconditional allow grants held directly at the global scope id, including those pinned to
+    /// the current environment.
     pub async fn roles(pool: &PgPool, user_id: UserId) -> Result<Vec<Self>, sqlx::Error> {
-        let rows =
-            UserRoleRow::roles_in_scope(pool, user_id, GLOBAL_SCOPE, GLOBAL_SCOPE_ID).await?;
-        Ok(rows
-            .into_iter()
-            .map(|row| Self {
-                user_id: row.user_id,
-                role_name: row.role_name,
-            })
-            .collect())
+        sqlx::query_as::<_, AccessRoleRow>(
+            r#"
+            SELECT DISTINCT user_id, role_name
+            FROM auth.user_roles
+            WHERE user_id = $1
+              AND scope = ANY($2)
+              AND scope_id = $3
+              AND effect = 'allow'
+              AND condition IS NULL
+            ORDER BY role_name ASC
+            "#,
+        )
+        .bind(user_id)
+        .bind(lookup_scopes(GLOBAL_SCOPE))
+        .bind(GLOBAL_SCOPE_ID)
+        .fetch_all(pool)
+        .await
     }
 }
 
 #[derive(Debug, Clone, FromRow)]
 pub struct GroupRoleRow {
-    pub group_id: Uuid,
+    pub group_id: GroupId,
     pub scope: String,
     pub scope_id: String,
     pub role_name: String,
+    pub effect: RoleEffect,
+    /// A `policy::GrantCondition` limiting when the grant applies.
+    pub condition: Option<Value>,
 }
 
 impl GroupRoleRow {
     pub fn new(group_id: GroupId, scope: &str, scope_id: &str, role_name: &str) -> Self {
         Self {
-            group_id: group_id.0,
+            group_id,
             scope: scope.to_string(),
             scope_id: scope_id.to_string(),
             role_name: role_name.to_string(),
+            effect: RoleEffect::default(),
+            condition: None,
         }
     }
 
+    pub fn with_condition(mut self, condition: &GrantCondition) -> Self {
+        self.condition = Some(condition.to_value());
+        self
+    }
+
     pub fn table_name() -> &'static str {
         "auth.group_roles"
     }
 
     pub fn columns() -> &'static str {
-        "group_id, scope, scope_id, role_name"
+        group_role_columns!()
     }
 
+    /// Grant the role, replacing a deny for the same role and scope id. Ignores `row.effect`.
     pub async fn allow(pool: &PgPool, row: &GroupRoleRow) -> Result<(), sqlx::Error> {
-        sqlx::query(&format!(
+        Self::set_effect(pool, row, RoleEffect::Allow).await
+    }
+
+    /// Block the role in the scope id even if it is allowed elsewhere. Ignores `row.effect`.
+    pub async fn deny(pool: &PgPool, row: &GroupRoleRow) -> Result<(), sqlx::Error> {
+        Self::set_effect(pool, row, RoleEffect::Deny).await
+    }
+
+    async fn set_effect(
+        pool: &PgPool,
+        row: &GroupRoleRow,
+        effect: RoleEffect,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query(concat!(
+            "INSERT INTO auth.group_roles (",
+            group_role_columns!(),
+            ")",
             r#"
-            INSERT INTO {} ({})
-            VALUES ($1, $2, $3, $4)
-            ON CONFLICT (group_id, scope, scope_id, role_name) DO NOTHING
+            VALUES ($1, $2, $3, $4, $5, $6)
+            ON CONFLICT (group_id, scope, scope_id, role_name) DO UPDATE
+            SET effect = EXCLUDED.effect, condition = EXCLUDED.condition
             "#,
-            Self::table_name(),
-            Self::columns()
         ))
         .bind(row.group_id)
         .bind(&row.scope)
         .bind(&row.scope_id)
         .bind(&row.role_name)
+        .bind(effect)
+        .bind(&row.condition)
         .execute(pool)
         .await?;
+        RoleAssignmentTarget::Group(row.group_id).invalidate_role_snapshots();
 
         Ok(())
     }
 
     pub async fn revoke(pool: &PgPool, row: &GroupRoleRow) -> Result<(), sqlx::Error> {
-        sqlx::query(&format!(
+        sqlx::query(
             r#"
-            DELETE FROM {}
+            DELETE FROM auth.group_roles
             WHERE group_id = $1
               AND scope = $2
               AND scope_id = $3
               AND role_name = $4
             "#,
-            Self::table_name()
-        ))
+        )
         .bind(row.group_id)
         .bind(&row.scope)
         .bind(&row.scope_id)
         .bind(&row.role_name)
         .execute(pool)
         .await?;
+        RoleAssignmentTarget::Group(row.group_id).invalidate_role_snapshots();
 
         Ok(())
     }
 
+    /// Whether the group's grants allow the role in `scope_id`, including through a wildcard, and
+    /// none deny it.
     pub async fn has_role(
         pool: &PgPool,
         group_id: GroupId,
@@ -451,39 +1925,36 @@ impl GroupRoleRow {
         scope_id: &str,
         role_name: &str,
     ) -> Result<bool, sqlx::Error> {
-        let count: (i64,) = sqlx::query_as(&format!(
+        sqlx::query_scalar(concat!(
+            "SELECT ",
+            grant_decision!(),
             r#"
-            SELECT COUNT(*)
-            FROM {}
+            FROM auth.group_roles
             WHERE group_id = $1
-              AND scope = $2
-              AND scope_id = $3
+              AND scope = ANY($2)
               AND role_name = $4
             "#,
-            Self::table_name()
         ))
-        .bind(group_id.0)
-        .bind(scope)
+        .bind(group_id)
+        .bind(lookup_scopes(scope))
         .bind(scope_id)
         .bind(role_name)
+        .bind(GLOBAL_SCOPE_ID)
         .fetch_one(pool)
-        .await?;
-
-        Ok(count.0 > 0)
+        .await
     }
 
     pub async fn roles(pool: &PgPool, group_id: GroupId) -> Result<Vec<Self>, sqlx::Error> {
-        sqlx::query_as::<_, GroupRoleRow>(&format!(
+        sqlx::query_as::<_, GroupRoleRow>(concat!(
+            "SELECT ",
+            group_role_columns!(),
             r#"
-            SELECT {}
-            FROM {}
+            FROM auth.group_roles
             WHERE group_id = $1
             ORDER BY scope ASC, scope_id ASC, role_name ASC
             "#,
-            Self::columns(),
-            Self::table_name()
         ))
-        .bind(group_id.0)
+        .bind(group_id)
         .fetch_all(pool)
         .await
     }
@@ -494,24 +1965,57 @@ impl GroupRoleRow {
         scope: &str,
         scope_id: &str,
     ) -> Result<Vec<Self>, sqlx::Error> {
-        sqlx::query_as::<_, GroupRoleRow>(&format!(
+        sqlx::query_as::<_, GroupRoleRow>(concat!(
+            "SELECT ",
+            group_role_columns!(),
             r#"
-            SELECT {}
-            FROM {}
+            FROM auth.group_roles
             WHERE group_id = $1
               AND scope = $2
               AND scope_id = $3
             ORDER BY role_name ASC
             "#,
-            Self::columns(),
-            Self::table_name()
         ))
-        .bind(group_id.0)
+        .bind(group_id)
         .bind(scope)
         .bind(scope_id)
         .fetch_all(pool)
         .await
     }
+
+    /// Revoke every role the group holds in one scope. Returns the revoked grants.
+    pub async fn revoke_all_in_scope(
+        pool: &PgPool,
+        group_id: GroupId,
+        scope: &str,
+        scope_id: &str,
+    ) -> Result<Vec<Self>, sqlx::Error> {
+        let report =
+            revoke_group_roles_report(pool, None, Some(group_id), Some((scope, scope_id)), false)
+                .await?;
+        Ok(report.effects)
+    }
+
+    /// Revoke every role the group holds, in any scope. Returns the revoked grants.
+    pub async fn revoke_all_for_group(
+        pool: &PgPool,
+        group_id: GroupId,
+    ) -> Result<Vec<Self>, sqlx::Error> {
+        let report = revoke_group_roles_report(pool, None, Some(group_id), None, false).await?;
+        Ok(report.effects)
+    }
+
+    /// Revoke every group's roles in one scope, e.g. when the scope is deleted. Returns the
+    /// revoked grants.
+    pub async fn revoke_all_for_scope(
+        pool: &PgPool,
+        scope: &str,
+        scope_id: &str,
+    ) -> Result<Vec<Self>, sqlx::Error> {
+        let report =
+            revoke_group_roles_report(pool, None, None, Some((scope, scope_id)), false).await?;
+        Ok(report.effects)
+    }
 }
 
 #[derive(Debug, Clone, FromRow)]
@@ -537,18 +2041,18 @@ impl RoleDelegationPolicyRow {
     }
 
     pub fn columns() -> &'static str {
-        "scope, scope_id, admin_role, grantable_role"
+        role_delegation_policy_columns!()
     }
 
     pub async fn allow(pool: &PgPool, row: &RoleDelegationPolicyRow) -> Result<(), sqlx::Error> {
-        sqlx::query(&format!(
+        sqlx::query(concat!(
+            "INSERT INTO auth.role_delegation_policy (",
+            role_delegation_policy_columns!(),
+            ")",
             r#"
-            INSERT INTO {} ({})
             VALUES ($1, $2, $3, $4)
             ON CONFLICT (scope, scope_id, admin_role, grantable_role) DO NOTHING
             "#,
-            Self::table_name(),
-            Self::columns()
         ))
         .bind(&row.scope)
         .bind(&row.scope_id)
@@ -561,16 +2065,15 @@ impl RoleDelegationPolicyRow {
     }
 
     pub async fn revoke(pool: &PgPool, row: &RoleDelegationPolicyRow) -> Result<(), sqlx::Error> {
-        sqlx::query(&format!(
+        sqlx::query(
             r#"
-            DELETE FROM {}
+            DELETE FROM auth.role_delegation_policy
             WHERE scope = $1
               AND scope_id = $2
               AND admin_role = $3
               AND grantable_role = $4
             "#,
-            Self::table_name()
-        ))
+        )
         .bind(&row.scope)
         .bind(&row.scope_id)
         .bind(&row.admin_role)
@@ -587,17 +2090,16 @@ impl RoleDelegationPolicyRow {
         scope_id: &str,
         grantable_role: &str,
     ) -> Result<Vec<String>, sqlx::Error> {
-        let rows: Vec<(String,)> = sqlx::query_as(&format!(
+        let rows: Vec<(String,)> = sqlx::query_as(
             r#"
             SELECT DISTINCT admin_role
-            FROM {}
+            FROM auth.role_delegation_policy
             WHERE scope = $1
               AND grantable_role = $2
               AND (scope_id = $3 OR scope_id = $4)
             ORDER BY admin_role ASC
             "#,
-            Self::table_name()
-        ))
+        )
         .bind(scope)
         .bind(grantable_role)
         .bind(scope_id)
@@ -609,7 +2111,8 @@ impl RoleDelegationPolicyRow {
     }
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(tag = "target_type", content = "target_id", rename_all = "lowercase")]
 pub enum RoleAssignmentTarget {
     User(UserId),
     Group(GroupId),
@@ -629,6 +2132,17 @@ impl RoleAssignmentTarget {
             Self::Group(group_id) => group_id.to_string(),
         }
     }
+
+    /// Discard the session role snapshots a change to the target's grants makes stale: the
+    /// user's, or for a group, everyone's.
+    #[cfg_attr(not(feature = "api"), allow(unused_variables))]
+    fn invalidate_role_snapshots(self) {
+        #[cfg(feature = "api")]
+        match self {
+            Self::User(user_id) => crate::guard::invalidate_role_snapshots(user_id),
+            Self::Group(_) => crate::guard::invalidate_all_role_snapshots(),
+        }
+    }
 }
 
 pub async fn is_super_admin(pool: &PgPool, user_id: UserId) -> Result<bool, sqlx::Error> {
@@ -655,6 +2169,8 @@ pub async fn user_is_group_admin_for_scope(
     }
 }
 
+/// Whether the role is allowed in `scope_id`, directly or through a group, and not denied there
+/// or at the global id.
 pub async fn user_has_effective_role(
     pool: &PgPool,
     user_id: UserId,
@@ -662,30 +2178,44 @@ pub async fn user_has_effective_role(
     scope_id: &str,
     role_name: &str,
 ) -> Result<bool, sqlx::Error> {
-    if UserRoleRow::has_role(pool, user_id, scope, scope_id, role_name).await? {
-        return Ok(true);
-    }
-
-    let count: (i64,) = sqlx::query_as(
-        r#"
-        SELECT COUNT(*)
-        FROM auth.group_memberships gm
-        JOIN auth.group_roles gr
-          ON gr.group_id = gm.group_id
-        WHERE gm.user_id = $1
-          AND gr.scope = $2
-          AND gr.scope_id = $3
-          AND gr.role_name = $4
-        "#,
-    )
-    .bind(user_id.0)
-    .bind(scope)
+    sqlx::query_scalar(concat!(
+        "SELECT ",
+        grant_decision!(),
+        " FROM (",
+        effective_grants!(),
+        ") AS grants",
+    ))
+    .bind(user_id)
+    .bind(lookup_scopes(scope))
     .bind(scope_id)
     .bind(role_name)
+    .bind(GLOBAL_SCOPE_ID)
     .fetch_one(pool)
-    .await?;
+    .await
+}
 
-    Ok(count.0 > 0)
+/// Whether a deny grant, direct or through a group, blocks the role in `scope_id`.
+pub async fn user_is_denied_role(
+    pool: &PgPool,
+    user_id: UserId,
+    scope: &str,
+    scope_id: &str,
+    role_name: &str,
+) -> Result<bool, sqlx::Error> {
+    sqlx::query_scalar(concat!(
+        "SELECT COALESCE(bool_or(effect = 'deny' AND (scope_id = $5 OR ",
+        scope_id_matches!("scope_id", "$3"),
+        ")), FALSE) FROM (",
+        effective_grants!(),
+        ") AS grants",
+    ))
+    .bind(user_id)
+    .bind(lookup_scopes(scope))
+    .bind(scope_id)
+    .bind(role_name)
+    .bind(GLOBAL_SCOPE_ID)
+    .fetch_one(pool)
+    .await
 }
 
 pub async fn can_manage_role_assignment(
@@ -714,6 +2244,9 @@ pub async fn can_manage_role_assignment(
     }
 
     for admin_role in admin_roles {
+        if user_is_denied_role(pool, actor_user_id, scope, scope_id, &admin_role).await? {
+            continue;
+        }
         if user_has_effective_role(pool, actor_user_id, scope, scope_id, &admin_role).await?
             || user_has_effective_role(pool, actor_user_id, scope, GLOBAL_SCOPE_ID, &admin_role)
                 .await?
@@ -736,6 +2269,10 @@ pub async fn user_has_effective_access(
         return Ok(true);
     }
 
+    if user_is_denied_role(pool, user_id, scope, scope_id, role_name).await? {
+        return Ok(false);
+    }
+
     if scope != GLOBAL_SCOPE
         && scope_id != GLOBAL_SCOPE_ID
         && user_is_group_admin_for_scope(pool, user_id, scope_id).await?
@@ -750,6 +2287,332 @@ pub async fn user_has_effective_access(
     user_has_effective_role(pool, user_id, scope, GLOBAL_SCOPE_ID, role_name).await
 }
 
+/// `user_has_effective_access` with conditional grants evaluated against the request.
+///
+/// Also honors a conditional super_admin grant, which the context-free checks ignore.
+pub async fn user_has_effective_access_in_context(
+    pool: &PgPool,
+    user_id: UserId,
+    scope: &str,
+    scope_id: &str,
+    role_name: &str,
+    context: &RequestContext,
+) -> Result<bool, sqlx::Error> {
+    let roles = effective_roles(pool, user_id).await?;
+    if roles_allow(
+        &roles,
+        GLOBAL_SCOPE,
+        GLOBAL_SCOPE_ID,
+        SUPER_ADMIN_ROLE,
+        Some(context),
+    ) {
+        return Ok(true);
+    }
+
+    if roles_deny(&roles, scope, scope_id, role_name, Some(context)) {
+        return Ok(false);
+    }
+
+    if scope != GLOBAL_SCOPE
+        && scope_id != GLOBAL_SCOPE_ID
+        && user_is_group_admin_for_scope(pool, user_id, scope_id).await?
+    {
+        return Ok(true);
+    }
+
+    Ok(roles_allow(
+        &roles,
+        scope,
+        scope_id,
+        role_name,
+        Some(context),
+    ))
+}
+
+/// Where a grant examined by `explain_effective_access` comes from.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum GrantSource {
+    /// Held by the user directly.
+    User,
+    /// Inherited through membership in a group.
+    Group {
+        group_id: Uuid,
+        display_name: String,
+        member_role: String,
+    },
+}
+
+/// One grant considered while explaining an access decision.
+#[derive(Debug, Clone, Serialize)]
+pub struct ExplainedGrant {
+    pub source: GrantSource,
+    pub scope: String,
+    pub scope_id: String,
+    pub role_name: String,
+    pub effect: RoleEffect,
+    pub condition: Option<Value>,
+    /// Whether the grant's scope id covers the requested one, exactly, by wildcard, or as the
+    /// global id.
+    pub covers: bool,
+    /// Whether the grant's condition holds, or it has none.
+    pub applies: bool,
+}
+
+/// Why `explain_effective_access` reached its decision, in evaluation order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AccessDecisionReason {
+    SuperAdmin,
+    Denied,
+    GroupAdmin,
+    Allowed,
+    NoMatchingGrant,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct AccessExplanation {
+    pub allowed: bool,
+    pub reason: AccessDecisionReason,
+    /// Grants of the requested role in the requested scope, plus any super_admin grants.
+    pub grants: Vec<ExplainedGrant>,
+}
+
+#[derive(Debug, Clone, FromRow)]
+struct SourcedGrantRow {
+    group_id: Option<Uuid>,
+    display_name: Option<String>,
+    member_role: Option<String>,
+    scope: String,
+    scope_id: String,
+    role_name: String,
+    effect: RoleEffect,
+    condition: Option<Value>,
+}
+
+/// Explain `user_has_effective_access` (without a context) or
+/// `user_has_effective_access_in_context`: the decision, the rule that settled it, and every
+/// grant that was considered with where it came from.
+pub async fn explain_effective_access(
+    pool: &PgPool,
+    user_id: UserId,
+    scope: &str,
+    scope_id: &str,
+    role_name: &str,
+    context: Option<&RequestContext>,
+) -> Result<AccessExplanation, sqlx::Error> {
+    let rows = sqlx::query_as::<_, SourcedGrantRow>(
+        r#"
+        SELECT NULL::UUID AS group_id, NULL AS display_name, NULL AS member_role,
+               scope, scope_id, role_name, effect, condition
+        FROM auth.user_roles
+        WHERE user_id = $1
+          AND ((scope = ANY($2) AND role_name = $3)
+               OR (scope = ANY($4) AND scope_id = $6 AND role_name = $5))
+        UNION ALL
+        SELECT g.id, g.display_name, gm.role_name,
+               gr.scope, gr.scope_id, gr.role_name, gr.effect, gr.condition
+        FROM auth.group_memberships gm
+        JOIN auth.groups g
+          ON g.id = gm.group_id
+         AND g.deleted_at IS NULL
+        JOIN auth.group_roles gr
+          ON gr.group_id = gm.group_id
+        WHERE gm.user_id = $1
+          AND ((gr.scope = ANY($2) AND gr.role_name = $3)
+               OR (gr.scope = ANY($4) AND gr.scope_id = $6 AND gr.role_name = $5))
+        ORDER BY scope ASC, scope_id ASC, role_name ASC
+        "#,
+    )
+    .bind(user_id)
+    .bind(lookup_scopes(scope))
+    .bind(role_name)
+    .bind(lookup_scopes(GLOBAL_SCOPE))
+    .bind(SUPER_ADMIN_ROLE)
+    .bind(GLOBAL_SCOPE_ID)
+    .fetch_all(pool)
+    .await?;
+
+    // Pinned grants are judged under the scope they are pinned in; `grants` keeps the stored one.
+    let environment = environment();
+    let roles: Vec<EffectiveRole> = rows
+        .iter()
+        .map(|row| EffectiveRole {
+            scope: resolve_scope_for(environment.as_ref(), &row.scope)
+                .unwrap_or(&row.scope)
+                .to_string(),
+            scope_id: row.scope_id.clone(),
+            role_name: row.role_name.clone(),
+            effect: row.effect,
+            condition: row.condition.clone(),
+        })
+        .collect();
+    let grants = rows
+        .into_iter()
+        .zip(&roles)
+        .map(|(row, role)| {
+            let requested_id = if row.role_name == SUPER_ADMIN_ROLE && role.scope == GLOBAL_SCOPE {
+                GLOBAL_SCOPE_ID
+            } else {
+                scope_id
+            };
+            ExplainedGrant {
+                source: match (row.group_id, row.display_name, row.member_role) {
+                    (Some(group_id), Some(display_name), Some(member_role)) => GrantSource::Group {
+                        group_id,
+                        display_name,
+                        member_role,
+                    },
+                    _ => GrantSource::User,
+                },
+                covers: scope_id_matches(&row.scope_id, requested_id)
+                    || row.scope_id == GLOBAL_SCOPE_ID,
+                applies: role.applies(context),
+                scope: row.scope,
+                scope_id: row.scope_id,
+                role_name: row.role_name,
+                effect: row.effect,
+                condition: row.condition,
+            }
+        })
+        .collect();
+
+    let reason = if roles_allow(
+        &roles,
+        GLOBAL_SCOPE,
+        GLOBAL_SCOPE_ID,
+        SUPER_ADMIN_ROLE,
+        context,
+    ) {
+        AccessDecisionReason::SuperAdmin
+    } else if roles_deny(&roles, scope, scope_id, role_name, context) {
+        AccessDecisionReason::Denied
+    } else if scope != GLOBAL_SCOPE
+        && scope_id != GLOBAL_SCOPE_ID
+        && user_is_group_admin_for_scope(pool, user_id, scope_id).await?
+    {
+        AccessDecisionReason::GroupAdmin
+    } else if roles_allow(&roles, scope, scope_id, role_name, context) {
+        AccessDecisionReason::Allowed
+    } else {
+        AccessDecisionReason::NoMatchingGrant
+    };
+
+    Ok(AccessExplanation {
+        allowed: !matches!(
+            reason,
+            AccessDecisionReason::Denied | AccessDecisionReason::NoMatchingGrant
+        ),
+        reason,
+        grants,
+    })
+}
+
+/// A role grant that applies to the user directly or through a group membership.
+#[derive(Debug, Clone, PartialEq, Eq, FromRow, Serialize, Deserialize)]
+pub struct EffectiveRole {
+    pub scope: String,
+    pub scope_id: String,
+    pub role_name: String,
+    #[serde(default)]
+    pub effect: RoleEffect,
+    #[serde(default)]
+    pub condition: Option<Value>,
+}
+
+impl EffectiveRole {
+    /// Whether the grant applies to the request. Unconditional grants always do. Without a
+    /// context, or with a condition that no longer parses, conditional denies apply and
+    /// conditional allows do not.
+    pub fn applies(&self, context: Option<&RequestContext>) -> bool {
+        let Some(condition) = &self.condition else {
+            return true;
+        };
+        match (GrantCondition::parse(condition), context) {
+            (Ok(condition), Some(context)) => condition.evaluate(context),
+            _ => self.effect == RoleEffect::Deny,
+        }
+    }
+}
+
+/// Whether a deny among `roles` blocks the role in `scope_id`, directly, by wildcard, or at the
+/// global id of the scope.
+pub fn roles_deny(
+    roles: &[EffectiveRole],
+    scope: &str,
+    scope_id: &str,
+    role_name: &str,
+    context: Option<&RequestContext>,
+) -> bool {
+    roles.iter().any(|role| {
+        role.effect == RoleEffect::Deny
+            && role.scope == scope
+            && role.role_name == role_name
+            && (scope_id_matches(&role.scope_id, scope_id) || role.scope_id == GLOBAL_SCOPE_ID)
+            && role.applies(context)
+    })
+}
+
+/// Whether `roles` allow the role in `scope_id`, directly, by wildcard, or at the global id of
+/// the scope, and no deny blocks it.
+pub fn roles_allow(
+    roles: &[EffectiveRole],
+    scope: &str,
+    scope_id: &str,
+    role_name: &str,
+    context: Option<&RequestContext>,
+) -> bool {
+    !roles_deny(roles, scope, scope_id, role_name, context)
+        && roles.iter().any(|role| {
+            role.effect == RoleEffect::Allow
+                && role.scope == scope
+                && role.role_name == role_name
+                && (scope_id_matches(&role.scope_id, scope_id) || role.scope_id == GLOBAL_SCOPE_ID)
+                && role.applies(context)
+        })
+}
+
+/// The user's grants that count in the current environment, with pinned scopes resolved to the
+/// scope they are pinned in. See `environment`.
+pub async fn effective_roles(
+    pool: &PgPool,
+    user_id: UserId,
+) -> Result<Vec<EffectiveRole>, sqlx::Error> {
+    let roles = sqlx::query_as::<_, EffectiveRole>(
+        r#"
+        SELECT scope, scope_id, role_name, effect, condition
+        FROM auth.user_roles
+        WHERE user_id = $1
+        UNION
+        SELECT gr.scope, gr.scope_id, gr.role_name, gr.effect, gr.condition
+        FROM auth.group_memberships gm
+        JOIN auth.groups g
+          ON g.id = gm.group_id
+         AND g.deleted_at IS NULL
+        JOIN auth.group_roles gr
+          ON gr.group_id = gm.group_id
+        WHERE gm.user_id = $1
+        ORDER BY scope ASC, scope_id ASC, role_name ASC
+        "#,
+    )
+    .bind(user_id)
+    .fetch_all(pool)
+    .await?;
+    Ok(resolve_environment(roles))
+}
+
+/// Drop grants pinned to other environments and resolve pinned scopes, as `effective_roles`.
+pub(crate) fn resolve_environment(roles: Vec<EffectiveRole>) -> Vec<EffectiveRole> {
+    let environment = environment();
+    roles
+        .into_iter()
+        .filter_map(|mut role| {
+            role.scope = resolve_scope_for(environment.as_ref(), &role.scope)?.to_string();
+            Some(role)
+        })
+        .collect()
+}
+
 pub async fn grant_role_assignment_with_audit(
     pool: &PgPool,
     actor_user_id: UserId,
@@ -758,20 +2621,79 @@ pub async fn grant_role_assignment_with_audit(
     scope_id: &str,
     role_name: &str,
 ) -> Result<bool, sqlx::Error> {
+    set_role_assignment_with_audit(
+        pool,
+        actor_user_id,
+        target,
+        scope,
+        scope_id,
+        role_name,
+        RoleEffect::Allow,
+        None,
+    )
+    .await
+}
+
+/// Block a role for the target in a scope id, replacing an allow grant for the same role.
+pub async fn deny_role_assignment_with_audit(
+    pool: &PgPool,
+    actor_user_id: UserId,
+    target: RoleAssignmentTarget,
+    scope: &str,
+    scope_id: &str,
+    role_name: &str,
+) -> Result<bool, sqlx::Error> {
+    set_role_assignment_with_audit(
+        pool,
+        actor_user_id,
+        target,
+        scope,
+        scope_id,
+        role_name,
+        RoleEffect::Deny,
+        None,
+    )
+    .await
+}
+
+/// Grant or deny a role for the target, optionally under a condition, replacing an existing grant
+/// for the same role and scope id. Returns false if an identical grant already existed.
+#[allow(clippy::too_many_arguments)]
+pub async fn set_role_assignment_with_audit(
+    pool: &PgPool,
+    actor_user_id: UserId,
+    target: RoleAssignmentTarget,
+    scope: &str,
+    scope_id: &str,
+    role_name: &str,
+    effect: RoleEffect,
+    condition: Option<&GrantCondition>,
+) -> Result<bool, sqlx::Error> {
+    let condition = condition.map(GrantCondition::to_value);
     let mut tx = pool.begin().await?;
     let changed = match target {
         RoleAssignmentTarget::User(user_id) => {
             let result = sqlx::query(
                 r#"
-                INSERT INTO auth.user_roles (user_id, scope, scope_id, role_name)
-                VALUES ($1, $2, $3, $4)
-                ON CONFLICT (user_id, scope, scope_id, role_name) DO NOTHING
+                INSERT INTO auth.user_roles (user_id, scope, scope_id, role_name, effect, condition)
+                VALUES ($1, $2, $3, $4, $5, $6)
+                ON CONFLICT (user_id, scope, scope_id, role_name) DO UPDATE
+                SET effect = EXCLUDED.effect,
+                    condition = EXCLUDED.condition,
+                    source_group_id = NULL,
+                    source_provider = NULL
+                WHERE auth.user_roles.effect <> EXCLUDED.effect
+                   OR auth.user_roles.condition IS DISTINCT FROM EXCLUDED.condition
+                   OR auth.user_roles.source_group_id IS NOT NULL
+                   OR auth.user_roles.source_provider IS NOT NULL
                 "#,
             )
-            .bind(user_id.0)
+            .bind(user_id)
             .bind(scope)
             .bind(scope_id)
             .bind(role_name)
+            .bind(effect)
+            .bind(&condition)
             .execute(&mut *tx)
             .await?;
             result.rows_affected() > 0
@@ -779,15 +2701,20 @@ pub async fn grant_role_assignment_with_audit(
         RoleAssignmentTarget::Group(group_id) => {
             let result = sqlx::query(
                 r#"
-                INSERT INTO auth.group_roles (group_id, scope, scope_id, role_name)
-                VALUES ($1, $2, $3, $4)
-                ON CONFLICT (group_id, scope, scope_id, role_name) DO NOTHING
+                INSERT INTO auth.group_roles (group_id, scope, scope_id, role_name, effect, condition)
+                VALUES ($1, $2, $3, $4, $5, $6)
+                ON CONFLICT (group_id, scope, scope_id, role_name) DO UPDATE
+                SET effect = EXCLUDED.effect, condition = EXCLUDED.condition
+                WHERE auth.group_roles.effect <> EXCLUDED.effect
+                   OR auth.group_roles.condition IS DISTINCT FROM EXCLUDED.condition
                 "#,
             )
-            .bind(group_id.0)
+            .bind(group_id)
             .bind(scope)
             .bind(scope_id)
             .bind(role_name)
+            .bind(effect)
+            .bind(&condition)
             .execute(&mut *tx)
             .await?;
             result.rows_affected() > 0
@@ -797,17 +2724,26 @@ pub async fn grant_role_assignment_with_audit(
     if changed {
         insert_role_audit_log(
             &mut tx,
-            actor_user_id,
-            "role_grant",
+            Some(actor_user_id),
+            match effect {
+                RoleEffect::Allow => "role_grant",
+                RoleEffect::Deny => "role_deny",
+            },
             target,
             scope,
             scope_id,
             role_name,
+            condition.as_ref(),
         )
         .await?;
     }
 
     tx.commit().await?;
+    // Any change can narrow access, e.g. a grant replacing an unconditional allow with a
+    // conditional one.
+    if changed {
+        target.invalidate_role_snapshots();
+    }
     Ok(changed)
 }
 
@@ -831,7 +2767,7 @@ pub async fn revoke_role_assignment_with_audit(
                   AND role_name = $4
                 "#,
             )
-            .bind(user_id.0)
+            .bind(user_id)
             .bind(scope)
             .bind(scope_id)
             .bind(role_name)
@@ -849,7 +2785,7 @@ pub async fn revoke_role_assignment_with_audit(
                   AND role_name = $4
                 "#,
             )
-            .bind(group_id.0)
+            .bind(group_id)
             .bind(scope)
             .bind(scope_id)
             .bind(role_name)
@@ -862,178 +2798,1534 @@ pub async fn revoke_role_assignment_with_audit(
     if changed {
         insert_role_audit_log(
             &mut tx,
-            actor_user_id,
+            Some(actor_user_id),
             "role_revoke",
             target,
             scope,
             scope_id,
             role_name,
+            None,
         )
         .await?;
     }
 
     tx.commit().await?;
+    if changed {
+        target.invalidate_role_snapshots();
+    }
     Ok(changed)
 }
 
-async fn insert_role_audit_log(
-    tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
-    actor_user_id: UserId,
+/// Append `action` to `auth.log` under `user_id`, tagged with the current request id.
+pub(crate) async fn insert_audit_log<'e, E>(
+    executor: E,
+    user_id: Option<UserId>,
+    action: Value,
+) -> Result<(), sqlx::Error>
+where
+    E: sqlx::PgExecutor<'e>,
+{
+    sqlx::query(
+        r#"
+        INSERT INTO auth.log (id, user_id, action, timestamp, request_id)
+        VALUES ($1, $2, $3, $4, $5)
+        "#,
+    )
+    .bind(new_uuid())
+    .bind(user_id)
+    .bind(action)
+    .bind(chrono::Utc::now().naive_utc())
+    .bind(current_request_id())
+    .execute(executor)
+    .await?;
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn insert_role_audit_log(
+    conn: &mut PgConnection,
+    actor_user_id: Option<UserId>,
     action_type: &str,
     target: RoleAssignmentTarget,
     scope: &str,
     scope_id: &str,
     role_name: &str,
+    condition: Option<&Value>,
 ) -> Result<(), sqlx::Error> {
-    let action = json!({
+    let mut action = json!({
         "type": action_type,
-        "actor_user_id": actor_user_id.to_string(),
+        "actor_user_id": actor_user_id.map(|id| id.to_string()),
         "target_type": target.target_type(),
         "target_id": target.target_id(),
         "scope": scope,
         "scope_id": scope_id,
         "role_name": role_name,
     });
+    if let Some(condition) = condition {
+        action["condition"] = condition.clone();
+    }
+    // Revocations without an actor (maintenance jobs) are filed under the affected user.
+    let log_user_id = actor_user_id.or(match target {
+        RoleAssignmentTarget::User(user_id) => Some(user_id),
+        RoleAssignmentTarget::Group(_) => None,
+    });
+    insert_audit_log(conn, log_user_id, action).await
+}
 
-    sqlx::query(
+/// Revoke user role grants in bulk, writing a `role_revoke` audit entry for each.
+///
+/// `user_id` and `scope` (`(scope, scope_id)`) narrow what is revoked; with neither, nothing is.
+/// `actor_user_id` is `None` for maintenance jobs. Unless this is a dry run, the affected users'
+/// session role snapshots are discarded.
+pub async fn revoke_user_roles_report(
+    pool: &PgPool,
+    actor_user_id: Option<UserId>,
+    user_id: Option<UserId>,
+    scope: Option<(&str, &str)>,
+    dry_run: bool,
+) -> Result<BulkReport<Vec<UserRoleRow>>, sqlx::Error> {
+    if user_id.is_none() && scope.is_none() {
+        return Ok(BulkReport {
+            dry_run,
+            effects: Vec::new(),
+        });
+    }
+    let mut tx = pool.begin().await?;
+    let revoked = sqlx::query_as::<_, UserRoleRow>(concat!(
         r#"
-        INSERT INTO auth.log (id, user_id, action, timestamp)
-        VALUES ($1, $2, $3, $4)
+        DELETE FROM auth.user_roles
+        WHERE ($1::UUID IS NULL OR user_id = $1)
+          AND ($2::TEXT IS NULL OR (scope = $2 AND scope_id = $3))
+        RETURNING "#,
+        user_role_columns!(),
+    ))
+    .bind(user_id)
+    .bind(scope.map(|(scope, _)| scope))
+    .bind(scope.map(|(_, scope_id)| scope_id))
+    .fetch_all(&mut *tx)
+    .await?;
+
+    for row in &revoked {
+        insert_role_audit_log(
+            &mut tx,
+            actor_user_id,
+            "role_revoke",
+            RoleAssignmentTarget::User(row.user_id),
+            &row.scope,
+            &row.scope_id,
+            &row.role_name,
+            None,
+        )
+        .await?;
+    }
+    let report = finish_bulk(tx, dry_run, revoked).await?;
+    if !dry_run {
+        for row in &report.effects {
+            RoleAssignmentTarget::User(row.user_id).invalidate_role_snapshots();
+        }
+    }
+    Ok(report)
+}
+
+/// Revoke group role grants in bulk, writing a `role_revoke` audit entry for each.
+///
+/// `group_id` and `scope` (`(scope, scope_id)`) narrow what is revoked; with neither, nothing is.
+/// Unless this is a dry run, any revocation discards every session role snapshot.
+pub async fn revoke_group_roles_report(
+    pool: &PgPool,
+    actor_user_id: Option<UserId>,
+    group_id: Option<GroupId>,
+    scope: Option<(&str, &str)>,
+    dry_run: bool,
+) -> Result<BulkReport<Vec<GroupRoleRow>>, sqlx::Error> {
+    if group_id.is_none() && scope.is_none() {
+        return Ok(BulkReport {
+            dry_run,
+            effects: Vec::new(),
+        });
+    }
+    let mut tx = pool.begin().await?;
+    let revoked = sqlx::query_as::<_, GroupRoleRow>(concat!(
+        r#"
+        DELETE FROM auth.group_roles
+        WHERE ($1::UUID IS NULL OR group_id = $1)
+          AND ($2::TEXT IS NULL OR (scope = $2 AND scope_id = $3))
+        RETURNING "#,
+        group_role_columns!(),
+    ))
+    .bind(group_id)
+    .bind(scope.map(|(scope, _)| scope))
+    .bind(scope.map(|(_, scope_id)| scope_id))
+    .fetch_all(&mut *tx)
+    .await?;
+
+    for row in &revoked {
+        insert_role_audit_log(
+            &mut tx,
+            actor_user_id,
+            "role_revoke",
+            RoleAssignmentTarget::Group(row.group_id),
+            &row.scope,
+            &row.scope_id,
+            &row.role_name,
+            None,
+        )
+        .await?;
+    }
+    let report = finish_bulk(tx, dry_run, revoked).await?;
+    if let (false, Some(row)) = (dry_run, report.effects.first()) {
+        RoleAssignmentTarget::Group(row.group_id).invalidate_role_snapshots();
+    }
+    Ok(report)
+}
+
+/// Outcome of a bulk operation. For a dry run the changes were made inside a transaction that was
+/// then rolled back, so `effects` describes what would have happened.
+#[derive(Debug, Clone, Serialize)]
+pub struct BulkReport<T> {
+    pub dry_run: bool,
+    pub effects: T,
+}
+
+/// Commit a bulk operation's transaction, or roll it back for a dry run.
+pub async fn finish_bulk<T>(
+    tx: sqlx::Transaction<'_, sqlx::Postgres>,
+    dry_run: bool,
+    effects: T,
+) -> Result<BulkReport<T>, sqlx::Error> {
+    if dry_run {
+        tx.rollback().await?;
+    } else {
+        tx.commit().await?;
+    }
+    Ok(BulkReport { dry_run, effects })
+}
+
+/// Deactivate every user with no login since `cutoff`, writing an audit entry for each and
+/// revoking their remember-me tokens.
+///
+/// Returns the deactivated user ids so callers can emit events.
+pub async fn deactivate_dormant(
+    pool: &PgPool,
+    cutoff: chrono::NaiveDateTime,
+) -> Result<Vec<UserId>, sqlx::Error> {
+    Ok(deactivate_dormant_report(pool, cutoff, false)
+        .await?
+        .effects)
+}
+
+/// `deactivate_dormant`, optionally as a dry run that reports the users it would deactivate.
+pub async fn deactivate_dormant_report(
+    pool: &PgPool,
+    cutoff: chrono::NaiveDateTime,
+    dry_run: bool,
+) -> Result<BulkReport<Vec<UserId>>, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    let deactivated: Vec<(UserId,)> = sqlx::query_as(
+        r#"
+        UPDATE auth.users
+        SET active = FALSE,
+            deactivated_at = CURRENT_TIMESTAMP
+        WHERE active = TRUE
+          AND COALESCE(last_login_at, created_at) < $1
+        RETURNING id
         "#,
     )
-    .bind(Uuid::new_v4())
-    .bind(Some(actor_user_id.0))
-    .bind(action)
-    .bind(chrono::Utc::now().naive_utc())
-    .execute(&mut **tx)
+    .bind(cutoff)
+    .fetch_all(&mut *tx)
     .await?;
 
-    Ok(())
+    for (user_id,) in &deactivated {
+        insert_audit_log(
+            &mut *tx,
+            Some(*user_id),
+            json!({
+                "type": "user_dormant_deactivated",
+                "user_id": user_id.to_string(),
+                "cutoff": cutoff,
+            }),
+        )
+        .await?;
+        RememberedSession::revoke_all_in(&mut tx, *user_id).await?;
+    }
+
+    let deactivated = deactivated.into_iter().map(|(id,)| id).collect();
+    finish_bulk(tx, dry_run, deactivated).await
+}
+
+/// Who can find and join a group without an invitation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize, sqlx::Type)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(type_name = "TEXT", rename_all = "lowercase")]
+pub enum GroupVisibility {
+    /// Hidden from discovery; members are added by admins.
+    #[default]
+    Private,
+    /// Listed in discovery; joining requires admin approval.
+    Discoverable,
+    /// Listed in discovery; anyone can join immediately.
+    Open,
 }
 
 #[derive(Debug, Clone, FromRow)]
 pub struct GroupRow {
-    pub id: Uuid,
+    pub id: GroupId,
     pub display_name: String,
     pub details: Option<Value>,
+    pub version: i64,
+    pub visibility: GroupVisibility,
+    pub external_id: Option<String>,
+    /// Set while the group is archived; see `archival`.
+    pub archived_at: Option<chrono::NaiveDateTime>,
 }
 
 impl GroupRow {
-    pub fn new(id: Uuid, details: Option<Value>, display_name: &str) -> Self {
+    pub fn new(id: GroupId, details: Option<Value>, display_name: &str) -> Self {
         Self {
             id,
             display_name: display_name.to_string(),
             details,
+            version: 1,
+            visibility: GroupVisibility::default(),
+            external_id: Some(DEFAULT_EXTERNAL_ID_GENERATOR.generate()),
+            archived_at: None,
         }
     }
 
+    pub fn with_visibility(mut self, visibility: GroupVisibility) -> Self {
+        self.visibility = visibility;
+        self
+    }
+
+    pub fn with_external_id(mut self, generator: &ExternalIdGenerator) -> Self {
+        self.external_id = Some(generator.generate());
+        self
+    }
+
     pub fn table_name() -> &'static str {
         "auth.groups"
     }
 
     pub fn columns() -> &'static str {
-        "id, display_name, details"
+        group_columns!()
     }
 
     pub async fn insert(pool: &PgPool, row: &GroupRow) -> Result<(), sqlx::Error> {
-        sqlx::query(&format!(
+        let mut conn = pool.acquire().await?;
+        Self::insert_in(&mut conn, row).await
+    }
+
+    pub(crate) async fn insert_in(
+        conn: &mut PgConnection,
+        row: &GroupRow,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query(concat!(
+            "INSERT INTO auth.groups (",
+            group_columns!(),
+            ")",
             r#"
-            INSERT INTO {} ({})
-            VALUES ($1, $2, $3)
+            VALUES ($1, $2, $3, $4, $5, $6, $7)
             "#,
-            Self::table_name(),
-            Self::columns()
         ))
         .bind(row.id)
         .bind(&row.display_name)
         .bind(&row.details)
-        .execute(pool)
+        .bind(row.version)
+        .bind(row.visibility)
+        .bind(&row.external_id)
+        .bind(row.archived_at)
+        .execute(&mut *conn)
         .await?;
 
         Ok(())
     }
 
+    /// The group, unless it is soft-deleted; see `recycle_bin`.
     pub async fn get(pool: &PgPool, group_id: GroupId) -> Result<Option<Self>, sqlx::Error> {
-        sqlx::query_as::<_, GroupRow>(&format!(
+        Self::lookup(pool, group_id, false).await
+    }
+
+    /// `get`, including soft-deleted groups.
+    pub(crate) async fn get_including_deleted(
+        pool: &PgPool,
+        group_id: GroupId,
+    ) -> Result<Option<Self>, sqlx::Error> {
+        Self::lookup(pool, group_id, true).await
+    }
+
+    async fn lookup(
+        pool: &PgPool,
+        group_id: GroupId,
+        include_deleted: bool,
+    ) -> Result<Option<Self>, sqlx::Error> {
+        sqlx::query_as::<_, GroupRow>(concat!(
+            "SELECT ",
+            group_columns!(),
+            r#"
+            FROM auth.groups
+            WHERE id = $1
+              AND ($2 OR deleted_at IS NULL)
+            LIMIT 1
+            "#,
+        ))
+        .bind(group_id)
+        .bind(include_deleted)
+        .fetch_optional(pool)
+        .await
+    }
+
+    /// An active group that is not private, i.e. one users may ask to join.
+    pub async fn get_joinable(
+        pool: &PgPool,
+        group_id: GroupId,
+    ) -> Result<Option<Self>, sqlx::Error> {
+        sqlx::query_as::<_, GroupRow>(concat!(
+            "SELECT ",
+            group_columns!(),
             r#"
-            SELECT {}
-            FROM {}
+            FROM auth.groups
             WHERE id = $1
+              AND active = TRUE
+              AND visibility <> 'private'
+            LIMIT 1
+            "#,
+        ))
+        .bind(group_id)
+        .fetch_optional(pool)
+        .await
+    }
+
+    /// Active discoverable and open groups, optionally filtered by a case-insensitive name match.
+    pub async fn discoverable(
+        pool: &PgPool,
+        search: Option<&str>,
+        archived: ArchivedFilter,
+        page: Option<(i64, i64)>,
+    ) -> Result<Vec<Self>, sqlx::Error> {
+        let (limit, offset) = match page {
+            Some((limit, offset)) => (Some(limit), offset),
+            None => (None, 0),
+        };
+        sqlx::query_as::<_, GroupRow>(concat!(
+            "SELECT ",
+            group_columns!(),
+            r#"
+            FROM auth.groups
+            WHERE active = TRUE
+              AND visibility <> 'private'
+              AND ($1::TEXT IS NULL OR display_name ILIKE '%' || $1 || '%')
+              AND ($4::BOOLEAN IS NULL OR (archived_at IS NOT NULL) = $4)
+            ORDER BY display_name ASC
+            LIMIT $2 OFFSET $3
+            "#,
+        ))
+        .bind(search)
+        .bind(limit)
+        .bind(offset)
+        .bind(archived.archived())
+        .fetch_all(pool)
+        .await
+    }
+
+    pub async fn get_by_external_id(
+        pool: &PgPool,
+        external_id: &str,
+    ) -> Result<Option<Self>, sqlx::Error> {
+        Self::lookup_by_external_id(pool, external_id, false).await
+    }
+
+    /// `get_by_external_id`, including soft-deleted groups.
+    pub(crate) async fn get_by_external_id_including_deleted(
+        pool: &PgPool,
+        external_id: &str,
+    ) -> Result<Option<Self>, sqlx::Error> {
+        Self::lookup_by_external_id(pool, external_id, true).await
+    }
+
+    async fn lookup_by_external_id(
+        pool: &PgPool,
+        external_id: &str,
+        include_deleted: bool,
+    ) -> Result<Option<Self>, sqlx::Error> {
+        sqlx::query_as::<_, GroupRow>(concat!(
+            "SELECT ",
+            group_columns!(),
+            r#"
+            FROM auth.groups
+            WHERE external_id = $1
+              AND ($2 OR deleted_at IS NULL)
             LIMIT 1
             "#,
-            Self::columns(),
-            Self::table_name()
         ))
-        .bind(group_id.0)
+        .bind(external_id)
+        .bind(include_deleted)
         .fetch_optional(pool)
         .await
     }
 
+    /// Replace the group's external id. Returns the previous one.
+    pub async fn set_external_id(
+        pool: &PgPool,
+        group_id: GroupId,
+        external_id: &str,
+    ) -> Result<Option<String>, ExternalIdChangeError> {
+        set_external_id_in(
+            pool,
+            set_external_id_query!("auth.groups"),
+            group_id.0,
+            external_id,
+        )
+        .await
+    }
+
+    pub async fn set_visibility(
+        pool: &PgPool,
+        group_id: GroupId,
+        visibility: GroupVisibility,
+    ) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query(
+            r#"
+            UPDATE auth.groups
+            SET visibility = $1
+            WHERE id = $2
+            "#,
+        )
+        .bind(visibility)
+        .bind(group_id)
+        .execute(pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
     pub async fn set_details(
         pool: &PgPool,
         group_id: GroupId,
         details: Option<Value>,
     ) -> Result<(), sqlx::Error> {
-        sqlx::query(&format!(
+        sqlx::query(
             r#"
-            UPDATE {}
-            SET details = $1
+            UPDATE auth.groups
+            SET details = $1,
+                version = version + 1
             WHERE id = $2
             "#,
-            Self::table_name()
-        ))
+        )
         .bind(details)
-        .bind(group_id.0)
+        .bind(group_id)
+        .execute(pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Replace `details` only if the row is still at `expected_version`. Returns the new version.
+    pub async fn set_details_if_version(
+        pool: &PgPool,
+        group_id: GroupId,
+        details: Option<Value>,
+        expected_version: i64,
+    ) -> Result<i64, VersionConflictError> {
+        set_details_if_version!("auth.groups", pool, group_id, details, expected_version)
+    }
+
+    pub async fn deactivate(pool: &PgPool, group_id: GroupId) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            r#"
+            UPDATE auth.groups
+            SET active = FALSE
+            WHERE id = $1
+            "#,
+        )
+        .bind(group_id)
+        .execute(pool)
+        .await?;
+
+        Ok(())
+    }
+
+    #[cfg(feature = "hard-delete")]
+    pub async fn delete(pool: &PgPool, group_id: GroupId) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            r#"
+            DELETE FROM auth.groups
+            WHERE id = $1
+            "#,
+        )
+        .bind(group_id)
         .execute(pool)
         .await?;
 
-        Ok(())
-    }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, sqlx::Type)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(type_name = "TEXT", rename_all = "lowercase")]
+pub enum JoinRequestStatus {
+    Pending,
+    Approved,
+    Rejected,
+}
+
+#[derive(Debug, Clone, FromRow, Serialize)]
+pub struct GroupJoinRequestRow {
+    pub group_id: GroupId,
+    pub user_id: UserId,
+    pub message: Option<String>,
+    pub status: JoinRequestStatus,
+    pub created_at: chrono::NaiveDateTime,
+    pub decided_at: Option<chrono::NaiveDateTime>,
+    pub decided_by: Option<Uuid>,
+}
+
+impl GroupJoinRequestRow {
+    pub fn table_name() -> &'static str {
+        "auth.group_join_requests"
+    }
+
+    pub fn columns() -> &'static str {
+        group_join_request_columns!()
+    }
+
+    /// File a pending request, reopening an earlier decided one. An already pending request is
+    /// returned unchanged.
+    pub async fn request(
+        pool: &PgPool,
+        group_id: GroupId,
+        user_id: UserId,
+        message: Option<&str>,
+    ) -> Result<Self, sqlx::Error> {
+        let reopened = sqlx::query_as::<_, GroupJoinRequestRow>(concat!(
+            r#"
+            INSERT INTO auth.group_join_requests (group_id, user_id, message)
+            VALUES ($1, $2, $3)
+            ON CONFLICT (group_id, user_id) DO UPDATE
+            SET message = EXCLUDED.message,
+                status = 'pending',
+                created_at = CURRENT_TIMESTAMP,
+                decided_at = NULL,
+                decided_by = NULL
+            WHERE auth.group_join_requests.status <> 'pending'
+            RETURNING "#,
+            group_join_request_columns!(),
+        ))
+        .bind(group_id)
+        .bind(user_id)
+        .bind(message)
+        .fetch_optional(pool)
+        .await?;
+        match reopened {
+            Some(row) => Ok(row),
+            None => Self::get(pool, group_id, user_id)
+                .await?
+                .ok_or(sqlx::Error::RowNotFound),
+        }
+    }
+
+    pub async fn get(
+        pool: &PgPool,
+        group_id: GroupId,
+        user_id: UserId,
+    ) -> Result<Option<Self>, sqlx::Error> {
+        sqlx::query_as::<_, GroupJoinRequestRow>(concat!(
+            "SELECT ",
+            group_join_request_columns!(),
+            r#"
+            FROM auth.group_join_requests
+            WHERE group_id = $1
+              AND user_id = $2
+            "#,
+        ))
+        .bind(group_id)
+        .bind(user_id)
+        .fetch_optional(pool)
+        .await
+    }
+
+    pub async fn pending_for_group(
+        pool: &PgPool,
+        group_id: GroupId,
+        page: Option<(i64, i64)>,
+    ) -> Result<Vec<Self>, sqlx::Error> {
+        let (limit, offset) = match page {
+            Some((limit, offset)) => (Some(limit), offset),
+            None => (None, 0),
+        };
+        sqlx::query_as::<_, GroupJoinRequestRow>(concat!(
+            "SELECT ",
+            group_join_request_columns!(),
+            r#"
+            FROM auth.group_join_requests
+            WHERE group_id = $1
+              AND status = 'pending'
+            ORDER BY created_at ASC
+            LIMIT $2 OFFSET $3
+            "#,
+        ))
+        .bind(group_id)
+        .bind(limit)
+        .bind(offset)
+        .fetch_all(pool)
+        .await
+    }
+
+    /// Approve or reject a pending request, adding the user as a `GROUP_MEMBER_ROLE` member on
+    /// approval and writing an audit entry. Returns `false` if there was no pending request.
+    pub async fn decide(
+        pool: &PgPool,
+        group_id: GroupId,
+        user_id: UserId,
+        approve: bool,
+        decided_by: UserId,
+    ) -> Result<bool, sqlx::Error> {
+        let status = if approve {
+            JoinRequestStatus::Approved
+        } else {
+            JoinRequestStatus::Rejected
+        };
+
+        let mut tx = pool.begin().await?;
+        let updated = sqlx::query(
+            r#"
+            UPDATE auth.group_join_requests
+            SET status = $1,
+                decided_at = CURRENT_TIMESTAMP,
+                decided_by = $2
+            WHERE group_id = $3
+              AND user_id = $4
+              AND status = 'pending'
+            "#,
+        )
+        .bind(status)
+        .bind(decided_by)
+        .bind(group_id)
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await?;
+        if updated.rows_affected() == 0 {
+            return Ok(false);
+        }
+
+        if approve {
+            sqlx::query(
+                r#"
+                INSERT INTO auth.group_memberships (group_id, user_id, role_name)
+                VALUES ($1, $2, $3)
+                ON CONFLICT (group_id, user_id) DO NOTHING
+                "#,
+            )
+            .bind(group_id)
+            .bind(user_id)
+            .bind(GROUP_MEMBER_ROLE)
+            .execute(&mut *tx)
+            .await?;
+            apply_group_default_roles(&mut tx, Some(decided_by), group_id, Some(user_id), None)
+                .await?;
+        }
+
+        insert_audit_log(
+            &mut *tx,
+            Some(decided_by),
+            json!({
+                "type": "group_join_request_decided",
+                "group_id": group_id.to_string(),
+                "user_id": user_id.to_string(),
+                "status": status,
+            }),
+        )
+        .await?;
+
+        tx.commit().await?;
+        Ok(true)
+    }
+}
+
+#[derive(Clone, FromRow, Serialize)]
+pub struct UsernameHistoryRow {
+    pub id: Uuid,
+    pub user_id: UserId,
+    pub username: String,
+    pub changed_at: chrono::NaiveDateTime,
+    pub released_at: chrono::NaiveDateTime,
+}
+
+impl fmt::Debug for UsernameHistoryRow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("UsernameHistoryRow")
+            .field("id", &self.id)
+            .field("user_id", &self.user_id)
+            .field("username", &Sensitive(&self.username))
+            .field("changed_at", &self.changed_at)
+            .field("released_at", &self.released_at)
+            .finish()
+    }
+}
+
+impl UsernameHistoryRow {
+    pub fn table_name() -> &'static str {
+        "auth.username_history"
+    }
+
+    pub fn columns() -> &'static str {
+        username_history_columns!()
+    }
+
+    /// Former usernames of a user, most recent first.
+    pub async fn for_user(
+        pool: &PgPool,
+        user_id: UserId,
+        page: Option<(i64, i64)>,
+    ) -> Result<Vec<Self>, sqlx::Error> {
+        let (limit, offset) = match page {
+            Some((limit, offset)) => (Some(limit), offset),
+            None => (None, 0),
+        };
+        sqlx::query_as::<_, UsernameHistoryRow>(concat!(
+            "SELECT ",
+            username_history_columns!(),
+            r#"
+            FROM auth.username_history
+            WHERE user_id = $1
+            ORDER BY changed_at DESC
+            LIMIT $2 OFFSET $3
+            "#,
+        ))
+        .bind(user_id)
+        .bind(limit)
+        .bind(offset)
+        .fetch_all(pool)
+        .await
+    }
+
+    /// When an equivalent username released by someone other than `claimant` becomes available
+    /// again, or `None` if it is not held.
+    pub async fn held_until<'e, E>(
+        executor: E,
+        username: &str,
+        claimant: UserId,
+    ) -> Result<Option<chrono::NaiveDateTime>, sqlx::Error>
+    where
+        E: sqlx::PgExecutor<'e>,
+    {
+        let held: (Option<chrono::NaiveDateTime>,) = sqlx::query_as(
+            r#"
+            SELECT MAX(released_at)
+            FROM auth.username_history
+            WHERE username_canonical = auth.username_canonical($1)
+              AND user_id <> $2
+              AND released_at > $3
+            "#,
+        )
+        .bind(username)
+        .bind(claimant)
+        .bind(chrono::Utc::now().naive_utc())
+        .fetch_one(executor)
+        .await?;
+        Ok(held.0)
+    }
+}
+
+#[cfg(feature = "password-hashing")]
+#[derive(Debug)]
+pub enum PasswordCheckError {
+    Hash(PasswordHashError),
+    Database(sqlx::Error),
+}
+
+#[cfg(feature = "password-hashing")]
+impl fmt::Display for PasswordCheckError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Hash(err) => write!(f, "{}", err),
+            Self::Database(err) => write!(f, "Database error: {}", err),
+        }
+    }
+}
+
+#[cfg(feature = "password-hashing")]
+impl std::error::Error for PasswordCheckError {}
+
+#[cfg(feature = "password-hashing")]
+impl From<sqlx::Error> for PasswordCheckError {
+    fn from(err: sqlx::Error) -> Self {
+        Self::Database(err)
+    }
+}
+
+#[cfg(feature = "password-hashing")]
+impl From<PasswordHashError> for PasswordCheckError {
+    fn from(err: PasswordHashError) -> Self {
+        Self::Hash(err)
+    }
+}
+
+#[derive(Debug, Clone, FromRow)]
+pub struct UserPasswordRow {
+    pub user_id: UserId,
+    pub password_hash: String,
+    pub updated_at: chrono::NaiveDateTime,
+}
+
+impl UserPasswordRow {
+    pub fn table_name() -> &'static str {
+        "auth.user_passwords"
+    }
+
+    pub fn columns() -> &'static str {
+        user_password_columns!()
+    }
+
+    pub async fn get(pool: &PgPool, user_id: UserId) -> Result<Option<Self>, sqlx::Error> {
+        sqlx::query_as::<_, UserPasswordRow>(concat!(
+            "SELECT ",
+            user_password_columns!(),
+            r#"
+            FROM auth.user_passwords
+            WHERE user_id = $1
+            "#,
+        ))
+        .bind(user_id)
+        .fetch_optional(pool)
+        .await
+    }
+
+    /// Store an already hashed password, e.g. a new hash or one imported from a legacy system.
+    /// The hash must use a scheme `HashScheme::identify` recognizes.
+    pub async fn set(
+        pool: &PgPool,
+        user_id: UserId,
+        password_hash: &str,
+    ) -> Result<(), sqlx::Error> {
+        let now = chrono::Utc::now().naive_utc();
+        let mut tx = pool.begin().await?;
+        sqlx::query(
+            r#"
+            INSERT INTO auth.user_passwords (user_id, password_hash, updated_at)
+            VALUES ($1, $2, $3)
+            ON CONFLICT (user_id) DO UPDATE
+            SET password_hash = EXCLUDED.password_hash,
+                updated_at = EXCLUDED.updated_at
+            "#,
+        )
+        .bind(user_id)
+        .bind(password_hash)
+        .bind(now)
+        .execute(&mut *tx)
+        .await?;
+
+        insert_audit_log(
+            &mut *tx,
+            Some(user_id),
+            json!({
+                "type": "password_set",
+                "scheme": HashScheme::identify(password_hash).map(|scheme| scheme.as_str()),
+            }),
+        )
+        .await?;
+
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// Check a login password. On success a hash in a legacy scheme, or with outdated
+    /// parameters, is transparently replaced by one from `hasher`'s preferred scheme.
+    ///
+    /// Returns `false` for a wrong password and for users without a password. Wrong passwords
+    /// are logged as `login_failed`, which `/auth/admin/stats` counts.
+    #[cfg(feature = "password-hashing")]
+    pub async fn verify(
+        pool: &PgPool,
+        user_id: UserId,
+        password: &str,
+        hasher: &PasswordHasher,
+    ) -> Result<bool, PasswordCheckError> {
+        let Some(current) = Self::get(pool, user_id).await? else {
+            return Ok(false);
+        };
+
+        let verification = {
+            let hasher = hasher.clone();
+            let password = password.to_string();
+            let stored = current.password_hash.clone();
+            tokio::task::spawn_blocking(move || hasher.verify_and_upgrade(&password, &stored))
+                .await
+                .map_err(|err| PasswordHashError::Hashing(err.to_string()))??
+        };
+        let rehashed = match verification {
+            PasswordVerification::Invalid => {
+                insert_audit_log(
+                    pool,
+                    Some(user_id),
+                    json!({
+                        "type": "login_failed",
+                        "method": "password",
+                    }),
+                )
+                .await?;
+                return Ok(false);
+            }
+            PasswordVerification::Valid { rehashed: None } => return Ok(true),
+            PasswordVerification::Valid {
+                rehashed: Some(rehashed),
+            } => rehashed,
+        };
+
+        let now = chrono::Utc::now().naive_utc();
+        let mut tx = pool.begin().await?;
+        // Only replace the hash that was verified, so a concurrent password change wins.
+        let updated = sqlx::query(
+            r#"
+            UPDATE auth.user_passwords
+            SET password_hash = $1,
+                updated_at = $2
+            WHERE user_id = $3
+              AND password_hash = $4
+            "#,
+        )
+        .bind(&rehashed)
+        .bind(now)
+        .bind(user_id)
+        .bind(&current.password_hash)
+        .execute(&mut *tx)
+        .await?;
+        if updated.rows_affected() > 0 {
+            insert_audit_log(&mut *tx, Some(user_id), json!({
+                "type": "password_rehashed",
+                "from": HashScheme::identify(&current.password_hash).map(|scheme| scheme.as_str()),
+                "to": hasher.preferred().as_str(),
+            })).await?;
+        }
+        tx.commit().await?;
+        Ok(true)
+    }
+}
+
+/// What a group member may do within the group, as a bitset stored in
+/// `auth.group_role_definitions.capabilities` and, for capabilities delegated to one member,
+/// `auth.group_memberships.capabilities`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct GroupCapabilities(pub u64);
+
+impl GroupCapabilities {
+    pub const NONE: Self = Self(0);
+    pub const VIEW_MEMBERS: Self = Self(1 << 0);
+    pub const INVITE_MEMBERS: Self = Self(1 << 1);
+    pub const REMOVE_MEMBERS: Self = Self(1 << 2);
+    pub const MANAGE_ROLES: Self = Self(1 << 3);
+    pub const EDIT_DETAILS: Self = Self(1 << 4);
+    pub const APPROVE_JOIN_REQUESTS: Self = Self(1 << 5);
+    pub const ALL: Self = Self((1 << 6) - 1);
+
+    const NAMES: &[(&str, Self)] = &[
+        ("view_members", Self::VIEW_MEMBERS),
+        ("invite_members", Self::INVITE_MEMBERS),
+        ("remove_members", Self::REMOVE_MEMBERS),
+        ("manage_roles", Self::MANAGE_ROLES),
+        ("edit_details", Self::EDIT_DETAILS),
+        ("approve_join_requests", Self::APPROVE_JOIN_REQUESTS),
+    ];
+
+    pub fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn union(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+
+    /// Capability names, in bit order.
+    pub fn names(self) -> Vec<&'static str> {
+        Self::NAMES
+            .iter()
+            .filter(|(_, capability)| self.contains(*capability))
+            .map(|(name, _)| *name)
+            .collect()
+    }
+
+    /// Parse capability names, returning the first unknown name on failure.
+    pub fn from_names<I, S>(names: I) -> Result<Self, String>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        names.into_iter().try_fold(Self::NONE, |acc, name| {
+            let name = name.as_ref();
+            Self::NAMES
+                .iter()
+                .find(|(known, _)| *known == name)
+                .map(|(_, capability)| acc.union(*capability))
+                .ok_or_else(|| name.to_string())
+        })
+    }
+
+    /// Capabilities of the built-in roles, or `None` for a custom role name.
+    pub fn builtin(role_name: &str) -> Option<Self> {
+        match role_name {
+            GROUP_ADMIN_ROLE => Some(Self::ALL),
+            GROUP_MEMBER_ROLE => Some(Self::VIEW_MEMBERS),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, FromRow)]
+pub struct GroupRoleDefinitionRow {
+    pub group_id: GroupId,
+    pub role_name: String,
+    pub capabilities: i64,
+    pub description: Option<String>,
+}
+
+impl GroupRoleDefinitionRow {
+    pub fn new(
+        group_id: GroupId,
+        role_name: &str,
+        capabilities: GroupCapabilities,
+        description: Option<String>,
+    ) -> Self {
+        Self {
+            group_id,
+            role_name: role_name.to_string(),
+            capabilities: capabilities.0 as i64,
+            description,
+        }
+    }
+
+    pub fn table_name() -> &'static str {
+        "auth.group_role_definitions"
+    }
+
+    pub fn columns() -> &'static str {
+        group_role_definition_columns!()
+    }
+
+    pub fn capabilities(&self) -> GroupCapabilities {
+        GroupCapabilities(self.capabilities as u64)
+    }
+
+    /// Create or update a custom role definition.
+    pub async fn upsert(pool: &PgPool, row: &GroupRoleDefinitionRow) -> Result<(), sqlx::Error> {
+        sqlx::query(concat!(
+            "INSERT INTO auth.group_role_definitions (",
+            group_role_definition_columns!(),
+            ")",
+            r#"
+            VALUES ($1, $2, $3, $4)
+            ON CONFLICT (group_id, role_name) DO UPDATE
+            SET capabilities = EXCLUDED.capabilities,
+                description = EXCLUDED.description
+            "#,
+        ))
+        .bind(row.group_id)
+        .bind(&row.role_name)
+        .bind(row.capabilities)
+        .bind(&row.description)
+        .execute(pool)
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn get(
+        pool: &PgPool,
+        group_id: GroupId,
+        role_name: &str,
+    ) -> Result<Option<Self>, sqlx::Error> {
+        sqlx::query_as::<_, GroupRoleDefinitionRow>(concat!(
+            "SELECT ",
+            group_role_definition_columns!(),
+            r#"
+            FROM auth.group_role_definitions
+            WHERE group_id = $1
+              AND role_name = $2
+            "#,
+        ))
+        .bind(group_id)
+        .bind(role_name)
+        .fetch_optional(pool)
+        .await
+    }
+
+    pub async fn for_group(pool: &PgPool, group_id: GroupId) -> Result<Vec<Self>, sqlx::Error> {
+        sqlx::query_as::<_, GroupRoleDefinitionRow>(concat!(
+            "SELECT ",
+            group_role_definition_columns!(),
+            r#"
+            FROM auth.group_role_definitions
+            WHERE group_id = $1
+            ORDER BY role_name ASC
+            "#,
+        ))
+        .bind(group_id)
+        .fetch_all(pool)
+        .await
+    }
+
+    /// Remove a custom role unless a member still holds it. Returns `false` if the role is in use
+    /// or does not exist.
+    pub async fn delete_unused(
+        pool: &PgPool,
+        group_id: GroupId,
+        role_name: &str,
+    ) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query(
+            r#"
+            DELETE FROM auth.group_role_definitions d
+            WHERE d.group_id = $1
+              AND d.role_name = $2
+              AND NOT EXISTS (
+                SELECT 1
+                FROM auth.group_memberships gm
+                WHERE gm.group_id = d.group_id
+                  AND gm.role_name = d.role_name
+              )
+            "#,
+        )
+        .bind(group_id)
+        .bind(role_name)
+        .execute(pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+}
+
+/// Whether `role_name` is a built-in role or defined for the group.
+pub async fn group_role_exists(
+    pool: &PgPool,
+    group_id: GroupId,
+    role_name: &str,
+) -> Result<bool, sqlx::Error> {
+    if GroupCapabilities::builtin(role_name).is_some() {
+        return Ok(true);
+    }
+    Ok(GroupRoleDefinitionRow::get(pool, group_id, role_name)
+        .await?
+        .is_some())
+}
+
+/// Capabilities the user holds in the group: those of their membership role plus any delegated
+/// to their membership. `NONE` for non-members.
+pub async fn group_capabilities(
+    pool: &PgPool,
+    group_id: GroupId,
+    user_id: UserId,
+) -> Result<GroupCapabilities, sqlx::Error> {
+    let membership: Option<(String, Option<i64>, i64)> = sqlx::query_as(
+        r#"
+        SELECT gm.role_name, d.capabilities, gm.capabilities
+        FROM auth.group_memberships gm
+        LEFT JOIN auth.group_role_definitions d
+          ON d.group_id = gm.group_id
+         AND d.role_name = gm.role_name
+        WHERE gm.group_id = $1
+          AND gm.user_id = $2
+        "#,
+    )
+    .bind(group_id)
+    .bind(user_id)
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(match membership {
+        Some((role_name, capabilities, delegated)) => GroupCapabilities::builtin(&role_name)
+            .or(capabilities.map(|bits| GroupCapabilities(bits as u64)))
+            .unwrap_or(GroupCapabilities::NONE)
+            .union(GroupCapabilities(delegated as u64)),
+        None => GroupCapabilities::NONE,
+    })
+}
+
+/// A grant a group hands to each of its members. Members receive it as a direct user grant when
+/// they join and lose it when they leave, unless they also hold it directly or through another
+/// group's defaults.
+#[derive(Debug, Clone, PartialEq, Eq, FromRow, Serialize)]
+pub struct GroupDefaultRoleRow {
+    pub group_id: GroupId,
+    pub scope: String,
+    pub scope_id: String,
+    pub role_name: String,
+}
+
+impl GroupDefaultRoleRow {
+    pub fn new(group_id: GroupId, scope: &str, scope_id: &str, role_name: &str) -> Self {
+        Self {
+            group_id,
+            scope: scope.to_string(),
+            scope_id: scope_id.to_string(),
+            role_name: role_name.to_string(),
+        }
+    }
+
+    pub fn table_name() -> &'static str {
+        "auth.group_default_roles"
+    }
+
+    pub fn columns() -> &'static str {
+        group_default_role_columns!()
+    }
+
+    pub async fn for_group(pool: &PgPool, group_id: GroupId) -> Result<Vec<Self>, sqlx::Error> {
+        sqlx::query_as::<_, GroupDefaultRoleRow>(concat!(
+            "SELECT ",
+            group_default_role_columns!(),
+            r#"
+            FROM auth.group_default_roles
+            WHERE group_id = $1
+            ORDER BY scope ASC, scope_id ASC, role_name ASC
+            "#,
+        ))
+        .bind(group_id)
+        .fetch_all(pool)
+        .await
+    }
+
+    /// Add a default role and grant it to every current member. Returns `false` if the group
+    /// already had it.
+    pub async fn add(
+        pool: &PgPool,
+        actor_user_id: UserId,
+        row: &GroupDefaultRoleRow,
+    ) -> Result<bool, sqlx::Error> {
+        let mut tx = pool.begin().await?;
+        let added = sqlx::query(concat!(
+            "INSERT INTO auth.group_default_roles (",
+            group_default_role_columns!(),
+            ")",
+            r#"
+            VALUES ($1, $2, $3, $4)
+            ON CONFLICT DO NOTHING
+            "#,
+        ))
+        .bind(row.group_id)
+        .bind(&row.scope)
+        .bind(&row.scope_id)
+        .bind(&row.role_name)
+        .execute(&mut *tx)
+        .await?
+        .rows_affected()
+            > 0;
+        if added {
+            insert_group_default_role_log(&mut tx, actor_user_id, "group_default_role_added", row)
+                .await?;
+            apply_group_default_roles(&mut tx, Some(actor_user_id), row.group_id, None, Some(row))
+                .await?;
+        }
+
+        tx.commit().await?;
+        if added {
+            RoleAssignmentTarget::Group(row.group_id).invalidate_role_snapshots();
+        }
+        Ok(added)
+    }
+
+    /// Remove a default role and revoke it from members who hold it only because of it. Returns
+    /// `false` if the group did not have it.
+    pub async fn remove(
+        pool: &PgPool,
+        actor_user_id: UserId,
+        row: &GroupDefaultRoleRow,
+    ) -> Result<bool, sqlx::Error> {
+        let mut tx = pool.begin().await?;
+        let removed = sqlx::query(
+            r#"
+            DELETE FROM auth.group_default_roles
+            WHERE group_id = $1
+              AND scope = $2
+              AND scope_id = $3
+              AND role_name = $4
+            "#,
+        )
+        .bind(row.group_id)
+        .bind(&row.scope)
+        .bind(&row.scope_id)
+        .bind(&row.role_name)
+        .execute(&mut *tx)
+        .await?
+        .rows_affected()
+            > 0;
+        if removed {
+            insert_group_default_role_log(
+                &mut tx,
+                actor_user_id,
+                "group_default_role_removed",
+                row,
+            )
+            .await?;
+            release_group_default_roles(
+                &mut tx,
+                Some(actor_user_id),
+                row.group_id,
+                None,
+                Some(row),
+            )
+            .await?;
+        }
+
+        tx.commit().await?;
+        if removed {
+            RoleAssignmentTarget::Group(row.group_id).invalidate_role_snapshots();
+        }
+        Ok(removed)
+    }
+}
+
+async fn insert_group_default_role_log(
+    tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+    actor_user_id: UserId,
+    action_type: &str,
+    row: &GroupDefaultRoleRow,
+) -> Result<(), sqlx::Error> {
+    insert_audit_log(
+        &mut **tx,
+        Some(actor_user_id),
+        json!({
+            "type": action_type,
+            "group_id": row.group_id.to_string(),
+            "scope": row.scope,
+            "scope_id": row.scope_id,
+            "role_name": row.role_name,
+        }),
+    )
+    .await?;
+
+    Ok(())
+}
+
+/// Grant the group's default roles, or just `only`, to one member or to every member. Grants the
+/// member already holds, including denies, are left alone.
+pub(crate) async fn apply_group_default_roles(
+    conn: &mut PgConnection,
+    actor_user_id: Option<UserId>,
+    group_id: GroupId,
+    user_id: Option<UserId>,
+    only: Option<&GroupDefaultRoleRow>,
+) -> Result<(), sqlx::Error> {
+    let granted = sqlx::query_as::<_, (UserId, String, String, String)>(
+        r#"
+        INSERT INTO auth.user_roles (user_id, scope, scope_id, role_name, source_group_id)
+        SELECT gm.user_id, d.scope, d.scope_id, d.role_name, d.group_id
+        FROM auth.group_default_roles d
+        JOIN auth.group_memberships gm
+          ON gm.group_id = d.group_id
+        WHERE d.group_id = $1
+          AND ($2::UUID IS NULL OR gm.user_id = $2)
+          AND ($3::TEXT IS NULL OR (d.scope = $3 AND d.scope_id = $4 AND d.role_name = $5))
+        ON CONFLICT DO NOTHING
+        RETURNING user_id, scope, scope_id, role_name
+        "#,
+    )
+    .bind(group_id)
+    .bind(user_id)
+    .bind(only.map(|row| &row.scope))
+    .bind(only.map(|row| &row.scope_id))
+    .bind(only.map(|row| &row.role_name))
+    .fetch_all(&mut *conn)
+    .await?;
 
-    pub async fn deactivate(pool: &PgPool, group_id: GroupId) -> Result<(), sqlx::Error> {
-        sqlx::query(&format!(
-            r#"
-            UPDATE {}
-            SET active = FALSE
-            WHERE id = $1
-            "#,
-            Self::table_name()
-        ))
-        .bind(group_id.0)
-        .execute(pool)
+    for (user_id, scope, scope_id, role_name) in granted {
+        insert_role_audit_log(
+            &mut *conn,
+            actor_user_id,
+            "role_grant",
+            RoleAssignmentTarget::User(user_id),
+            &scope,
+            &scope_id,
+            &role_name,
+            None,
+        )
         .await?;
-
-        Ok(())
     }
+    Ok(())
+}
 
-    pub async fn delete(pool: &PgPool, group_id: GroupId) -> Result<(), sqlx::Error> {
-        sqlx::query(&format!(
-            r#"
-            DELETE FROM {}
-            WHERE id = $1
-            "#,
-            Self::table_name()
-        ))
-        .bind(group_id.0)
-        .execute(pool)
-        .await?;
+/// Revoke grants that came from the group's default roles, or just `only`, from one member or
+/// from every member. A grant another of the member's groups also hands out is handed over to
+/// that group instead of being revoked.
+pub(crate) async fn release_group_default_roles(
+    conn: &mut PgConnection,
+    actor_user_id: Option<UserId>,
+    group_id: GroupId,
+    user_id: Option<UserId>,
+    only: Option<&GroupDefaultRoleRow>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        r#"
+        UPDATE auth.user_roles ur
+        SET source_group_id = other.group_id
+        FROM (
+            SELECT DISTINCT ON (gm.user_id, d.scope, d.scope_id, d.role_name)
+                gm.user_id, d.scope, d.scope_id, d.role_name, d.group_id
+            FROM auth.group_default_roles d
+            JOIN auth.group_memberships gm
+              ON gm.group_id = d.group_id
+            WHERE d.group_id <> $1
+            ORDER BY gm.user_id, d.scope, d.scope_id, d.role_name, d.group_id ASC
+        ) other
+        WHERE other.user_id = ur.user_id
+          AND other.scope = ur.scope
+          AND other.scope_id = ur.scope_id
+          AND other.role_name = ur.role_name
+          AND ur.source_group_id = $1
+          AND ($2::UUID IS NULL OR ur.user_id = $2)
+          AND ($3::TEXT IS NULL OR (ur.scope = $3 AND ur.scope_id = $4 AND ur.role_name = $5))
+        "#,
+    )
+    .bind(group_id)
+    .bind(user_id)
+    .bind(only.map(|row| &row.scope))
+    .bind(only.map(|row| &row.scope_id))
+    .bind(only.map(|row| &row.role_name))
+    .execute(&mut *conn)
+    .await?;
 
-        Ok(())
+    let revoked = sqlx::query_as::<_, (UserId, String, String, String)>(
+        r#"
+        DELETE FROM auth.user_roles ur
+        WHERE ur.source_group_id = $1
+          AND ($2::UUID IS NULL OR ur.user_id = $2)
+          AND ($3::TEXT IS NULL OR (ur.scope = $3 AND ur.scope_id = $4 AND ur.role_name = $5))
+        RETURNING user_id, scope, scope_id, role_name
+        "#,
+    )
+    .bind(group_id)
+    .bind(user_id)
+    .bind(only.map(|row| &row.scope))
+    .bind(only.map(|row| &row.scope_id))
+    .bind(only.map(|row| &row.role_name))
+    .fetch_all(&mut *conn)
+    .await?;
+
+    for (user_id, scope, scope_id, role_name) in revoked {
+        insert_role_audit_log(
+            &mut *conn,
+            actor_user_id,
+            "role_revoke",
+            RoleAssignmentTarget::User(user_id),
+            &scope,
+            &scope_id,
+            &role_name,
+            None,
+        )
+        .await?;
     }
+    Ok(())
 }
 
 #[derive(Debug, Clone, FromRow)]
 pub struct GroupMembershipRow {
-    pub group_id: Uuid,
-    pub user_id: Uuid,
+    pub group_id: GroupId,
+    pub user_id: UserId,
     pub role_name: String,
 }
 
 impl GroupMembershipRow {
     pub fn new(group_id: GroupId, user_id: UserId, role_name: &str) -> Self {
         Self {
-            group_id: group_id.0,
-            user_id: user_id.0,
+            group_id,
+            user_id,
             role_name: role_name.to_string(),
         }
     }
@@ -1043,27 +4335,60 @@ impl GroupMembershipRow {
     }
 
     pub fn columns() -> &'static str {
-        "group_id, user_id, role_name"
+        group_membership_columns!()
     }
 
+    /// Add a member and grant them the group's default roles.
     pub async fn add_member(pool: &PgPool, row: &GroupMembershipRow) -> Result<(), sqlx::Error> {
-        sqlx::query(&format!(
+        let mut tx = pool.begin().await?;
+        sqlx::query(concat!(
+            "INSERT INTO auth.group_memberships (",
+            group_membership_columns!(),
+            ")",
             r#"
-            INSERT INTO {} ({})
             VALUES ($1, $2, $3)
             "#,
-            Self::table_name(),
-            Self::columns()
         ))
         .bind(row.group_id)
         .bind(row.user_id)
         .bind(&row.role_name)
-        .execute(pool)
+        .execute(&mut *tx)
         .await?;
+        apply_group_default_roles(&mut tx, None, row.group_id, Some(row.user_id), None).await?;
 
+        tx.commit().await?;
+        RoleAssignmentTarget::User(row.user_id).invalidate_role_snapshots();
         Ok(())
     }
 
+    /// Change a member's role. Returns `false` if the user is not a member.
+    pub async fn set_role(
+        pool: &PgPool,
+        group_id: GroupId,
+        user_id: UserId,
+        role_name: &str,
+    ) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query(
+            r#"
+            UPDATE auth.group_memberships
+            SET role_name = $1
+            WHERE group_id = $2
+              AND user_id = $3
+            "#,
+        )
+        .bind(role_name)
+        .bind(group_id)
+        .bind(user_id)
+        .execute(pool)
+        .await?;
+        let changed = result.rows_affected() > 0;
+        if changed {
+            RoleAssignmentTarget::User(user_id).invalidate_role_snapshots();
+        }
+
+        Ok(changed)
+    }
+
     pub async fn remove_member(
         pool: &PgPool,
         group_id: GroupId,
@@ -1073,6 +4398,9 @@ impl GroupMembershipRow {
         Ok(())
     }
 
+    /// Remove a member, revoking the grants they received from the group's default roles. If
+    /// they were the last group admin, another member inherits the role: `inheritor_user_id`
+    /// when given and a member, otherwise the longest-standing member.
     pub async fn remove_member_with_inheritance(
         pool: &PgPool,
         group_id: GroupId,
@@ -1089,8 +4417,8 @@ impl GroupMembershipRow {
             FOR UPDATE
             "#,
         )
-        .bind(group_id.0)
-        .bind(user_id.0)
+        .bind(group_id)
+        .bind(user_id)
         .fetch_optional(&mut *tx)
         .await?;
 
@@ -1112,8 +4440,8 @@ impl GroupMembershipRow {
                 )
                 "#,
             )
-            .bind(group_id.0)
-            .bind(user_id.0)
+            .bind(group_id)
+            .bind(user_id)
             .bind(GROUP_ADMIN_ROLE)
             .fetch_one(&mut *tx)
             .await?;
@@ -1129,8 +4457,8 @@ impl GroupMembershipRow {
                         )
                         "#,
                     )
-                    .bind(group_id.0)
-                    .bind(inheritor_user_id.0)
+                    .bind(group_id)
+                    .bind(inheritor_user_id)
                     .fetch_one(&mut *tx)
                     .await?;
                     if is_member.0 {
@@ -1139,7 +4467,7 @@ impl GroupMembershipRow {
                         None
                     }
                 } else {
-                    sqlx::query_as::<_, (Uuid,)>(
+                    sqlx::query_as::<_, (UserId,)>(
                         r#"
                         SELECT user_id
                         FROM auth.group_memberships
@@ -1150,11 +4478,11 @@ impl GroupMembershipRow {
                         FOR UPDATE
                         "#,
                     )
-                    .bind(group_id.0)
-                    .bind(user_id.0)
+                    .bind(group_id)
+                    .bind(user_id)
                     .fetch_optional(&mut *tx)
                     .await?
-                    .map(|(id,)| UserId(id))
+                    .map(|(id,)| id)
                 };
 
                 if let Some(inheritor_user_id) = inheritor {
@@ -1165,8 +4493,8 @@ impl GroupMembershipRow {
                         WHERE group_id = $1 AND user_id = $2
                         "#,
                     )
-                    .bind(group_id.0)
-                    .bind(inheritor_user_id.0)
+                    .bind(group_id)
+                    .bind(inheritor_user_id)
                     .bind(GROUP_ADMIN_ROLE)
                     .execute(&mut *tx)
                     .await?;
@@ -1182,33 +4510,85 @@ impl GroupMembershipRow {
             WHERE group_id = $1 AND user_id = $2
             "#,
         )
-        .bind(group_id.0)
-        .bind(user_id.0)
+        .bind(group_id)
+        .bind(user_id)
         .execute(&mut *tx)
         .await?;
+        release_group_default_roles(&mut tx, None, group_id, Some(user_id), None).await?;
 
         tx.commit().await?;
+        for user_id in std::iter::once(user_id).chain(inherited_to) {
+            RoleAssignmentTarget::User(user_id).invalidate_role_snapshots();
+        }
         Ok(inherited_to)
     }
 
+    /// Capabilities delegated to the member on top of their role's, or `None` for non-members.
+    pub async fn delegated_capabilities(
+        pool: &PgPool,
+        group_id: GroupId,
+        user_id: UserId,
+    ) -> Result<Option<GroupCapabilities>, sqlx::Error> {
+        let capabilities: Option<(i64,)> = sqlx::query_as(
+            r#"
+            SELECT capabilities
+            FROM auth.group_memberships
+            WHERE group_id = $1 AND user_id = $2
+            "#,
+        )
+        .bind(group_id)
+        .bind(user_id)
+        .fetch_optional(pool)
+        .await?;
+
+        Ok(capabilities.map(|(bits,)| GroupCapabilities(bits as u64)))
+    }
+
+    /// Replace the capabilities delegated to a member. Returns `false` if the user is not a
+    /// member.
+    pub async fn set_capabilities(
+        pool: &PgPool,
+        group_id: GroupId,
+        user_id: UserId,
+        capabilities: GroupCapabilities,
+    ) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query(
+            r#"
+            UPDATE auth.group_memberships
+            SET capabilities = $1
+            WHERE group_id = $2
+              AND user_id = $3
+            "#,
+        )
+        .bind(capabilities.0 as i64)
+        .bind(group_id)
+        .bind(user_id)
+        .execute(pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
     pub async fn is_member(
         pool: &PgPool,
         group_id: GroupId,
         user_id: UserId,
     ) -> Result<bool, sqlx::Error> {
-        let count: (i64,) = sqlx::query_as(&format!(
+        let is_member: (bool,) = sqlx::query_as(
             r#"
-            SELECT COUNT(*) FROM {}
-            WHERE group_id = $1 AND user_id = $2
+            SELECT EXISTS (
+                SELECT 1
+                FROM auth.group_memberships
+                WHERE group_id = $1 AND user_id = $2
+            )
             "#,
-            Self::table_name()
-        ))
-        .bind(group_id.0)
-        .bind(user_id.0)
+        )
+        .bind(group_id)
+        .bind(user_id)
         .fetch_one(pool)
         .await?;
 
-        Ok(count.0 > 0)
+        Ok(is_member.0)
     }
 
     pub async fn members(
@@ -1217,34 +4597,32 @@ impl GroupMembershipRow {
         page: Option<(i64, i64)>,
     ) -> Result<Vec<Self>, sqlx::Error> {
         let rows = if let Some((limit, offset)) = page {
-            let query = format!(
+            let query = concat!(
+                "SELECT ",
+                group_membership_columns!(),
                 r#"
-                SELECT {}
-                FROM {}
+                FROM auth.group_memberships
                 WHERE group_id = $1
                 LIMIT $2 OFFSET $3
                 "#,
-                Self::columns(),
-                Self::table_name()
             );
-            sqlx::query_as::<_, GroupMembershipRow>(&query)
-                .bind(group_id.0)
+            sqlx::query_as::<_, GroupMembershipRow>(query)
+                .bind(group_id)
                 .bind(limit)
                 .bind(offset)
                 .fetch_all(pool)
                 .await?
         } else {
-            let query = format!(
+            let query = concat!(
+                "SELECT ",
+                group_membership_columns!(),
                 r#"
-                SELECT {}
-                FROM {}
+                FROM auth.group_memberships
                 WHERE group_id = $1
                 "#,
-                Self::columns(),
-                Self::table_name()
             );
-            sqlx::query_as::<_, GroupMembershipRow>(&query)
-                .bind(group_id.0)
+            sqlx::query_as::<_, GroupMembershipRow>(query)
+                .bind(group_id)
                 .fetch_all(pool)
                 .await?
         };
@@ -1255,18 +4633,18 @@ impl GroupMembershipRow {
         pool: &PgPool,
         user_id: UserId,
     ) -> Result<Vec<GroupRow>, sqlx::Error> {
-        let rows = sqlx::query_as::<_, GroupRow>(&format!(
+        let rows = sqlx::query_as::<_, GroupRow>(
             r#"
-            SELECT g.id, g.display_name, g.details
-            FROM {} gm
+            SELECT g.id, g.display_name, g.details, g.version, g.visibility, g.external_id,
+                   g.archived_at
+            FROM auth.group_memberships gm
             JOIN auth.groups g
               ON g.id = gm.group_id
             WHERE gm.user_id = $1
               AND g.active = TRUE
             "#,
-            Self::table_name()
-        ))
-        .bind(user_id.0)
+        )
+        .bind(user_id)
         .fetch_all(pool)
         .await?;
 
@@ -1279,38 +4657,216 @@ impl GroupMembershipRow {
         user_id: UserId,
         role_name: &str,
     ) -> Result<bool, sqlx::Error> {
-        let count: (i64,) = sqlx::query_as(&format!(
+        let has_role: (bool,) = sqlx::query_as(
             r#"
-            SELECT COUNT(*) FROM {}
-            WHERE group_id = $1 AND user_id = $2 AND role_name = $3
+            SELECT EXISTS (
+                SELECT 1
+                FROM auth.group_memberships
+                WHERE group_id = $1 AND user_id = $2 AND role_name = $3
+            )
             "#,
-            Self::table_name()
-        ))
-        .bind(group_id.0)
-        .bind(user_id.0)
+        )
+        .bind(group_id)
+        .bind(user_id)
         .bind(role_name)
         .fetch_one(pool)
         .await?;
 
-        Ok(count.0 > 0)
+        Ok(has_role.0)
+    }
+}
+
+#[derive(Clone, FromRow)]
+pub struct LoginHistoryRow {
+    pub id: Uuid,
+    pub user_id: UserId,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+    pub method: String,
+    /// Issuer of the identity provider, if recorded.
+    pub provider: Option<String>,
+    pub created_at: chrono::NaiveDateTime,
+}
+
+impl fmt::Debug for LoginHistoryRow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LoginHistoryRow")
+            .field("id", &self.id)
+            .field("user_id", &self.user_id)
+            .field("ip", &self.ip.as_ref().map(Sensitive))
+            .field("user_agent", &self.user_agent.as_ref().map(Sensitive))
+            .field("method", &self.method)
+            .field("provider", &self.provider)
+            .field("created_at", &self.created_at)
+            .finish()
+    }
+}
+
+impl LoginHistoryRow {
+    pub fn table_name() -> &'static str {
+        "auth.login_history"
+    }
+
+    pub fn columns() -> &'static str {
+        login_history_columns!()
+    }
+
+    pub async fn for_user(
+        pool: &PgPool,
+        user_id: UserId,
+        page: Option<(i64, i64)>,
+    ) -> Result<Vec<Self>, sqlx::Error> {
+        let (limit, offset) = match page {
+            Some((limit, offset)) => (Some(limit), offset),
+            None => (None, 0),
+        };
+        sqlx::query_as::<_, LoginHistoryRow>(concat!(
+            "SELECT ",
+            login_history_columns!(),
+            r#"
+            FROM auth.login_history
+            WHERE user_id = $1
+            ORDER BY created_at DESC
+            LIMIT $2 OFFSET $3
+            "#,
+        ))
+        .bind(user_id)
+        .bind(limit)
+        .bind(offset)
+        .fetch_all(pool)
+        .await
+    }
+}
+
+#[derive(Debug, Clone, FromRow)]
+pub struct LastLogin {
+    pub last_login_at: Option<chrono::NaiveDateTime>,
+    pub last_login_ip: Option<String>,
+}
+
+impl LastLogin {
+    pub async fn get(pool: &PgPool, user_id: UserId) -> Result<Option<Self>, sqlx::Error> {
+        sqlx::query_as::<_, LastLogin>(
+            r#"
+            SELECT last_login_at, last_login_ip
+            FROM auth.users
+            WHERE id = $1
+            "#,
+        )
+        .bind(user_id)
+        .fetch_optional(pool)
+        .await
+    }
+}
+
+/// Whether a login with `user_agent` would be the user's first from that device. A user with no
+/// login history has no known devices yet, so their first login is not reported as new.
+pub async fn is_new_device(
+    pool: &PgPool,
+    user_id: UserId,
+    user_agent: Option<&str>,
+) -> Result<bool, sqlx::Error> {
+    let (has_history, seen): (bool, bool) = sqlx::query_as(
+        r#"
+        SELECT
+            EXISTS (SELECT 1 FROM auth.login_history WHERE user_id = $1),
+            EXISTS (
+                SELECT 1
+                FROM auth.login_history
+                WHERE user_id = $1
+                  AND user_agent IS NOT DISTINCT FROM $2
+            )
+        "#,
+    )
+    .bind(user_id)
+    .bind(user_agent)
+    .fetch_one(pool)
+    .await?;
+    Ok(has_history && !seen)
+}
+
+/// Record a successful login: updates `last_login_at`/`last_login_ip` and appends to
+/// `auth.login_history`.
+///
+/// Returns `false` without recording anything if the user has no `auth.users` row yet.
+pub async fn record_login(
+    pool: &PgPool,
+    user_id: UserId,
+    ip: Option<&str>,
+    user_agent: Option<&str>,
+    method: &str,
+) -> Result<bool, sqlx::Error> {
+    record_provider_login(pool, user_id, None, ip, user_agent, method).await
+}
+
+/// `record_login`, noting the issuer of the identity provider the user signed in through.
+pub async fn record_provider_login(
+    pool: &PgPool,
+    user_id: UserId,
+    provider: Option<&str>,
+    ip: Option<&str>,
+    user_agent: Option<&str>,
+    method: &str,
+) -> Result<bool, sqlx::Error> {
+    let now = chrono::Utc::now().naive_utc();
+    let mut tx = pool.begin().await?;
+    let updated = sqlx::query(
+        r#"
+        UPDATE auth.users
+        SET last_login_at = $2, last_login_ip = $3
+        WHERE id = $1
+        "#,
+    )
+    .bind(user_id)
+    .bind(now)
+    .bind(ip)
+    .execute(&mut *tx)
+    .await?;
+    if updated.rows_affected() == 0 {
+        tx.rollback().await?;
+        return Ok(false);
     }
+
+    sqlx::query(
+        r#"
+        INSERT INTO auth.login_history
+            (id, user_id, ip, user_agent, method, provider, created_at)
+        VALUES ($1, $2, $3, $4, $5, $6, $7)
+        "#,
+    )
+    .bind(new_uuid())
+    .bind(user_id)
+    .bind(ip)
+    .bind(user_agent)
+    .bind(method)
+    .bind(provider)
+    .bind(now)
+    .execute(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+    Ok(true)
 }
 
 #[derive(Debug, Clone, FromRow)]
 pub struct LogRow {
     pub id: Uuid,
-    pub user_id: Option<Uuid>,
+    pub user_id: Option<UserId>,
     pub action: Value,
     pub timestamp: chrono::NaiveDateTime,
+    /// `X-Request-Id` of the request that wrote the entry; see `request_id`.
+    pub request_id: Option<String>,
 }
 
 impl LogRow {
+    /// Captures the current request id, so rows queued for `audit::AuditWriter` keep it.
     pub fn new(user_id: UserId, action: Value) -> Self {
         Self {
-            id: Uuid::new_v4(),
-            user_id: Some(user_id.0),
+            id: new_uuid(),
+            user_id: Some(user_id),
             action,
             timestamp: chrono::Utc::now().naive_utc(),
+            request_id: current_request_id(),
         }
     }
 
@@ -1319,67 +4875,223 @@ impl LogRow {
     }
 
     pub fn columns() -> &'static str {
-        "id, user_id, action, timestamp"
+        log_columns!()
     }
 
     pub async fn insert(pool: &PgPool, row: &LogRow) -> Result<(), sqlx::Error> {
-        sqlx::query(&format!(
+        sqlx::query(concat!(
+            "INSERT INTO auth.log (",
+            log_columns!(),
+            ")",
             r#"
-            INSERT INTO {} ({})
-            VALUES ($1, $2, $3, $4)
+            VALUES ($1, $2, $3, $4, $5)
             "#,
-            Self::table_name(),
-            Self::columns()
         ))
         .bind(row.id)
         .bind(row.user_id)
         .bind(&row.action)
         .bind(row.timestamp)
+        .bind(&row.request_id)
         .execute(pool)
         .await?;
 
         Ok(())
     }
 
+    /// Insert many rows in one transaction, with multi-row `VALUES` statements of at most
+    /// `LOG_BATCH_ROWS` rows each.
+    pub async fn insert_batch(pool: &PgPool, rows: &[LogRow]) -> Result<(), sqlx::Error> {
+        if rows.is_empty() {
+            return Ok(());
+        }
+
+        let mut tx = pool.begin().await?;
+        for chunk in rows.chunks(LOG_BATCH_ROWS) {
+            let mut builder = sqlx::QueryBuilder::<sqlx::Postgres>::new(concat!(
+                "INSERT INTO auth.log (",
+                log_columns!(),
+                ") ",
+            ));
+            builder.push_values(chunk, |mut b, row| {
+                b.push_bind(row.id)
+                    .push_bind(row.user_id)
+                    .push_bind(&row.action)
+                    .push_bind(row.timestamp)
+                    .push_bind(&row.request_id);
+            });
+            builder.build().execute(&mut *tx).await?;
+        }
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    #[deprecated(note = "use `LogRow::events_for_user_in`, which can bound the partition scan")]
     pub async fn events_for_user(
         pool: &PgPool,
         user_id: UserId,
         page: Option<(i64, i64)>,
     ) -> Result<Vec<Self>, sqlx::Error> {
-        let rows = if let Some((limit, offset)) = page {
-            let query = format!(
-                r#"
-                SELECT {}
-                FROM {}
-                WHERE user_id = $1
-                ORDER BY timestamp DESC
-                LIMIT $2 OFFSET $3
-                "#,
-                Self::columns(),
-                Self::table_name()
-            );
-            sqlx::query_as::<_, LogRow>(&query)
-                .bind(user_id.0)
-                .bind(limit)
-                .bind(offset)
-                .fetch_all(pool)
-                .await?
-        } else {
-            let query = format!(
-                r#"
-                SELECT {}
-                FROM {}
-                WHERE user_id = $1
-                ORDER BY timestamp DESC
-                "#,
-                Self::columns(),
-                Self::table_name()
-            );
-            sqlx::query_as::<_, LogRow>(&query)
-                .bind(user_id.0)
-                .fetch_all(pool)
-                .await?
-        };
-        Ok(rows)
+        Self::events_for_user_in(pool, user_id, None, page).await
+    }
+
+    /// Events for a user, newest first, optionally within `range` as `[since, until)`.
+    ///
+    /// Bounding on `timestamp` lets Postgres prune the monthly `auth.log` partitions instead of
+    /// scanning all of them.
+    pub async fn events_for_user_in(
+        pool: &PgPool,
+        user_id: UserId,
+        range: Option<(chrono::NaiveDateTime, chrono::NaiveDateTime)>,
+        page: Option<(i64, i64)>,
+    ) -> Result<Vec<Self>, sqlx::Error> {
+        let mut builder = sqlx::QueryBuilder::<sqlx::Postgres>::new(concat!(
+            "SELECT ",
+            log_columns!(),
+            " FROM auth.log WHERE user_id = ",
+        ));
+        builder.push_bind(user_id);
+        push_log_range(&mut builder, range);
+        builder.push(" ORDER BY timestamp DESC");
+        if let Some((limit, offset)) = page {
+            builder.push(" LIMIT ").push_bind(limit);
+            builder.push(" OFFSET ").push_bind(offset);
+        }
+        builder.build_query_as::<LogRow>().fetch_all(pool).await
+    }
+
+    /// Every event written while handling the request with id `request_id`, oldest first,
+    /// optionally within `range` as `[since, until)`.
+    pub async fn events_for_request_in(
+        pool: &PgPool,
+        request_id: &str,
+        range: Option<(chrono::NaiveDateTime, chrono::NaiveDateTime)>,
+    ) -> Result<Vec<Self>, sqlx::Error> {
+        let mut builder = sqlx::QueryBuilder::<sqlx::Postgres>::new(concat!(
+            "SELECT ",
+            log_columns!(),
+            " FROM auth.log WHERE request_id = ",
+        ));
+        builder.push_bind(request_id);
+        push_log_range(&mut builder, range);
+        builder.push(" ORDER BY timestamp ASC");
+        builder.build_query_as::<LogRow>().fetch_all(pool).await
+    }
+
+    /// All events within `[since, until)`, newest first.
+    pub async fn events_between(
+        pool: &PgPool,
+        since: chrono::NaiveDateTime,
+        until: chrono::NaiveDateTime,
+        page: Option<(i64, i64)>,
+    ) -> Result<Vec<Self>, sqlx::Error> {
+        let mut builder = sqlx::QueryBuilder::<sqlx::Postgres>::new(concat!(
+            "SELECT ",
+            log_columns!(),
+            " FROM auth.log WHERE TRUE",
+        ));
+        push_log_range(&mut builder, Some((since, until)));
+        builder.push(" ORDER BY timestamp DESC");
+        if let Some((limit, offset)) = page {
+            builder.push(" LIMIT ").push_bind(limit);
+            builder.push(" OFFSET ").push_bind(offset);
+        }
+        builder.build_query_as::<LogRow>().fetch_all(pool).await
+    }
+
+    /// Permanently delete all events older than `cutoff`. Returns the number of rows removed.
+    #[cfg(feature = "hard-delete")]
+    pub async fn purge_before(
+        pool: &PgPool,
+        cutoff: chrono::NaiveDateTime,
+    ) -> Result<u64, sqlx::Error> {
+        Ok(Self::purge_before_report(pool, cutoff, false)
+            .await?
+            .effects)
+    }
+
+    /// `purge_before`, optionally as a dry run that reports how many rows it would remove.
+    #[cfg(feature = "hard-delete")]
+    pub async fn purge_before_report(
+        pool: &PgPool,
+        cutoff: chrono::NaiveDateTime,
+        dry_run: bool,
+    ) -> Result<BulkReport<u64>, sqlx::Error> {
+        let mut tx = pool.begin().await?;
+        let result = sqlx::query(
+            r#"
+            DELETE FROM auth.log
+            WHERE timestamp < $1
+            "#,
+        )
+        .bind(cutoff)
+        .execute(&mut *tx)
+        .await?;
+
+        finish_bulk(tx, dry_run, result.rows_affected()).await
+    }
+}
+
+/// Restrict a `LogRow` query to `[since, until)` with bare comparisons on the partition key, so the
+/// planner can prune partitions.
+fn push_log_range(
+    builder: &mut sqlx::QueryBuilder<'_, sqlx::Postgres>,
+    range: Option<(chrono::NaiveDateTime, chrono::NaiveDateTime)>,
+) {
+    if let Some((since, until)) = range {
+        builder.push(" AND timestamp >= ").push_bind(since);
+        builder.push(" AND timestamp < ").push_bind(until);
+    }
+}
+
+/// Create the `auth.log` partition for the month containing `month`.
+///
+/// Rows that already fell into the default partition for that month are moved over. Returns the
+/// partition table name; calling this for an existing partition is a no-op.
+pub async fn create_log_partition(
+    pool: &PgPool,
+    month: chrono::NaiveDateTime,
+) -> Result<String, sqlx::Error> {
+    let name: (String,) = sqlx::query_as("SELECT auth.create_log_partition($1)")
+        .bind(month)
+        .fetch_one(pool)
+        .await?;
+    Ok(name.0)
+}
+
+/// Maintenance helper that ensures next month's `auth.log` partition exists.
+///
+/// Run this on a schedule (e.g. daily) so inserts never fall through to the default partition.
+pub async fn create_next_partition(pool: &PgPool) -> Result<String, sqlx::Error> {
+    let name: (String,) = sqlx::query_as(
+        r#"
+        SELECT auth.create_log_partition(
+            date_trunc('month', CURRENT_TIMESTAMP::TIMESTAMP) + INTERVAL '1 month'
+        )
+        "#,
+    )
+    .fetch_one(pool)
+    .await?;
+    Ok(name.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_valid_scope_id_pattern, scope_id_matches};
+
+    #[test]
+    fn wildcard_scope_ids() {
+        assert!(scope_id_matches("p1", "p1"));
+        assert!(!scope_id_matches("p1", "p10"));
+        assert!(scope_id_matches("*", "anything"));
+        assert!(scope_id_matches("org:1/project:*", "org:1/project:5"));
+        assert!(scope_id_matches("org:1/*", "org:1/project:*"));
+        assert!(!scope_id_matches("org:1/project:5", "org:1/project:*"));
+        assert!(!scope_id_matches("org:1/project:*", "org:2/project:5"));
+
+        assert!(is_valid_scope_id_pattern("org:1/project:*"));
+        assert!(is_valid_scope_id_pattern("global"));
+        assert!(!is_valid_scope_id_pattern("org:*/project:1"));
+        assert!(!is_valid_scope_id_pattern("**"));
     }
 }
@@ -0,0 +1,415 @@
+//! Why a user or group was deactivated, by whom and when.
+//!
+//! Deactivating records a reason, an optional admin note, the actor and the time next to the
+//! `active` flag. Reactivating has to acknowledge the recorded reason, so an account closed for
+//! abuse is not reopened by someone who did not know why it was closed; the reason is then
+//! cleared. Both steps are logged to `auth.log`.
+
+use std::fmt;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::{FromRow, PgPool};
+use uuid::Uuid;
+
+use crate::db::insert_audit_log;
+use crate::group_id::GroupId;
+use crate::remember::RememberedSession;
+use crate::user_id::UserId;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize, sqlx::Type)]
+#[serde(rename_all = "snake_case")]
+#[sqlx(type_name = "TEXT", rename_all = "snake_case")]
+pub enum DeactivationReason {
+    /// The user closed their own account.
+    UserRequested,
+    Abuse,
+    NonPayment,
+    /// e.g. a compromised account, pending investigation.
+    Security,
+}
+
+impl DeactivationReason {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::UserRequested => "user_requested",
+            Self::Abuse => "abuse",
+            Self::NonPayment => "non_payment",
+            Self::Security => "security",
+        }
+    }
+}
+
+impl fmt::Display for DeactivationReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for DeactivationReason {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "user_requested" => Ok(Self::UserRequested),
+            "abuse" => Ok(Self::Abuse),
+            "non_payment" => Ok(Self::NonPayment),
+            "security" => Ok(Self::Security),
+            other => Err(format!("Unknown deactivation reason: {}", other)),
+        }
+    }
+}
+
+/// The recorded deactivation of an inactive user or group. Every field is `None` for rows
+/// deactivated before reasons were recorded; dormancy sweeps only set `deactivated_at`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, FromRow)]
+pub struct Deactivation {
+    pub reason: Option<DeactivationReason>,
+    pub note: Option<String>,
+    pub deactivated_by: Option<UserId>,
+    pub deactivated_at: Option<chrono::NaiveDateTime>,
+}
+
+/// A reactivation did not apply.
+#[derive(Debug)]
+pub enum ReactivationError {
+    NotFound,
+    /// The user or group is active.
+    NotDeactivated,
+    /// The acknowledged reason is not the recorded one.
+    Unacknowledged {
+        reason: Option<DeactivationReason>,
+    },
+    Database(sqlx::Error),
+}
+
+impl fmt::Display for ReactivationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotFound => write!(f, "Not found"),
+            Self::NotDeactivated => write!(f, "Not deactivated"),
+            Self::Unacknowledged {
+                reason: Some(reason),
+            } => {
+                write!(f, "Deactivation reason {} must be acknowledged", reason)
+            }
+            Self::Unacknowledged { reason: None } => {
+                write!(f, "No deactivation reason was recorded; acknowledge none")
+            }
+            Self::Database(err) => write!(f, "Database error: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for ReactivationError {}
+
+impl From<sqlx::Error> for ReactivationError {
+    fn from(err: sqlx::Error) -> Self {
+        Self::Database(err)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Subject {
+    User(UserId),
+    Group(GroupId),
+}
+
+impl Subject {
+    fn id(self) -> Uuid {
+        match self {
+            Self::User(user_id) => user_id.0,
+            Self::Group(group_id) => group_id.0,
+        }
+    }
+
+    fn log_fields(self) -> (&'static str, String, &'static str) {
+        match self {
+            Self::User(user_id) => ("user_id", user_id.to_string(), "user"),
+            Self::Group(group_id) => ("group_id", group_id.to_string(), "group"),
+        }
+    }
+}
+
+macro_rules! deactivate_query {
+    ($table:literal) => {
+        concat!(
+            "UPDATE ",
+            $table,
+            r#"
+            SET active = FALSE,
+                deactivation_reason = $2,
+                deactivation_note = $3,
+                deactivated_by = $4,
+                deactivated_at = $5
+            WHERE id = $1
+              AND active IS DISTINCT FROM FALSE
+            "#
+        )
+    };
+}
+
+macro_rules! reactivate_query {
+    ($table:literal) => {
+        concat!(
+            "UPDATE ",
+            $table,
+            r#"
+            SET active = TRUE,
+                deactivation_reason = NULL,
+                deactivation_note = NULL,
+                deactivated_by = NULL,
+                deactivated_at = NULL
+            WHERE id = $1
+            "#
+        )
+    };
+}
+
+macro_rules! lock_query {
+    ($table:literal) => {
+        concat!(
+            "SELECT active, deactivation_reason FROM ",
+            $table,
+            " WHERE id = $1 AND deleted_at IS NULL FOR UPDATE"
+        )
+    };
+}
+
+macro_rules! deactivation_query {
+    ($table:literal) => {
+        concat!(
+            r#"
+            SELECT deactivation_reason AS reason,
+                   deactivation_note AS note,
+                   deactivated_by,
+                   deactivated_at
+            FROM "#,
+            $table,
+            r#"
+            WHERE id = $1
+              AND active = FALSE
+            "#
+        )
+    };
+}
+
+/// Deactivate a user, record why and revoke their remember-me tokens. Returns false if the user
+/// does not exist or is already inactive; the recorded reason is then left alone.
+pub async fn deactivate_user(
+    pool: &PgPool,
+    actor_user_id: UserId,
+    user_id: UserId,
+    reason: DeactivationReason,
+    note: Option<&str>,
+) -> Result<bool, sqlx::Error> {
+    deactivate(
+        pool,
+        actor_user_id,
+        Subject::User(user_id),
+        deactivate_query!("auth.users"),
+        reason,
+        note,
+    )
+    .await
+}
+
+/// `deactivate_user` for a group.
+pub async fn deactivate_group(
+    pool: &PgPool,
+    actor_user_id: UserId,
+    group_id: GroupId,
+    reason: DeactivationReason,
+    note: Option<&str>,
+) -> Result<bool, sqlx::Error> {
+    deactivate(
+        pool,
+        actor_user_id,
+        Subject::Group(group_id),
+        deactivate_query!("auth.groups"),
+        reason,
+        note,
+    )
+    .await
+}
+
+/// Reactivate a user whose recorded reason is `acknowledged`, and clear the reason. `None`
+/// acknowledges a deactivation without one.
+pub async fn reactivate_user(
+    pool: &PgPool,
+    actor_user_id: UserId,
+    user_id: UserId,
+    acknowledged: Option<DeactivationReason>,
+    note: Option<&str>,
+) -> Result<(), ReactivationError> {
+    reactivate(
+        pool,
+        actor_user_id,
+        Subject::User(user_id),
+        (lock_query!("auth.users"), reactivate_query!("auth.users")),
+        acknowledged,
+        note,
+    )
+    .await
+}
+
+/// `reactivate_user` for a group.
+pub async fn reactivate_group(
+    pool: &PgPool,
+    actor_user_id: UserId,
+    group_id: GroupId,
+    acknowledged: Option<DeactivationReason>,
+    note: Option<&str>,
+) -> Result<(), ReactivationError> {
+    reactivate(
+        pool,
+        actor_user_id,
+        Subject::Group(group_id),
+        (lock_query!("auth.groups"), reactivate_query!("auth.groups")),
+        acknowledged,
+        note,
+    )
+    .await
+}
+
+/// How an inactive user was deactivated. `None` if the user is active or does not exist.
+pub async fn user_deactivation(
+    pool: &PgPool,
+    user_id: UserId,
+) -> Result<Option<Deactivation>, sqlx::Error> {
+    sqlx::query_as::<_, Deactivation>(deactivation_query!("auth.users"))
+        .bind(user_id)
+        .fetch_optional(pool)
+        .await
+}
+
+/// `user_deactivation` for a group.
+pub async fn group_deactivation(
+    pool: &PgPool,
+    group_id: GroupId,
+) -> Result<Option<Deactivation>, sqlx::Error> {
+    sqlx::query_as::<_, Deactivation>(deactivation_query!("auth.groups"))
+        .bind(group_id)
+        .fetch_optional(pool)
+        .await
+}
+
+async fn deactivate(
+    pool: &PgPool,
+    actor_user_id: UserId,
+    subject: Subject,
+    query: &'static str,
+    reason: DeactivationReason,
+    note: Option<&str>,
+) -> Result<bool, sqlx::Error> {
+    let now = chrono::Utc::now().naive_utc();
+    let mut tx = pool.begin().await?;
+    let updated = sqlx::query(query)
+        .bind(subject.id())
+        .bind(reason)
+        .bind(note)
+        .bind(actor_user_id)
+        .bind(now)
+        .execute(&mut *tx)
+        .await?
+        .rows_affected();
+    if updated == 0 {
+        return Ok(false);
+    }
+
+    let (key, id, kind) = subject.log_fields();
+    insert_audit_log(
+        &mut *tx,
+        Some(actor_user_id),
+        json!({
+            "type": format!("{}_deactivated", kind),
+            key: id,
+            "reason": reason,
+            "note": note,
+        }),
+    )
+    .await?;
+    if let Subject::User(user_id) = subject {
+        RememberedSession::revoke_all_in(&mut tx, user_id).await?;
+    }
+    tx.commit().await?;
+    Ok(true)
+}
+
+fn check_acknowledged(
+    locked: Option<(Option<bool>, Option<DeactivationReason>)>,
+    acknowledged: Option<DeactivationReason>,
+) -> Result<(), ReactivationError> {
+    match locked {
+        None => Err(ReactivationError::NotFound),
+        Some((active, _)) if active != Some(false) => Err(ReactivationError::NotDeactivated),
+        Some((_, reason)) if reason != acknowledged => {
+            Err(ReactivationError::Unacknowledged { reason })
+        }
+        Some(_) => Ok(()),
+    }
+}
+
+async fn reactivate(
+    pool: &PgPool,
+    actor_user_id: UserId,
+    subject: Subject,
+    (lock, query): (&'static str, &'static str),
+    acknowledged: Option<DeactivationReason>,
+    note: Option<&str>,
+) -> Result<(), ReactivationError> {
+    let mut tx = pool.begin().await?;
+    let locked = sqlx::query_as(lock)
+        .bind(subject.id())
+        .fetch_optional(&mut *tx)
+        .await?;
+    check_acknowledged(locked, acknowledged)?;
+    sqlx::query(query)
+        .bind(subject.id())
+        .execute(&mut *tx)
+        .await?;
+
+    let (key, id, kind) = subject.log_fields();
+    insert_audit_log(
+        &mut *tx,
+        Some(actor_user_id),
+        json!({
+            "type": format!("{}_reactivated", kind),
+            key: id,
+            "acknowledged_reason": acknowledged,
+            "note": note,
+        }),
+    )
+    .await?;
+    tx.commit().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reactivation_requires_the_recorded_reason() {
+        let abuse = Some(DeactivationReason::Abuse);
+        assert!(check_acknowledged(Some((Some(false), abuse)), abuse).is_ok());
+        assert!(check_acknowledged(Some((Some(false), None)), None).is_ok());
+        assert!(matches!(
+            check_acknowledged(Some((Some(false), abuse)), None),
+            Err(ReactivationError::Unacknowledged { reason }) if reason == abuse
+        ));
+        assert!(matches!(
+            check_acknowledged(Some((Some(true), None)), None),
+            Err(ReactivationError::NotDeactivated)
+        ));
+        assert!(matches!(
+            check_acknowledged(None, abuse),
+            Err(ReactivationError::NotFound)
+        ));
+        assert_eq!(
+            "non_payment".parse::<DeactivationReason>(),
+            Ok(DeactivationReason::NonPayment)
+        );
+    }
+}
@@ -0,0 +1,356 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::sync::Arc;
+
+use email_address::EmailAddress;
+use futures_util::future::BoxFuture;
+use once_cell::sync::Lazy;
+
+const GMAIL_DOMAINS: &[&str] = &["gmail.com", "googlemail.com"];
+
+/// Well-known throwaway email providers rejected by the default `EmailDomainPolicy`.
+pub const DEFAULT_DISPOSABLE_DOMAINS: &[&str] = &[
+    "10minutemail.com",
+    "20minutemail.com",
+    "33mail.com",
+    "burnermail.io",
+    "discard.email",
+    "dispostable.com",
+    "emailondeck.com",
+    "fakeinbox.com",
+    "getairmail.com",
+    "getnada.com",
+    "guerrillamail.com",
+    "guerrillamail.net",
+    "guerrillamail.org",
+    "guerrillamailblock.com",
+    "inboxkitten.com",
+    "maildrop.cc",
+    "mailinator.com",
+    "mailnesia.com",
+    "mintemail.com",
+    "mohmal.com",
+    "mytemp.email",
+    "sharklasers.com",
+    "spamgourmet.com",
+    "temp-mail.org",
+    "tempail.com",
+    "tempmail.com",
+    "tempmail.dev",
+    "tempmailo.com",
+    "tempr.email",
+    "throwawaymail.com",
+    "trashmail.com",
+    "trashmail.de",
+    "yopmail.com",
+    "yopmail.fr",
+];
+
+pub static DEFAULT_EMAIL_NORMALIZER: Lazy<EmailNormalizer> = Lazy::new(EmailNormalizer::default);
+
+pub static DEFAULT_EMAIL_DOMAIN_POLICY: Lazy<EmailDomainPolicy> =
+    Lazy::new(EmailDomainPolicy::default);
+
+/// Produces the canonical email stored in `auth.users.email_canonical`.
+///
+/// The default only trims and lowercases. Enable the collapsing options to treat
+/// `first.last+tag@gmail.com` and `firstlast@gmail.com` as the same account.
+#[derive(Debug, Clone, Default)]
+pub struct EmailNormalizer {
+    collapse_plus: bool,
+    collapse_gmail_dots: bool,
+}
+
+impl EmailNormalizer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Drop `+tag` suffixes from the local part for every domain.
+    pub fn with_plus_collapsing(mut self, enabled: bool) -> Self {
+        self.collapse_plus = enabled;
+        self
+    }
+
+    /// Remove dots from Gmail local parts and fold `googlemail.com` into `gmail.com`.
+    pub fn with_gmail_dot_collapsing(mut self, enabled: bool) -> Self {
+        self.collapse_gmail_dots = enabled;
+        self
+    }
+
+    pub fn canonical(&self, email: &str) -> String {
+        let email = email.trim().to_lowercase();
+        let Some((local, domain)) = email.rsplit_once('@') else {
+            return email;
+        };
+
+        let mut local = local.to_string();
+        let mut domain = domain.to_string();
+        if self.collapse_plus
+            && let Some((base, _tag)) = local.split_once('+')
+        {
+            local = base.to_string();
+        }
+        if self.collapse_gmail_dots && GMAIL_DOMAINS.contains(&domain.as_str()) {
+            local = local.replace('.', "");
+            domain = GMAIL_DOMAINS[0].to_string();
+        }
+
+        format!("{}@{}", local, domain)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EmailDomainError {
+    Invalid,
+    Disposable { domain: String },
+    Denied { domain: String },
+    NoMailServer { domain: String },
+}
+
+impl EmailDomainError {
+    /// Stable machine-readable code for API responses.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::Invalid => "email_invalid",
+            Self::Disposable { .. } => "email_domain_disposable",
+            Self::Denied { .. } => "email_domain_denied",
+            Self::NoMailServer { .. } => "email_domain_no_mail_server",
+        }
+    }
+
+    pub fn domain(&self) -> Option<&str> {
+        match self {
+            Self::Invalid => None,
+            Self::Disposable { domain }
+            | Self::Denied { domain }
+            | Self::NoMailServer { domain } => Some(domain),
+        }
+    }
+}
+
+impl fmt::Display for EmailDomainError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Invalid => write!(f, "Email address is invalid"),
+            Self::Disposable { domain } => {
+                write!(f, "Disposable email domain {} is not allowed", domain)
+            }
+            Self::Denied { domain } => write!(f, "Email domain {} is not allowed", domain),
+            Self::NoMailServer { domain } => {
+                write!(f, "Email domain {} does not accept mail", domain)
+            }
+        }
+    }
+}
+
+impl std::error::Error for EmailDomainError {}
+
+/// Checks whether a domain can receive mail, e.g. by resolving its MX records.
+pub trait MailServerCheck: Send + Sync {
+    fn has_mail_server<'a>(&'a self, domain: &'a str) -> BoxFuture<'a, bool>;
+}
+
+/// Rules for which email domains may register or be set on an account.
+///
+/// A domain matches a list entry if it equals the entry or is a subdomain of it. Allowed domains
+/// bypass every other check; denied domains are rejected even when not disposable.
+#[derive(Clone)]
+pub struct EmailDomainPolicy {
+    block_disposable: bool,
+    disposable: HashSet<String>,
+    allowed: HashSet<String>,
+    denied: HashSet<String>,
+    mail_server_check: Option<Arc<dyn MailServerCheck>>,
+}
+
+impl Default for EmailDomainPolicy {
+    fn default() -> Self {
+        Self {
+            block_disposable: true,
+            disposable: DEFAULT_DISPOSABLE_DOMAINS
+                .iter()
+                .map(|domain| domain.to_string())
+                .collect(),
+            allowed: HashSet::new(),
+            denied: HashSet::new(),
+            mail_server_check: None,
+        }
+    }
+}
+
+impl fmt::Debug for EmailDomainPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EmailDomainPolicy")
+            .field("block_disposable", &self.block_disposable)
+            .field("disposable", &self.disposable.len())
+            .field("allowed", &self.allowed)
+            .field("denied", &self.denied)
+            .field("mail_server_check", &self.mail_server_check.is_some())
+            .finish()
+    }
+}
+
+impl EmailDomainPolicy {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A policy that accepts every syntactically valid address.
+    pub fn permissive() -> Self {
+        Self::default().with_disposable_blocking(false)
+    }
+
+    pub fn with_disposable_blocking(mut self, enabled: bool) -> Self {
+        self.block_disposable = enabled;
+        self
+    }
+
+    /// Add domains to the disposable list.
+    pub fn with_disposable_domains<I, S>(mut self, domains: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        self.disposable.extend(
+            domains
+                .into_iter()
+                .map(|domain| normalize_domain(domain.as_ref())),
+        );
+        self
+    }
+
+    pub fn allow_domain(mut self, domain: &str) -> Self {
+        self.allowed.insert(normalize_domain(domain));
+        self
+    }
+
+    pub fn deny_domain(mut self, domain: &str) -> Self {
+        self.denied.insert(normalize_domain(domain));
+        self
+    }
+
+    /// Also require the domain to accept mail, checked after the list rules pass.
+    pub fn with_mail_server_check(mut self, check: Arc<dyn MailServerCheck>) -> Self {
+        self.mail_server_check = Some(check);
+        self
+    }
+
+    /// Apply the list rules. Does not run the mail server check.
+    pub fn check(&self, email: &str) -> Result<(), EmailDomainError> {
+        let domain = email_domain(email).ok_or(EmailDomainError::Invalid)?;
+        if matches_domain(&self.allowed, &domain) {
+            return Ok(());
+        }
+        if matches_domain(&self.denied, &domain) {
+            return Err(EmailDomainError::Denied { domain });
+        }
+        if self.block_disposable && matches_domain(&self.disposable, &domain) {
+            return Err(EmailDomainError::Disposable { domain });
+        }
+        Ok(())
+    }
+
+    /// Apply the list rules, then the mail server check if one is configured. Allowed domains
+    /// skip the mail server check.
+    pub async fn validate(&self, email: &str) -> Result<(), EmailDomainError> {
+        self.check(email)?;
+        let Some(mail_server_check) = &self.mail_server_check else {
+            return Ok(());
+        };
+        let domain = email_domain(email).ok_or(EmailDomainError::Invalid)?;
+        if matches_domain(&self.allowed, &domain)
+            || mail_server_check.has_mail_server(&domain).await
+        {
+            Ok(())
+        } else {
+            Err(EmailDomainError::NoMailServer { domain })
+        }
+    }
+}
+
+pub(crate) fn normalize_domain(domain: &str) -> String {
+    domain.trim().trim_end_matches('.').to_lowercase()
+}
+
+/// Lowercased domain of a syntactically valid email address.
+pub fn email_domain(email: &str) -> Option<String> {
+    let email = email.trim();
+    if !EmailAddress::is_valid(email) {
+        return None;
+    }
+    email
+        .rsplit_once('@')
+        .map(|(_, domain)| normalize_domain(domain))
+}
+
+pub(crate) fn matches_domain(list: &HashSet<String>, domain: &str) -> bool {
+    let mut candidate = domain;
+    loop {
+        if list.contains(candidate) {
+            return true;
+        }
+        match candidate.split_once('.') {
+            Some((_, parent)) if parent.contains('.') => candidate = parent,
+            _ => return false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{EmailDomainError, EmailDomainPolicy, EmailNormalizer};
+
+    #[test]
+    fn default_only_trims_and_lowercases() {
+        let normalizer = EmailNormalizer::default();
+        assert_eq!(
+            normalizer.canonical("  First.Last+news@GMail.com "),
+            "first.last+news@gmail.com"
+        );
+    }
+
+    #[test]
+    fn collapsing_folds_plus_tags_and_gmail_dots() {
+        let normalizer = EmailNormalizer::new()
+            .with_plus_collapsing(true)
+            .with_gmail_dot_collapsing(true);
+        assert_eq!(
+            normalizer.canonical("First.Last+news@googlemail.com"),
+            "firstlast@gmail.com"
+        );
+        assert_eq!(
+            normalizer.canonical("first.last+1@example.com"),
+            "first.last@example.com"
+        );
+        assert_eq!(normalizer.canonical("not-an-email"), "not-an-email");
+    }
+
+    #[test]
+    fn domain_policy_applies_overrides_to_subdomains() {
+        let policy = EmailDomainPolicy::default();
+        assert_eq!(policy.check("someone@example.com"), Ok(()));
+        assert_eq!(
+            policy.check("someone@Mail.Mailinator.com"),
+            Err(EmailDomainError::Disposable {
+                domain: "mail.mailinator.com".to_string()
+            })
+        );
+        assert_eq!(policy.check("not-an-email"), Err(EmailDomainError::Invalid));
+
+        let policy = EmailDomainPolicy::default()
+            .allow_domain("mailinator.com")
+            .deny_domain("example.com");
+        assert_eq!(policy.check("someone@mailinator.com"), Ok(()));
+        assert_eq!(
+            policy.check("someone@corp.example.com"),
+            Err(EmailDomainError::Denied {
+                domain: "corp.example.com".to_string()
+            })
+        );
+        assert_eq!(
+            EmailDomainPolicy::permissive().check("someone@yopmail.com"),
+            Ok(())
+        );
+    }
+}
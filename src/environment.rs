@@ -0,0 +1,206 @@
+//! Environment namespaces for role grants, so one auth database can serve staging and production
+//! services without one's grants granting access in the other.
+//!
+//! A grant is pinned to an environment by storing its scope as `scope@environment`, e.g.
+//! `project@staging`; grants with a bare scope are shared by every environment. A process
+//! configured with an environment (`AuthConfig::environment`, applied with `set_environment` at
+//! startup) resolves role checks against its own pinned grants and, in `EnvironmentMode::Shared`,
+//! the shared ones. Grants pinned to other environments never count. Without an environment only
+//! shared grants count, as before.
+//!
+//! Every path that stores a grant checks its scope with `validate_scope`. Grants stored before
+//! environments existed are not checked, and a scope containing `@` among them reads as pinned to
+//! whatever follows its last `@`. Look for such scopes in `auth.user_roles`, `auth.group_roles`,
+//! `auth.role_bundle_grants` and `auth.group_default_roles` (`WHERE scope LIKE '%@%'`) and rename
+//! them before configuring an environment.
+
+use std::str::FromStr;
+use std::sync::RwLock;
+
+use serde::{Deserialize, Serialize};
+
+/// Separates a scope from the environment it is pinned to.
+pub const ENVIRONMENT_SEPARATOR: char = '@';
+
+/// Which grants count in a process running in an environment.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EnvironmentMode {
+    /// Grants pinned to this environment and shared grants.
+    #[default]
+    Shared,
+    /// Only grants pinned to this environment. Pin new grants through the `environment` field
+    /// of the role APIs.
+    Isolated,
+}
+
+impl FromStr for EnvironmentMode {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_ascii_lowercase().as_str() {
+            "shared" => Ok(EnvironmentMode::Shared),
+            "isolated" => Ok(EnvironmentMode::Isolated),
+            _ => Err("expected shared or isolated".to_string()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Environment {
+    /// E.g. `staging` or `prod`.
+    pub name: String,
+    #[serde(default)]
+    pub mode: EnvironmentMode,
+}
+
+impl Environment {
+    pub fn new(name: impl Into<String>, mode: EnvironmentMode) -> Self {
+        Self {
+            name: name.into(),
+            mode,
+        }
+    }
+}
+
+/// Whether `name` can name an environment: ASCII letters, digits, `-` and `_`.
+pub fn is_valid_environment_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+static ENVIRONMENT: RwLock<Option<Environment>> = RwLock::new(None);
+
+/// Set the process-wide environment, e.g. from `AuthConfig::environment` at startup.
+pub fn set_environment(environment: Option<Environment>) {
+    *ENVIRONMENT.write().unwrap_or_else(|err| err.into_inner()) = environment;
+}
+
+pub fn environment() -> Option<Environment> {
+    ENVIRONMENT
+        .read()
+        .unwrap_or_else(|err| err.into_inner())
+        .clone()
+}
+
+/// The scope of a grant pinned to `environment`.
+pub fn pinned_scope(scope: &str, environment: &str) -> String {
+    format!("{}{}{}", scope, ENVIRONMENT_SEPARATOR, environment)
+}
+
+/// Check a grant scope before it is stored. It must not be blank or contain
+/// `ENVIRONMENT_SEPARATOR`, which would read as a pin to another environment; pinned scopes are
+/// built with `pinned_scope`.
+pub fn validate_scope(scope: &str) -> Result<(), &'static str> {
+    if scope.trim().is_empty() {
+        return Err("scope must not be blank");
+    }
+    if scope.contains(ENVIRONMENT_SEPARATOR) {
+        return Err("scope may not contain @");
+    }
+    Ok(())
+}
+
+/// A stored grant scope split into the scope and the environment it is pinned to, if any.
+pub fn split_scope(scope: &str) -> (&str, Option<&str>) {
+    match scope.rsplit_once(ENVIRONMENT_SEPARATOR) {
+        Some((scope, environment)) => (scope, Some(environment)),
+        None => (scope, None),
+    }
+}
+
+/// The stored scopes whose grants count for `scope` in the current environment.
+pub fn lookup_scopes(scope: &str) -> Vec<String> {
+    lookup_scopes_for(environment().as_ref(), scope)
+}
+
+/// The scope a grant stored as `stored` counts for in the current environment, or `None` if it
+/// belongs to another one.
+pub fn resolve_scope(stored: &str) -> Option<&str> {
+    resolve_scope_for(environment().as_ref(), stored)
+}
+
+/// The scope to store a grant for `scope` under so it counts in the current environment: pinned
+/// in `EnvironmentMode::Isolated`, bare otherwise.
+pub fn grant_scope(scope: &str) -> String {
+    match environment() {
+        Some(environment) if environment.mode == EnvironmentMode::Isolated => {
+            pinned_scope(scope, &environment.name)
+        }
+        _ => scope.to_string(),
+    }
+}
+
+pub fn lookup_scopes_for(environment: Option<&Environment>, scope: &str) -> Vec<String> {
+    match environment {
+        None => vec![scope.to_string()],
+        Some(environment) => {
+            let pinned = pinned_scope(scope, &environment.name);
+            match environment.mode {
+                EnvironmentMode::Shared => vec![scope.to_string(), pinned],
+                EnvironmentMode::Isolated => vec![pinned],
+            }
+        }
+    }
+}
+
+pub fn resolve_scope_for<'a>(
+    environment: Option<&Environment>,
+    stored: &'a str,
+) -> Option<&'a str> {
+    match (split_scope(stored), environment) {
+        ((scope, None), None) => Some(scope),
+        ((_, Some(_)), None) => None,
+        ((scope, None), Some(environment)) => {
+            (environment.mode == EnvironmentMode::Shared).then_some(scope)
+        }
+        ((scope, Some(pinned)), Some(environment)) => (pinned == environment.name).then_some(scope),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        Environment, EnvironmentMode, lookup_scopes_for, resolve_scope_for, validate_scope,
+    };
+
+    #[test]
+    fn resolves_grants_by_environment() {
+        assert_eq!(lookup_scopes_for(None, "project"), vec!["project"]);
+        assert_eq!(resolve_scope_for(None, "project"), Some("project"));
+        assert_eq!(resolve_scope_for(None, "project@prod"), None);
+
+        let shared = Environment::new("staging", EnvironmentMode::Shared);
+        assert_eq!(
+            lookup_scopes_for(Some(&shared), "project"),
+            vec!["project", "project@staging"]
+        );
+        assert_eq!(resolve_scope_for(Some(&shared), "project"), Some("project"));
+        assert_eq!(
+            resolve_scope_for(Some(&shared), "project@staging"),
+            Some("project")
+        );
+        assert_eq!(resolve_scope_for(Some(&shared), "project@prod"), None);
+
+        let isolated = Environment::new("prod", EnvironmentMode::Isolated);
+        assert_eq!(
+            lookup_scopes_for(Some(&isolated), "global"),
+            vec!["global@prod"]
+        );
+        assert_eq!(resolve_scope_for(Some(&isolated), "global"), None);
+        assert_eq!(
+            resolve_scope_for(Some(&isolated), "global@prod"),
+            Some("global")
+        );
+    }
+
+    #[test]
+    fn rejects_scopes_that_read_as_pinned() {
+        assert!(validate_scope("project").is_ok());
+        assert!(validate_scope(" ").is_err());
+        assert!(validate_scope("project@prod").is_err());
+    }
+}
@@ -0,0 +1,362 @@
+//! Versioned JSON snapshots of the auth schema, for cloning an environment or rehearsing disaster
+//! recovery. `import::restore` loads one into an empty schema.
+//!
+//! A snapshot holds users, groups, memberships, grants, group role definitions and defaults,
+//! delegation policies and role bundles. It holds no secrets or sessions: password hashes, HMAC
+//! and signing keys, OAuth clients, tokens, remembered sessions and invitations stay behind, as do
+//! the audit log and login history. `anonymize` strips personal data from the rest.
+
+pub mod anonymize;
+
+use std::collections::BTreeMap;
+
+use chrono::NaiveDateTime;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sqlx::{FromRow, PgPool};
+
+use crate::bundle::BundleGrant;
+use crate::db::{GroupVisibility, RoleEffect, applied_migrations};
+use crate::export::anonymize::AnonymizeConfig;
+use crate::group_id::GroupId;
+use crate::user_id::UserId;
+
+/// The snapshot layout `dump` writes and `import::restore` reads. Bumped whenever a record
+/// changes shape.
+pub const SNAPSHOT_FORMAT_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, FromRow)]
+#[serde(deny_unknown_fields)]
+pub struct UserRecord {
+    pub id: UserId,
+    pub username: Option<String>,
+    pub email: String,
+    pub details: Option<Value>,
+    pub email_canonical: Option<String>,
+    pub version: i64,
+    pub locale: Option<String>,
+    #[serde(default)]
+    pub display_name: Option<String>,
+    #[serde(default)]
+    pub timezone: Option<String>,
+    pub external_id: Option<String>,
+    pub active: Option<bool>,
+    pub pending_approval: bool,
+    pub created_at: Option<NaiveDateTime>,
+    pub last_login_at: Option<NaiveDateTime>,
+    pub deactivation_reason: Option<String>,
+    pub deactivation_note: Option<String>,
+    pub deactivated_by: Option<UserId>,
+    pub deactivated_at: Option<NaiveDateTime>,
+    #[serde(default)]
+    pub deleted_at: Option<NaiveDateTime>,
+    #[serde(default)]
+    pub deleted_by: Option<UserId>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, FromRow)]
+#[serde(deny_unknown_fields)]
+pub struct GroupRecord {
+    pub id: GroupId,
+    pub display_name: String,
+    pub details: Option<Value>,
+    pub version: i64,
+    pub visibility: GroupVisibility,
+    pub external_id: Option<String>,
+    pub active: Option<bool>,
+    pub created_at: Option<NaiveDateTime>,
+    pub deactivation_reason: Option<String>,
+    pub deactivation_note: Option<String>,
+    pub deactivated_by: Option<UserId>,
+    pub deactivated_at: Option<NaiveDateTime>,
+    #[serde(default)]
+    pub archived_at: Option<NaiveDateTime>,
+    #[serde(default)]
+    pub archived_by: Option<UserId>,
+    #[serde(default)]
+    pub deleted_at: Option<NaiveDateTime>,
+    #[serde(default)]
+    pub deleted_by: Option<UserId>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, FromRow)]
+#[serde(deny_unknown_fields)]
+pub struct MembershipRecord {
+    pub group_id: GroupId,
+    pub user_id: UserId,
+    pub role_name: String,
+    pub created_at: Option<NaiveDateTime>,
+    pub source_provider: Option<String>,
+    /// `GroupCapabilities` bits delegated on top of the role's.
+    #[serde(default)]
+    pub capabilities: i64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, FromRow)]
+#[serde(deny_unknown_fields)]
+pub struct UserGrantRecord {
+    pub user_id: UserId,
+    pub scope: String,
+    pub scope_id: String,
+    pub role_name: String,
+    pub effect: RoleEffect,
+    pub condition: Option<Value>,
+    pub created_at: Option<NaiveDateTime>,
+    pub source_provider: Option<String>,
+    pub source_group_id: Option<GroupId>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, FromRow)]
+#[serde(deny_unknown_fields)]
+pub struct GroupGrantRecord {
+    pub group_id: GroupId,
+    pub scope: String,
+    pub scope_id: String,
+    pub role_name: String,
+    pub effect: RoleEffect,
+    pub condition: Option<Value>,
+    pub created_at: Option<NaiveDateTime>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, FromRow)]
+#[serde(deny_unknown_fields)]
+pub struct GroupRoleDefinitionRecord {
+    pub group_id: GroupId,
+    pub role_name: String,
+    pub capabilities: i64,
+    pub description: Option<String>,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, FromRow)]
+#[serde(deny_unknown_fields)]
+pub struct GroupDefaultRoleRecord {
+    pub group_id: GroupId,
+    pub scope: String,
+    pub scope_id: String,
+    pub role_name: String,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, FromRow)]
+#[serde(deny_unknown_fields)]
+pub struct DelegationPolicyRecord {
+    pub scope: String,
+    pub scope_id: String,
+    pub admin_role: String,
+    pub grantable_role: String,
+    pub created_at: Option<NaiveDateTime>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RoleBundleRecord {
+    pub name: String,
+    pub description: Option<String>,
+    pub created_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+    pub grants: Vec<BundleGrant>,
+}
+
+/// Everything `dump` exports, in a stable order so two dumps of the same data are identical but
+/// for `exported_at`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AuthSnapshot {
+    pub format_version: u32,
+    /// The newest migration applied to the exported database. A snapshot only restores into a
+    /// schema at least this new.
+    pub schema_version: i64,
+    pub exported_at: NaiveDateTime,
+    /// Personal data was replaced by `anonymize`.
+    #[serde(default)]
+    pub anonymized: bool,
+    pub users: Vec<UserRecord>,
+    pub groups: Vec<GroupRecord>,
+    pub memberships: Vec<MembershipRecord>,
+    pub user_grants: Vec<UserGrantRecord>,
+    pub group_grants: Vec<GroupGrantRecord>,
+    pub group_role_definitions: Vec<GroupRoleDefinitionRecord>,
+    pub group_default_roles: Vec<GroupDefaultRoleRecord>,
+    pub delegation_policies: Vec<DelegationPolicyRecord>,
+    pub role_bundles: Vec<RoleBundleRecord>,
+}
+
+#[derive(FromRow)]
+struct BundleRow {
+    name: String,
+    description: Option<String>,
+    created_at: NaiveDateTime,
+    updated_at: NaiveDateTime,
+}
+
+#[derive(FromRow)]
+struct BundleGrantRow {
+    bundle_name: String,
+    scope: String,
+    role_name: String,
+}
+
+/// Export the auth schema. Reads in one repeatable-read transaction, so the snapshot is
+/// consistent however long it takes.
+pub async fn dump(pool: &PgPool) -> Result<AuthSnapshot, sqlx::Error> {
+    let schema_version = applied_migrations(pool)
+        .await?
+        .iter()
+        .filter(|migration| migration.success)
+        .map(|migration| migration.version)
+        .max()
+        .unwrap_or(0);
+
+    let mut tx = pool.begin().await?;
+    sqlx::query("SET TRANSACTION ISOLATION LEVEL REPEATABLE READ READ ONLY")
+        .execute(&mut *tx)
+        .await?;
+    let exported_at = sqlx::query_scalar("SELECT LOCALTIMESTAMP")
+        .fetch_one(&mut *tx)
+        .await?;
+    let users = sqlx::query_as::<_, UserRecord>(
+        r#"
+        SELECT id, username, email, details, email_canonical, version, locale, display_name,
+               timezone, external_id, active, pending_approval, created_at, last_login_at,
+               deactivation_reason, deactivation_note, deactivated_by, deactivated_at, deleted_at,
+               deleted_by
+        FROM auth.users
+        ORDER BY id ASC
+        "#,
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+    let groups = sqlx::query_as::<_, GroupRecord>(
+        r#"
+        SELECT id, display_name, details, version, visibility, external_id, active, created_at,
+               deactivation_reason, deactivation_note, deactivated_by, deactivated_at,
+               archived_at, archived_by, deleted_at, deleted_by
+        FROM auth.groups
+        ORDER BY id ASC
+        "#,
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+    let memberships = sqlx::query_as::<_, MembershipRecord>(
+        r#"
+        SELECT group_id, user_id, role_name, created_at, source_provider, capabilities
+        FROM auth.group_memberships
+        ORDER BY group_id ASC, user_id ASC
+        "#,
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+    let user_grants = sqlx::query_as::<_, UserGrantRecord>(
+        r#"
+        SELECT user_id, scope, scope_id, role_name, effect, condition, created_at,
+               source_provider, source_group_id
+        FROM auth.user_roles
+        ORDER BY user_id ASC, scope ASC, scope_id ASC, role_name ASC
+        "#,
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+    let group_grants = sqlx::query_as::<_, GroupGrantRecord>(
+        r#"
+        SELECT group_id, scope, scope_id, role_name, effect, condition, created_at
+        FROM auth.group_roles
+        ORDER BY group_id ASC, scope ASC, scope_id ASC, role_name ASC
+        "#,
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+    let group_role_definitions = sqlx::query_as::<_, GroupRoleDefinitionRecord>(
+        r#"
+        SELECT group_id, role_name, capabilities, description, created_at
+        FROM auth.group_role_definitions
+        ORDER BY group_id ASC, role_name ASC
+        "#,
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+    let group_default_roles = sqlx::query_as::<_, GroupDefaultRoleRecord>(
+        r#"
+        SELECT group_id, scope, scope_id, role_name, created_at
+        FROM auth.group_default_roles
+        ORDER BY group_id ASC, scope ASC, scope_id ASC, role_name ASC
+        "#,
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+    let delegation_policies = sqlx::query_as::<_, DelegationPolicyRecord>(
+        r#"
+        SELECT scope, scope_id, admin_role, grantable_role, created_at
+        FROM auth.role_delegation_policy
+        ORDER BY scope ASC, scope_id ASC, admin_role ASC, grantable_role ASC
+        "#,
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+    let bundles = sqlx::query_as::<_, BundleRow>(
+        r#"
+        SELECT name, description, created_at, updated_at
+        FROM auth.role_bundles
+        ORDER BY name ASC
+        "#,
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+    let bundle_grants = sqlx::query_as::<_, BundleGrantRow>(
+        r#"
+        SELECT bundle_name, scope, role_name
+        FROM auth.role_bundle_grants
+        ORDER BY bundle_name ASC, scope ASC, role_name ASC
+        "#,
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+    tx.commit().await?;
+
+    let mut grants_by_bundle: BTreeMap<String, Vec<BundleGrant>> = BTreeMap::new();
+    for grant in bundle_grants {
+        grants_by_bundle
+            .entry(grant.bundle_name)
+            .or_default()
+            .push(BundleGrant {
+                scope: grant.scope,
+                role_name: grant.role_name,
+            });
+    }
+    let role_bundles = bundles
+        .into_iter()
+        .map(|bundle| RoleBundleRecord {
+            grants: grants_by_bundle.remove(&bundle.name).unwrap_or_default(),
+            name: bundle.name,
+            description: bundle.description,
+            created_at: bundle.created_at,
+            updated_at: bundle.updated_at,
+        })
+        .collect();
+
+    Ok(AuthSnapshot {
+        format_version: SNAPSHOT_FORMAT_VERSION,
+        schema_version,
+        exported_at,
+        anonymized: false,
+        users,
+        groups,
+        memberships,
+        user_grants,
+        group_grants,
+        group_role_definitions,
+        group_default_roles,
+        delegation_policies,
+        role_bundles,
+    })
+}
+
+/// `dump`, then `anonymize::anonymize`, for loading production data into staging.
+pub async fn dump_anonymized(
+    pool: &PgPool,
+    config: &AnonymizeConfig,
+) -> Result<AuthSnapshot, sqlx::Error> {
+    let mut snapshot = dump(pool).await?;
+    anonymize::anonymize(&mut snapshot, config);
+    Ok(snapshot)
+}
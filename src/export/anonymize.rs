@@ -0,0 +1,291 @@
+//! Strips personal data from a snapshot so production can be copied into staging.
+//!
+//! Emails, usernames, display names and external ids are replaced with salted hashes, so the same
+//! person gets the same stand-in on every refresh with the same salt and uniqueness constraints
+//! still hold.
+//! Deactivation notes are dropped. `details` is rewritten by `DetailsRule`s, since only the
+//! application knows what it keeps there. Ids, grants and memberships are left alone.
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+
+use super::AuthSnapshot;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AnonymizeConfig {
+    /// Mixed into every hash. Keep it secret: with it, anyone can check a guessed email against
+    /// the snapshot.
+    pub salt: String,
+    /// Domain of the rewritten emails.
+    #[serde(default = "default_email_domain")]
+    pub email_domain: String,
+    /// Replace group display names too, for groups named after customers.
+    #[serde(default)]
+    pub rename_groups: bool,
+    /// Applied in order to the `details` of every user and group.
+    #[serde(default)]
+    pub details: Vec<DetailsRule>,
+}
+
+fn default_email_domain() -> String {
+    "example.invalid".to_string()
+}
+
+impl AnonymizeConfig {
+    pub fn new(salt: impl Into<String>) -> Self {
+        Self {
+            salt: salt.into(),
+            email_domain: default_email_domain(),
+            rename_groups: false,
+            details: Vec::new(),
+        }
+    }
+
+    pub fn email_domain(mut self, domain: impl Into<String>) -> Self {
+        self.email_domain = domain.into();
+        self
+    }
+
+    pub fn rename_groups(mut self, enabled: bool) -> Self {
+        self.rename_groups = enabled;
+        self
+    }
+
+    pub fn details_rule(mut self, path: impl Into<String>, action: DetailsAction) -> Self {
+        self.details.push(DetailsRule {
+            path: path.into(),
+            action,
+        });
+        self
+    }
+}
+
+/// Rewrites the values at `path` in `details`.
+///
+/// The path is slash-separated keys or array indexes, e.g. `/address/street`; `*` matches every
+/// key or index at its level, and `/` alone is the whole document.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DetailsRule {
+    pub path: String,
+    pub action: DetailsAction,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DetailsAction {
+    /// Delete the key, or null the array element.
+    Remove,
+    /// Replace the value with a salted hash of its JSON text.
+    Hash,
+    Replace(Value),
+}
+
+/// Rewrite `snapshot` in place as `config` says and mark it anonymized.
+pub fn anonymize(snapshot: &mut AuthSnapshot, config: &AnonymizeConfig) {
+    let hasher = Hasher(&config.salt);
+    for user in &mut snapshot.users {
+        let email = format!(
+            "{}@{}",
+            hasher.hash("email", &user.email, 16),
+            config.email_domain
+        );
+        if user.email_canonical.is_some() {
+            user.email_canonical = Some(email.to_lowercase());
+        }
+        user.email = email;
+        user.username = user
+            .username
+            .as_deref()
+            .map(|username| format!("user_{}", hasher.hash("username", username, 12)));
+        user.display_name = user
+            .display_name
+            .as_deref()
+            .map(|name| format!("User {}", hasher.hash("display_name", name, 8)));
+        user.external_id = user
+            .external_id
+            .as_deref()
+            .map(|external_id| hasher.hash("external_id", external_id, 32));
+        user.deactivation_note = None;
+        apply_rules(&mut user.details, &config.details, &hasher);
+    }
+    for group in &mut snapshot.groups {
+        if config.rename_groups {
+            group.display_name = format!("group_{}", hasher.hash("group", &group.display_name, 12));
+        }
+        group.external_id = group
+            .external_id
+            .as_deref()
+            .map(|external_id| hasher.hash("external_id", external_id, 32));
+        group.deactivation_note = None;
+        apply_rules(&mut group.details, &config.details, &hasher);
+    }
+    snapshot.anonymized = true;
+}
+
+struct Hasher<'a>(&'a str);
+
+impl Hasher<'_> {
+    /// The first `len` hex digits of the salted hash. `kind` keeps equal values of different
+    /// fields from getting equal stand-ins.
+    fn hash(&self, kind: &str, value: &str, len: usize) -> String {
+        let digest = Sha256::new()
+            .chain_update(self.0.as_bytes())
+            .chain_update([0])
+            .chain_update(kind.as_bytes())
+            .chain_update([0])
+            .chain_update(value.as_bytes())
+            .finalize();
+        let mut hex: String = digest.iter().map(|byte| format!("{:02x}", byte)).collect();
+        hex.truncate(len);
+        hex
+    }
+}
+
+fn apply_rules(details: &mut Option<Value>, rules: &[DetailsRule], hasher: &Hasher<'_>) {
+    let Some(value) = details else {
+        return;
+    };
+    for rule in rules {
+        let segments: Vec<&str> = rule
+            .path
+            .split('/')
+            .filter(|segment| !segment.is_empty())
+            .collect();
+        if segments.is_empty() {
+            match &rule.action {
+                DetailsAction::Remove => {
+                    *details = None;
+                    return;
+                }
+                action => rewrite(value, action, hasher),
+            }
+        } else {
+            apply_at(value, &segments, &rule.action, hasher);
+        }
+    }
+}
+
+fn apply_at(value: &mut Value, segments: &[&str], action: &DetailsAction, hasher: &Hasher<'_>) {
+    let (segment, rest) = (segments[0], &segments[1..]);
+    let children: Vec<&mut Value> = match value {
+        Value::Object(fields) if rest.is_empty() && *action == DetailsAction::Remove => {
+            if segment == "*" {
+                fields.clear();
+            } else {
+                fields.remove(segment);
+            }
+            return;
+        }
+        Value::Object(fields) if segment == "*" => fields.values_mut().collect(),
+        Value::Object(fields) => fields.get_mut(segment).into_iter().collect(),
+        Value::Array(items) if segment == "*" => items.iter_mut().collect(),
+        Value::Array(items) => segment
+            .parse::<usize>()
+            .ok()
+            .and_then(|index| items.get_mut(index))
+            .into_iter()
+            .collect(),
+        _ => return,
+    };
+    for child in children {
+        if rest.is_empty() {
+            rewrite(child, action, hasher);
+        } else {
+            apply_at(child, rest, action, hasher);
+        }
+    }
+}
+
+fn rewrite(value: &mut Value, action: &DetailsAction, hasher: &Hasher<'_>) {
+    *value = match action {
+        DetailsAction::Remove => Value::Null,
+        DetailsAction::Hash => Value::String(hasher.hash("details", &value.to_string(), 16)),
+        DetailsAction::Replace(replacement) => replacement.clone(),
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::{AnonymizeConfig, DetailsAction, anonymize};
+    use crate::export::{AuthSnapshot, SNAPSHOT_FORMAT_VERSION, UserRecord};
+    use crate::user_id::UserId;
+
+    fn snapshot(email: &str, details: serde_json::Value) -> AuthSnapshot {
+        AuthSnapshot {
+            format_version: SNAPSHOT_FORMAT_VERSION,
+            schema_version: 0,
+            exported_at: chrono::DateTime::UNIX_EPOCH.naive_utc(),
+            anonymized: false,
+            users: vec![UserRecord {
+                id: UserId(uuid::Uuid::nil()),
+                username: Some("Ada".to_string()),
+                email: email.to_string(),
+                details: Some(details),
+                email_canonical: Some(email.to_lowercase()),
+                version: 1,
+                locale: None,
+                display_name: Some("Ada Lovelace".to_string()),
+                timezone: Some("Europe/London".to_string()),
+                external_id: Some("google|123".to_string()),
+                active: Some(true),
+                pending_approval: false,
+                created_at: None,
+                last_login_at: None,
+                deactivation_reason: None,
+                deactivation_note: Some("called support".to_string()),
+                deactivated_by: None,
+                deactivated_at: None,
+                deleted_at: None,
+                deleted_by: None,
+            }],
+            groups: Vec::new(),
+            memberships: Vec::new(),
+            user_grants: Vec::new(),
+            group_grants: Vec::new(),
+            group_role_definitions: Vec::new(),
+            group_default_roles: Vec::new(),
+            delegation_policies: Vec::new(),
+            role_bundles: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn rewrites_personal_data() {
+        let config = AnonymizeConfig::new("salt")
+            .details_rule("/phone", DetailsAction::Remove)
+            .details_rule("/contacts/*/name", DetailsAction::Hash)
+            .details_rule("/plan", DetailsAction::Replace(json!("free")));
+        let details = json!({
+            "phone": "555-0100",
+            "plan": "enterprise",
+            "theme": "dark",
+            "contacts": [{"name": "Bob", "kind": "billing"}],
+        });
+        let mut first = snapshot("Ada@Example.com", details.clone());
+        anonymize(&mut first, &config);
+        let user = &first.users[0];
+        assert!(first.anonymized);
+        assert!(user.email.ends_with("@example.invalid"));
+        assert!(!user.email.contains("ada"));
+        assert_eq!(user.email_canonical.as_deref(), Some(user.email.as_str()));
+        assert!(user.username.as_deref().unwrap().starts_with("user_"));
+        assert!(!user.display_name.as_deref().unwrap().contains("Ada"));
+        assert_eq!(user.deactivation_note, None);
+        let details = user.details.as_ref().unwrap();
+        assert_eq!(details.get("phone"), None);
+        assert_eq!(details["plan"], "free");
+        assert_eq!(details["theme"], "dark");
+        assert_eq!(details["contacts"][0]["kind"], "billing");
+        assert_ne!(details["contacts"][0]["name"], "Bob");
+
+        let mut again = snapshot("Ada@Example.com", json!({}));
+        anonymize(&mut again, &config);
+        assert_eq!(again.users[0].email, user.email);
+        let mut salted = snapshot("Ada@Example.com", json!({}));
+        anonymize(&mut salted, &AnonymizeConfig::new("other"));
+        assert_ne!(salted.users[0].email, user.email);
+    }
+}
@@ -0,0 +1,158 @@
+use std::fmt;
+use std::sync::Arc;
+
+use once_cell::sync::Lazy;
+use ring::rand::{SecureRandom, SystemRandom};
+use uuid::Uuid;
+
+/// The URL-safe alphabet of nanoid.
+pub const NANOID_ALPHABET: &str =
+    "_-0123456789abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ";
+pub const DEFAULT_EXTERNAL_ID_LENGTH: usize = 12;
+pub const MAX_EXTERNAL_ID_LENGTH: usize = 64;
+
+pub static DEFAULT_EXTERNAL_ID_GENERATOR: Lazy<ExternalIdGenerator> =
+    Lazy::new(ExternalIdGenerator::default);
+
+type GenerateFn = Arc<dyn Fn() -> String + Send + Sync>;
+
+/// Produces the `external_id` of new users and groups: an identifier for URLs that does not
+/// reveal the row's UUID.
+///
+/// The default is a 12 character nanoid, e.g. `V1StGXR8_Z5j`. Generated ids are not checked
+/// against `is_valid_external_id`; a custom generator must keep to it for `set_external_id` and
+/// API lookups to accept its ids.
+#[derive(Clone)]
+pub struct ExternalIdGenerator {
+    alphabet: Vec<char>,
+    length: usize,
+    prefix: String,
+    custom: Option<GenerateFn>,
+}
+
+impl Default for ExternalIdGenerator {
+    fn default() -> Self {
+        Self {
+            alphabet: NANOID_ALPHABET.chars().collect(),
+            length: DEFAULT_EXTERNAL_ID_LENGTH,
+            prefix: String::new(),
+            custom: None,
+        }
+    }
+}
+
+impl fmt::Debug for ExternalIdGenerator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ExternalIdGenerator")
+            .field("alphabet", &self.alphabet.iter().collect::<String>())
+            .field("length", &self.length)
+            .field("prefix", &self.prefix)
+            .field("custom", &self.custom.is_some())
+            .finish()
+    }
+}
+
+impl ExternalIdGenerator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Draw characters from `alphabet`, e.g. to avoid look-alikes. Duplicates are dropped; an
+    /// alphabet with fewer than two or more than 256 characters is ignored.
+    pub fn with_alphabet(mut self, alphabet: &str) -> Self {
+        let mut chars: Vec<char> = Vec::new();
+        for c in alphabet.chars() {
+            if !chars.contains(&c) {
+                chars.push(c);
+            }
+        }
+        if (2..=256).contains(&chars.len()) {
+            self.alphabet = chars;
+        }
+        self
+    }
+
+    /// Random characters per id, not counting the prefix.
+    pub fn with_length(mut self, length: usize) -> Self {
+        self.length = length.clamp(1, MAX_EXTERNAL_ID_LENGTH);
+        self
+    }
+
+    /// Start every id with `prefix`, e.g. `grp_`.
+    pub fn with_prefix<S: Into<String>>(mut self, prefix: S) -> Self {
+        self.prefix = prefix.into();
+        self
+    }
+
+    /// Use `generate` instead of random characters, e.g. to derive ids from a sequence.
+    pub fn with_fn<F>(mut self, generate: F) -> Self
+    where
+        F: Fn() -> String + Send + Sync + 'static,
+    {
+        self.custom = Some(Arc::new(generate));
+        self
+    }
+
+    pub fn generate(&self) -> String {
+        if let Some(generate) = &self.custom {
+            return generate();
+        }
+        let rng = SystemRandom::new();
+        // Rejection sampling keeps every character equally likely.
+        let mask = self.alphabet.len().next_power_of_two() - 1;
+        let mut id = self.prefix.clone();
+        let mut remaining = self.length;
+        let mut bytes = [0u8; 32];
+        while remaining > 0 {
+            rng.fill(&mut bytes)
+                .expect("system random number generator failed");
+            for byte in bytes {
+                if let Some(c) = self.alphabet.get(usize::from(byte) & mask) {
+                    id.push(*c);
+                    remaining -= 1;
+                    if remaining == 0 {
+                        break;
+                    }
+                }
+            }
+        }
+        id
+    }
+}
+
+/// Whether `value` can be a user's or group's `external_id`: 1 to 64 ASCII letters, digits, `-`
+/// or `_`, and not itself a UUID, so a path segment is never ambiguous.
+pub fn is_valid_external_id(value: &str) -> bool {
+    (1..=MAX_EXTERNAL_ID_LENGTH).contains(&value.len())
+        && value
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+        && Uuid::parse_str(value).is_err()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ExternalIdGenerator, is_valid_external_id};
+
+    #[test]
+    fn generates_valid_ids() {
+        let generator = ExternalIdGenerator::new();
+        let id = generator.generate();
+        assert_eq!(id.len(), 12);
+        assert!(is_valid_external_id(&id));
+        assert_ne!(id, generator.generate());
+
+        let id = ExternalIdGenerator::new()
+            .with_alphabet("0123456789")
+            .with_length(8)
+            .with_prefix("grp_")
+            .generate();
+        assert!(id.starts_with("grp_"));
+        assert!(id[4..].bytes().all(|b| b.is_ascii_digit()));
+        assert_eq!(id.len(), 12);
+
+        assert!(!is_valid_external_id(&uuid::Uuid::new_v4().to_string()));
+        assert!(!is_valid_external_id("acme corp"));
+        assert!(!is_valid_external_id(""));
+    }
+}
@@ -0,0 +1,133 @@
+use std::fmt;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// The response fields a client asked for, e.g. with `?fields=id,email`, so large values such as
+/// `details` stay off the wire. Empty selects every field.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct FieldMask {
+    fields: Vec<String>,
+}
+
+#[derive(Debug)]
+pub enum FieldMaskError {
+    /// A requested field is not part of the response.
+    UnknownField(String),
+    Serialize(serde_json::Error),
+}
+
+impl fmt::Display for FieldMaskError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnknownField(field) => write!(f, "Unknown field {}", field),
+            Self::Serialize(err) => write!(f, "Failed to serialize response: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for FieldMaskError {}
+
+impl FieldMask {
+    /// Every field.
+    pub fn all() -> Self {
+        Self::default()
+    }
+
+    pub fn is_all(&self) -> bool {
+        self.fields.is_empty()
+    }
+
+    pub fn includes(&self, field: &str) -> bool {
+        self.is_all() || self.fields.iter().any(|selected| selected == field)
+    }
+
+    /// `value` serialized with only the selected fields. Applies to an object, or to each object
+    /// of an array; other values are returned whole. Naming a field an object lacks is an error.
+    pub fn apply<T: Serialize>(&self, value: &T) -> Result<Value, FieldMaskError> {
+        let mut value = serde_json::to_value(value).map_err(FieldMaskError::Serialize)?;
+        if self.is_all() {
+            return Ok(value);
+        }
+        match &mut value {
+            Value::Array(items) => {
+                for item in items {
+                    self.retain(item)?;
+                }
+            }
+            item => self.retain(item)?,
+        }
+        Ok(value)
+    }
+
+    fn retain(&self, value: &mut Value) -> Result<(), FieldMaskError> {
+        let Value::Object(map) = value else {
+            return Ok(());
+        };
+        if let Some(unknown) = self.fields.iter().find(|field| !map.contains_key(*field)) {
+            return Err(FieldMaskError::UnknownField(unknown.clone()));
+        }
+        map.retain(|key, _| self.includes(key));
+        Ok(())
+    }
+}
+
+impl FromStr for FieldMask {
+    type Err = String;
+
+    /// Comma-separated field names. Blank entries and repeats are dropped.
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let mut fields: Vec<String> = Vec::new();
+        for field in value.split(',').map(str::trim).filter(|f| !f.is_empty()) {
+            if !field
+                .bytes()
+                .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'_')
+            {
+                return Err(format!("invalid field name {field}"));
+            }
+            if !fields.iter().any(|selected| selected == field) {
+                fields.push(field.to_string());
+            }
+        }
+        Ok(Self { fields })
+    }
+}
+
+impl TryFrom<String> for FieldMask {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::{FieldMask, FieldMaskError};
+
+    #[test]
+    fn keeps_selected_fields() {
+        let users = json!([
+            {"id": 1, "email": "a@example.com", "details": {"bio": "long"}},
+            {"id": 2, "email": "b@example.com", "details": null},
+        ]);
+        let mask: FieldMask = "id, email,id".parse().unwrap();
+        assert_eq!(
+            mask.apply(&users).unwrap(),
+            json!([
+                {"id": 1, "email": "a@example.com"},
+                {"id": 2, "email": "b@example.com"},
+            ])
+        );
+        assert_eq!(FieldMask::all().apply(&users).unwrap(), users);
+        assert!(matches!(
+            "id,name".parse::<FieldMask>().unwrap().apply(&users),
+            Err(FieldMaskError::UnknownField(field)) if field == "name"
+        ));
+        assert!("id;email".parse::<FieldMask>().is_err());
+    }
+}
@@ -0,0 +1,321 @@
+//! async-graphql integration: the authenticated user in the resolver context, role guards for
+//! fields, and `AuthQuery`, a query fragment for the user's own account and groups.
+//!
+//! ```ignore
+//! #[derive(MergedObject, Default)]
+//! struct Query(AuthQuery, ProjectQuery);
+//!
+//! #[Object]
+//! impl ProjectQuery {
+//!     #[graphql(guard = RoleGuard::new("project", "editor").scope_id(&id))]
+//!     async fn draft(&self, id: String) -> Draft { ... }
+//! }
+//!
+//! async fn graphql(
+//!     auth: GraphQLAuth,
+//!     Json(request): Json<async_graphql::Request>,
+//! ) -> Json<Response> {
+//!     Json(SCHEMA.execute(auth.request(request)).await)
+//! }
+//! ```
+//!
+//! Resolvers get the data with `ctx.auth_user()` and `ctx.authz_loader()` from `GraphQLAuthExt`.
+//! Role checks go through the request's `AuthzLoader`, so guards on the fields of a list are
+//! answered by one query.
+
+use async_graphql::{Context, Enum, ErrorExtensions, Guard, Object, Result, SimpleObject};
+use axum::extract::FromRequestParts;
+use axum::http::request::Parts;
+use uuid::Uuid;
+
+use crate::api::HasPool;
+use crate::auth_context::AuthContext;
+use crate::authz_loader::{AuthzLoader, LoadError};
+use crate::db::{
+    GLOBAL_SCOPE, GLOBAL_SCOPE_ID, GroupMembershipRow, GroupRow, GroupVisibility, SUPER_ADMIN_ROLE,
+    UserRow,
+};
+use crate::group_id::GroupId;
+use crate::prelude::AuthenticatedUser;
+use crate::user_id::UserId;
+
+/// The caller and the request's `AuthzLoader`, as context data for one execution.
+///
+/// As an extractor it takes the `AuthenticatedUser` left by `AuthLayer`, if any, so a schema can
+/// serve anonymous queries next to guarded ones.
+#[derive(Clone)]
+pub struct GraphQLAuth {
+    user: Option<AuthenticatedUser>,
+    loader: AuthzLoader,
+}
+
+impl GraphQLAuth {
+    pub fn new(user: Option<AuthenticatedUser>, loader: AuthzLoader) -> Self {
+        Self { user, loader }
+    }
+
+    /// `request` with this as its context data.
+    pub fn request(self, request: async_graphql::Request) -> async_graphql::Request {
+        request.data(self)
+    }
+}
+
+impl<S> FromRequestParts<S> for GraphQLAuth
+where
+    S: HasPool + Send + Sync,
+{
+    type Rejection = std::convert::Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let user = parts.extensions.get::<AuthenticatedUser>().cloned();
+        let loader = AuthzLoader::from_request_parts(parts, state).await?;
+        Ok(Self::new(user, loader))
+    }
+}
+
+/// Access to `GraphQLAuth` from a resolver or guard.
+pub trait GraphQLAuthExt {
+    /// The caller, or an `UNAUTHENTICATED` error.
+    fn auth_user(&self) -> Result<&AuthenticatedUser>;
+    fn authz_loader(&self) -> Result<&AuthzLoader>;
+}
+
+impl GraphQLAuthExt for Context<'_> {
+    fn auth_user(&self) -> Result<&AuthenticatedUser> {
+        self.data::<GraphQLAuth>()?
+            .user
+            .as_ref()
+            .ok_or_else(|| error("UNAUTHENTICATED", "Authentication required"))
+    }
+
+    fn authz_loader(&self) -> Result<&AuthzLoader> {
+        Ok(&self.data::<GraphQLAuth>()?.loader)
+    }
+}
+
+fn error(code: &'static str, message: &str) -> async_graphql::Error {
+    async_graphql::Error::new(message).extend_with(|_, extensions| extensions.set("code", code))
+}
+
+fn database_error(_: LoadError) -> async_graphql::Error {
+    error("INTERNAL", "Failed to reach database")
+}
+
+/// Allows the field to callers with the role, as `db::user_has_effective_access`.
+///
+/// Checks the global id of the scope unless `scope_id` names one, e.g. from a field argument.
+#[derive(Debug, Clone)]
+pub struct RoleGuard {
+    scope: String,
+    scope_id: String,
+    role_name: String,
+}
+
+impl RoleGuard {
+    pub fn new(scope: impl Into<String>, role_name: impl Into<String>) -> Self {
+        Self {
+            scope: scope.into(),
+            scope_id: GLOBAL_SCOPE_ID.to_string(),
+            role_name: role_name.into(),
+        }
+    }
+
+    pub fn scope_id(mut self, scope_id: impl ToString) -> Self {
+        self.scope_id = scope_id.to_string();
+        self
+    }
+}
+
+impl Guard for RoleGuard {
+    async fn check(&self, ctx: &Context<'_>) -> Result<()> {
+        let user = ctx.auth_user()?;
+        let allowed = ctx
+            .authz_loader()?
+            .has_role(user.id(), &self.scope, &self.scope_id, &self.role_name)
+            .await
+            .map_err(database_error)?;
+        if allowed {
+            Ok(())
+        } else {
+            Err(error("FORBIDDEN", "Missing required role"))
+        }
+    }
+}
+
+#[derive(Debug, Clone, SimpleObject)]
+#[graphql(name = "AuthUser")]
+pub struct User {
+    pub id: Uuid,
+    pub email: String,
+    pub username: Option<String>,
+    pub display_name: Option<String>,
+    pub locale: Option<String>,
+    pub timezone: Option<String>,
+}
+
+impl From<UserRow> for User {
+    fn from(row: UserRow) -> Self {
+        Self {
+            id: row.id.0,
+            email: row.email,
+            username: row.username,
+            display_name: row.display_name,
+            locale: row.locale,
+            timezone: row.timezone,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Enum)]
+#[graphql(name = "AuthGroupVisibility")]
+pub enum Visibility {
+    Private,
+    Discoverable,
+    Open,
+}
+
+impl From<GroupVisibility> for Visibility {
+    fn from(visibility: GroupVisibility) -> Self {
+        match visibility {
+            GroupVisibility::Private => Self::Private,
+            GroupVisibility::Discoverable => Self::Discoverable,
+            GroupVisibility::Open => Self::Open,
+        }
+    }
+}
+
+#[derive(Debug, Clone, SimpleObject)]
+#[graphql(name = "AuthGroup")]
+pub struct Group {
+    pub id: Uuid,
+    pub display_name: String,
+    pub visibility: Visibility,
+}
+
+impl From<GroupRow> for Group {
+    fn from(row: GroupRow) -> Self {
+        Self {
+            id: row.id.0,
+            display_name: row.display_name,
+            visibility: row.visibility.into(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, SimpleObject)]
+#[graphql(name = "AuthGroupMembership")]
+pub struct GroupMembership {
+    pub group: Group,
+    /// The caller's role in the group, e.g. `member`.
+    pub role_name: String,
+}
+
+/// Queries about the caller's account and groups, to merge into an application's query root.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct AuthQuery;
+
+#[Object]
+impl AuthQuery {
+    /// The caller, or null until their first login provisions them.
+    async fn me(&self, ctx: &Context<'_>) -> Result<Option<User>> {
+        let user = ctx.auth_user()?;
+        let row = ctx
+            .authz_loader()?
+            .user(user.id())
+            .await
+            .map_err(database_error)?;
+        Ok(row.map(User::from))
+    }
+
+    /// Active groups the caller belongs to.
+    async fn my_groups(&self, ctx: &Context<'_>) -> Result<Vec<GroupMembership>> {
+        let user = ctx.auth_user()?;
+        let context = AuthContext::load(ctx.authz_loader()?.pool(), user.id())
+            .await
+            .map_err(|err| database_error(err.into()))?;
+        Ok(context
+            .groups
+            .into_iter()
+            .map(|membership| GroupMembership {
+                group: membership.group.into(),
+                role_name: membership.role_name,
+            })
+            .collect())
+    }
+
+    /// A user by id. Callers can look up themselves; anyone else needs `super_admin`.
+    async fn user(&self, ctx: &Context<'_>, id: Uuid) -> Result<Option<User>> {
+        let user = ctx.auth_user()?;
+        let user_id = UserId(id);
+        if user_id != user.id() {
+            RoleGuard::new(GLOBAL_SCOPE, SUPER_ADMIN_ROLE)
+                .check(ctx)
+                .await?;
+        }
+        let row = ctx
+            .authz_loader()?
+            .user(user_id)
+            .await
+            .map_err(database_error)?;
+        Ok(row.map(User::from))
+    }
+
+    /// A group by id, for its members and `super_admin`s.
+    async fn group(&self, ctx: &Context<'_>, id: Uuid) -> Result<Option<Group>> {
+        let user = ctx.auth_user()?;
+        let group_id = GroupId(id);
+        let pool = ctx.authz_loader()?.pool();
+        let is_member = GroupMembershipRow::is_member(pool, group_id, user.id())
+            .await
+            .map_err(|err| database_error(err.into()))?;
+        if !is_member {
+            RoleGuard::new(GLOBAL_SCOPE, SUPER_ADMIN_ROLE)
+                .check(ctx)
+                .await?;
+        }
+        let row = GroupRow::get(pool, group_id)
+            .await
+            .map_err(|err| database_error(err.into()))?;
+        Ok(row.map(Group::from))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use async_graphql::{EmptyMutation, EmptySubscription, MergedObject, Object, Schema};
+    use sqlx::PgPool;
+
+    use super::{AuthQuery, GraphQLAuth, RoleGuard};
+    use crate::authz_loader::AuthzLoader;
+
+    #[derive(Default)]
+    struct ProjectQuery;
+
+    #[Object]
+    impl ProjectQuery {
+        #[graphql(guard = RoleGuard::new("project", "editor").scope_id(&id))]
+        async fn draft(&self, id: String) -> String {
+            id
+        }
+    }
+
+    #[derive(MergedObject, Default)]
+    struct Query(AuthQuery, ProjectQuery);
+
+    #[tokio::test]
+    async fn anonymous_callers_are_unauthenticated() {
+        let schema = Schema::new(Query::default(), EmptyMutation, EmptySubscription);
+        let pool = Arc::new(PgPool::connect_lazy("postgres://localhost/unused").unwrap());
+        let auth = GraphQLAuth::new(None, AuthzLoader::new(pool));
+        let response = schema
+            .execute(auth.request(r#"{ me { id } draft(id: "p1") }"#.into()))
+            .await;
+        assert_eq!(response.errors.len(), 2);
+        for error in response.errors {
+            let code = error.extensions.as_ref().and_then(|ext| ext.get("code"));
+            assert_eq!(code, Some(&async_graphql::Value::from("UNAUTHENTICATED")));
+        }
+    }
+}
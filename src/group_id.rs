@@ -1,12 +1,31 @@
 use std::fmt;
 use std::str;
 
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize, Default)]
+use crate::ids::{new_uuid, uuid_timestamp};
+
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize, Default,
+)]
+#[cfg_attr(feature = "sqlx", derive(sqlx::Type), sqlx(transparent))]
 pub struct GroupId(pub Uuid);
 
+impl GroupId {
+    /// A new id of the process-wide `ids::uuid_version`.
+    pub fn new() -> Self {
+        GroupId(new_uuid())
+    }
+
+    /// When the id was generated, if it is time-based (UUIDv7). Meant for debugging, e.g. dating a
+    /// group in logs.
+    pub fn created_at(&self) -> Option<DateTime<Utc>> {
+        uuid_timestamp(self.0)
+    }
+}
+
 impl fmt::Display for GroupId {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "{}", self.0)
@@ -0,0 +1,643 @@
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use axum::extract::Request;
+use axum::response::{IntoResponse, Response};
+use chrono::{DateTime, Utc};
+use futures_util::future::BoxFuture;
+use hyper::StatusCode;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::task::JoinHandle;
+use tower::{Layer, Service};
+use tower_sessions::Session;
+
+use crate::api::HasPool;
+use crate::auth_context::AuthContext;
+use crate::db::{
+    EffectiveRole, GLOBAL_SCOPE, GLOBAL_SCOPE_ID, SUPER_ADMIN_ROLE, roles_allow,
+    user_has_effective_access_in_context,
+};
+use crate::policy::RequestContext;
+use crate::prelude::{AuthenticatedUser, RejectReason, UserId};
+use crate::updates::{UpdateKind, UserUpdate, UserUpdates};
+
+/// Require a role in a scope before a host-app route runs.
+///
+/// The request must already carry an `AuthenticatedUser` extension, i.e. the router is served
+/// behind `AuthService`. Unauthenticated requests get `401`, users without the role get the
+/// `missing_scope_check` error body. Roles are checked against `HasPool::pool`, so a revoked role
+/// stops working before replicas catch up, with conditional grants evaluated against the request's
+/// `policy::RequestContext`.
+///
+/// ```ignore
+/// let reports = Router::new()
+///     .route("/reports", get(list_reports))
+///     .layer(require_role_layer(app.clone(), "reports", "viewer"));
+/// ```
+pub fn require_role_layer<S>(app: S, scope: &str, role: &str) -> RequireRoleLayer<S>
+where
+    S: HasPool,
+{
+    RequireRoleLayer::new(app, scope, [role])
+}
+
+/// Like `require_role_layer`, but any one of `roles` is enough.
+pub fn require_any_role_layer<S, I, R>(app: S, scope: &str, roles: I) -> RequireRoleLayer<S>
+where
+    S: HasPool,
+    I: IntoIterator<Item = R>,
+    R: AsRef<str>,
+{
+    RequireRoleLayer::new(app, scope, roles)
+}
+
+#[derive(Clone)]
+pub struct RequireRoleLayer<S> {
+    app: S,
+    requirement: Arc<RoleRequirement>,
+}
+
+#[derive(Debug, Clone)]
+struct RoleRequirement {
+    scope: String,
+    scope_id: String,
+    roles: Vec<String>,
+}
+
+impl<S> RequireRoleLayer<S>
+where
+    S: HasPool,
+{
+    pub fn new<I, R>(app: S, scope: &str, roles: I) -> Self
+    where
+        I: IntoIterator<Item = R>,
+        R: AsRef<str>,
+    {
+        Self {
+            app,
+            requirement: Arc::new(RoleRequirement {
+                scope: scope.to_string(),
+                scope_id: GLOBAL_SCOPE_ID.to_string(),
+                roles: roles
+                    .into_iter()
+                    .map(|role| role.as_ref().to_string())
+                    .collect(),
+            }),
+        }
+    }
+
+    /// Check the roles against a specific scope id instead of the global one.
+    pub fn with_scope_id(mut self, scope_id: &str) -> Self {
+        let requirement = Arc::make_mut(&mut self.requirement);
+        requirement.scope_id = scope_id.to_string();
+        self
+    }
+}
+
+impl<S, Inner> Layer<Inner> for RequireRoleLayer<S>
+where
+    S: Clone,
+{
+    type Service = RequireRole<S, Inner>;
+
+    fn layer(&self, inner: Inner) -> Self::Service {
+        RequireRole {
+            app: self.app.clone(),
+            requirement: self.requirement.clone(),
+            inner,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct RequireRole<S, Inner> {
+    app: S,
+    requirement: Arc<RoleRequirement>,
+    inner: Inner,
+}
+
+impl<S, Inner, B> Service<Request<B>> for RequireRole<S, Inner>
+where
+    S: HasPool + Clone + Send + Sync + 'static,
+    Inner: Service<Request<B>, Response = Response> + Clone + Send + 'static,
+    Inner::Future: Send + 'static,
+    B: Send + 'static,
+{
+    type Response = Response;
+    type Error = Inner::Error;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<B>) -> Self::Future {
+        let pool = self.app.pool();
+        let requirement = self.requirement.clone();
+        let clone = self.inner.clone();
+        // https://docs.rs/tower/latest/tower/trait.Service.html#be-careful-when-cloning-inner-services
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        Box::pin(async move {
+            let Some(auth_user) = req.extensions().get::<AuthenticatedUser>() else {
+                return Ok(StatusCode::UNAUTHORIZED.into_response());
+            };
+            let user_id = auth_user.id();
+            let context = RequestContext::from_extensions(req.extensions());
+
+            for role in &requirement.roles {
+                match user_has_effective_access_in_context(
+                    &pool,
+                    user_id,
+                    &requirement.scope,
+                    &requirement.scope_id,
+                    role,
+                    &context,
+                )
+                .await
+                {
+                    Ok(true) => return inner.call(req).await,
+                    Ok(false) => {}
+                    Err(err) => {
+                        tracing::error!("Failed to check roles for {}: {}", user_id, err);
+                        return Ok(
+                            RejectReason::database("Failed to reach database").into_response()
+                        );
+                    }
+                }
+            }
+
+            tracing::info!(
+                "Rejecting {} without any of {:?} in {}/{}",
+                user_id,
+                requirement.roles,
+                requirement.scope,
+                requirement.scope_id
+            );
+            Ok(RejectReason::forbidden_missing_scope_check(
+                "Missing required role",
+                requirement.scope.clone(),
+                requirement.scope_id.clone(),
+                requirement.roles.clone(),
+            )
+            .into_response())
+        })
+    }
+}
+
+pub const ROLE_SNAPSHOT_SESSION_KEY: &str = "subseq_auth.role_snapshot";
+
+/// Roles of the authenticated user, inserted into request extensions by `ResolveRolesLayer`.
+///
+/// Read it with `Extension<ResolvedRoles>` or `req.extensions().get::<ResolvedRoles>()`.
+#[derive(Debug, Clone)]
+pub struct ResolvedRoles {
+    roles: Arc<Vec<EffectiveRole>>,
+    fetched_at: DateTime<Utc>,
+}
+
+impl ResolvedRoles {
+    pub fn new(roles: Vec<EffectiveRole>) -> Self {
+        Self {
+            roles: Arc::new(roles),
+            fetched_at: Utc::now(),
+        }
+    }
+
+    pub fn roles(&self) -> &[EffectiveRole] {
+        &self.roles
+    }
+
+    /// When the roles were read from the database. Older than the request when served from the
+    /// session snapshot.
+    pub fn fetched_at(&self) -> DateTime<Utc> {
+        self.fetched_at
+    }
+
+    /// Whether the role is held in `scope_id`, through a wildcard grant covering it, or in the
+    /// global id of the same scope, and no deny grant covers any of those.
+    ///
+    /// Conditional grants are not evaluated: conditional allows are ignored and conditional
+    /// denies apply. Use `has_role_in_context` to evaluate them.
+    pub fn has_role(&self, scope: &str, scope_id: &str, role_name: &str) -> bool {
+        roles_allow(&self.roles, scope, scope_id, role_name, None)
+    }
+
+    /// `has_role` with conditional grants evaluated against the request.
+    pub fn has_role_in_context(
+        &self,
+        scope: &str,
+        scope_id: &str,
+        role_name: &str,
+        context: &RequestContext,
+    ) -> bool {
+        roles_allow(&self.roles, scope, scope_id, role_name, Some(context))
+    }
+
+    pub fn is_super_admin(&self) -> bool {
+        self.has_role(GLOBAL_SCOPE, GLOBAL_SCOPE_ID, SUPER_ADMIN_ROLE)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct RoleSnapshot {
+    user_id: UserId,
+    fetched_at: DateTime<Utc>,
+    roles: Vec<EffectiveRole>,
+}
+
+#[derive(Default)]
+struct RoleInvalidations {
+    all: Option<DateTime<Utc>>,
+    users: HashMap<UserId, DateTime<Utc>>,
+    /// Longest snapshot TTL configured in this process. Snapshots older than that are expired
+    /// anyway, so invalidations older than that no longer matter.
+    max_ttl: Duration,
+}
+
+static ROLE_INVALIDATIONS: Lazy<RwLock<RoleInvalidations>> = Lazy::new(Default::default);
+
+fn record_snapshot_ttl(ttl: Duration) {
+    if let Ok(mut invalidations) = ROLE_INVALIDATIONS.write() {
+        invalidations.max_ttl = invalidations.max_ttl.max(ttl);
+    }
+}
+
+/// Discard session role snapshots for a user taken before now.
+///
+/// Invalidation is tracked in process. The crate's own role and membership writes call this, and
+/// `invalidate_role_snapshots_on` applies changes made by other instances; without it, those
+/// take effect here only once the snapshot TTL expires.
+pub fn invalidate_role_snapshots(user_id: UserId) {
+    if let Ok(mut invalidations) = ROLE_INVALIDATIONS.write() {
+        let now = Utc::now();
+        let max_ttl =
+            chrono::Duration::from_std(invalidations.max_ttl).unwrap_or(chrono::Duration::MAX);
+        invalidations
+            .users
+            .retain(|_, invalidated_at| now.signed_duration_since(*invalidated_at) <= max_ttl);
+        invalidations.users.insert(user_id, now);
+    }
+}
+
+/// Discard every session role snapshot taken before now, e.g. after a group role is revoked.
+pub fn invalidate_all_role_snapshots() {
+    if let Ok(mut invalidations) = ROLE_INVALIDATIONS.write() {
+        invalidations.users.clear();
+        invalidations.all = Some(Utc::now());
+    }
+}
+
+/// Discard role snapshots as `updates` reports role and membership changes, including those
+/// written by other instances, so a revocation anywhere applies here without waiting for the
+/// snapshot TTL. Run one per process next to `UserUpdates::spawn`.
+///
+/// ```ignore
+/// let (updates, _) = UserUpdates::spawn(pool.clone());
+/// invalidate_role_snapshots_on(&updates);
+/// ```
+pub fn invalidate_role_snapshots_on(updates: &UserUpdates) -> JoinHandle<()> {
+    let mut received = updates.subscribe();
+    tokio::spawn(async move {
+        loop {
+            match received.recv().await {
+                Ok(update) => invalidate_for_update(&update),
+                Err(RecvError::Lagged(_)) => invalidate_all_role_snapshots(),
+                Err(RecvError::Closed) => return,
+            }
+        }
+    })
+}
+
+fn invalidate_for_update(update: &UserUpdate) {
+    match (update.kind, update.user_id) {
+        (UpdateKind::Profile, _) => {}
+        (UpdateKind::Roles | UpdateKind::Groups, Some(user_id)) => {
+            invalidate_role_snapshots(user_id)
+        }
+        // Changes to a group's grants or status, and resyncs, may concern anyone.
+        _ => invalidate_all_role_snapshots(),
+    }
+}
+
+fn snapshot_invalidated(snapshot: &RoleSnapshot) -> bool {
+    let Ok(invalidations) = ROLE_INVALIDATIONS.read() else {
+        return true;
+    };
+    invalidations
+        .all
+        .iter()
+        .chain(invalidations.users.get(&snapshot.user_id))
+        .any(|invalidated_at| *invalidated_at >= snapshot.fetched_at)
+}
+
+/// Load the authenticated user's roles once per request and insert them as `ResolvedRoles`.
+///
+/// Must run inside `AuthLayer`/`AuthService`. Anonymous requests pass through untouched.
+pub fn resolve_roles_layer<S>(app: S) -> ResolveRolesLayer<S>
+where
+    S: HasPool,
+{
+    ResolveRolesLayer {
+        app,
+        snapshot_ttl: None,
+        strict: false,
+    }
+}
+
+#[derive(Clone)]
+pub struct ResolveRolesLayer<S> {
+    app: S,
+    snapshot_ttl: Option<Duration>,
+    strict: bool,
+}
+
+impl<S> ResolveRolesLayer<S> {
+    /// Cache the role set in the tower session and reuse it until it is older than `ttl` or
+    /// invalidated by a revoke. Requires `SessionManagerLayer` outside this layer; without a
+    /// session the roles are read from the database as usual.
+    ///
+    /// Revocations on other instances are only seen here within `ttl` unless
+    /// `invalidate_role_snapshots_on` runs.
+    pub fn with_session_snapshot(mut self, ttl: Duration) -> Self {
+        record_snapshot_ttl(ttl);
+        self.snapshot_ttl = Some(ttl);
+        self
+    }
+
+    /// Always read roles from the primary database, ignoring any session snapshot and read
+    /// replica. Use on endpoints where a revoked role must never be honoured.
+    pub fn strict(mut self) -> Self {
+        self.strict = true;
+        self
+    }
+}
+
+impl<S, Inner> Layer<Inner> for ResolveRolesLayer<S>
+where
+    S: Clone,
+{
+    type Service = ResolveRoles<S, Inner>;
+
+    fn layer(&self, inner: Inner) -> Self::Service {
+        ResolveRoles {
+            app: self.app.clone(),
+            snapshot_ttl: if self.strict { None } else { self.snapshot_ttl },
+            strict: self.strict,
+            inner,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct ResolveRoles<S, Inner> {
+    app: S,
+    snapshot_ttl: Option<Duration>,
+    strict: bool,
+    inner: Inner,
+}
+
+async fn cached_roles(session: &Session, user_id: UserId, ttl: Duration) -> Option<ResolvedRoles> {
+    let snapshot = match session.get::<RoleSnapshot>(ROLE_SNAPSHOT_SESSION_KEY).await {
+        Ok(snapshot) => snapshot?,
+        Err(err) => {
+            tracing::warn!("Failed to read role snapshot from session: {}", err);
+            return None;
+        }
+    };
+    let ttl = chrono::Duration::from_std(ttl).unwrap_or(chrono::Duration::MAX);
+    if snapshot.user_id != user_id
+        || snapshot
+            .fetched_at
+            .checked_add_signed(ttl)
+            .is_some_and(|expires_at| expires_at <= Utc::now())
+        || snapshot_invalidated(&snapshot)
+    {
+        return None;
+    }
+    Some(ResolvedRoles {
+        roles: Arc::new(snapshot.roles),
+        fetched_at: snapshot.fetched_at,
+    })
+}
+
+/// Roles from a fresh `AuthContext`, which is returned too so handlers can reuse it.
+async fn load_roles(
+    pool: &PgPool,
+    session: Option<&Session>,
+    user_id: UserId,
+) -> Result<(ResolvedRoles, AuthContext), sqlx::Error> {
+    let fetched_at = Utc::now();
+    let context = AuthContext::load(pool, user_id).await?;
+    let roles = context.roles.clone();
+    if let Some(session) = session {
+        let snapshot = RoleSnapshot {
+            user_id,
+            fetched_at,
+            roles: roles.clone(),
+        };
+        if let Err(err) = session.insert(ROLE_SNAPSHOT_SESSION_KEY, snapshot).await {
+            tracing::warn!("Failed to store role snapshot in session: {}", err);
+        }
+    }
+    let resolved = ResolvedRoles {
+        roles: Arc::new(roles),
+        fetched_at,
+    };
+    Ok((resolved, context))
+}
+
+impl<S, Inner, B> Service<Request<B>> for ResolveRoles<S, Inner>
+where
+    S: HasPool + Clone + Send + Sync + 'static,
+    Inner: Service<Request<B>, Response = Response> + Clone + Send + 'static,
+    Inner::Future: Send + 'static,
+    B: Send + 'static,
+{
+    type Response = Response;
+    type Error = Inner::Error;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: Request<B>) -> Self::Future {
+        let pool = if self.strict {
+            self.app.pool()
+        } else {
+            self.app.reader_pool()
+        };
+        let snapshot_ttl = self.snapshot_ttl;
+        let clone = self.inner.clone();
+        // https://docs.rs/tower/latest/tower/trait.Service.html#be-careful-when-cloning-inner-services
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        Box::pin(async move {
+            let user_id = req
+                .extensions()
+                .get::<AuthenticatedUser>()
+                .map(AuthenticatedUser::id);
+            let Some(user_id) = user_id else {
+                return inner.call(req).await;
+            };
+
+            let session = snapshot_ttl.and_then(|_| req.extensions().get::<Session>().cloned());
+            let cached = match (&session, snapshot_ttl) {
+                (Some(session), Some(ttl)) => cached_roles(session, user_id, ttl).await,
+                _ => None,
+            };
+            let roles = match cached {
+                Some(roles) => roles,
+                None => match load_roles(&pool, session.as_ref(), user_id).await {
+                    Ok((roles, context)) => {
+                        req.extensions_mut().insert(context);
+                        roles
+                    }
+                    Err(err) => {
+                        tracing::error!("Failed to resolve roles for {}: {}", user_id, err);
+                        return Ok(
+                            RejectReason::database("Failed to reach database").into_response()
+                        );
+                    }
+                },
+            };
+            req.extensions_mut().insert(roles);
+            inner.call(req).await
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tower_sessions::MemoryStore;
+
+    use super::*;
+
+    #[derive(Clone)]
+    struct App {
+        pool: Arc<PgPool>,
+    }
+
+    impl HasPool for App {
+        fn pool(&self) -> Arc<PgPool> {
+            self.pool.clone()
+        }
+    }
+
+    async fn session_with_snapshot(user_id: UserId, age: chrono::Duration) -> Session {
+        let session = Session::new(None, Arc::new(MemoryStore::default()), None);
+        let snapshot = RoleSnapshot {
+            user_id,
+            fetched_at: Utc::now() - age,
+            roles: Vec::new(),
+        };
+        session
+            .insert(ROLE_SNAPSHOT_SESSION_KEY, snapshot)
+            .await
+            .unwrap();
+        session
+    }
+
+    fn new_user_id() -> UserId {
+        UserId(uuid::Uuid::new_v4())
+    }
+
+    #[tokio::test]
+    async fn snapshots_expire_after_their_ttl() {
+        let user_id = new_user_id();
+        let session = session_with_snapshot(user_id, chrono::Duration::minutes(2)).await;
+        let hour = Duration::from_secs(60 * 60);
+        assert!(cached_roles(&session, user_id, hour).await.is_some());
+        assert!(
+            cached_roles(&session, user_id, Duration::from_secs(60))
+                .await
+                .is_none()
+        );
+        assert!(cached_roles(&session, new_user_id(), hour).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn invalidation_discards_older_snapshots_only() {
+        let hour = Duration::from_secs(60 * 60);
+        let user_id = new_user_id();
+        let other_user_id = new_user_id();
+        let session = session_with_snapshot(user_id, chrono::Duration::seconds(1)).await;
+        let other_session =
+            session_with_snapshot(other_user_id, chrono::Duration::seconds(1)).await;
+
+        invalidate_role_snapshots(user_id);
+        assert!(cached_roles(&session, user_id, hour).await.is_none());
+        assert!(
+            cached_roles(&other_session, other_user_id, hour)
+                .await
+                .is_some()
+        );
+
+        let fresh = session_with_snapshot(user_id, -chrono::Duration::seconds(1)).await;
+        assert!(cached_roles(&fresh, user_id, hour).await.is_some());
+    }
+
+    #[tokio::test]
+    async fn role_and_membership_updates_invalidate_their_user() {
+        let hour = Duration::from_secs(60 * 60);
+        let update = |kind, user_id| UserUpdate {
+            kind,
+            user_id: Some(user_id),
+            group_id: None,
+        };
+
+        let user_id = new_user_id();
+        let session = session_with_snapshot(user_id, chrono::Duration::seconds(1)).await;
+        invalidate_for_update(&update(UpdateKind::Profile, user_id));
+        assert!(cached_roles(&session, user_id, hour).await.is_some());
+        invalidate_for_update(&update(UpdateKind::Roles, user_id));
+        assert!(cached_roles(&session, user_id, hour).await.is_none());
+
+        let user_id = new_user_id();
+        let session = session_with_snapshot(user_id, chrono::Duration::seconds(1)).await;
+        invalidate_for_update(&update(UpdateKind::Groups, user_id));
+        assert!(cached_roles(&session, user_id, hour).await.is_none());
+    }
+
+    #[test]
+    fn invalidations_older_than_the_longest_ttl_are_pruned() {
+        record_snapshot_ttl(Duration::from_secs(60 * 60));
+        let stale = new_user_id();
+        ROLE_INVALIDATIONS
+            .write()
+            .unwrap()
+            .users
+            .insert(stale, Utc::now() - chrono::Duration::days(2));
+
+        let user_id = new_user_id();
+        invalidate_role_snapshots(user_id);
+        let invalidations = ROLE_INVALIDATIONS.read().unwrap();
+        assert!(!invalidations.users.contains_key(&stale));
+        assert!(invalidations.users.contains_key(&user_id));
+    }
+
+    #[tokio::test]
+    async fn strict_skips_session_snapshots() {
+        let app = App {
+            pool: Arc::new(PgPool::connect_lazy("postgres://auth@127.0.0.1:1/auth").unwrap()),
+        };
+        let ttl = Duration::from_secs(60);
+
+        let cached = resolve_roles_layer(app.clone())
+            .with_session_snapshot(ttl)
+            .layer(());
+        assert_eq!(cached.snapshot_ttl, Some(ttl));
+        assert!(!cached.strict);
+
+        let strict = resolve_roles_layer(app)
+            .with_session_snapshot(ttl)
+            .strict()
+            .layer(());
+        assert_eq!(strict.snapshot_ttl, None);
+        assert!(strict.strict);
+    }
+}
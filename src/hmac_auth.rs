@@ -0,0 +1,828 @@
+//! HMAC request signing for internal services that cannot do OAuth, and for outgoing webhooks.
+//!
+//! A request is signed over its canonical form, see `canonical_request`, with a secret shared
+//! between the caller and this service, and carries the signature in
+//! `Authorization: HMAC-SHA256 key_id="..", timestamp="..", nonce="..", signature=".."`.
+//! The timestamp must be within the verifier's clock skew and each nonce is accepted once, so a
+//! captured request cannot be replayed.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use axum::body::Body;
+use axum::extract::{FromRequestParts, Request};
+use axum::http::request::Parts;
+use axum::response::{IntoResponse, Response};
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use chrono::{DateTime, Utc};
+use futures_util::future::{self, BoxFuture};
+use hyper::header::{AUTHORIZATION, HeaderValue, WWW_AUTHENTICATE};
+use openidconnect::CsrfToken;
+use ring::hmac;
+use sha2::{Digest, Sha256};
+use tower::{Layer, Service};
+
+use crate::prelude::{AuthRejectReason, RejectReason};
+
+/// Authorization scheme, and first line of every canonical request.
+pub const HMAC_SCHEME: &str = "HMAC-SHA256";
+
+/// Header carrying the signature of outgoing webhooks, in the `Authorization` format. A separate
+/// header leaves `Authorization` to whatever the receiver already uses.
+pub const WEBHOOK_SIGNATURE_HEADER: &str = "webhook-signature";
+
+/// How far a request timestamp may be from the verifier's clock.
+const DEFAULT_MAX_SKEW: Duration = Duration::from_secs(300);
+
+/// Largest body `RequireHmac` buffers to check the signature.
+const DEFAULT_BODY_LIMIT: usize = 1024 * 1024;
+
+/// Random bytes in generated nonces, before base64 encoding.
+const NONCE_BYTES: u32 = 18;
+
+const MIN_NONCE_LEN: usize = 16;
+const MAX_NONCE_LEN: usize = 128;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HmacError {
+    /// The request carries no credentials.
+    Missing,
+    /// The credentials are not in the `HMAC-SHA256` format; the message says why.
+    Malformed(String),
+    /// No active key has this id.
+    UnknownKey(String),
+    /// The timestamp is outside the allowed clock skew.
+    Stale,
+    /// The nonce was already used with this key.
+    Replayed,
+    BadSignature,
+    /// The body is larger than the verifier buffers.
+    BodyTooLarge,
+    /// The key store failed, e.g. the database is unreachable.
+    Store(String),
+}
+
+impl fmt::Display for HmacError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HmacError::Missing => write!(f, "Missing HMAC signature"),
+            HmacError::Malformed(reason) => write!(f, "Malformed HMAC signature: {}", reason),
+            HmacError::UnknownKey(key_id) => write!(f, "Unknown HMAC key: {}", key_id),
+            HmacError::Stale => write!(f, "Request timestamp outside the allowed clock skew"),
+            HmacError::Replayed => write!(f, "Request nonce was already used"),
+            HmacError::BadSignature => write!(f, "HMAC signature does not match"),
+            HmacError::BodyTooLarge => write!(f, "Request body too large to verify"),
+            HmacError::Store(err) => write!(f, "HMAC key store error: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for HmacError {}
+
+/// The string that gets signed: the scheme, upper-case method, path with query string as sent,
+/// unix timestamp, nonce and the base64url SHA-256 of the body, one per line. The host is not
+/// included, so a signature is good for every host that shares the key.
+pub fn canonical_request(
+    method: &str,
+    path_and_query: &str,
+    timestamp: i64,
+    nonce: &str,
+    body: &[u8],
+) -> String {
+    format!(
+        "{}\n{}\n{}\n{}\n{}\n{}",
+        HMAC_SCHEME,
+        method.to_ascii_uppercase(),
+        path_and_query,
+        timestamp,
+        nonce,
+        URL_SAFE_NO_PAD.encode(Sha256::digest(body)),
+    )
+}
+
+/// A fresh random secret for a new key.
+pub fn generate_secret() -> String {
+    CsrfToken::new_random_len(32).secret().clone()
+}
+
+/// A shared secret and the id callers name it by.
+#[derive(Clone)]
+pub struct HmacKey {
+    key_id: String,
+    key: hmac::Key,
+}
+
+impl fmt::Debug for HmacKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("HmacKey")
+            .field("key_id", &self.key_id)
+            .field("key", &"[redacted]")
+            .finish()
+    }
+}
+
+impl HmacKey {
+    pub fn new<S: Into<String>>(key_id: S, secret: &[u8]) -> Self {
+        Self {
+            key_id: key_id.into(),
+            key: hmac::Key::new(hmac::HMAC_SHA256, secret),
+        }
+    }
+
+    pub fn key_id(&self) -> &str {
+        &self.key_id
+    }
+
+    /// Credentials for a request sent now with a random nonce.
+    pub fn sign(&self, method: &str, path_and_query: &str, body: &[u8]) -> HmacCredentials {
+        let nonce = CsrfToken::new_random_len(NONCE_BYTES).secret().clone();
+        self.sign_at(method, path_and_query, Utc::now().timestamp(), nonce, body)
+    }
+
+    pub fn sign_at<S: Into<String>>(
+        &self,
+        method: &str,
+        path_and_query: &str,
+        timestamp: i64,
+        nonce: S,
+        body: &[u8],
+    ) -> HmacCredentials {
+        let nonce = nonce.into();
+        let canonical = canonical_request(method, path_and_query, timestamp, &nonce, body);
+        let tag = hmac::sign(&self.key, canonical.as_bytes());
+        HmacCredentials {
+            key_id: self.key_id.clone(),
+            timestamp,
+            nonce,
+            signature: URL_SAFE_NO_PAD.encode(tag.as_ref()),
+        }
+    }
+
+    /// Sign an outgoing webhook, adding `WEBHOOK_SIGNATURE_HEADER`. The body has to be in memory;
+    /// streaming bodies cannot be signed.
+    pub fn sign_webhook(&self, request: &mut reqwest::Request) -> Result<(), HmacError> {
+        let body = match request.body() {
+            Some(body) => body.as_bytes().ok_or_else(|| {
+                HmacError::Malformed("streaming bodies cannot be signed".to_string())
+            })?,
+            None => &[],
+        };
+        let url = request.url();
+        let path_and_query = match url.query() {
+            Some(query) => format!("{}?{}", url.path(), query),
+            None => url.path().to_string(),
+        };
+        let credentials = self.sign(request.method().as_str(), &path_and_query, body);
+        let value = reqwest::header::HeaderValue::from_str(&credentials.to_string())
+            .map_err(|err| HmacError::Malformed(err.to_string()))?;
+        request
+            .headers_mut()
+            .insert(WEBHOOK_SIGNATURE_HEADER, value);
+        Ok(())
+    }
+
+    /// Check the signature alone; timestamps and nonces are `HmacVerifier`'s job.
+    fn verify_signature(
+        &self,
+        credentials: &HmacCredentials,
+        method: &str,
+        path_and_query: &str,
+        body: &[u8],
+    ) -> Result<(), HmacError> {
+        let signature = URL_SAFE_NO_PAD
+            .decode(&credentials.signature)
+            .map_err(|_| HmacError::BadSignature)?;
+        let canonical = canonical_request(
+            method,
+            path_and_query,
+            credentials.timestamp,
+            &credentials.nonce,
+            body,
+        );
+        hmac::verify(&self.key, canonical.as_bytes(), &signature)
+            .map_err(|_| HmacError::BadSignature)
+    }
+}
+
+/// The parameters of an `HMAC-SHA256` authorization. `Display` writes the header value.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HmacCredentials {
+    pub key_id: String,
+    pub timestamp: i64,
+    pub nonce: String,
+    /// Base64url, without padding.
+    pub signature: String,
+}
+
+impl fmt::Display for HmacCredentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            r#"{} key_id="{}", timestamp="{}", nonce="{}", signature="{}""#,
+            HMAC_SCHEME, self.key_id, self.timestamp, self.nonce, self.signature
+        )
+    }
+}
+
+impl FromStr for HmacCredentials {
+    type Err = HmacError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let malformed = |reason: &str| HmacError::Malformed(reason.to_string());
+        let (scheme, params) = value
+            .trim()
+            .split_once(' ')
+            .ok_or_else(|| malformed("expected parameters"))?;
+        if !scheme.eq_ignore_ascii_case(HMAC_SCHEME) {
+            return Err(malformed("expected the HMAC-SHA256 scheme"));
+        }
+        let mut key_id = None;
+        let mut timestamp = None;
+        let mut nonce = None;
+        let mut signature = None;
+        for param in params.split(',') {
+            let (name, value) = param
+                .trim()
+                .split_once('=')
+                .ok_or_else(|| malformed("expected name=value parameters"))?;
+            let value = value.trim().trim_matches('"').to_string();
+            let slot = match name.trim() {
+                "key_id" => &mut key_id,
+                "timestamp" => &mut timestamp,
+                "nonce" => &mut nonce,
+                "signature" => &mut signature,
+                _ => continue,
+            };
+            if slot.replace(value).is_some() {
+                return Err(malformed("repeated parameter"));
+            }
+        }
+        let key_id = key_id
+            .filter(|key_id| !key_id.is_empty())
+            .ok_or_else(|| malformed("missing key_id"))?;
+        let timestamp = timestamp
+            .ok_or_else(|| malformed("missing timestamp"))?
+            .parse()
+            .map_err(|_| malformed("timestamp must be unix seconds"))?;
+        let nonce = nonce.ok_or_else(|| malformed("missing nonce"))?;
+        if !(MIN_NONCE_LEN..=MAX_NONCE_LEN).contains(&nonce.len())
+            || !nonce
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+        {
+            return Err(malformed(
+                "nonce must be 16 to 128 letters, digits, '-' or '_'",
+            ));
+        }
+        let signature = signature.ok_or_else(|| malformed("missing signature"))?;
+        Ok(Self {
+            key_id,
+            timestamp,
+            nonce,
+            signature,
+        })
+    }
+}
+
+/// A key and the client it belongs to.
+#[derive(Debug, Clone)]
+pub struct HmacClientKey {
+    pub client_id: String,
+    pub key: HmacKey,
+}
+
+/// Where `HmacVerifier` finds keys and remembers nonces: `PgHmacKeys`, `MemoryHmacKeys`, or an
+/// app's own impl.
+pub trait HmacKeyStore: Send + Sync {
+    /// The active key named `key_id`, if any.
+    fn key(&self, key_id: &str) -> BoxFuture<'_, Result<Option<HmacClientKey>, HmacError>>;
+
+    /// Record that `nonce` was used with `key_id`. Returns `false` if it already was. The nonce
+    /// may be forgotten after `expires_at`.
+    fn claim_nonce(
+        &self,
+        key_id: &str,
+        nonce: &str,
+        expires_at: DateTime<Utc>,
+    ) -> BoxFuture<'_, Result<bool, HmacError>>;
+}
+
+/// Keys held in memory, with nonces remembered per process. For tests, and for services that
+/// receive webhooks from one sender.
+#[derive(Debug, Default)]
+pub struct MemoryHmacKeys {
+    keys: HashMap<String, HmacClientKey>,
+    nonces: Mutex<HashMap<(String, String), DateTime<Utc>>>,
+}
+
+impl MemoryHmacKeys {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_key<S: Into<String>>(mut self, client_id: S, key: HmacKey) -> Self {
+        self.keys.insert(
+            key.key_id().to_string(),
+            HmacClientKey {
+                client_id: client_id.into(),
+                key,
+            },
+        );
+        self
+    }
+}
+
+impl HmacKeyStore for MemoryHmacKeys {
+    fn key(&self, key_id: &str) -> BoxFuture<'_, Result<Option<HmacClientKey>, HmacError>> {
+        Box::pin(future::ready(Ok(self.keys.get(key_id).cloned())))
+    }
+
+    fn claim_nonce(
+        &self,
+        key_id: &str,
+        nonce: &str,
+        expires_at: DateTime<Utc>,
+    ) -> BoxFuture<'_, Result<bool, HmacError>> {
+        let now = Utc::now();
+        let mut nonces = self.nonces.lock().unwrap_or_else(|err| err.into_inner());
+        nonces.retain(|_, expires_at| *expires_at > now);
+        let claimed = nonces
+            .insert((key_id.to_string(), nonce.to_string()), expires_at)
+            .is_none();
+        Box::pin(future::ready(Ok(claimed)))
+    }
+}
+
+/// Checks signed requests against a key store.
+#[derive(Clone)]
+pub struct HmacVerifier {
+    store: Arc<dyn HmacKeyStore>,
+    max_skew: Duration,
+    body_limit: usize,
+}
+
+impl HmacVerifier {
+    pub fn new(store: Arc<dyn HmacKeyStore>) -> Self {
+        Self {
+            store,
+            max_skew: DEFAULT_MAX_SKEW,
+            body_limit: DEFAULT_BODY_LIMIT,
+        }
+    }
+
+    /// Accept timestamps up to `max_skew` from this service's clock. Nonces are remembered for as
+    /// long. Defaults to five minutes.
+    pub fn with_max_skew(mut self, max_skew: Duration) -> Self {
+        self.max_skew = max_skew;
+        self
+    }
+
+    /// Refuse bodies larger than `body_limit` bytes in `RequireHmac`. Defaults to 1 MiB.
+    pub fn with_body_limit(mut self, body_limit: usize) -> Self {
+        self.body_limit = body_limit;
+        self
+    }
+
+    /// Check a request carrying `credentials`, the value of its `Authorization` header or, for a
+    /// received webhook, of `WEBHOOK_SIGNATURE_HEADER`. Uses up the nonce.
+    pub async fn verify(
+        &self,
+        credentials: &str,
+        method: &str,
+        path_and_query: &str,
+        body: &[u8],
+    ) -> Result<HmacAuth, HmacError> {
+        let credentials: HmacCredentials = credentials.parse()?;
+        let timestamp =
+            DateTime::from_timestamp(credentials.timestamp, 0).ok_or(HmacError::Stale)?;
+        let skew = chrono::Duration::from_std(self.max_skew).unwrap_or(chrono::Duration::MAX);
+        if (Utc::now() - timestamp).abs() > skew {
+            return Err(HmacError::Stale);
+        }
+        let client_key = self
+            .store
+            .key(&credentials.key_id)
+            .await?
+            .ok_or_else(|| HmacError::UnknownKey(credentials.key_id.clone()))?;
+        client_key
+            .key
+            .verify_signature(&credentials, method, path_and_query, body)?;
+        // Only signed nonces are recorded, so unauthenticated callers cannot fill the store.
+        if !self
+            .store
+            .claim_nonce(&credentials.key_id, &credentials.nonce, timestamp + skew)
+            .await?
+        {
+            return Err(HmacError::Replayed);
+        }
+        Ok(HmacAuth {
+            client_id: client_key.client_id,
+            key_id: credentials.key_id,
+        })
+    }
+}
+
+/// Require an HMAC-signed request before a route runs.
+///
+/// The body is buffered to check its digest and handed on unchanged. Requests without a valid
+/// signature get `401`; the verified caller is inserted into request extensions for the
+/// `HmacAuth` extractor.
+///
+/// ```ignore
+/// let internal = Router::new()
+///     .route("/internal/sync", post(sync))
+///     .layer(RequireHmacLayer::new(HmacVerifier::new(Arc::new(PgHmacKeys::new(pool)))));
+/// ```
+#[derive(Clone)]
+pub struct RequireHmacLayer {
+    verifier: HmacVerifier,
+}
+
+impl RequireHmacLayer {
+    pub fn new(verifier: HmacVerifier) -> Self {
+        Self { verifier }
+    }
+}
+
+impl<Inner> Layer<Inner> for RequireHmacLayer {
+    type Service = RequireHmac<Inner>;
+
+    fn layer(&self, inner: Inner) -> Self::Service {
+        RequireHmac {
+            verifier: self.verifier.clone(),
+            inner,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct RequireHmac<Inner> {
+    verifier: HmacVerifier,
+    inner: Inner,
+}
+
+impl<Inner> Service<Request> for RequireHmac<Inner>
+where
+    Inner: Service<Request, Response = Response> + Clone + Send + 'static,
+    Inner::Future: Send + 'static,
+{
+    type Response = Response;
+    type Error = Inner::Error;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request) -> Self::Future {
+        let verifier = self.verifier.clone();
+        let clone = self.inner.clone();
+        // https://docs.rs/tower/latest/tower/trait.Service.html#be-careful-when-cloning-inner-services
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        Box::pin(async move {
+            let (mut parts, body) = req.into_parts();
+            let Some(credentials) = parts
+                .headers
+                .get(AUTHORIZATION)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_string)
+            else {
+                return Ok(rejection(HmacError::Missing));
+            };
+            let Ok(body) = axum::body::to_bytes(body, verifier.body_limit).await else {
+                return Ok(rejection(HmacError::BodyTooLarge));
+            };
+            let path_and_query = parts
+                .uri
+                .path_and_query()
+                .map(|path| path.as_str())
+                .unwrap_or("/");
+            let verified = verifier
+                .verify(&credentials, parts.method.as_str(), path_and_query, &body)
+                .await;
+            match verified {
+                Ok(auth) => {
+                    parts.extensions.insert(auth);
+                    inner
+                        .call(Request::from_parts(parts, Body::from(body)))
+                        .await
+                }
+                Err(err) => Ok(rejection(err)),
+            }
+        })
+    }
+}
+
+fn rejection(err: HmacError) -> Response {
+    let reject = match &err {
+        HmacError::Missing => RejectReason::auth(AuthRejectReason::no_session_token()),
+        HmacError::BodyTooLarge => RejectReason::bad_request(err.to_string()),
+        HmacError::Store(msg) => {
+            tracing::error!("Failed to verify HMAC signature: {}", msg);
+            return RejectReason::database("Failed to reach database").into_response();
+        }
+        _ => RejectReason::auth(AuthRejectReason::invalid_session_token(err.to_string())),
+    };
+    let mut response = reject.into_response();
+    response
+        .headers_mut()
+        .insert(WWW_AUTHENTICATE, HeaderValue::from_static(HMAC_SCHEME));
+    response
+}
+
+/// The caller of a request verified by `RequireHmacLayer`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HmacAuth {
+    pub client_id: String,
+    pub key_id: String,
+}
+
+impl<S> FromRequestParts<S> for HmacAuth
+where
+    S: Send + Sync,
+{
+    type Rejection = RejectReason;
+
+    fn from_request_parts(
+        parts: &mut Parts,
+        _state: &S,
+    ) -> impl Future<Output = Result<Self, Self::Rejection>> + Send {
+        let auth = parts
+            .extensions
+            .get::<HmacAuth>()
+            .cloned()
+            .ok_or_else(|| RejectReason::auth(AuthRejectReason::no_session_token()));
+        future::ready(auth)
+    }
+}
+
+#[cfg(feature = "sqlx")]
+pub use store::{PgHmacKeys, purge_hmac_nonces};
+
+#[cfg(feature = "sqlx")]
+mod store {
+    use std::sync::Arc;
+
+    use chrono::{DateTime, NaiveDateTime, Utc};
+    use futures_util::future::BoxFuture;
+    use serde_json::json;
+    use sqlx::PgPool;
+
+    use super::{HmacClientKey, HmacError, HmacKey, HmacKeyStore, generate_secret};
+    use crate::cipher::FieldCipher;
+    use crate::db::insert_audit_log;
+    use crate::ids::new_uuid;
+    use crate::user_id::UserId;
+
+    /// Keys in `auth.hmac_keys`, belonging to enabled clients in `auth.clients`, with nonces in
+    /// `auth.hmac_nonces` so every process behind a load balancer sees them.
+    #[derive(Clone)]
+    pub struct PgHmacKeys {
+        pool: Arc<PgPool>,
+        cipher: Option<Arc<dyn FieldCipher>>,
+    }
+
+    impl PgHmacKeys {
+        pub fn new(pool: Arc<PgPool>) -> Self {
+            Self { pool, cipher: None }
+        }
+
+        /// Encrypt secrets at rest. Secrets stored in plaintext before keep working.
+        pub fn with_cipher(mut self, cipher: Arc<dyn FieldCipher>) -> Self {
+            self.cipher = Some(cipher);
+            self
+        }
+
+        /// Add a key for `client_id` and log `hmac_key_created`. Returns the key id and secret
+        /// to hand to the client; the caller's copy is the only one outside the database.
+        pub async fn create(
+            &self,
+            actor_user_id: UserId,
+            client_id: &str,
+        ) -> Result<(String, String), HmacError> {
+            let key_id = new_uuid().to_string();
+            let secret = generate_secret();
+            let stored = match &self.cipher {
+                Some(cipher) => cipher
+                    .encrypt(secret.as_bytes(), secret_aad(&key_id).as_bytes())
+                    .map_err(|err| HmacError::Store(err.to_string()))?,
+                None => secret.clone(),
+            };
+            let mut tx = self.pool.begin().await.map_err(store_error)?;
+            sqlx::query(
+                r#"
+                INSERT INTO auth.hmac_keys (key_id, client_id, secret)
+                VALUES ($1, $2, $3)
+                "#,
+            )
+            .bind(&key_id)
+            .bind(client_id)
+            .bind(stored)
+            .execute(&mut *tx)
+            .await
+            .map_err(store_error)?;
+            insert_audit_log(
+                &mut *tx,
+                Some(actor_user_id),
+                json!({
+                    "type": "hmac_key_created",
+                    "client_id": client_id,
+                    "key_id": key_id,
+                }),
+            )
+            .await
+            .map_err(store_error)?;
+            tx.commit().await.map_err(store_error)?;
+            Ok((key_id, secret))
+        }
+
+        /// Stop accepting `key_id` and log `hmac_key_revoked`. Returns `false` if there was no
+        /// active key.
+        pub async fn revoke(&self, actor_user_id: UserId, key_id: &str) -> Result<bool, HmacError> {
+            let mut tx = self.pool.begin().await.map_err(store_error)?;
+            let client_id: Option<(String,)> = sqlx::query_as(
+                r#"
+                UPDATE auth.hmac_keys
+                SET revoked_at = $2
+                WHERE key_id = $1
+                  AND revoked_at IS NULL
+                RETURNING client_id
+                "#,
+            )
+            .bind(key_id)
+            .bind(Utc::now().naive_utc())
+            .fetch_optional(&mut *tx)
+            .await
+            .map_err(store_error)?;
+            let Some((client_id,)) = client_id else {
+                return Ok(false);
+            };
+            insert_audit_log(
+                &mut *tx,
+                Some(actor_user_id),
+                json!({
+                    "type": "hmac_key_revoked",
+                    "client_id": client_id,
+                    "key_id": key_id,
+                }),
+            )
+            .await
+            .map_err(store_error)?;
+            tx.commit().await.map_err(store_error)?;
+            Ok(true)
+        }
+    }
+
+    impl HmacKeyStore for PgHmacKeys {
+        fn key(&self, key_id: &str) -> BoxFuture<'_, Result<Option<HmacClientKey>, HmacError>> {
+            let key_id = key_id.to_string();
+            Box::pin(async move {
+                let row: Option<(String, String)> = sqlx::query_as(
+                    r#"
+                    SELECT k.client_id, k.secret
+                    FROM auth.hmac_keys k
+                    JOIN auth.clients c ON c.client_id = k.client_id
+                    WHERE k.key_id = $1
+                      AND k.revoked_at IS NULL
+                      AND c.disabled_at IS NULL
+                    "#,
+                )
+                .bind(&key_id)
+                .fetch_optional(&*self.pool)
+                .await
+                .map_err(store_error)?;
+                let Some((client_id, stored)) = row else {
+                    return Ok(None);
+                };
+                let secret = match &self.cipher {
+                    Some(cipher) if crate::cipher::is_encrypted(&stored) => cipher
+                        .decrypt(&stored, secret_aad(&key_id).as_bytes())
+                        .map_err(|err| HmacError::Store(err.to_string()))?,
+                    _ => stored.into_bytes(),
+                };
+                Ok(Some(HmacClientKey {
+                    client_id,
+                    key: HmacKey::new(key_id, &secret),
+                }))
+            })
+        }
+
+        fn claim_nonce(
+            &self,
+            key_id: &str,
+            nonce: &str,
+            expires_at: DateTime<Utc>,
+        ) -> BoxFuture<'_, Result<bool, HmacError>> {
+            let key_id = key_id.to_string();
+            let nonce = nonce.to_string();
+            Box::pin(async move {
+                // Expired nonces of the key are cleared on the way, which keeps the table at
+                // about one skew window of traffic.
+                let claimed = sqlx::query(
+                    r#"
+                    WITH expired AS (
+                        DELETE FROM auth.hmac_nonces
+                        WHERE key_id = $1
+                          AND expires_at < $4
+                    )
+                    INSERT INTO auth.hmac_nonces (key_id, nonce, expires_at)
+                    VALUES ($1, $2, $3)
+                    ON CONFLICT (key_id, nonce) DO NOTHING
+                    "#,
+                )
+                .bind(&key_id)
+                .bind(&nonce)
+                .bind(expires_at.naive_utc())
+                .bind(Utc::now().naive_utc())
+                .execute(&*self.pool)
+                .await
+                .map_err(store_error)?
+                .rows_affected()
+                    > 0;
+                Ok(claimed)
+            })
+        }
+    }
+
+    /// Delete nonces that expired before `before`. `claim_nonce` only clears those of the key it
+    /// is claiming for, so nonces of keys gone quiet stay behind until this runs. Returns the
+    /// number deleted.
+    pub async fn purge_hmac_nonces(
+        pool: &PgPool,
+        before: NaiveDateTime,
+    ) -> Result<u64, sqlx::Error> {
+        sqlx::query("DELETE FROM auth.hmac_nonces WHERE expires_at < $1")
+            .bind(before)
+            .execute(pool)
+            .await
+            .map(|result| result.rows_affected())
+    }
+
+    /// Binds an encrypted secret to its row.
+    fn secret_aad(key_id: &str) -> String {
+        format!("auth.hmac_keys.secret:{}", key_id)
+    }
+
+    fn store_error(err: sqlx::Error) -> HmacError {
+        HmacError::Store(err.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::{HmacCredentials, HmacError, HmacKey, HmacVerifier, MemoryHmacKeys};
+
+    #[tokio::test]
+    async fn verifies_signed_requests_once() {
+        let key = HmacKey::new("key-1", b"shared secret");
+        let verifier = HmacVerifier::new(Arc::new(
+            MemoryHmacKeys::new().with_key("billing", key.clone()),
+        ));
+        let credentials = key.sign("post", "/internal/sync?full=1", b"{}").to_string();
+        assert_eq!(
+            credentials.parse::<HmacCredentials>().unwrap().key_id,
+            "key-1"
+        );
+
+        let auth = verifier
+            .verify(&credentials, "POST", "/internal/sync?full=1", b"{}")
+            .await
+            .unwrap();
+        assert_eq!(auth.client_id, "billing");
+        assert_eq!(
+            verifier
+                .verify(&credentials, "POST", "/internal/sync?full=1", b"{}")
+                .await,
+            Err(HmacError::Replayed)
+        );
+
+        let tampered = key.sign("POST", "/internal/sync", b"{}").to_string();
+        assert_eq!(
+            verifier
+                .verify(&tampered, "POST", "/internal/sync", b"{\"all\":true}")
+                .await,
+            Err(HmacError::BadSignature)
+        );
+
+        let stale = key
+            .sign_at(
+                "POST",
+                "/",
+                chrono::Utc::now().timestamp() - 3600,
+                "a".repeat(16),
+                b"",
+            )
+            .to_string();
+        assert_eq!(
+            verifier.verify(&stale, "POST", "/", b"").await,
+            Err(HmacError::Stale)
+        );
+    }
+}
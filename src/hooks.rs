@@ -0,0 +1,237 @@
+//! Callbacks around the lifecycle operations of the `/auth` handlers, for business rules the
+//! crate does not know about, e.g. refusing a group join when the group's plan has no seats left.
+//!
+//! A `before_*` callback runs once the handler has validated and authorized the request and
+//! before anything is written; returning a `HookRejection` vetoes the operation and answers `403`
+//! with the rejection's code. An `after_*` callback runs once the change is stored, and only if
+//! something changed. Every callback defaults to allowing and doing nothing.
+//!
+//! ```ignore
+//! struct Seats(Billing);
+//!
+//! impl Hooks for Seats {
+//!     fn before_group_join<'a>(
+//!         &'a self,
+//!         change: &'a MembershipChange,
+//!     ) -> BoxFuture<'a, Result<(), HookRejection>> {
+//!         Box::pin(async move {
+//!             if self.0.seats_left(change.group_id).await == 0 {
+//!                 return Err(HookRejection::new("seat_limit_reached", "No seats left"));
+//!             }
+//!             Ok(())
+//!         })
+//!     }
+//! }
+//!
+//! let state = AuthState::from_config(config).await?.with_hooks(Seats(billing));
+//! ```
+
+use std::fmt;
+
+use futures_util::future::BoxFuture;
+
+use crate::api::User;
+use crate::db::{RoleAssignmentTarget, RoleEffect};
+use crate::deactivation::DeactivationReason;
+use crate::group_id::GroupId;
+use crate::prelude::RejectReason;
+use crate::user_id::UserId;
+
+/// A veto from a `before_*` callback. `code` is the stable error code clients see.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HookRejection {
+    pub code: String,
+    pub message: String,
+}
+
+impl HookRejection {
+    pub fn new<S1: Into<String>, S2: Into<String>>(code: S1, message: S2) -> Self {
+        Self {
+            code: code.into(),
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for HookRejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.code, self.message)
+    }
+}
+
+impl std::error::Error for HookRejection {}
+
+impl From<HookRejection> for RejectReason {
+    fn from(rejection: HookRejection) -> Self {
+        RejectReason::forbidden_detailed(rejection.code, rejection.message, None)
+    }
+}
+
+/// A user record about to be created on first sign-in, through `/auth/me` or a trusted proxy, by
+/// accepting an invitation, or by a `migrate::import` run given `ImportOptions::with_hooks`.
+#[derive(Debug, Clone)]
+pub struct NewUser<'a> {
+    pub id: UserId,
+    pub email: &'a str,
+    pub username: Option<&'a str>,
+    /// Created by accepting an invitation rather than on first sign-in.
+    pub invited: bool,
+}
+
+/// A role grant, deny or revoke through `/auth/roles`.
+#[derive(Debug, Clone)]
+pub struct RoleChange<'a> {
+    pub actor_user_id: UserId,
+    pub target: RoleAssignmentTarget,
+    pub scope: &'a str,
+    pub scope_id: &'a str,
+    pub role_name: &'a str,
+}
+
+/// A user joining or leaving a group. The actor is the user themselves, or the admin deciding
+/// their join request.
+#[derive(Debug, Clone, Copy)]
+pub struct MembershipChange {
+    pub actor_user_id: UserId,
+    pub group_id: GroupId,
+    pub user_id: UserId,
+}
+
+/// A user or group deactivation, by an admin or, for users, by themselves. Soft-deleting a user
+/// or group deactivates it too; see `recycle_bin`.
+#[derive(Debug, Clone, Copy)]
+pub struct DeactivationChange<T> {
+    pub actor_user_id: UserId,
+    pub subject: T,
+    /// `None` for a soft delete, which records no reason.
+    pub reason: Option<DeactivationReason>,
+}
+
+fn allow<'a>() -> BoxFuture<'a, Result<(), HookRejection>> {
+    Box::pin(async { Ok(()) })
+}
+
+fn done<'a>() -> BoxFuture<'a, ()> {
+    Box::pin(async {})
+}
+
+/// Host-app callbacks around lifecycle operations; see the module docs. Set with `AuthApp::hooks`
+/// or `AuthState::with_hooks`.
+pub trait Hooks: Send + Sync {
+    fn before_user_create<'a>(
+        &'a self,
+        _user: &'a NewUser<'a>,
+    ) -> BoxFuture<'a, Result<(), HookRejection>> {
+        allow()
+    }
+
+    fn after_user_create<'a>(&'a self, _user: &'a User) -> BoxFuture<'a, ()> {
+        done()
+    }
+
+    /// Runs for denies too, with `effect` set to `RoleEffect::Deny`.
+    fn before_role_grant<'a>(
+        &'a self,
+        _change: &'a RoleChange<'a>,
+        _effect: RoleEffect,
+    ) -> BoxFuture<'a, Result<(), HookRejection>> {
+        allow()
+    }
+
+    fn after_role_grant<'a>(
+        &'a self,
+        _change: &'a RoleChange<'a>,
+        _effect: RoleEffect,
+    ) -> BoxFuture<'a, ()> {
+        done()
+    }
+
+    fn before_role_revoke<'a>(
+        &'a self,
+        _change: &'a RoleChange<'a>,
+    ) -> BoxFuture<'a, Result<(), HookRejection>> {
+        allow()
+    }
+
+    fn after_role_revoke<'a>(&'a self, _change: &'a RoleChange<'a>) -> BoxFuture<'a, ()> {
+        done()
+    }
+
+    fn before_group_join<'a>(
+        &'a self,
+        _change: &'a MembershipChange,
+    ) -> BoxFuture<'a, Result<(), HookRejection>> {
+        allow()
+    }
+
+    fn after_group_join<'a>(&'a self, _change: &'a MembershipChange) -> BoxFuture<'a, ()> {
+        done()
+    }
+
+    fn before_group_leave<'a>(
+        &'a self,
+        _change: &'a MembershipChange,
+    ) -> BoxFuture<'a, Result<(), HookRejection>> {
+        allow()
+    }
+
+    fn after_group_leave<'a>(&'a self, _change: &'a MembershipChange) -> BoxFuture<'a, ()> {
+        done()
+    }
+
+    fn before_user_deactivate<'a>(
+        &'a self,
+        _change: &'a DeactivationChange<UserId>,
+    ) -> BoxFuture<'a, Result<(), HookRejection>> {
+        allow()
+    }
+
+    fn after_user_deactivate<'a>(
+        &'a self,
+        _change: &'a DeactivationChange<UserId>,
+    ) -> BoxFuture<'a, ()> {
+        done()
+    }
+
+    fn before_group_deactivate<'a>(
+        &'a self,
+        _change: &'a DeactivationChange<GroupId>,
+    ) -> BoxFuture<'a, Result<(), HookRejection>> {
+        allow()
+    }
+
+    fn after_group_deactivate<'a>(
+        &'a self,
+        _change: &'a DeactivationChange<GroupId>,
+    ) -> BoxFuture<'a, ()> {
+        done()
+    }
+}
+
+/// Allows everything. The default `AuthApp::hooks`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoHooks;
+
+impl Hooks for NoHooks {}
+
+#[cfg(test)]
+mod tests {
+    use uuid::Uuid;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn no_hooks_allow_everything() {
+        let change = MembershipChange {
+            actor_user_id: UserId(Uuid::new_v4()),
+            group_id: GroupId(Uuid::new_v4()),
+            user_id: UserId(Uuid::new_v4()),
+        };
+        assert_eq!(NoHooks.before_group_join(&change).await, Ok(()));
+        let rejection = HookRejection::new("seat_limit_reached", "No seats left");
+        assert!(matches!(
+            RejectReason::from(rejection),
+            RejectReason::ForbiddenDetailed { code, .. } if code == "seat_limit_reached"
+        ));
+    }
+}
@@ -0,0 +1,421 @@
+use std::fmt;
+
+use fluent_bundle::concurrent::FluentBundle;
+use fluent_bundle::{FluentArgs, FluentResource};
+use fluent_langneg::{NegotiationStrategy, negotiate_languages};
+use once_cell::sync::Lazy;
+use unic_langid::LanguageIdentifier;
+
+/// Session key holding the locale chosen by the user for this session.
+pub const LOCALE_SESSION_KEY: &str = "subseq_auth.locale";
+
+pub const DEFAULT_LOCALE: &str = "en";
+
+const EN_RESOURCE: &str = include_str!("../locales/en/auth.ftl");
+
+pub static DEFAULT_TRANSLATIONS: Lazy<FluentTranslations> = Lazy::new(FluentTranslations::default);
+
+/// Parse a BCP 47 language tag such as `pt-BR`.
+pub fn parse_locale(tag: &str) -> Option<LanguageIdentifier> {
+    tag.trim().parse().ok()
+}
+
+/// Locales from an `Accept-Language` header, most preferred first.
+pub fn parse_accept_language(header: &str) -> Vec<LanguageIdentifier> {
+    fluent_langneg::accepted_languages::parse(header)
+}
+
+/// Localized text for user-facing messages.
+///
+/// Keys are Fluent message ids; the English resource shipped with the crate
+/// (`locales/en/auth.ftl`) lists every key the crate uses.
+pub trait Translations: Send + Sync {
+    /// Locale used when nothing better matches. Its messages are the fallback for every locale.
+    fn default_locale(&self) -> LanguageIdentifier;
+
+    fn available_locales(&self) -> Vec<LanguageIdentifier>;
+
+    /// The message `key` in `locale`, or `None` if neither `locale` nor the default locale has it.
+    fn translate(
+        &self,
+        locale: &LanguageIdentifier,
+        key: &str,
+        args: &[(&str, &str)],
+    ) -> Option<String>;
+
+    /// Best available locale for a list of requested locales, most preferred first.
+    fn negotiate(&self, requested: &[LanguageIdentifier]) -> LanguageIdentifier {
+        let available = self.available_locales();
+        let default = self.default_locale();
+        negotiate_languages(
+            requested,
+            &available,
+            Some(&default),
+            NegotiationStrategy::Lookup,
+        )
+        .first()
+        .map(|locale| (*locale).clone())
+        .unwrap_or(default)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TranslationError {
+    InvalidLocale(String),
+    InvalidResource(String),
+}
+
+impl fmt::Display for TranslationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidLocale(tag) => write!(f, "Invalid locale {:?}", tag),
+            Self::InvalidResource(msg) => write!(f, "Invalid Fluent resource: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for TranslationError {}
+
+/// `Translations` backed by Fluent resources. Starts with the crate's English messages; add
+/// other locales, or override English ones, with `with_resource`.
+pub struct FluentTranslations {
+    default_locale: LanguageIdentifier,
+    bundles: Vec<(LanguageIdentifier, FluentBundle<FluentResource>)>,
+}
+
+impl Default for FluentTranslations {
+    fn default() -> Self {
+        Self {
+            default_locale: parse_locale(DEFAULT_LOCALE).expect("valid default locale"),
+            bundles: Vec::new(),
+        }
+        .with_resource(DEFAULT_LOCALE, EN_RESOURCE)
+        .expect("valid built-in resource")
+    }
+}
+
+impl fmt::Debug for FluentTranslations {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FluentTranslations")
+            .field("default_locale", &self.default_locale)
+            .field("locales", &self.available_locales())
+            .finish()
+    }
+}
+
+impl FluentTranslations {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add Fluent messages for `locale`. Messages already defined for the locale are replaced.
+    pub fn with_resource(mut self, locale: &str, source: &str) -> Result<Self, TranslationError> {
+        let locale =
+            parse_locale(locale).ok_or_else(|| TranslationError::InvalidLocale(locale.into()))?;
+        let resource = FluentResource::try_new(source.to_string()).map_err(|(_, errors)| {
+            TranslationError::InvalidResource(
+                errors
+                    .iter()
+                    .map(|err| err.to_string())
+                    .collect::<Vec<_>>()
+                    .join("; "),
+            )
+        })?;
+
+        let index = match self.bundles.iter().position(|(known, _)| *known == locale) {
+            Some(index) => index,
+            None => {
+                let mut bundle = FluentBundle::new_concurrent(vec![locale.clone()]);
+                // Messages end up in JSON bodies and plain-text email, not bidi-aware markup.
+                bundle.set_use_isolating(false);
+                self.bundles.push((locale, bundle));
+                self.bundles.len() - 1
+            }
+        };
+        self.bundles[index].1.add_resource_overriding(resource);
+        Ok(self)
+    }
+
+    /// Use a locale other than English as the fallback. It must have resources added.
+    pub fn with_default_locale(mut self, locale: &str) -> Result<Self, TranslationError> {
+        self.default_locale =
+            parse_locale(locale).ok_or_else(|| TranslationError::InvalidLocale(locale.into()))?;
+        Ok(self)
+    }
+
+    fn format(&self, locale: &LanguageIdentifier, key: &str, args: &FluentArgs) -> Option<String> {
+        let (_, bundle) = self.bundles.iter().find(|(known, _)| known == locale)?;
+        let pattern = bundle.get_message(key)?.value()?;
+        let mut errors = Vec::new();
+        let text = bundle.format_pattern(pattern, Some(args), &mut errors);
+        if !errors.is_empty() {
+            tracing::warn!("Errors formatting {} for {}: {:?}", key, locale, errors);
+        }
+        Some(text.into_owned())
+    }
+}
+
+impl Translations for FluentTranslations {
+    fn default_locale(&self) -> LanguageIdentifier {
+        self.default_locale.clone()
+    }
+
+    fn available_locales(&self) -> Vec<LanguageIdentifier> {
+        self.bundles
+            .iter()
+            .map(|(locale, _)| locale.clone())
+            .collect()
+    }
+
+    fn translate(
+        &self,
+        locale: &LanguageIdentifier,
+        key: &str,
+        args: &[(&str, &str)],
+    ) -> Option<String> {
+        let mut fluent_args = FluentArgs::new();
+        for (name, value) in args {
+            fluent_args.set(*name, *value);
+        }
+        self.format(locale, key, &fluent_args)
+            .or_else(|| self.format(&self.default_locale, key, &fluent_args))
+    }
+}
+
+/// Locale negotiated for a request: the session's chosen locale if set, otherwise the
+/// `Accept-Language` header, otherwise the default locale.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RequestLocale(pub LanguageIdentifier);
+
+#[cfg(feature = "api")]
+mod api_support {
+    use std::task::{Context, Poll};
+
+    use axum::body::Body;
+    use axum::extract::{FromRequestParts, Request};
+    use axum::http::header::{ACCEPT_LANGUAGE, CONTENT_LANGUAGE, CONTENT_LENGTH};
+    use axum::http::request::Parts;
+    use axum::http::{HeaderMap, HeaderValue, StatusCode};
+    use axum::response::Response;
+    use futures_util::future::BoxFuture;
+    use serde_json::Value;
+    use tower::{Layer, Service};
+    use tower_sessions::Session;
+    use unic_langid::LanguageIdentifier;
+
+    use super::{
+        LOCALE_SESSION_KEY, RequestLocale, Translations, parse_accept_language, parse_locale,
+    };
+    use crate::api::AuthApp;
+    use crate::prelude::ErrorCode;
+
+    /// Error bodies are small JSON documents; anything bigger is passed through untouched.
+    const MAX_ERROR_BODY: usize = 64 * 1024;
+
+    async fn negotiate_request_locale(
+        translations: &dyn Translations,
+        headers: &HeaderMap,
+        session: Option<&Session>,
+    ) -> LanguageIdentifier {
+        if let Some(session) = session
+            && let Ok(Some(tag)) = session.get::<String>(LOCALE_SESSION_KEY).await
+            && let Some(locale) = parse_locale(&tag)
+        {
+            return translations.negotiate(&[locale]);
+        }
+        let requested = headers
+            .get(ACCEPT_LANGUAGE)
+            .and_then(|value| value.to_str().ok())
+            .map(parse_accept_language)
+            .unwrap_or_default();
+        translations.negotiate(&requested)
+    }
+
+    impl<S> FromRequestParts<S> for RequestLocale
+    where
+        S: AuthApp + Send + Sync,
+    {
+        type Rejection = StatusCode;
+
+        async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+            let session = parts.extensions.get::<Session>().cloned();
+            Ok(RequestLocale(
+                negotiate_request_locale(state.translations(), &parts.headers, session.as_ref())
+                    .await,
+            ))
+        }
+    }
+
+    /// Rewrite error messages from `RejectReason` responses into the request's locale.
+    ///
+    /// The message is looked up as `error-<code>` (underscores become hyphens), with string fields
+    /// of the error details as arguments. Requests negotiated to the default locale keep the
+    /// original, more specific message. The session locale is only seen when the layer runs inside
+    /// a `SessionManagerLayer`; otherwise `Accept-Language` decides.
+    ///
+    /// ```ignore
+    /// let app = Router::new()
+    ///     .merge(subseq_auth::api::routes(store))
+    ///     .layer(LocalizeErrorsLayer::new(app_state.clone()))
+    ///     .with_state(app_state);
+    /// ```
+    #[derive(Clone)]
+    pub struct LocalizeErrorsLayer<S> {
+        app: S,
+    }
+
+    impl<S> LocalizeErrorsLayer<S>
+    where
+        S: AuthApp,
+    {
+        pub fn new(app: S) -> Self {
+            Self { app }
+        }
+    }
+
+    impl<S, Inner> Layer<Inner> for LocalizeErrorsLayer<S>
+    where
+        S: Clone,
+    {
+        type Service = LocalizeErrors<S, Inner>;
+
+        fn layer(&self, inner: Inner) -> Self::Service {
+            LocalizeErrors {
+                app: self.app.clone(),
+                inner,
+            }
+        }
+    }
+
+    #[derive(Clone)]
+    pub struct LocalizeErrors<S, Inner> {
+        app: S,
+        inner: Inner,
+    }
+
+    impl<S, Inner, B> Service<Request<B>> for LocalizeErrors<S, Inner>
+    where
+        S: AuthApp + Clone + Send + Sync + 'static,
+        Inner: Service<Request<B>, Response = Response> + Clone + Send + 'static,
+        Inner::Future: Send + 'static,
+        B: Send + 'static,
+    {
+        type Response = Response;
+        type Error = Inner::Error;
+        type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+        fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            self.inner.poll_ready(cx)
+        }
+
+        fn call(&mut self, req: Request<B>) -> Self::Future {
+            let app = self.app.clone();
+            let clone = self.inner.clone();
+            // https://docs.rs/tower/latest/tower/trait.Service.html#be-careful-when-cloning-inner-services
+            let mut inner = std::mem::replace(&mut self.inner, clone);
+            Box::pin(async move {
+                let headers = req.headers().clone();
+                let session = req.extensions().get::<Session>().cloned();
+                let response = inner.call(req).await?;
+                if response.extensions().get::<ErrorCode>().is_none() {
+                    return Ok(response);
+                }
+                let translations = app.translations();
+                let locale =
+                    negotiate_request_locale(translations, &headers, session.as_ref()).await;
+                if locale == translations.default_locale() {
+                    return Ok(response);
+                }
+                Ok(localize_error_response(response, translations, &locale).await)
+            })
+        }
+    }
+
+    async fn localize_error_response(
+        response: Response,
+        translations: &dyn Translations,
+        locale: &LanguageIdentifier,
+    ) -> Response {
+        let Some(ErrorCode(code)) = response.extensions().get::<ErrorCode>().cloned() else {
+            return response;
+        };
+        let (mut parts, body) = response.into_parts();
+        let bytes = match axum::body::to_bytes(body, MAX_ERROR_BODY).await {
+            Ok(bytes) => bytes,
+            Err(err) => {
+                tracing::warn!("Failed to read error body for localization: {}", err);
+                return Response::from_parts(parts, Body::empty());
+            }
+        };
+        let Ok(mut value) = serde_json::from_slice::<Value>(&bytes) else {
+            return Response::from_parts(parts, Body::from(bytes));
+        };
+
+        let details: Vec<(String, String)> = value
+            .pointer("/error/details")
+            .and_then(Value::as_object)
+            .map(|details| {
+                details
+                    .iter()
+                    .filter_map(|(name, value)| Some((name.clone(), value.as_str()?.to_string())))
+                    .collect()
+            })
+            .unwrap_or_default();
+        let args: Vec<(&str, &str)> = details
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_str()))
+            .collect();
+        let key = format!("error-{}", code.replace('_', "-"));
+        let Some(message) = translations.translate(locale, &key, &args) else {
+            return Response::from_parts(parts, Body::from(bytes));
+        };
+
+        match value.get_mut("error") {
+            Some(Value::String(error)) => *error = message,
+            Some(Value::Object(error)) => {
+                error.insert("message".to_string(), Value::String(message));
+            }
+            _ => return Response::from_parts(parts, Body::from(bytes)),
+        }
+        parts.headers.remove(CONTENT_LENGTH);
+        if let Ok(language) = HeaderValue::from_str(&locale.to_string()) {
+            parts.headers.insert(CONTENT_LANGUAGE, language);
+        }
+        let body = serde_json::to_vec(&value).expect("valid json");
+        Response::from_parts(parts, Body::from(body))
+    }
+}
+
+#[cfg(feature = "api")]
+pub use api_support::{LocalizeErrors, LocalizeErrorsLayer};
+
+#[cfg(test)]
+mod tests {
+    use super::{FluentTranslations, Translations, parse_accept_language, parse_locale};
+
+    #[test]
+    fn translations_fall_back_to_default_locale() {
+        let translations = FluentTranslations::new()
+            .with_resource("fr", "error-not-found = Ressource introuvable.")
+            .unwrap();
+        let fr = translations.negotiate(&parse_accept_language("fr-CA,fr;q=0.8,en;q=0.5"));
+        assert_eq!(fr, parse_locale("fr").unwrap());
+        assert_eq!(
+            translations.translate(&fr, "error-not-found", &[]),
+            Some("Ressource introuvable.".to_string())
+        );
+        assert_eq!(
+            translations.translate(
+                &fr,
+                "error-email-domain-denied",
+                &[("domain", "example.com")]
+            ),
+            Some("Email addresses from example.com are not allowed.".to_string())
+        );
+        assert_eq!(translations.translate(&fr, "no-such-key", &[]), None);
+        assert_eq!(
+            translations.negotiate(&parse_accept_language("ja")),
+            parse_locale("en").unwrap()
+        );
+    }
+}
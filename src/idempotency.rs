@@ -0,0 +1,615 @@
+//! Idempotency keys, so a client retrying a POST does not send a second invitation or grant.
+//!
+//! A client sends a unique `Idempotency-Key` header with a POST. The first request with the key
+//! claims it in `auth.idempotency_keys` and its response is stored; a retry with the same key gets
+//! the stored response back, with its `Content-Type` and `Location` and marked with
+//! `Idempotent-Replayed: true`, without running the handler again. Keys are per user and expire
+//! after a retention window, 24 hours by default.
+//!
+//! A retry while the first request is still running gets `409`, and reusing a key for a
+//! different request (another path or body) gets `422` with the `idempotency_key_reused` code.
+//! Server errors are not stored, so the client can retry them. Responses marked
+//! `Cache-Control: no-store`, like those carrying a newly issued client secret or invitation
+//! token, and responses setting a cookie only record that the request succeeded: a retry gets
+//! `422` with the `idempotent_response_withheld` code rather than a second copy of the secret,
+//! which would otherwise sit in the table in plaintext. A request that never finishes, because the
+//! client went away or the server stopped, holds its key for a lease of 5 minutes by default;
+//! after that a retry claims the key again.
+
+use std::time::Duration;
+
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use chrono::NaiveDateTime;
+use sha2::{Digest, Sha256};
+use sqlx::{FromRow, PgPool};
+
+use crate::user_id::UserId;
+
+pub const IDEMPOTENCY_KEY_HEADER: &str = "idempotency-key";
+/// Set on responses replayed from a stored key.
+pub const IDEMPOTENT_REPLAYED_HEADER: &str = "idempotent-replayed";
+
+pub const DEFAULT_IDEMPOTENCY_RETENTION: Duration = Duration::from_secs(24 * 60 * 60);
+/// How long an unfinished request holds its key.
+pub const DEFAULT_IDEMPOTENCY_LEASE: Duration = Duration::from_secs(5 * 60);
+
+/// Longest key accepted.
+const MAX_KEY_LEN: usize = 255;
+
+/// A response kept for replay.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StoredResponse {
+    pub status_code: i32,
+    pub content_type: Option<String>,
+    pub location: Option<String>,
+    pub body: Vec<u8>,
+}
+
+#[derive(FromRow)]
+struct KeyRow {
+    fingerprint: String,
+    /// `None` until the first request completes.
+    status_code: Option<i32>,
+    content_type: Option<String>,
+    location: Option<String>,
+    body: Option<Vec<u8>>,
+    withheld: bool,
+}
+
+/// What a request found when it tried to claim its key.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Claim {
+    /// The key is new, expired or abandoned; the request should run and `complete_key`
+    /// afterwards. Carries the claim's `created_at`, which `complete_key`, `withhold_key` and
+    /// `release_key` check so a request whose claim was taken over cannot touch the new one.
+    Claimed(NaiveDateTime),
+    /// Another request with the key has not finished.
+    InProgress,
+    /// The key was used for a different request.
+    Mismatch,
+    Completed(StoredResponse),
+    /// The first request succeeded, but its response was not stored; see `withhold_key`.
+    Withheld,
+}
+
+/// Whether a client-supplied key is usable: 1 to 255 bytes of printable ASCII.
+pub fn is_valid_key(key: &str) -> bool {
+    !key.is_empty() && key.len() <= MAX_KEY_LEN && key.bytes().all(|byte| byte.is_ascii_graphic())
+}
+
+/// Identifies the request a key was first used with: method, path with query, and body.
+pub fn fingerprint(method: &str, path_and_query: &str, body: &[u8]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(method.as_bytes());
+    hasher.update(b" ");
+    hasher.update(path_and_query.as_bytes());
+    hasher.update(b"\n");
+    hasher.update(body);
+    URL_SAFE_NO_PAD.encode(hasher.finalize())
+}
+
+fn cutoff_before(retention: Duration) -> NaiveDateTime {
+    let retention = chrono::Duration::from_std(retention).unwrap_or(chrono::Duration::MAX);
+    let now = chrono::Utc::now().naive_utc();
+    now.checked_sub_signed(retention)
+        .unwrap_or(NaiveDateTime::MIN)
+}
+
+/// Claim `key` for a request, or find what an earlier request with it left. Keys older than
+/// `retention`, and unfinished claims older than `lease`, are claimed again as if new.
+pub async fn claim_key(
+    pool: &PgPool,
+    user_id: UserId,
+    key: &str,
+    fingerprint: &str,
+    retention: Duration,
+    lease: Duration,
+) -> Result<Claim, sqlx::Error> {
+    let claimed: Option<NaiveDateTime> = sqlx::query_scalar(
+        r#"
+        INSERT INTO auth.idempotency_keys (user_id, key, fingerprint, created_at)
+        VALUES ($1, $2, $3, $4)
+        ON CONFLICT (user_id, key) DO UPDATE
+        SET fingerprint = EXCLUDED.fingerprint,
+            status_code = NULL,
+            content_type = NULL,
+            location = NULL,
+            body = NULL,
+            withheld = FALSE,
+            created_at = EXCLUDED.created_at,
+            completed_at = NULL
+        WHERE auth.idempotency_keys.created_at < $5
+           OR (auth.idempotency_keys.status_code IS NULL
+               AND auth.idempotency_keys.created_at < $6)
+        RETURNING created_at
+        "#,
+    )
+    .bind(user_id)
+    .bind(key)
+    .bind(fingerprint)
+    .bind(chrono::Utc::now().naive_utc())
+    .bind(cutoff_before(retention))
+    .bind(cutoff_before(lease))
+    .fetch_optional(pool)
+    .await?;
+    if let Some(claimed_at) = claimed {
+        return Ok(Claim::Claimed(claimed_at));
+    }
+
+    let existing = sqlx::query_as::<_, KeyRow>(
+        r#"
+        SELECT fingerprint, status_code, content_type, location, body, withheld
+        FROM auth.idempotency_keys
+        WHERE user_id = $1
+          AND key = $2
+        "#,
+    )
+    .bind(user_id)
+    .bind(key)
+    .fetch_optional(pool)
+    .await?;
+    Ok(match existing {
+        // Released between the two statements; the other request is retrying it.
+        None => Claim::InProgress,
+        Some(row) if row.fingerprint != fingerprint => Claim::Mismatch,
+        Some(KeyRow {
+            status_code: None, ..
+        }) => Claim::InProgress,
+        Some(KeyRow { withheld: true, .. }) => Claim::Withheld,
+        Some(KeyRow {
+            status_code: Some(status_code),
+            content_type,
+            location,
+            body,
+            ..
+        }) => Claim::Completed(StoredResponse {
+            status_code,
+            content_type,
+            location,
+            body: body.unwrap_or_default(),
+        }),
+    })
+}
+
+/// Store the response of a claimed key for replay. `claimed_at` is from `Claim::Claimed`.
+pub async fn complete_key(
+    pool: &PgPool,
+    user_id: UserId,
+    key: &str,
+    claimed_at: NaiveDateTime,
+    response: &StoredResponse,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        r#"
+        UPDATE auth.idempotency_keys
+        SET status_code = $4,
+            content_type = $5,
+            location = $6,
+            body = $7,
+            completed_at = $8
+        WHERE user_id = $1
+          AND key = $2
+          AND created_at = $3
+        "#,
+    )
+    .bind(user_id)
+    .bind(key)
+    .bind(claimed_at)
+    .bind(response.status_code)
+    .bind(&response.content_type)
+    .bind(&response.location)
+    .bind(&response.body)
+    .bind(chrono::Utc::now().naive_utc())
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// Complete a claimed key without storing its response, for responses that must not be kept,
+/// e.g. ones carrying a secret. A retry gets `Claim::Withheld`.
+pub async fn withhold_key(
+    pool: &PgPool,
+    user_id: UserId,
+    key: &str,
+    claimed_at: NaiveDateTime,
+    status_code: i32,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        r#"
+        UPDATE auth.idempotency_keys
+        SET status_code = $4,
+            withheld = TRUE,
+            completed_at = $5
+        WHERE user_id = $1
+          AND key = $2
+          AND created_at = $3
+        "#,
+    )
+    .bind(user_id)
+    .bind(key)
+    .bind(claimed_at)
+    .bind(status_code)
+    .bind(chrono::Utc::now().naive_utc())
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// Give up a claimed key without storing a response, so a retry runs the request again.
+pub async fn release_key(
+    pool: &PgPool,
+    user_id: UserId,
+    key: &str,
+    claimed_at: NaiveDateTime,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        r#"
+        DELETE FROM auth.idempotency_keys
+        WHERE user_id = $1
+          AND key = $2
+          AND created_at = $3
+          AND status_code IS NULL
+        "#,
+    )
+    .bind(user_id)
+    .bind(key)
+    .bind(claimed_at)
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// Delete keys claimed before `before`. Returns the number deleted.
+pub async fn purge_idempotency_keys(
+    pool: &PgPool,
+    before: NaiveDateTime,
+) -> Result<u64, sqlx::Error> {
+    sqlx::query("DELETE FROM auth.idempotency_keys WHERE created_at < $1")
+        .bind(before)
+        .execute(pool)
+        .await
+        .map(|result| result.rows_affected())
+}
+
+#[cfg(feature = "api")]
+mod api_support {
+    use std::sync::Arc;
+    use std::task::{Context, Poll};
+    use std::time::Duration;
+
+    use axum::body::Body;
+    use axum::extract::Request;
+    use axum::http::header::{CACHE_CONTROL, CONTENT_TYPE, LOCATION, SET_COOKIE};
+    use axum::http::{HeaderMap, HeaderValue, Method, StatusCode};
+    use axum::response::{IntoResponse, Response};
+    use chrono::NaiveDateTime;
+    use futures_util::future::BoxFuture;
+    use sqlx::PgPool;
+    use tower::{Layer, Service};
+
+    use super::{
+        Claim, DEFAULT_IDEMPOTENCY_LEASE, DEFAULT_IDEMPOTENCY_RETENTION, IDEMPOTENCY_KEY_HEADER,
+        IDEMPOTENT_REPLAYED_HEADER, StoredResponse, claim_key, complete_key, fingerprint,
+        is_valid_key, release_key, withhold_key,
+    };
+    use crate::api::HasPool;
+    use crate::prelude::{AuthenticatedUser, RejectReason};
+    use crate::user_id::UserId;
+
+    /// Largest request or response body handled; auth requests and responses are small JSON.
+    const MAX_BODY: usize = 1024 * 1024;
+
+    /// Honor `Idempotency-Key` on POST requests; see the module docs. Requests without the
+    /// header, and unauthenticated ones, pass untouched.
+    ///
+    /// Goes inside `AuthLayer`, which identifies the user the key belongs to.
+    ///
+    /// ```ignore
+    /// let app = Router::new()
+    ///     .merge(subseq_auth::api::routes(store))
+    ///     .layer(IdempotencyLayer::new(app_state.clone()))
+    ///     .layer(AuthLayer::new(app_state.clone()))
+    ///     .with_state(app_state);
+    /// ```
+    #[derive(Clone)]
+    pub struct IdempotencyLayer<S> {
+        app: S,
+        retention: Duration,
+        lease: Duration,
+    }
+
+    impl<S> IdempotencyLayer<S>
+    where
+        S: HasPool,
+    {
+        pub fn new(app: S) -> Self {
+            Self {
+                app,
+                retention: DEFAULT_IDEMPOTENCY_RETENTION,
+                lease: DEFAULT_IDEMPOTENCY_LEASE,
+            }
+        }
+
+        /// How long a key is remembered.
+        pub fn with_retention(mut self, retention: Duration) -> Self {
+            self.retention = retention;
+            self
+        }
+
+        /// How long an unfinished request holds its key. Longer than the slowest handler, or a
+        /// retry can run alongside it.
+        pub fn with_lease(mut self, lease: Duration) -> Self {
+            self.lease = lease;
+            self
+        }
+    }
+
+    impl<S, Inner> Layer<Inner> for IdempotencyLayer<S>
+    where
+        S: Clone,
+    {
+        type Service = Idempotency<S, Inner>;
+
+        fn layer(&self, inner: Inner) -> Self::Service {
+            Idempotency {
+                app: self.app.clone(),
+                retention: self.retention,
+                lease: self.lease,
+                inner,
+            }
+        }
+    }
+
+    #[derive(Clone)]
+    pub struct Idempotency<S, Inner> {
+        app: S,
+        retention: Duration,
+        lease: Duration,
+        inner: Inner,
+    }
+
+    /// Releases a claimed key if the request is dropped before it finishes, e.g. when the client
+    /// disconnects, so a retry does not wait out the lease.
+    struct ClaimGuard {
+        pool: Arc<PgPool>,
+        user_id: UserId,
+        claimed_at: NaiveDateTime,
+        key: Option<String>,
+    }
+
+    impl ClaimGuard {
+        /// Release the key now, so a retry runs the request again.
+        async fn release(mut self) {
+            if let Some(key) = self.key.take()
+                && let Err(err) = release_key(&self.pool, self.user_id, &key, self.claimed_at).await
+            {
+                tracing::error!("Failed to release idempotency key: {}", err);
+            }
+        }
+
+        /// Keep the key; its response is stored.
+        fn keep(mut self) {
+            self.key = None;
+        }
+    }
+
+    impl Drop for ClaimGuard {
+        fn drop(&mut self) {
+            let Some(key) = self.key.take() else {
+                return;
+            };
+            let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+                return;
+            };
+            let pool = self.pool.clone();
+            let user_id = self.user_id;
+            let claimed_at = self.claimed_at;
+            runtime.spawn(async move {
+                if let Err(err) = release_key(&pool, user_id, &key, claimed_at).await {
+                    tracing::error!("Failed to release abandoned idempotency key: {}", err);
+                }
+            });
+        }
+    }
+
+    impl<S, Inner> Service<Request> for Idempotency<S, Inner>
+    where
+        S: HasPool + Clone + Send + Sync + 'static,
+        Inner: Service<Request, Response = Response> + Clone + Send + 'static,
+        Inner::Error: Send,
+        Inner::Future: Send + 'static,
+    {
+        type Response = Response;
+        type Error = Inner::Error;
+        type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+        fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            self.inner.poll_ready(cx)
+        }
+
+        fn call(&mut self, req: Request) -> Self::Future {
+            let pool = self.app.pool();
+            let retention = self.retention;
+            let lease = self.lease;
+            let clone = self.inner.clone();
+            // Keep the service that was polled ready; see tower's `Service` docs on cloning
+            // inner services.
+            let mut inner = std::mem::replace(&mut self.inner, clone);
+            Box::pin(async move {
+                if req.method() != Method::POST {
+                    return inner.call(req).await;
+                }
+                let key = req
+                    .headers()
+                    .get(IDEMPOTENCY_KEY_HEADER)
+                    .map(|value| value.to_str().unwrap_or_default().to_string());
+                let user_id = req
+                    .extensions()
+                    .get::<AuthenticatedUser>()
+                    .map(|auth_user| auth_user.id());
+                let (Some(key), Some(user_id)) = (key, user_id) else {
+                    return inner.call(req).await;
+                };
+                if !is_valid_key(&key) {
+                    return Ok(RejectReason::bad_request(
+                        "Idempotency-Key must be 1 to 255 printable ASCII characters",
+                    )
+                    .into_response());
+                }
+
+                let (parts, body) = req.into_parts();
+                let Ok(body) = axum::body::to_bytes(body, MAX_BODY).await else {
+                    return Ok(RejectReason::bad_request(
+                        "Request body is too large for an idempotent request",
+                    )
+                    .into_response());
+                };
+                let path = parts
+                    .uri
+                    .path_and_query()
+                    .map(|path| path.as_str())
+                    .unwrap_or_else(|| parts.uri.path());
+                let fingerprint = fingerprint(parts.method.as_str(), path, &body);
+                let claimed_at =
+                    match claim_key(&pool, user_id, &key, &fingerprint, retention, lease).await {
+                        Ok(Claim::Claimed(claimed_at)) => claimed_at,
+                        Ok(Claim::Completed(stored)) => return Ok(replay(stored)),
+                        Ok(Claim::InProgress) => {
+                            return Ok(RejectReason::conflict(
+                                "A request with this Idempotency-Key is still in progress",
+                            )
+                            .into_response());
+                        }
+                        Ok(Claim::Mismatch) => {
+                            return Ok(RejectReason::idempotency_key_reused().into_response());
+                        }
+                        Ok(Claim::Withheld) => {
+                            return Ok(RejectReason::idempotent_response_withheld().into_response());
+                        }
+                        Err(err) => {
+                            tracing::error!("Failed to claim idempotency key: {}", err);
+                            return Ok(
+                                RejectReason::database("Failed to reach database").into_response()
+                            );
+                        }
+                    };
+
+                let guard = ClaimGuard {
+                    pool: pool.clone(),
+                    user_id,
+                    claimed_at,
+                    key: Some(key.clone()),
+                };
+                let response = inner
+                    .call(Request::from_parts(parts, Body::from(body)))
+                    .await;
+                let response = match response {
+                    Ok(response) if !response.status().is_server_error() => response,
+                    response => {
+                        guard.release().await;
+                        return response;
+                    }
+                };
+
+                if must_withhold(response.headers()) {
+                    let status_code = i32::from(response.status().as_u16());
+                    if let Err(err) =
+                        withhold_key(&pool, user_id, &key, claimed_at, status_code).await
+                    {
+                        tracing::error!("Failed to complete idempotency key: {}", err);
+                    }
+                    guard.keep();
+                    return Ok(response);
+                }
+
+                let (parts, body) = response.into_parts();
+                let body = match axum::body::to_bytes(body, MAX_BODY).await {
+                    Ok(body) => body,
+                    Err(err) => {
+                        tracing::error!("Failed to read response to store for replay: {}", err);
+                        guard.release().await;
+                        return Ok(StatusCode::INTERNAL_SERVER_ERROR.into_response());
+                    }
+                };
+                let header = |name| {
+                    parts
+                        .headers
+                        .get(name)
+                        .and_then(|value| value.to_str().ok())
+                        .map(str::to_string)
+                };
+                let stored = StoredResponse {
+                    status_code: i32::from(parts.status.as_u16()),
+                    content_type: header(CONTENT_TYPE),
+                    location: header(LOCATION),
+                    body: body.to_vec(),
+                };
+                if let Err(err) = complete_key(&pool, user_id, &key, claimed_at, &stored).await {
+                    tracing::error!("Failed to store idempotent response: {}", err);
+                }
+                guard.keep();
+                Ok(Response::from_parts(parts, Body::from(body)))
+            })
+        }
+    }
+
+    /// Whether the response asks not to be stored anywhere, or sets a cookie, which is not
+    /// replayed: a session cookie is a credential, and a replay without it would differ.
+    fn must_withhold(headers: &HeaderMap) -> bool {
+        headers.contains_key(SET_COOKIE)
+            || headers
+                .get_all(CACHE_CONTROL)
+                .iter()
+                .filter_map(|value| value.to_str().ok())
+                .flat_map(|value| value.split(','))
+                .any(|directive| directive.trim().eq_ignore_ascii_case("no-store"))
+    }
+
+    fn replay(stored: StoredResponse) -> Response {
+        let status = u16::try_from(stored.status_code)
+            .ok()
+            .and_then(|status| StatusCode::from_u16(status).ok())
+            .unwrap_or(StatusCode::OK);
+        let mut response = (status, stored.body).into_response();
+        let headers = response.headers_mut();
+        match stored
+            .content_type
+            .and_then(|value| HeaderValue::from_str(&value).ok())
+        {
+            Some(content_type) => {
+                headers.insert(CONTENT_TYPE, content_type);
+            }
+            None => {
+                headers.remove(CONTENT_TYPE);
+            }
+        }
+        if let Some(location) = stored
+            .location
+            .and_then(|value| HeaderValue::from_str(&value).ok())
+        {
+            headers.insert(LOCATION, location);
+        }
+        headers.insert(IDEMPOTENT_REPLAYED_HEADER, HeaderValue::from_static("true"));
+        response
+    }
+}
+
+#[cfg(feature = "api")]
+pub use api_support::{Idempotency, IdempotencyLayer};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fingerprints_cover_path_and_body() {
+        let original = fingerprint("POST", "/auth/roles/grant", b"{}");
+        assert_eq!(original, fingerprint("POST", "/auth/roles/grant", b"{}"));
+        assert_ne!(original, fingerprint("POST", "/auth/roles/revoke", b"{}"));
+        assert_ne!(original, fingerprint("POST", "/auth/roles/grant", b"{ }"));
+        assert!(is_valid_key("3f1c-retry"));
+        assert!(!is_valid_key(""));
+        assert!(!is_valid_key("with space"));
+    }
+}
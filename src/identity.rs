@@ -0,0 +1,666 @@
+//! Ready-made `ValidatesIdentity` implementations, so an app that signs users in one of the usual
+//! ways needs no identity code of its own.
+//!
+//! - `IdentityProvider`: OIDC ID tokens as bearer tokens and sessions from the browser login.
+//! - `SessionOnly`: the same sessions, with bearer tokens refused.
+//! - `JwtIdentity`: bearer JWTs signed by a `keys::KeyRing`, e.g. tokens the app issues itself.
+//! - `TrustedHeaderIdentity`: identity headers from an authenticating proxy.
+//! - `ApiKeyIdentity`: static API keys sent as bearer tokens.
+//!
+//! `IdentityValidator` holds any of them and is picked by `config::IdentityConfig`, so the app
+//! state only has to delegate:
+//!
+//! ```ignore
+//! let identity = IdentityValidator::from_config(&config).await?;
+//!
+//! impl ValidatesIdentity for AppState {
+//!     fn validate_bearer(
+//!         &self,
+//!         token: &str,
+//!     ) -> Result<(CoreIdToken, CoreIdTokenClaims), ClaimsVerificationError> {
+//!         self.identity.validate_bearer(token)
+//!     }
+//!     // validate_token and refresh_token likewise
+//! }
+//! ```
+//!
+//! Credentials that are not ID tokens are checked and then exchanged for a short-lived ID token
+//! signed by a per-process key, so `AuthenticatedUser` looks the same whichever way the user
+//! signed in.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::{Context, anyhow};
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use chrono::Utc;
+use openidconnect::core::{CoreHmacKey, CoreIdToken, CoreIdTokenClaims, CoreJwsSigningAlgorithm};
+use openidconnect::{
+    Audience, ClaimsVerificationError, CsrfToken, EmptyAdditionalClaims, EndUserEmail,
+    EndUserUsername, IssuerUrl, StandardClaims, SubjectIdentifier,
+};
+use sha2::{Digest, Sha256};
+
+use crate::config::{AuthConfig, IdentityMode};
+use crate::keys::{FileKeySource, KeyError, KeyRing};
+use crate::oidc::{IdentityProvider, OidcToken};
+use crate::prelude::{UserId, ValidatesIdentity};
+#[cfg(feature = "api")]
+use crate::{api::AuthApp, trusted_proxy::TrustedProxyConfig, trusted_proxy::TrustedProxyLayer};
+
+/// `iss` of the ID tokens behind users authenticated by an API key.
+pub const API_KEY_ISSUER: &str = "urn:subseq-auth:api-key";
+
+const LOCAL_AUDIENCE: &str = "subseq-auth";
+
+/// Lifetime of the ID tokens minted for users authenticated by other means.
+const LOCAL_TOKEN_TTL_SECS: i64 = 300;
+
+/// How long keys listed after the first in `IdentityConfig::jwt_keys_file` keep verifying.
+const JWT_KEY_GRACE_DAYS: i64 = 1;
+
+impl ValidatesIdentity for IdentityProvider {
+    fn validate_bearer(
+        &self,
+        token: &str,
+    ) -> Result<(CoreIdToken, CoreIdTokenClaims), ClaimsVerificationError> {
+        IdentityProvider::validate_bearer(self, token)
+    }
+
+    fn validate_token(
+        &self,
+        token: &OidcToken,
+    ) -> Result<(CoreIdToken, CoreIdTokenClaims), ClaimsVerificationError> {
+        IdentityProvider::validate_token(self, token)
+    }
+
+    fn refresh_token(
+        &self,
+        token: OidcToken,
+    ) -> impl std::future::Future<Output = anyhow::Result<OidcToken>> + Send {
+        self.refresh(token)
+    }
+}
+
+impl<V> ValidatesIdentity for Arc<V>
+where
+    V: ValidatesIdentity + Send + Sync,
+{
+    fn validate_bearer(
+        &self,
+        token: &str,
+    ) -> Result<(CoreIdToken, CoreIdTokenClaims), ClaimsVerificationError> {
+        (**self).validate_bearer(token)
+    }
+
+    fn validate_token(
+        &self,
+        token: &OidcToken,
+    ) -> Result<(CoreIdToken, CoreIdTokenClaims), ClaimsVerificationError> {
+        (**self).validate_token(token)
+    }
+
+    fn refresh_token(
+        &self,
+        token: OidcToken,
+    ) -> impl std::future::Future<Output = anyhow::Result<OidcToken>> + Send {
+        (**self).refresh_token(token)
+    }
+}
+
+/// Signs ID tokens for users this process authenticated some other way. The key is random and
+/// never leaves the process, so the tokens cannot be presented back to it.
+pub(crate) struct LocalIssuer {
+    issuer: String,
+    key: CoreHmacKey,
+}
+
+impl fmt::Debug for LocalIssuer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LocalIssuer")
+            .field("issuer", &self.issuer)
+            .finish_non_exhaustive()
+    }
+}
+
+impl LocalIssuer {
+    pub(crate) fn new(issuer: &str) -> Self {
+        Self {
+            issuer: issuer.to_string(),
+            key: CoreHmacKey::new(CsrfToken::new_random_len(32).secret().as_bytes()),
+        }
+    }
+
+    /// Claims vouching for `user_id`, valid for a few minutes.
+    pub(crate) fn mint(
+        &self,
+        user_id: UserId,
+        email: &str,
+        username: Option<&str>,
+    ) -> anyhow::Result<(CoreIdToken, CoreIdTokenClaims)> {
+        let claims = user_claims(
+            &self.issuer,
+            LOCAL_AUDIENCE,
+            chrono::Duration::seconds(LOCAL_TOKEN_TTL_SECS),
+            user_id,
+            email,
+            username,
+        )?;
+        Ok((self.sign(claims.clone())?, claims))
+    }
+
+    /// An ID token carrying `claims`, which were verified elsewhere.
+    pub(crate) fn sign(&self, claims: CoreIdTokenClaims) -> anyhow::Result<CoreIdToken> {
+        Ok(CoreIdToken::new(
+            claims,
+            &self.key,
+            CoreJwsSigningAlgorithm::HmacSha256,
+            None,
+            None,
+        )?)
+    }
+}
+
+fn user_claims(
+    issuer: &str,
+    audience: &str,
+    ttl: chrono::Duration,
+    user_id: UserId,
+    email: &str,
+    username: Option<&str>,
+) -> anyhow::Result<CoreIdTokenClaims> {
+    let now = Utc::now();
+    let standard = StandardClaims::new(SubjectIdentifier::new(user_id.to_string()))
+        .set_email(Some(EndUserEmail::new(email.to_string())))
+        .set_email_verified(Some(true))
+        .set_preferred_username(username.map(|name| EndUserUsername::new(name.to_string())));
+    Ok(CoreIdTokenClaims::new(
+        IssuerUrl::new(issuer.to_string())?,
+        vec![Audience::new(audience.to_string())],
+        now + ttl,
+        now,
+        standard,
+        EmptyAdditionalClaims {},
+    ))
+}
+
+fn refused(message: &str) -> ClaimsVerificationError {
+    ClaimsVerificationError::Other(message.to_string())
+}
+
+/// Browser sessions only: sessions are checked and refreshed by the wrapped validator, bearer
+/// tokens are refused. For apps with no API clients, where a leaked ID token should not be
+/// usable outside the browser.
+#[derive(Clone, Debug)]
+pub struct SessionOnly<V>(pub V);
+
+impl<V> ValidatesIdentity for SessionOnly<V>
+where
+    V: ValidatesIdentity + Send + Sync,
+{
+    fn validate_bearer(
+        &self,
+        _token: &str,
+    ) -> Result<(CoreIdToken, CoreIdTokenClaims), ClaimsVerificationError> {
+        Err(refused("Bearer tokens are not accepted"))
+    }
+
+    fn validate_token(
+        &self,
+        token: &OidcToken,
+    ) -> Result<(CoreIdToken, CoreIdTokenClaims), ClaimsVerificationError> {
+        self.0.validate_token(token)
+    }
+
+    fn refresh_token(
+        &self,
+        token: OidcToken,
+    ) -> impl std::future::Future<Output = anyhow::Result<OidcToken>> + Send {
+        self.0.refresh_token(token)
+    }
+}
+
+/// Bearer JWTs signed by a `KeyRing`, with the standard ID token claims (`sub` is the user id,
+/// plus `email` and optionally `preferred_username`). `issue` makes them, e.g. for a CLI login
+/// or a service the app trusts to act as a user. They cannot be refreshed; the holder asks for a
+/// new one.
+#[derive(Clone, Debug)]
+pub struct JwtIdentity {
+    ring: KeyRing,
+    issuer: String,
+    audience: String,
+    ttl: chrono::Duration,
+    local: Arc<LocalIssuer>,
+}
+
+impl JwtIdentity {
+    /// Accept tokens from `ring` whose `iss` is `issuer` and whose `aud` includes `audience`.
+    /// `issue` makes tokens valid for an hour.
+    pub fn new<I: Into<String>, A: Into<String>>(ring: KeyRing, issuer: I, audience: A) -> Self {
+        let issuer = issuer.into();
+        Self {
+            local: Arc::new(LocalIssuer::new(&issuer)),
+            ring,
+            issuer,
+            audience: audience.into(),
+            ttl: chrono::Duration::hours(1),
+        }
+    }
+
+    pub fn with_ttl(mut self, ttl: chrono::Duration) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    pub fn key_ring(&self) -> &KeyRing {
+        &self.ring
+    }
+
+    /// A token identifying `user_id`, signed with the ring's active key.
+    pub fn issue(
+        &self,
+        user_id: UserId,
+        email: &str,
+        username: Option<&str>,
+    ) -> Result<String, KeyError> {
+        let claims = user_claims(
+            &self.issuer,
+            &self.audience,
+            self.ttl,
+            user_id,
+            email,
+            username,
+        )
+        .map_err(|err| KeyError::Invalid(err.to_string()))?;
+        self.ring.sign(&claims)
+    }
+
+    fn verify(
+        &self,
+        token: &str,
+    ) -> Result<(CoreIdToken, CoreIdTokenClaims), ClaimsVerificationError> {
+        let claims: CoreIdTokenClaims = self
+            .ring
+            .verify_with(token, |validation| {
+                validation.set_issuer(&[&self.issuer]);
+                validation.set_audience(&[&self.audience]);
+            })
+            .map_err(|err| ClaimsVerificationError::Other(err.to_string()))?;
+        // The ring may sign with EdDSA, which `CoreIdToken` cannot parse, so hand on a local copy.
+        let id_token = self
+            .local
+            .sign(claims.clone())
+            .map_err(|err| ClaimsVerificationError::Other(err.to_string()))?;
+        Ok((id_token, claims))
+    }
+}
+
+impl ValidatesIdentity for JwtIdentity {
+    fn validate_bearer(
+        &self,
+        token: &str,
+    ) -> Result<(CoreIdToken, CoreIdTokenClaims), ClaimsVerificationError> {
+        self.verify(token)
+    }
+
+    fn validate_token(
+        &self,
+        token: &OidcToken,
+    ) -> Result<(CoreIdToken, CoreIdTokenClaims), ClaimsVerificationError> {
+        self.verify(&token.id_token().to_string())
+    }
+
+    fn refresh_token(
+        &self,
+        _token: OidcToken,
+    ) -> impl std::future::Future<Output = anyhow::Result<OidcToken>> + Send {
+        std::future::ready(Err(anyhow!("JWT identities cannot be refreshed")))
+    }
+}
+
+/// Identity from an authenticating proxy's headers. The proxy's headers are read by the
+/// `TrustedProxyLayer` from `layer`, not by `auth::AuthLayer`, so every token is refused here.
+#[cfg(feature = "api")]
+#[derive(Clone, Debug)]
+pub struct TrustedHeaderIdentity {
+    config: TrustedProxyConfig,
+}
+
+#[cfg(feature = "api")]
+impl TrustedHeaderIdentity {
+    pub fn new(config: TrustedProxyConfig) -> Self {
+        Self { config }
+    }
+
+    /// The layer that authenticates requests by the proxy's headers.
+    pub fn layer<S: AuthApp>(&self, app: S) -> TrustedProxyLayer<S> {
+        TrustedProxyLayer::new(app, self.config.clone())
+    }
+}
+
+#[cfg(feature = "api")]
+impl ValidatesIdentity for TrustedHeaderIdentity {
+    fn validate_bearer(
+        &self,
+        _token: &str,
+    ) -> Result<(CoreIdToken, CoreIdTokenClaims), ClaimsVerificationError> {
+        Err(refused("Identity comes from the trusted proxy"))
+    }
+
+    fn validate_token(
+        &self,
+        _token: &OidcToken,
+    ) -> Result<(CoreIdToken, CoreIdTokenClaims), ClaimsVerificationError> {
+        Err(refused("Identity comes from the trusted proxy"))
+    }
+
+    fn refresh_token(
+        &self,
+        _token: OidcToken,
+    ) -> impl std::future::Future<Output = anyhow::Result<OidcToken>> + Send {
+        std::future::ready(Err(anyhow!("Identity comes from the trusted proxy")))
+    }
+}
+
+/// The user an API key acts as.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ApiKeyOwner {
+    pub user_id: UserId,
+    pub email: String,
+    pub username: Option<String>,
+}
+
+/// Static API keys sent as `Authorization: Bearer <key>`, each acting as one user. Only the
+/// SHA-256 of each key is held, as produced by `hash_key`.
+#[derive(Clone)]
+pub struct ApiKeyIdentity {
+    keys: Arc<HashMap<String, ApiKeyOwner>>,
+    local: Arc<LocalIssuer>,
+}
+
+impl fmt::Debug for ApiKeyIdentity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ApiKeyIdentity")
+            .field("keys", &self.keys.len())
+            .finish_non_exhaustive()
+    }
+}
+
+impl Default for ApiKeyIdentity {
+    fn default() -> Self {
+        Self {
+            keys: Arc::new(HashMap::new()),
+            local: Arc::new(LocalIssuer::new(API_KEY_ISSUER)),
+        }
+    }
+}
+
+impl ApiKeyIdentity {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A fresh random key to hand to a client.
+    pub fn generate_key() -> String {
+        CsrfToken::new_random_len(32).secret().clone()
+    }
+
+    /// base64url SHA-256 of `key`, the form keys are stored and configured in.
+    pub fn hash_key(key: &str) -> String {
+        URL_SAFE_NO_PAD.encode(Sha256::digest(key.trim().as_bytes()))
+    }
+
+    pub fn with_key(self, key: &str, owner: ApiKeyOwner) -> Self {
+        self.with_key_hash(Self::hash_key(key), owner)
+    }
+
+    pub fn with_key_hash<S: Into<String>>(mut self, hash: S, owner: ApiKeyOwner) -> Self {
+        Arc::make_mut(&mut self.keys).insert(hash.into(), owner);
+        self
+    }
+
+    /// Parse one key per line as `<hash> <user-id> <email> [username]`, with `hash` from
+    /// `hash_key`. Blank lines and lines starting with `#` are skipped.
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut identity = Self::new();
+        for (index, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let (hash, user_id, email, username) = match fields.as_slice() {
+                [hash, user_id, email] => (*hash, *user_id, *email, None),
+                [hash, user_id, email, username] => (*hash, *user_id, *email, Some(*username)),
+                _ => {
+                    return Err(format!(
+                        "line {}: expected <hash> <user-id> <email> [username]",
+                        index + 1
+                    ));
+                }
+            };
+            let user_id = user_id
+                .parse()
+                .map_err(|err| format!("line {}: {}", index + 1, err))?;
+            identity = identity.with_key_hash(
+                hash,
+                ApiKeyOwner {
+                    user_id,
+                    email: email.to_string(),
+                    username: username.map(str::to_string),
+                },
+            );
+        }
+        Ok(identity)
+    }
+
+    /// Keys from a file in the `parse` format.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        Self::parse(&text).map_err(|err| anyhow!("{}: {}", path.display(), err))
+    }
+}
+
+impl ValidatesIdentity for ApiKeyIdentity {
+    fn validate_bearer(
+        &self,
+        token: &str,
+    ) -> Result<(CoreIdToken, CoreIdTokenClaims), ClaimsVerificationError> {
+        let owner = self
+            .keys
+            .get(&Self::hash_key(token))
+            .ok_or_else(|| refused("Unknown API key"))?;
+        self.local
+            .mint(owner.user_id, &owner.email, owner.username.as_deref())
+            .map_err(|err| ClaimsVerificationError::Other(err.to_string()))
+    }
+
+    fn validate_token(
+        &self,
+        _token: &OidcToken,
+    ) -> Result<(CoreIdToken, CoreIdTokenClaims), ClaimsVerificationError> {
+        Err(refused("API key identities have no sessions"))
+    }
+
+    fn refresh_token(
+        &self,
+        _token: OidcToken,
+    ) -> impl std::future::Future<Output = anyhow::Result<OidcToken>> + Send {
+        std::future::ready(Err(anyhow!("API key identities have no sessions")))
+    }
+}
+
+/// Whichever built-in validator `IdentityConfig::mode` names.
+#[derive(Clone)]
+pub enum IdentityValidator {
+    Oidc(Arc<IdentityProvider>),
+    SessionOnly(SessionOnly<Arc<IdentityProvider>>),
+    Jwt(JwtIdentity),
+    #[cfg(feature = "api")]
+    TrustedHeader(TrustedHeaderIdentity),
+    ApiKey(ApiKeyIdentity),
+}
+
+impl IdentityValidator {
+    /// Build the validator for `config.identity`, fetching the provider metadata for the OIDC
+    /// modes and reading the key files for the others.
+    pub async fn from_config(config: &AuthConfig) -> anyhow::Result<Self> {
+        let identity = &config.identity;
+        let provider = || async {
+            let oidc = config
+                .oidc
+                .as_ref()
+                .ok_or_else(|| anyhow!("Identity mode {} needs the oidc section", identity.mode))?;
+            Ok::<_, anyhow::Error>(Arc::new(oidc.identity_provider().await?))
+        };
+        Ok(match identity.mode {
+            IdentityMode::Oidc => IdentityValidator::Oidc(provider().await?),
+            IdentityMode::SessionOnly => {
+                IdentityValidator::SessionOnly(SessionOnly(provider().await?))
+            }
+            IdentityMode::Jwt => {
+                let (Some(issuer), Some(audience), Some(keys_file)) = (
+                    &identity.jwt_issuer,
+                    &identity.jwt_audience,
+                    &identity.jwt_keys_file,
+                ) else {
+                    return Err(anyhow!(
+                        "Identity mode jwt needs jwt_issuer, jwt_audience and jwt_keys_file"
+                    ));
+                };
+                let ring = KeyRing::load(
+                    Arc::new(FileKeySource::new(keys_file)),
+                    chrono::Duration::days(JWT_KEY_GRACE_DAYS),
+                )
+                .await?;
+                IdentityValidator::Jwt(JwtIdentity::new(ring, issuer, audience))
+            }
+            #[cfg(feature = "api")]
+            IdentityMode::TrustedHeader => IdentityValidator::TrustedHeader(
+                TrustedHeaderIdentity::new(identity.trusted_proxy_config()?),
+            ),
+            #[cfg(not(feature = "api"))]
+            IdentityMode::TrustedHeader => {
+                return Err(anyhow!(
+                    "Identity mode trusted_header needs the api feature"
+                ));
+            }
+            IdentityMode::ApiKey => {
+                let path = identity
+                    .api_keys_file
+                    .as_ref()
+                    .ok_or_else(|| anyhow!("Identity mode api_key needs api_keys_file"))?;
+                IdentityValidator::ApiKey(ApiKeyIdentity::from_file(path)?)
+            }
+        })
+    }
+}
+
+impl ValidatesIdentity for IdentityValidator {
+    fn validate_bearer(
+        &self,
+        token: &str,
+    ) -> Result<(CoreIdToken, CoreIdTokenClaims), ClaimsVerificationError> {
+        match self {
+            IdentityValidator::Oidc(validator) => validator.validate_bearer(token),
+            IdentityValidator::SessionOnly(validator) => validator.validate_bearer(token),
+            IdentityValidator::Jwt(validator) => validator.validate_bearer(token),
+            #[cfg(feature = "api")]
+            IdentityValidator::TrustedHeader(validator) => validator.validate_bearer(token),
+            IdentityValidator::ApiKey(validator) => validator.validate_bearer(token),
+        }
+    }
+
+    fn validate_token(
+        &self,
+        token: &OidcToken,
+    ) -> Result<(CoreIdToken, CoreIdTokenClaims), ClaimsVerificationError> {
+        match self {
+            IdentityValidator::Oidc(validator) => validator.validate_token(token),
+            IdentityValidator::SessionOnly(validator) => validator.validate_token(token),
+            IdentityValidator::Jwt(validator) => validator.validate_token(token),
+            #[cfg(feature = "api")]
+            IdentityValidator::TrustedHeader(validator) => validator.validate_token(token),
+            IdentityValidator::ApiKey(validator) => validator.validate_token(token),
+        }
+    }
+
+    async fn refresh_token(&self, token: OidcToken) -> anyhow::Result<OidcToken> {
+        match self {
+            IdentityValidator::Oidc(validator) => validator.refresh(token).await,
+            IdentityValidator::SessionOnly(validator) => validator.refresh_token(token).await,
+            IdentityValidator::Jwt(validator) => validator.refresh_token(token).await,
+            #[cfg(feature = "api")]
+            IdentityValidator::TrustedHeader(validator) => validator.refresh_token(token).await,
+            IdentityValidator::ApiKey(validator) => validator.refresh_token(token).await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use base64::Engine;
+    use futures_util::future::BoxFuture;
+
+    use super::{ApiKeyIdentity, ApiKeyOwner, JwtIdentity, SessionOnly};
+    use crate::keys::{KeyError, KeyRing, KeySource, SigningKey, parse_keys};
+    use crate::prelude::{AuthenticatedUser, UserId, ValidatesIdentity};
+
+    struct StaticSource(String);
+
+    impl KeySource for StaticSource {
+        fn load(&self) -> BoxFuture<'_, Result<Vec<SigningKey>, KeyError>> {
+            Box::pin(async move { parse_keys(&self.0) })
+        }
+    }
+
+    #[tokio::test]
+    async fn validates_jwts_and_api_keys() {
+        let user_id = UserId::new();
+        let secret = base64::engine::general_purpose::STANDARD.encode([7; 32]);
+        let ring = KeyRing::load(
+            Arc::new(StaticSource(format!("k1:{}", secret))),
+            chrono::Duration::zero(),
+        )
+        .await
+        .unwrap();
+        let jwt = JwtIdentity::new(ring.clone(), "https://app.example.com", "app");
+        let token = jwt.issue(user_id, "a@example.com", Some("a")).unwrap();
+        let (id_token, claims) = jwt.validate_bearer(&token).unwrap();
+        let user = AuthenticatedUser::from_claims(id_token, claims)
+            .await
+            .unwrap();
+        assert_eq!(user.id(), user_id);
+
+        let other = JwtIdentity::new(ring, "https://app.example.com", "other");
+        assert!(other.validate_bearer(&token).is_err());
+        assert!(SessionOnly(jwt).validate_bearer(&token).is_err());
+
+        let key = ApiKeyIdentity::generate_key();
+        let keys = ApiKeyIdentity::parse(&format!(
+            "# ci\n{} {} ci@example.com\n",
+            ApiKeyIdentity::hash_key(&key),
+            user_id
+        ))
+        .unwrap()
+        .with_key(
+            "other-key",
+            ApiKeyOwner {
+                user_id: UserId::new(),
+                email: "b@example.com".to_string(),
+                username: None,
+            },
+        );
+        let (_, claims) = keys.validate_bearer(&key).unwrap();
+        assert_eq!(claims.subject().as_str(), user_id.to_string());
+        assert!(keys.validate_bearer("wrong").is_err());
+        assert!(ApiKeyIdentity::parse("hash not-a-uuid a@example.com").is_err());
+    }
+}
@@ -0,0 +1,75 @@
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU8, Ordering};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+/// How new user, group, log and login history ids are generated.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UuidVersion {
+    /// Random.
+    #[default]
+    V4,
+    /// Millisecond timestamp followed by random bits. Ids created close together sort close
+    /// together, which keeps inserts into the primary key index of busy tables like `auth.log`
+    /// at its right edge. The id reveals when the row was created.
+    V7,
+}
+
+impl FromStr for UuidVersion {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_ascii_lowercase().as_str() {
+            "v4" | "4" => Ok(UuidVersion::V4),
+            "v7" | "7" => Ok(UuidVersion::V7),
+            _ => Err("expected v4 or v7".to_string()),
+        }
+    }
+}
+
+static VERSION: AtomicU8 = AtomicU8::new(0);
+
+/// Set the process-wide version, e.g. from `AuthConfig::uuid_version` at startup. Existing ids
+/// are left alone; both versions can share a table.
+pub fn set_uuid_version(version: UuidVersion) {
+    VERSION.store(version as u8, Ordering::Relaxed);
+}
+
+pub fn uuid_version() -> UuidVersion {
+    match VERSION.load(Ordering::Relaxed) {
+        1 => UuidVersion::V7,
+        _ => UuidVersion::V4,
+    }
+}
+
+/// A new row id of the current `uuid_version`.
+pub fn new_uuid() -> Uuid {
+    match uuid_version() {
+        UuidVersion::V4 => Uuid::new_v4(),
+        UuidVersion::V7 => Uuid::now_v7(),
+    }
+}
+
+/// When a time-based id, e.g. a UUIDv7, was generated. `None` for random ids.
+pub fn uuid_timestamp(id: Uuid) -> Option<DateTime<Utc>> {
+    let (secs, nanos) = id.get_timestamp()?.to_unix();
+    DateTime::from_timestamp(i64::try_from(secs).ok()?, nanos)
+}
+
+#[cfg(test)]
+mod tests {
+    use uuid::Uuid;
+
+    use super::uuid_timestamp;
+
+    #[test]
+    fn reads_v7_timestamps() {
+        let before = chrono::Utc::now() - chrono::Duration::milliseconds(1);
+        let at = uuid_timestamp(Uuid::now_v7()).unwrap();
+        assert!(at >= before && at <= chrono::Utc::now());
+        assert_eq!(uuid_timestamp(Uuid::new_v4()), None);
+    }
+}
@@ -0,0 +1,497 @@
+//! Restores an `export::AuthSnapshot` into an empty auth schema. Unrelated to `migrate::import`,
+//! which brings users in from other identity providers.
+//!
+//! `parse` checks an archive against the snapshot format and `restore` checks it against the
+//! database, then writes everything in one transaction, so a failed restore leaves nothing behind.
+//! Users come back without passwords or sessions; they sign in through their identity provider or
+//! a password reset.
+
+use std::collections::HashSet;
+use std::fmt;
+
+use serde_json::{Value, json};
+use sqlx::PgPool;
+use sqlx::types::Json;
+
+use crate::db::applied_migrations;
+use crate::environment::{is_valid_environment_name, split_scope, validate_scope};
+use crate::export::{AuthSnapshot, SNAPSHOT_FORMAT_VERSION};
+
+#[derive(Debug)]
+pub enum RestoreError {
+    /// The archive is not JSON, or does not match the snapshot format.
+    Malformed(serde_json::Error),
+    UnsupportedFormat(u32),
+    /// The snapshot was taken from a database with migrations this one has not run.
+    SchemaBehind {
+        snapshot: i64,
+        database: i64,
+    },
+    /// The snapshot refers to something it does not contain.
+    Invalid(String),
+    /// The database already has users, groups or bundles.
+    NotEmpty,
+    Database(sqlx::Error),
+}
+
+impl fmt::Display for RestoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Malformed(err) => write!(f, "Malformed snapshot: {}", err),
+            Self::UnsupportedFormat(version) => write!(
+                f,
+                "Unsupported snapshot format {} (expected {})",
+                version, SNAPSHOT_FORMAT_VERSION
+            ),
+            Self::SchemaBehind { snapshot, database } => write!(
+                f,
+                "Snapshot needs schema version {} but the database is at {}",
+                snapshot, database
+            ),
+            Self::Invalid(reason) => write!(f, "Invalid snapshot: {}", reason),
+            Self::NotEmpty => write!(f, "Refusing to restore into a database that has auth data"),
+            Self::Database(err) => write!(f, "Database error: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for RestoreError {}
+
+impl From<sqlx::Error> for RestoreError {
+    fn from(err: sqlx::Error) -> Self {
+        Self::Database(err)
+    }
+}
+
+/// Read an archive written from `export::dump`. The format version is checked first, so an
+/// archive from a newer release fails with `UnsupportedFormat` rather than a field error.
+pub fn parse(archive: &str) -> Result<AuthSnapshot, RestoreError> {
+    let value: Value = serde_json::from_str(archive).map_err(RestoreError::Malformed)?;
+    if let Some(version) = value.get("format_version").and_then(Value::as_u64)
+        && version != u64::from(SNAPSHOT_FORMAT_VERSION)
+    {
+        return Err(RestoreError::UnsupportedFormat(
+            u32::try_from(version).unwrap_or(u32::MAX),
+        ));
+    }
+    let snapshot: AuthSnapshot = serde_json::from_value(value).map_err(RestoreError::Malformed)?;
+    validate(&snapshot)?;
+    Ok(snapshot)
+}
+
+/// Check that every membership, grant and definition in the snapshot names a user and group it
+/// contains, and that grant scopes pass `environment::validate_scope`. Uniqueness and value
+/// constraints are left to the database.
+pub fn validate(snapshot: &AuthSnapshot) -> Result<(), RestoreError> {
+    if snapshot.format_version != SNAPSHOT_FORMAT_VERSION {
+        return Err(RestoreError::UnsupportedFormat(snapshot.format_version));
+    }
+    let mut users = HashSet::new();
+    for user in &snapshot.users {
+        if !users.insert(user.id) {
+            return Err(RestoreError::Invalid(format!(
+                "user {} appears twice",
+                user.id
+            )));
+        }
+    }
+    let mut groups = HashSet::new();
+    for group in &snapshot.groups {
+        if !groups.insert(group.id) {
+            return Err(RestoreError::Invalid(format!(
+                "group {} appears twice",
+                group.id
+            )));
+        }
+    }
+
+    let deactivators = snapshot
+        .users
+        .iter()
+        .filter_map(|user| user.deactivated_by)
+        .chain(
+            snapshot
+                .groups
+                .iter()
+                .filter_map(|group| group.deactivated_by),
+        );
+    for user_id in deactivators {
+        if !users.contains(&user_id) {
+            return Err(RestoreError::Invalid(format!(
+                "deactivated by unknown user {}",
+                user_id
+            )));
+        }
+    }
+    for user_id in snapshot.groups.iter().filter_map(|group| group.archived_by) {
+        if !users.contains(&user_id) {
+            return Err(RestoreError::Invalid(format!(
+                "archived by unknown user {}",
+                user_id
+            )));
+        }
+    }
+    let deleters = snapshot
+        .users
+        .iter()
+        .filter_map(|user| user.deleted_by)
+        .chain(snapshot.groups.iter().filter_map(|group| group.deleted_by));
+    for user_id in deleters {
+        if !users.contains(&user_id) {
+            return Err(RestoreError::Invalid(format!(
+                "deleted by unknown user {}",
+                user_id
+            )));
+        }
+    }
+    for membership in &snapshot.memberships {
+        if !users.contains(&membership.user_id) || !groups.contains(&membership.group_id) {
+            return Err(RestoreError::Invalid(format!(
+                "membership of user {} in group {} names an unknown principal",
+                membership.user_id, membership.group_id
+            )));
+        }
+    }
+    for grant in &snapshot.user_grants {
+        if !users.contains(&grant.user_id) {
+            return Err(RestoreError::Invalid(format!(
+                "grant to unknown user {}",
+                grant.user_id
+            )));
+        }
+        if let Some(group_id) = grant.source_group_id
+            && !groups.contains(&group_id)
+        {
+            return Err(RestoreError::Invalid(format!(
+                "grant from unknown group {}",
+                group_id
+            )));
+        }
+    }
+    let group_refs = snapshot
+        .group_grants
+        .iter()
+        .map(|grant| grant.group_id)
+        .chain(
+            snapshot
+                .group_role_definitions
+                .iter()
+                .map(|role| role.group_id),
+        )
+        .chain(
+            snapshot
+                .group_default_roles
+                .iter()
+                .map(|role| role.group_id),
+        );
+    for group_id in group_refs {
+        if !groups.contains(&group_id) {
+            return Err(RestoreError::Invalid(format!("unknown group {}", group_id)));
+        }
+    }
+
+    // User and group grants may be pinned to an environment; the rest are stored bare.
+    let stored_scopes = snapshot
+        .user_grants
+        .iter()
+        .map(|grant| grant.scope.as_str())
+        .chain(
+            snapshot
+                .group_grants
+                .iter()
+                .map(|grant| grant.scope.as_str()),
+        );
+    for stored in stored_scopes {
+        let (scope, environment) = split_scope(stored);
+        if validate_scope(scope).is_err()
+            || environment.is_some_and(|environment| !is_valid_environment_name(environment))
+        {
+            return Err(RestoreError::Invalid(format!(
+                "invalid grant scope {}",
+                stored
+            )));
+        }
+    }
+    let bare_scopes = snapshot
+        .group_default_roles
+        .iter()
+        .map(|role| role.scope.as_str())
+        .chain(
+            snapshot
+                .role_bundles
+                .iter()
+                .flat_map(|bundle| bundle.grants.iter().map(|grant| grant.scope.as_str())),
+        );
+    for scope in bare_scopes {
+        if validate_scope(scope).is_err() {
+            return Err(RestoreError::Invalid(format!(
+                "invalid grant scope {}",
+                scope
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Load `snapshot` into a database with no users, groups or bundles, e.g. one fresh from
+/// `create_user_tables`. The delegation policy is replaced with the snapshot's.
+///
+/// The database must have run every migration the source had. Ids, versions and timestamps are
+/// kept, so tokens and caches keyed by them stay valid across the copy.
+pub async fn restore(pool: &PgPool, snapshot: &AuthSnapshot) -> Result<(), RestoreError> {
+    validate(snapshot)?;
+    let database = applied_migrations(pool)
+        .await?
+        .iter()
+        .filter(|migration| migration.success)
+        .map(|migration| migration.version)
+        .max()
+        .unwrap_or(0);
+    if database < snapshot.schema_version {
+        return Err(RestoreError::SchemaBehind {
+            snapshot: snapshot.schema_version,
+            database,
+        });
+    }
+
+    let mut tx = pool.begin().await?;
+    let has_data: bool = sqlx::query_scalar(
+        r#"
+        SELECT EXISTS (SELECT 1 FROM auth.users)
+            OR EXISTS (SELECT 1 FROM auth.groups)
+            OR EXISTS (SELECT 1 FROM auth.role_bundles)
+        "#,
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+    if has_data {
+        return Err(RestoreError::NotEmpty);
+    }
+
+    // Users can be deactivated and deleted by each other, so `deactivated_by` and `deleted_by` are
+    // filled in once they all exist.
+    sqlx::query(
+        r#"
+        INSERT INTO auth.users (
+            id, username, email, details, email_canonical, version, locale, display_name, timezone,
+            external_id, active, pending_approval, created_at, last_login_at, deactivation_reason,
+            deactivation_note, deactivated_at, deleted_at
+        )
+        SELECT id, username, email, details, email_canonical, version, locale, display_name,
+               timezone, external_id, active, pending_approval, created_at, last_login_at,
+               deactivation_reason, deactivation_note, deactivated_at, deleted_at
+        FROM jsonb_populate_recordset(NULL::auth.users, $1)
+        "#,
+    )
+    .bind(Json(&snapshot.users))
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query(
+        r#"
+        UPDATE auth.users u
+        SET deactivated_by = r.deactivated_by,
+            deleted_by = r.deleted_by
+        FROM jsonb_populate_recordset(NULL::auth.users, $1) r
+        WHERE u.id = r.id AND (r.deactivated_by IS NOT NULL OR r.deleted_by IS NOT NULL)
+        "#,
+    )
+    .bind(Json(&snapshot.users))
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query(
+        r#"
+        INSERT INTO auth.groups (
+            id, display_name, details, version, visibility, external_id, active, created_at,
+            deactivation_reason, deactivation_note, deactivated_by, deactivated_at
+        )
+        SELECT id, display_name, details, version, visibility, external_id, active, created_at,
+               deactivation_reason, deactivation_note, deactivated_by, deactivated_at
+        FROM jsonb_populate_recordset(NULL::auth.groups, $1)
+        "#,
+    )
+    .bind(Json(&snapshot.groups))
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query(
+        r#"
+        INSERT INTO auth.group_memberships
+            (group_id, user_id, role_name, created_at, source_provider, capabilities)
+        SELECT group_id, user_id, role_name, created_at, source_provider, capabilities
+        FROM jsonb_populate_recordset(NULL::auth.group_memberships, $1)
+        "#,
+    )
+    .bind(Json(&snapshot.memberships))
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query(
+        r#"
+        INSERT INTO auth.user_roles (
+            user_id, scope, scope_id, role_name, effect, condition, created_at, source_provider,
+            source_group_id
+        )
+        SELECT user_id, scope, scope_id, role_name, effect, condition, created_at, source_provider,
+               source_group_id
+        FROM jsonb_populate_recordset(NULL::auth.user_roles, $1)
+        "#,
+    )
+    .bind(Json(&snapshot.user_grants))
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query(
+        r#"
+        INSERT INTO auth.group_roles (group_id, scope, scope_id, role_name, effect, condition, created_at)
+        SELECT group_id, scope, scope_id, role_name, effect, condition, created_at
+        FROM jsonb_populate_recordset(NULL::auth.group_roles, $1)
+        "#,
+    )
+    .bind(Json(&snapshot.group_grants))
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query(
+        r#"
+        INSERT INTO auth.group_role_definitions (group_id, role_name, capabilities, description, created_at)
+        SELECT group_id, role_name, capabilities, description, created_at
+        FROM jsonb_populate_recordset(NULL::auth.group_role_definitions, $1)
+        "#,
+    )
+    .bind(Json(&snapshot.group_role_definitions))
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query(
+        r#"
+        INSERT INTO auth.group_default_roles (group_id, scope, scope_id, role_name, created_at)
+        SELECT group_id, scope, scope_id, role_name, created_at
+        FROM jsonb_populate_recordset(NULL::auth.group_default_roles, $1)
+        "#,
+    )
+    .bind(Json(&snapshot.group_default_roles))
+    .execute(&mut *tx)
+    .await?;
+    // Migrations seed the delegation policy, so the snapshot's replaces it rather than adding to it.
+    sqlx::query("DELETE FROM auth.role_delegation_policy")
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query(
+        r#"
+        INSERT INTO auth.role_delegation_policy (scope, scope_id, admin_role, grantable_role, created_at)
+        SELECT scope, scope_id, admin_role, grantable_role, created_at
+        FROM jsonb_populate_recordset(NULL::auth.role_delegation_policy, $1)
+        "#,
+    )
+    .bind(Json(&snapshot.delegation_policies))
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query(
+        r#"
+        INSERT INTO auth.role_bundles (name, description, created_at, updated_at)
+        SELECT name, description, created_at, updated_at
+        FROM jsonb_populate_recordset(NULL::auth.role_bundles, $1)
+        "#,
+    )
+    .bind(Json(&snapshot.role_bundles))
+    .execute(&mut *tx)
+    .await?;
+    let bundle_grants: Vec<Value> = snapshot
+        .role_bundles
+        .iter()
+        .flat_map(|bundle| {
+            bundle.grants.iter().map(|grant| {
+                json!({
+                    "bundle_name": bundle.name,
+                    "scope": grant.scope,
+                    "role_name": grant.role_name,
+                })
+            })
+        })
+        .collect();
+    sqlx::query(
+        r#"
+        INSERT INTO auth.role_bundle_grants (bundle_name, scope, role_name)
+        SELECT bundle_name, scope, role_name
+        FROM jsonb_populate_recordset(NULL::auth.role_bundle_grants, $1)
+        "#,
+    )
+    .bind(Json(bundle_grants))
+    .execute(&mut *tx)
+    .await?;
+    // Archived and deleted groups reject changes to their rows, so they are archived and deleted
+    // once those are in.
+    sqlx::query(
+        r#"
+        UPDATE auth.groups g
+        SET archived_at = r.archived_at,
+            archived_by = r.archived_by,
+            deleted_at = r.deleted_at,
+            deleted_by = r.deleted_by
+        FROM jsonb_populate_recordset(NULL::auth.groups, $1) r
+        WHERE g.id = r.id AND (r.archived_at IS NOT NULL OR r.deleted_at IS NOT NULL)
+        "#,
+    )
+    .bind(Json(&snapshot.groups))
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
+    // Ids are kept, so sessions from before the restore may hold snapshots of the same users.
+    #[cfg(feature = "api")]
+    crate::guard::invalidate_all_role_snapshots();
+
+    tracing::info!(
+        "Restored {} users and {} groups from a snapshot taken at {}",
+        snapshot.users.len(),
+        snapshot.groups.len(),
+        snapshot.exported_at
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::{RestoreError, parse};
+
+    #[test]
+    fn parse_checks_format_and_references() {
+        let user_id = uuid::Uuid::new_v4();
+        let group_id = uuid::Uuid::new_v4();
+        let mut archive = json!({
+            "format_version": 1,
+            "schema_version": 20260410000000_i64,
+            "exported_at": "2026-04-10T00:00:00",
+            "users": [],
+            "groups": [],
+            "memberships": [{
+                "group_id": group_id,
+                "user_id": user_id,
+                "role_name": "member",
+                "created_at": null,
+                "source_provider": null,
+            }],
+            "user_grants": [],
+            "group_grants": [],
+            "group_role_definitions": [],
+            "group_default_roles": [],
+            "delegation_policies": [],
+            "role_bundles": [],
+        });
+        assert!(matches!(
+            parse(&archive.to_string()),
+            Err(RestoreError::Invalid(_))
+        ));
+
+        archive["memberships"] = json!([]);
+        assert!(parse(&archive.to_string()).is_ok());
+
+        archive["password_hashes"] = json!([]);
+        assert!(matches!(
+            parse(&archive.to_string()),
+            Err(RestoreError::Malformed(_))
+        ));
+
+        archive["format_version"] = json!(2);
+        assert!(matches!(
+            parse(&archive.to_string()),
+            Err(RestoreError::UnsupportedFormat(2))
+        ));
+    }
+}
@@ -0,0 +1,311 @@
+use serde::Serialize;
+use serde_json::{Value, json};
+use sqlx::PgPool;
+
+use crate::db::{GROUP_ADMIN_ROLE, GROUP_MEMBER_ROLE, insert_audit_log};
+use crate::user_id::UserId;
+
+/// Number of offending rows returned with each finding.
+const EXAMPLE_LIMIT: i64 = 10;
+
+/// A kind of inconsistency `check` looks for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IntegrityIssue {
+    /// Role grants for users that no longer exist.
+    DanglingUserRoles,
+    /// Role grants for groups that no longer exist.
+    DanglingGroupRoles,
+    /// Memberships whose user or group no longer exists.
+    DanglingMemberships,
+    /// Memberships in deactivated groups. Soft-deleted groups keep theirs for a restore.
+    InactiveGroupMemberships,
+    /// Role grants held by deactivated groups, other than soft-deleted ones.
+    InactiveGroupRoles,
+    /// Pending join requests to deactivated groups or from deactivated users.
+    StaleJoinRequests,
+    /// Members holding a group role that is neither built in nor defined for their group.
+    UndefinedMemberRoles,
+    /// Grants from a group's default roles whose holder left the group or whose default was
+    /// removed.
+    StaleDefaultRoleGrants,
+}
+
+impl IntegrityIssue {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::DanglingUserRoles => "dangling_user_roles",
+            Self::DanglingGroupRoles => "dangling_group_roles",
+            Self::DanglingMemberships => "dangling_memberships",
+            Self::InactiveGroupMemberships => "inactive_group_memberships",
+            Self::InactiveGroupRoles => "inactive_group_roles",
+            Self::StaleJoinRequests => "stale_join_requests",
+            Self::UndefinedMemberRoles => "undefined_member_roles",
+            Self::StaleDefaultRoleGrants => "stale_default_role_grants",
+        }
+    }
+}
+
+struct Check {
+    issue: IntegrityIssue,
+    /// Selects the offending rows, one JSONB document per row.
+    find: &'static str,
+    /// Removes or fixes every offending row.
+    repair: &'static str,
+}
+
+// `$1` and `$2` are the built-in group admin and member role names.
+const CHECKS: &[Check] = &[
+    Check {
+        issue: IntegrityIssue::DanglingUserRoles,
+        find: r#"
+            SELECT to_jsonb(r) FROM auth.user_roles r
+            WHERE NOT EXISTS (SELECT 1 FROM auth.users u WHERE u.id = r.user_id)
+        "#,
+        repair: r#"
+            DELETE FROM auth.user_roles r
+            WHERE NOT EXISTS (SELECT 1 FROM auth.users u WHERE u.id = r.user_id)
+        "#,
+    },
+    Check {
+        issue: IntegrityIssue::DanglingGroupRoles,
+        find: r#"
+            SELECT to_jsonb(r) FROM auth.group_roles r
+            WHERE NOT EXISTS (SELECT 1 FROM auth.groups g WHERE g.id = r.group_id)
+        "#,
+        repair: r#"
+            DELETE FROM auth.group_roles r
+            WHERE NOT EXISTS (SELECT 1 FROM auth.groups g WHERE g.id = r.group_id)
+        "#,
+    },
+    Check {
+        issue: IntegrityIssue::DanglingMemberships,
+        find: r#"
+            SELECT to_jsonb(m) FROM auth.group_memberships m
+            WHERE NOT EXISTS (SELECT 1 FROM auth.users u WHERE u.id = m.user_id)
+               OR NOT EXISTS (SELECT 1 FROM auth.groups g WHERE g.id = m.group_id)
+        "#,
+        repair: r#"
+            DELETE FROM auth.group_memberships m
+            WHERE NOT EXISTS (SELECT 1 FROM auth.users u WHERE u.id = m.user_id)
+               OR NOT EXISTS (SELECT 1 FROM auth.groups g WHERE g.id = m.group_id)
+        "#,
+    },
+    Check {
+        issue: IntegrityIssue::InactiveGroupMemberships,
+        find: r#"
+            SELECT to_jsonb(m) FROM auth.group_memberships m
+            JOIN auth.groups g ON g.id = m.group_id
+            WHERE g.active = FALSE AND g.deleted_at IS NULL
+        "#,
+        repair: r#"
+            DELETE FROM auth.group_memberships m
+            USING auth.groups g
+            WHERE g.id = m.group_id AND g.active = FALSE AND g.deleted_at IS NULL
+        "#,
+    },
+    Check {
+        issue: IntegrityIssue::InactiveGroupRoles,
+        find: r#"
+            SELECT to_jsonb(r) FROM auth.group_roles r
+            JOIN auth.groups g ON g.id = r.group_id
+            WHERE g.active = FALSE AND g.deleted_at IS NULL
+        "#,
+        repair: r#"
+            DELETE FROM auth.group_roles r
+            USING auth.groups g
+            WHERE g.id = r.group_id AND g.active = FALSE AND g.deleted_at IS NULL
+        "#,
+    },
+    Check {
+        issue: IntegrityIssue::StaleJoinRequests,
+        find: r#"
+            SELECT to_jsonb(j) FROM auth.group_join_requests j
+            JOIN auth.groups g ON g.id = j.group_id
+            JOIN auth.users u ON u.id = j.user_id
+            WHERE j.status = 'pending' AND (g.active = FALSE OR u.active = FALSE)
+        "#,
+        repair: r#"
+            DELETE FROM auth.group_join_requests j
+            USING auth.groups g, auth.users u
+            WHERE g.id = j.group_id AND u.id = j.user_id
+              AND j.status = 'pending' AND (g.active = FALSE OR u.active = FALSE)
+        "#,
+    },
+    Check {
+        issue: IntegrityIssue::UndefinedMemberRoles,
+        find: r#"
+            SELECT to_jsonb(m) FROM auth.group_memberships m
+            WHERE m.role_name NOT IN ($1, $2)
+              AND NOT EXISTS (
+                  SELECT 1 FROM auth.group_role_definitions d
+                  WHERE d.group_id = m.group_id AND d.role_name = m.role_name
+              )
+        "#,
+        repair: r#"
+            UPDATE auth.group_memberships m
+            SET role_name = $2
+            WHERE m.role_name NOT IN ($1, $2)
+              AND NOT EXISTS (
+                  SELECT 1 FROM auth.group_role_definitions d
+                  WHERE d.group_id = m.group_id AND d.role_name = m.role_name
+              )
+        "#,
+    },
+    // Runs after the membership checks so grants of members removed above are caught too.
+    Check {
+        issue: IntegrityIssue::StaleDefaultRoleGrants,
+        find: r#"
+            SELECT to_jsonb(r) FROM auth.user_roles r
+            WHERE r.source_group_id IS NOT NULL
+              AND (
+                  NOT EXISTS (
+                      SELECT 1 FROM auth.group_memberships m
+                      WHERE m.group_id = r.source_group_id AND m.user_id = r.user_id
+                  )
+                  OR NOT EXISTS (
+                      SELECT 1 FROM auth.group_default_roles d
+                      WHERE d.group_id = r.source_group_id
+                        AND d.scope = r.scope
+                        AND d.scope_id = r.scope_id
+                        AND d.role_name = r.role_name
+                  )
+              )
+        "#,
+        repair: r#"
+            DELETE FROM auth.user_roles r
+            WHERE r.source_group_id IS NOT NULL
+              AND (
+                  NOT EXISTS (
+                      SELECT 1 FROM auth.group_memberships m
+                      WHERE m.group_id = r.source_group_id AND m.user_id = r.user_id
+                  )
+                  OR NOT EXISTS (
+                      SELECT 1 FROM auth.group_default_roles d
+                      WHERE d.group_id = r.source_group_id
+                        AND d.scope = r.scope
+                        AND d.scope_id = r.scope_id
+                        AND d.role_name = r.role_name
+                  )
+              )
+        "#,
+    },
+];
+
+#[derive(Debug, Clone, Serialize)]
+pub struct IntegrityFinding {
+    pub issue: IntegrityIssue,
+    /// Offending rows found, or rows fixed by `repair`.
+    pub count: u64,
+    /// Up to ten of the offending rows as found by `check`; empty for `repair`.
+    pub examples: Vec<Value>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct IntegrityReport {
+    pub repaired: bool,
+    /// One finding per issue with a nonzero count.
+    pub findings: Vec<IntegrityFinding>,
+}
+
+impl IntegrityReport {
+    pub fn is_clean(&self) -> bool {
+        self.findings.is_empty()
+    }
+}
+
+/// Look for orphaned or inconsistent rows in the auth schema without changing anything.
+pub async fn check(pool: &PgPool) -> Result<IntegrityReport, sqlx::Error> {
+    let mut findings = Vec::new();
+    for check in CHECKS {
+        let count: i64 =
+            sqlx::query_scalar(&format!("SELECT COUNT(*) FROM ({}) AS found", check.find))
+                .bind(GROUP_ADMIN_ROLE)
+                .bind(GROUP_MEMBER_ROLE)
+                .fetch_one(pool)
+                .await?;
+        if count == 0 {
+            continue;
+        }
+        let examples: Vec<Value> = sqlx::query_scalar(&format!("{} LIMIT $3", check.find))
+            .bind(GROUP_ADMIN_ROLE)
+            .bind(GROUP_MEMBER_ROLE)
+            .bind(EXAMPLE_LIMIT)
+            .fetch_all(pool)
+            .await?;
+        findings.push(IntegrityFinding {
+            issue: check.issue,
+            count: count as u64,
+            examples,
+        });
+    }
+
+    Ok(IntegrityReport {
+        repaired: false,
+        findings,
+    })
+}
+
+/// Fix everything `check` reports in one transaction and log an `integrity_repaired` entry.
+///
+/// Orphaned and stale rows are deleted; undefined member roles are reset to `member`.
+/// Callers serving requests should invalidate role snapshots afterwards.
+pub async fn repair(
+    pool: &PgPool,
+    actor_user_id: Option<UserId>,
+) -> Result<IntegrityReport, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    let mut findings = Vec::new();
+    for check in CHECKS {
+        let count = sqlx::query(check.repair)
+            .bind(GROUP_ADMIN_ROLE)
+            .bind(GROUP_MEMBER_ROLE)
+            .execute(&mut *tx)
+            .await?
+            .rows_affected();
+        if count > 0 {
+            findings.push(IntegrityFinding {
+                issue: check.issue,
+                count,
+                examples: Vec::new(),
+            });
+        }
+    }
+
+    if !findings.is_empty() {
+        let repaired: serde_json::Map<String, Value> = findings
+            .iter()
+            .map(|finding| (finding.issue.as_str().to_string(), json!(finding.count)))
+            .collect();
+        insert_audit_log(
+            &mut *tx,
+            actor_user_id,
+            json!({
+                "type": "integrity_repaired",
+                "repaired": repaired,
+            }),
+        )
+        .await?;
+    }
+    tx.commit().await?;
+
+    Ok(IntegrityReport {
+        repaired: true,
+        findings,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CHECKS;
+
+    #[test]
+    fn issue_names_match_serialization() {
+        for check in CHECKS {
+            assert_eq!(
+                serde_json::to_value(check.issue).unwrap(),
+                check.issue.as_str()
+            );
+        }
+    }
+}
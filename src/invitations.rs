@@ -0,0 +1,396 @@
+use std::collections::HashSet;
+use std::fmt;
+
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use chrono::NaiveDateTime;
+use openidconnect::CsrfToken;
+use serde::Serialize;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use sqlx::{FromRow, PgConnection, PgPool};
+use uuid::Uuid;
+
+use crate::db::insert_audit_log;
+use crate::email::{EmailNormalizer, email_domain};
+use crate::redact::Sensitive;
+use crate::user_id::UserId;
+
+/// How long an invitation can be accepted when the admin does not say otherwise.
+pub const DEFAULT_INVITATION_TTL: chrono::Duration = chrono::Duration::days(7);
+
+/// Random bytes in an invitation token, before base64 encoding.
+const TOKEN_BYTES: u32 = 32;
+
+macro_rules! invitation_columns {
+    () => {
+        "id, email, invited_by, created_at, expires_at, accepted_at, accepted_by, revoked_at, \
+         bootstrap_admin"
+    };
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum InvitationStatus {
+    Pending,
+    Accepted,
+    Revoked,
+    Expired,
+}
+
+#[derive(Clone, FromRow, Serialize)]
+pub struct InvitationRow {
+    pub id: Uuid,
+    pub email: String,
+    pub invited_by: Option<Uuid>,
+    pub created_at: NaiveDateTime,
+    pub expires_at: NaiveDateTime,
+    pub accepted_at: Option<NaiveDateTime>,
+    pub accepted_by: Option<Uuid>,
+    pub revoked_at: Option<NaiveDateTime>,
+    /// Issued by `bootstrap::bootstrap`; accepting it grants `super_admin`.
+    pub bootstrap_admin: bool,
+}
+
+impl fmt::Debug for InvitationRow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("InvitationRow")
+            .field("id", &self.id)
+            .field("email", &Sensitive(&self.email))
+            .field("invited_by", &self.invited_by)
+            .field("created_at", &self.created_at)
+            .field("expires_at", &self.expires_at)
+            .field("accepted_at", &self.accepted_at)
+            .field("accepted_by", &self.accepted_by)
+            .field("revoked_at", &self.revoked_at)
+            .field("bootstrap_admin", &self.bootstrap_admin)
+            .finish()
+    }
+}
+
+impl InvitationRow {
+    pub fn status(&self, now: NaiveDateTime) -> InvitationStatus {
+        if self.accepted_at.is_some() {
+            InvitationStatus::Accepted
+        } else if self.revoked_at.is_some() {
+            InvitationStatus::Revoked
+        } else if self.expires_at <= now {
+            InvitationStatus::Expired
+        } else {
+            InvitationStatus::Pending
+        }
+    }
+
+    /// Every invitation, newest first.
+    pub async fn list(pool: &PgPool, page: Option<(i64, i64)>) -> Result<Vec<Self>, sqlx::Error> {
+        let (limit, offset) = match page {
+            Some((limit, offset)) => (Some(limit), offset),
+            None => (None, 0),
+        };
+        sqlx::query_as::<_, InvitationRow>(concat!(
+            "SELECT ",
+            invitation_columns!(),
+            r#"
+            FROM auth.user_invitations
+            ORDER BY created_at DESC
+            LIMIT $1 OFFSET $2
+            "#,
+        ))
+        .bind(limit)
+        .bind(offset)
+        .fetch_all(pool)
+        .await
+    }
+
+    /// The invitation `token` was issued for, if it can still be accepted.
+    pub async fn get_acceptable(pool: &PgPool, token: &str) -> Result<Option<Self>, sqlx::Error> {
+        sqlx::query_as::<_, InvitationRow>(concat!(
+            "SELECT ",
+            invitation_columns!(),
+            r#"
+            FROM auth.user_invitations
+            WHERE token_hash = $1
+              AND accepted_at IS NULL
+              AND revoked_at IS NULL
+              AND expires_at > $2
+            "#,
+        ))
+        .bind(token_hash(token))
+        .bind(chrono::Utc::now().naive_utc())
+        .fetch_optional(pool)
+        .await
+    }
+
+    /// Record that `user_id` accepted the invitation. Returns `false` if it was accepted or
+    /// revoked in the meantime.
+    pub async fn mark_accepted(
+        pool: &PgPool,
+        invitation_id: Uuid,
+        user_id: UserId,
+    ) -> Result<bool, sqlx::Error> {
+        let mut tx = pool.begin().await?;
+        let accepted = Self::mark_accepted_in(&mut tx, invitation_id, user_id).await?;
+        tx.commit().await?;
+        Ok(accepted)
+    }
+
+    /// `mark_accepted` on `conn`, so the acceptance commits or rolls back with the account it
+    /// creates.
+    pub(crate) async fn mark_accepted_in(
+        conn: &mut PgConnection,
+        invitation_id: Uuid,
+        user_id: UserId,
+    ) -> Result<bool, sqlx::Error> {
+        let accepted = sqlx::query(
+            r#"
+            UPDATE auth.user_invitations
+            SET accepted_at = $2,
+                accepted_by = $3
+            WHERE id = $1
+              AND accepted_at IS NULL
+              AND revoked_at IS NULL
+            "#,
+        )
+        .bind(invitation_id)
+        .bind(chrono::Utc::now().naive_utc())
+        .bind(user_id)
+        .execute(&mut *conn)
+        .await?
+        .rows_affected()
+            > 0;
+        if accepted {
+            insert_audit_log(
+                &mut *conn,
+                Some(user_id),
+                json!({
+                    "type": "invitation_accepted",
+                    "invitation_id": invitation_id,
+                }),
+            )
+            .await?;
+        }
+        Ok(accepted)
+    }
+
+    /// Revoke an invitation that has not been accepted. Returns `false` if there was none.
+    pub async fn revoke(
+        pool: &PgPool,
+        actor_user_id: UserId,
+        invitation_id: Uuid,
+    ) -> Result<bool, sqlx::Error> {
+        let mut tx = pool.begin().await?;
+        let revoked = sqlx::query(
+            r#"
+            UPDATE auth.user_invitations
+            SET revoked_at = $2
+            WHERE id = $1
+              AND accepted_at IS NULL
+              AND revoked_at IS NULL
+            "#,
+        )
+        .bind(invitation_id)
+        .bind(chrono::Utc::now().naive_utc())
+        .execute(&mut *tx)
+        .await?
+        .rows_affected()
+            > 0;
+        if revoked {
+            insert_audit_log(
+                &mut *tx,
+                Some(actor_user_id),
+                json!({
+                    "type": "invitation_revoked",
+                    "invitation_id": invitation_id,
+                }),
+            )
+            .await?;
+        }
+        tx.commit().await?;
+        Ok(revoked)
+    }
+}
+
+/// A new invitation with the token to deliver. The token is not stored and cannot be recovered.
+#[derive(Clone, Serialize)]
+pub struct IssuedInvitation {
+    pub invitation: InvitationRow,
+    pub token: String,
+}
+
+impl fmt::Debug for IssuedInvitation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("IssuedInvitation")
+            .field("invitation", &self.invitation)
+            .field("token", &"[redacted]")
+            .finish()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SkipReason {
+    InvalidEmail,
+    /// The address, after normalization, already belongs to a user.
+    AlreadyRegistered,
+    /// The address appeared earlier in the same batch.
+    Duplicate,
+}
+
+#[derive(Clone, Serialize)]
+pub struct SkippedInvitation {
+    pub email: String,
+    pub reason: SkipReason,
+}
+
+impl fmt::Debug for SkippedInvitation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SkippedInvitation")
+            .field("email", &Sensitive(&self.email))
+            .field("reason", &self.reason)
+            .finish()
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct InvitationBatch {
+    pub issued: Vec<IssuedInvitation>,
+    pub skipped: Vec<SkippedInvitation>,
+}
+
+/// Invite every address in `emails`, valid for `ttl`. Inviting an address that already has an
+/// outstanding invitation revokes the old one, so only the newest token works.
+pub async fn create_invitations(
+    pool: &PgPool,
+    actor_user_id: UserId,
+    emails: &[String],
+    ttl: chrono::Duration,
+    normalizer: &EmailNormalizer,
+) -> Result<InvitationBatch, sqlx::Error> {
+    let now = chrono::Utc::now().naive_utc();
+    let expires_at = now.checked_add_signed(ttl).unwrap_or(NaiveDateTime::MAX);
+    let mut batch = InvitationBatch::default();
+    let mut seen = HashSet::new();
+
+    let mut tx = pool.begin().await?;
+    for email in emails {
+        let email = email.trim();
+        let email_canonical = normalizer.canonical(email);
+        let skip = if email_domain(email).is_none() {
+            Some(SkipReason::InvalidEmail)
+        } else if !seen.insert(email_canonical.clone()) {
+            Some(SkipReason::Duplicate)
+        } else {
+            None
+        };
+        if let Some(reason) = skip {
+            batch.skipped.push(SkippedInvitation {
+                email: email.to_string(),
+                reason,
+            });
+            continue;
+        }
+
+        let registered: Option<(Uuid,)> = sqlx::query_as(
+            r#"
+            SELECT id
+            FROM auth.users
+            WHERE email_canonical = $1
+               OR email = $2
+            LIMIT 1
+            "#,
+        )
+        .bind(&email_canonical)
+        .bind(email)
+        .fetch_optional(&mut *tx)
+        .await?;
+        if registered.is_some() {
+            batch.skipped.push(SkippedInvitation {
+                email: email.to_string(),
+                reason: SkipReason::AlreadyRegistered,
+            });
+            continue;
+        }
+
+        sqlx::query(
+            r#"
+            UPDATE auth.user_invitations
+            SET revoked_at = $2
+            WHERE email_canonical = $1
+              AND accepted_at IS NULL
+              AND revoked_at IS NULL
+            "#,
+        )
+        .bind(&email_canonical)
+        .bind(now)
+        .execute(&mut *tx)
+        .await?;
+
+        batch.issued.push(
+            issue_invitation(
+                &mut tx,
+                email,
+                &email_canonical,
+                Some(actor_user_id),
+                expires_at,
+                false,
+            )
+            .await?,
+        );
+    }
+
+    if !batch.issued.is_empty() {
+        insert_audit_log(
+            &mut *tx,
+            Some(actor_user_id),
+            json!({
+                "type": "invitations_created",
+                "invitation_ids": batch
+                    .issued
+                    .iter()
+                    .map(|issued| issued.invitation.id)
+                    .collect::<Vec<_>>(),
+                "expires_at": expires_at,
+            }),
+        )
+        .await?;
+    }
+    tx.commit().await?;
+    Ok(batch)
+}
+
+/// Insert an invitation with a fresh token. The caller revokes any outstanding invitation for the
+/// address first.
+pub(crate) async fn issue_invitation(
+    conn: &mut PgConnection,
+    email: &str,
+    email_canonical: &str,
+    invited_by: Option<UserId>,
+    expires_at: NaiveDateTime,
+    bootstrap_admin: bool,
+) -> Result<IssuedInvitation, sqlx::Error> {
+    let token = CsrfToken::new_random_len(TOKEN_BYTES).secret().clone();
+    let invitation = sqlx::query_as::<_, InvitationRow>(concat!(
+        r#"
+        INSERT INTO auth.user_invitations
+            (id, email, email_canonical, token_hash, invited_by, created_at, expires_at,
+             bootstrap_admin)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+        RETURNING "#,
+        invitation_columns!(),
+    ))
+    .bind(Uuid::new_v4())
+    .bind(email)
+    .bind(email_canonical)
+    .bind(token_hash(&token))
+    .bind(invited_by)
+    .bind(chrono::Utc::now().naive_utc())
+    .bind(expires_at)
+    .bind(bootstrap_admin)
+    .fetch_one(&mut *conn)
+    .await?;
+    Ok(IssuedInvitation { invitation, token })
+}
+
+fn token_hash(token: &str) -> String {
+    URL_SAFE_NO_PAD.encode(Sha256::digest(token.as_bytes()))
+}
@@ -0,0 +1,272 @@
+use std::fmt;
+
+use serde::Deserialize;
+use serde_json::{Map, Value};
+
+/// One RFC 6902 operation.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+pub enum PatchOperation {
+    Add { path: String, value: Value },
+    Remove { path: String },
+    Replace { path: String, value: Value },
+    Move { from: String, path: String },
+    Copy { from: String, path: String },
+    Test { path: String, value: Value },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum JsonPatchError {
+    InvalidPatch(String),
+    InvalidPointer(String),
+    PathNotFound(String),
+    TestFailed(String),
+}
+
+impl fmt::Display for JsonPatchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidPatch(msg) => write!(f, "Invalid patch document: {}", msg),
+            Self::InvalidPointer(path) => write!(f, "Invalid JSON pointer {:?}", path),
+            Self::PathNotFound(path) => write!(f, "Path {:?} does not exist", path),
+            Self::TestFailed(path) => write!(f, "Test operation failed at {:?}", path),
+        }
+    }
+}
+
+impl std::error::Error for JsonPatchError {}
+
+/// Parse an RFC 6902 patch document (a JSON array of operations).
+pub fn parse_json_patch(patch: Value) -> Result<Vec<PatchOperation>, JsonPatchError> {
+    serde_json::from_value(patch).map_err(|err| JsonPatchError::InvalidPatch(err.to_string()))
+}
+
+/// Apply RFC 6902 operations in order. On error `target` is left unchanged.
+pub fn apply_json_patch(
+    target: &mut Value,
+    patch: &[PatchOperation],
+) -> Result<(), JsonPatchError> {
+    let mut patched = target.clone();
+    for operation in patch {
+        apply_operation(&mut patched, operation)?;
+    }
+    *target = patched;
+    Ok(())
+}
+
+/// Apply an RFC 7386 merge patch: objects merge recursively, `null` removes a key, anything else
+/// replaces.
+pub fn apply_merge_patch(target: &mut Value, patch: &Value) {
+    let Value::Object(patch) = patch else {
+        *target = patch.clone();
+        return;
+    };
+    if !target.is_object() {
+        *target = Value::Object(Map::new());
+    }
+    let Value::Object(target) = target else {
+        unreachable!("target was just made an object");
+    };
+    for (key, value) in patch {
+        if value.is_null() {
+            target.remove(key);
+        } else {
+            apply_merge_patch(target.entry(key.clone()).or_insert(Value::Null), value);
+        }
+    }
+}
+
+fn parse_pointer(path: &str) -> Result<Vec<String>, JsonPatchError> {
+    if path.is_empty() {
+        return Ok(Vec::new());
+    }
+    let Some(rest) = path.strip_prefix('/') else {
+        return Err(JsonPatchError::InvalidPointer(path.to_string()));
+    };
+    Ok(rest
+        .split('/')
+        .map(|token| token.replace("~1", "/").replace("~0", "~"))
+        .collect())
+}
+
+fn array_index(
+    token: &str,
+    len: usize,
+    allow_end: bool,
+    path: &str,
+) -> Result<usize, JsonPatchError> {
+    if allow_end && token == "-" {
+        return Ok(len);
+    }
+    if token.len() > 1 && token.starts_with('0') {
+        return Err(JsonPatchError::InvalidPointer(path.to_string()));
+    }
+    let index: usize = token
+        .parse()
+        .map_err(|_| JsonPatchError::InvalidPointer(path.to_string()))?;
+    let limit = if allow_end {
+        len
+    } else {
+        len.saturating_sub(1)
+    };
+    if index > limit || (!allow_end && len == 0) {
+        return Err(JsonPatchError::PathNotFound(path.to_string()));
+    }
+    Ok(index)
+}
+
+fn get<'a>(target: &'a Value, path: &str) -> Result<&'a Value, JsonPatchError> {
+    parse_pointer(path)?;
+    target
+        .pointer(path)
+        .ok_or_else(|| JsonPatchError::PathNotFound(path.to_string()))
+}
+
+fn parent_mut<'a>(
+    target: &'a mut Value,
+    tokens: &[String],
+    path: &str,
+) -> Result<&'a mut Value, JsonPatchError> {
+    let mut current = target;
+    for token in tokens {
+        current = match current {
+            Value::Object(map) => map.get_mut(token),
+            Value::Array(items) => {
+                let index = array_index(token, items.len(), false, path)?;
+                items.get_mut(index)
+            }
+            _ => None,
+        }
+        .ok_or_else(|| JsonPatchError::PathNotFound(path.to_string()))?;
+    }
+    Ok(current)
+}
+
+fn add(target: &mut Value, path: &str, value: Value) -> Result<(), JsonPatchError> {
+    let tokens = parse_pointer(path)?;
+    let Some((last, parents)) = tokens.split_last() else {
+        *target = value;
+        return Ok(());
+    };
+    match parent_mut(target, parents, path)? {
+        Value::Object(map) => {
+            map.insert(last.clone(), value);
+        }
+        Value::Array(items) => {
+            let index = array_index(last, items.len(), true, path)?;
+            items.insert(index, value);
+        }
+        _ => return Err(JsonPatchError::PathNotFound(path.to_string())),
+    }
+    Ok(())
+}
+
+fn remove(target: &mut Value, path: &str) -> Result<Value, JsonPatchError> {
+    let tokens = parse_pointer(path)?;
+    let Some((last, parents)) = tokens.split_last() else {
+        return Ok(std::mem::take(target));
+    };
+    match parent_mut(target, parents, path)? {
+        Value::Object(map) => map
+            .remove(last)
+            .ok_or_else(|| JsonPatchError::PathNotFound(path.to_string())),
+        Value::Array(items) => {
+            let index = array_index(last, items.len(), false, path)?;
+            Ok(items.remove(index))
+        }
+        _ => Err(JsonPatchError::PathNotFound(path.to_string())),
+    }
+}
+
+fn apply_operation(target: &mut Value, operation: &PatchOperation) -> Result<(), JsonPatchError> {
+    match operation {
+        PatchOperation::Add { path, value } => add(target, path, value.clone()),
+        PatchOperation::Remove { path } => remove(target, path).map(|_| ()),
+        PatchOperation::Replace { path, value } => {
+            remove(target, path)?;
+            add(target, path, value.clone())
+        }
+        PatchOperation::Move { from, path } => {
+            if path.starts_with(&format!("{}/", from)) {
+                return Err(JsonPatchError::InvalidPatch(format!(
+                    "cannot move {:?} into its own child {:?}",
+                    from, path
+                )));
+            }
+            let value = remove(target, from)?;
+            add(target, path, value)
+        }
+        PatchOperation::Copy { from, path } => {
+            let value = get(target, from)?.clone();
+            add(target, path, value)
+        }
+        PatchOperation::Test { path, value } => {
+            if get(target, path)? == value {
+                Ok(())
+            } else {
+                Err(JsonPatchError::TestFailed(path.clone()))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::{JsonPatchError, apply_json_patch, apply_merge_patch, parse_json_patch};
+
+    #[test]
+    fn merge_patch_follows_rfc_7386() {
+        let mut target = json!({"a": "b", "c": {"d": "e", "f": "g"}});
+        apply_merge_patch(&mut target, &json!({"a": "z", "c": {"f": null}}));
+        assert_eq!(target, json!({"a": "z", "c": {"d": "e"}}));
+
+        let mut target = json!(["a"]);
+        apply_merge_patch(&mut target, &json!({"b": {"c": 1}}));
+        assert_eq!(target, json!({"b": {"c": 1}}));
+    }
+
+    #[test]
+    fn json_patch_applies_all_operations() {
+        let mut target = json!({"foo": ["bar", "baz"], "a/b": 1, "q": {"x": 1}});
+        let patch = parse_json_patch(json!([
+            {"op": "test", "path": "/a~1b", "value": 1},
+            {"op": "add", "path": "/foo/1", "value": "qux"},
+            {"op": "add", "path": "/foo/-", "value": "end"},
+            {"op": "remove", "path": "/foo/0"},
+            {"op": "replace", "path": "/a~1b", "value": 2},
+            {"op": "copy", "from": "/q", "path": "/r"},
+            {"op": "move", "from": "/q/x", "path": "/y"},
+        ]))
+        .unwrap();
+        apply_json_patch(&mut target, &patch).unwrap();
+        assert_eq!(
+            target,
+            json!({"foo": ["qux", "baz", "end"], "a/b": 2, "q": {}, "r": {"x": 1}, "y": 1})
+        );
+    }
+
+    #[test]
+    fn json_patch_is_atomic() {
+        let original = json!({"a": 1});
+        let mut target = original.clone();
+        let patch = parse_json_patch(json!([
+            {"op": "replace", "path": "/a", "value": 2},
+            {"op": "test", "path": "/a", "value": 1},
+        ]))
+        .unwrap();
+        assert_eq!(
+            apply_json_patch(&mut target, &patch),
+            Err(JsonPatchError::TestFailed("/a".to_string()))
+        );
+        assert_eq!(target, original);
+
+        let patch = parse_json_patch(json!([{"op": "remove", "path": "/missing"}])).unwrap();
+        assert_eq!(
+            apply_json_patch(&mut target, &patch),
+            Err(JsonPatchError::PathNotFound("/missing".to_string()))
+        );
+        assert!(parse_json_patch(json!([{"op": "frobnicate", "path": "/a"}])).is_err());
+    }
+}
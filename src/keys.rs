@@ -0,0 +1,560 @@
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use chrono::{DateTime, Utc};
+use futures_util::future::BoxFuture;
+use jsonwebtoken::jwk::{
+    AlgorithmParameters, CommonParameters, EllipticCurve, Jwk, JwkSet, KeyAlgorithm,
+    OctetKeyPairParameters, OctetKeyPairType, PublicKeyUse,
+};
+use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use ring::signature::{Ed25519KeyPair, KeyPair};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+/// Shortest secret accepted for HS256.
+pub const MIN_SECRET_LEN: usize = 32;
+
+/// Marks an Ed25519 entry in the `parse_keys` format.
+const ED25519_PREFIX: &str = "ed25519:";
+
+#[derive(Clone, PartialEq, Eq)]
+enum KeyMaterial {
+    Hmac(Vec<u8>),
+    Ed25519 { pkcs8: Vec<u8>, public: Vec<u8> },
+}
+
+/// An HMAC secret or Ed25519 key pair identified by the `kid` written into the tokens it signs.
+#[derive(Clone, PartialEq, Eq)]
+pub struct SigningKey {
+    pub kid: String,
+    material: KeyMaterial,
+}
+
+impl SigningKey {
+    pub fn new<S: Into<String>>(kid: S, secret: Vec<u8>) -> Result<Self, KeyError> {
+        let kid = checked_kid(kid.into())?;
+        if secret.len() < MIN_SECRET_LEN {
+            return Err(KeyError::Source(format!(
+                "key {} is shorter than {} bytes",
+                kid, MIN_SECRET_LEN
+            )));
+        }
+        Ok(Self {
+            kid,
+            material: KeyMaterial::Hmac(secret),
+        })
+    }
+
+    /// An Ed25519 key pair from a PKCS#8 document. Tokens it signs can be verified with the
+    /// public key alone, e.g. from `KeyRing::public_jwks`.
+    pub fn ed25519<S: Into<String>>(kid: S, pkcs8: Vec<u8>) -> Result<Self, KeyError> {
+        let kid = checked_kid(kid.into())?;
+        let public = Ed25519KeyPair::from_pkcs8_maybe_unchecked(&pkcs8)
+            .map_err(|err| KeyError::Source(format!("key {}: {}", kid, err)))?
+            .public_key()
+            .as_ref()
+            .to_vec();
+        Ok(Self {
+            kid,
+            material: KeyMaterial::Ed25519 { pkcs8, public },
+        })
+    }
+
+    pub fn algorithm(&self) -> Algorithm {
+        match self.material {
+            KeyMaterial::Hmac(_) => Algorithm::HS256,
+            KeyMaterial::Ed25519 { .. } => Algorithm::EdDSA,
+        }
+    }
+
+    /// The public half as a JWK, or `None` for HMAC secrets.
+    pub fn public_jwk(&self) -> Option<Jwk> {
+        let KeyMaterial::Ed25519 { public, .. } = &self.material else {
+            return None;
+        };
+        Some(Jwk {
+            common: CommonParameters {
+                public_key_use: Some(PublicKeyUse::Signature),
+                key_algorithm: Some(KeyAlgorithm::EdDSA),
+                key_id: Some(self.kid.clone()),
+                ..CommonParameters::default()
+            },
+            algorithm: AlgorithmParameters::OctetKeyPair(OctetKeyPairParameters {
+                key_type: OctetKeyPairType::OctetKeyPair,
+                curve: EllipticCurve::Ed25519,
+                x: URL_SAFE_NO_PAD.encode(public),
+            }),
+        })
+    }
+
+    fn encoding_key(&self) -> EncodingKey {
+        match &self.material {
+            KeyMaterial::Hmac(secret) => EncodingKey::from_secret(secret),
+            KeyMaterial::Ed25519 { pkcs8, .. } => EncodingKey::from_ed_der(pkcs8),
+        }
+    }
+
+    fn decoding_key(&self) -> DecodingKey {
+        match &self.material {
+            KeyMaterial::Hmac(secret) => DecodingKey::from_secret(secret),
+            KeyMaterial::Ed25519 { public, .. } => DecodingKey::from_ed_der(public),
+        }
+    }
+}
+
+impl fmt::Debug for SigningKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SigningKey")
+            .field("kid", &self.kid)
+            .field("algorithm", &self.algorithm())
+            .finish_non_exhaustive()
+    }
+}
+
+fn checked_kid(kid: String) -> Result<String, KeyError> {
+    if kid.is_empty() {
+        return Err(KeyError::Source("key id must be non-empty".to_string()));
+    }
+    Ok(kid)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KeyError {
+    /// The key source failed or returned malformed keys.
+    Source(String),
+    NoKeys,
+    /// The token has no `kid`, or names a key that is unknown or past its grace window.
+    UnknownKey(Option<String>),
+    /// Bad signature, expired, or otherwise not a token this ring issued.
+    Invalid(String),
+    /// The token verified but has been revoked.
+    Revoked,
+}
+
+impl fmt::Display for KeyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KeyError::Source(err) => write!(f, "Failed to load signing keys: {}", err),
+            KeyError::NoKeys => write!(f, "No signing keys configured"),
+            KeyError::UnknownKey(Some(kid)) => write!(f, "Unknown signing key: {}", kid),
+            KeyError::UnknownKey(None) => write!(f, "Token has no key id"),
+            KeyError::Invalid(err) => write!(f, "Invalid token: {}", err),
+            KeyError::Revoked => write!(f, "Token has been revoked"),
+        }
+    }
+}
+
+impl std::error::Error for KeyError {}
+
+/// Where signing keys come from: the environment, a file, or a KMS behind an app's own impl.
+pub trait KeySource: Send + Sync {
+    /// Current keys, newest first. The first key signs; the rest only verify.
+    fn load(&self) -> BoxFuture<'_, Result<Vec<SigningKey>, KeyError>>;
+}
+
+/// Parse `kid:base64-secret` and `kid:ed25519:base64-pkcs8` entries separated by commas or
+/// newlines. Blank lines and lines starting with `#` are skipped.
+pub fn parse_keys(text: &str) -> Result<Vec<SigningKey>, KeyError> {
+    text.split([',', '\n'])
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty() && !entry.starts_with('#'))
+        .map(|entry| {
+            let (kid, secret) = entry
+                .split_once(':')
+                .ok_or_else(|| KeyError::Source("expected kid:secret".to_string()))?;
+            let (ed25519, secret) = match secret.trim().strip_prefix(ED25519_PREFIX) {
+                Some(pkcs8) => (true, pkcs8),
+                None => (false, secret),
+            };
+            let secret = base64::engine::general_purpose::STANDARD
+                .decode(secret.trim())
+                .map_err(|err| KeyError::Source(format!("key {}: {}", kid.trim(), err)))?;
+            if ed25519 {
+                SigningKey::ed25519(kid.trim(), secret)
+            } else {
+                SigningKey::new(kid.trim(), secret)
+            }
+        })
+        .collect()
+}
+
+/// Keys from an environment variable in the `parse_keys` format, re-read on every load.
+#[derive(Debug, Clone)]
+pub struct EnvKeySource {
+    var: String,
+}
+
+impl EnvKeySource {
+    pub fn new<S: Into<String>>(var: S) -> Self {
+        Self { var: var.into() }
+    }
+}
+
+impl KeySource for EnvKeySource {
+    fn load(&self) -> BoxFuture<'_, Result<Vec<SigningKey>, KeyError>> {
+        Box::pin(async move {
+            let text = std::env::var(&self.var)
+                .map_err(|_| KeyError::Source(format!("{} is not set", self.var)))?;
+            parse_keys(&text)
+        })
+    }
+}
+
+/// Keys from a file in the `parse_keys` format, e.g. a mounted secret, re-read on every load.
+#[derive(Debug, Clone)]
+pub struct FileKeySource {
+    path: PathBuf,
+}
+
+impl FileKeySource {
+    pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+        Self { path: path.into() }
+    }
+}
+
+impl KeySource for FileKeySource {
+    fn load(&self) -> BoxFuture<'_, Result<Vec<SigningKey>, KeyError>> {
+        Box::pin(async move {
+            let text = std::fs::read_to_string(&self.path)
+                .map_err(|err| KeyError::Source(format!("{}: {}", self.path.display(), err)))?;
+            parse_keys(&text)
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+struct RetiredKey {
+    key: SigningKey,
+    retired_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone)]
+struct Keys {
+    active: SigningKey,
+    retired: Vec<RetiredKey>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct RetiredKeyStatus {
+    pub kid: String,
+    pub verifies_until: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct KeyRingStatus {
+    pub active_kid: String,
+    pub retired: Vec<RetiredKeyStatus>,
+}
+
+/// Signs JWTs with the active key (HS256 or EdDSA) and verifies them against every key still in
+/// its grace window, so rotating a secret does not invalidate tokens already handed out.
+///
+/// A key retires when the source stops listing it first; it keeps verifying for `grace` after
+/// the ring first saw it retired, as long as the source still lists it. Keys dropped from the
+/// source stop verifying on the next `rotate`.
+#[derive(Clone)]
+pub struct KeyRing {
+    source: Arc<dyn KeySource>,
+    grace: chrono::Duration,
+    keys: Arc<RwLock<Keys>>,
+}
+
+impl fmt::Debug for KeyRing {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("KeyRing")
+            .field("grace", &self.grace)
+            .field("status", &self.status())
+            .finish_non_exhaustive()
+    }
+}
+
+impl KeyRing {
+    pub async fn load(
+        source: Arc<dyn KeySource>,
+        grace: chrono::Duration,
+    ) -> Result<Self, KeyError> {
+        let keys = next_keys(None, source.load().await?, Utc::now())?;
+        Ok(Self {
+            source,
+            grace,
+            keys: Arc::new(RwLock::new(keys)),
+        })
+    }
+
+    /// Re-read the source, making its first key the signing key and retiring the previous one.
+    pub async fn rotate(&self) -> Result<KeyRingStatus, KeyError> {
+        let loaded = self.source.load().await?;
+        let now = Utc::now();
+        let mut keys = self.keys.write().expect("key ring lock poisoned");
+        let next = next_keys(Some(&keys), loaded, now)?;
+        if next.active.kid != keys.active.kid {
+            tracing::info!(
+                "Rotated signing key from {} to {}",
+                keys.active.kid,
+                next.active.kid
+            );
+        }
+        *keys = next;
+        Ok(status(&keys, self.grace, now))
+    }
+
+    pub fn status(&self) -> KeyRingStatus {
+        let keys = self.keys.read().expect("key ring lock poisoned");
+        status(&keys, self.grace, Utc::now())
+    }
+
+    /// Sign `claims` with the active key, recording its `kid` in the header.
+    pub fn sign<T: Serialize>(&self, claims: &T) -> Result<String, KeyError> {
+        self.sign_typed("JWT", claims)
+    }
+
+    /// Like `sign`, with a `typ` header other than `JWT`, e.g. `at+jwt` for access tokens.
+    pub fn sign_typed<T: Serialize>(&self, typ: &str, claims: &T) -> Result<String, KeyError> {
+        let keys = self.keys.read().expect("key ring lock poisoned");
+        let mut header = Header::new(keys.active.algorithm());
+        header.typ = Some(typ.to_string());
+        header.kid = Some(keys.active.kid.clone());
+        jsonwebtoken::encode(&header, claims, &keys.active.encoding_key())
+            .map_err(|err| KeyError::Invalid(err.to_string()))
+    }
+
+    /// Verify a token from `sign`. Tokens must carry an `exp` claim.
+    pub fn verify<T: DeserializeOwned>(&self, token: &str) -> Result<T, KeyError> {
+        self.verify_with(token, |_| {})
+    }
+
+    /// Like `verify`, with extra checks, e.g. the audience or issuer. Tokens with an `aud` claim
+    /// only verify if `configure` sets an audience or turns off `validate_aud`.
+    pub fn verify_with<T: DeserializeOwned>(
+        &self,
+        token: &str,
+        configure: impl FnOnce(&mut Validation),
+    ) -> Result<T, KeyError> {
+        let header =
+            jsonwebtoken::decode_header(token).map_err(|err| KeyError::Invalid(err.to_string()))?;
+        let kid = header.kid.ok_or(KeyError::UnknownKey(None))?;
+        let key = self
+            .verifying_keys()
+            .into_iter()
+            .find(|key| key.kid == kid)
+            .ok_or_else(|| KeyError::UnknownKey(Some(kid.clone())))?;
+        let mut validation = Validation::new(key.algorithm());
+        configure(&mut validation);
+        jsonwebtoken::decode::<T>(token, &key.decoding_key(), &validation)
+            .map(|data| data.claims)
+            .map_err(|err| KeyError::Invalid(err.to_string()))
+    }
+
+    /// Public keys of the Ed25519 keys that still verify, for services that check tokens
+    /// without the secrets. HMAC keys are left out.
+    pub fn public_jwks(&self) -> JwkSet {
+        JwkSet {
+            keys: self
+                .verifying_keys()
+                .iter()
+                .filter_map(SigningKey::public_jwk)
+                .collect(),
+        }
+    }
+
+    /// The active key followed by retired keys still in their grace window.
+    fn verifying_keys(&self) -> Vec<SigningKey> {
+        let keys = self.keys.read().expect("key ring lock poisoned");
+        let now = Utc::now();
+        std::iter::once(&keys.active)
+            .chain(
+                keys.retired
+                    .iter()
+                    .filter(|retired| retired.retired_at + self.grace > now)
+                    .map(|retired| &retired.key),
+            )
+            .cloned()
+            .collect()
+    }
+}
+
+fn next_keys(
+    previous: Option<&Keys>,
+    loaded: Vec<SigningKey>,
+    now: DateTime<Utc>,
+) -> Result<Keys, KeyError> {
+    let mut loaded = loaded.into_iter();
+    let active = loaded.next().ok_or(KeyError::NoKeys)?;
+    let retired = loaded
+        .filter(|key| key.kid != active.kid)
+        .map(|key| {
+            let retired_at = previous
+                .and_then(|previous| {
+                    previous
+                        .retired
+                        .iter()
+                        .find(|retired| retired.key.kid == key.kid)
+                })
+                .map(|retired| retired.retired_at)
+                .unwrap_or(now);
+            RetiredKey { key, retired_at }
+        })
+        .collect();
+    Ok(Keys { active, retired })
+}
+
+fn status(keys: &Keys, grace: chrono::Duration, now: DateTime<Utc>) -> KeyRingStatus {
+    KeyRingStatus {
+        active_kid: keys.active.kid.clone(),
+        retired: keys
+            .retired
+            .iter()
+            .map(|retired| RetiredKeyStatus {
+                kid: retired.key.kid.clone(),
+                verifies_until: retired.retired_at + grace,
+            })
+            .filter(|retired| retired.verifies_until > now)
+            .collect(),
+    }
+}
+
+/// Record a rotation in the audit log as `signing_keys_rotated`.
+#[cfg(feature = "sqlx")]
+pub async fn log_rotation(
+    pool: &sqlx::PgPool,
+    actor_user_id: crate::user_id::UserId,
+    previous_kid: &str,
+    status: &KeyRingStatus,
+) -> Result<(), sqlx::Error> {
+    crate::db::insert_audit_log(
+        pool,
+        Some(actor_user_id),
+        serde_json::json!({
+            "type": "signing_keys_rotated",
+            "previous_kid": previous_kid,
+            "active_kid": status.active_kid,
+        }),
+    )
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use base64::Engine;
+    use futures_util::future::BoxFuture;
+    use serde::{Deserialize, Serialize};
+
+    use super::{KeyError, KeyRing, KeySource, SigningKey, parse_keys};
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Claims {
+        sub: String,
+        exp: i64,
+    }
+
+    struct StaticSource(Mutex<String>);
+
+    impl KeySource for StaticSource {
+        fn load(&self) -> BoxFuture<'_, Result<Vec<SigningKey>, KeyError>> {
+            let text = self.0.lock().unwrap().clone();
+            Box::pin(async move { parse_keys(&text) })
+        }
+    }
+
+    fn entry(kid: &str, byte: u8) -> String {
+        format!(
+            "{}:{}",
+            kid,
+            base64::engine::general_purpose::STANDARD.encode([byte; 32])
+        )
+    }
+
+    fn claims() -> Claims {
+        Claims {
+            sub: "user".to_string(),
+            exp: chrono::Utc::now().timestamp() + 60,
+        }
+    }
+
+    #[tokio::test]
+    async fn verifies_retired_keys_during_grace() {
+        let source = Arc::new(StaticSource(Mutex::new(entry("k1", 1))));
+        let ring = KeyRing::load(source.clone(), chrono::Duration::hours(1))
+            .await
+            .unwrap();
+        let old = ring.sign(&claims()).unwrap();
+
+        *source.0.lock().unwrap() = format!("{},{}", entry("k2", 2), entry("k1", 1));
+        let status = ring.rotate().await.unwrap();
+        assert_eq!(status.active_kid, "k2");
+        assert_eq!(status.retired.len(), 1);
+        assert_eq!(ring.verify::<Claims>(&old).unwrap().sub, "user");
+        assert!(
+            ring.verify::<Claims>(&ring.sign(&claims()).unwrap())
+                .is_ok()
+        );
+
+        *source.0.lock().unwrap() = entry("k2", 2);
+        ring.rotate().await.unwrap();
+        assert_eq!(
+            ring.verify::<Claims>(&old),
+            Err(KeyError::UnknownKey(Some("k1".to_string())))
+        );
+
+        let expired = KeyRing::load(
+            Arc::new(StaticSource(Mutex::new(format!(
+                "{},{}",
+                entry("k2", 2),
+                entry("k1", 1)
+            )))),
+            chrono::Duration::zero(),
+        )
+        .await
+        .unwrap();
+        assert!(matches!(
+            expired.verify::<Claims>(&old),
+            Err(KeyError::UnknownKey(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn signs_with_ed25519_and_publishes_public_keys() {
+        let pkcs8 =
+            ring::signature::Ed25519KeyPair::generate_pkcs8(&ring::rand::SystemRandom::new())
+                .unwrap();
+        let text = format!(
+            "e1:ed25519:{},{}",
+            base64::engine::general_purpose::STANDARD.encode(pkcs8.as_ref()),
+            entry("k1", 1)
+        );
+        let ring = KeyRing::load(
+            Arc::new(StaticSource(Mutex::new(text))),
+            chrono::Duration::hours(1),
+        )
+        .await
+        .unwrap();
+        let token = ring.sign(&claims()).unwrap();
+        assert_eq!(ring.verify::<Claims>(&token).unwrap().sub, "user");
+
+        let jwks = ring.public_jwks();
+        assert_eq!(jwks.keys.len(), 1);
+        let key = jsonwebtoken::DecodingKey::from_jwk(jwks.find("e1").unwrap()).unwrap();
+        let validation = jsonwebtoken::Validation::new(jsonwebtoken::Algorithm::EdDSA);
+        assert!(jsonwebtoken::decode::<Claims>(&token, &key, &validation).is_ok());
+        assert!(matches!(
+            parse_keys("e2:ed25519:c2hvcnQ="),
+            Err(KeyError::Source(_))
+        ));
+    }
+
+    #[test]
+    fn rejects_short_or_malformed_keys() {
+        assert!(matches!(
+            parse_keys("k1:c2hvcnQ="),
+            Err(KeyError::Source(_))
+        ));
+        assert!(matches!(parse_keys("no-secret"), Err(KeyError::Source(_))));
+        assert_eq!(parse_keys("# comment\n").unwrap(), Vec::new());
+    }
+}
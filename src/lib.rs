@@ -1,12 +1,112 @@
+#[cfg(feature = "sqlx")]
+pub mod access;
+pub mod access_tokens;
+#[cfg(feature = "sqlx")]
+pub mod activity;
 #[cfg(feature = "api")]
 pub mod api;
+#[cfg(feature = "sqlx")]
+pub mod archival;
+#[cfg(feature = "sqlx")]
+pub mod audit;
 pub mod auth;
 #[cfg(feature = "sqlx")]
+pub mod auth_context;
+#[cfg(feature = "sqlx")]
+pub mod authz_loader;
+#[cfg(feature = "sqlx")]
+pub mod bootstrap;
+pub mod breaker;
+#[cfg(feature = "sqlx")]
+pub mod bundle;
+pub mod captcha;
+#[cfg(feature = "sqlx")]
+pub mod casbin;
+pub mod cipher;
+#[cfg(feature = "sqlx")]
+pub mod claims;
+#[cfg(feature = "sqlx")]
+pub mod clients;
+pub mod config;
+#[cfg(feature = "sqlx")]
 pub mod db;
+#[cfg(feature = "sqlx")]
+pub mod deactivation;
+pub mod email;
+pub mod environment;
+#[cfg(feature = "sqlx")]
+pub mod export;
+pub mod external_id;
+pub mod fields;
+#[cfg(feature = "graphql")]
+pub mod graphql;
 pub mod group_id;
+#[cfg(feature = "api")]
+pub mod guard;
+pub mod hmac_auth;
+#[cfg(feature = "api")]
+pub mod hooks;
+pub mod i18n;
+#[cfg(feature = "sqlx")]
+pub mod idempotency;
+pub mod identity;
+pub mod ids;
+#[cfg(feature = "sqlx")]
+pub mod import;
+#[cfg(feature = "sqlx")]
+pub mod integrity;
+#[cfg(feature = "sqlx")]
+pub mod invitations;
+pub mod json_patch;
+pub mod keys;
+pub mod logout;
+#[cfg(feature = "sqlx")]
+pub mod maintenance;
+#[cfg(feature = "sqlx")]
+pub mod membership_sync;
+#[cfg(feature = "import")]
+pub mod migrate;
+#[cfg(feature = "sqlx")]
+pub mod moderation;
+#[cfg(feature = "mtls")]
+pub mod mtls;
+pub mod notify;
+#[cfg(feature = "oauth-server")]
+pub mod oauth_server;
 pub mod oidc;
+pub mod password;
+#[cfg(feature = "sqlx")]
+pub mod permission_changes;
+pub mod permission_tokens;
+pub mod policy;
 pub mod prelude;
+#[cfg(feature = "sqlx")]
+pub mod provisioning;
+#[cfg(feature = "sqlx")]
+pub mod query_plans;
+#[cfg(feature = "sqlx")]
+pub mod recycle_bin;
+pub mod redact;
+#[cfg(feature = "sqlx")]
+pub mod remember;
+#[cfg(feature = "sqlx")]
+pub mod reports;
+pub mod request_id;
 pub mod rustls;
+#[cfg(feature = "api")]
+pub mod state;
+#[cfg(feature = "sqlx")]
+pub mod stats;
+pub mod step_up;
+#[cfg(feature = "sqlx")]
+pub mod suspension;
+#[cfg(feature = "role-manifest")]
+pub mod sync;
 pub mod tokens;
+#[cfg(feature = "api")]
+pub mod trusted_proxy;
+#[cfg(feature = "sqlx")]
+pub mod updates;
 pub mod user_id;
+pub mod username;
 pub mod workload;
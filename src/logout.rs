@@ -0,0 +1,386 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::sync::RwLock;
+
+use chrono::{DateTime, NaiveDateTime};
+use jsonwebtoken::jwk::JwkSet;
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use once_cell::sync::Lazy;
+use serde::Deserialize;
+use serde_json::{Map, Value};
+#[cfg(feature = "sqlx")]
+use sqlx::PgPool;
+
+use crate::prelude::{CoreIdToken, CoreIdTokenClaims, validated_token_claim_string};
+
+/// `events` member that marks a JWT as an OIDC back-channel logout token.
+pub const BACKCHANNEL_LOGOUT_EVENT: &str = "http://schemas.openid.net/event/backchannel-logout";
+
+/// Logout tokens issued longer ago than this are refused.
+const MAX_LOGOUT_TOKEN_AGE: chrono::Duration = chrono::Duration::minutes(10);
+
+/// How long a logout is remembered. Sessions outliving this after being ended at the provider
+/// are accepted again; keep it above the longest session the app allows.
+pub const LOGOUT_RETENTION: chrono::Duration = chrono::Duration::days(30);
+
+/// A session the identity provider ended, from a verified logout token.
+///
+/// With a `session_id` only that provider session ends; without one, every session of `subject`
+/// authenticated at or before `logged_out_at` does.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FederatedLogout {
+    pub issuer: String,
+    pub subject: Option<String>,
+    pub session_id: Option<String>,
+    pub jti: String,
+    pub logged_out_at: NaiveDateTime,
+}
+
+#[derive(Debug)]
+pub enum LogoutTokenError {
+    Invalid(String),
+    /// A logout token with this `jti` was already processed.
+    Replayed,
+    Database,
+}
+
+impl fmt::Display for LogoutTokenError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Invalid(reason) => write!(f, "Invalid logout token: {}", reason),
+            Self::Replayed => write!(f, "Logout token was already used"),
+            Self::Database => write!(f, "Failed to reach database"),
+        }
+    }
+}
+
+impl std::error::Error for LogoutTokenError {}
+
+#[derive(Deserialize)]
+struct LogoutTokenClaims {
+    iss: String,
+    iat: i64,
+    jti: String,
+    sub: Option<String>,
+    sid: Option<String>,
+    #[serde(default)]
+    events: Map<String, Value>,
+    nonce: Option<Value>,
+}
+
+/// Verify a logout token (OpenID Connect Back-Channel Logout 1.0, section 2.6) signed with one of
+/// `jwks`, issued by `issuer` for `client_id`.
+pub(crate) fn verify_logout_token(
+    token: &str,
+    jwks: &JwkSet,
+    issuer: &str,
+    client_id: &str,
+) -> Result<FederatedLogout, LogoutTokenError> {
+    let invalid = |reason: &str| LogoutTokenError::Invalid(reason.to_string());
+    let header = jsonwebtoken::decode_header(token).map_err(|err| invalid(&err.to_string()))?;
+    if matches!(
+        header.alg,
+        Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512
+    ) {
+        return Err(invalid("symmetric signatures are not supported"));
+    }
+    let jwk = match &header.kid {
+        Some(kid) => jwks.find(kid),
+        None if jwks.keys.len() == 1 => jwks.keys.first(),
+        None => None,
+    }
+    .ok_or_else(|| invalid("unknown signing key"))?;
+    let key = DecodingKey::from_jwk(jwk).map_err(|err| invalid(&err.to_string()))?;
+
+    let mut validation = Validation::new(header.alg);
+    validation.set_issuer(&[issuer]);
+    validation.set_audience(&[client_id]);
+    validation.required_spec_claims = HashSet::from(["iss".to_string(), "aud".to_string()]);
+    let claims = jsonwebtoken::decode::<LogoutTokenClaims>(token, &key, &validation)
+        .map_err(|err| invalid(&err.to_string()))?
+        .claims;
+
+    if !claims
+        .events
+        .get(BACKCHANNEL_LOGOUT_EVENT)
+        .is_some_and(Value::is_object)
+    {
+        return Err(invalid("missing back-channel logout event"));
+    }
+    if claims.nonce.is_some() {
+        return Err(invalid("logout tokens must not carry a nonce"));
+    }
+    let subject = claims.sub.filter(|sub| !sub.is_empty());
+    let session_id = claims.sid.filter(|sid| !sid.is_empty());
+    if subject.is_none() && session_id.is_none() {
+        return Err(invalid("either sub or sid is required"));
+    }
+    let logged_out_at = DateTime::from_timestamp(claims.iat, 0)
+        .ok_or_else(|| invalid("iat is out of range"))?
+        .naive_utc();
+    let now = chrono::Utc::now().naive_utc();
+    let leeway = chrono::Duration::seconds(validation.leeway as i64);
+    if logged_out_at > now + leeway || logged_out_at < now - MAX_LOGOUT_TOKEN_AGE {
+        return Err(invalid("iat is not recent"));
+    }
+
+    Ok(FederatedLogout {
+        issuer: claims.iss,
+        subject,
+        session_id,
+        jti: claims.jti,
+        logged_out_at,
+    })
+}
+
+#[derive(Default, Clone, Copy)]
+struct SubjectLogout {
+    /// From logout tokens without `sid`.
+    every_session: Option<NaiveDateTime>,
+    /// From logout tokens with `sid`, for ID tokens that carry none.
+    sessions_without_sid: Option<NaiveDateTime>,
+}
+
+#[derive(Default)]
+struct LogoutRegistry {
+    sessions: HashMap<(String, String), NaiveDateTime>,
+    subjects: HashMap<(String, String), SubjectLogout>,
+}
+
+impl LogoutRegistry {
+    fn record(&mut self, logout: &FederatedLogout) {
+        let cutoff = chrono::Utc::now().naive_utc() - LOGOUT_RETENTION;
+        self.sessions.retain(|_, at| *at > cutoff);
+        self.subjects.retain(|_, subject| {
+            subject.every_session.is_some_and(|at| at > cutoff)
+                || subject.sessions_without_sid.is_some_and(|at| at > cutoff)
+        });
+
+        if let Some(sid) = &logout.session_id {
+            self.sessions
+                .entry((logout.issuer.clone(), sid.clone()))
+                .and_modify(|at| *at = (*at).max(logout.logged_out_at))
+                .or_insert(logout.logged_out_at);
+        }
+        if let Some(sub) = &logout.subject {
+            let subject = self
+                .subjects
+                .entry((logout.issuer.clone(), sub.clone()))
+                .or_default();
+            let at = match logout.session_id {
+                Some(_) => &mut subject.sessions_without_sid,
+                None => &mut subject.every_session,
+            };
+            *at = (*at).max(Some(logout.logged_out_at));
+        }
+    }
+
+    fn ended(
+        &self,
+        issuer: &str,
+        subject: &str,
+        sid: Option<&str>,
+        authenticated_at: NaiveDateTime,
+    ) -> bool {
+        if let Some(sid) = sid
+            && self
+                .sessions
+                .contains_key(&(issuer.to_string(), sid.to_string()))
+        {
+            return true;
+        }
+        let Some(logout) = self
+            .subjects
+            .get(&(issuer.to_string(), subject.to_string()))
+        else {
+            return false;
+        };
+        let ended_by = |at: Option<NaiveDateTime>| at.is_some_and(|at| authenticated_at <= at);
+        ended_by(logout.every_session) || (sid.is_none() && ended_by(logout.sessions_without_sid))
+    }
+}
+
+static FEDERATED_LOGOUTS: Lazy<RwLock<LogoutRegistry>> =
+    Lazy::new(|| RwLock::new(LogoutRegistry::default()));
+
+/// End matching sessions in this process. `AuthService` refuses their ID tokens from now on.
+pub fn record_federated_logout(logout: &FederatedLogout) {
+    FEDERATED_LOGOUTS
+        .write()
+        .unwrap_or_else(|err| err.into_inner())
+        .record(logout);
+}
+
+/// Whether the identity provider ended the session `id_token` belongs to.
+pub fn session_logged_out(id_token: &CoreIdToken, claims: &CoreIdTokenClaims) -> bool {
+    let sid = validated_token_claim_string(id_token, "sid");
+    let authenticated_at = claims
+        .auth_time()
+        .unwrap_or_else(|| claims.issue_time())
+        .naive_utc();
+    FEDERATED_LOGOUTS
+        .read()
+        .unwrap_or_else(|err| err.into_inner())
+        .ended(
+            claims.issuer().as_str(),
+            claims.subject().as_str(),
+            sid.as_deref(),
+            authenticated_at,
+        )
+}
+
+/// Store `logout` in `auth.federated_logouts` so other instances pick it up in
+/// `sync_federated_logouts`. A `jti` seen before is `LogoutTokenError::Replayed`.
+#[cfg(feature = "sqlx")]
+pub async fn store_federated_logout(
+    pool: &PgPool,
+    logout: &FederatedLogout,
+) -> Result<(), LogoutTokenError> {
+    let inserted = sqlx::query(
+        r#"
+        INSERT INTO auth.federated_logouts (issuer, jti, subject, session_id, logged_out_at)
+        VALUES ($1, $2, $3, $4, $5)
+        ON CONFLICT (issuer, jti) DO NOTHING
+        "#,
+    )
+    .bind(&logout.issuer)
+    .bind(&logout.jti)
+    .bind(&logout.subject)
+    .bind(&logout.session_id)
+    .bind(logout.logged_out_at)
+    .execute(pool)
+    .await
+    .map_err(|err| {
+        tracing::error!("Failed to store federated logout: {}", err);
+        LogoutTokenError::Database
+    })?
+    .rows_affected();
+    if inserted == 0 {
+        return Err(LogoutTokenError::Replayed);
+    }
+
+    let cutoff = chrono::Utc::now().naive_utc() - LOGOUT_RETENTION;
+    if let Err(err) = sqlx::query("DELETE FROM auth.federated_logouts WHERE logged_out_at < $1")
+        .bind(cutoff)
+        .execute(pool)
+        .await
+    {
+        tracing::warn!("Failed to prune federated logouts: {}", err);
+    }
+    Ok(())
+}
+
+#[cfg(feature = "sqlx")]
+type LogoutRow = (
+    String,
+    String,
+    Option<String>,
+    Option<String>,
+    NaiveDateTime,
+);
+
+/// Load logouts received by any instance within `LOGOUT_RETENTION` into this process. Run it on
+/// every replica, e.g. every few seconds, when the back-channel endpoint reaches only one.
+#[cfg(feature = "sqlx")]
+pub async fn sync_federated_logouts(pool: &PgPool) -> Result<usize, sqlx::Error> {
+    let cutoff = chrono::Utc::now().naive_utc() - LOGOUT_RETENTION;
+    let rows: Vec<LogoutRow> = sqlx::query_as(
+        r#"
+            SELECT issuer, jti, subject, session_id, logged_out_at
+            FROM auth.federated_logouts
+            WHERE logged_out_at >= $1
+            "#,
+    )
+    .bind(cutoff)
+    .fetch_all(pool)
+    .await?;
+
+    let mut registry = FEDERATED_LOGOUTS
+        .write()
+        .unwrap_or_else(|err| err.into_inner());
+    for (issuer, jti, subject, session_id, logged_out_at) in &rows {
+        registry.record(&FederatedLogout {
+            issuer: issuer.clone(),
+            subject: subject.clone(),
+            session_id: session_id.clone(),
+            jti: jti.clone(),
+            logged_out_at: *logged_out_at,
+        });
+    }
+    Ok(rows.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BACKCHANNEL_LOGOUT_EVENT, LogoutRegistry, LogoutTokenError, verify_logout_token};
+    use crate::keys::SigningKey;
+
+    #[test]
+    fn verifies_logout_tokens_and_ends_sessions() {
+        let pkcs8 =
+            ring::signature::Ed25519KeyPair::generate_pkcs8(&ring::rand::SystemRandom::new())
+                .unwrap();
+        let key = SigningKey::ed25519("k1", pkcs8.as_ref().to_vec()).unwrap();
+        let jwks = jsonwebtoken::jwk::JwkSet {
+            keys: vec![key.public_jwk().unwrap()],
+        };
+        let now = chrono::Utc::now().timestamp();
+        let sign = |claims: serde_json::Value| {
+            let mut header = jsonwebtoken::Header::new(jsonwebtoken::Algorithm::EdDSA);
+            header.typ = Some("logout+jwt".to_string());
+            header.kid = Some("k1".to_string());
+            let encoding = jsonwebtoken::EncodingKey::from_ed_der(pkcs8.as_ref());
+            jsonwebtoken::encode(&header, &claims, &encoding).unwrap()
+        };
+        let issuer = "https://idp.example.com";
+        let claims = |sid: Option<&str>| {
+            let mut claims = serde_json::json!({
+                "iss": issuer,
+                "aud": "app",
+                "iat": now,
+                "jti": uuid::Uuid::new_v4().to_string(),
+                "sub": "alice",
+                "events": { BACKCHANNEL_LOGOUT_EVENT: {} },
+            });
+            if let Some(sid) = sid {
+                claims["sid"] = sid.into();
+            }
+            claims
+        };
+
+        let logout = verify_logout_token(&sign(claims(Some("s1"))), &jwks, issuer, "app").unwrap();
+        assert_eq!(logout.subject.as_deref(), Some("alice"));
+        assert_eq!(logout.session_id.as_deref(), Some("s1"));
+        assert!(matches!(
+            verify_logout_token(&sign(claims(None)), &jwks, issuer, "other"),
+            Err(LogoutTokenError::Invalid(_))
+        ));
+        let mut with_nonce = claims(None);
+        with_nonce["nonce"] = "n".into();
+        assert!(matches!(
+            verify_logout_token(&sign(with_nonce), &jwks, issuer, "app"),
+            Err(LogoutTokenError::Invalid(_))
+        ));
+        let mut without_event = claims(None);
+        without_event["events"] = serde_json::json!({});
+        assert!(matches!(
+            verify_logout_token(&sign(without_event), &jwks, issuer, "app"),
+            Err(LogoutTokenError::Invalid(_))
+        ));
+
+        let before = logout.logged_out_at - chrono::Duration::minutes(1);
+        let after = logout.logged_out_at + chrono::Duration::minutes(1);
+        let mut registry = LogoutRegistry::default();
+        registry.record(&logout);
+        assert!(registry.ended(issuer, "alice", Some("s1"), after));
+        assert!(!registry.ended(issuer, "alice", Some("s2"), before));
+        assert!(registry.ended(issuer, "alice", None, before));
+        assert!(!registry.ended(issuer, "alice", None, after));
+
+        let everywhere = verify_logout_token(&sign(claims(None)), &jwks, issuer, "app").unwrap();
+        registry.record(&everywhere);
+        assert!(registry.ended(issuer, "alice", Some("s2"), before));
+        assert!(!registry.ended(issuer, "alice", Some("s2"), after));
+        assert!(!registry.ended(issuer, "bob", None, before));
+    }
+}
@@ -0,0 +1,628 @@
+//! Periodic cleanup of the auth tables, run by `spawn_maintenance` rather than a cron job in every
+//! app: dormant accounts, expired tokens, codes and nonces, stats and retention.
+//!
+//! Two jobs are deliberately absent. Role grants carry no expiry in this schema, so there is no
+//! role expiry to enforce; time-limited access needs an app task that revokes the grants. And the
+//! crate keeps no outbox to dispatch: changes reach other processes through the
+//! `auth_user_updates` notifications read by `updates::UserUpdates`. Apps that need either can
+//! schedule it with `MaintenanceConfig::with_task`.
+
+use std::collections::BTreeMap;
+use std::future;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use chrono::NaiveDateTime;
+use futures_util::future::{BoxFuture, join_all};
+use serde::Serialize;
+use sqlx::PgPool;
+use sqlx::pool::PoolConnection;
+use sqlx::postgres::Postgres;
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+use tower_sessions::session_store::ExpiredDeletion;
+use uuid::Uuid;
+
+use crate::db::deactivate_dormant;
+use crate::hmac_auth::purge_hmac_nonces;
+use crate::idempotency::{DEFAULT_IDEMPOTENCY_RETENTION, purge_idempotency_keys};
+use crate::remember::purge_remembered_sessions;
+use crate::stats::refresh_daily_stats;
+
+/// How often a maintenance task runs, and whether it runs at all.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TaskSchedule {
+    pub enabled: bool,
+    pub every: Duration,
+}
+
+impl TaskSchedule {
+    pub fn every(every: Duration) -> Self {
+        Self {
+            enabled: true,
+            every,
+        }
+    }
+
+    pub fn disabled(every: Duration) -> Self {
+        Self {
+            enabled: false,
+            every,
+        }
+    }
+}
+
+/// One run of a task: the number of rows or records it affected, or an error message.
+pub type TaskResult = Result<u64, String>;
+
+type TaskFn = Arc<dyn Fn(Arc<PgPool>) -> BoxFuture<'static, TaskResult> + Send + Sync>;
+
+/// A task added with `MaintenanceConfig::with_task`.
+#[derive(Clone)]
+pub struct MaintenanceTask {
+    name: String,
+    schedule: TaskSchedule,
+    run: TaskFn,
+}
+
+#[derive(Clone)]
+pub struct MaintenanceConfig {
+    /// Up to this much random delay is added before every run, so replicas started together do
+    /// not hit the database in lockstep.
+    pub jitter: Duration,
+    /// Coordinate runs across replicas through `with_leadership` and `auth.maintenance_runs`, so
+    /// each task runs at most once per interval however many replicas schedule it. Only turn this
+    /// off for a single instance.
+    pub coordinate: bool,
+    /// Deactivate users with no login for `dormant_after`. Off by default. Unlike
+    /// `api::deactivate_dormant_users`, this announces nothing to the app.
+    pub dormant_users: TaskSchedule,
+    pub dormant_after: Duration,
+    /// Refresh `auth.daily_stats` for `/auth/admin/stats`. Off by default; the endpoint can
+    /// refresh on demand instead.
+    pub daily_stats: TaskSchedule,
+    /// Delete idempotency keys claimed more than `retain_idempotency_keys_for` ago. Expired keys
+    /// are never replayed either way; this only reclaims the space. Off by default.
+    pub idempotency_keys: TaskSchedule,
+    pub retain_idempotency_keys_for: Duration,
+    /// Delete OAuth authorization codes past their expiry. Off by default.
+    #[cfg(feature = "oauth-server")]
+    pub authorization_codes: TaskSchedule,
+    /// Delete remember-me tokens once expired or revoked. Off by default.
+    pub remembered_sessions: TaskSchedule,
+    /// Delete HMAC nonces past their expiry, including those of keys no longer signing. Off by
+    /// default.
+    pub hmac_nonces: TaskSchedule,
+    /// Delete `auth.log` entries older than `retain_logs_for`. Off by default.
+    #[cfg(feature = "hard-delete")]
+    pub log_retention: TaskSchedule,
+    #[cfg(feature = "hard-delete")]
+    pub retain_logs_for: Duration,
+    /// Permanently delete users and groups soft-deleted more than `retain_deleted_for` ago; see
+    /// `recycle_bin`. Off by default, so deleted rows stay restorable until purged.
+    #[cfg(feature = "hard-delete")]
+    pub deleted_purge: TaskSchedule,
+    #[cfg(feature = "hard-delete")]
+    pub retain_deleted_for: Duration,
+    /// App-specific tasks; see `with_task`.
+    pub tasks: Vec<MaintenanceTask>,
+}
+
+const HOUR: Duration = Duration::from_secs(60 * 60);
+const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+impl Default for MaintenanceConfig {
+    fn default() -> Self {
+        Self {
+            jitter: Duration::from_secs(60),
+            coordinate: true,
+            dormant_users: TaskSchedule::disabled(DAY),
+            dormant_after: 180 * DAY,
+            daily_stats: TaskSchedule::disabled(HOUR),
+            idempotency_keys: TaskSchedule::disabled(HOUR),
+            retain_idempotency_keys_for: DEFAULT_IDEMPOTENCY_RETENTION,
+            #[cfg(feature = "oauth-server")]
+            authorization_codes: TaskSchedule::disabled(HOUR),
+            remembered_sessions: TaskSchedule::disabled(DAY),
+            hmac_nonces: TaskSchedule::disabled(HOUR),
+            #[cfg(feature = "hard-delete")]
+            log_retention: TaskSchedule::disabled(DAY),
+            #[cfg(feature = "hard-delete")]
+            retain_logs_for: 365 * DAY,
+            #[cfg(feature = "hard-delete")]
+            deleted_purge: TaskSchedule::disabled(DAY),
+            #[cfg(feature = "hard-delete")]
+            retain_deleted_for: 30 * DAY,
+            tasks: Vec::new(),
+        }
+    }
+}
+
+impl MaintenanceConfig {
+    /// Run an app-specific task on the same schedule machinery, with the same jitter and metrics.
+    pub fn with_task<F, Fut>(mut self, name: &str, schedule: TaskSchedule, task: F) -> Self
+    where
+        F: Fn(Arc<PgPool>) -> Fut + Send + Sync + 'static,
+        Fut: future::Future<Output = TaskResult> + Send + 'static,
+    {
+        self.tasks.push(MaintenanceTask {
+            name: name.to_string(),
+            schedule,
+            run: Arc::new(move |pool| Box::pin(task(pool))),
+        });
+        self
+    }
+
+    /// Delete expired sessions from a store that supports it. The `MemoryStore` used by
+    /// `api::routes` does not; this is for apps serving sessions from a persistent store.
+    pub fn with_session_cleanup<T>(self, store: T, schedule: TaskSchedule) -> Self
+    where
+        T: ExpiredDeletion + Clone,
+    {
+        self.with_task("expired_sessions", schedule, move |_pool| {
+            let store = store.clone();
+            async move {
+                store
+                    .delete_expired()
+                    .await
+                    .map(|()| 0)
+                    .map_err(|err| err.to_string())
+            }
+        })
+    }
+
+    fn into_tasks(self) -> Vec<MaintenanceTask> {
+        let mut tasks = Vec::new();
+        let dormant_after =
+            chrono::Duration::from_std(self.dormant_after).unwrap_or(chrono::Duration::MAX);
+        tasks.push(MaintenanceTask {
+            name: "dormant_users".to_string(),
+            schedule: self.dormant_users,
+            run: Arc::new(move |pool| {
+                Box::pin(async move {
+                    let cutoff = cutoff_before(dormant_after);
+                    deactivate_dormant(&pool, cutoff)
+                        .await
+                        .map(|users| users.len() as u64)
+                        .map_err(|err| err.to_string())
+                })
+            }),
+        });
+        tasks.push(MaintenanceTask {
+            name: "daily_stats".to_string(),
+            schedule: self.daily_stats,
+            run: Arc::new(|pool| {
+                Box::pin(async move {
+                    refresh_daily_stats(&pool)
+                        .await
+                        .map(|()| 0)
+                        .map_err(|err| err.to_string())
+                })
+            }),
+        });
+        let retain_idempotency_keys_for =
+            chrono::Duration::from_std(self.retain_idempotency_keys_for)
+                .unwrap_or(chrono::Duration::MAX);
+        tasks.push(MaintenanceTask {
+            name: "idempotency_keys".to_string(),
+            schedule: self.idempotency_keys,
+            run: Arc::new(move |pool| {
+                Box::pin(async move {
+                    let cutoff = cutoff_before(retain_idempotency_keys_for);
+                    purge_idempotency_keys(&pool, cutoff)
+                        .await
+                        .map_err(|err| err.to_string())
+                })
+            }),
+        });
+        #[cfg(feature = "oauth-server")]
+        tasks.push(MaintenanceTask {
+            name: "authorization_codes".to_string(),
+            schedule: self.authorization_codes,
+            run: Arc::new(|pool| {
+                Box::pin(async move {
+                    crate::oauth_server::purge_authorization_codes(&pool, now())
+                        .await
+                        .map_err(|err| err.to_string())
+                })
+            }),
+        });
+        tasks.push(MaintenanceTask {
+            name: "remembered_sessions".to_string(),
+            schedule: self.remembered_sessions,
+            run: Arc::new(|pool| {
+                Box::pin(async move {
+                    purge_remembered_sessions(&pool, now())
+                        .await
+                        .map_err(|err| err.to_string())
+                })
+            }),
+        });
+        tasks.push(MaintenanceTask {
+            name: "hmac_nonces".to_string(),
+            schedule: self.hmac_nonces,
+            run: Arc::new(|pool| {
+                Box::pin(async move {
+                    purge_hmac_nonces(&pool, now())
+                        .await
+                        .map_err(|err| err.to_string())
+                })
+            }),
+        });
+        #[cfg(feature = "hard-delete")]
+        {
+            let retain_logs_for =
+                chrono::Duration::from_std(self.retain_logs_for).unwrap_or(chrono::Duration::MAX);
+            tasks.push(MaintenanceTask {
+                name: "log_retention".to_string(),
+                schedule: self.log_retention,
+                run: Arc::new(move |pool| {
+                    Box::pin(async move {
+                        let cutoff = cutoff_before(retain_logs_for);
+                        crate::db::LogRow::purge_before(&pool, cutoff)
+                            .await
+                            .map_err(|err| err.to_string())
+                    })
+                }),
+            });
+        }
+        #[cfg(feature = "hard-delete")]
+        {
+            let retain_deleted_for = chrono::Duration::from_std(self.retain_deleted_for)
+                .unwrap_or(chrono::Duration::MAX);
+            tasks.push(MaintenanceTask {
+                name: "deleted_purge".to_string(),
+                schedule: self.deleted_purge,
+                run: Arc::new(move |pool| {
+                    Box::pin(async move {
+                        let cutoff = cutoff_before(retain_deleted_for);
+                        crate::recycle_bin::purge_deleted(&pool, cutoff)
+                            .await
+                            .map_err(|err| err.to_string())
+                    })
+                }),
+            });
+        }
+        tasks.extend(self.tasks);
+        tasks.retain(|task| task.schedule.enabled);
+        tasks
+    }
+}
+
+fn cutoff_before(age: chrono::Duration) -> NaiveDateTime {
+    now().checked_sub_signed(age).unwrap_or(NaiveDateTime::MIN)
+}
+
+fn now() -> NaiveDateTime {
+    chrono::Utc::now().naive_utc()
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct TaskMetrics {
+    pub runs: u64,
+    pub failures: u64,
+    /// Scheduled runs skipped because another replica was running the task or had run it within
+    /// the interval.
+    pub skipped: u64,
+    /// Rows or records affected by the last successful run.
+    pub last_affected: u64,
+    pub total_affected: u64,
+    pub last_run_at: Option<NaiveDateTime>,
+    pub last_duration_ms: Option<u64>,
+    pub last_error: Option<String>,
+}
+
+/// Handle to the task started by `spawn_maintenance`.
+///
+/// ```ignore
+/// let config = MaintenanceConfig {
+///     dormant_users: TaskSchedule::every(Duration::from_secs(6 * 60 * 60)),
+///     ..MaintenanceConfig::default()
+/// };
+/// let (maintenance, task) = spawn_maintenance(pool.clone(), config);
+/// // On shutdown
+/// maintenance.shutdown().await;
+/// ```
+#[derive(Clone)]
+pub struct Maintenance {
+    metrics: Arc<Mutex<BTreeMap<String, TaskMetrics>>>,
+    cancel: CancellationToken,
+    stopped: CancellationToken,
+}
+
+impl Maintenance {
+    /// Metrics for every enabled task, keyed by task name.
+    pub fn metrics(&self) -> BTreeMap<String, TaskMetrics> {
+        self.metrics
+            .lock()
+            .map(|metrics| metrics.clone())
+            .unwrap_or_default()
+    }
+
+    /// Stop scheduling runs and wait for runs already in progress to finish.
+    pub async fn shutdown(&self) {
+        self.cancel.cancel();
+        self.stopped.cancelled().await;
+    }
+}
+
+/// Run the enabled maintenance tasks in the background, each on its own schedule. A task's first
+/// run happens after the jitter delay, and a failed run is logged and retried on schedule.
+pub fn spawn_maintenance(
+    pool: Arc<PgPool>,
+    config: MaintenanceConfig,
+) -> (Maintenance, JoinHandle<()>) {
+    spawn_maintenance_with_cancellation(pool, config, CancellationToken::new())
+}
+
+/// Like `spawn_maintenance`, but the runner also stops, after finishing runs in progress, once
+/// `cancel` is cancelled. Share the host app's shutdown token to stop it with everything else.
+pub fn spawn_maintenance_with_cancellation(
+    pool: Arc<PgPool>,
+    config: MaintenanceConfig,
+    cancel: CancellationToken,
+) -> (Maintenance, JoinHandle<()>) {
+    let jitter = config.jitter;
+    let coordinate = config.coordinate;
+    let tasks = config.into_tasks();
+    let metrics = Arc::new(Mutex::new(
+        tasks
+            .iter()
+            .map(|task| (task.name.clone(), TaskMetrics::default()))
+            .collect::<BTreeMap<_, _>>(),
+    ));
+    let cancel = cancel.child_token();
+    let stopped = CancellationToken::new();
+
+    let runs: Vec<_> = tasks
+        .into_iter()
+        .map(|task| {
+            run_task(
+                pool.clone(),
+                task,
+                jitter,
+                coordinate,
+                metrics.clone(),
+                cancel.clone(),
+            )
+        })
+        .collect();
+    // Dropped when the runner ends, even by a task panicking, so `shutdown` cannot hang.
+    let stopped_guard = stopped.clone().drop_guard();
+    let handle = tokio::spawn(async move {
+        let _stopped_guard = stopped_guard;
+        join_all(runs).await;
+    });
+
+    (
+        Maintenance {
+            metrics,
+            cancel,
+            stopped,
+        },
+        handle,
+    )
+}
+
+async fn run_task(
+    pool: Arc<PgPool>,
+    task: MaintenanceTask,
+    jitter: Duration,
+    coordinate: bool,
+    metrics: Arc<Mutex<BTreeMap<String, TaskMetrics>>>,
+    cancel: CancellationToken,
+) {
+    let mut delay = random_delay(jitter);
+    loop {
+        tokio::select! {
+            _ = tokio::time::sleep(delay) => {}
+            _ = cancel.cancelled() => return,
+        }
+
+        delay = task.schedule.every + random_delay(jitter);
+        let started = Instant::now();
+        let Some(result) = run_once(&pool, &task, coordinate).await else {
+            tracing::debug!("Maintenance task {} ran elsewhere; skipping", task.name);
+            if let Ok(mut metrics) = metrics.lock() {
+                metrics.entry(task.name.clone()).or_default().skipped += 1;
+            }
+            continue;
+        };
+        let elapsed = started.elapsed();
+        match &result {
+            Ok(affected) => tracing::info!(
+                "Maintenance task {} affected {} rows in {:?}",
+                task.name,
+                affected,
+                elapsed
+            ),
+            Err(err) => tracing::error!("Maintenance task {} failed: {}", task.name, err),
+        }
+        if let Ok(mut metrics) = metrics.lock() {
+            let entry = metrics.entry(task.name.clone()).or_default();
+            entry.runs += 1;
+            entry.last_run_at = Some(chrono::Utc::now().naive_utc());
+            entry.last_duration_ms = Some(elapsed.as_millis() as u64);
+            match result {
+                Ok(affected) => {
+                    entry.last_affected = affected;
+                    entry.total_affected += affected;
+                    entry.last_error = None;
+                }
+                Err(err) => {
+                    entry.failures += 1;
+                    entry.last_error = Some(err);
+                }
+            }
+        }
+    }
+}
+
+/// Run the task unless another replica is running it or ran it within its interval.
+async fn run_once(
+    pool: &Arc<PgPool>,
+    task: &MaintenanceTask,
+    coordinate: bool,
+) -> Option<TaskResult> {
+    if !coordinate {
+        return Some((task.run)(pool.clone()).await);
+    }
+    let key = format!("maintenance:{}", task.name);
+    let outcome = with_leadership(pool, &key, async {
+        match claim_run(pool, &task.name, task.schedule.every).await {
+            Ok(true) => Some((task.run)(pool.clone()).await),
+            Ok(false) => None,
+            Err(err) => Some(Err(err.to_string())),
+        }
+    })
+    .await;
+    match outcome {
+        Ok(ran) => ran.flatten(),
+        Err(err) => Some(Err(err.to_string())),
+    }
+}
+
+/// Record a run of `task` unless one started less than `every` ago.
+async fn claim_run(pool: &PgPool, task: &str, every: Duration) -> Result<bool, sqlx::Error> {
+    let now = chrono::Utc::now().naive_utc();
+    let due_before = chrono::Duration::from_std(every)
+        .ok()
+        .and_then(|every| now.checked_sub_signed(every))
+        .unwrap_or(NaiveDateTime::MIN);
+    let claimed = sqlx::query(
+        r#"
+        INSERT INTO auth.maintenance_runs (task, last_run_at)
+        VALUES ($1, $2)
+        ON CONFLICT (task) DO UPDATE
+        SET last_run_at = EXCLUDED.last_run_at
+        WHERE auth.maintenance_runs.last_run_at <= $3
+        "#,
+    )
+    .bind(task)
+    .bind(now)
+    .bind(due_before)
+    .execute(pool)
+    .await?
+    .rows_affected();
+
+    Ok(claimed > 0)
+}
+
+/// Advisory locks taken by this crate use this as their first key, keeping them apart from
+/// single-key locks the app takes itself.
+const ADVISORY_LOCK_NAMESPACE: i32 = 0x6175_7468;
+
+/// Run `fut` only if this process can take the Postgres advisory lock for `key`, holding it until
+/// `fut` completes. Returns `None` without running `fut` when another session holds the lock,
+/// e.g. another replica running the same job.
+///
+/// The lock lives on a dedicated pool connection. If `fut` is cancelled or panics, that
+/// connection is closed rather than returned to the pool, which releases the lock.
+pub async fn with_leadership<F, T>(
+    pool: &PgPool,
+    key: &str,
+    fut: F,
+) -> Result<Option<T>, sqlx::Error>
+where
+    F: future::Future<Output = T>,
+{
+    let mut conn = pool.acquire().await?;
+    let acquired: bool = sqlx::query_scalar("SELECT pg_try_advisory_lock($1, hashtext($2))")
+        .bind(ADVISORY_LOCK_NAMESPACE)
+        .bind(key)
+        .fetch_one(&mut *conn)
+        .await?;
+    if !acquired {
+        return Ok(None);
+    }
+
+    let mut lock = LeaderLock(Some(conn));
+    let output = fut.await;
+    if let Some(mut conn) = lock.0.take() {
+        let unlocked = sqlx::query("SELECT pg_advisory_unlock($1, hashtext($2))")
+            .bind(ADVISORY_LOCK_NAMESPACE)
+            .bind(key)
+            .execute(&mut *conn)
+            .await;
+        if let Err(err) = unlocked {
+            tracing::warn!("Failed to release advisory lock {}: {}", key, err);
+            drop(conn.detach());
+        }
+    }
+    Ok(Some(output))
+}
+
+/// Connection holding an advisory lock; closed instead of pooled if dropped while still held.
+struct LeaderLock(Option<PoolConnection<Postgres>>);
+
+impl Drop for LeaderLock {
+    fn drop(&mut self) {
+        if let Some(conn) = self.0.take() {
+            drop(conn.detach());
+        }
+    }
+}
+
+fn random_delay(max: Duration) -> Duration {
+    let max_nanos = max.as_nanos();
+    if max_nanos == 0 {
+        return Duration::ZERO;
+    }
+    Duration::from_nanos((Uuid::new_v4().as_u128() % max_nanos) as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::time::Duration;
+
+    use sqlx::PgPool;
+
+    use super::{MaintenanceConfig, TaskSchedule, random_delay, spawn_maintenance};
+
+    #[test]
+    fn random_delay_stays_below_jitter() {
+        assert_eq!(random_delay(Duration::ZERO), Duration::ZERO);
+        for _ in 0..100 {
+            assert!(random_delay(Duration::from_millis(5)) < Duration::from_millis(5));
+        }
+    }
+
+    #[tokio::test]
+    async fn runs_enabled_tasks_and_records_metrics() {
+        let pool = Arc::new(PgPool::connect_lazy("postgres://localhost/unused").unwrap());
+        let calls = Arc::new(AtomicU64::new(0));
+        let counter = calls.clone();
+        let config = MaintenanceConfig {
+            jitter: Duration::from_millis(1),
+            coordinate: false,
+            ..MaintenanceConfig::default()
+        }
+        .with_task(
+            "count",
+            TaskSchedule::every(Duration::from_millis(5)),
+            move |_pool| {
+                let counter = counter.clone();
+                async move { Ok(counter.fetch_add(1, Ordering::SeqCst) + 1) }
+            },
+        )
+        .with_task(
+            "skipped",
+            TaskSchedule::disabled(Duration::from_millis(5)),
+            |_pool| async { Err("should not run".to_string()) },
+        );
+
+        let (maintenance, task) = spawn_maintenance(pool, config);
+        while calls.load(Ordering::SeqCst) < 3 {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        maintenance.shutdown().await;
+        task.await.unwrap();
+
+        let metrics = maintenance.metrics();
+        assert_eq!(metrics.keys().collect::<Vec<_>>(), vec!["count"]);
+        assert!(metrics["count"].runs >= 3);
+        assert_eq!(metrics["count"].failures, 0);
+    }
+}
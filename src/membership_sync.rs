@@ -0,0 +1,391 @@
+//! Declarative group membership: make a group's members exactly a desired list, e.g. from an HR
+//! system, instead of adding and removing them one call at a time.
+//!
+//! `MembershipDiff::between` works out the members to add, to remove and whose role changes;
+//! `sync_group_members` applies it in one transaction, granting and releasing the group's default
+//! roles as single joins and leaves do, and logs each change with `"source": "sync"`.
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+use serde::Serialize;
+use serde_json::json;
+use sqlx::{PgConnection, PgPool};
+
+use crate::db::{
+    GROUP_ADMIN_ROLE, GroupMembershipRow, apply_group_default_roles, insert_audit_log,
+    release_group_default_roles,
+};
+use crate::group_id::GroupId;
+use crate::user_id::UserId;
+
+/// A member and their role in the group.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct MemberRole {
+    pub user_id: UserId,
+    pub role_name: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct MemberRoleChange {
+    pub user_id: UserId,
+    pub from: String,
+    pub to: String,
+}
+
+/// What a sync changes, or changed. Each list is ordered by user id.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct MembershipDiff {
+    pub added: Vec<MemberRole>,
+    pub removed: Vec<MemberRole>,
+    pub role_changes: Vec<MemberRoleChange>,
+}
+
+impl MembershipDiff {
+    /// Changes that turn `current` into `desired`. `desired` must not repeat a user.
+    pub fn between(current: &[GroupMembershipRow], desired: &[MemberRole]) -> Self {
+        let current_roles: HashMap<UserId, &str> = current
+            .iter()
+            .map(|row| (row.user_id, row.role_name.as_str()))
+            .collect();
+        let desired_users: HashSet<UserId> = desired.iter().map(|member| member.user_id).collect();
+
+        let mut diff = Self::default();
+        for member in desired {
+            match current_roles.get(&member.user_id) {
+                None => diff.added.push(member.clone()),
+                Some(role_name) if *role_name != member.role_name => {
+                    diff.role_changes.push(MemberRoleChange {
+                        user_id: member.user_id,
+                        from: role_name.to_string(),
+                        to: member.role_name.clone(),
+                    })
+                }
+                Some(_) => {}
+            }
+        }
+        diff.removed = current
+            .iter()
+            .filter(|row| !desired_users.contains(&row.user_id))
+            .map(|row| MemberRole {
+                user_id: row.user_id,
+                role_name: row.role_name.clone(),
+            })
+            .collect();
+        diff.added.sort_by_key(|member| member.user_id.0);
+        diff.removed.sort_by_key(|member| member.user_id.0);
+        diff.role_changes.sort_by_key(|change| change.user_id.0);
+        diff
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.role_changes.is_empty()
+    }
+
+    /// Every user the sync adds, removes or changes the role of.
+    pub fn affected_users(&self) -> Vec<UserId> {
+        self.added
+            .iter()
+            .map(|member| member.user_id)
+            .chain(self.removed.iter().map(|member| member.user_id))
+            .chain(self.role_changes.iter().map(|change| change.user_id))
+            .collect()
+    }
+}
+
+/// A sync that was not applied.
+#[derive(Debug)]
+pub enum MembershipSyncError {
+    /// The group does not exist or is soft-deleted.
+    GroupNotFound,
+    /// The group was archived at this time and its members cannot change.
+    Archived(chrono::NaiveDateTime),
+    /// The desired list names a user more than once.
+    DuplicateMember(UserId),
+    /// The desired list names users that do not exist.
+    UnknownUsers(Vec<UserId>),
+    /// The desired list has no admin; a group always keeps one.
+    NoAdmin,
+    Database(sqlx::Error),
+}
+
+impl fmt::Display for MembershipSyncError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::GroupNotFound => write!(f, "Group not found"),
+            Self::Archived(_) => write!(f, "Group is archived"),
+            Self::DuplicateMember(user_id) => write!(f, "User {} is listed twice", user_id),
+            Self::UnknownUsers(user_ids) => {
+                let user_ids: Vec<String> = user_ids.iter().map(UserId::to_string).collect();
+                write!(f, "Unknown users: {}", user_ids.join(", "))
+            }
+            Self::NoAdmin => write!(f, "The group must keep at least one {}", GROUP_ADMIN_ROLE),
+            Self::Database(err) => write!(f, "Database error: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for MembershipSyncError {}
+
+impl From<sqlx::Error> for MembershipSyncError {
+    fn from(err: sqlx::Error) -> Self {
+        Self::Database(err)
+    }
+}
+
+/// Check a desired member list before diffing it.
+pub fn check_desired(desired: &[MemberRole]) -> Result<(), MembershipSyncError> {
+    let mut seen = HashSet::new();
+    for member in desired {
+        if !seen.insert(member.user_id) {
+            return Err(MembershipSyncError::DuplicateMember(member.user_id));
+        }
+    }
+    if !desired
+        .iter()
+        .any(|member| member.role_name == GROUP_ADMIN_ROLE)
+    {
+        return Err(MembershipSyncError::NoAdmin);
+    }
+    Ok(())
+}
+
+/// What `sync_group_members` would change now, without changing it.
+pub async fn preview_membership_sync(
+    pool: &PgPool,
+    group_id: GroupId,
+    desired: &[MemberRole],
+) -> Result<MembershipDiff, MembershipSyncError> {
+    check_desired(desired)?;
+    let mut conn = pool.acquire().await?;
+    lock_group(&mut conn, group_id, false).await?;
+    check_users_exist(&mut conn, desired).await?;
+    let current = current_members(&mut conn, group_id, false).await?;
+    Ok(MembershipDiff::between(&current, desired))
+}
+
+/// Make the group's members exactly `desired`, in one transaction. Returns what changed.
+pub async fn sync_group_members(
+    pool: &PgPool,
+    actor_user_id: UserId,
+    group_id: GroupId,
+    desired: &[MemberRole],
+) -> Result<MembershipDiff, MembershipSyncError> {
+    check_desired(desired)?;
+    let mut tx = pool.begin().await?;
+    // Serializes syncs of the group with each other.
+    lock_group(&mut tx, group_id, true).await?;
+    check_users_exist(&mut tx, desired).await?;
+    let current = current_members(&mut tx, group_id, true).await?;
+    let diff = MembershipDiff::between(&current, desired);
+
+    for member in &diff.removed {
+        sqlx::query(
+            r#"
+            DELETE FROM auth.group_memberships
+            WHERE group_id = $1 AND user_id = $2
+            "#,
+        )
+        .bind(group_id)
+        .bind(member.user_id)
+        .execute(&mut *tx)
+        .await?;
+        release_group_default_roles(&mut tx, None, group_id, Some(member.user_id), None).await?;
+        log(
+            &mut tx,
+            actor_user_id,
+            json!({
+                "type": "group_leave",
+                "group_id": group_id.to_string(),
+                "user_id": member.user_id.to_string(),
+                "source": "sync",
+            }),
+        )
+        .await?;
+    }
+    for change in &diff.role_changes {
+        sqlx::query(
+            r#"
+            UPDATE auth.group_memberships
+            SET role_name = $3
+            WHERE group_id = $1 AND user_id = $2
+            "#,
+        )
+        .bind(group_id)
+        .bind(change.user_id)
+        .bind(&change.to)
+        .execute(&mut *tx)
+        .await?;
+        log(
+            &mut tx,
+            actor_user_id,
+            json!({
+                "type": "group_member_role_changed",
+                "group_id": group_id.to_string(),
+                "user_id": change.user_id.to_string(),
+                "role_name": change.to,
+                "source": "sync",
+            }),
+        )
+        .await?;
+    }
+    for member in &diff.added {
+        sqlx::query(
+            r#"
+            INSERT INTO auth.group_memberships (group_id, user_id, role_name)
+            VALUES ($1, $2, $3)
+            "#,
+        )
+        .bind(group_id)
+        .bind(member.user_id)
+        .bind(&member.role_name)
+        .execute(&mut *tx)
+        .await?;
+        apply_group_default_roles(&mut tx, None, group_id, Some(member.user_id), None).await?;
+        log(
+            &mut tx,
+            actor_user_id,
+            json!({
+                "type": "group_join",
+                "group_id": group_id.to_string(),
+                "user_id": member.user_id.to_string(),
+                "role_name": member.role_name,
+                "source": "sync",
+            }),
+        )
+        .await?;
+    }
+
+    tx.commit().await?;
+    Ok(diff)
+}
+
+async fn lock_group(
+    conn: &mut PgConnection,
+    group_id: GroupId,
+    for_update: bool,
+) -> Result<(), MembershipSyncError> {
+    let query = if for_update {
+        "SELECT archived_at FROM auth.groups WHERE id = $1 AND deleted_at IS NULL FOR UPDATE"
+    } else {
+        "SELECT archived_at FROM auth.groups WHERE id = $1 AND deleted_at IS NULL"
+    };
+    let archived_at: Option<chrono::NaiveDateTime> = sqlx::query_scalar(query)
+        .bind(group_id)
+        .fetch_optional(&mut *conn)
+        .await?
+        .ok_or(MembershipSyncError::GroupNotFound)?;
+    match archived_at {
+        Some(archived_at) => Err(MembershipSyncError::Archived(archived_at)),
+        None => Ok(()),
+    }
+}
+
+async fn check_users_exist(
+    conn: &mut PgConnection,
+    desired: &[MemberRole],
+) -> Result<(), MembershipSyncError> {
+    let wanted: Vec<UserId> = desired.iter().map(|member| member.user_id).collect();
+    let found: Vec<(UserId,)> =
+        sqlx::query_as("SELECT id FROM auth.users WHERE id = ANY($1) AND deleted_at IS NULL")
+            .bind(&wanted)
+            .fetch_all(&mut *conn)
+            .await?;
+    let found: HashSet<UserId> = found.into_iter().map(|(user_id,)| user_id).collect();
+    let mut unknown: Vec<UserId> = wanted
+        .into_iter()
+        .filter(|user_id| !found.contains(user_id))
+        .collect();
+    if unknown.is_empty() {
+        return Ok(());
+    }
+    unknown.sort_by_key(|user_id| user_id.0);
+    Err(MembershipSyncError::UnknownUsers(unknown))
+}
+
+async fn current_members(
+    conn: &mut PgConnection,
+    group_id: GroupId,
+    for_update: bool,
+) -> Result<Vec<GroupMembershipRow>, sqlx::Error> {
+    let query = if for_update {
+        r#"
+        SELECT group_id, user_id, role_name
+        FROM auth.group_memberships
+        WHERE group_id = $1
+        FOR UPDATE
+        "#
+    } else {
+        r#"
+        SELECT group_id, user_id, role_name
+        FROM auth.group_memberships
+        WHERE group_id = $1
+        "#
+    };
+    sqlx::query_as::<_, GroupMembershipRow>(query)
+        .bind(group_id)
+        .fetch_all(&mut *conn)
+        .await
+}
+
+async fn log(
+    conn: &mut PgConnection,
+    actor_user_id: UserId,
+    action: serde_json::Value,
+) -> Result<(), sqlx::Error> {
+    insert_audit_log(&mut *conn, Some(actor_user_id), action).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use uuid::Uuid;
+
+    use super::*;
+
+    fn member(user_id: UserId, role_name: &str) -> MemberRole {
+        MemberRole {
+            user_id,
+            role_name: role_name.to_string(),
+        }
+    }
+
+    #[test]
+    fn diff_adds_removes_and_changes_roles() {
+        let group_id = GroupId(Uuid::new_v4());
+        let [kept, promoted, dropped, hired] = [(); 4].map(|()| UserId(Uuid::new_v4()));
+        let current = vec![
+            GroupMembershipRow::new(group_id, kept, GROUP_ADMIN_ROLE),
+            GroupMembershipRow::new(group_id, promoted, "member"),
+            GroupMembershipRow::new(group_id, dropped, "member"),
+        ];
+        let desired = vec![
+            member(kept, GROUP_ADMIN_ROLE),
+            member(promoted, GROUP_ADMIN_ROLE),
+            member(hired, "member"),
+        ];
+        assert!(check_desired(&desired).is_ok());
+
+        let diff = MembershipDiff::between(&current, &desired);
+        assert_eq!(diff.added, vec![member(hired, "member")]);
+        assert_eq!(diff.removed, vec![member(dropped, "member")]);
+        assert_eq!(
+            diff.role_changes,
+            vec![MemberRoleChange {
+                user_id: promoted,
+                from: "member".to_string(),
+                to: GROUP_ADMIN_ROLE.to_string(),
+            }]
+        );
+        assert!(MembershipDiff::between(&current[..1], &desired[..1]).is_empty());
+
+        assert!(matches!(
+            check_desired(&[member(kept, GROUP_ADMIN_ROLE), member(kept, "member")]),
+            Err(MembershipSyncError::DuplicateMember(user_id)) if user_id == kept
+        ));
+        assert!(matches!(
+            check_desired(&[member(kept, "member")]),
+            Err(MembershipSyncError::NoAdmin)
+        ));
+    }
+}
@@ -0,0 +1,3 @@
+//! Moving users into the auth schema from other systems.
+
+pub mod import;
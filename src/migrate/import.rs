@@ -0,0 +1,841 @@
+//! Bulk import of users from a legacy export.
+//!
+//! Records are read from CSV or NDJSON and written in batched transactions. Each record runs in
+//! its own savepoint, so a conflicting record is skipped and reported without losing the rest of
+//! its batch. Progress is checkpointed in `auth.import_runs` after every batch; running the same
+//! import again under the same run name resumes after the last committed batch.
+//!
+//! CSV exports use the columns `email`, `username`, `password_hash`, `groups` and `roles`, with
+//! `;`-separated lists. NDJSON exports use the same field names with arrays for the lists.
+//! Roles are either an access role name (`admin`) or a scoped role (`scope:scope_id:role_name`).
+
+use std::fmt;
+use std::io::{BufRead, BufReader, Read};
+#[cfg(feature = "api")]
+use std::sync::Arc;
+
+use email_address::EmailAddress;
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use sqlx::{PgConnection, PgPool};
+use uuid::Uuid;
+
+#[cfg(feature = "api")]
+use crate::api::User;
+use crate::db::{
+    GLOBAL_SCOPE, GLOBAL_SCOPE_ID, UserRow, apply_group_default_roles, insert_audit_log,
+};
+use crate::environment::validate_scope;
+use crate::external_id::DEFAULT_EXTERNAL_ID_GENERATOR;
+use crate::group_id::GroupId;
+#[cfg(feature = "api")]
+use crate::hooks::{Hooks, NewUser};
+use crate::ids::new_uuid;
+use crate::password::HashScheme;
+use crate::redact::Sensitive;
+use crate::user_id::UserId;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImportFormat {
+    Csv,
+    Ndjson,
+}
+
+/// A role from the legacy export.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LegacyRole {
+    pub scope: String,
+    pub scope_id: String,
+    pub role_name: String,
+}
+
+impl LegacyRole {
+    /// Parse `role_name`, a global access role, or `scope:scope_id:role_name`.
+    pub fn parse(value: &str) -> Option<Self> {
+        let parts: Vec<&str> = value.trim().split(':').collect();
+        let (scope, scope_id, role_name) = match parts.as_slice() {
+            [role_name] => (GLOBAL_SCOPE, GLOBAL_SCOPE_ID, *role_name),
+            [scope, scope_id, role_name] => (*scope, *scope_id, *role_name),
+            _ => return None,
+        };
+        if validate_scope(scope).is_err() || scope_id.is_empty() || role_name.is_empty() {
+            return None;
+        }
+        Some(Self {
+            scope: scope.to_string(),
+            scope_id: scope_id.to_string(),
+            role_name: role_name.to_string(),
+        })
+    }
+}
+
+/// One user from the legacy export.
+#[derive(Clone, Default, Deserialize)]
+pub struct LegacyUser {
+    pub email: String,
+    #[serde(default)]
+    pub username: Option<String>,
+    /// Hash in a scheme `HashScheme::identify` recognizes, e.g. bcrypt `$2b$...`.
+    #[serde(default)]
+    pub password_hash: Option<String>,
+    /// Group display names. Missing groups are created unless disabled in `ImportOptions`.
+    #[serde(default)]
+    pub groups: Vec<String>,
+    #[serde(default)]
+    pub roles: Vec<String>,
+    #[serde(default)]
+    pub details: Option<Value>,
+}
+
+impl fmt::Debug for LegacyUser {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LegacyUser")
+            .field("email", &Sensitive(&self.email))
+            .field("username", &self.username.as_ref().map(Sensitive))
+            .field("password_hash", &self.password_hash.as_ref().map(Sensitive))
+            .field("groups", &self.groups)
+            .field("roles", &self.roles)
+            .field("details", &self.details.as_ref().map(Sensitive))
+            .finish()
+    }
+}
+
+#[derive(Deserialize)]
+struct CsvRecord {
+    email: String,
+    #[serde(default)]
+    username: Option<String>,
+    #[serde(default)]
+    password_hash: Option<String>,
+    #[serde(default)]
+    groups: Option<String>,
+    #[serde(default)]
+    roles: Option<String>,
+}
+
+fn split_list(value: Option<String>) -> Vec<String> {
+    value
+        .unwrap_or_default()
+        .split(';')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+fn non_empty(value: Option<String>) -> Option<String> {
+    value
+        .map(|value| value.trim().to_string())
+        .filter(|value| !value.is_empty())
+}
+
+impl From<CsvRecord> for LegacyUser {
+    fn from(record: CsvRecord) -> Self {
+        Self {
+            email: record.email.trim().to_string(),
+            username: non_empty(record.username),
+            password_hash: non_empty(record.password_hash),
+            groups: split_list(record.groups),
+            roles: split_list(record.roles),
+            details: None,
+        }
+    }
+}
+
+/// Why a record was skipped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ImportConflictKind {
+    /// The record could not be parsed.
+    Malformed,
+    InvalidEmail,
+    EmailTaken,
+    UsernameTaken,
+    UnsupportedPasswordHash,
+    InvalidRole,
+    /// The record names a group that does not exist and group creation is disabled.
+    UnknownGroup,
+    /// `Hooks::before_user_create` vetoed the user.
+    Rejected,
+}
+
+impl ImportConflictKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Malformed => "malformed",
+            Self::InvalidEmail => "invalid_email",
+            Self::EmailTaken => "email_taken",
+            Self::UsernameTaken => "username_taken",
+            Self::UnsupportedPasswordHash => "unsupported_password_hash",
+            Self::InvalidRole => "invalid_role",
+            Self::UnknownGroup => "unknown_group",
+            Self::Rejected => "rejected",
+        }
+    }
+
+    fn from_str(value: &str) -> Self {
+        match value {
+            "invalid_email" => Self::InvalidEmail,
+            "email_taken" => Self::EmailTaken,
+            "username_taken" => Self::UsernameTaken,
+            "unsupported_password_hash" => Self::UnsupportedPasswordHash,
+            "invalid_role" => Self::InvalidRole,
+            "unknown_group" => Self::UnknownGroup,
+            "rejected" => Self::Rejected,
+            _ => Self::Malformed,
+        }
+    }
+}
+
+#[derive(Clone, Serialize)]
+pub struct ImportConflict {
+    /// 1-based position of the record in the input, header excluded.
+    pub record: i64,
+    pub email: Option<String>,
+    pub kind: ImportConflictKind,
+    pub message: String,
+}
+
+impl fmt::Debug for ImportConflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ImportConflict")
+            .field("record", &self.record)
+            .field("email", &self.email.as_ref().map(Sensitive))
+            .field("kind", &self.kind)
+            .field("message", &self.message)
+            .finish()
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ImportReport {
+    pub run_name: String,
+    pub imported: i64,
+    pub last_record: i64,
+    pub finished: bool,
+    /// The import ran in a transaction that was rolled back; nothing was written.
+    pub dry_run: bool,
+    pub conflicts: Vec<ImportConflict>,
+}
+
+impl ImportReport {
+    /// Current state of an import run, including conflicts from earlier attempts.
+    pub async fn load(pool: &PgPool, run_name: &str) -> Result<Option<Self>, sqlx::Error> {
+        let mut conn = pool.acquire().await?;
+        Self::load_from(&mut conn, run_name).await
+    }
+
+    async fn load_from(
+        conn: &mut PgConnection,
+        run_name: &str,
+    ) -> Result<Option<Self>, sqlx::Error> {
+        let run: Option<(i64, i64, Option<chrono::NaiveDateTime>)> = sqlx::query_as(
+            r#"
+            SELECT last_record, imported, finished_at
+            FROM auth.import_runs
+            WHERE name = $1
+            "#,
+        )
+        .bind(run_name)
+        .fetch_optional(&mut *conn)
+        .await?;
+        let Some((last_record, imported, finished_at)) = run else {
+            return Ok(None);
+        };
+
+        let conflicts: Vec<(i64, Option<String>, String, String)> = sqlx::query_as(
+            r#"
+            SELECT record, email, kind, message
+            FROM auth.import_conflicts
+            WHERE run_name = $1
+            ORDER BY record
+            "#,
+        )
+        .bind(run_name)
+        .fetch_all(&mut *conn)
+        .await?;
+        Ok(Some(Self {
+            run_name: run_name.to_string(),
+            imported,
+            last_record,
+            finished: finished_at.is_some(),
+            dry_run: false,
+            conflicts: conflicts
+                .into_iter()
+                .map(|(record, email, kind, message)| ImportConflict {
+                    record,
+                    email,
+                    kind: ImportConflictKind::from_str(&kind),
+                    message,
+                })
+                .collect(),
+        }))
+    }
+}
+
+#[derive(Debug)]
+pub enum ImportError {
+    /// The input could not be read. Malformed records are reported as conflicts instead.
+    Read(String),
+    Database(sqlx::Error),
+}
+
+impl fmt::Display for ImportError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Read(msg) => write!(f, "Failed to read import: {}", msg),
+            Self::Database(err) => write!(f, "Database error: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for ImportError {}
+
+impl From<sqlx::Error> for ImportError {
+    fn from(err: sqlx::Error) -> Self {
+        Self::Database(err)
+    }
+}
+
+#[derive(Clone)]
+pub struct ImportOptions {
+    run_name: String,
+    batch_size: usize,
+    create_missing_groups: bool,
+    group_role: String,
+    dry_run: bool,
+    #[cfg(feature = "api")]
+    hooks: Option<Arc<dyn Hooks>>,
+}
+
+impl fmt::Debug for ImportOptions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ImportOptions")
+            .field("run_name", &self.run_name)
+            .field("batch_size", &self.batch_size)
+            .field("create_missing_groups", &self.create_missing_groups)
+            .field("group_role", &self.group_role)
+            .field("dry_run", &self.dry_run)
+            .finish_non_exhaustive()
+    }
+}
+
+impl ImportOptions {
+    /// `run_name` identifies the run for resuming; reuse it to continue an interrupted import.
+    pub fn new<S: Into<String>>(run_name: S) -> Self {
+        Self {
+            run_name: run_name.into(),
+            batch_size: 500,
+            create_missing_groups: true,
+            group_role: "member".to_string(),
+            dry_run: false,
+            #[cfg(feature = "api")]
+            hooks: None,
+        }
+    }
+
+    pub fn with_batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = batch_size.max(1);
+        self
+    }
+
+    /// Skip records naming groups that do not exist instead of creating them.
+    pub fn with_create_missing_groups(mut self, create_missing_groups: bool) -> Self {
+        self.create_missing_groups = create_missing_groups;
+        self
+    }
+
+    /// Membership role given to imported users in their groups. Defaults to `member`.
+    pub fn with_group_role<S: Into<String>>(mut self, group_role: S) -> Self {
+        self.group_role = group_role.into();
+        self
+    }
+
+    /// Run the whole import in one transaction and roll it back, reporting the records that
+    /// would be imported and the conflicts that would be recorded.
+    pub fn with_dry_run(mut self, dry_run: bool) -> Self {
+        self.dry_run = dry_run;
+        self
+    }
+
+    /// Run the app's user creation hooks for imported users, as the `/auth` handlers do. A veto
+    /// skips the record with an `ImportConflictKind::Rejected` conflict; `after_user_create` runs
+    /// once the record's batch is committed, and never on a dry run.
+    #[cfg(feature = "api")]
+    pub fn with_hooks<H: Hooks + 'static>(mut self, hooks: H) -> Self {
+        self.hooks = Some(Arc::new(hooks));
+        self
+    }
+}
+
+type Records<'r> = Box<dyn Iterator<Item = Result<LegacyUser, RecordError>> + Send + 'r>;
+
+enum RecordError {
+    Malformed(String),
+    Read(String),
+}
+
+fn records<'r, R: Read + Send + 'r>(reader: R, format: ImportFormat) -> Records<'r> {
+    match format {
+        ImportFormat::Csv => Box::new(
+            csv::ReaderBuilder::new()
+                .trim(csv::Trim::Headers)
+                .from_reader(reader)
+                .into_deserialize::<CsvRecord>()
+                .map(|record| {
+                    record
+                        .map(LegacyUser::from)
+                        .map_err(|err| match err.kind() {
+                            csv::ErrorKind::Io(_) => RecordError::Read(err.to_string()),
+                            _ => RecordError::Malformed(err.to_string()),
+                        })
+                }),
+        ),
+        ImportFormat::Ndjson => Box::new(
+            BufReader::new(reader)
+                .lines()
+                .filter(|line| !matches!(line, Ok(line) if line.trim().is_empty()))
+                .map(|line| {
+                    let line = line.map_err(|err| RecordError::Read(err.to_string()))?;
+                    serde_json::from_str(&line)
+                        .map_err(|err| RecordError::Malformed(err.to_string()))
+                }),
+        ),
+    }
+}
+
+/// Import users from `reader` into the auth schema.
+///
+/// Returns the run's report, including conflicts from earlier attempts of the same run. A run that
+/// already finished is not imported again.
+pub async fn import<R: Read + Send>(
+    pool: &PgPool,
+    reader: R,
+    format: ImportFormat,
+    options: &ImportOptions,
+) -> Result<ImportReport, ImportError> {
+    if options.dry_run {
+        // Batches become savepoints of this transaction, so their checkpoints roll back too.
+        let mut tx = pool.begin().await?;
+        let mut report = run_import(&mut tx, reader, format, options).await?;
+        tx.rollback().await?;
+        report.dry_run = true;
+        Ok(report)
+    } else {
+        let mut conn = pool.acquire().await?;
+        run_import(&mut conn, reader, format, options).await
+    }
+}
+
+async fn run_import<R: Read + Send>(
+    conn: &mut PgConnection,
+    reader: R,
+    format: ImportFormat,
+    options: &ImportOptions,
+) -> Result<ImportReport, ImportError> {
+    sqlx::query(
+        r#"
+        INSERT INTO auth.import_runs (name)
+        VALUES ($1)
+        ON CONFLICT (name) DO NOTHING
+        "#,
+    )
+    .bind(&options.run_name)
+    .execute(&mut *conn)
+    .await?;
+    let (resume_after, finished): (i64, bool) = sqlx::query_as(
+        r#"
+        SELECT last_record, finished_at IS NOT NULL
+        FROM auth.import_runs
+        WHERE name = $1
+        "#,
+    )
+    .bind(&options.run_name)
+    .fetch_one(&mut *conn)
+    .await?;
+
+    if !finished {
+        if resume_after > 0 {
+            tracing::info!(
+                "Resuming import {} after record {}",
+                options.run_name,
+                resume_after
+            );
+        }
+        let mut records = records(reader, format)
+            .enumerate()
+            .map(|(index, record)| (index as i64 + 1, record))
+            .skip_while(|(record, _)| *record <= resume_after)
+            .peekable();
+        while records.peek().is_some() {
+            let batch: Vec<_> = records.by_ref().take(options.batch_size).collect();
+            import_batch(conn, batch, options).await?;
+        }
+
+        sqlx::query(
+            r#"
+            UPDATE auth.import_runs
+            SET finished_at = $2,
+                updated_at = $2
+            WHERE name = $1
+            "#,
+        )
+        .bind(&options.run_name)
+        .bind(chrono::Utc::now().naive_utc())
+        .execute(&mut *conn)
+        .await?;
+    }
+
+    ImportReport::load_from(conn, &options.run_name)
+        .await?
+        .ok_or_else(|| ImportError::Database(sqlx::Error::RowNotFound))
+}
+
+async fn import_batch(
+    conn: &mut PgConnection,
+    batch: Vec<(i64, Result<LegacyUser, RecordError>)>,
+    options: &ImportOptions,
+) -> Result<(), ImportError> {
+    let Some(last_record) = batch.last().map(|(record, _)| *record) else {
+        return Ok(());
+    };
+    let mut tx = sqlx::Connection::begin(conn).await?;
+    let mut imported = 0i64;
+    let mut created = Vec::new();
+    for (record, user) in batch {
+        let user = match user {
+            Ok(user) => user,
+            Err(RecordError::Read(msg)) => return Err(ImportError::Read(msg)),
+            Err(RecordError::Malformed(msg)) => {
+                record_conflict(
+                    &mut tx,
+                    options,
+                    record,
+                    None,
+                    ImportConflictKind::Malformed,
+                    &msg,
+                )
+                .await?;
+                continue;
+            }
+        };
+
+        // Each record gets a savepoint so a conflict only discards that record's writes.
+        let mut savepoint = sqlx::Connection::begin(&mut *tx).await?;
+        match import_user(&mut savepoint, &user, options).await? {
+            Ok(row) => {
+                savepoint.commit().await?;
+                imported += 1;
+                created.push(row);
+            }
+            Err((kind, msg)) => {
+                savepoint.rollback().await?;
+                record_conflict(&mut tx, options, record, Some(&user.email), kind, &msg).await?;
+            }
+        }
+    }
+
+    sqlx::query(
+        r#"
+        UPDATE auth.import_runs
+        SET last_record = $2,
+            imported = imported + $3,
+            updated_at = $4
+        WHERE name = $1
+        "#,
+    )
+    .bind(&options.run_name)
+    .bind(last_record)
+    .bind(imported)
+    .bind(chrono::Utc::now().naive_utc())
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
+    tracing::info!(
+        "Import {}: committed through record {} ({} imported)",
+        options.run_name,
+        last_record,
+        imported
+    );
+    #[cfg(feature = "api")]
+    if !options.dry_run
+        && let Some(hooks) = &options.hooks
+    {
+        for row in created {
+            hooks.after_user_create(&User::from(row)).await;
+        }
+    }
+    Ok(())
+}
+
+async fn record_conflict(
+    conn: &mut PgConnection,
+    options: &ImportOptions,
+    record: i64,
+    email: Option<&str>,
+    kind: ImportConflictKind,
+    message: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        r#"
+        INSERT INTO auth.import_conflicts (run_name, record, email, kind, message)
+        VALUES ($1, $2, $3, $4, $5)
+        ON CONFLICT (run_name, record) DO UPDATE
+        SET email = EXCLUDED.email,
+            kind = EXCLUDED.kind,
+            message = EXCLUDED.message
+        "#,
+    )
+    .bind(&options.run_name)
+    .bind(record)
+    .bind(email)
+    .bind(kind.as_str())
+    .bind(message)
+    .execute(conn)
+    .await?;
+    Ok(())
+}
+
+type Conflict = (ImportConflictKind, String);
+
+/// Write one user and return its row. The outer `Err` aborts the batch; the inner one skips the
+/// record.
+async fn import_user(
+    conn: &mut PgConnection,
+    user: &LegacyUser,
+    options: &ImportOptions,
+) -> Result<Result<UserRow, Conflict>, sqlx::Error> {
+    if !EmailAddress::is_valid(&user.email) {
+        return Ok(Err((
+            ImportConflictKind::InvalidEmail,
+            format!("{} is not a valid email address", user.email),
+        )));
+    }
+    if let Some(hash) = &user.password_hash
+        && HashScheme::identify(hash).is_none()
+    {
+        return Ok(Err((
+            ImportConflictKind::UnsupportedPasswordHash,
+            "Password hash scheme is not supported".to_string(),
+        )));
+    }
+    let mut roles = Vec::with_capacity(user.roles.len());
+    for role in &user.roles {
+        match LegacyRole::parse(role) {
+            Some(role) => roles.push(role),
+            None => {
+                return Ok(Err((
+                    ImportConflictKind::InvalidRole,
+                    format!("Invalid role {}", role),
+                )));
+            }
+        }
+    }
+
+    let row = UserRow::new(
+        UserId::new(),
+        user.username.clone(),
+        user.email.clone(),
+        user.details.clone(),
+    );
+    let email_taken: (bool,) = sqlx::query_as(
+        r#"
+        SELECT EXISTS (
+            SELECT 1 FROM auth.users WHERE email = $1 OR email_canonical = $2
+        )
+        "#,
+    )
+    .bind(&row.email)
+    .bind(&row.email_canonical)
+    .fetch_one(&mut *conn)
+    .await?;
+    if email_taken.0 {
+        return Ok(Err((
+            ImportConflictKind::EmailTaken,
+            "A user with this email address already exists".to_string(),
+        )));
+    }
+    if let Some(username) = &row.username {
+        let username_taken: (bool,) = sqlx::query_as(
+            r#"
+            SELECT EXISTS (
+                SELECT 1 FROM auth.users
+                WHERE username_canonical = auth.username_canonical($1)
+            )
+            "#,
+        )
+        .bind(username)
+        .fetch_one(&mut *conn)
+        .await?;
+        if username_taken.0 {
+            return Ok(Err((
+                ImportConflictKind::UsernameTaken,
+                format!("Username {} is already taken", username),
+            )));
+        }
+    }
+
+    #[cfg(feature = "api")]
+    if let Some(hooks) = &options.hooks
+        && let Err(rejection) = hooks
+            .before_user_create(&NewUser {
+                id: row.id,
+                email: &row.email,
+                username: row.username.as_deref(),
+                invited: false,
+            })
+            .await
+    {
+        return Ok(Err((ImportConflictKind::Rejected, rejection.to_string())));
+    }
+
+    let inserted = sqlx::query(
+        r#"
+        INSERT INTO auth.users
+            (id, username, email, details, email_canonical, version, locale, external_id)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+        "#,
+    )
+    .bind(row.id)
+    .bind(&row.username)
+    .bind(&row.email)
+    .bind(&row.details)
+    .bind(&row.email_canonical)
+    .bind(row.version)
+    .bind(&row.locale)
+    .bind(&row.external_id)
+    .execute(&mut *conn)
+    .await;
+    match inserted {
+        Ok(_) => {}
+        Err(sqlx::Error::Database(db_err)) if db_err.is_unique_violation() => {
+            return Ok(Err((
+                ImportConflictKind::EmailTaken,
+                format!("Duplicate user: {}", db_err.message()),
+            )));
+        }
+        Err(err) => return Err(err),
+    }
+
+    if let Some(hash) = &user.password_hash {
+        sqlx::query(
+            r#"
+            INSERT INTO auth.user_passwords (user_id, password_hash)
+            VALUES ($1, $2)
+            "#,
+        )
+        .bind(row.id)
+        .bind(hash)
+        .execute(&mut *conn)
+        .await?;
+    }
+
+    for group_name in &user.groups {
+        if options.create_missing_groups {
+            sqlx::query(
+                r#"
+                INSERT INTO auth.groups (id, display_name, external_id)
+                VALUES ($1, $2, $3)
+                ON CONFLICT (display_name) DO NOTHING
+                "#,
+            )
+            .bind(new_uuid())
+            .bind(group_name)
+            .bind(DEFAULT_EXTERNAL_ID_GENERATOR.generate())
+            .execute(&mut *conn)
+            .await?;
+        }
+        let group: Option<(Uuid,)> = sqlx::query_as(
+            "SELECT id FROM auth.groups WHERE display_name = $1 AND deleted_at IS NULL",
+        )
+        .bind(group_name)
+        .fetch_optional(&mut *conn)
+        .await?;
+        let Some((group_id,)) = group else {
+            return Ok(Err((
+                ImportConflictKind::UnknownGroup,
+                format!("Group {} does not exist", group_name),
+            )));
+        };
+        sqlx::query(
+            r#"
+            INSERT INTO auth.group_memberships (group_id, user_id, role_name)
+            VALUES ($1, $2, $3)
+            ON CONFLICT (group_id, user_id) DO NOTHING
+            "#,
+        )
+        .bind(group_id)
+        .bind(row.id)
+        .bind(&options.group_role)
+        .execute(&mut *conn)
+        .await?;
+        apply_group_default_roles(&mut *conn, None, GroupId(group_id), Some(row.id), None).await?;
+    }
+
+    for role in &roles {
+        sqlx::query(
+            r#"
+            INSERT INTO auth.user_roles (user_id, scope, scope_id, role_name)
+            VALUES ($1, $2, $3, $4)
+            ON CONFLICT (user_id, scope, scope_id, role_name) DO UPDATE
+            SET source_group_id = NULL,
+                source_provider = NULL
+            "#,
+        )
+        .bind(row.id)
+        .bind(&role.scope)
+        .bind(&role.scope_id)
+        .bind(&role.role_name)
+        .execute(&mut *conn)
+        .await?;
+    }
+
+    insert_audit_log(
+        &mut *conn,
+        Some(row.id),
+        json!({
+            "type": "user_imported",
+            "run": options.run_name,
+            "groups": user.groups,
+            "roles": user.roles,
+        }),
+    )
+    .await?;
+
+    Ok(Ok(row))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ImportFormat, LegacyRole, RecordError, records};
+
+    #[test]
+    fn csv_lists_and_roles_parse() {
+        let input = "email,username,password_hash,groups,roles\n\
+                     a@example.com,alice,$2b$04$abc,Staff; Ops,admin;project:p1:editor\n\
+                     b@example.com,,,,\n";
+        let users: Vec<_> = records(input.as_bytes(), ImportFormat::Csv)
+            .map(|record| record.ok().unwrap())
+            .collect();
+        assert_eq!(users[0].groups, vec!["Staff", "Ops"]);
+        assert_eq!(users[0].roles, vec!["admin", "project:p1:editor"]);
+        assert_eq!(users[1].username, None);
+        assert_eq!(users[1].password_hash, None);
+        assert_eq!(
+            LegacyRole::parse("project:p1:editor"),
+            Some(LegacyRole {
+                scope: "project".to_string(),
+                scope_id: "p1".to_string(),
+                role_name: "editor".to_string(),
+            })
+        );
+        assert_eq!(LegacyRole::parse("a:b"), None);
+        assert_eq!(LegacyRole::parse("project@prod:p1:editor"), None);
+
+        let input = "{\"email\":\"a@example.com\",\"groups\":[\"Staff\"]}\n\nnot json\n";
+        let users: Vec<_> = records(input.as_bytes(), ImportFormat::Ndjson).collect();
+        assert_eq!(users.len(), 2);
+        assert!(matches!(&users[0], Ok(user) if user.groups == vec!["Staff"]));
+        assert!(matches!(users[1], Err(RecordError::Malformed(_))));
+    }
+}
@@ -0,0 +1,432 @@
+//! Abuse reports against users and groups, and the queue moderators work them from.
+//!
+//! Users file reports with `file_report`; each report moves `open` → `reviewing` → `actioned`
+//! or `dismissed` through `transition_report`. Evidence is kept next to the report: what the
+//! reporter sent, what `EvidenceCollector` adds when the report is filed, and anything host
+//! handlers attach later with `attach_evidence`, e.g. the chat message that was reported.
+
+use std::fmt;
+use std::str::FromStr;
+
+use futures_util::future::BoxFuture;
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use sqlx::{FromRow, PgPool};
+use uuid::Uuid;
+
+use crate::db::insert_audit_log;
+use crate::group_id::GroupId;
+use crate::ids::new_uuid;
+use crate::user_id::UserId;
+
+macro_rules! report_columns {
+    () => {
+        "id, reporter_id, subject_type, subject_id, category, message, status, created_at, \
+         updated_at, reviewer_id, resolution_note, resolved_at"
+    };
+}
+macro_rules! report_evidence_columns {
+    () => {
+        "id, report_id, kind, payload, attached_by, created_at"
+    };
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize, sqlx::Type)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(type_name = "TEXT", rename_all = "lowercase")]
+pub enum ReportStatus {
+    Open,
+    /// A moderator has picked it up.
+    Reviewing,
+    /// Action was taken against the subject.
+    Actioned,
+    Dismissed,
+}
+
+impl ReportStatus {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Open => "open",
+            Self::Reviewing => "reviewing",
+            Self::Actioned => "actioned",
+            Self::Dismissed => "dismissed",
+        }
+    }
+
+    pub fn is_resolved(self) -> bool {
+        matches!(self, Self::Actioned | Self::Dismissed)
+    }
+
+    /// Reports are reviewed before they are resolved, and stay resolved.
+    pub fn can_transition_to(self, next: ReportStatus) -> bool {
+        matches!(
+            (self, next),
+            (Self::Open, Self::Reviewing)
+                | (Self::Reviewing, Self::Actioned)
+                | (Self::Reviewing, Self::Dismissed)
+        )
+    }
+}
+
+impl fmt::Display for ReportStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for ReportStatus {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "open" => Ok(Self::Open),
+            "reviewing" => Ok(Self::Reviewing),
+            "actioned" => Ok(Self::Actioned),
+            "dismissed" => Ok(Self::Dismissed),
+            other => Err(format!("Unknown report status: {}", other)),
+        }
+    }
+}
+
+/// Who or what a report is about.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReportSubject {
+    User(UserId),
+    Group(GroupId),
+}
+
+impl ReportSubject {
+    pub fn subject_type(self) -> &'static str {
+        match self {
+            Self::User(_) => "user",
+            Self::Group(_) => "group",
+        }
+    }
+
+    fn id(self) -> Uuid {
+        match self {
+            Self::User(user_id) => user_id.0,
+            Self::Group(group_id) => group_id.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, FromRow)]
+pub struct AbuseReport {
+    pub id: Uuid,
+    /// `None` once the reporter's account is deleted.
+    pub reporter_id: Option<UserId>,
+    /// `user` or `group`.
+    pub subject_type: String,
+    pub subject_id: Uuid,
+    pub category: String,
+    pub message: Option<String>,
+    pub status: ReportStatus,
+    pub created_at: chrono::NaiveDateTime,
+    pub updated_at: chrono::NaiveDateTime,
+    /// The moderator who last moved the report.
+    pub reviewer_id: Option<UserId>,
+    pub resolution_note: Option<String>,
+    pub resolved_at: Option<chrono::NaiveDateTime>,
+}
+
+impl AbuseReport {
+    pub fn subject(&self) -> Option<ReportSubject> {
+        match self.subject_type.as_str() {
+            "user" => Some(ReportSubject::User(UserId(self.subject_id))),
+            "group" => Some(ReportSubject::Group(GroupId(self.subject_id))),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, FromRow)]
+pub struct ReportEvidence {
+    pub id: Uuid,
+    pub report_id: Uuid,
+    /// What the payload is, e.g. `reporter`, `message` or `screenshot_url`.
+    pub kind: String,
+    pub payload: Value,
+    pub attached_by: Option<UserId>,
+    pub created_at: chrono::NaiveDateTime,
+}
+
+/// Evidence to attach to a report.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct NewEvidence {
+    pub kind: String,
+    pub payload: Value,
+}
+
+/// A report to file with `file_report`.
+#[derive(Debug, Clone)]
+pub struct NewReport {
+    pub subject: ReportSubject,
+    pub category: String,
+    pub message: Option<String>,
+    pub evidence: Vec<NewEvidence>,
+}
+
+/// Host-app evidence for reports as they are filed through `/auth/reports`, e.g. recent messages
+/// between the reporter and the subject. Set with `AuthApp::evidence_collector`.
+pub trait EvidenceCollector: Send + Sync {
+    fn collect<'a>(&'a self, report: &'a AbuseReport) -> BoxFuture<'a, Vec<NewEvidence>>;
+}
+
+/// A status change did not apply.
+#[derive(Debug)]
+pub enum ReportTransitionError {
+    NotFound,
+    Invalid {
+        from: ReportStatus,
+        to: ReportStatus,
+    },
+    Database(sqlx::Error),
+}
+
+impl fmt::Display for ReportTransitionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotFound => write!(f, "Report not found"),
+            Self::Invalid { from, to } => {
+                write!(f, "A report cannot move from {} to {}", from, to)
+            }
+            Self::Database(err) => write!(f, "Database error: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for ReportTransitionError {}
+
+impl From<sqlx::Error> for ReportTransitionError {
+    fn from(err: sqlx::Error) -> Self {
+        Self::Database(err)
+    }
+}
+
+/// File a report with its evidence. Returns `None` if the reporter already has an unresolved
+/// report about the same subject.
+pub async fn file_report(
+    pool: &PgPool,
+    reporter_id: UserId,
+    report: &NewReport,
+) -> Result<Option<AbuseReport>, sqlx::Error> {
+    let now = chrono::Utc::now().naive_utc();
+    let mut tx = pool.begin().await?;
+    let Some(row) = sqlx::query_as::<_, AbuseReport>(concat!(
+        r#"
+        INSERT INTO auth.reports
+            (id, reporter_id, subject_type, subject_id, category, message, status, created_at,
+             updated_at)
+        VALUES ($1, $2, $3, $4, $5, $6, 'open', $7, $7)
+        ON CONFLICT (reporter_id, subject_type, subject_id)
+            WHERE status IN ('open', 'reviewing')
+            DO NOTHING
+        RETURNING "#,
+        report_columns!(),
+    ))
+    .bind(new_uuid())
+    .bind(reporter_id)
+    .bind(report.subject.subject_type())
+    .bind(report.subject.id())
+    .bind(&report.category)
+    .bind(&report.message)
+    .bind(now)
+    .fetch_optional(&mut *tx)
+    .await?
+    else {
+        return Ok(None);
+    };
+
+    for evidence in &report.evidence {
+        insert_evidence(&mut tx, row.id, Some(reporter_id), evidence).await?;
+    }
+    insert_audit_log(
+        &mut *tx,
+        Some(reporter_id),
+        json!({
+            "type": "abuse_reported",
+            "report_id": row.id.to_string(),
+            "subject_type": row.subject_type,
+            "subject_id": row.subject_id.to_string(),
+            "category": row.category,
+        }),
+    )
+    .await?;
+    tx.commit().await?;
+    Ok(Some(row))
+}
+
+/// Attach evidence to a report. `attached_by` is `None` for evidence from the host app itself.
+/// Returns `None` if the report does not exist.
+pub async fn attach_evidence(
+    pool: &PgPool,
+    report_id: Uuid,
+    attached_by: Option<UserId>,
+    evidence: &NewEvidence,
+) -> Result<Option<ReportEvidence>, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    let exists: Option<(Uuid,)> = sqlx::query_as("SELECT id FROM auth.reports WHERE id = $1")
+        .bind(report_id)
+        .fetch_optional(&mut *tx)
+        .await?;
+    if exists.is_none() {
+        return Ok(None);
+    }
+    let row = insert_evidence(&mut tx, report_id, attached_by, evidence).await?;
+    tx.commit().await?;
+    Ok(Some(row))
+}
+
+async fn insert_evidence(
+    tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+    report_id: Uuid,
+    attached_by: Option<UserId>,
+    evidence: &NewEvidence,
+) -> Result<ReportEvidence, sqlx::Error> {
+    sqlx::query_as::<_, ReportEvidence>(concat!(
+        r#"
+        INSERT INTO auth.report_evidence (id, report_id, kind, payload, attached_by, created_at)
+        VALUES ($1, $2, $3, $4, $5, $6)
+        RETURNING "#,
+        report_evidence_columns!(),
+    ))
+    .bind(new_uuid())
+    .bind(report_id)
+    .bind(&evidence.kind)
+    .bind(&evidence.payload)
+    .bind(attached_by)
+    .bind(chrono::Utc::now().naive_utc())
+    .fetch_one(&mut **tx)
+    .await
+}
+
+pub async fn get_report(
+    pool: &PgPool,
+    report_id: Uuid,
+) -> Result<Option<AbuseReport>, sqlx::Error> {
+    sqlx::query_as::<_, AbuseReport>(concat!(
+        "SELECT ",
+        report_columns!(),
+        " FROM auth.reports WHERE id = $1",
+    ))
+    .bind(report_id)
+    .fetch_optional(pool)
+    .await
+}
+
+/// Evidence attached to a report, oldest first.
+pub async fn report_evidence(
+    pool: &PgPool,
+    report_id: Uuid,
+) -> Result<Vec<ReportEvidence>, sqlx::Error> {
+    sqlx::query_as::<_, ReportEvidence>(concat!(
+        "SELECT ",
+        report_evidence_columns!(),
+        r#"
+        FROM auth.report_evidence
+        WHERE report_id = $1
+        ORDER BY created_at ASC
+        "#,
+    ))
+    .bind(report_id)
+    .fetch_all(pool)
+    .await
+}
+
+/// Reports in `status`, or all unresolved ones, oldest first so the queue is worked in order.
+pub async fn moderation_queue(
+    pool: &PgPool,
+    status: Option<ReportStatus>,
+    (limit, offset): (i64, i64),
+) -> Result<Vec<AbuseReport>, sqlx::Error> {
+    sqlx::query_as::<_, AbuseReport>(concat!(
+        "SELECT ",
+        report_columns!(),
+        r#"
+        FROM auth.reports
+        WHERE ($1::TEXT IS NULL AND status IN ('open', 'reviewing')) OR status = $1
+        ORDER BY created_at ASC
+        LIMIT $2 OFFSET $3
+        "#,
+    ))
+    .bind(status)
+    .bind(limit)
+    .bind(offset)
+    .fetch_all(pool)
+    .await
+}
+
+/// Move a report to `to` as `moderator`, and log a `abuse_report_<status>` entry. Resolving
+/// records `note` as the resolution.
+pub async fn transition_report(
+    pool: &PgPool,
+    moderator: UserId,
+    report_id: Uuid,
+    to: ReportStatus,
+    note: Option<&str>,
+) -> Result<AbuseReport, ReportTransitionError> {
+    let now = chrono::Utc::now().naive_utc();
+    let mut tx = pool.begin().await?;
+    let from: Option<(ReportStatus,)> =
+        sqlx::query_as("SELECT status FROM auth.reports WHERE id = $1 FOR UPDATE")
+            .bind(report_id)
+            .fetch_optional(&mut *tx)
+            .await?;
+    let Some((from,)) = from else {
+        return Err(ReportTransitionError::NotFound);
+    };
+    if !from.can_transition_to(to) {
+        return Err(ReportTransitionError::Invalid { from, to });
+    }
+
+    let row = sqlx::query_as::<_, AbuseReport>(concat!(
+        r#"
+        UPDATE auth.reports
+        SET status = $2,
+            reviewer_id = $3,
+            updated_at = $4,
+            resolution_note = CASE WHEN $5 THEN $6 ELSE resolution_note END,
+            resolved_at = CASE WHEN $5 THEN $4 ELSE resolved_at END
+        WHERE id = $1
+        RETURNING "#,
+        report_columns!(),
+    ))
+    .bind(report_id)
+    .bind(to)
+    .bind(moderator)
+    .bind(now)
+    .bind(to.is_resolved())
+    .bind(note)
+    .fetch_one(&mut *tx)
+    .await?;
+
+    insert_audit_log(
+        &mut *tx,
+        Some(moderator),
+        json!({
+            "type": format!("abuse_report_{}", to),
+            "report_id": report_id.to_string(),
+            "from": from,
+            "note": note,
+        }),
+    )
+    .await?;
+    tx.commit().await?;
+    Ok(row)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ReportStatus::*;
+
+    #[test]
+    fn reports_are_reviewed_before_resolution() {
+        assert!(Open.can_transition_to(Reviewing));
+        assert!(Reviewing.can_transition_to(Actioned));
+        assert!(Reviewing.can_transition_to(Dismissed));
+        assert!(!Open.can_transition_to(Actioned));
+        assert!(!Dismissed.can_transition_to(Reviewing));
+        assert!(!Actioned.can_transition_to(Dismissed));
+    }
+}
@@ -0,0 +1,626 @@
+//! Service principals from mTLS client certificates.
+//!
+//! The certificate is taken from the TLS connection, via the `PeerCertificates` extension, or
+//! from the `X-Forwarded-Client-Cert` header a mesh sidecar such as Envoy sets after terminating
+//! TLS. Its SPIFFE ID or DNS name selects a row of `auth.service_accounts`, and the account's SPKI
+//! pins, if any, must include the certificate's key. Chain validation is the TLS layer's job;
+//! certificates reaching this module are assumed to be trusted by it.
+
+use std::fmt;
+use std::str::FromStr;
+
+use axum::extract::FromRequestParts;
+use axum::http::request::Parts;
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use chrono::NaiveDateTime;
+use rustls::pki_types::CertificateDer;
+use serde::Serialize;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use sqlx::{FromRow, PgPool};
+use uuid::Uuid;
+
+use crate::api::AuthApp;
+use crate::db::insert_audit_log;
+use crate::ids::new_uuid;
+use crate::prelude::{AuthRejectReason, RejectReason, UserId};
+
+/// Header set by Envoy and compatible proxies (`forward_client_cert_details`).
+pub const FORWARDED_CLIENT_CERT_HEADER: &str = "x-forwarded-client-cert";
+
+const MAX_SPIFFE_ID_LEN: usize = 2048;
+
+macro_rules! service_account_columns {
+    () => {
+        "id, name, san, spki_pins, created_at, updated_at, disabled_at"
+    };
+}
+
+/// A SPIFFE ID, `spiffe://<trust domain>/<path>`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize)]
+#[serde(into = "String")]
+pub struct SpiffeId {
+    trust_domain: String,
+    path: String,
+}
+
+impl SpiffeId {
+    pub fn trust_domain(&self) -> &str {
+        &self.trust_domain
+    }
+
+    /// Empty, or `/` followed by segments, e.g. `/ns/billing/sa/api`.
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+}
+
+impl fmt::Display for SpiffeId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "spiffe://{}{}", self.trust_domain, self.path)
+    }
+}
+
+impl From<SpiffeId> for String {
+    fn from(id: SpiffeId) -> Self {
+        id.to_string()
+    }
+}
+
+impl FromStr for SpiffeId {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        if value.len() > MAX_SPIFFE_ID_LEN {
+            return Err("SPIFFE ID is too long".to_string());
+        }
+        let rest = value
+            .strip_prefix("spiffe://")
+            .ok_or_else(|| "expected the spiffe:// scheme".to_string())?;
+        let (trust_domain, path) = rest.split_at(rest.find('/').unwrap_or(rest.len()));
+        if trust_domain.is_empty()
+            || !trust_domain.bytes().all(|b| {
+                b.is_ascii_lowercase() || b.is_ascii_digit() || matches!(b, b'.' | b'-' | b'_')
+            })
+        {
+            return Err(
+                "trust domain must be lowercase letters, digits, '.', '-' or '_'".to_string(),
+            );
+        }
+        let valid_segment = |segment: &str| {
+            !segment.is_empty()
+                && segment != "."
+                && segment != ".."
+                && segment
+                    .bytes()
+                    .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'.' | b'-' | b'_'))
+        };
+        if !path.is_empty() && !path[1..].split('/').all(valid_segment) {
+            return Err("path segments must be letters, digits, '.', '-' or '_'".to_string());
+        }
+        Ok(Self {
+            trust_domain: trust_domain.to_string(),
+            path: path.to_string(),
+        })
+    }
+}
+
+/// The client's certificate chain, leaf first, as verified by the TLS acceptor. Insert it into
+/// request extensions when the app terminates TLS itself, e.g. from
+/// `rustls::ServerConnection::peer_certificates` after the handshake.
+#[derive(Debug, Clone)]
+pub struct PeerCertificates(pub Vec<CertificateDer<'static>>);
+
+#[derive(Debug)]
+pub enum ClientCertError {
+    /// The request carries no client certificate.
+    Missing,
+    /// The certificate or forwarded header cannot be read; the message says why.
+    Malformed(String),
+    /// No enabled service account has any of the presented names.
+    UnknownPrincipal,
+    /// The presented names belong to more than one service account.
+    Ambiguous,
+    /// The account pins keys and the certificate's key is not one of them.
+    PinMismatch,
+    Database(sqlx::Error),
+}
+
+impl fmt::Display for ClientCertError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Missing => write!(f, "Missing client certificate"),
+            Self::Malformed(reason) => write!(f, "Malformed client certificate: {}", reason),
+            Self::UnknownPrincipal => write!(f, "Client certificate matches no service account"),
+            Self::Ambiguous => write!(f, "Client certificate matches several service accounts"),
+            Self::PinMismatch => write!(f, "Client certificate key is not pinned"),
+            Self::Database(err) => write!(f, "Database error: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for ClientCertError {}
+
+impl From<sqlx::Error> for ClientCertError {
+    fn from(err: sqlx::Error) -> Self {
+        Self::Database(err)
+    }
+}
+
+/// Names and key a client presented.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ClientIdentity {
+    pub uris: Vec<String>,
+    /// Lower-cased.
+    pub dns_names: Vec<String>,
+    /// Base64url SHA-256 of the DER SubjectPublicKeyInfo. `None` when a proxy forwarded the
+    /// names without the certificate, in which case pinned accounts refuse the request.
+    pub spki_sha256: Option<String>,
+}
+
+impl ClientIdentity {
+    pub fn from_der(der: &CertificateDer<'_>) -> Result<Self, ClientCertError> {
+        let cert = webpki::EndEntityCert::try_from(der)
+            .map_err(|err| ClientCertError::Malformed(err.to_string()))?;
+        Ok(Self {
+            uris: cert.valid_uri_names().map(str::to_string).collect(),
+            dns_names: cert
+                .valid_dns_names()
+                .map(|name| name.to_ascii_lowercase())
+                .collect(),
+            spki_sha256: Some(
+                URL_SAFE_NO_PAD.encode(Sha256::digest(cert.subject_public_key_info().as_ref())),
+            ),
+        })
+    }
+
+    /// Read `X-Forwarded-Client-Cert`. Each proxy appends an element, so the last one is the
+    /// nearest proxy's account of its peer. A `Cert` field is preferred over the `URI` and `DNS`
+    /// fields, since only the certificate carries the key.
+    pub fn from_forwarded_header(value: &str) -> Result<Self, ClientCertError> {
+        let element = split_unquoted(value, ',')
+            .pop()
+            .map(str::trim)
+            .filter(|element| !element.is_empty())
+            .ok_or_else(|| ClientCertError::Malformed("empty forwarded header".to_string()))?;
+        let mut identity = Self::default();
+        for field in split_unquoted(element, ';') {
+            let Some((name, value)) = field.split_once('=') else {
+                continue;
+            };
+            let value = unquote(value.trim());
+            match name.trim().to_ascii_lowercase().as_str() {
+                "cert" => return Self::from_forwarded_pem(&value),
+                "uri" if !value.is_empty() => identity.uris.push(value),
+                "dns" if !value.is_empty() => identity.dns_names.push(value.to_ascii_lowercase()),
+                _ => {}
+            }
+        }
+        Ok(identity)
+    }
+
+    fn from_forwarded_pem(value: &str) -> Result<Self, ClientCertError> {
+        let pem = urlencoding::decode(value)
+            .map_err(|err| ClientCertError::Malformed(err.to_string()))?;
+        let der = rustls_pemfile::certs(&mut pem.as_bytes())
+            .next()
+            .ok_or_else(|| ClientCertError::Malformed("no certificate in Cert field".to_string()))?
+            .map_err(|err| ClientCertError::Malformed(err.to_string()))?;
+        Self::from_der(&der)
+    }
+}
+
+/// Split on `separator` outside double quotes.
+fn split_unquoted(value: &str, separator: char) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut start = 0;
+    let mut quoted = false;
+    let mut escaped = false;
+    for (index, c) in value.char_indices() {
+        if escaped {
+            escaped = false;
+            continue;
+        }
+        match c {
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            c if c == separator && !quoted => {
+                parts.push(&value[start..index]);
+                start = index + c.len_utf8();
+            }
+            _ => {}
+        }
+    }
+    parts.push(&value[start..]);
+    parts
+}
+
+fn unquote(value: &str) -> String {
+    match value
+        .strip_prefix('"')
+        .and_then(|value| value.strip_suffix('"'))
+    {
+        Some(inner) => inner.replace("\\\"", "\""),
+        None => value.to_string(),
+    }
+}
+
+/// Where client certificates may come from and which SPIFFE trust domains count. Returned by
+/// `AuthApp::client_cert_policy`.
+#[derive(Debug, Clone, Default)]
+pub struct ClientCertPolicy {
+    trust_forwarded_header: bool,
+    trust_domains: Vec<String>,
+}
+
+impl ClientCertPolicy {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Read `X-Forwarded-Client-Cert` when the connection has no `PeerCertificates`. Only for
+    /// services reachable solely through a proxy that sets the header itself, since anyone
+    /// else can send it too.
+    pub fn trust_forwarded_header(mut self) -> Self {
+        self.trust_forwarded_header = true;
+        self
+    }
+
+    /// Accept SPIFFE IDs of `trust_domain`. With none configured, every trust domain is accepted.
+    pub fn with_trust_domain<S: Into<String>>(mut self, trust_domain: S) -> Self {
+        self.trust_domains.push(trust_domain.into());
+        self
+    }
+
+    /// The identity presented with a request, from `PeerCertificates` or, if trusted, the
+    /// forwarded header.
+    pub fn identity(&self, parts: &Parts) -> Result<ClientIdentity, ClientCertError> {
+        if let Some(PeerCertificates(certs)) = parts.extensions.get::<PeerCertificates>()
+            && let Some(leaf) = certs.first()
+        {
+            return ClientIdentity::from_der(leaf);
+        }
+        if self.trust_forwarded_header
+            && let Some(value) = parts.headers.get(FORWARDED_CLIENT_CERT_HEADER)
+        {
+            let value = value.to_str().map_err(|_| {
+                ClientCertError::Malformed("forwarded header is not ASCII".to_string())
+            })?;
+            return ClientIdentity::from_forwarded_header(value);
+        }
+        Err(ClientCertError::Missing)
+    }
+
+    /// SANs to look accounts up by: SPIFFE IDs of accepted trust domains, then DNS names.
+    pub fn candidate_sans(&self, identity: &ClientIdentity) -> Vec<String> {
+        identity
+            .uris
+            .iter()
+            .filter_map(|uri| uri.parse::<SpiffeId>().ok())
+            .filter(|id| {
+                self.trust_domains.is_empty()
+                    || self
+                        .trust_domains
+                        .iter()
+                        .any(|domain| domain == id.trust_domain())
+            })
+            .map(|id| id.to_string())
+            .chain(identity.dns_names.iter().cloned())
+            .collect()
+    }
+
+    /// The service account `identity` belongs to.
+    pub async fn resolve(
+        &self,
+        pool: &PgPool,
+        identity: &ClientIdentity,
+    ) -> Result<ServicePrincipal, ClientCertError> {
+        let sans = self.candidate_sans(identity);
+        if sans.is_empty() {
+            return Err(ClientCertError::UnknownPrincipal);
+        }
+        let mut accounts = ServiceAccount::find_by_sans(pool, &sans).await?;
+        let account = match accounts.len() {
+            0 => return Err(ClientCertError::UnknownPrincipal),
+            1 => accounts.remove(0),
+            _ => return Err(ClientCertError::Ambiguous),
+        };
+        if !account.spki_pins.is_empty()
+            && !identity
+                .spki_sha256
+                .as_ref()
+                .is_some_and(|spki| account.spki_pins.contains(spki))
+        {
+            tracing::warn!(
+                "Refusing client certificate for service account {}: key not pinned",
+                account.name
+            );
+            return Err(ClientCertError::PinMismatch);
+        }
+        Ok(ServicePrincipal {
+            account_id: account.id,
+            spiffe_id: account.san.parse().ok(),
+            name: account.name,
+            san: account.san,
+        })
+    }
+}
+
+/// A service that signs in with a client certificate.
+#[derive(Debug, Clone, PartialEq, Eq, FromRow, Serialize)]
+pub struct ServiceAccount {
+    pub id: Uuid,
+    pub name: String,
+    /// SPIFFE ID or DNS name the certificate must present.
+    pub san: String,
+    /// Base64url SHA-256 digests of accepted SubjectPublicKeyInfos. Empty accepts any key.
+    pub spki_pins: Vec<String>,
+    pub created_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+    pub disabled_at: Option<NaiveDateTime>,
+}
+
+#[derive(Debug)]
+pub enum ServiceAccountError {
+    /// The settings are unusable; the message says why.
+    Invalid(String),
+    /// The name or SAN is already registered.
+    Conflict,
+    Database(sqlx::Error),
+}
+
+impl fmt::Display for ServiceAccountError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Invalid(reason) => write!(f, "{}", reason),
+            Self::Conflict => write!(f, "Service account already exists"),
+            Self::Database(err) => write!(f, "Database error: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for ServiceAccountError {}
+
+impl From<sqlx::Error> for ServiceAccountError {
+    fn from(err: sqlx::Error) -> Self {
+        match err {
+            sqlx::Error::Database(db_err) if db_err.is_unique_violation() => Self::Conflict,
+            err => Self::Database(err),
+        }
+    }
+}
+
+impl ServiceAccount {
+    /// Register a service and log `service_account_created`. `san` is a SPIFFE ID or a DNS name.
+    pub async fn create(
+        pool: &PgPool,
+        actor_user_id: UserId,
+        name: &str,
+        san: &str,
+        spki_pins: &[String],
+    ) -> Result<Self, ServiceAccountError> {
+        let invalid = |reason: &str| ServiceAccountError::Invalid(reason.to_string());
+        let name = name.trim();
+        if name.is_empty() {
+            return Err(invalid("Service account name must not be empty"));
+        }
+        let san = normalize_san(san).map_err(ServiceAccountError::Invalid)?;
+        if spki_pins.iter().any(|pin| {
+            URL_SAFE_NO_PAD
+                .decode(pin)
+                .map_or(true, |digest| digest.len() != 32)
+        }) {
+            return Err(invalid("SPKI pins must be base64url SHA-256 digests"));
+        }
+        let mut tx = pool.begin().await?;
+        let account = sqlx::query_as::<_, ServiceAccount>(concat!(
+            r#"
+            INSERT INTO auth.service_accounts (id, name, san, spki_pins)
+            VALUES ($1, $2, $3, $4)
+            RETURNING "#,
+            service_account_columns!(),
+        ))
+        .bind(new_uuid())
+        .bind(name)
+        .bind(&san)
+        .bind(spki_pins)
+        .fetch_one(&mut *tx)
+        .await?;
+        insert_audit_log(
+            &mut *tx,
+            Some(actor_user_id),
+            json!({
+                "type": "service_account_created",
+                "service_account_id": account.id,
+                "san": account.san,
+            }),
+        )
+        .await?;
+        tx.commit().await?;
+        Ok(account)
+    }
+
+    pub async fn list(pool: &PgPool) -> Result<Vec<Self>, sqlx::Error> {
+        sqlx::query_as::<_, ServiceAccount>(concat!(
+            "SELECT ",
+            service_account_columns!(),
+            r#"
+            FROM auth.service_accounts
+            ORDER BY name ASC
+            "#,
+        ))
+        .fetch_all(pool)
+        .await
+    }
+
+    /// Enabled accounts with any of `sans`.
+    pub async fn find_by_sans(pool: &PgPool, sans: &[String]) -> Result<Vec<Self>, sqlx::Error> {
+        sqlx::query_as::<_, ServiceAccount>(concat!(
+            "SELECT ",
+            service_account_columns!(),
+            r#"
+            FROM auth.service_accounts
+            WHERE san = ANY($1)
+              AND disabled_at IS NULL
+            "#,
+        ))
+        .bind(sans)
+        .fetch_all(pool)
+        .await
+    }
+
+    /// Refuse the account's certificates from now on and log `service_account_disabled`.
+    /// Returns `false` if there was no enabled account.
+    pub async fn disable(
+        pool: &PgPool,
+        actor_user_id: UserId,
+        id: Uuid,
+    ) -> Result<bool, sqlx::Error> {
+        let mut tx = pool.begin().await?;
+        let now = chrono::Utc::now().naive_utc();
+        let disabled = sqlx::query(
+            r#"
+            UPDATE auth.service_accounts
+            SET disabled_at = $2,
+                updated_at = $2
+            WHERE id = $1
+              AND disabled_at IS NULL
+            "#,
+        )
+        .bind(id)
+        .bind(now)
+        .execute(&mut *tx)
+        .await?
+        .rows_affected()
+            > 0;
+        if disabled {
+            insert_audit_log(
+                &mut *tx,
+                Some(actor_user_id),
+                json!({
+                    "type": "service_account_disabled",
+                    "service_account_id": id,
+                }),
+            )
+            .await?;
+        }
+        tx.commit().await?;
+        Ok(disabled)
+    }
+}
+
+/// A SPIFFE ID as is, or a DNS name lower-cased.
+fn normalize_san(san: &str) -> Result<String, String> {
+    let san = san.trim();
+    if san.starts_with("spiffe://") {
+        return san.parse::<SpiffeId>().map(|id| id.to_string());
+    }
+    let dns = san.to_ascii_lowercase();
+    if dns.is_empty()
+        || dns.split('.').any(|label| {
+            label.is_empty()
+                || label.starts_with('-')
+                || !label
+                    .bytes()
+                    .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'-')
+        })
+    {
+        return Err("SAN must be a SPIFFE ID or a DNS name".to_string());
+    }
+    Ok(dns)
+}
+
+/// The service account of the request's client certificate, per `AuthApp::client_cert_policy`.
+/// Requests without a certificate, or whose certificate maps to no enabled account, get `401`.
+///
+/// ```ignore
+/// async fn sync(principal: ServicePrincipal) -> impl IntoResponse {
+///     tracing::info!("Sync requested by {}", principal.san);
+/// }
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ServicePrincipal {
+    pub account_id: Uuid,
+    pub name: String,
+    pub san: String,
+    /// `san` parsed, if it is a SPIFFE ID.
+    pub spiffe_id: Option<SpiffeId>,
+}
+
+impl<S> FromRequestParts<S> for ServicePrincipal
+where
+    S: AuthApp + Send + Sync,
+{
+    type Rejection = RejectReason;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let Some(policy) = state.client_cert_policy() else {
+            return Err(RejectReason::auth(AuthRejectReason::no_session_token()));
+        };
+        let principal = match policy.identity(parts) {
+            Ok(identity) => policy.resolve(&state.pool(), &identity).await,
+            Err(err) => Err(err),
+        };
+        principal.map_err(|err| match err {
+            ClientCertError::Missing => RejectReason::auth(AuthRejectReason::no_session_token()),
+            ClientCertError::Database(err) => {
+                tracing::error!("Failed to look up service account: {}", err);
+                RejectReason::database("Failed to reach database")
+            }
+            err => RejectReason::auth(AuthRejectReason::invalid_session_token(err.to_string())),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rustls::pki_types::CertificateDer;
+
+    use super::{ClientCertPolicy, ClientIdentity, SpiffeId};
+
+    const CERT_PEM: &str = "-----BEGIN CERTIFICATE-----
+MIIBwzCCAWigAwIBAgIUV6qcpk1dWLxhiOBR42rWnSIbQMcwCgYIKoZIzj0EAwIw
+EjEQMA4GA1UEAwwHYmlsbGluZzAgFw0yNjEwMTgwMDM0MTNaGA8yMTI2MDkyNDAw
+MzQxM1owEjEQMA4GA1UEAwwHYmlsbGluZzBZMBMGByqGSM49AgEGCCqGSM49AwEH
+A0IABAhiHG4/ycQ8UEA3hFl5A800bfB52OLH+tzIacKJ2Wwluaui6eGEz/anYa/0
+YpTYll+ZzU/8KeC0V0Rqa2h6PdejgZkwgZYwHQYDVR0OBBYEFCZWwkGDDeci+JPg
+s50LIvL9WZCKMB8GA1UdIwQYMBaAFCZWwkGDDeci+JPgs50LIvL9WZCKMA8GA1Ud
+EwEB/wQFMAMBAf8wQwYDVR0RBDwwOoYmc3BpZmZlOi8vZXhhbXBsZS5vcmcvbnMv
+YmlsbGluZy9zYS9hcGmCEGJpbGxpbmcuaW50ZXJuYWwwCgYIKoZIzj0EAwIDSQAw
+RgIhALFzQUt9IEt9jRTvwcJk0DKMcHsMj224gnHJma8lOqiWAiEA4hpmYwZ1OxwE
+vyDmJYv0yZR8MBUuUeieVQC1Sw1Svyk=
+-----END CERTIFICATE-----
+";
+
+    #[test]
+    fn reads_identity_from_certificate_and_forwarded_header() {
+        let der: CertificateDer = rustls_pemfile::certs(&mut CERT_PEM.as_bytes())
+            .next()
+            .unwrap()
+            .unwrap();
+        let identity = ClientIdentity::from_der(&der).unwrap();
+        assert_eq!(identity.uris, ["spiffe://example.org/ns/billing/sa/api"]);
+        assert_eq!(identity.dns_names, ["billing.internal"]);
+        assert_eq!(
+            identity.spki_sha256.as_deref(),
+            Some("OIEKD_XVu3DDgAc-RHSJc8rTtKG2z15a4dztGHwMnqc")
+        );
+
+        let header = format!(
+            r#"By=spiffe://example.org/edge;URI=spiffe://evil.org/x,By=spiffe://example.org/api;Hash=abc;Cert="{}";Subject="CN=billing""#,
+            urlencoding::encode(CERT_PEM)
+        );
+        assert_eq!(
+            ClientIdentity::from_forwarded_header(&header).unwrap(),
+            identity
+        );
+
+        let policy = ClientCertPolicy::new().with_trust_domain("other.org");
+        assert_eq!(policy.candidate_sans(&identity), ["billing.internal"]);
+
+        assert!("spiffe://example.org/ns/../x".parse::<SpiffeId>().is_err());
+        assert!("spiffe://Example.org/x".parse::<SpiffeId>().is_err());
+        assert!("spiffe://example.org/x/".parse::<SpiffeId>().is_err());
+    }
+}
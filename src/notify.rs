@@ -0,0 +1,723 @@
+#[cfg(feature = "sqlx")]
+use std::collections::BTreeMap;
+use std::fmt;
+
+use chrono::NaiveDateTime;
+use futures_util::future::BoxFuture;
+use serde::Serialize;
+#[cfg(feature = "sqlx")]
+use serde_json::json;
+#[cfg(feature = "sqlx")]
+use sqlx::PgPool;
+
+#[cfg(feature = "sqlx")]
+use crate::db::{UserRow, insert_audit_log};
+use crate::group_id::GroupId;
+use crate::i18n::{DEFAULT_TRANSLATIONS, Translations, parse_locale};
+#[cfg(feature = "sqlx")]
+use crate::redact::Sensitive;
+use crate::user_id::UserId;
+
+/// Who a notification is addressed to.
+#[derive(Clone, Serialize)]
+pub struct Recipient {
+    pub user_id: Option<UserId>,
+    pub email: String,
+    pub display_name: Option<String>,
+    /// BCP 47 tag used to pick the message language. `None` uses the default locale.
+    pub locale: Option<String>,
+    /// IANA time zone name, for templates that show times in the recipient's local time.
+    pub timezone: Option<String>,
+}
+
+impl fmt::Debug for Recipient {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Recipient")
+            .field("user_id", &self.user_id)
+            .field("email", &Sensitive(&self.email))
+            .field("display_name", &self.display_name.as_ref().map(Sensitive))
+            .field("locale", &self.locale)
+            .field("timezone", &self.timezone)
+            .finish()
+    }
+}
+
+impl Recipient {
+    pub fn new<S: Into<String>>(email: S) -> Self {
+        Self {
+            user_id: None,
+            email: email.into(),
+            display_name: None,
+            locale: None,
+            timezone: None,
+        }
+    }
+
+    /// Addressed to `user`, in their locale and time zone, by display name or else username.
+    #[cfg(feature = "sqlx")]
+    pub fn for_user(user: &UserRow) -> Self {
+        Self {
+            user_id: Some(user.id),
+            email: user.email.clone(),
+            display_name: user.display_name.clone().or_else(|| user.username.clone()),
+            locale: user.locale.clone(),
+            timezone: user.timezone.clone(),
+        }
+    }
+
+    pub fn with_user_id(mut self, user_id: UserId) -> Self {
+        self.user_id = Some(user_id);
+        self
+    }
+
+    pub fn with_display_name<S: Into<String>>(mut self, display_name: S) -> Self {
+        self.display_name = Some(display_name.into());
+        self
+    }
+
+    pub fn with_locale(mut self, locale: Option<String>) -> Self {
+        self.locale = locale;
+        self
+    }
+
+    pub fn with_timezone(mut self, timezone: Option<String>) -> Self {
+        self.timezone = timezone;
+        self
+    }
+}
+
+// The URLs carry single-use tokens, so they are redacted along with personal data.
+
+#[derive(Clone, Serialize)]
+pub struct VerificationEmailContext {
+    pub recipient: Recipient,
+    pub verify_url: String,
+    pub expires_at: NaiveDateTime,
+}
+
+impl fmt::Debug for VerificationEmailContext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("VerificationEmailContext")
+            .field("recipient", &self.recipient)
+            .field("verify_url", &Sensitive(&self.verify_url))
+            .field("expires_at", &self.expires_at)
+            .finish()
+    }
+}
+
+#[derive(Clone, Serialize)]
+pub struct PasswordResetContext {
+    pub recipient: Recipient,
+    pub reset_url: String,
+    pub expires_at: NaiveDateTime,
+}
+
+impl fmt::Debug for PasswordResetContext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PasswordResetContext")
+            .field("recipient", &self.recipient)
+            .field("reset_url", &Sensitive(&self.reset_url))
+            .field("expires_at", &self.expires_at)
+            .finish()
+    }
+}
+
+#[derive(Clone, Serialize)]
+pub struct NewDeviceLoginContext {
+    pub recipient: Recipient,
+    pub logged_in_at: NaiveDateTime,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+impl fmt::Debug for NewDeviceLoginContext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("NewDeviceLoginContext")
+            .field("recipient", &self.recipient)
+            .field("logged_in_at", &self.logged_in_at)
+            .field("ip", &self.ip.as_ref().map(Sensitive))
+            .field("user_agent", &self.user_agent.as_ref().map(Sensitive))
+            .finish()
+    }
+}
+
+#[derive(Clone, Serialize)]
+pub struct InvitationContext {
+    pub recipient: Recipient,
+    pub group_id: GroupId,
+    pub group_name: String,
+    pub inviter_name: Option<String>,
+    pub accept_url: String,
+    pub expires_at: Option<NaiveDateTime>,
+}
+
+impl fmt::Debug for InvitationContext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("InvitationContext")
+            .field("recipient", &self.recipient)
+            .field("group_id", &self.group_id)
+            .field("group_name", &self.group_name)
+            .field("inviter_name", &self.inviter_name.as_ref().map(Sensitive))
+            .field("accept_url", &Sensitive(&self.accept_url))
+            .field("expires_at", &self.expires_at)
+            .finish()
+    }
+}
+
+/// An invitation to create an account, from `/auth/admin/invitations`.
+#[derive(Clone, Serialize)]
+pub struct AccountInvitationContext {
+    pub recipient: Recipient,
+    pub inviter_name: Option<String>,
+    pub accept_url: String,
+    pub expires_at: NaiveDateTime,
+}
+
+impl fmt::Debug for AccountInvitationContext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AccountInvitationContext")
+            .field("recipient", &self.recipient)
+            .field("inviter_name", &self.inviter_name.as_ref().map(Sensitive))
+            .field("accept_url", &Sensitive(&self.accept_url))
+            .field("expires_at", &self.expires_at)
+            .finish()
+    }
+}
+
+/// A change to the account's sign-in security, e.g. a new password.
+#[derive(Debug, Clone, Serialize)]
+pub struct AccountChangeContext {
+    pub recipient: Recipient,
+    pub changed_at: NaiveDateTime,
+}
+
+/// Sent to the previous address, so its owner learns about the change.
+#[derive(Clone, Serialize)]
+pub struct EmailChangedContext {
+    pub recipient: Recipient,
+    pub changed_at: NaiveDateTime,
+    pub new_email: String,
+}
+
+impl fmt::Debug for EmailChangedContext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EmailChangedContext")
+            .field("recipient", &self.recipient)
+            .field("changed_at", &self.changed_at)
+            .field("new_email", &Sensitive(&self.new_email))
+            .finish()
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ApiKeyCreatedContext {
+    pub recipient: Recipient,
+    pub created_at: NaiveDateTime,
+    pub key_name: String,
+}
+
+/// A message to a user. Every auth flow that contacts users sends one of these through the app's
+/// `Notifier`.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", content = "context", rename_all = "snake_case")]
+pub enum Notification {
+    VerificationEmail(VerificationEmailContext),
+    PasswordReset(PasswordResetContext),
+    NewDeviceLogin(NewDeviceLoginContext),
+    InvitationReceived(InvitationContext),
+    AccountInvitation(AccountInvitationContext),
+    PasswordChanged(AccountChangeContext),
+    EmailChanged(EmailChangedContext),
+    MfaDisabled(AccountChangeContext),
+    ApiKeyCreated(ApiKeyCreatedContext),
+}
+
+/// Notification kinds users can turn off in their preferences. The others answer something the
+/// user just asked for, such as a password reset, and are always sent.
+pub const SECURITY_NOTIFICATIONS: [&str; 5] = [
+    "new_device_login",
+    "password_changed",
+    "email_changed",
+    "mfa_disabled",
+    "api_key_created",
+];
+
+/// Subject and plain-text body produced by `Notification::render`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RenderedNotification {
+    pub subject: String,
+    pub text: String,
+}
+
+impl Notification {
+    pub fn recipient(&self) -> &Recipient {
+        match self {
+            Self::VerificationEmail(context) => &context.recipient,
+            Self::PasswordReset(context) => &context.recipient,
+            Self::NewDeviceLogin(context) => &context.recipient,
+            Self::InvitationReceived(context) => &context.recipient,
+            Self::AccountInvitation(context) => &context.recipient,
+            Self::PasswordChanged(context) => &context.recipient,
+            Self::EmailChanged(context) => &context.recipient,
+            Self::MfaDisabled(context) => &context.recipient,
+            Self::ApiKeyCreated(context) => &context.recipient,
+        }
+    }
+
+    /// Stable name of the message type, e.g. for choosing a host-app template.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::VerificationEmail(_) => "verification_email",
+            Self::PasswordReset(_) => "password_reset",
+            Self::NewDeviceLogin(_) => "new_device_login",
+            Self::InvitationReceived(_) => "invitation_received",
+            Self::AccountInvitation(_) => "account_invitation",
+            Self::PasswordChanged(_) => "password_changed",
+            Self::EmailChanged(_) => "email_changed",
+            Self::MfaDisabled(_) => "mfa_disabled",
+            Self::ApiKeyCreated(_) => "api_key_created",
+        }
+    }
+
+    /// Whether the recipient's notification preferences can turn this message off.
+    pub fn is_optional(&self) -> bool {
+        SECURITY_NOTIFICATIONS.contains(&self.kind())
+    }
+
+    /// Plain-text rendering with the crate's built-in messages, in the recipient's locale where
+    /// available.
+    pub fn render(&self) -> RenderedNotification {
+        self.render_with(&*DEFAULT_TRANSLATIONS)
+    }
+
+    /// Plain-text rendering in the recipient's locale, negotiated against `translations`.
+    ///
+    /// Messages are `notification-<kind>-subject` and `notification-<kind>-body`, with the
+    /// context fields and a `$greeting` as arguments.
+    pub fn render_with(&self, translations: &dyn Translations) -> RenderedNotification {
+        let recipient = self.recipient();
+        let requested: Vec<_> = recipient
+            .locale
+            .as_deref()
+            .and_then(parse_locale)
+            .into_iter()
+            .collect();
+        let locale = translations.negotiate(&requested);
+        let text = |key: &str, args: &[(&str, &str)]| {
+            translations
+                .translate(&locale, key, args)
+                .unwrap_or_else(|| key.to_string())
+        };
+
+        let greeting = match &recipient.display_name {
+            Some(name) => text("notification-greeting-named", &[("name", name)]),
+            None => text("notification-greeting", &[]),
+        };
+        let unknown = text("notification-unknown", &[]);
+        let someone = text("notification-someone", &[]);
+        let owned: Vec<(&str, String)> = match self {
+            Self::VerificationEmail(context) => vec![
+                ("verify_url", context.verify_url.clone()),
+                ("expires_at", context.expires_at.to_string()),
+            ],
+            Self::PasswordReset(context) => vec![
+                ("reset_url", context.reset_url.clone()),
+                ("expires_at", context.expires_at.to_string()),
+            ],
+            Self::NewDeviceLogin(context) => vec![
+                ("logged_in_at", context.logged_in_at.to_string()),
+                (
+                    "user_agent",
+                    context
+                        .user_agent
+                        .clone()
+                        .unwrap_or_else(|| unknown.clone()),
+                ),
+                ("ip", context.ip.clone().unwrap_or_else(|| unknown.clone())),
+            ],
+            Self::InvitationReceived(context) => vec![
+                ("group_name", context.group_name.clone()),
+                (
+                    "inviter_name",
+                    context.inviter_name.clone().unwrap_or(someone),
+                ),
+                ("accept_url", context.accept_url.clone()),
+            ],
+            Self::AccountInvitation(context) => vec![
+                (
+                    "inviter_name",
+                    context.inviter_name.clone().unwrap_or(someone),
+                ),
+                ("accept_url", context.accept_url.clone()),
+                ("expires_at", context.expires_at.to_string()),
+            ],
+            Self::PasswordChanged(context) | Self::MfaDisabled(context) => {
+                vec![("changed_at", context.changed_at.to_string())]
+            }
+            Self::EmailChanged(context) => vec![
+                ("changed_at", context.changed_at.to_string()),
+                ("new_email", context.new_email.clone()),
+            ],
+            Self::ApiKeyCreated(context) => vec![
+                ("created_at", context.created_at.to_string()),
+                ("key_name", context.key_name.clone()),
+            ],
+        };
+        let mut args: Vec<(&str, &str)> = owned
+            .iter()
+            .map(|(name, value)| (*name, value.as_str()))
+            .collect();
+        args.push(("greeting", &greeting));
+
+        let kind = self.kind().replace('_', "-");
+        RenderedNotification {
+            subject: text(&format!("notification-{}-subject", kind), &args),
+            text: text(&format!("notification-{}-body", kind), &args),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NotifyError {
+    /// The message can never be delivered, e.g. the address is malformed.
+    Rejected(String),
+    /// Delivery failed and may succeed later.
+    Unavailable(String),
+}
+
+impl fmt::Display for NotifyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Rejected(msg) => write!(f, "Notification rejected: {}", msg),
+            Self::Unavailable(msg) => write!(f, "Notification delivery failed: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for NotifyError {}
+
+/// Delivers notifications to users, e.g. by email.
+pub trait Notifier: Send + Sync {
+    fn notify<'a>(
+        &'a self,
+        notification: &'a Notification,
+    ) -> BoxFuture<'a, Result<(), NotifyError>>;
+}
+
+/// Send a notification, logging instead of failing the calling flow when delivery fails.
+pub async fn notify_or_log(notifier: &dyn Notifier, notification: &Notification) {
+    if let Err(err) = notifier.notify(notification).await {
+        tracing::warn!(
+            "Failed to send {} notification to {:?}: {}",
+            notification.kind(),
+            notification.recipient().user_id,
+            err
+        );
+    }
+}
+
+/// Which optional notifications the user gets, for every kind in `SECURITY_NOTIFICATIONS`.
+/// Kinds the user never changed are on.
+#[cfg(feature = "sqlx")]
+pub async fn notification_preferences(
+    pool: &PgPool,
+    user_id: UserId,
+) -> Result<BTreeMap<String, bool>, sqlx::Error> {
+    let stored: Vec<(String, bool)> = sqlx::query_as(
+        r#"
+        SELECT kind, enabled
+        FROM auth.notification_preferences
+        WHERE user_id = $1
+        "#,
+    )
+    .bind(user_id)
+    .fetch_all(pool)
+    .await?;
+    let mut preferences: BTreeMap<String, bool> = SECURITY_NOTIFICATIONS
+        .iter()
+        .map(|kind| (kind.to_string(), true))
+        .collect();
+    for (kind, enabled) in stored {
+        if let Some(preference) = preferences.get_mut(&kind) {
+            *preference = enabled;
+        }
+    }
+    Ok(preferences)
+}
+
+/// Turn optional notifications on or off and log `notification_preferences_updated`. Kinds not
+/// in `SECURITY_NOTIFICATIONS` are ignored.
+#[cfg(feature = "sqlx")]
+pub async fn set_notification_preferences(
+    pool: &PgPool,
+    user_id: UserId,
+    changes: &BTreeMap<String, bool>,
+) -> Result<(), sqlx::Error> {
+    let changes: BTreeMap<&str, bool> = changes
+        .iter()
+        .filter(|(kind, _)| SECURITY_NOTIFICATIONS.contains(&kind.as_str()))
+        .map(|(kind, enabled)| (kind.as_str(), *enabled))
+        .collect();
+    if changes.is_empty() {
+        return Ok(());
+    }
+    let now = chrono::Utc::now().naive_utc();
+    let mut tx = pool.begin().await?;
+    for (kind, enabled) in &changes {
+        sqlx::query(
+            r#"
+            INSERT INTO auth.notification_preferences (user_id, kind, enabled, updated_at)
+            VALUES ($1, $2, $3, $4)
+            ON CONFLICT (user_id, kind) DO UPDATE
+            SET enabled = EXCLUDED.enabled,
+                updated_at = EXCLUDED.updated_at
+            "#,
+        )
+        .bind(user_id)
+        .bind(kind)
+        .bind(enabled)
+        .bind(now)
+        .execute(&mut *tx)
+        .await?;
+    }
+    insert_audit_log(
+        &mut *tx,
+        Some(user_id),
+        json!({"type": "notification_preferences_updated", "changes": changes}),
+    )
+    .await?;
+    tx.commit().await?;
+    Ok(())
+}
+
+/// `notify_or_log`, unless the recipient turned this kind of notification off. Failing to read
+/// the preferences sends the message anyway.
+#[cfg(feature = "sqlx")]
+pub async fn notify_user(pool: &PgPool, notifier: &dyn Notifier, notification: &Notification) {
+    if let Some(user_id) = notification.recipient().user_id
+        && notification.is_optional()
+    {
+        match notification_preferences(pool, user_id).await {
+            Ok(preferences) if preferences.get(notification.kind()) == Some(&false) => {
+                tracing::debug!(
+                    "{} turned off {} notifications",
+                    user_id,
+                    notification.kind()
+                );
+                return;
+            }
+            Ok(_) => {}
+            Err(err) => tracing::warn!(
+                "Failed to read notification preferences of {}: {}",
+                user_id,
+                err
+            ),
+        }
+    }
+    notify_or_log(notifier, notification).await;
+}
+
+/// A security-relevant account change for `notify_security_event`.
+#[derive(Debug, Clone)]
+pub enum SecurityEvent {
+    PasswordChanged,
+    /// The stored email is already the new one; the message goes to `previous_email`.
+    EmailChanged {
+        previous_email: String,
+    },
+    MfaDisabled,
+    ApiKeyCreated {
+        key_name: String,
+    },
+}
+
+/// Tell the user about a change to their account, respecting their notification preferences.
+/// Call it from the flows that make these changes, e.g. after `api::set_user_password`.
+#[cfg(feature = "sqlx")]
+pub async fn notify_security_event(
+    pool: &PgPool,
+    notifier: &dyn Notifier,
+    user_id: UserId,
+    event: SecurityEvent,
+) {
+    let user = match UserRow::get(pool, user_id).await {
+        Ok(Some(user)) => user,
+        Ok(None) => return,
+        Err(err) => {
+            tracing::warn!("Failed to load user for security notification: {}", err);
+            return;
+        }
+    };
+    let recipient = |email: String| Recipient {
+        email,
+        ..Recipient::for_user(&user)
+    };
+    let now = chrono::Utc::now().naive_utc();
+    let notification = match event {
+        SecurityEvent::PasswordChanged => Notification::PasswordChanged(AccountChangeContext {
+            recipient: recipient(user.email.clone()),
+            changed_at: now,
+        }),
+        SecurityEvent::EmailChanged { previous_email } => {
+            Notification::EmailChanged(EmailChangedContext {
+                recipient: recipient(previous_email),
+                changed_at: now,
+                new_email: user.email.clone(),
+            })
+        }
+        SecurityEvent::MfaDisabled => Notification::MfaDisabled(AccountChangeContext {
+            recipient: recipient(user.email.clone()),
+            changed_at: now,
+        }),
+        SecurityEvent::ApiKeyCreated { key_name } => {
+            Notification::ApiKeyCreated(ApiKeyCreatedContext {
+                recipient: recipient(user.email.clone()),
+                created_at: now,
+                key_name,
+            })
+        }
+    };
+    notify_user(pool, notifier, &notification).await;
+}
+
+/// Sends notifications as plain-text email over SMTP.
+#[cfg(feature = "smtp")]
+#[derive(Clone)]
+pub struct SmtpNotifier {
+    transport: lettre::AsyncSmtpTransport<lettre::Tokio1Executor>,
+    from: lettre::message::Mailbox,
+    translations: Option<std::sync::Arc<dyn Translations>>,
+}
+
+#[cfg(feature = "smtp")]
+impl SmtpNotifier {
+    /// Connect to `host` with implicit TLS. `from` is a mailbox such as
+    /// `Example <no-reply@example.com>`.
+    pub fn new(host: &str, from: &str) -> Result<Self, NotifyError> {
+        let transport = lettre::AsyncSmtpTransport::<lettre::Tokio1Executor>::relay(host)
+            .map_err(|err| NotifyError::Rejected(err.to_string()))?
+            .build();
+        Self::with_transport(transport, from)
+    }
+
+    /// Connect to `host` and upgrade with STARTTLS, usually on port 587.
+    pub fn starttls(host: &str, from: &str) -> Result<Self, NotifyError> {
+        let transport = lettre::AsyncSmtpTransport::<lettre::Tokio1Executor>::starttls_relay(host)
+            .map_err(|err| NotifyError::Rejected(err.to_string()))?
+            .build();
+        Self::with_transport(transport, from)
+    }
+
+    /// Use a preconfigured transport, e.g. one with credentials or a local unencrypted relay.
+    pub fn with_transport(
+        transport: lettre::AsyncSmtpTransport<lettre::Tokio1Executor>,
+        from: &str,
+    ) -> Result<Self, NotifyError> {
+        let from = from
+            .parse()
+            .map_err(|err: lettre::address::AddressError| NotifyError::Rejected(err.to_string()))?;
+        Ok(Self {
+            transport,
+            from,
+            translations: None,
+        })
+    }
+
+    /// Render messages with app-provided translations instead of the built-in ones.
+    pub fn with_translations(mut self, translations: std::sync::Arc<dyn Translations>) -> Self {
+        self.translations = Some(translations);
+        self
+    }
+}
+
+#[cfg(feature = "smtp")]
+impl Notifier for SmtpNotifier {
+    fn notify<'a>(
+        &'a self,
+        notification: &'a Notification,
+    ) -> BoxFuture<'a, Result<(), NotifyError>> {
+        use lettre::AsyncTransport;
+        use lettre::message::Mailbox;
+
+        Box::pin(async move {
+            let recipient = notification.recipient();
+            let to = Mailbox::new(
+                recipient.display_name.clone(),
+                recipient
+                    .email
+                    .parse()
+                    .map_err(|err: lettre::address::AddressError| {
+                        NotifyError::Rejected(err.to_string())
+                    })?,
+            );
+            let rendered = match &self.translations {
+                Some(translations) => notification.render_with(&**translations),
+                None => notification.render(),
+            };
+            let message = lettre::Message::builder()
+                .from(self.from.clone())
+                .to(to)
+                .subject(rendered.subject)
+                .header(lettre::message::header::ContentType::TEXT_PLAIN)
+                .body(rendered.text)
+                .map_err(|err| NotifyError::Rejected(err.to_string()))?;
+            self.transport
+                .send(message)
+                .await
+                .map_err(|err| NotifyError::Unavailable(err.to_string()))?;
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveDate;
+    use serde_json::json;
+
+    use super::{EmailChangedContext, NewDeviceLoginContext, Notification, Recipient};
+
+    #[test]
+    fn new_device_login_renders_and_serializes() {
+        let logged_in_at = NaiveDate::from_ymd_opt(2026, 3, 1)
+            .unwrap()
+            .and_hms_opt(12, 0, 0)
+            .unwrap();
+        let notification = Notification::NewDeviceLogin(NewDeviceLoginContext {
+            recipient: Recipient::new("user@example.com").with_display_name("user"),
+            logged_in_at,
+            ip: Some("203.0.113.7".to_string()),
+            user_agent: None,
+        });
+
+        let rendered = notification.render();
+        assert_eq!(rendered.subject, "New sign-in to your account");
+        assert!(rendered.text.starts_with("Hi user,"));
+        assert!(rendered.text.contains("IP address: 203.0.113.7"));
+        assert!(rendered.text.contains("Device: unknown"));
+
+        let value = serde_json::to_value(&notification).unwrap();
+        assert_eq!(value["type"], json!("new_device_login"));
+        assert_eq!(
+            value["context"]["recipient"]["email"],
+            json!("user@example.com")
+        );
+    }
+
+    #[test]
+    fn email_changed_goes_to_the_previous_address() {
+        let notification = Notification::EmailChanged(EmailChangedContext {
+            recipient: Recipient::new("old@example.com"),
+            changed_at: NaiveDate::from_ymd_opt(2026, 3, 1)
+                .unwrap()
+                .and_hms_opt(12, 0, 0)
+                .unwrap(),
+            new_email: "new@example.com".to_string(),
+        });
+
+        let rendered = notification.render();
+        assert_eq!(rendered.subject, "Your email address was changed");
+        assert!(rendered.text.contains("changed to new@example.com"));
+        assert!(notification.is_optional());
+    }
+}
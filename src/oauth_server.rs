@@ -0,0 +1,1159 @@
+use std::fmt;
+use std::time::Duration;
+
+use axum::extract::{OriginalUri, Path, Query, State};
+use axum::response::{IntoResponse, Redirect, Response};
+use axum::{Form, Json};
+use base64::Engine;
+use base64::engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD};
+use chrono::NaiveDateTime;
+use hyper::header::{AUTHORIZATION, CACHE_CONTROL, HeaderValue, PRAGMA, RETRY_AFTER};
+use hyper::{HeaderMap, StatusCode};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use sqlx::{FromRow, PgPool};
+use url::Url;
+use uuid::Uuid;
+
+pub use crate::access_tokens::{ACCESS_TOKEN_TYPE, AccessTokenClaims};
+use crate::api::AuthApp;
+use crate::clients::{Client, random_token, secret_hash};
+use crate::db::{UserRow, insert_audit_log};
+use crate::prelude::{AuthRejectReason, AuthenticatedUser, MaybeAuthenticatedUser, RejectReason};
+use crate::user_id::UserId;
+
+/// Lifetime of access and ID tokens when the app does not override
+/// `AuthApp::oauth_access_token_ttl`.
+pub const DEFAULT_ACCESS_TOKEN_TTL: chrono::Duration = chrono::Duration::hours(1);
+
+/// How long a client has to redeem an authorization code.
+pub const AUTHORIZATION_CODE_TTL: chrono::Duration = chrono::Duration::seconds(60);
+
+/// Scopes the provider understands. Each client may only request those in its
+/// `allowed_scopes`. `openid` is required for an ID token and userinfo.
+pub const SUPPORTED_SCOPES: &[&str] = &["openid", "profile", "email"];
+
+/// Claims in access tokens, ID tokens and userinfo besides those of permission tokens.
+pub const PROVIDER_CLAIMS: &[&str] = &[
+    "aud",
+    "client_id",
+    "scope",
+    "nonce",
+    "email",
+    "preferred_username",
+    "name",
+    "locale",
+    "zoneinfo",
+];
+
+/// Standard OAuth error codes (RFC 6749 and OpenID Connect Core).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OAuthErrorCode {
+    InvalidRequest,
+    InvalidClient,
+    InvalidGrant,
+    InvalidScope,
+    UnsupportedGrantType,
+    UnsupportedResponseType,
+    AccessDenied,
+    LoginRequired,
+    ConsentRequired,
+    ServerError,
+    /// The client is over its rate limit.
+    TemporarilyUnavailable,
+}
+
+impl OAuthErrorCode {
+    fn as_str(self) -> &'static str {
+        match self {
+            OAuthErrorCode::InvalidRequest => "invalid_request",
+            OAuthErrorCode::InvalidClient => "invalid_client",
+            OAuthErrorCode::InvalidGrant => "invalid_grant",
+            OAuthErrorCode::InvalidScope => "invalid_scope",
+            OAuthErrorCode::UnsupportedGrantType => "unsupported_grant_type",
+            OAuthErrorCode::UnsupportedResponseType => "unsupported_response_type",
+            OAuthErrorCode::AccessDenied => "access_denied",
+            OAuthErrorCode::LoginRequired => "login_required",
+            OAuthErrorCode::ConsentRequired => "consent_required",
+            OAuthErrorCode::ServerError => "server_error",
+            OAuthErrorCode::TemporarilyUnavailable => "temporarily_unavailable",
+        }
+    }
+}
+
+/// An error in the format OAuth clients expect, answered as JSON or passed back to the client's
+/// redirect URI.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct OAuthError {
+    pub error: OAuthErrorCode,
+    pub error_description: String,
+    /// Sent as `Retry-After`.
+    #[serde(skip)]
+    pub retry_after: Option<Duration>,
+}
+
+impl OAuthError {
+    pub fn new<S: Into<String>>(error: OAuthErrorCode, description: S) -> Self {
+        Self {
+            error,
+            error_description: description.into(),
+            retry_after: None,
+        }
+    }
+
+    fn rate_limited(retry_after: Duration) -> Self {
+        Self {
+            retry_after: Some(retry_after),
+            ..Self::new(
+                OAuthErrorCode::TemporarilyUnavailable,
+                "Too many requests from the client",
+            )
+        }
+    }
+
+    fn database() -> Self {
+        Self::new(OAuthErrorCode::ServerError, "Failed to reach database")
+    }
+}
+
+impl fmt::Display for OAuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.error.as_str(), self.error_description)
+    }
+}
+
+impl std::error::Error for OAuthError {}
+
+impl IntoResponse for OAuthError {
+    fn into_response(self) -> Response {
+        let status = match self.error {
+            OAuthErrorCode::InvalidClient => StatusCode::UNAUTHORIZED,
+            OAuthErrorCode::ServerError => StatusCode::INTERNAL_SERVER_ERROR,
+            OAuthErrorCode::TemporarilyUnavailable => StatusCode::TOO_MANY_REQUESTS,
+            _ => StatusCode::BAD_REQUEST,
+        };
+        let mut response = (status, no_store(), Json(&self)).into_response();
+        if let Some(retry_after) = self.retry_after {
+            response
+                .headers_mut()
+                .insert(RETRY_AFTER, HeaderValue::from(retry_after.as_secs().max(1)));
+        }
+        response
+    }
+}
+
+/// Parse a space-separated scope parameter, keeping only supported scopes in a stable order.
+pub fn parse_scopes(scope: &str) -> Result<Vec<String>, OAuthError> {
+    let requested: Vec<&str> = scope.split_whitespace().collect();
+    if let Some(unknown) = requested
+        .iter()
+        .find(|scope| !SUPPORTED_SCOPES.contains(scope))
+    {
+        return Err(OAuthError::new(
+            OAuthErrorCode::InvalidScope,
+            format!("Unsupported scope {}", unknown),
+        ));
+    }
+    Ok(SUPPORTED_SCOPES
+        .iter()
+        .filter(|scope| requested.contains(scope))
+        .map(|scope| scope.to_string())
+        .collect())
+}
+
+/// Whether `code_verifier` matches an S256 `code_challenge` (RFC 7636).
+pub fn verify_pkce(code_verifier: &str, code_challenge: &str) -> bool {
+    URL_SAFE_NO_PAD.encode(Sha256::digest(code_verifier.as_bytes())) == code_challenge
+}
+
+/// What the user approved at `/auth/oauth/authorize`, redeemed once at `/auth/oauth/token`.
+#[derive(Debug, Clone, PartialEq, Eq, FromRow)]
+pub struct AuthorizationGrant {
+    pub client_id: String,
+    pub user_id: UserId,
+    pub redirect_uri: String,
+    pub scopes: Vec<String>,
+    pub code_challenge: String,
+    pub nonce: Option<String>,
+}
+
+/// Store `grant` and return the code to hand to the client.
+pub async fn issue_authorization_code(
+    pool: &PgPool,
+    grant: &AuthorizationGrant,
+) -> Result<String, sqlx::Error> {
+    let code = random_token();
+    let now = chrono::Utc::now().naive_utc();
+    sqlx::query(
+        r#"
+        INSERT INTO auth.oauth_authorization_codes
+            (code_hash, client_id, user_id, redirect_uri, scopes, code_challenge, nonce,
+             created_at, expires_at)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)
+        "#,
+    )
+    .bind(secret_hash(&code))
+    .bind(&grant.client_id)
+    .bind(grant.user_id)
+    .bind(&grant.redirect_uri)
+    .bind(&grant.scopes)
+    .bind(&grant.code_challenge)
+    .bind(&grant.nonce)
+    .bind(now)
+    .bind(now + AUTHORIZATION_CODE_TTL)
+    .execute(pool)
+    .await?;
+    Ok(code)
+}
+
+/// The grant behind `code`, marking it used. Returns `None` for unknown, expired or already used
+/// codes.
+pub async fn redeem_authorization_code(
+    pool: &PgPool,
+    code: &str,
+) -> Result<Option<AuthorizationGrant>, sqlx::Error> {
+    let now = chrono::Utc::now().naive_utc();
+    sqlx::query_as::<_, AuthorizationGrant>(
+        r#"
+        UPDATE auth.oauth_authorization_codes
+        SET consumed_at = $2
+        WHERE code_hash = $1
+          AND consumed_at IS NULL
+          AND expires_at > $2
+        RETURNING client_id, user_id, redirect_uri, scopes, code_challenge, nonce
+        "#,
+    )
+    .bind(secret_hash(code))
+    .bind(now)
+    .fetch_optional(pool)
+    .await
+}
+
+/// Delete authorization codes that expired before `before`, used or not. Returns the number
+/// deleted.
+pub async fn purge_authorization_codes(
+    pool: &PgPool,
+    before: NaiveDateTime,
+) -> Result<u64, sqlx::Error> {
+    sqlx::query("DELETE FROM auth.oauth_authorization_codes WHERE expires_at < $1")
+        .bind(before)
+        .execute(pool)
+        .await
+        .map(|result| result.rows_affected())
+}
+
+/// Whether the user currently allows the client every one of `scopes`.
+pub async fn has_consent(
+    pool: &PgPool,
+    user_id: UserId,
+    client_id: &str,
+    scopes: &[String],
+) -> Result<bool, sqlx::Error> {
+    consent_covers(pool, user_id, client_id, scopes, None).await
+}
+
+/// Whether `scopes` are allowed as of `issued_at` and have not been revoked since. Tokens issued
+/// before a revocation stay refused even if the user grants the scopes again.
+async fn consent_covers(
+    pool: &PgPool,
+    user_id: UserId,
+    client_id: &str,
+    scopes: &[String],
+    issued_at: Option<NaiveDateTime>,
+) -> Result<bool, sqlx::Error> {
+    if scopes.is_empty() {
+        return Ok(false);
+    }
+    // `issued_at` comes from a token's `iat`, which drops the fraction of a second.
+    let (covered,): (i64,) = sqlx::query_as(
+        r#"
+        SELECT COUNT(DISTINCT scope)
+        FROM auth.consents
+        WHERE user_id = $1
+          AND client_id = $2
+          AND scope = ANY($3)
+          AND revoked_at IS NULL
+          AND ($4::TIMESTAMP IS NULL OR granted_at < $4 + INTERVAL '1 second')
+        "#,
+    )
+    .bind(user_id)
+    .bind(client_id)
+    .bind(scopes)
+    .bind(issued_at)
+    .fetch_one(pool)
+    .await?;
+    let mut wanted: Vec<&String> = scopes.iter().collect();
+    wanted.sort();
+    wanted.dedup();
+    Ok(covered == wanted.len() as i64)
+}
+
+/// Record that the user allows the client `scopes`, on top of earlier consent. Logs
+/// `oauth_consent_granted` when a scope is new or was revoked.
+pub async fn grant_consent(
+    pool: &PgPool,
+    user_id: UserId,
+    client_id: &str,
+    scopes: &[String],
+) -> Result<(), sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    // Scopes that are already allowed keep their grant time, so earlier tokens stay valid. A
+    // scope granted again counts from after the second it was revoked in, since tokens only carry
+    // whole seconds.
+    let granted: Vec<(String,)> = sqlx::query_as(
+        r#"
+        INSERT INTO auth.consents (user_id, client_id, scope, granted_at)
+        SELECT $1, $2, scope, $4
+        FROM unnest($3::TEXT[]) AS scope
+        ON CONFLICT (user_id, client_id, scope) DO UPDATE
+        SET granted_at = GREATEST(
+                EXCLUDED.granted_at,
+                date_trunc('second', auth.consents.revoked_at) + INTERVAL '1 second'
+            ),
+            revoked_at = NULL
+        WHERE auth.consents.revoked_at IS NOT NULL
+        RETURNING scope
+        "#,
+    )
+    .bind(user_id)
+    .bind(client_id)
+    .bind(scopes)
+    .bind(chrono::Utc::now().naive_utc())
+    .fetch_all(&mut *tx)
+    .await?;
+    if !granted.is_empty() {
+        insert_audit_log(
+            &mut *tx,
+            Some(user_id),
+            json!({
+                "type": "oauth_consent_granted",
+                "client_id": client_id,
+                "scopes": granted.into_iter().map(|(scope,)| scope).collect::<Vec<_>>(),
+            }),
+        )
+        .await?;
+    }
+    tx.commit().await?;
+    Ok(())
+}
+
+/// Withdraw the client's access to `scope`, or to every scope, and log `oauth_consent_revoked`.
+/// Returns the scopes revoked.
+pub async fn revoke_consent(
+    pool: &PgPool,
+    user_id: UserId,
+    client_id: &str,
+    scope: Option<&str>,
+) -> Result<Vec<String>, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    let revoked: Vec<(String,)> = sqlx::query_as(
+        r#"
+        UPDATE auth.consents
+        SET revoked_at = $4
+        WHERE user_id = $1
+          AND client_id = $2
+          AND ($3::TEXT IS NULL OR scope = $3)
+          AND revoked_at IS NULL
+        RETURNING scope
+        "#,
+    )
+    .bind(user_id)
+    .bind(client_id)
+    .bind(scope)
+    .bind(chrono::Utc::now().naive_utc())
+    .fetch_all(&mut *tx)
+    .await?;
+    let revoked: Vec<String> = revoked.into_iter().map(|(scope,)| scope).collect();
+    if !revoked.is_empty() {
+        insert_audit_log(
+            &mut *tx,
+            Some(user_id),
+            json!({
+                "type": "oauth_consent_revoked",
+                "client_id": client_id,
+                "scopes": revoked,
+            }),
+        )
+        .await?;
+    }
+    tx.commit().await?;
+    Ok(revoked)
+}
+
+/// A client the user has allowed access, with the scopes still in force.
+#[derive(Debug, Clone, PartialEq, Eq, FromRow, Serialize)]
+pub struct AppAuthorization {
+    pub client_id: String,
+    pub name: String,
+    pub scopes: Vec<String>,
+    /// When the most recent of `scopes` was granted.
+    pub granted_at: NaiveDateTime,
+}
+
+/// Clients the user currently allows access, by name.
+pub async fn list_authorizations(
+    pool: &PgPool,
+    user_id: UserId,
+) -> Result<Vec<AppAuthorization>, sqlx::Error> {
+    sqlx::query_as::<_, AppAuthorization>(
+        r#"
+        SELECT c.client_id,
+               c.name,
+               array_agg(s.scope ORDER BY s.scope) AS scopes,
+               MAX(s.granted_at) AS granted_at
+        FROM auth.consents s
+        JOIN auth.clients c ON c.client_id = s.client_id
+        WHERE s.user_id = $1
+          AND s.revoked_at IS NULL
+        GROUP BY c.client_id, c.name
+        ORDER BY c.name ASC, c.client_id ASC
+        "#,
+    )
+    .bind(user_id)
+    .fetch_all(pool)
+    .await
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct IdTokenClaims {
+    iss: String,
+    sub: Uuid,
+    aud: String,
+    iat: i64,
+    exp: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    nonce: Option<String>,
+    #[serde(flatten)]
+    profile: serde_json::Map<String, serde_json::Value>,
+}
+
+/// Claims about the user released under `scopes`, for ID tokens and userinfo.
+fn profile_claims(
+    user: &UserRow,
+    has_scope: impl Fn(&str) -> bool,
+) -> serde_json::Map<String, serde_json::Value> {
+    let mut claims = serde_json::Map::new();
+    if has_scope("email") {
+        claims.insert("email".to_string(), json!(user.email));
+    }
+    if has_scope("profile") {
+        if let Some(username) = &user.username {
+            claims.insert("preferred_username".to_string(), json!(username));
+        }
+        if let Some(name) = &user.display_name {
+            claims.insert("name".to_string(), json!(name));
+        }
+        if let Some(locale) = &user.locale {
+            claims.insert("locale".to_string(), json!(locale));
+        }
+        if let Some(timezone) = &user.timezone {
+            claims.insert("zoneinfo".to_string(), json!(timezone));
+        }
+    }
+    claims
+}
+
+#[derive(Clone, Serialize)]
+pub struct TokenResponse {
+    pub access_token: String,
+    pub token_type: &'static str,
+    pub expires_in: i64,
+    pub scope: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id_token: Option<String>,
+}
+
+impl fmt::Debug for TokenResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TokenResponse")
+            .field("access_token", &"[redacted]")
+            .field("expires_in", &self.expires_in)
+            .field("scope", &self.scope)
+            .finish_non_exhaustive()
+    }
+}
+
+/// The provider endpoints in `/auth/.well-known/openid-configuration`.
+#[derive(Debug, Clone, Serialize)]
+pub struct ProviderMetadata {
+    pub authorization_endpoint: String,
+    pub token_endpoint: String,
+    pub userinfo_endpoint: String,
+    pub response_types_supported: Vec<&'static str>,
+    pub grant_types_supported: Vec<&'static str>,
+    pub code_challenge_methods_supported: Vec<&'static str>,
+    pub scopes_supported: Vec<&'static str>,
+    pub token_endpoint_auth_methods_supported: Vec<&'static str>,
+}
+
+impl ProviderMetadata {
+    /// Endpoints under `issuer`, which is where the `/auth` routes are served.
+    pub fn for_issuer(issuer: &str) -> Self {
+        let issuer = issuer.trim_end_matches('/');
+        Self {
+            authorization_endpoint: format!("{}/oauth/authorize", issuer),
+            token_endpoint: format!("{}/oauth/token", issuer),
+            userinfo_endpoint: format!("{}/oauth/userinfo", issuer),
+            response_types_supported: vec!["code"],
+            grant_types_supported: vec!["authorization_code"],
+            code_challenge_methods_supported: vec!["S256"],
+            scopes_supported: SUPPORTED_SCOPES.to_vec(),
+            token_endpoint_auth_methods_supported: vec![
+                "client_secret_basic",
+                "client_secret_post",
+                "none",
+            ],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct AuthorizeQuery {
+    #[serde(default)]
+    pub response_type: String,
+    #[serde(default)]
+    pub client_id: String,
+    #[serde(default)]
+    pub redirect_uri: String,
+    #[serde(default)]
+    pub scope: String,
+    pub state: Option<String>,
+    pub code_challenge: Option<String>,
+    pub code_challenge_method: Option<String>,
+    pub nonce: Option<String>,
+    /// `none` answers `login_required` or `consent_required` instead of prompting.
+    pub prompt: Option<String>,
+}
+
+/// An authorization request that named a known client and one of its redirect URIs. Errors past
+/// this point go back to the client.
+struct ValidRequest {
+    client: Client,
+    redirect_uri: String,
+    state: Option<String>,
+    scopes: Vec<String>,
+    code_challenge: String,
+    nonce: Option<String>,
+}
+
+enum AuthorizeError {
+    /// The client or redirect URI could not be trusted; answer the user agent directly.
+    Direct(OAuthError),
+    /// Send the error to the client's redirect URI.
+    Redirect {
+        redirect_uri: String,
+        state: Option<String>,
+        error: OAuthError,
+    },
+}
+
+impl AuthorizeError {
+    fn into_response(self) -> Response {
+        match self {
+            AuthorizeError::Direct(error) => error.into_response(),
+            AuthorizeError::Redirect {
+                redirect_uri,
+                state,
+                error,
+            } => Redirect::to(&error_redirect(&redirect_uri, state.as_deref(), &error))
+                .into_response(),
+        }
+    }
+}
+
+async fn validate_request(
+    pool: &PgPool,
+    query: &AuthorizeQuery,
+) -> Result<ValidRequest, AuthorizeError> {
+    let client = Client::get_enabled(pool, &query.client_id)
+        .await
+        .map_err(|_| AuthorizeError::Direct(OAuthError::database()))?
+        .ok_or_else(|| {
+            AuthorizeError::Direct(OAuthError::new(
+                OAuthErrorCode::InvalidClient,
+                "Unknown client",
+            ))
+        })?;
+    if !client.allows_redirect(&query.redirect_uri) {
+        return Err(AuthorizeError::Direct(OAuthError::new(
+            OAuthErrorCode::InvalidRequest,
+            "Redirect URI is not registered for the client",
+        )));
+    }
+    let redirect = |error| AuthorizeError::Redirect {
+        redirect_uri: query.redirect_uri.clone(),
+        state: query.state.clone(),
+        error,
+    };
+    if query.response_type != "code" {
+        return Err(redirect(OAuthError::new(
+            OAuthErrorCode::UnsupportedResponseType,
+            "Only the code response type is supported",
+        )));
+    }
+    let code_challenge = match (
+        &query.code_challenge,
+        query.code_challenge_method.as_deref(),
+    ) {
+        (Some(challenge), Some("S256")) if !challenge.is_empty() => challenge.clone(),
+        _ => {
+            return Err(redirect(OAuthError::new(
+                OAuthErrorCode::InvalidRequest,
+                "PKCE with code_challenge_method S256 is required",
+            )));
+        }
+    };
+    let scopes = parse_scopes(&query.scope).map_err(redirect)?;
+    if scopes.is_empty() {
+        return Err(redirect(OAuthError::new(
+            OAuthErrorCode::InvalidScope,
+            "At least one scope is required",
+        )));
+    }
+    if let Some(denied) = scopes.iter().find(|scope| !client.allows_scope(scope)) {
+        return Err(redirect(OAuthError::new(
+            OAuthErrorCode::InvalidScope,
+            format!("Scope {} is not allowed for the client", denied),
+        )));
+    }
+    Ok(ValidRequest {
+        client,
+        redirect_uri: query.redirect_uri.clone(),
+        state: query.state.clone(),
+        scopes,
+        code_challenge,
+        nonce: query.nonce.clone(),
+    })
+}
+
+/// Whether the user exists, is active and has been approved.
+async fn can_sign_in(pool: &PgPool, user_id: UserId) -> Result<bool, sqlx::Error> {
+    let allowed: Option<(bool,)> = sqlx::query_as(
+        r#"
+        SELECT active AND NOT pending_approval
+        FROM auth.users
+        WHERE id = $1
+        "#,
+    )
+    .bind(user_id)
+    .fetch_optional(pool)
+    .await?;
+    Ok(allowed.is_some_and(|(allowed,)| allowed))
+}
+
+async fn issue_code_redirect(
+    pool: &PgPool,
+    request: &ValidRequest,
+    user_id: UserId,
+) -> Result<String, AuthorizeError> {
+    let grant = AuthorizationGrant {
+        client_id: request.client.client_id.clone(),
+        user_id,
+        redirect_uri: request.redirect_uri.clone(),
+        scopes: request.scopes.clone(),
+        code_challenge: request.code_challenge.clone(),
+        nonce: request.nonce.clone(),
+    };
+    let code =
+        issue_authorization_code(pool, &grant)
+            .await
+            .map_err(|_| AuthorizeError::Redirect {
+                redirect_uri: request.redirect_uri.clone(),
+                state: request.state.clone(),
+                error: OAuthError::database(),
+            })?;
+    let mut pairs = vec![("code", code.as_str())];
+    pairs.extend(request.state.as_deref().map(|state| ("state", state)));
+    Ok(with_query(&request.redirect_uri, &pairs))
+}
+
+/// Start of the authorization code flow. Signed-out users are sent to
+/// `AuthApp::oauth_login_url` and come back here; untrusted clients need the user's consent,
+/// collected by the page at `AuthApp::oauth_consent_url`.
+pub async fn authorize_handler<S>(
+    app: State<S>,
+    MaybeAuthenticatedUser(auth_user): MaybeAuthenticatedUser,
+    OriginalUri(uri): OriginalUri,
+    Query(query): Query<AuthorizeQuery>,
+) -> Response
+where
+    S: AuthApp + Clone + Send + Sync + 'static,
+{
+    let pool = app.pool();
+    let request = match validate_request(&pool, &query).await {
+        Ok(request) => request,
+        Err(err) => return err.into_response(),
+    };
+    let prompt_none = query.prompt.as_deref() == Some("none");
+    let redirect = |error| AuthorizeError::Redirect {
+        redirect_uri: request.redirect_uri.clone(),
+        state: request.state.clone(),
+        error,
+    };
+
+    let Some(auth_user) = auth_user else {
+        if prompt_none {
+            return redirect(OAuthError::new(
+                OAuthErrorCode::LoginRequired,
+                "The user is not signed in",
+            ))
+            .into_response();
+        }
+        return Redirect::to(&app.oauth_login_url(&uri.to_string())).into_response();
+    };
+    match can_sign_in(&pool, auth_user.id()).await {
+        Ok(true) => {}
+        Ok(false) => {
+            return redirect(OAuthError::new(
+                OAuthErrorCode::AccessDenied,
+                "The account cannot sign in to other applications",
+            ))
+            .into_response();
+        }
+        Err(_) => return redirect(OAuthError::database()).into_response(),
+    }
+
+    if request.client.trusted {
+        // Recorded so the user can see and revoke the client like any other.
+        if grant_consent(
+            &pool,
+            auth_user.id(),
+            &request.client.client_id,
+            &request.scopes,
+        )
+        .await
+        .is_err()
+        {
+            return redirect(OAuthError::database()).into_response();
+        }
+    } else {
+        let consented = match has_consent(
+            &pool,
+            auth_user.id(),
+            &request.client.client_id,
+            &request.scopes,
+        )
+        .await
+        {
+            Ok(consented) => consented,
+            Err(_) => return redirect(OAuthError::database()).into_response(),
+        };
+        if !consented {
+            let consent_url = app.oauth_consent_url(&request.client, uri.query().unwrap_or(""));
+            return match consent_url {
+                Some(consent_url) if !prompt_none => Redirect::to(&consent_url).into_response(),
+                _ => redirect(OAuthError::new(
+                    OAuthErrorCode::ConsentRequired,
+                    "The user has not approved the client",
+                ))
+                .into_response(),
+            };
+        }
+    }
+
+    match issue_code_redirect(&pool, &request, auth_user.id()).await {
+        Ok(location) => Redirect::to(&location).into_response(),
+        Err(err) => err.into_response(),
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ConsentPayload {
+    #[serde(flatten)]
+    pub request: AuthorizeQuery,
+    pub approve: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ConsentResponse {
+    /// Where to send the user agent: the client's redirect URI with a code or an error.
+    pub redirect_to: String,
+}
+
+/// The user's answer on the consent page, with the parameters of the original authorization
+/// request. Not stored by caches or `IdempotencyLayer`: the redirect carries the code.
+pub async fn consent_handler<S>(
+    app: State<S>,
+    auth_user: AuthenticatedUser,
+    Json(payload): Json<ConsentPayload>,
+) -> Result<impl IntoResponse, OAuthError>
+where
+    S: AuthApp + Clone + Send + Sync + 'static,
+{
+    let pool = app.pool();
+    let request = match validate_request(&pool, &payload.request).await {
+        Ok(request) => request,
+        Err(AuthorizeError::Direct(err)) => return Err(err),
+        Err(AuthorizeError::Redirect {
+            redirect_uri,
+            state,
+            error,
+        }) => {
+            return Ok((
+                no_store(),
+                Json(ConsentResponse {
+                    redirect_to: error_redirect(&redirect_uri, state.as_deref(), &error),
+                }),
+            ));
+        }
+    };
+    let denied = |description: &str| {
+        let response = Json(ConsentResponse {
+            redirect_to: error_redirect(
+                &request.redirect_uri,
+                request.state.as_deref(),
+                &OAuthError::new(OAuthErrorCode::AccessDenied, description),
+            ),
+        });
+        (no_store(), response)
+    };
+    if !payload.approve {
+        return Ok(denied("The user declined"));
+    }
+    if !can_sign_in(&pool, auth_user.id())
+        .await
+        .map_err(|_| OAuthError::database())?
+    {
+        return Ok(denied("The account cannot sign in to other applications"));
+    }
+
+    grant_consent(
+        &pool,
+        auth_user.id(),
+        &request.client.client_id,
+        &request.scopes,
+    )
+    .await
+    .map_err(|_| OAuthError::database())?;
+    match issue_code_redirect(&pool, &request, auth_user.id()).await {
+        Ok(redirect_to) => Ok((no_store(), Json(ConsentResponse { redirect_to }))),
+        Err(_) => Err(OAuthError::database()),
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct TokenRequest {
+    #[serde(default)]
+    pub grant_type: String,
+    pub code: Option<String>,
+    pub redirect_uri: Option<String>,
+    pub client_id: Option<String>,
+    pub client_secret: Option<String>,
+    pub code_verifier: Option<String>,
+}
+
+/// Exchange an authorization code for an access token and, with the `openid` scope, an ID token.
+/// Confidential clients authenticate with HTTP Basic or `client_secret` in the form. Requests
+/// count against the client's `rate_limit_per_minute`.
+pub async fn token_handler<S>(
+    app: State<S>,
+    headers: HeaderMap,
+    Form(form): Form<TokenRequest>,
+) -> Result<Response, OAuthError>
+where
+    S: AuthApp + Clone + Send + Sync + 'static,
+{
+    if form.grant_type != "authorization_code" {
+        return Err(OAuthError::new(
+            OAuthErrorCode::UnsupportedGrantType,
+            "Only the authorization_code grant is supported",
+        ));
+    }
+    let (Some(issuer), Some(key_ring)) = (app.token_issuer(), app.key_ring()) else {
+        return Err(OAuthError::new(
+            OAuthErrorCode::ServerError,
+            "Token issuance is not configured",
+        ));
+    };
+    let pool = app.pool();
+
+    let (client_id, client_secret) = match basic_credentials(&headers) {
+        Some((client_id, client_secret)) => (Some(client_id), Some(client_secret)),
+        None => (form.client_id.clone(), form.client_secret.clone()),
+    };
+    let client_id = client_id.ok_or_else(|| {
+        OAuthError::new(OAuthErrorCode::InvalidClient, "Missing client credentials")
+    })?;
+    let client = Client::authenticate(&pool, &client_id, client_secret.as_deref())
+        .await
+        .map_err(|_| OAuthError::database())?
+        .ok_or_else(|| {
+            OAuthError::new(
+                OAuthErrorCode::InvalidClient,
+                "Client authentication failed",
+            )
+        })?;
+    app.client_rate_limiter()
+        .check(&client)
+        .map_err(OAuthError::rate_limited)?;
+
+    let (Some(code), Some(code_verifier)) = (&form.code, &form.code_verifier) else {
+        return Err(OAuthError::new(
+            OAuthErrorCode::InvalidRequest,
+            "code and code_verifier are required",
+        ));
+    };
+    let invalid_grant = || OAuthError::new(OAuthErrorCode::InvalidGrant, "Invalid code");
+    let grant = redeem_authorization_code(&pool, code)
+        .await
+        .map_err(|_| OAuthError::database())?
+        .ok_or_else(invalid_grant)?;
+    if grant.client_id != client_id
+        || form.redirect_uri.as_deref() != Some(grant.redirect_uri.as_str())
+        || !verify_pkce(code_verifier, &grant.code_challenge)
+    {
+        return Err(invalid_grant());
+    }
+    let user_id = grant.user_id;
+    let user = match UserRow::get(&pool, user_id).await {
+        Ok(Some(user)) if can_sign_in(&pool, user_id).await.unwrap_or(false) => user,
+        Ok(_) => return Err(invalid_grant()),
+        Err(_) => return Err(OAuthError::database()),
+    };
+    if !has_consent(&pool, user_id, &client_id, &grant.scopes)
+        .await
+        .map_err(|_| OAuthError::database())?
+    {
+        return Err(OAuthError::new(
+            OAuthErrorCode::InvalidGrant,
+            "The user revoked the client's access",
+        ));
+    }
+
+    let ttl = app.oauth_access_token_ttl();
+    let now = chrono::Utc::now();
+    let scope = grant.scopes.join(" ");
+    let access_claims = AccessTokenClaims {
+        iss: issuer.to_string(),
+        sub: grant.user_id.0,
+        aud: client_id.clone(),
+        client_id: client_id.clone(),
+        scope: scope.clone(),
+        iat: now.timestamp(),
+        exp: (now + ttl).timestamp(),
+        jti: Uuid::new_v4(),
+    };
+    let signing_error = |err| {
+        tracing::error!("Failed to sign OAuth token: {}", err);
+        OAuthError::new(OAuthErrorCode::ServerError, "Failed to sign token")
+    };
+    let access_token = key_ring
+        .sign_typed(ACCESS_TOKEN_TYPE, &access_claims)
+        .map_err(signing_error)?;
+    let id_token = if grant.scopes.iter().any(|scope| scope == "openid") {
+        let id_claims = IdTokenClaims {
+            iss: issuer.to_string(),
+            sub: grant.user_id.0,
+            aud: client_id.clone(),
+            iat: access_claims.iat,
+            exp: access_claims.exp,
+            nonce: grant.nonce.clone(),
+            profile: profile_claims(&user, |scope| grant.scopes.iter().any(|s| s == scope)),
+        };
+        Some(key_ring.sign(&id_claims).map_err(signing_error)?)
+    } else {
+        None
+    };
+
+    Ok((
+        no_store(),
+        Json(TokenResponse {
+            access_token,
+            token_type: "Bearer",
+            expires_in: ttl.num_seconds(),
+            scope,
+            id_token,
+        }),
+    )
+        .into_response())
+}
+
+/// Claims about the holder of an access token with the `openid` scope. Tokens stop working here
+/// once the user revokes any of their scopes.
+pub async fn userinfo_handler<S>(
+    app: State<S>,
+    headers: HeaderMap,
+) -> Result<impl IntoResponse, RejectReason>
+where
+    S: AuthApp + Clone + Send + Sync + 'static,
+{
+    let (Some(issuer), Some(key_ring)) = (app.token_issuer(), app.key_ring()) else {
+        return Err(RejectReason::not_found("token issuer"));
+    };
+    let token = headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .ok_or_else(|| RejectReason::auth(AuthRejectReason::no_session_token()))?;
+    let claims = key_ring
+        .verify_with::<AccessTokenClaims>(token, |validation| {
+            validation.set_issuer(&[issuer]);
+            // Any registered client's token may ask for userinfo.
+            validation.validate_aud = false;
+        })
+        .map_err(|err| {
+            RejectReason::auth(AuthRejectReason::invalid_session_token(err.to_string()))
+        })?;
+    if !claims.has_scope("openid") {
+        return Err(RejectReason::forbidden(
+            UserId(claims.sub),
+            "Access token lacks the openid scope",
+        ));
+    }
+    // Checked on the primary so a revocation takes effect at once.
+    let scopes: Vec<String> = claims.scope.split_whitespace().map(String::from).collect();
+    let revoked = || {
+        RejectReason::auth(AuthRejectReason::invalid_session_token(
+            "Access to the client was revoked",
+        ))
+    };
+    let issued_at = chrono::DateTime::from_timestamp(claims.iat, 0).ok_or_else(revoked)?;
+    if !consent_covers(
+        &app.pool(),
+        UserId(claims.sub),
+        &claims.client_id,
+        &scopes,
+        Some(issued_at.naive_utc()),
+    )
+    .await
+    .map_err(|_| RejectReason::database("Failed to reach database"))?
+    {
+        return Err(revoked());
+    }
+
+    let pool = app.reader_pool();
+    let user = UserRow::get(&pool, UserId(claims.sub))
+        .await
+        .map_err(|_| RejectReason::database("Failed to reach database"))?
+        .ok_or_else(|| RejectReason::not_found("user"))?;
+    let mut body = profile_claims(&user, |scope| claims.has_scope(scope));
+    body.insert("sub".to_string(), json!(claims.sub));
+    Ok((no_store(), Json(body)))
+}
+
+/// Clients the signed-in user has allowed access, with the scopes each may use.
+pub async fn authorizations_handler<S>(
+    app: State<S>,
+    auth_user: AuthenticatedUser,
+) -> Result<impl IntoResponse, RejectReason>
+where
+    S: AuthApp + Clone + Send + Sync + 'static,
+{
+    let pool = app.reader_pool();
+    let authorizations = list_authorizations(&pool, auth_user.id())
+        .await
+        .map_err(|_| RejectReason::database("Failed to reach database"))?;
+    Ok(Json(authorizations))
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct RevokeAuthorizationQuery {
+    /// Revoke only this scope instead of all of the client's access.
+    pub scope: Option<String>,
+}
+
+/// Withdraw a client's access for the signed-in user. The client needs the user's consent again,
+/// or for trusted clients a new sign-in, to get tokens.
+pub async fn revoke_authorization_handler<S>(
+    app: State<S>,
+    auth_user: AuthenticatedUser,
+    Path(client_id): Path<String>,
+    Query(query): Query<RevokeAuthorizationQuery>,
+) -> Result<impl IntoResponse, RejectReason>
+where
+    S: AuthApp + Clone + Send + Sync + 'static,
+{
+    let pool = app.pool();
+    let revoked = revoke_consent(&pool, auth_user.id(), &client_id, query.scope.as_deref())
+        .await
+        .map_err(|_| RejectReason::database("Failed to reach database"))?;
+    if revoked.is_empty() {
+        return Err(RejectReason::not_found("authorization"));
+    }
+    Ok(Json(json!({ "revoked": revoked })))
+}
+
+/// Login URL used by `AuthApp::oauth_login_url` unless the app overrides it. The return URL is
+/// encoded twice because `auth::auth` decodes the stored origin once more.
+pub fn default_login_url(return_to: &str) -> String {
+    format!(
+        "/auth/login?origin={}",
+        urlencoding::encode(&urlencoding::encode(return_to))
+    )
+}
+
+fn basic_credentials(headers: &HeaderMap) -> Option<(String, String)> {
+    let encoded = headers
+        .get(AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Basic ")?;
+    let decoded = String::from_utf8(STANDARD.decode(encoded.trim()).ok()?).ok()?;
+    let (client_id, client_secret) = decoded.split_once(':')?;
+    Some((
+        urlencoding::decode(client_id).ok()?.into_owned(),
+        urlencoding::decode(client_secret).ok()?.into_owned(),
+    ))
+}
+
+fn no_store() -> [(hyper::header::HeaderName, &'static str); 2] {
+    [(CACHE_CONTROL, "no-store"), (PRAGMA, "no-cache")]
+}
+
+fn with_query(uri: &str, pairs: &[(&str, &str)]) -> String {
+    match Url::parse(uri) {
+        Ok(mut url) => {
+            url.query_pairs_mut().extend_pairs(pairs);
+            url.to_string()
+        }
+        // Registered redirect URIs are absolute; keep anything else working as a relative URI.
+        Err(_) => {
+            let query = url::form_urlencoded::Serializer::new(String::new())
+                .extend_pairs(pairs)
+                .finish();
+            let separator = if uri.contains('?') { '&' } else { '?' };
+            format!("{}{}{}", uri, separator, query)
+        }
+    }
+}
+
+fn error_redirect(redirect_uri: &str, state: Option<&str>, error: &OAuthError) -> String {
+    let mut pairs = vec![
+        ("error", error.error.as_str()),
+        ("error_description", error.error_description.as_str()),
+    ];
+    pairs.extend(state.map(|state| ("state", state)));
+    with_query(redirect_uri, &pairs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{OAuthErrorCode, parse_scopes, verify_pkce, with_query};
+
+    #[test]
+    fn parses_scopes_and_checks_pkce() {
+        assert_eq!(
+            parse_scopes("email openid email").unwrap(),
+            vec!["openid".to_string(), "email".to_string()]
+        );
+        assert_eq!(
+            parse_scopes("openid admin").unwrap_err().error,
+            OAuthErrorCode::InvalidScope
+        );
+        // RFC 7636, appendix B.
+        assert!(verify_pkce(
+            "dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk",
+            "E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw-cM"
+        ));
+        assert!(!verify_pkce(
+            "other",
+            "E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw-cM"
+        ));
+        assert_eq!(
+            with_query("https://app.example.com/cb?x=1", &[("code", "a b")]),
+            "https://app.example.com/cb?x=1&code=a+b"
+        );
+    }
+}
@@ -1,21 +1,27 @@
 use std::str::FromStr;
 
 use anyhow::{Result as AnyResult, anyhow};
+use jsonwebtoken::jwk::JwkSet;
 use openidconnect::core::{
-    CoreAuthenticationFlow, CoreClient, CoreIdToken, CoreIdTokenClaims, CoreTokenResponse,
+    CoreAuthPrompt, CoreAuthenticationFlow, CoreClient, CoreIdToken, CoreIdTokenClaims,
+    CoreTokenResponse,
 };
 use openidconnect::reqwest::Error as RequestError;
 use openidconnect::{
-    AccessToken, AccessTokenHash, Audience, AuthorizationCode, ClaimsVerificationError, ClientId,
-    ClientSecret, CsrfToken, EndSessionUrl, HttpRequest, HttpResponse, IssuerUrl, Nonce,
-    NonceVerifier, OAuth2TokenResponse, PkceCodeChallenge, PkceCodeVerifier,
-    ProviderMetadataWithLogout, RedirectUrl, RefreshToken, Scope, SignatureVerificationError,
-    SigningError, TokenResponse,
+    AccessToken, AccessTokenHash, Audience, AuthenticationContextClass, AuthorizationCode,
+    ClaimsVerificationError, ClientId, ClientSecret, CsrfToken, EndSessionUrl, ErrorResponse,
+    HttpRequest, HttpResponse, IssuerUrl, Nonce, NonceVerifier, OAuth2TokenResponse,
+    PkceCodeChallenge, PkceCodeVerifier, ProviderMetadataWithLogout, RedirectUrl, RefreshToken,
+    RequestTokenError, Scope, SignatureVerificationError, SigningError, TokenResponse,
 };
 use reqwest::{Client, redirect::Policy};
 use serde::{Deserialize, Serialize};
 use url::Url;
 
+use crate::breaker::{
+    CallError, CircuitBreaker, CircuitBreakerConfig, ProviderHealth, ProviderUnavailable,
+};
+use crate::logout::{FederatedLogout, LogoutTokenError, verify_logout_token};
 use crate::rustls::get_cert_pool;
 
 fn new_client() -> Client {
@@ -97,6 +103,10 @@ impl OidcToken {
         })
     }
 
+    pub fn id_token(&self) -> &CoreIdToken {
+        &self.id_token
+    }
+
     pub fn refresh(self, token: CoreTokenResponse) -> Option<Self> {
         Some(Self {
             id_token: token.id_token().cloned()?,
@@ -196,6 +206,12 @@ pub struct IdentityProvider {
     allowed_other_audiences: Option<AllowedOtherAudiencesInternal>,
     base_url: Url,
     logout_url: EndSessionUrl,
+    issuer: String,
+    client_id: String,
+    /// The provider's signing keys, for logout tokens; ID tokens are checked by `client`.
+    jwks: JwkSet,
+    mfa_acr: Option<AuthenticationContextClass>,
+    breaker: CircuitBreaker,
 }
 
 impl IdentityProvider {
@@ -211,11 +227,21 @@ impl IdentityProvider {
             .end_session_endpoint
             .clone()
             .ok_or_else(|| anyhow!("No logout URL"))?;
+        let issuer = config.issuer().to_string();
+        let jwks = serde_json::to_value(config.jwks())
+            .and_then(serde_json::from_value)
+            .unwrap_or_else(|err| {
+                tracing::warn!(
+                    "Unsupported provider keys; logout tokens will be refused: {}",
+                    err
+                );
+                JwkSet { keys: Vec::new() }
+            });
 
         let client = CoreClient::from_provider_metadata(
             config,
             oidc.client_id.clone(),
-            oidc.client_secret.as_ref().map(|secret| secret.clone()),
+            oidc.client_secret.clone(),
         )
         .set_redirect_uri(oidc.redirect_url.clone());
 
@@ -233,6 +259,59 @@ impl IdentityProvider {
             allowed_other_audiences,
             base_url: oidc.base_url.clone(),
             logout_url,
+            issuer,
+            client_id: oidc.client_id.as_str().to_string(),
+            jwks,
+            mfa_acr: None,
+            breaker: CircuitBreaker::new(idp_url.as_str(), CircuitBreakerConfig::default()),
+        })
+    }
+
+    /// Replace the default circuit breaker around token endpoint calls.
+    pub fn with_circuit_breaker(mut self, config: CircuitBreakerConfig) -> Self {
+        self.breaker = CircuitBreaker::new(self.breaker.name(), config);
+        self
+    }
+
+    /// Authentication context requested by `reauth_oidc` when MFA is required, e.g. Okta's
+    /// `urn:okta:loa:2fa:any`. Without one, the provider's own policy decides whether MFA is asked.
+    pub fn with_mfa_acr<S: Into<String>>(mut self, acr: S) -> Self {
+        self.mfa_acr = Some(AuthenticationContextClass::new(acr.into()));
+        self
+    }
+
+    /// The provider's issuer identifier.
+    pub fn issuer(&self) -> &str {
+        &self.issuer
+    }
+
+    /// Error rate, latency and circuit state of token endpoint calls.
+    pub fn health(&self) -> ProviderHealth {
+        self.breaker.health()
+    }
+
+    /// Call the token endpoint through the circuit breaker. Error responses from the provider,
+    /// e.g. an expired code, do not count against it.
+    async fn request_token<F, RE, T>(
+        &self,
+        request: F,
+    ) -> Result<CoreTokenResponse, CallError<RequestTokenError<RE, T>>>
+    where
+        F: std::future::Future<Output = Result<CoreTokenResponse, RequestTokenError<RE, T>>>,
+        RE: std::error::Error + 'static,
+        T: ErrorResponse + 'static,
+    {
+        self.breaker
+            .call(request, |err| {
+                !matches!(err, RequestTokenError::ServerResponse(_))
+            })
+            .await
+    }
+
+    fn unavailable(&self, retry_after: Option<std::time::Duration>) -> anyhow::Error {
+        anyhow::Error::new(ProviderUnavailable {
+            provider: self.breaker.name().to_string(),
+            retry_after,
         })
     }
 
@@ -250,12 +329,22 @@ impl IdentityProvider {
             .exchange_refresh_token(refresh_token)
             .add_scope(Scope::new("offline_access".into()));
         tracing::trace!("token_request: {:?}", token_request);
-        let token_response = match token_request.request_async(async_http_client).await {
+        let token_response = match self
+            .request_token(token_request.request_async(async_http_client))
+            .await
+        {
             Ok(tok) => tok,
-            Err(e) => {
+            Err(CallError::Open { retry_after }) => return Err(self.unavailable(Some(retry_after))),
+            Err(CallError::TimedOut) => return Err(self.unavailable(None)),
+            Err(CallError::Failed(e @ RequestTokenError::ServerResponse(_))) => {
                 tracing::debug!("Error refreshing token: {:?}", e);
                 return Err(anyhow!("Error refreshing token"));
             }
+            // The provider could not be reached or answered nonsense, rather than refusing.
+            Err(CallError::Failed(e)) => {
+                tracing::debug!("Error refreshing token: {:?}", e);
+                return Err(self.unavailable(None));
+            }
         };
         tracing::trace!("refresh request");
         match token.refresh(token_response) {
@@ -265,6 +354,25 @@ impl IdentityProvider {
     }
 
     pub fn login_oidc(&self, scopes: Vec<String>) -> (Url, CsrfToken, PkceCodeVerifier, Nonce) {
+        self.authorize_url(scopes, None)
+    }
+
+    /// Like `login_oidc`, but the provider has to ask for credentials again even with a session
+    /// of its own (`prompt=login`, `max_age=0`). With `mfa`, the `with_mfa_acr` authentication
+    /// context is requested too.
+    pub fn reauth_oidc(
+        &self,
+        scopes: Vec<String>,
+        mfa: bool,
+    ) -> (Url, CsrfToken, PkceCodeVerifier, Nonce) {
+        self.authorize_url(scopes, Some(mfa))
+    }
+
+    fn authorize_url(
+        &self,
+        scopes: Vec<String>,
+        reauth_mfa: Option<bool>,
+    ) -> (Url, CsrfToken, PkceCodeVerifier, Nonce) {
         let (challenge, verifier) = PkceCodeChallenge::new_random_sha256();
         let mut auth_builder = self.client.authorize_url(
             CoreAuthenticationFlow::AuthorizationCode,
@@ -274,6 +382,14 @@ impl IdentityProvider {
         for scope in scopes {
             auth_builder = auth_builder.add_scope(Scope::new(scope));
         }
+        if let Some(mfa) = reauth_mfa {
+            auth_builder = auth_builder
+                .add_prompt(CoreAuthPrompt::Login)
+                .set_max_age(std::time::Duration::ZERO);
+            if mfa && let Some(acr) = &self.mfa_acr {
+                auth_builder = auth_builder.add_auth_context_value(acr.clone());
+            }
+        }
         let (auth_url, csrf_token, nonce) = auth_builder.set_pkce_challenge(challenge).url();
         (auth_url, csrf_token, verifier, nonce)
     }
@@ -288,18 +404,28 @@ impl IdentityProvider {
         logout_url
     }
 
+    /// Verify a back-channel logout token the provider posted. Record the result with
+    /// `logout::record_federated_logout`, or use `auth::backchannel_logout`.
+    pub fn verify_logout_token(&self, token: &str) -> Result<FederatedLogout, LogoutTokenError> {
+        verify_logout_token(token, &self.jwks, &self.issuer, &self.client_id)
+    }
+
     pub async fn token_oidc(
         &self,
         code: AuthorizationCode,
         verifier: PkceCodeVerifier,
         nonce: Nonce,
     ) -> AnyResult<OidcToken> {
-        let token_response = self
-            .client
-            .exchange_code(code)
-            .set_pkce_verifier(verifier)
-            .request_async(async_http_client)
-            .await?;
+        let token_request = self.client.exchange_code(code).set_pkce_verifier(verifier);
+        let token_response = match self
+            .request_token(token_request.request_async(async_http_client))
+            .await
+        {
+            Ok(tok) => tok,
+            Err(CallError::Open { retry_after }) => return Err(self.unavailable(Some(retry_after))),
+            Err(CallError::TimedOut) => return Err(self.unavailable(None)),
+            Err(CallError::Failed(e)) => return Err(e.into()),
+        };
         let oidc_token = OidcToken::from_token_response(token_response, nonce)?;
         tracing::trace!("validate_token");
         self.validate_token(&oidc_token)?;
@@ -316,13 +442,7 @@ impl IdentityProvider {
             .id_token_verifier()
             .set_other_audience_verifier_fn(|aud: &Audience| match &self.allowed_other_audiences {
                 Some(AllowedOtherAudiencesInternal::Any) => true,
-                Some(AllowedOtherAudiencesInternal::List(list)) => {
-                    if list.contains(aud) {
-                        true
-                    } else {
-                        false
-                    }
-                }
+                Some(AllowedOtherAudiencesInternal::List(list)) => list.contains(aud),
                 None => false,
             });
         let id_token = CoreIdToken::from_str(token).map_err(|_| {
@@ -344,13 +464,7 @@ impl IdentityProvider {
             .id_token_verifier()
             .set_other_audience_verifier_fn(|aud: &Audience| match &self.allowed_other_audiences {
                 Some(AllowedOtherAudiencesInternal::Any) => true,
-                Some(AllowedOtherAudiencesInternal::List(list)) => {
-                    if list.contains(aud) {
-                        true
-                    } else {
-                        false
-                    }
-                }
+                Some(AllowedOtherAudiencesInternal::List(list)) => list.contains(aud),
                 None => false,
             });
         let id_token = &token.id_token;
@@ -0,0 +1,701 @@
+use std::fmt;
+
+use futures_util::future::BoxFuture;
+use once_cell::sync::Lazy;
+use serde::Serialize;
+
+/// Passwords rejected outright regardless of length, compared case-insensitively.
+pub const COMMON_PASSWORDS: &[&str] = &[
+    "123456",
+    "123456789",
+    "12345678",
+    "1234567890",
+    "111111",
+    "000000",
+    "password",
+    "password1",
+    "password123",
+    "passw0rd",
+    "qwerty",
+    "qwerty123",
+    "qwertyuiop",
+    "1q2w3e4r",
+    "1qaz2wsx",
+    "abc123",
+    "iloveyou",
+    "admin",
+    "admin123",
+    "welcome",
+    "welcome1",
+    "letmein",
+    "monkey",
+    "dragon",
+    "football",
+    "baseball",
+    "sunshine",
+    "princess",
+    "superman",
+    "trustno1",
+    "starwars",
+    "whatever",
+    "changeme",
+    "secret",
+    "master",
+    "login",
+    "shadow",
+    "michael",
+    "jennifer",
+    "zaq12wsx",
+    "asdfghjkl",
+    "correcthorsebatterystaple",
+];
+
+pub static DEFAULT_PASSWORD_POLICY: Lazy<PasswordPolicy> = Lazy::new(PasswordPolicy::default);
+
+/// Why a password scored low, for guiding the user towards a better one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PasswordFeedback {
+    TooCommon,
+    ContainsUserInput,
+    Repetitive,
+    Sequential,
+    SingleCharacterClass,
+    Short,
+}
+
+/// Estimated guessing resistance, scored 0 (trivial) to 4 (strong) on the same scale as zxcvbn.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PasswordStrength {
+    pub score: u8,
+    pub guesses_log10: f64,
+    pub feedback: Vec<PasswordFeedback>,
+}
+
+const SCORE_THRESHOLDS_LOG10: [f64; 4] = [3.0, 6.0, 8.0, 10.0];
+
+fn score_for(guesses_log10: f64) -> u8 {
+    SCORE_THRESHOLDS_LOG10
+        .iter()
+        .take_while(|threshold| guesses_log10 >= **threshold)
+        .count() as u8
+}
+
+/// Estimate how many guesses an attacker needs for `password`.
+///
+/// Characters continuing a repeat (`aaa`) or a run (`abc`, `321`) add almost nothing; everything
+/// else adds the entropy of the character classes used. Common passwords and passwords built from
+/// `user_inputs` (email, username) score 0.
+pub fn estimate_strength(password: &str, user_inputs: &[&str]) -> PasswordStrength {
+    let lowered = password.to_lowercase();
+    let mut feedback = Vec::new();
+
+    if COMMON_PASSWORDS.contains(&lowered.as_str()) {
+        return PasswordStrength {
+            score: 0,
+            guesses_log10: 0.0,
+            feedback: vec![PasswordFeedback::TooCommon],
+        };
+    }
+    let contains_input = user_inputs.iter().any(|input| {
+        let input = input.trim().to_lowercase();
+        let local = input.split('@').next().unwrap_or_default();
+        (local.chars().count() >= 3 && lowered.contains(local)) || lowered == input
+    });
+    if contains_input {
+        feedback.push(PasswordFeedback::ContainsUserInput);
+    }
+
+    let chars: Vec<char> = password.chars().collect();
+    let (mut lower, mut upper, mut digit, mut symbol, mut other) =
+        (false, false, false, false, false);
+    for c in &chars {
+        match c {
+            'a'..='z' => lower = true,
+            'A'..='Z' => upper = true,
+            '0'..='9' => digit = true,
+            c if c.is_ascii() => symbol = true,
+            _ => other = true,
+        }
+    }
+    let classes = [lower, upper, digit, symbol, other];
+    let pool: u32 = [26, 26, 10, 33, 100]
+        .iter()
+        .zip(classes)
+        .filter(|(_, used)| *used)
+        .map(|(size, _)| size)
+        .sum();
+    if classes.iter().filter(|used| **used).count() == 1 {
+        feedback.push(PasswordFeedback::SingleCharacterClass);
+    }
+
+    let (mut repeats, mut sequential) = (0usize, 0usize);
+    for window in chars.windows(2) {
+        let (prev, next) = (window[0] as i64, window[1] as i64);
+        if prev == next {
+            repeats += 1;
+        } else if (next - prev).abs() == 1 {
+            sequential += 1;
+        }
+    }
+    if repeats * 3 >= chars.len().max(1) {
+        feedback.push(PasswordFeedback::Repetitive);
+    }
+    if sequential * 3 >= chars.len().max(1) {
+        feedback.push(PasswordFeedback::Sequential);
+    }
+
+    let free_chars = chars.len() - repeats - sequential;
+    let bits = free_chars as f64 * f64::from(pool.max(1)).log2() + (repeats + sequential) as f64;
+    let mut guesses_log10 = bits * std::f64::consts::LOG10_2;
+    if contains_input {
+        guesses_log10 = guesses_log10.min(SCORE_THRESHOLDS_LOG10[0] - 0.01);
+    }
+    if chars.len() < 8 {
+        feedback.push(PasswordFeedback::Short);
+    }
+
+    PasswordStrength {
+        score: score_for(guesses_log10),
+        guesses_log10,
+        feedback,
+    }
+}
+
+/// A rule a password failed.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum PasswordViolation {
+    TooShort { min: usize },
+    TooLong { max: usize },
+    TooWeak { score: u8, min_score: u8 },
+    Breached { count: u64 },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PasswordRejection {
+    pub violations: Vec<PasswordViolation>,
+    pub strength: PasswordStrength,
+}
+
+impl fmt::Display for PasswordRejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let reasons: Vec<String> = self
+            .violations
+            .iter()
+            .map(|violation| match violation {
+                PasswordViolation::TooShort { min } => format!("shorter than {} characters", min),
+                PasswordViolation::TooLong { max } => format!("longer than {} characters", max),
+                PasswordViolation::TooWeak { .. } => "too easy to guess".to_string(),
+                PasswordViolation::Breached { .. } => "found in a data breach".to_string(),
+            })
+            .collect();
+        write!(f, "Password is {}", reasons.join(", "))
+    }
+}
+
+impl std::error::Error for PasswordRejection {}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BreachCheckError(pub String);
+
+impl fmt::Display for BreachCheckError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Breach check failed: {}", self.0)
+    }
+}
+
+impl std::error::Error for BreachCheckError {}
+
+/// Looks up how often a password appears in known breaches.
+pub trait BreachChecker: Send + Sync {
+    fn breach_count<'a>(
+        &'a self,
+        password: &'a str,
+    ) -> BoxFuture<'a, Result<u64, BreachCheckError>>;
+}
+
+/// Rules for local passwords, applied when one is set or reset.
+#[derive(Debug, Clone)]
+pub struct PasswordPolicy {
+    min_length: usize,
+    max_length: usize,
+    min_score: u8,
+    max_breach_count: u64,
+    fail_open: bool,
+}
+
+impl Default for PasswordPolicy {
+    fn default() -> Self {
+        Self {
+            min_length: 10,
+            max_length: 256,
+            min_score: 3,
+            max_breach_count: 0,
+            fail_open: true,
+        }
+    }
+}
+
+impl PasswordPolicy {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_min_length(mut self, min_length: usize) -> Self {
+        self.min_length = min_length;
+        self
+    }
+
+    pub fn with_max_length(mut self, max_length: usize) -> Self {
+        self.max_length = max_length;
+        self
+    }
+
+    /// Minimum `estimate_strength` score, 0 to 4.
+    pub fn with_min_score(mut self, min_score: u8) -> Self {
+        self.min_score = min_score.min(4);
+        self
+    }
+
+    /// Allow passwords seen in up to this many breaches. Defaults to 0.
+    pub fn with_max_breach_count(mut self, max_breach_count: u64) -> Self {
+        self.max_breach_count = max_breach_count;
+        self
+    }
+
+    /// Reject passwords when the breach checker is unreachable instead of skipping the check.
+    pub fn with_breach_check_required(mut self, required: bool) -> Self {
+        self.fail_open = !required;
+        self
+    }
+
+    /// Check `password` against every rule, collecting all violations. `user_inputs` are values
+    /// the password should not be built from, such as the email and username.
+    pub async fn validate(
+        &self,
+        password: &str,
+        user_inputs: &[&str],
+        breach_checker: Option<&dyn BreachChecker>,
+    ) -> Result<PasswordStrength, PasswordRejection> {
+        let length = password.chars().count();
+        let strength = estimate_strength(password, user_inputs);
+        let mut violations = Vec::new();
+        if length < self.min_length {
+            violations.push(PasswordViolation::TooShort {
+                min: self.min_length,
+            });
+        }
+        if length > self.max_length {
+            violations.push(PasswordViolation::TooLong {
+                max: self.max_length,
+            });
+        }
+        if strength.score < self.min_score {
+            violations.push(PasswordViolation::TooWeak {
+                score: strength.score,
+                min_score: self.min_score,
+            });
+        }
+
+        if let Some(breach_checker) = breach_checker {
+            match breach_checker.breach_count(password).await {
+                Ok(count) if count > self.max_breach_count => {
+                    violations.push(PasswordViolation::Breached { count });
+                }
+                Ok(_) => {}
+                Err(err) if self.fail_open => tracing::warn!("Skipping breach check: {}", err),
+                Err(err) => {
+                    tracing::warn!("Rejecting password without breach check: {}", err);
+                    violations.push(PasswordViolation::Breached { count: 0 });
+                }
+            }
+        }
+
+        if violations.is_empty() {
+            Ok(strength)
+        } else {
+            Err(PasswordRejection {
+                violations,
+                strength,
+            })
+        }
+    }
+}
+
+#[cfg(feature = "hibp")]
+pub const HIBP_RANGE_URL: &str = "https://api.pwnedpasswords.com/range/";
+
+/// Have I Been Pwned password lookup using k-anonymity: only the first five hex characters of
+/// the password's SHA-1 leave the process.
+#[cfg(feature = "hibp")]
+#[derive(Debug, Clone)]
+pub struct HibpBreachChecker {
+    client: reqwest::Client,
+    range_url: String,
+}
+
+#[cfg(feature = "hibp")]
+impl Default for HibpBreachChecker {
+    fn default() -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            range_url: HIBP_RANGE_URL.to_string(),
+        }
+    }
+}
+
+#[cfg(feature = "hibp")]
+impl HibpBreachChecker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Query a mirror of the range API instead of the public one.
+    pub fn with_range_url<S: Into<String>>(mut self, range_url: S) -> Self {
+        self.range_url = range_url.into();
+        self
+    }
+}
+
+#[cfg(feature = "hibp")]
+impl BreachChecker for HibpBreachChecker {
+    fn breach_count<'a>(
+        &'a self,
+        password: &'a str,
+    ) -> BoxFuture<'a, Result<u64, BreachCheckError>> {
+        use sha1::{Digest, Sha1};
+
+        Box::pin(async move {
+            let digest: String = Sha1::digest(password.as_bytes())
+                .iter()
+                .map(|byte| format!("{:02X}", byte))
+                .collect();
+            let (prefix, suffix) = digest.split_at(5);
+            let body = self
+                .client
+                .get(format!("{}{}", self.range_url, prefix))
+                // Padded responses hide the real number of matches from observers.
+                .header("Add-Padding", "true")
+                .send()
+                .await
+                .and_then(|response| response.error_for_status())
+                .map_err(|err| BreachCheckError(err.to_string()))?
+                .text()
+                .await
+                .map_err(|err| BreachCheckError(err.to_string()))?;
+            Ok(body
+                .lines()
+                .filter_map(|line| line.trim().split_once(':'))
+                .find(|(candidate, _)| candidate.eq_ignore_ascii_case(suffix))
+                .and_then(|(_, count)| count.trim().parse().ok())
+                .unwrap_or(0))
+        })
+    }
+}
+
+/// Password hash algorithm, identified by the prefix of a stored hash.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HashScheme {
+    Argon2id,
+    Bcrypt,
+    Scrypt,
+}
+
+impl HashScheme {
+    /// Scheme of a stored hash: `$argon2id$`, `$2a$`/`$2b$`/`$2x$`/`$2y$` or `$scrypt$`.
+    pub fn identify(hash: &str) -> Option<Self> {
+        if hash.starts_with("$argon2id$") {
+            Some(Self::Argon2id)
+        } else if ["$2a$", "$2b$", "$2x$", "$2y$"]
+            .iter()
+            .any(|prefix| hash.starts_with(prefix))
+        {
+            Some(Self::Bcrypt)
+        } else if hash.starts_with("$scrypt$") {
+            Some(Self::Scrypt)
+        } else {
+            None
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Argon2id => "argon2id",
+            Self::Bcrypt => "bcrypt",
+            Self::Scrypt => "scrypt",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PasswordHashError {
+    /// The stored hash does not start with a supported scheme prefix.
+    UnknownScheme,
+    /// The stored hash has a known prefix but cannot be parsed.
+    Malformed(String),
+    Hashing(String),
+}
+
+impl fmt::Display for PasswordHashError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnknownScheme => write!(f, "Unsupported password hash scheme"),
+            Self::Malformed(msg) => write!(f, "Malformed password hash: {}", msg),
+            Self::Hashing(msg) => write!(f, "Password hashing failed: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for PasswordHashError {}
+
+/// Outcome of `PasswordHasher::verify_and_upgrade`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PasswordVerification {
+    Invalid,
+    /// The password matched. `rehashed` is a replacement hash when the stored one uses another
+    /// scheme or weaker parameters than the hasher is configured for.
+    Valid {
+        rehashed: Option<String>,
+    },
+}
+
+#[cfg(feature = "password-hashing")]
+pub static DEFAULT_PASSWORD_HASHER: Lazy<PasswordHasher> = Lazy::new(PasswordHasher::default);
+
+/// Hashes new passwords with the preferred scheme and verifies hashes of every supported scheme,
+/// so imported legacy hashes keep working until they are upgraded on the next successful login.
+///
+/// Hashing is deliberately slow; call it from `spawn_blocking` in async code.
+#[cfg(feature = "password-hashing")]
+#[derive(Debug, Clone)]
+pub struct PasswordHasher {
+    preferred: HashScheme,
+    argon2_params: argon2::Params,
+    bcrypt_cost: u32,
+    scrypt_params: scrypt::Params,
+}
+
+#[cfg(feature = "password-hashing")]
+impl Default for PasswordHasher {
+    fn default() -> Self {
+        Self {
+            preferred: HashScheme::Argon2id,
+            argon2_params: argon2::Params::default(),
+            bcrypt_cost: bcrypt::DEFAULT_COST,
+            scrypt_params: scrypt::Params::recommended(),
+        }
+    }
+}
+
+#[cfg(feature = "password-hashing")]
+impl PasswordHasher {
+    pub fn new(preferred: HashScheme) -> Self {
+        Self {
+            preferred,
+            ..Self::default()
+        }
+    }
+
+    pub fn preferred(&self) -> HashScheme {
+        self.preferred
+    }
+
+    pub fn with_argon2_params(mut self, params: argon2::Params) -> Self {
+        self.argon2_params = params;
+        self
+    }
+
+    pub fn with_bcrypt_cost(mut self, cost: u32) -> Self {
+        self.bcrypt_cost = cost;
+        self
+    }
+
+    pub fn with_scrypt_params(mut self, params: scrypt::Params) -> Self {
+        self.scrypt_params = params;
+        self
+    }
+
+    fn argon2(&self) -> argon2::Argon2<'static> {
+        argon2::Argon2::new(
+            argon2::Algorithm::Argon2id,
+            argon2::Version::V0x13,
+            self.argon2_params.clone(),
+        )
+    }
+
+    /// Hash `password` with the preferred scheme.
+    pub fn hash(&self, password: &str) -> Result<String, PasswordHashError> {
+        use password_hash::{PasswordHasher as _, SaltString, rand_core::OsRng};
+
+        let salt = SaltString::generate(&mut OsRng);
+        let hashed = match self.preferred {
+            HashScheme::Argon2id => self
+                .argon2()
+                .hash_password(password.as_bytes(), &salt)
+                .map(|hash| hash.to_string()),
+            HashScheme::Scrypt => scrypt::Scrypt
+                .hash_password_customized(
+                    password.as_bytes(),
+                    None,
+                    None,
+                    self.scrypt_params,
+                    &salt,
+                )
+                .map(|hash| hash.to_string()),
+            HashScheme::Bcrypt => {
+                return bcrypt::hash(password, self.bcrypt_cost)
+                    .map_err(|err| PasswordHashError::Hashing(err.to_string()));
+            }
+        };
+        hashed.map_err(|err| PasswordHashError::Hashing(err.to_string()))
+    }
+
+    /// Check `password` against a stored hash of any supported scheme.
+    pub fn verify(&self, password: &str, hash: &str) -> Result<bool, PasswordHashError> {
+        use password_hash::{PasswordHash, PasswordVerifier};
+
+        let scheme = HashScheme::identify(hash).ok_or(PasswordHashError::UnknownScheme)?;
+        if scheme == HashScheme::Bcrypt {
+            return bcrypt::verify(password, hash)
+                .map_err(|err| PasswordHashError::Malformed(err.to_string()));
+        }
+        let parsed =
+            PasswordHash::new(hash).map_err(|err| PasswordHashError::Malformed(err.to_string()))?;
+        let verified = match scheme {
+            HashScheme::Argon2id => self.argon2().verify_password(password.as_bytes(), &parsed),
+            _ => scrypt::Scrypt.verify_password(password.as_bytes(), &parsed),
+        };
+        match verified {
+            Ok(()) => Ok(true),
+            Err(password_hash::Error::Password) => Ok(false),
+            Err(err) => Err(PasswordHashError::Malformed(err.to_string())),
+        }
+    }
+
+    /// Whether a stored hash should be replaced: it uses another scheme than the preferred one,
+    /// or different parameters than configured. Unparseable hashes are left alone.
+    pub fn needs_rehash(&self, hash: &str) -> bool {
+        use password_hash::PasswordHash;
+
+        let Some(scheme) = HashScheme::identify(hash) else {
+            return false;
+        };
+        if scheme != self.preferred {
+            return true;
+        }
+        match scheme {
+            HashScheme::Bcrypt => hash
+                .get(4..6)
+                .and_then(|cost| cost.parse::<u32>().ok())
+                .is_some_and(|cost| cost < self.bcrypt_cost),
+            HashScheme::Argon2id => PasswordHash::new(hash)
+                .ok()
+                .and_then(|parsed| argon2::Params::try_from(&parsed).ok())
+                .is_some_and(|params| {
+                    (params.m_cost(), params.t_cost(), params.p_cost())
+                        != (
+                            self.argon2_params.m_cost(),
+                            self.argon2_params.t_cost(),
+                            self.argon2_params.p_cost(),
+                        )
+                }),
+            HashScheme::Scrypt => PasswordHash::new(hash)
+                .ok()
+                .and_then(|parsed| scrypt::Params::try_from(&parsed).ok())
+                .is_some_and(|params| {
+                    (params.log_n(), params.r(), params.p())
+                        != (
+                            self.scrypt_params.log_n(),
+                            self.scrypt_params.r(),
+                            self.scrypt_params.p(),
+                        )
+                }),
+        }
+    }
+
+    /// Verify a login and, when it succeeds, produce a replacement hash if `needs_rehash`.
+    pub fn verify_and_upgrade(
+        &self,
+        password: &str,
+        hash: &str,
+    ) -> Result<PasswordVerification, PasswordHashError> {
+        if !self.verify(password, hash)? {
+            return Ok(PasswordVerification::Invalid);
+        }
+        let rehashed = if self.needs_rehash(hash) {
+            Some(self.hash(password)?)
+        } else {
+            None
+        };
+        Ok(PasswordVerification::Valid { rehashed })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{PasswordFeedback, PasswordPolicy, PasswordViolation, estimate_strength};
+
+    #[test]
+    fn strength_penalizes_common_patterns() {
+        assert_eq!(estimate_strength("Password", &[]).score, 0);
+        assert!(estimate_strength("aaaaaaaaaaaa", &[]).score <= 1);
+        assert!(estimate_strength("abcdefghijkl", &[]).score <= 1);
+        let with_input = estimate_strength("jdoe-2026!x", &["jdoe@example.com"]);
+        assert_eq!(with_input.score, 0);
+        assert!(
+            with_input
+                .feedback
+                .contains(&PasswordFeedback::ContainsUserInput)
+        );
+        assert_eq!(estimate_strength("v7#Lq9!zR2@mW4", &[]).score, 4);
+    }
+
+    #[tokio::test]
+    async fn policy_collects_all_violations() {
+        let policy = PasswordPolicy::default();
+        let rejection = policy.validate("qwerty", &[], None).await.unwrap_err();
+        assert_eq!(
+            rejection.violations,
+            vec![
+                PasswordViolation::TooShort { min: 10 },
+                PasswordViolation::TooWeak {
+                    score: 0,
+                    min_score: 3
+                },
+            ]
+        );
+        assert!(policy.validate("v7#Lq9!zR2@mW4", &[], None).await.is_ok());
+    }
+
+    #[cfg(feature = "password-hashing")]
+    #[test]
+    fn legacy_hashes_verify_and_upgrade() {
+        use super::{HashScheme, PasswordHasher, PasswordVerification};
+
+        let legacy = PasswordHasher::new(HashScheme::Bcrypt).with_bcrypt_cost(4);
+        let stored = legacy.hash("correct horse").unwrap();
+        assert_eq!(HashScheme::identify(&stored), Some(HashScheme::Bcrypt));
+
+        let hasher = PasswordHasher::default();
+        assert!(hasher.needs_rehash(&stored));
+        assert_eq!(
+            hasher.verify_and_upgrade("wrong horse", &stored),
+            Ok(PasswordVerification::Invalid)
+        );
+        let Ok(PasswordVerification::Valid {
+            rehashed: Some(rehashed),
+        }) = hasher.verify_and_upgrade("correct horse", &stored)
+        else {
+            panic!("expected an upgraded hash");
+        };
+        assert_eq!(HashScheme::identify(&rehashed), Some(HashScheme::Argon2id));
+        assert!(!hasher.needs_rehash(&rehashed));
+        assert!(hasher.verify("correct horse", &rehashed).unwrap());
+        assert_eq!(
+            hasher.verify("correct horse", "md5$abc"),
+            Err(super::PasswordHashError::UnknownScheme)
+        );
+    }
+}
@@ -0,0 +1,182 @@
+//! The ordered feed of grant and membership changes behind `GET /auth/sync/changes`.
+//!
+//! A downstream cache reads the feed from cursor 0, which starts with every row that existed when
+//! the feed was created, applies each change in order, and keeps the returned cursor to ask for
+//! the changes after it next time. Scopes are as stored, so a grant pinned to an environment has
+//! its `scope@environment` scope.
+
+use chrono::NaiveDateTime;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sqlx::{FromRow, PgPool};
+
+use crate::db::RoleEffect;
+use crate::group_id::GroupId;
+use crate::user_id::UserId;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, sqlx::Type)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(type_name = "TEXT", rename_all = "lowercase")]
+pub enum ChangeOperation {
+    /// The record as it is now; replaces any cached record with the same key.
+    Upsert,
+    /// The record as it was before it was removed.
+    Delete,
+}
+
+/// A grant or membership row. The keys are every field but `effect` and `condition` for grants,
+/// and `group_id` and `user_id` for memberships.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum PermissionRecord {
+    UserRole {
+        user_id: UserId,
+        scope: String,
+        scope_id: String,
+        role_name: String,
+        #[serde(default)]
+        effect: RoleEffect,
+        #[serde(default)]
+        condition: Option<Value>,
+    },
+    GroupRole {
+        group_id: GroupId,
+        scope: String,
+        scope_id: String,
+        role_name: String,
+        #[serde(default)]
+        effect: RoleEffect,
+        #[serde(default)]
+        condition: Option<Value>,
+    },
+    Membership {
+        group_id: GroupId,
+        user_id: UserId,
+        role_name: String,
+        /// `GroupCapabilities` bits delegated on top of the role's.
+        #[serde(default)]
+        capabilities: i64,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PermissionChange {
+    /// Position in the feed; pass it as `since` to read the changes after this one.
+    pub cursor: i64,
+    pub changed_at: NaiveDateTime,
+    pub operation: ChangeOperation,
+    #[serde(flatten)]
+    pub record: PermissionRecord,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PermissionChangePage {
+    pub changes: Vec<PermissionChange>,
+    /// The cursor of the last change, or `since` if there are none.
+    pub cursor: i64,
+    /// More changes follow; read again from `cursor` right away.
+    pub has_more: bool,
+}
+
+#[derive(FromRow)]
+struct ChangeRow {
+    id: i64,
+    kind: String,
+    operation: ChangeOperation,
+    record: Value,
+    changed_at: NaiveDateTime,
+}
+
+impl TryFrom<ChangeRow> for PermissionChange {
+    type Error = sqlx::Error;
+
+    fn try_from(row: ChangeRow) -> Result<Self, Self::Error> {
+        let mut record = row.record;
+        if let Value::Object(fields) = &mut record {
+            fields.insert("kind".to_string(), Value::String(row.kind));
+        }
+        let record =
+            serde_json::from_value(record).map_err(|err| sqlx::Error::Decode(Box::new(err)))?;
+        Ok(Self {
+            cursor: row.id,
+            changed_at: row.changed_at,
+            operation: row.operation,
+            record,
+        })
+    }
+}
+
+/// Up to `limit` changes after the `since` cursor, oldest first.
+pub async fn permission_changes(
+    pool: &PgPool,
+    since: i64,
+    limit: i64,
+) -> Result<PermissionChangePage, sqlx::Error> {
+    let mut rows = sqlx::query_as::<_, ChangeRow>(
+        r#"
+        SELECT id, kind, operation, record, changed_at
+        FROM auth.permission_changes
+        WHERE id > $1
+        ORDER BY id ASC
+        LIMIT $2
+        "#,
+    )
+    .bind(since)
+    .bind(limit + 1)
+    .fetch_all(pool)
+    .await?;
+    let has_more = rows.len() as i64 > limit;
+    rows.truncate(limit as usize);
+    let changes = rows
+        .into_iter()
+        .map(PermissionChange::try_from)
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(PermissionChangePage {
+        cursor: changes.last().map_or(since, |change| change.cursor),
+        changes,
+        has_more,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::{ChangeOperation, ChangeRow, PermissionChange, PermissionRecord};
+
+    #[test]
+    fn decodes_trigger_records() {
+        let user_id = uuid::Uuid::new_v4();
+        let row = ChangeRow {
+            id: 7,
+            kind: "user_role".to_string(),
+            operation: ChangeOperation::Delete,
+            record: json!({
+                "user_id": user_id,
+                "scope": "project",
+                "scope_id": "p1",
+                "role_name": "editor",
+                "effect": "deny",
+                "condition": null,
+                "created_at": "2026-04-10T00:00:00",
+                "source_provider": null,
+            }),
+            changed_at: chrono::DateTime::UNIX_EPOCH.naive_utc(),
+        };
+        let change = PermissionChange::try_from(row).unwrap();
+        assert_eq!(change.cursor, 7);
+        let PermissionRecord::UserRole {
+            user_id: decoded,
+            effect,
+            ..
+        } = change.record
+        else {
+            panic!("expected a user role");
+        };
+        assert_eq!(decoded.0, user_id);
+        assert_eq!(effect, crate::db::RoleEffect::Deny);
+        let serialized = serde_json::to_value(&change).unwrap();
+        assert_eq!(serialized["kind"], "user_role");
+        assert_eq!(serialized["operation"], "delete");
+    }
+}
@@ -0,0 +1,447 @@
+use std::collections::HashSet;
+
+use chrono::NaiveDateTime;
+use jsonwebtoken::jwk::JwkSet;
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use uuid::Uuid;
+
+use crate::group_id::GroupId;
+use crate::keys::KeyError;
+
+#[cfg(feature = "sqlx")]
+pub use issue::{
+    IssuedPermissionToken, PermissionTokenError, RevokeTarget, issue_permission_token,
+    revoke_permission_tokens, revoked_permission_tokens,
+};
+
+/// How long a permission token is valid when the caller does not ask for less.
+pub const DEFAULT_PERMISSION_TOKEN_TTL: chrono::Duration = chrono::Duration::minutes(5);
+
+/// Longest lifetime a caller can ask for.
+pub const DEFAULT_PERMISSION_TOKEN_MAX_TTL: chrono::Duration = chrono::Duration::hours(1);
+
+/// Lifetimes of the permission tokens issued by `/auth/permission-tokens`. A token is a snapshot,
+/// so role changes reach edge services only once it expires or is revoked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PermissionTokenPolicy {
+    ttl: chrono::Duration,
+    max_ttl: chrono::Duration,
+}
+
+impl Default for PermissionTokenPolicy {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PermissionTokenPolicy {
+    pub const fn new() -> Self {
+        Self {
+            ttl: DEFAULT_PERMISSION_TOKEN_TTL,
+            max_ttl: DEFAULT_PERMISSION_TOKEN_MAX_TTL,
+        }
+    }
+
+    pub fn with_ttl(mut self, ttl: chrono::Duration) -> Self {
+        self.ttl = ttl;
+        self.max_ttl = self.max_ttl.max(ttl);
+        self
+    }
+
+    pub fn with_max_ttl(mut self, max_ttl: chrono::Duration) -> Self {
+        self.max_ttl = max_ttl;
+        self.ttl = self.ttl.min(max_ttl);
+        self
+    }
+
+    pub fn ttl(&self) -> chrono::Duration {
+        self.ttl
+    }
+
+    pub fn max_ttl(&self) -> chrono::Duration {
+        self.max_ttl
+    }
+
+    /// The lifetime for a caller asking for `requested`, capped at `max_ttl`.
+    pub fn ttl_for(&self, requested: Option<chrono::Duration>) -> chrono::Duration {
+        requested
+            .filter(|requested| *requested > chrono::Duration::zero())
+            .map_or(self.ttl, |requested| requested.min(self.max_ttl))
+    }
+}
+
+pub static DEFAULT_PERMISSION_TOKEN_POLICY: PermissionTokenPolicy = PermissionTokenPolicy::new();
+
+/// A grant in a permission token. Grants inherited through several groups appear once.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PermissionGrant {
+    pub scope: String,
+    pub scope_id: String,
+    pub role: String,
+    /// Set only for deny grants.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub deny: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub condition: Option<Value>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PermissionMembership {
+    pub group_id: GroupId,
+    pub role: String,
+}
+
+/// Claims of a permission token: the user's memberships and grants when it was issued.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PermissionClaims {
+    /// `AuthApp::token_issuer`, when the app sets one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub iss: Option<String>,
+    pub sub: Uuid,
+    pub jti: Uuid,
+    pub iat: i64,
+    pub exp: i64,
+    #[serde(default)]
+    pub groups: Vec<PermissionMembership>,
+    #[serde(default)]
+    pub grants: Vec<PermissionGrant>,
+}
+
+impl PermissionClaims {
+    /// Whether an allow grant for `role` on `scope`/`scope_id` is present and not denied.
+    /// Conditional grants are ignored; evaluate `grants` directly to honor them.
+    pub fn allows(&self, scope: &str, scope_id: &str, role: &str) -> bool {
+        let matching = |grant: &&PermissionGrant| {
+            grant.scope == scope
+                && grant.scope_id == scope_id
+                && grant.role == role
+                && grant.condition.is_none()
+        };
+        let mut grants = self.grants.iter().filter(matching);
+        grants.clone().any(|grant| !grant.deny) && !grants.any(|grant| grant.deny)
+    }
+}
+
+/// Checks permission tokens offline, e.g. in an edge service, with the keys published at
+/// `/auth/.well-known/jwks.json` and the ids published at `/auth/permission-tokens/revocations`.
+/// Both lists should be refreshed periodically.
+#[derive(Debug, Clone)]
+pub struct PermissionTokenVerifier {
+    jwks: JwkSet,
+    issuer: Option<String>,
+    revoked: HashSet<Uuid>,
+}
+
+impl PermissionTokenVerifier {
+    pub fn new(jwks: JwkSet) -> Self {
+        Self {
+            jwks,
+            issuer: None,
+            revoked: HashSet::new(),
+        }
+    }
+
+    /// Only accept tokens whose `iss` is `issuer`.
+    pub fn with_issuer<S: Into<String>>(mut self, issuer: S) -> Self {
+        self.issuer = Some(issuer.into());
+        self
+    }
+
+    pub fn with_revoked<I: IntoIterator<Item = Uuid>>(mut self, revoked: I) -> Self {
+        self.revoked.extend(revoked);
+        self
+    }
+
+    pub fn set_revoked<I: IntoIterator<Item = Uuid>>(&mut self, revoked: I) {
+        self.revoked = revoked.into_iter().collect();
+    }
+
+    pub fn verify(&self, token: &str) -> Result<PermissionClaims, KeyError> {
+        let header =
+            jsonwebtoken::decode_header(token).map_err(|err| KeyError::Invalid(err.to_string()))?;
+        if header.alg != Algorithm::EdDSA {
+            return Err(KeyError::Invalid(format!(
+                "unsupported algorithm {:?}",
+                header.alg
+            )));
+        }
+        let kid = header.kid.ok_or(KeyError::UnknownKey(None))?;
+        let jwk = self
+            .jwks
+            .find(&kid)
+            .ok_or_else(|| KeyError::UnknownKey(Some(kid.clone())))?;
+        let key = DecodingKey::from_jwk(jwk).map_err(|err| KeyError::Invalid(err.to_string()))?;
+        let mut validation = Validation::new(Algorithm::EdDSA);
+        if let Some(issuer) = &self.issuer {
+            validation.set_issuer(&[issuer]);
+        }
+        let claims = jsonwebtoken::decode::<PermissionClaims>(token, &key, &validation)
+            .map(|data| data.claims)
+            .map_err(|err| KeyError::Invalid(err.to_string()))?;
+        if self.revoked.contains(&claims.jti) {
+            return Err(KeyError::Revoked);
+        }
+        Ok(claims)
+    }
+}
+
+/// A revoked token that has not expired yet. Edge services can drop it after `expires_at`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "sqlx", derive(sqlx::FromRow))]
+pub struct RevokedPermissionToken {
+    pub jti: Uuid,
+    pub expires_at: NaiveDateTime,
+}
+
+#[cfg(feature = "sqlx")]
+mod issue {
+    use std::fmt;
+
+    use chrono::NaiveDateTime;
+    use serde::Serialize;
+    use serde_json::json;
+    use sqlx::PgPool;
+    use uuid::Uuid;
+
+    use super::{PermissionClaims, PermissionGrant, PermissionMembership, RevokedPermissionToken};
+    use crate::access::access_snapshot;
+    use crate::db::{RoleAssignmentTarget, RoleEffect, insert_audit_log};
+    use crate::keys::{KeyError, KeyRing};
+    use crate::user_id::UserId;
+
+    #[derive(Debug)]
+    pub enum PermissionTokenError {
+        Database(sqlx::Error),
+        Signing(KeyError),
+    }
+
+    impl fmt::Display for PermissionTokenError {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                PermissionTokenError::Database(err) => write!(f, "Database error: {}", err),
+                PermissionTokenError::Signing(err) => write!(f, "Signing error: {}", err),
+            }
+        }
+    }
+
+    impl std::error::Error for PermissionTokenError {}
+
+    impl From<sqlx::Error> for PermissionTokenError {
+        fn from(err: sqlx::Error) -> Self {
+            PermissionTokenError::Database(err)
+        }
+    }
+
+    #[derive(Clone, Serialize)]
+    pub struct IssuedPermissionToken {
+        pub token: String,
+        pub jti: Uuid,
+        pub expires_at: NaiveDateTime,
+    }
+
+    impl fmt::Debug for IssuedPermissionToken {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.debug_struct("IssuedPermissionToken")
+                .field("token", &"[redacted]")
+                .field("jti", &self.jti)
+                .field("expires_at", &self.expires_at)
+                .finish()
+        }
+    }
+
+    /// Snapshot `user_id`'s access into a token signed by `key_ring`, valid for `ttl`. The key
+    /// ring needs an Ed25519 active key for edge services to verify the token.
+    pub async fn issue_permission_token(
+        pool: &PgPool,
+        key_ring: &KeyRing,
+        issuer: Option<&str>,
+        user_id: UserId,
+        ttl: chrono::Duration,
+    ) -> Result<IssuedPermissionToken, PermissionTokenError> {
+        let snapshot = access_snapshot(pool, RoleAssignmentTarget::User(user_id)).await?;
+        let issued_at = snapshot.taken_at;
+        let expires_at = issued_at
+            .checked_add_signed(ttl)
+            .unwrap_or(NaiveDateTime::MAX);
+
+        let mut grants: Vec<PermissionGrant> = snapshot
+            .grants
+            .into_iter()
+            .map(|grant| PermissionGrant {
+                scope: grant.scope,
+                scope_id: grant.scope_id,
+                role: grant.role_name,
+                deny: grant.effect == RoleEffect::Deny,
+                condition: grant.condition,
+            })
+            .collect();
+        grants.sort_by(|a, b| {
+            (&a.scope, &a.scope_id, &a.role, a.deny).cmp(&(&b.scope, &b.scope_id, &b.role, b.deny))
+        });
+        grants.dedup();
+
+        let claims = PermissionClaims {
+            iss: issuer.map(str::to_string),
+            sub: user_id.0,
+            jti: Uuid::new_v4(),
+            iat: issued_at.and_utc().timestamp(),
+            exp: expires_at.and_utc().timestamp(),
+            groups: snapshot
+                .memberships
+                .into_iter()
+                .map(|membership| PermissionMembership {
+                    group_id: membership.group_id,
+                    role: membership.role_name,
+                })
+                .collect(),
+            grants,
+        };
+        let token = key_ring
+            .sign(&claims)
+            .map_err(PermissionTokenError::Signing)?;
+
+        sqlx::query(
+            r#"
+            INSERT INTO auth.permission_tokens (jti, user_id, issued_at, expires_at)
+            VALUES ($1, $2, $3, $4)
+            "#,
+        )
+        .bind(claims.jti)
+        .bind(user_id)
+        .bind(issued_at)
+        .bind(expires_at)
+        .execute(pool)
+        .await?;
+
+        Ok(IssuedPermissionToken {
+            token,
+            jti: claims.jti,
+            expires_at,
+        })
+    }
+
+    /// Which tokens `revoke_permission_tokens` revokes.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum RevokeTarget {
+        Token(Uuid),
+        /// Every unexpired token issued to the user.
+        User(UserId),
+    }
+
+    /// Revoke unexpired tokens and log `permission_tokens_revoked`. Returns how many were
+    /// revoked.
+    pub async fn revoke_permission_tokens(
+        pool: &PgPool,
+        actor_user_id: UserId,
+        target: RevokeTarget,
+    ) -> Result<u64, sqlx::Error> {
+        let (jti, user_id) = match target {
+            RevokeTarget::Token(jti) => (Some(jti), None),
+            RevokeTarget::User(user_id) => (None, Some(user_id)),
+        };
+        let now = chrono::Utc::now().naive_utc();
+        let mut tx = pool.begin().await?;
+        let revoked = sqlx::query(
+            r#"
+            UPDATE auth.permission_tokens
+            SET revoked_at = $3,
+                revoked_by = $4
+            WHERE (jti = $1 OR user_id = $2)
+              AND revoked_at IS NULL
+              AND expires_at > $3
+            "#,
+        )
+        .bind(jti)
+        .bind(user_id)
+        .bind(now)
+        .bind(actor_user_id)
+        .execute(&mut *tx)
+        .await?
+        .rows_affected();
+        if revoked > 0 {
+            insert_audit_log(
+                &mut *tx,
+                Some(actor_user_id),
+                json!({
+                    "type": "permission_tokens_revoked",
+                    "jti": jti,
+                    "target_user_id": user_id,
+                    "count": revoked,
+                }),
+            )
+            .await?;
+        }
+        tx.commit().await?;
+        Ok(revoked)
+    }
+
+    /// Revoked tokens that have not expired, oldest expiry first.
+    pub async fn revoked_permission_tokens(
+        pool: &PgPool,
+    ) -> Result<Vec<RevokedPermissionToken>, sqlx::Error> {
+        sqlx::query_as::<_, RevokedPermissionToken>(
+            r#"
+            SELECT jti, expires_at
+            FROM auth.permission_tokens
+            WHERE revoked_at IS NOT NULL
+              AND expires_at > $1
+            ORDER BY expires_at ASC
+            "#,
+        )
+        .bind(chrono::Utc::now().naive_utc())
+        .fetch_all(pool)
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Duration;
+
+    use super::{PermissionClaims, PermissionGrant, PermissionTokenPolicy};
+
+    fn grant(role: &str, deny: bool) -> PermissionGrant {
+        PermissionGrant {
+            scope: "project".to_string(),
+            scope_id: "p1".to_string(),
+            role: role.to_string(),
+            deny,
+            condition: None,
+        }
+    }
+
+    #[test]
+    fn caps_ttl_and_honors_denies() {
+        let policy = PermissionTokenPolicy::new()
+            .with_ttl(Duration::minutes(10))
+            .with_max_ttl(Duration::minutes(30));
+        assert_eq!(policy.ttl_for(None), Duration::minutes(10));
+        assert_eq!(
+            policy.ttl_for(Some(Duration::hours(2))),
+            Duration::minutes(30)
+        );
+        assert_eq!(
+            policy.ttl_for(Some(Duration::seconds(-5))),
+            Duration::minutes(10)
+        );
+
+        let claims = PermissionClaims {
+            iss: None,
+            sub: uuid::Uuid::new_v4(),
+            jti: uuid::Uuid::new_v4(),
+            iat: 0,
+            exp: 0,
+            groups: Vec::new(),
+            grants: vec![
+                grant("reader", false),
+                grant("writer", false),
+                grant("writer", true),
+            ],
+        };
+        assert!(claims.allows("project", "p1", "reader"));
+        assert!(!claims.allows("project", "p1", "writer"));
+        assert!(!claims.allows("project", "p2", "reader"));
+    }
+}
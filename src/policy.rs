@@ -0,0 +1,265 @@
+use std::convert::Infallible;
+use std::fmt;
+use std::future;
+use std::net::{IpAddr, SocketAddr};
+
+use axum::extract::{ConnectInfo, FromRequestParts};
+use axum::http::Extensions;
+use axum::http::request::Parts;
+use chrono::{DateTime, Timelike, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::prelude::AuthenticatedUser;
+
+/// Conditions attached to a role grant. The grant only applies when every present field holds
+/// for the request.
+///
+/// Stored as JSON on the grant, e.g. `{"ip_cidr": "10.0.0.0/8", "hours": "09-18", "mfa": true}`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct GrantCondition {
+    /// The client address must fall in this block, e.g. `10.0.0.0/8` or a single address.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ip_cidr: Option<String>,
+    /// UTC hours as `HH-HH`, start inclusive and end exclusive. Wraps past midnight when the
+    /// start is after the end, so `22-06` covers the night.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hours: Option<String>,
+    /// Whether the session must have been authenticated with multiple factors.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mfa: Option<bool>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConditionError {
+    /// Not a JSON object with the known condition fields.
+    Malformed(String),
+    InvalidCidr(String),
+    InvalidHours(String),
+}
+
+impl fmt::Display for ConditionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConditionError::Malformed(err) => write!(f, "Malformed grant condition: {}", err),
+            ConditionError::InvalidCidr(cidr) => write!(f, "Invalid ip_cidr: {}", cidr),
+            ConditionError::InvalidHours(hours) => {
+                write!(f, "Invalid hours (expected HH-HH): {}", hours)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConditionError {}
+
+impl GrantCondition {
+    /// Parse and validate a stored or submitted condition.
+    pub fn parse(value: &Value) -> Result<Self, ConditionError> {
+        let condition: Self = serde_json::from_value(value.clone())
+            .map_err(|err| ConditionError::Malformed(err.to_string()))?;
+        if let Some(cidr) = &condition.ip_cidr
+            && parse_cidr(cidr).is_none()
+        {
+            return Err(ConditionError::InvalidCidr(cidr.clone()));
+        }
+        if let Some(hours) = &condition.hours
+            && parse_hours(hours).is_none()
+        {
+            return Err(ConditionError::InvalidHours(hours.clone()));
+        }
+        Ok(condition)
+    }
+
+    pub fn to_value(&self) -> Value {
+        serde_json::to_value(self).unwrap_or(Value::Null)
+    }
+
+    /// Whether the condition holds for the request. Fields that cannot be evaluated, like an IP
+    /// range when the client address is unknown, do not hold.
+    pub fn evaluate(&self, context: &RequestContext) -> bool {
+        if let Some(cidr) = &self.ip_cidr {
+            let Some(ip) = context.client_ip else {
+                return false;
+            };
+            if !parse_cidr(cidr).is_some_and(|(network, prefix)| in_network(network, prefix, ip)) {
+                return false;
+            }
+        }
+        if let Some(hours) = &self.hours {
+            let Some((start, end)) = parse_hours(hours) else {
+                return false;
+            };
+            let hour = context.now.hour();
+            let within = if start < end {
+                start <= hour && hour < end
+            } else {
+                hour >= start || hour < end
+            };
+            if !within {
+                return false;
+            }
+        }
+        if self.mfa == Some(true) && !context.mfa {
+            return false;
+        }
+        true
+    }
+}
+
+/// Whether a stored grant condition holds. Grants without a condition always apply; stored
+/// conditions that no longer parse never do.
+pub fn condition_holds(condition: Option<&Value>, context: &RequestContext) -> bool {
+    match condition {
+        None | Some(Value::Null) => true,
+        Some(value) => GrantCondition::parse(value)
+            .map(|condition| condition.evaluate(context))
+            .unwrap_or(false),
+    }
+}
+
+/// Request attributes grant conditions are evaluated against.
+///
+/// Extract it in a handler, or build it with `new` where there is no request. The client IP
+/// is the socket peer from `ConnectInfo`; behind a proxy, set it with `with_client_ip`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RequestContext {
+    pub client_ip: Option<IpAddr>,
+    pub mfa: bool,
+    pub now: DateTime<Utc>,
+}
+
+impl RequestContext {
+    pub fn new(now: DateTime<Utc>) -> Self {
+        Self {
+            client_ip: None,
+            mfa: false,
+            now,
+        }
+    }
+
+    pub fn with_client_ip(mut self, client_ip: IpAddr) -> Self {
+        self.client_ip = Some(client_ip);
+        self
+    }
+
+    pub fn with_mfa(mut self, mfa: bool) -> Self {
+        self.mfa = mfa;
+        self
+    }
+
+    /// Context of a request whose extensions carry `ConnectInfo` and, once authenticated, the
+    /// `AuthenticatedUser`.
+    pub fn from_extensions(extensions: &Extensions) -> Self {
+        Self {
+            client_ip: extensions
+                .get::<ConnectInfo<SocketAddr>>()
+                .map(|ConnectInfo(addr)| addr.ip()),
+            mfa: extensions
+                .get::<AuthenticatedUser>()
+                .is_some_and(AuthenticatedUser::mfa),
+            now: Utc::now(),
+        }
+    }
+}
+
+impl<S> FromRequestParts<S> for RequestContext
+where
+    S: Send + Sync,
+{
+    type Rejection = Infallible;
+
+    fn from_request_parts(
+        parts: &mut Parts,
+        _state: &S,
+    ) -> impl future::Future<Output = Result<Self, Self::Rejection>> + Send {
+        future::ready(Ok(Self::from_extensions(&parts.extensions)))
+    }
+}
+
+pub(crate) fn parse_cidr(cidr: &str) -> Option<(IpAddr, u32)> {
+    let (address, prefix) = match cidr.split_once('/') {
+        Some((address, prefix)) => (address, Some(prefix)),
+        None => (cidr, None),
+    };
+    let network: IpAddr = address.trim().parse().ok()?;
+    let max = if network.is_ipv4() { 32 } else { 128 };
+    let prefix = match prefix {
+        Some(prefix) => prefix.trim().parse().ok().filter(|prefix| *prefix <= max)?,
+        None => max,
+    };
+    Some((network, prefix))
+}
+
+pub(crate) fn in_network(network: IpAddr, prefix: u32, ip: IpAddr) -> bool {
+    match (network, ip.to_canonical()) {
+        (IpAddr::V4(network), IpAddr::V4(ip)) => {
+            let mask = u32::MAX.checked_shl(32 - prefix).unwrap_or(0);
+            u32::from(network) & mask == u32::from(ip) & mask
+        }
+        (IpAddr::V6(network), IpAddr::V6(ip)) => {
+            let mask = u128::MAX.checked_shl(128 - prefix).unwrap_or(0);
+            u128::from(network) & mask == u128::from(ip) & mask
+        }
+        _ => false,
+    }
+}
+
+fn parse_hours(hours: &str) -> Option<(u32, u32)> {
+    let (start, end) = hours.split_once('-')?;
+    let start: u32 = start.trim().parse().ok().filter(|hour| *hour < 24)?;
+    let end: u32 = end.trim().parse().ok().filter(|hour| *hour <= 24)?;
+    (start != end).then_some((start, end))
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{TimeZone, Utc};
+    use serde_json::json;
+
+    use super::{ConditionError, GrantCondition, RequestContext, condition_holds};
+
+    fn at(hour: u32) -> RequestContext {
+        RequestContext::new(Utc.with_ymd_and_hms(2026, 3, 2, hour, 30, 0).unwrap())
+    }
+
+    #[test]
+    fn evaluates_each_field() {
+        let condition =
+            GrantCondition::parse(&json!({"ip_cidr": "10.0.0.0/8", "hours": "09-18", "mfa": true}))
+                .unwrap();
+        let vpn = "10.1.2.3".parse().unwrap();
+        assert!(condition.evaluate(&at(10).with_client_ip(vpn).with_mfa(true)));
+        assert!(!condition.evaluate(&at(10).with_client_ip(vpn)));
+        assert!(!condition.evaluate(&at(19).with_client_ip(vpn).with_mfa(true)));
+        assert!(!condition.evaluate(&at(10).with_mfa(true)));
+        let outside = "192.168.1.1".parse().unwrap();
+        assert!(!condition.evaluate(&at(10).with_client_ip(outside).with_mfa(true)));
+    }
+
+    #[test]
+    fn hours_wrap_past_midnight() {
+        let condition = GrantCondition::parse(&json!({"hours": "22-06"})).unwrap();
+        assert!(condition.evaluate(&at(23)));
+        assert!(condition.evaluate(&at(5)));
+        assert!(!condition.evaluate(&at(12)));
+    }
+
+    #[test]
+    fn rejects_invalid_conditions() {
+        assert!(matches!(
+            GrantCondition::parse(&json!({"ip_cidr": "10.0.0.0/33"})),
+            Err(ConditionError::InvalidCidr(_))
+        ));
+        assert!(matches!(
+            GrantCondition::parse(&json!({"hours": "9"})),
+            Err(ConditionError::InvalidHours(_))
+        ));
+        assert!(matches!(
+            GrantCondition::parse(&json!({"weekday": "mon"})),
+            Err(ConditionError::Malformed(_))
+        ));
+        assert!(!condition_holds(Some(&json!({"weekday": "mon"})), &at(10)));
+        assert!(condition_holds(None, &at(10)));
+    }
+}
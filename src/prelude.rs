@@ -1,3 +1,5 @@
+use std::fmt;
+
 use anyhow::{Context, Result as AnyResult, anyhow};
 use axum::{
     http::{StatusCode, header},
@@ -13,18 +15,33 @@ use serde::Serialize;
 use serde_json::{Map, Value, json};
 use uuid::Uuid;
 
+use crate::breaker::ProviderUnavailable;
+use crate::captcha::CaptchaError;
+use crate::email::EmailDomainError;
 pub use crate::group_id::GroupId;
 pub use crate::oidc::OidcToken;
+use crate::password::{PasswordFeedback, PasswordRejection, PasswordViolation};
+use crate::redact::Sensitive;
 pub use crate::user_id::UserId;
 
 #[derive(Debug, Serialize)]
 #[non_exhaustive]
 pub enum AuthRejectReason {
-    OidcError { msg: &'static str },
+    OidcError {
+        msg: &'static str,
+    },
     CsrfMismatch,
-    TokenTransferFailed { msg: String },
+    TokenTransferFailed {
+        msg: String,
+    },
+    /// The identity provider is failing or its circuit breaker is open.
+    IdentityProviderUnavailable {
+        retry_after_secs: Option<u64>,
+    },
     InvalidCredentials,
-    InvalidSessionToken { reason: String },
+    InvalidSessionToken {
+        reason: String,
+    },
     NoSessionToken,
 }
 
@@ -36,6 +53,28 @@ pub enum ApiErrorDetails {
         scope_id: String,
         required_any_roles: Vec<String>,
     },
+    EmailDomain {
+        domain: String,
+    },
+    InsufficientScope {
+        required_scopes: Vec<String>,
+    },
+    ReauthRequired {
+        max_age_secs: u64,
+        mfa_required: bool,
+    },
+    PasswordPolicy {
+        violations: Vec<PasswordViolation>,
+        score: u8,
+        feedback: Vec<PasswordFeedback>,
+    },
+    Suspended {
+        /// `None` while the suspension lasts until lifted.
+        until: Option<chrono::DateTime<chrono::Utc>>,
+    },
+    GroupArchived {
+        archived_at: chrono::DateTime<chrono::Utc>,
+    },
 }
 
 #[derive(Debug, Serialize)]
@@ -66,6 +105,14 @@ impl AuthRejectReason {
         AuthRejectReason::TokenTransferFailed { msg: msg.into() }
     }
 
+    pub fn identity_provider_unavailable(unavailable: &ProviderUnavailable) -> Self {
+        AuthRejectReason::IdentityProviderUnavailable {
+            retry_after_secs: unavailable
+                .retry_after
+                .map(|retry_after| retry_after.as_secs().max(1)),
+        }
+    }
+
     pub fn invalid_credentials() -> Self {
         AuthRejectReason::InvalidCredentials
     }
@@ -81,7 +128,8 @@ impl AuthRejectReason {
     }
 }
 
-/// Implement this for your application state to enable identity validation
+/// Implement this for your application state to enable identity validation. The `identity`
+/// module has implementations for the common cases to delegate to.
 ///
 /// ```ignore
 /// impl ValidatesIdentity for AppState {
@@ -133,14 +181,14 @@ pub fn validate_bearer<S: ValidatesIdentity>(
     tracing::trace!("Splitting Bearer token from ({:?}, {:?})", name, token);
     if let Some(name) = name {
         if name.eq_ignore_ascii_case("Bearer") {
-            state.validate_bearer(&token)
+            state.validate_bearer(token)
         } else {
             Err(ClaimsVerificationError::Other(
                 "Invalid authorization scheme".to_string(),
             ))
         }
     } else {
-        state.validate_bearer(&token)
+        state.validate_bearer(token)
     }
 }
 
@@ -164,6 +212,12 @@ pub fn validated_token_claim_string(token: &CoreIdToken, claim_name: &str) -> Op
     }
 }
 
+/// Every claim of a token that has already been signature-verified, including custom ones such
+/// as a provider's `groups`. Like `validated_token_claim_string`, this does not verify the token.
+pub fn validated_token_claims(token: &CoreIdToken) -> Option<Map<String, Value>> {
+    decode_jwt_payload(token)
+}
+
 /// Resolve machine caller client id from common token claim locations.
 pub fn workload_client_id(token: &CoreIdToken) -> Option<String> {
     validated_token_claim_string(token, "client_id")
@@ -188,18 +242,28 @@ fn decode_jwt_payload(token: &CoreIdToken) -> Option<Map<String, Value>> {
     value.as_object().cloned()
 }
 
-#[derive(Clone, Debug, Serialize)]
+#[derive(Clone, Serialize)]
 pub struct AuthenticatedUser {
     pub(super) id: Uuid,
     pub(super) authorization: CoreIdToken,
     pub(super) claims: CoreIdTokenClaims,
 }
 
+// The token is a credential and never printed; the claims carry the user's email and name.
+impl fmt::Debug for AuthenticatedUser {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AuthenticatedUser")
+            .field("id", &self.id)
+            .field("claims", &Sensitive(&self.claims))
+            .finish_non_exhaustive()
+    }
+}
+
 impl AuthenticatedUser {
     pub async fn from_claims(token: CoreIdToken, claims: CoreIdTokenClaims) -> AnyResult<Self> {
         let user_id = Uuid::parse_str(claims.subject().as_str())
             .context("Failed to parse UUID from claims.subject()")?;
-        tracing::trace!("Claims: {:?}", claims);
+        tracing::trace!("Claims: {:?}", Sensitive(&claims));
 
         // Must include username and email
         let user_name = claims
@@ -311,7 +375,7 @@ impl AuthenticatedUser {
             .or_else(|| {
                 // If no email claim, check if username is a valid email
                 if let Some(user_name) = &user_name {
-                    if EmailAddress::is_valid(&user_name) {
+                    if EmailAddress::is_valid(user_name) {
                         Some(user_name.to_owned())
                     } else {
                         None
@@ -326,6 +390,25 @@ impl AuthenticatedUser {
         self.claims.email_verified().unwrap_or(false)
     }
 
+    /// When the user last entered credentials at the identity provider (`auth_time`).
+    pub fn authenticated_at(&self) -> Option<chrono::DateTime<chrono::Utc>> {
+        self.claims.auth_time()
+    }
+
+    /// Authentication methods (`amr`) the identity provider reported, e.g. `pwd` or `otp`.
+    pub fn auth_methods(&self) -> Vec<String> {
+        self.claims
+            .auth_method_refs()
+            .map(|methods| methods.iter().map(|method| method.to_string()).collect())
+            .unwrap_or_default()
+    }
+
+    /// Whether the identity provider reported multiple-factor authentication (`amr` contains
+    /// `mfa`, RFC 8176).
+    pub fn mfa(&self) -> bool {
+        self.auth_methods().iter().any(|method| method == "mfa")
+    }
+
     pub fn given_name(&self) -> Option<String> {
         self.claims
             .given_name()
@@ -464,7 +547,15 @@ pub enum RejectReason {
     NotFound {
         resource: String,
     },
+    PreconditionFailed {
+        reason: String,
+    },
     Session,
+    Unprocessable {
+        code: String,
+        reason: String,
+        details: Option<ApiErrorDetails>,
+    },
 }
 
 impl RejectReason {
@@ -530,6 +621,51 @@ impl RejectReason {
         }
     }
 
+    /// A bearer token lacks scopes the route requires. Answered with `403`.
+    pub fn insufficient_scope(required_scopes: Vec<String>) -> Self {
+        RejectReason::ForbiddenDetailed {
+            code: "insufficient_scope".to_string(),
+            reason: "Token lacks a required scope".to_string(),
+            details: Some(ApiErrorDetails::InsufficientScope { required_scopes }),
+        }
+    }
+
+    /// The user has to confirm their credentials again, or sign in with MFA, before this.
+    /// Answered with `403`.
+    pub fn reauth_required(max_age: std::time::Duration, mfa_required: bool) -> Self {
+        RejectReason::ForbiddenDetailed {
+            code: "reauth_required".to_string(),
+            reason: "Recent authentication required".to_string(),
+            details: Some(ApiErrorDetails::ReauthRequired {
+                max_age_secs: max_age.as_secs(),
+                mfa_required,
+            }),
+        }
+    }
+
+    /// The user is suspended until `until` (a UTC time), or until lifted. Answered with `403`.
+    pub fn suspended(until: Option<chrono::NaiveDateTime>) -> Self {
+        RejectReason::ForbiddenDetailed {
+            code: "suspended".to_string(),
+            reason: "Account is suspended".to_string(),
+            details: Some(ApiErrorDetails::Suspended {
+                until: until.map(|until| until.and_utc()),
+            }),
+        }
+    }
+
+    /// The group was archived at `archived_at` (a UTC time) and cannot change until unarchived.
+    /// Answered with `403`.
+    pub fn group_archived(archived_at: chrono::NaiveDateTime) -> Self {
+        RejectReason::ForbiddenDetailed {
+            code: "group_archived".to_string(),
+            reason: "Group is archived".to_string(),
+            details: Some(ApiErrorDetails::GroupArchived {
+                archived_at: archived_at.and_utc(),
+            }),
+        }
+    }
+
     pub fn missing_env_key<S: Into<String>>(key: S) -> Self {
         RejectReason::MissingEnvKey { key: key.into() }
     }
@@ -540,6 +676,66 @@ impl RejectReason {
         }
     }
 
+    pub fn precondition_failed<S: Into<String>>(reason: S) -> Self {
+        RejectReason::PreconditionFailed {
+            reason: reason.into(),
+        }
+    }
+
+    /// The request failed its CAPTCHA challenge. Answered with `403` and the error's code.
+    pub fn captcha_rejected(err: &CaptchaError) -> Self {
+        RejectReason::ForbiddenDetailed {
+            code: err.code().to_string(),
+            reason: err.to_string(),
+            details: None,
+        }
+    }
+
+    /// The email address was rejected by the app's `EmailDomainPolicy`.
+    pub fn email_rejected(err: &EmailDomainError) -> Self {
+        RejectReason::Unprocessable {
+            code: err.code().to_string(),
+            reason: err.to_string(),
+            details: err.domain().map(|domain| ApiErrorDetails::EmailDomain {
+                domain: domain.to_string(),
+            }),
+        }
+    }
+
+    /// The password failed the app's `PasswordPolicy`. Every violated rule is listed in the details.
+    pub fn password_rejected(rejection: &PasswordRejection) -> Self {
+        RejectReason::Unprocessable {
+            code: "password_rejected".to_string(),
+            reason: rejection.to_string(),
+            details: Some(ApiErrorDetails::PasswordPolicy {
+                violations: rejection.violations.clone(),
+                score: rejection.strength.score,
+                feedback: rejection.strength.feedback.clone(),
+            }),
+        }
+    }
+
+    /// An `Idempotency-Key` was sent again with a different request. Answered with `422`.
+    pub fn idempotency_key_reused() -> Self {
+        RejectReason::Unprocessable {
+            code: "idempotency_key_reused".to_string(),
+            reason: "Idempotency-Key was already used for a different request".to_string(),
+            details: None,
+        }
+    }
+
+    /// The request first sent with an `Idempotency-Key` succeeded, but its response held a secret
+    /// and was not stored for replay. Answered with `422`.
+    pub fn idempotent_response_withheld() -> Self {
+        RejectReason::Unprocessable {
+            code: "idempotent_response_withheld".to_string(),
+            reason: "The request with this Idempotency-Key already succeeded; its response held a \
+                     secret and was not stored"
+                .to_string(),
+            details: None,
+        }
+    }
+
     pub fn session() -> Self {
         RejectReason::Session
     }
@@ -595,10 +791,48 @@ pub fn structured_error_response<S1: Into<String>, S2: Into<String>>(
         .into_response()
 }
 
+/// Stable code of the error behind a rejection response, stored in the response's extensions so
+/// middleware such as `LocalizeErrorsLayer` can localize the message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ErrorCode(pub String);
+
+impl AuthRejectReason {
+    pub fn code(&self) -> &'static str {
+        match self {
+            AuthRejectReason::CsrfMismatch => "csrf_mismatch",
+            AuthRejectReason::InvalidCredentials
+            | AuthRejectReason::NoSessionToken
+            | AuthRejectReason::InvalidSessionToken { .. } => "unauthorized",
+            AuthRejectReason::TokenTransferFailed { .. } | AuthRejectReason::OidcError { .. } => {
+                "identity_provider_error"
+            }
+            AuthRejectReason::IdentityProviderUnavailable { .. } => "identity_provider_unavailable",
+        }
+    }
+}
+
+impl RejectReason {
+    /// Stable machine-readable code, e.g. `not_found` or `captcha_failed`.
+    pub fn code(&self) -> &str {
+        match self {
+            RejectReason::Auth { reason } => reason.code(),
+            RejectReason::BadRequest { .. } => "bad_request",
+            RejectReason::Conflict { .. } => "conflict",
+            RejectReason::Forbidden { .. } => "forbidden",
+            RejectReason::ForbiddenDetailed { code, .. } => code,
+            RejectReason::NotFound { .. } => "not_found",
+            RejectReason::PreconditionFailed { .. } => "precondition_failed",
+            RejectReason::Unprocessable { code, .. } => code,
+            _ => "internal_error",
+        }
+    }
+}
+
 impl IntoResponse for RejectReason {
     fn into_response(self) -> Response {
         tracing::trace!("RejectReason: {:?}", self);
-        match self {
+        let code = ErrorCode(self.code().to_string());
+        let mut response = match self {
             RejectReason::Auth { reason } => reason.into_response(),
             RejectReason::BadRequest { reason } => (
                 StatusCode::BAD_REQUEST,
@@ -632,6 +866,17 @@ impl IntoResponse for RejectReason {
                 serde_json::to_string(&json!({"error": resource})).expect("valid json"),
             )
                 .into_response(),
+            RejectReason::PreconditionFailed { reason } => (
+                StatusCode::PRECONDITION_FAILED,
+                [(header::CONTENT_TYPE, "application/json")],
+                serde_json::to_string(&json!({"error": reason})).expect("valid json"),
+            )
+                .into_response(),
+            RejectReason::Unprocessable {
+                code,
+                reason,
+                details,
+            } => structured_error_response(StatusCode::UNPROCESSABLE_ENTITY, code, reason, details),
             RejectReason::Anyhow { error } => error.into_response(),
             _ => {
                 tracing::error!("RejectReason: {:?}", self);
@@ -643,14 +888,17 @@ impl IntoResponse for RejectReason {
                 )
                     .into_response()
             }
-        }
+        };
+        response.extensions_mut().insert(code);
+        response
     }
 }
 
 impl IntoResponse for AuthRejectReason {
     fn into_response(self) -> Response {
         tracing::trace!("AuthRejectReason: {:?}", self);
-        match self {
+        let code = ErrorCode(self.code().to_string());
+        let mut response = match self {
             AuthRejectReason::CsrfMismatch => (
                 StatusCode::BAD_REQUEST,
                 [(header::CONTENT_TYPE, "application/json")],
@@ -682,6 +930,23 @@ impl IntoResponse for AuthRejectReason {
                 serde_json::to_string(&json!({"error": msg})).expect("valid json"),
             )
                 .into_response(),
-        }
+            AuthRejectReason::IdentityProviderUnavailable { retry_after_secs } => {
+                let mut response = (
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    [(header::CONTENT_TYPE, "application/json")],
+                    serde_json::to_string(&json!({"error": "Identity provider is unavailable"}))
+                        .expect("valid json"),
+                )
+                    .into_response();
+                if let Some(retry_after_secs) = retry_after_secs {
+                    response
+                        .headers_mut()
+                        .insert(header::RETRY_AFTER, retry_after_secs.into());
+                }
+                response
+            }
+        };
+        response.extensions_mut().insert(code);
+        response
     }
 }
@@ -0,0 +1,245 @@
+use std::collections::HashSet;
+
+use once_cell::sync::Lazy;
+use serde_json::json;
+use sqlx::{PgConnection, PgPool};
+
+use crate::claims::{MappedAccess, MappedMembership, MappedRole, grant_access};
+use crate::db::insert_audit_log;
+use crate::email::{EmailDomainError, email_domain, matches_domain, normalize_domain};
+use crate::user_id::UserId;
+
+pub static DEFAULT_PROVISIONING_POLICY: Lazy<ProvisioningPolicy> =
+    Lazy::new(ProvisioningPolicy::default);
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ProvisioningMode {
+    /// Create the user on first sign-in and apply the defaults right away.
+    #[default]
+    Automatic,
+    /// Create the user on first sign-in without any access. `/auth/me` answers `403` with code
+    /// `pending_approval`, and claims are not synced, until an admin approves the user.
+    RequireApproval,
+    /// Never create users on sign-in; they must be created or imported ahead of time.
+    Disabled,
+}
+
+/// What happens the first time someone signs in through the identity provider without a user
+/// record.
+///
+/// Domains listed here restrict who is provisioned on sign-in. They apply on top of
+/// `AuthApp::email_domain_policy`, whose allow list only exempts domains from its other checks.
+#[derive(Debug, Clone, Default)]
+pub struct ProvisioningPolicy {
+    mode: ProvisioningMode,
+    allowed_domains: HashSet<String>,
+    defaults: MappedAccess,
+}
+
+impl ProvisioningPolicy {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_mode(mut self, mode: ProvisioningMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Only provision users whose email is in one of these domains or their subdomains.
+    pub fn with_allowed_domains<I, S>(mut self, domains: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        self.allowed_domains.extend(
+            domains
+                .into_iter()
+                .map(|domain| normalize_domain(domain.as_ref())),
+        );
+        self
+    }
+
+    /// Grant a role to every new user.
+    pub fn with_default_role(mut self, role: MappedRole) -> Self {
+        self.defaults.roles.push(role);
+        self
+    }
+
+    /// Add every new user to a group. Groups that do not exist are skipped.
+    pub fn with_default_membership(mut self, membership: MappedMembership) -> Self {
+        self.defaults.memberships.push(membership);
+        self
+    }
+
+    pub fn mode(&self) -> ProvisioningMode {
+        self.mode
+    }
+
+    pub fn defaults(&self) -> &MappedAccess {
+        &self.defaults
+    }
+
+    pub fn check_email(&self, email: &str) -> Result<(), EmailDomainError> {
+        if self.allowed_domains.is_empty() {
+            return Ok(());
+        }
+        let domain = email_domain(email).ok_or(EmailDomainError::Invalid)?;
+        if matches_domain(&self.allowed_domains, &domain) {
+            Ok(())
+        } else {
+            Err(EmailDomainError::Denied { domain })
+        }
+    }
+}
+
+/// Apply `policy` to a user that was just created on sign-in: mark them pending under
+/// `ProvisioningMode::RequireApproval`, otherwise grant the defaults.
+pub async fn provision_user(
+    pool: &PgPool,
+    user_id: UserId,
+    policy: &ProvisioningPolicy,
+) -> Result<(), sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    if policy.mode() == ProvisioningMode::RequireApproval {
+        sqlx::query("UPDATE auth.users SET pending_approval = TRUE WHERE id = $1")
+            .bind(user_id)
+            .execute(&mut *tx)
+            .await?;
+        insert_audit_log(
+            &mut *tx,
+            Some(user_id),
+            json!({ "type": "user_pending_approval" }),
+        )
+        .await?;
+    } else {
+        grant_defaults(&mut tx, None, user_id, policy).await?;
+    }
+    tx.commit().await?;
+    Ok(())
+}
+
+/// Grant the policy defaults to a user admitted some other way, e.g. by accepting an invitation
+/// from `actor_user_id`. The approval mode does not apply.
+pub async fn provision_invited_user(
+    pool: &PgPool,
+    actor_user_id: Option<UserId>,
+    user_id: UserId,
+    policy: &ProvisioningPolicy,
+) -> Result<(), sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    provision_invited_user_in(&mut tx, actor_user_id, user_id, policy).await?;
+    tx.commit().await?;
+    Ok(())
+}
+
+/// `provision_invited_user` on `conn`, e.g. in the transaction that accepts the invitation.
+pub(crate) async fn provision_invited_user_in(
+    conn: &mut PgConnection,
+    actor_user_id: Option<UserId>,
+    user_id: UserId,
+    policy: &ProvisioningPolicy,
+) -> Result<(), sqlx::Error> {
+    grant_defaults(conn, actor_user_id, user_id, policy).await
+}
+
+/// Approve a pending user and grant the policy defaults. Returns `false` if the user was not
+/// pending approval.
+pub async fn approve_user(
+    pool: &PgPool,
+    actor_user_id: UserId,
+    user_id: UserId,
+    policy: &ProvisioningPolicy,
+) -> Result<bool, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    let approved = sqlx::query(
+        r#"
+        UPDATE auth.users
+        SET pending_approval = FALSE
+        WHERE id = $1
+          AND pending_approval
+        "#,
+    )
+    .bind(user_id)
+    .execute(&mut *tx)
+    .await?
+    .rows_affected()
+        > 0;
+    if !approved {
+        return Ok(false);
+    }
+    let (granted, joined) = grant_access(
+        &mut tx,
+        Some(actor_user_id),
+        user_id,
+        policy.defaults(),
+        None,
+    )
+    .await?;
+    insert_audit_log(
+        &mut *tx,
+        Some(user_id),
+        json!({
+            "type": "user_approved",
+            "actor_user_id": actor_user_id,
+            "granted": granted,
+            "joined": joined,
+        }),
+    )
+    .await?;
+    tx.commit().await?;
+    Ok(true)
+}
+
+async fn grant_defaults(
+    conn: &mut PgConnection,
+    actor_user_id: Option<UserId>,
+    user_id: UserId,
+    policy: &ProvisioningPolicy,
+) -> Result<(), sqlx::Error> {
+    let (granted, joined) =
+        grant_access(&mut *conn, actor_user_id, user_id, policy.defaults(), None).await?;
+    if granted.is_empty() && joined.is_empty() {
+        return Ok(());
+    }
+    insert_audit_log(
+        conn,
+        Some(user_id),
+        json!({
+            "type": "user_provisioned",
+            "actor_user_id": actor_user_id,
+            "granted": granted,
+            "joined": joined,
+        }),
+    )
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ProvisioningPolicy;
+    use crate::email::EmailDomainError;
+
+    #[test]
+    fn restricts_to_allowed_domains() {
+        assert!(
+            ProvisioningPolicy::new()
+                .check_email("ada@gmail.com")
+                .is_ok()
+        );
+
+        let policy = ProvisioningPolicy::new().with_allowed_domains(["Example.com."]);
+        assert!(policy.check_email("ada@example.com").is_ok());
+        assert!(policy.check_email("ada@eng.example.com").is_ok());
+        assert_eq!(
+            policy.check_email("ada@example.org"),
+            Err(EmailDomainError::Denied {
+                domain: "example.org".to_string()
+            })
+        );
+        assert_eq!(
+            policy.check_email("not an email"),
+            Err(EmailDomainError::Invalid)
+        );
+    }
+}
@@ -0,0 +1,219 @@
+//! Checks that the hot lookups are served by indexes rather than sequential scans.
+//!
+//! `explain_queries` plans a representative query for each lookup with sequential scans
+//! disabled, so the result says whether an index can serve the query rather than what the
+//! planner picks for a table that is still small. Run it after migrations, e.g. from a readiness
+//! check in staging, and alert on any entry that is not `uses_index`.
+
+use serde::Serialize;
+use serde_json::Value;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+struct Probe {
+    name: &'static str,
+    table: &'static str,
+    /// Planned with `$1` bound to the nil UUID.
+    sql: &'static str,
+}
+
+const PROBES: &[Probe] = &[
+    Probe {
+        name: "user_roles_by_user_scope",
+        table: "auth.user_roles",
+        sql: r#"
+            SELECT role_name
+            FROM auth.user_roles
+            WHERE user_id = $1
+              AND scope = 'global'
+              AND scope_id = 'global'
+        "#,
+    },
+    Probe {
+        name: "group_roles_by_group_scope",
+        table: "auth.group_roles",
+        sql: r#"
+            SELECT role_name
+            FROM auth.group_roles
+            WHERE group_id = $1
+              AND scope = 'global'
+              AND scope_id = 'global'
+        "#,
+    },
+    Probe {
+        name: "group_memberships_by_user",
+        table: "auth.group_memberships",
+        sql: r#"
+            SELECT group_id, role_name
+            FROM auth.group_memberships
+            WHERE user_id = $1
+        "#,
+    },
+    Probe {
+        name: "log_by_user_newest_first",
+        table: "auth.log",
+        sql: r#"
+            SELECT id
+            FROM auth.log
+            WHERE user_id = $1
+            ORDER BY timestamp DESC
+            LIMIT 50
+        "#,
+    },
+    Probe {
+        name: "log_by_subject_user",
+        table: "auth.log",
+        sql: r#"
+            SELECT id
+            FROM auth.log
+            WHERE action ? 'user_id'
+              AND action->>'user_id' = $1::TEXT
+            ORDER BY timestamp DESC
+            LIMIT 50
+        "#,
+    },
+    Probe {
+        name: "log_by_role_target_user",
+        table: "auth.log",
+        sql: r#"
+            SELECT id
+            FROM auth.log
+            WHERE action->>'target_type' = 'user'
+              AND action->>'target_id' = $1::TEXT
+            ORDER BY timestamp DESC
+            LIMIT 50
+        "#,
+    },
+    Probe {
+        name: "users_by_email_canonical",
+        table: "auth.users",
+        sql: r#"
+            SELECT id
+            FROM auth.users
+            WHERE email_canonical = 'user@example.com'
+              AND id <> $1
+        "#,
+    },
+    Probe {
+        name: "users_by_lower_email",
+        table: "auth.users",
+        sql: r#"
+            SELECT id
+            FROM auth.users
+            WHERE lower(email) = lower('User@Example.com')
+              AND id <> $1
+        "#,
+    },
+    Probe {
+        name: "import_conflicts_by_lower_email",
+        table: "auth.import_conflicts",
+        sql: r#"
+            SELECT record
+            FROM auth.import_conflicts
+            WHERE lower(email) = lower('User@Example.com')
+              AND run_name <> $1::TEXT
+        "#,
+    },
+];
+
+/// How the database plans one of the probed lookups.
+#[derive(Debug, Clone, Serialize)]
+pub struct QueryPlan {
+    pub name: &'static str,
+    pub table: &'static str,
+    /// No sequential scan anywhere in the plan.
+    pub uses_index: bool,
+    /// Indexes the plan reads, including those of `auth.log` partitions.
+    pub indexes: Vec<String>,
+    /// The `EXPLAIN (FORMAT JSON)` output.
+    pub plan: Value,
+}
+
+/// Plan each hot lookup and report whether it is served by an index. Changes nothing.
+pub async fn explain_queries(pool: &PgPool) -> Result<Vec<QueryPlan>, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    sqlx::query("SET LOCAL enable_seqscan = off")
+        .execute(&mut *tx)
+        .await?;
+    let mut plans = Vec::with_capacity(PROBES.len());
+    for probe in PROBES {
+        let plan: Value = sqlx::query_scalar(&format!("EXPLAIN (FORMAT JSON) {}", probe.sql))
+            .bind(Uuid::nil())
+            .fetch_one(&mut *tx)
+            .await?;
+        let mut scans = PlanScans::default();
+        scans.collect(&plan);
+        plans.push(QueryPlan {
+            name: probe.name,
+            table: probe.table,
+            uses_index: !scans.seq_scan,
+            indexes: scans.indexes,
+            plan,
+        });
+    }
+    tx.rollback().await?;
+    Ok(plans)
+}
+
+#[derive(Default)]
+struct PlanScans {
+    seq_scan: bool,
+    indexes: Vec<String>,
+}
+
+impl PlanScans {
+    fn collect(&mut self, node: &Value) {
+        match node {
+            Value::Array(nodes) => nodes.iter().for_each(|node| self.collect(node)),
+            Value::Object(fields) => {
+                if fields.get("Node Type").and_then(Value::as_str) == Some("Seq Scan") {
+                    self.seq_scan = true;
+                }
+                if let Some(index) = fields.get("Index Name").and_then(Value::as_str)
+                    && !self.indexes.iter().any(|seen| seen == index)
+                {
+                    self.indexes.push(index.to_string());
+                }
+                for key in ["Plan", "Plans"] {
+                    if let Some(child) = fields.get(key) {
+                        self.collect(child);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::PlanScans;
+
+    #[test]
+    fn finds_scans_in_nested_plans() {
+        let plan = json!([{
+            "Plan": {
+                "Node Type": "Limit",
+                "Plans": [{
+                    "Node Type": "Append",
+                    "Plans": [
+                        {"Node Type": "Index Scan", "Index Name": "log_default_idx"},
+                        {"Node Type": "Bitmap Heap Scan", "Plans": [
+                            {"Node Type": "Bitmap Index Scan", "Index Name": "log_y2026m10_idx"}
+                        ]}
+                    ]
+                }]
+            }
+        }]);
+        let mut scans = PlanScans::default();
+        scans.collect(&plan);
+        assert!(!scans.seq_scan);
+        assert_eq!(scans.indexes, vec!["log_default_idx", "log_y2026m10_idx"]);
+
+        let mut scans = PlanScans::default();
+        scans.collect(&json!([{"Plan": {"Node Type": "Seq Scan"}}]));
+        assert!(scans.seq_scan && scans.indexes.is_empty());
+    }
+}
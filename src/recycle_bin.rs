@@ -0,0 +1,292 @@
+//! Soft deletion of users and groups, with restore.
+//!
+//! Deleting marks the row with `deleted_at` and `deleted_by` and deactivates it, so a deleted user
+//! cannot sign in and a deleted group grants nothing, but every membership and grant stays in
+//! place for `restore_user` or `restore_group` to bring back. A restored row is active again
+//! unless it had a recorded deactivation before it was deleted. `purge_deleted`, run by the
+//! maintenance runner, removes rows deleted longer ago than the retention window for good. Every
+//! step is logged to `auth.log`.
+//!
+//! Lookups such as `UserRow::get` and `GroupRow::get` do not find deleted rows and grants through
+//! a deleted group do not count. A deleted group is frozen like an archived one: the schema
+//! rejects changes to it with an error `is_group_deleted` recognizes.
+
+use chrono::NaiveDateTime;
+use serde::Serialize;
+use serde_json::json;
+use sqlx::{FromRow, PgPool};
+use uuid::Uuid;
+
+use crate::db::insert_audit_log;
+use crate::group_id::GroupId;
+use crate::remember::RememberedSession;
+use crate::user_id::UserId;
+
+/// Raised by the schema for a write to a soft-deleted group.
+pub const GROUP_DELETED_SQLSTATE: &str = "SGD01";
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, FromRow)]
+pub struct DeletedUser {
+    pub id: UserId,
+    pub username: Option<String>,
+    pub email: String,
+    pub deleted_at: NaiveDateTime,
+    pub deleted_by: Option<UserId>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, FromRow)]
+pub struct DeletedGroup {
+    pub id: GroupId,
+    pub display_name: String,
+    pub deleted_at: NaiveDateTime,
+    pub deleted_by: Option<UserId>,
+}
+
+macro_rules! delete_query {
+    ($table:literal) => {
+        concat!(
+            "UPDATE ",
+            $table,
+            r#"
+            SET active = FALSE,
+                deleted_at = $2,
+                deleted_by = $3
+            WHERE id = $1
+              AND deleted_at IS NULL
+            "#
+        )
+    };
+}
+
+macro_rules! restore_query {
+    ($table:literal) => {
+        concat!(
+            "UPDATE ",
+            $table,
+            r#"
+            SET active = (deactivated_at IS NULL AND deactivation_reason IS NULL),
+                deleted_at = NULL,
+                deleted_by = NULL
+            WHERE id = $1
+              AND deleted_at IS NOT NULL
+            "#
+        )
+    };
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Subject {
+    User(UserId),
+    Group(GroupId),
+}
+
+impl Subject {
+    fn id(self) -> Uuid {
+        match self {
+            Self::User(user_id) => user_id.0,
+            Self::Group(group_id) => group_id.0,
+        }
+    }
+
+    fn query(self, deleting: bool) -> &'static str {
+        match (self, deleting) {
+            (Self::User(_), true) => delete_query!("auth.users"),
+            (Self::User(_), false) => restore_query!("auth.users"),
+            (Self::Group(_), true) => delete_query!("auth.groups"),
+            (Self::Group(_), false) => restore_query!("auth.groups"),
+        }
+    }
+
+    fn log_fields(self) -> (&'static str, String, &'static str) {
+        match self {
+            Self::User(user_id) => ("user_id", user_id.to_string(), "user"),
+            Self::Group(group_id) => ("group_id", group_id.to_string(), "group"),
+        }
+    }
+}
+
+/// The write failed because its group is soft-deleted.
+pub fn is_group_deleted(err: &sqlx::Error) -> bool {
+    matches!(
+        err,
+        sqlx::Error::Database(err) if err.code().as_deref() == Some(GROUP_DELETED_SQLSTATE)
+    )
+}
+
+/// Soft-delete a user and revoke their remember-me tokens. Returns false if the user does not
+/// exist or is already deleted.
+pub async fn delete_user(
+    pool: &PgPool,
+    actor_user_id: UserId,
+    user_id: UserId,
+) -> Result<bool, sqlx::Error> {
+    change(pool, actor_user_id, Subject::User(user_id), true).await
+}
+
+/// `delete_user` for a group.
+pub async fn delete_group(
+    pool: &PgPool,
+    actor_user_id: UserId,
+    group_id: GroupId,
+) -> Result<bool, sqlx::Error> {
+    change(pool, actor_user_id, Subject::Group(group_id), true).await
+}
+
+/// Bring back a soft-deleted user. Returns false if the user does not exist or is not deleted.
+pub async fn restore_user(
+    pool: &PgPool,
+    actor_user_id: UserId,
+    user_id: UserId,
+) -> Result<bool, sqlx::Error> {
+    change(pool, actor_user_id, Subject::User(user_id), false).await
+}
+
+/// `restore_user` for a group.
+pub async fn restore_group(
+    pool: &PgPool,
+    actor_user_id: UserId,
+    group_id: GroupId,
+) -> Result<bool, sqlx::Error> {
+    change(pool, actor_user_id, Subject::Group(group_id), false).await
+}
+
+/// The group, if it is soft-deleted. `None` if it is not deleted or does not exist.
+pub async fn deleted_group(
+    pool: &PgPool,
+    group_id: GroupId,
+) -> Result<Option<DeletedGroup>, sqlx::Error> {
+    sqlx::query_as::<_, DeletedGroup>(
+        r#"
+        SELECT id, display_name, deleted_at, deleted_by
+        FROM auth.groups
+        WHERE id = $1
+          AND deleted_at IS NOT NULL
+        "#,
+    )
+    .bind(group_id)
+    .fetch_optional(pool)
+    .await
+}
+
+/// Soft-deleted users, most recently deleted first.
+pub async fn deleted_users(
+    pool: &PgPool,
+    page: Option<(i64, i64)>,
+) -> Result<Vec<DeletedUser>, sqlx::Error> {
+    let (limit, offset) = match page {
+        Some((limit, offset)) => (Some(limit), offset),
+        None => (None, 0),
+    };
+    sqlx::query_as::<_, DeletedUser>(
+        r#"
+        SELECT id, username, email, deleted_at, deleted_by
+        FROM auth.users
+        WHERE deleted_at IS NOT NULL
+        ORDER BY deleted_at DESC, id ASC
+        LIMIT $1 OFFSET $2
+        "#,
+    )
+    .bind(limit)
+    .bind(offset)
+    .fetch_all(pool)
+    .await
+}
+
+/// Soft-deleted groups, most recently deleted first.
+pub async fn deleted_groups(
+    pool: &PgPool,
+    page: Option<(i64, i64)>,
+) -> Result<Vec<DeletedGroup>, sqlx::Error> {
+    let (limit, offset) = match page {
+        Some((limit, offset)) => (Some(limit), offset),
+        None => (None, 0),
+    };
+    sqlx::query_as::<_, DeletedGroup>(
+        r#"
+        SELECT id, display_name, deleted_at, deleted_by
+        FROM auth.groups
+        WHERE deleted_at IS NOT NULL
+        ORDER BY deleted_at DESC, id ASC
+        LIMIT $1 OFFSET $2
+        "#,
+    )
+    .bind(limit)
+    .bind(offset)
+    .fetch_all(pool)
+    .await
+}
+
+/// Permanently delete users and groups soft-deleted before `cutoff`. Users go through
+/// `UserRow::delete_cascade`, so their audit log rows are anonymized rather than removed. Returns
+/// the number of users and groups removed.
+#[cfg(feature = "hard-delete")]
+pub async fn purge_deleted(pool: &PgPool, cutoff: NaiveDateTime) -> Result<u64, sqlx::Error> {
+    let user_ids: Vec<UserId> = sqlx::query_scalar(
+        r#"
+        SELECT id
+        FROM auth.users
+        WHERE deleted_at < $1
+        "#,
+    )
+    .bind(cutoff)
+    .fetch_all(pool)
+    .await?;
+    let mut purged = 0;
+    for user_id in user_ids {
+        if crate::db::UserRow::delete_cascade(pool, user_id).await? {
+            purged += 1;
+        }
+    }
+    let groups = sqlx::query(
+        r#"
+        DELETE FROM auth.groups
+        WHERE deleted_at < $1
+        "#,
+    )
+    .bind(cutoff)
+    .execute(pool)
+    .await?;
+    Ok(purged + groups.rows_affected())
+}
+
+async fn change(
+    pool: &PgPool,
+    actor_user_id: UserId,
+    subject: Subject,
+    deleting: bool,
+) -> Result<bool, sqlx::Error> {
+    let now = chrono::Utc::now().naive_utc();
+    let mut tx = pool.begin().await?;
+    let query = sqlx::query(subject.query(deleting)).bind(subject.id());
+    let query = if deleting {
+        query.bind(now).bind(actor_user_id)
+    } else {
+        query
+    };
+    if query.execute(&mut *tx).await?.rows_affected() == 0 {
+        return Ok(false);
+    }
+
+    let (key, id, kind) = subject.log_fields();
+    let verb = if deleting { "deleted" } else { "restored" };
+    insert_audit_log(
+        &mut *tx,
+        Some(actor_user_id),
+        json!({
+            "type": format!("{}_{}", kind, verb),
+            key: id,
+        }),
+    )
+    .await?;
+    if let (true, Subject::User(user_id)) = (deleting, subject) {
+        RememberedSession::revoke_all_in(&mut tx, user_id).await?;
+    }
+    tx.commit().await?;
+    // Grants through a deleted group, or held by a deleted user, stop counting.
+    #[cfg(feature = "api")]
+    match subject {
+        Subject::User(user_id) => crate::guard::invalidate_role_snapshots(user_id),
+        Subject::Group(_) => crate::guard::invalidate_all_role_snapshots(),
+    }
+    Ok(true)
+}
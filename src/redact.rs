@@ -0,0 +1,137 @@
+use std::fmt;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU8, Ordering};
+
+use serde::{Deserialize, Serialize};
+
+/// How personal data (emails, usernames, IPs, user agents) and secrets in URLs appear in `Debug`
+/// output and logs.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RedactionMode {
+    /// Replace the value with `[redacted]`.
+    #[default]
+    Redact,
+    /// Replace the value with a short hash, so log lines about the same value can be correlated.
+    /// The hash is unkeyed and only hides values from casual reading.
+    Hash,
+    /// Print values as they are. Meant for local development.
+    Plain,
+}
+
+impl FromStr for RedactionMode {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_ascii_lowercase().as_str() {
+            "redact" => Ok(RedactionMode::Redact),
+            "hash" => Ok(RedactionMode::Hash),
+            "plain" => Ok(RedactionMode::Plain),
+            _ => Err("expected redact, hash or plain".to_string()),
+        }
+    }
+}
+
+static MODE: AtomicU8 = AtomicU8::new(0);
+
+/// Set the process-wide mode, e.g. from `AuthConfig::redaction` at startup.
+pub fn set_redaction_mode(mode: RedactionMode) {
+    MODE.store(mode as u8, Ordering::Relaxed);
+}
+
+pub fn redaction_mode() -> RedactionMode {
+    match MODE.load(Ordering::Relaxed) {
+        1 => RedactionMode::Hash,
+        2 => RedactionMode::Plain,
+        _ => RedactionMode::Redact,
+    }
+}
+
+/// Formats the wrapped value according to the current `RedactionMode`. Wrap personal data in
+/// hand-written `Debug` impls and tracing calls, e.g. `tracing::info!("{}", Sensitive(&email))`.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Sensitive<T>(pub T);
+
+impl<T: fmt::Debug> fmt::Debug for Sensitive<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write_with(f, redaction_mode(), &self.0, |f, value| {
+            fmt::Debug::fmt(value, f)
+        })
+    }
+}
+
+impl<T: fmt::Display> fmt::Display for Sensitive<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write_with(f, redaction_mode(), &self.0, |f, value| {
+            fmt::Display::fmt(value, f)
+        })
+    }
+}
+
+fn write_with<T>(
+    f: &mut fmt::Formatter<'_>,
+    mode: RedactionMode,
+    value: &T,
+    plain: impl Fn(&mut fmt::Formatter<'_>, &T) -> fmt::Result,
+) -> fmt::Result {
+    match mode {
+        RedactionMode::Redact => f.write_str("[redacted]"),
+        RedactionMode::Hash => {
+            let text = format!(
+                "{}",
+                Plain {
+                    value,
+                    plain: &plain
+                }
+            );
+            let mut hasher = DefaultHasher::new();
+            text.hash(&mut hasher);
+            write!(f, "[hash:{:016x}]", hasher.finish())
+        }
+        RedactionMode::Plain => plain(f, value),
+    }
+}
+
+struct Plain<'a, T, F> {
+    value: &'a T,
+    plain: &'a F,
+}
+
+impl<T, F> fmt::Display for Plain<'_, T, F>
+where
+    F: Fn(&mut fmt::Formatter<'_>, &T) -> fmt::Result,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        (self.plain)(f, self.value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fmt;
+
+    use super::{RedactionMode, write_with};
+
+    struct In(RedactionMode, &'static str);
+
+    impl fmt::Display for In {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write_with(f, self.0, &self.1, |f, value| fmt::Display::fmt(value, f))
+        }
+    }
+
+    #[test]
+    fn formats_by_mode() {
+        let email = "ada@example.com";
+        assert_eq!(In(RedactionMode::Redact, email).to_string(), "[redacted]");
+        assert_eq!(In(RedactionMode::Plain, email).to_string(), email);
+        let hashed = In(RedactionMode::Hash, email).to_string();
+        assert!(hashed.starts_with("[hash:") && !hashed.contains("ada"));
+        assert_eq!(hashed, In(RedactionMode::Hash, email).to_string());
+        assert_ne!(
+            hashed,
+            In(RedactionMode::Hash, "bob@example.com").to_string()
+        );
+    }
+}
@@ -0,0 +1,573 @@
+use std::fmt;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use axum::extract::Request;
+use axum::http::HeaderValue;
+use axum::http::header::{AUTHORIZATION, COOKIE, SET_COOKIE};
+use axum::response::Response;
+use axum_extra::extract::CookieJar as AxumCookieJar;
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use chrono::NaiveDateTime;
+use cookie::{Cookie, SameSite};
+use futures_util::future::BoxFuture;
+use ring::aead::{AES_256_GCM, Aad, LessSafeKey, NONCE_LEN, Nonce, UnboundKey};
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::Serialize;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use sqlx::{FromRow, PgConnection, PgPool};
+use tower::{Layer, Service};
+use uuid::Uuid;
+
+use crate::auth::{AUTH_COOKIE, REMEMBER_COOKIE, auth_cookie, cookies_from_request};
+use crate::breaker::ProviderUnavailable;
+use crate::clients::{random_token, secret_hash};
+use crate::db::insert_audit_log;
+use crate::oidc::OidcToken;
+use crate::prelude::{AuthenticatedUser, ValidatesIdentity};
+use crate::redact::Sensitive;
+use crate::user_id::UserId;
+
+/// Lifetime of remember-me tokens created by `auth::auth_with_login_tracking`.
+pub const DEFAULT_REMEMBER_FOR: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+macro_rules! remembered_session_columns {
+    () => {
+        "id, user_id, user_agent, ip, created_at, last_used_at, expires_at"
+    };
+}
+
+/// A device that can sign back in without the identity provider until `expires_at`.
+#[derive(Clone, FromRow, Serialize)]
+pub struct RememberedSession {
+    pub id: Uuid,
+    pub user_id: UserId,
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+    pub created_at: NaiveDateTime,
+    /// Last time the token re-established a session.
+    pub last_used_at: Option<NaiveDateTime>,
+    pub expires_at: NaiveDateTime,
+}
+
+impl fmt::Debug for RememberedSession {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RememberedSession")
+            .field("id", &self.id)
+            .field("user_id", &self.user_id)
+            .field("ip", &self.ip.as_ref().map(Sensitive))
+            .field("user_agent", &self.user_agent)
+            .field("created_at", &self.created_at)
+            .field("last_used_at", &self.last_used_at)
+            .field("expires_at", &self.expires_at)
+            .finish()
+    }
+}
+
+impl RememberedSession {
+    /// The user's unexpired, unrevoked remember-me tokens, most recently used first.
+    pub async fn list(pool: &PgPool, user_id: UserId) -> Result<Vec<Self>, sqlx::Error> {
+        sqlx::query_as::<_, RememberedSession>(concat!(
+            "SELECT ",
+            remembered_session_columns!(),
+            r#"
+            FROM auth.remembered_sessions
+            WHERE user_id = $1 AND revoked_at IS NULL AND expires_at > $2
+            ORDER BY COALESCE(last_used_at, created_at) DESC
+            "#,
+        ))
+        .bind(user_id)
+        .bind(chrono::Utc::now().naive_utc())
+        .fetch_all(pool)
+        .await
+    }
+
+    /// Revoke one of the user's remember-me tokens and log `remembered_session_revoked`. Returns
+    /// `false` if the user has no such active token. Sessions it already re-established last
+    /// until their own cookie goes away.
+    pub async fn revoke(pool: &PgPool, user_id: UserId, id: Uuid) -> Result<bool, sqlx::Error> {
+        let mut tx = pool.begin().await?;
+        let revoked = sqlx::query(
+            r#"
+            UPDATE auth.remembered_sessions
+            SET revoked_at = $3
+            WHERE id = $1 AND user_id = $2 AND revoked_at IS NULL
+            "#,
+        )
+        .bind(id)
+        .bind(user_id)
+        .bind(chrono::Utc::now().naive_utc())
+        .execute(&mut *tx)
+        .await?
+        .rows_affected();
+        if revoked > 0 {
+            insert_audit_log(
+                &mut *tx,
+                Some(user_id),
+                json!({"type": "remembered_session_revoked", "session_id": id}),
+            )
+            .await?;
+        }
+        tx.commit().await?;
+        Ok(revoked > 0)
+    }
+
+    /// Revoke every remember-me token of the user, e.g. after a password change.
+    pub async fn revoke_all(pool: &PgPool, user_id: UserId) -> Result<u64, sqlx::Error> {
+        let mut tx = pool.begin().await?;
+        let revoked = Self::revoke_all_in(&mut tx, user_id).await?;
+        tx.commit().await?;
+        Ok(revoked)
+    }
+
+    /// `revoke_all` on `conn`, e.g. in the transaction that deactivates, deletes or suspends the
+    /// user.
+    pub(crate) async fn revoke_all_in(
+        conn: &mut PgConnection,
+        user_id: UserId,
+    ) -> Result<u64, sqlx::Error> {
+        let revoked = sqlx::query(
+            r#"
+            UPDATE auth.remembered_sessions
+            SET revoked_at = $2
+            WHERE user_id = $1 AND revoked_at IS NULL
+            "#,
+        )
+        .bind(user_id)
+        .bind(chrono::Utc::now().naive_utc())
+        .execute(&mut *conn)
+        .await?
+        .rows_affected();
+        if revoked > 0 {
+            insert_audit_log(
+                conn,
+                Some(user_id),
+                json!({"type": "remembered_sessions_revoked", "count": revoked}),
+            )
+            .await?;
+        }
+        Ok(revoked)
+    }
+}
+
+/// Delete remember-me tokens that expired or were revoked before `before`. Returns the number
+/// deleted.
+pub async fn purge_remembered_sessions(
+    pool: &PgPool,
+    before: NaiveDateTime,
+) -> Result<u64, sqlx::Error> {
+    sqlx::query("DELETE FROM auth.remembered_sessions WHERE expires_at < $1 OR revoked_at < $1")
+        .bind(before)
+        .execute(pool)
+        .await
+        .map(|result| result.rows_affected())
+}
+
+/// Create a remember-me token for `token`'s session and add its cookie to `jar`.
+///
+/// The provider tokens are stored encrypted under a key derived from the cookie's secret, so the
+/// row alone cannot re-establish a session.
+pub async fn remember_session(
+    pool: &PgPool,
+    jar: AxumCookieJar,
+    user_id: UserId,
+    token: &OidcToken,
+    user_agent: Option<&str>,
+    ip: Option<&str>,
+    remember_for: Duration,
+) -> Result<AxumCookieJar, sqlx::Error> {
+    let id = Uuid::new_v4();
+    let secret = random_token();
+    let sealed = seal(&secret, id, token)
+        .ok_or_else(|| sqlx::Error::Protocol("Failed to encrypt session token".to_string()))?;
+    let now = chrono::Utc::now().naive_utc();
+    let remember_for = chrono::Duration::from_std(remember_for).unwrap_or(chrono::Duration::MAX);
+    let expires_at = now
+        .checked_add_signed(remember_for)
+        .unwrap_or(NaiveDateTime::MAX);
+    sqlx::query(
+        r#"
+        INSERT INTO auth.remembered_sessions
+            (id, user_id, secret_hash, token, user_agent, ip, created_at, expires_at)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+        "#,
+    )
+    .bind(id)
+    .bind(user_id)
+    .bind(secret_hash(&secret))
+    .bind(sealed)
+    .bind(user_agent)
+    .bind(ip)
+    .bind(now)
+    .bind(expires_at)
+    .execute(pool)
+    .await?;
+
+    let max_age = (expires_at - now).num_seconds();
+    Ok(jar.add(remember_cookie(
+        format!("{}.{}", id, secret),
+        cookie::time::Duration::seconds(max_age),
+    )))
+}
+
+/// Revoke the remember-me token in `jar`, if any, and remove its cookie. Call it from the app's
+/// logout handler alongside `auth::logout_with`.
+pub async fn forget_session(pool: &PgPool, jar: AxumCookieJar) -> AxumCookieJar {
+    let Some((id, secret)) = jar
+        .get(REMEMBER_COOKIE)
+        .and_then(|cookie| parse_cookie(cookie.value()))
+    else {
+        return jar;
+    };
+    if let Err(err) = sqlx::query(
+        r#"
+        UPDATE auth.remembered_sessions
+        SET revoked_at = $3
+        WHERE id = $1 AND secret_hash = $2 AND revoked_at IS NULL
+        "#,
+    )
+    .bind(id)
+    .bind(secret_hash(&secret))
+    .bind(chrono::Utc::now().naive_utc())
+    .execute(pool)
+    .await
+    {
+        tracing::warn!("Failed to revoke remembered session {}: {}", id, err);
+    }
+    jar.remove(Cookie::build(REMEMBER_COOKIE).path("/"))
+}
+
+fn remember_cookie<'a>(value: String, max_age: cookie::time::Duration) -> Cookie<'a> {
+    Cookie::build((REMEMBER_COOKIE, value))
+        .path("/")
+        .http_only(true)
+        .same_site(SameSite::Lax)
+        .secure(true)
+        .max_age(max_age)
+        .build()
+}
+
+fn parse_cookie(value: &str) -> Option<(Uuid, String)> {
+    let (id, secret) = value.split_once('.')?;
+    Some((Uuid::parse_str(id).ok()?, secret.to_string()))
+}
+
+fn token_key(secret: &str) -> Option<LessSafeKey> {
+    let digest = Sha256::new()
+        .chain_update(b"remember-me-token:")
+        .chain_update(secret.as_bytes())
+        .finalize();
+    UnboundKey::new(&AES_256_GCM, &digest)
+        .ok()
+        .map(LessSafeKey::new)
+}
+
+/// `token` encrypted for row `id`, as base64 of nonce and ciphertext.
+fn seal(secret: &str, id: Uuid, token: &OidcToken) -> Option<String> {
+    let key = token_key(secret)?;
+    let mut nonce = [0u8; NONCE_LEN];
+    SystemRandom::new().fill(&mut nonce).ok()?;
+    let mut sealed = serde_json::to_vec(token).ok()?;
+    key.seal_in_place_append_tag(
+        Nonce::assume_unique_for_key(nonce),
+        Aad::from(id.as_bytes()),
+        &mut sealed,
+    )
+    .ok()?;
+    let mut stored = nonce.to_vec();
+    stored.extend_from_slice(&sealed);
+    Some(URL_SAFE_NO_PAD.encode(stored))
+}
+
+fn open(secret: &str, id: Uuid, stored: &str) -> Option<OidcToken> {
+    let key = token_key(secret)?;
+    let mut stored = URL_SAFE_NO_PAD.decode(stored).ok()?;
+    if stored.len() < NONCE_LEN {
+        return None;
+    }
+    let mut sealed = stored.split_off(NONCE_LEN);
+    let nonce = Nonce::try_assume_unique_for_key(&stored).ok()?;
+    let plaintext = key
+        .open_in_place(nonce, Aad::from(id.as_bytes()), &mut sealed)
+        .ok()?;
+    serde_json::from_slice(plaintext).ok()
+}
+
+/// The database or the identity provider could not be reached, so a remember-me cookie could be
+/// neither restored nor ruled out.
+struct RestoreUnavailable;
+
+/// The session token for a remember-me cookie, refreshed with the provider if it expired, or
+/// `None` if the cookie is unknown, revoked, expired or no longer accepted by the provider, or its
+/// user is inactive, deleted or suspended.
+async fn restore_session<S: ValidatesIdentity>(
+    pool: &PgPool,
+    state: &S,
+    value: &str,
+) -> Result<Option<OidcToken>, RestoreUnavailable> {
+    let Some((id, secret)) = parse_cookie(value) else {
+        return Ok(None);
+    };
+    let row: Option<(Uuid, String)> = sqlx::query_as(
+        r#"
+        SELECT s.user_id, s.token
+        FROM auth.remembered_sessions s
+        JOIN auth.users u ON u.id = s.user_id
+        WHERE s.id = $1
+          AND s.secret_hash = $2
+          AND s.revoked_at IS NULL
+          AND s.expires_at > $3
+          AND u.active
+          AND u.deleted_at IS NULL
+          AND NOT EXISTS (
+              SELECT 1
+              FROM auth.suspensions x
+              WHERE x.user_id = s.user_id
+                AND x.lifted_at IS NULL
+                AND x.starts_at <= $3
+                AND (x.ends_at IS NULL OR x.ends_at > $3)
+          )
+        "#,
+    )
+    .bind(id)
+    .bind(secret_hash(&secret))
+    .bind(chrono::Utc::now().naive_utc())
+    .fetch_optional(pool)
+    .await
+    .map_err(|err| {
+        tracing::warn!("Failed to load remembered session {}: {}", id, err);
+        RestoreUnavailable
+    })?;
+    let Some((user_id, stored)) = row else {
+        return Ok(None);
+    };
+    let Some(token) = open(&secret, id, &stored) else {
+        return Ok(None);
+    };
+    let (auth_user, refreshed) =
+        match AuthenticatedUser::validate_session(state, token.clone()).await {
+            Ok(validated) => validated,
+            Err(err) if err.downcast_ref::<ProviderUnavailable>().is_some() => {
+                tracing::warn!("Remembered session {} not restored: {}", id, err);
+                return Err(RestoreUnavailable);
+            }
+            Err(err) => {
+                tracing::debug!("Remembered session {} not restored: {}", id, err);
+                return Ok(None);
+            }
+        };
+    if auth_user.id().0 != user_id {
+        tracing::warn!("Remembered session {} belongs to another user", id);
+        return Ok(None);
+    }
+
+    let token = refreshed.unwrap_or(token);
+    let sealed = seal(&secret, id, &token);
+    if let Err(err) = sqlx::query(
+        r#"
+        UPDATE auth.remembered_sessions
+        SET token = COALESCE($2, token), last_used_at = $3
+        WHERE id = $1
+        "#,
+    )
+    .bind(id)
+    .bind(sealed)
+    .bind(chrono::Utc::now().naive_utc())
+    .execute(pool)
+    .await
+    {
+        tracing::warn!("Failed to update remembered session {}: {}", id, err);
+    }
+    Ok(Some(token))
+}
+
+/// Re-establish sessions from remember-me cookies.
+///
+/// Requests without a bearer token or session cookie but with a valid `REMEMBER_COOKIE` get a
+/// fresh session cookie, both on the request, so `AuthService` inside this layer authenticates
+/// them, and on the response. Unusable remember-me cookies are removed. While the database or the
+/// identity provider cannot be reached, the request goes on unauthenticated and the cookie is
+/// kept for a later one.
+///
+/// ```ignore
+/// let app = Router::new()
+///     .route("/reports", get(list_reports))
+///     .layer(AuthLayer::new(state.clone()))
+///     .layer(remember_me_layer(state, pool));
+/// ```
+pub fn remember_me_layer<State>(state: State, pool: PgPool) -> RememberMeLayer<State>
+where
+    State: ValidatesIdentity,
+{
+    RememberMeLayer::new(state, pool)
+}
+
+#[derive(Clone)]
+pub struct RememberMeLayer<State> {
+    state: State,
+    pool: PgPool,
+}
+
+impl<State> RememberMeLayer<State>
+where
+    State: ValidatesIdentity,
+{
+    pub fn new(state: State, pool: PgPool) -> Self {
+        Self { state, pool }
+    }
+}
+
+impl<State, Inner> Layer<Inner> for RememberMeLayer<State>
+where
+    State: Clone,
+{
+    type Service = RememberMe<State, Inner>;
+
+    fn layer(&self, inner: Inner) -> Self::Service {
+        RememberMe {
+            state: self.state.clone(),
+            pool: self.pool.clone(),
+            inner,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct RememberMe<State, Inner> {
+    state: State,
+    pool: PgPool,
+    inner: Inner,
+}
+
+impl<State, Inner, B> Service<Request<B>> for RememberMe<State, Inner>
+where
+    State: Clone + Send + Sync + ValidatesIdentity + 'static,
+    Inner: Service<Request<B>, Response = Response> + Clone + Send + 'static,
+    Inner::Future: Send + 'static,
+    B: Send + 'static,
+{
+    type Response = Response;
+    type Error = Inner::Error;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: Request<B>) -> Self::Future {
+        let state = self.state.clone();
+        let pool = self.pool.clone();
+        let clone = self.inner.clone();
+        // https://docs.rs/tower/latest/tower/trait.Service.html#be-careful-when-cloning-inner-services
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        Box::pin(async move {
+            let remembered = if req.headers().contains_key(AUTHORIZATION) {
+                None
+            } else {
+                let mut remembered = None;
+                for cookie in cookies_from_request(req.headers()) {
+                    match cookie.name() {
+                        AUTH_COOKIE => {
+                            remembered = None;
+                            break;
+                        }
+                        REMEMBER_COOKIE => remembered = Some(cookie.value().to_string()),
+                        _ => {}
+                    }
+                }
+                remembered
+            };
+            let Some(remembered) = remembered else {
+                return inner.call(req).await;
+            };
+
+            let set_cookie = match restore_session(&pool, &state, &remembered).await {
+                Ok(Some(token)) => {
+                    let cookie = auth_cookie(token).encoded().to_string();
+                    let request_cookie = cookie.split(';').next().unwrap_or_default();
+                    if let Ok(value) = HeaderValue::from_str(request_cookie) {
+                        req.headers_mut().append(COOKIE, value);
+                    }
+                    cookie
+                }
+                Ok(None) => Cookie::build((REMEMBER_COOKIE, ""))
+                    .path("/")
+                    .max_age(cookie::time::Duration::ZERO)
+                    .build()
+                    .to_string(),
+                Err(RestoreUnavailable) => return inner.call(req).await,
+            };
+            let mut response = inner.call(req).await?;
+            if let Ok(value) = HeaderValue::from_str(&set_cookie) {
+                response.headers_mut().append(SET_COOKIE, value);
+            }
+            Ok(response)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use openidconnect::ClaimsVerificationError;
+    use openidconnect::core::{CoreIdToken, CoreIdTokenClaims};
+
+    use super::{RestoreUnavailable, open, restore_session, seal};
+    use crate::oidc::OidcToken;
+    use crate::prelude::ValidatesIdentity;
+
+    struct NoProvider;
+
+    impl ValidatesIdentity for NoProvider {
+        fn validate_bearer(
+            &self,
+            _token: &str,
+        ) -> Result<(CoreIdToken, CoreIdTokenClaims), ClaimsVerificationError> {
+            unreachable!()
+        }
+
+        fn validate_token(
+            &self,
+            _token: &OidcToken,
+        ) -> Result<(CoreIdToken, CoreIdTokenClaims), ClaimsVerificationError> {
+            unreachable!()
+        }
+
+        async fn refresh_token(&self, _token: OidcToken) -> anyhow::Result<OidcToken> {
+            unreachable!()
+        }
+    }
+
+    #[test]
+    fn sealed_tokens_need_the_cookie_secret_and_row() {
+        let id = uuid::Uuid::new_v4();
+        let token = OidcToken::from_bearer(concat!(
+            "eyJhbGciOiJSUzI1NiJ9.",
+            "eyJpc3MiOiJodHRwczovL2lkcC5leGFtcGxlLmNvbSIsInN1YiI6ImEiLCJhdWQiOiJhcHAiLCJleHAiOjIwMDAwMDAwMDAsImlhdCI6MTcwMDAwMDAwMH0",
+            ".c2ln:access:refresh:nonce"
+        ))
+        .unwrap();
+        let sealed = seal("secret", id, &token).unwrap();
+        assert_eq!(open("secret", id, &sealed), Some(token));
+        assert_eq!(open("other", id, &sealed), None);
+        assert_eq!(open("secret", uuid::Uuid::new_v4(), &sealed), None);
+    }
+
+    #[tokio::test]
+    async fn an_unreachable_database_keeps_the_cookie() {
+        let pool = sqlx::postgres::PgPoolOptions::new()
+            .acquire_timeout(std::time::Duration::from_millis(100))
+            .connect_lazy("postgres://auth@127.0.0.1:1/auth")
+            .unwrap();
+        let cookie = format!("{}.secret", uuid::Uuid::new_v4());
+        assert!(matches!(
+            restore_session(&pool, &NoProvider, &cookie).await,
+            Err(RestoreUnavailable)
+        ));
+        assert!(matches!(
+            restore_session(&pool, &NoProvider, "not a cookie").await,
+            Ok(None)
+        ));
+    }
+}
@@ -0,0 +1,236 @@
+use std::str::FromStr;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, PgPool};
+
+/// Width of the time buckets a report counts in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Bucket {
+    Hour,
+    #[default]
+    Day,
+    Week,
+    Month,
+}
+
+impl Bucket {
+    /// The `date_trunc` field name.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Bucket::Hour => "hour",
+            Bucket::Day => "day",
+            Bucket::Week => "week",
+            Bucket::Month => "month",
+        }
+    }
+}
+
+/// The window `[from, to)`, counted in `bucket`s. Buckets are aligned to UTC, so the first and
+/// last may be partial.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReportRange {
+    pub from: DateTime<Utc>,
+    pub to: DateTime<Utc>,
+    pub bucket: Bucket,
+}
+
+impl ReportRange {
+    pub fn new(from: DateTime<Utc>, to: DateTime<Utc>, bucket: Bucket) -> Self {
+        Self { from, to, bucket }
+    }
+
+    /// The last `days` days, by day.
+    pub fn last_days(days: u32) -> Self {
+        let to = Utc::now();
+        Self::new(
+            to - chrono::Duration::days(i64::from(days)),
+            to,
+            Bucket::Day,
+        )
+    }
+}
+
+/// One value of one series. A report is a flat list of these, oldest first, which Grafana's
+/// JSON and Infinity data sources read as time series by `series`. Buckets without activity
+/// are left out.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, FromRow)]
+pub struct ReportPoint {
+    /// Start of the bucket.
+    pub time: DateTime<Utc>,
+    pub series: String,
+    pub value: i64,
+}
+
+/// A report `run` can produce.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Report {
+    ActiveUsers,
+    LoginsByProvider,
+    RoleGrantChurn,
+    SignupCohorts,
+}
+
+impl Report {
+    pub const ALL: [Report; 4] = [
+        Report::ActiveUsers,
+        Report::LoginsByProvider,
+        Report::RoleGrantChurn,
+        Report::SignupCohorts,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Report::ActiveUsers => "active_users",
+            Report::LoginsByProvider => "logins_by_provider",
+            Report::RoleGrantChurn => "role_grant_churn",
+            Report::SignupCohorts => "signup_cohorts",
+        }
+    }
+
+    pub async fn run(
+        self,
+        pool: &PgPool,
+        range: &ReportRange,
+    ) -> Result<Vec<ReportPoint>, sqlx::Error> {
+        match self {
+            Report::ActiveUsers => active_users(pool, range).await,
+            Report::LoginsByProvider => logins_by_provider(pool, range).await,
+            Report::RoleGrantChurn => role_grant_churn(pool, range).await,
+            Report::SignupCohorts => signup_cohorts(pool, range).await,
+        }
+    }
+}
+
+impl FromStr for Report {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        Report::ALL
+            .into_iter()
+            .find(|report| report.as_str() == value)
+            .ok_or_else(|| format!("unknown report {value}"))
+    }
+}
+
+/// Distinct users who logged in, per bucket: daily, weekly or monthly active users. Series
+/// `active_users`.
+pub async fn active_users(
+    pool: &PgPool,
+    range: &ReportRange,
+) -> Result<Vec<ReportPoint>, sqlx::Error> {
+    sqlx::query_as::<_, ReportPoint>(
+        r#"
+        SELECT
+            date_trunc($1, created_at) AT TIME ZONE 'UTC' AS time,
+            'active_users' AS series,
+            count(DISTINCT user_id) AS value
+        FROM auth.login_history
+        WHERE created_at >= $2 AND created_at < $3
+        GROUP BY 1
+        ORDER BY 1
+        "#,
+    )
+    .bind(range.bucket.as_str())
+    .bind(range.from.naive_utc())
+    .bind(range.to.naive_utc())
+    .fetch_all(pool)
+    .await
+}
+
+/// Logins per bucket, one series per identity provider issuer. Logins recorded without a
+/// provider are filed under their method.
+pub async fn logins_by_provider(
+    pool: &PgPool,
+    range: &ReportRange,
+) -> Result<Vec<ReportPoint>, sqlx::Error> {
+    sqlx::query_as::<_, ReportPoint>(
+        r#"
+        SELECT
+            date_trunc($1, created_at) AT TIME ZONE 'UTC' AS time,
+            COALESCE(provider, method) AS series,
+            count(*) AS value
+        FROM auth.login_history
+        WHERE created_at >= $2 AND created_at < $3
+        GROUP BY 1, 2
+        ORDER BY 1, 2
+        "#,
+    )
+    .bind(range.bucket.as_str())
+    .bind(range.from.naive_utc())
+    .bind(range.to.naive_utc())
+    .fetch_all(pool)
+    .await
+}
+
+/// Role assignment changes per bucket from the audit log, as series `granted`, `denied` and
+/// `revoked`. Grants from every path that audits them are counted: the roles API, bundles,
+/// group defaults and claims mapping.
+pub async fn role_grant_churn(
+    pool: &PgPool,
+    range: &ReportRange,
+) -> Result<Vec<ReportPoint>, sqlx::Error> {
+    sqlx::query_as::<_, ReportPoint>(
+        r#"
+        SELECT
+            date_trunc($1, timestamp) AT TIME ZONE 'UTC' AS time,
+            CASE action->>'type'
+                WHEN 'role_grant' THEN 'granted'
+                WHEN 'role_deny' THEN 'denied'
+                ELSE 'revoked'
+            END AS series,
+            count(*) AS value
+        FROM auth.log
+        WHERE action->>'type' IN ('role_grant', 'role_deny', 'role_revoke')
+          AND timestamp >= $2 AND timestamp < $3
+        GROUP BY 1, 2
+        ORDER BY 1, 2
+        "#,
+    )
+    .bind(range.bucket.as_str())
+    .bind(range.from.naive_utc())
+    .bind(range.to.naive_utc())
+    .fetch_all(pool)
+    .await
+}
+
+/// Users grouped by the bucket they signed up in: series `signed_up`, and `returned` for those
+/// who logged in after their signup bucket ended and before the end of the range.
+pub async fn signup_cohorts(
+    pool: &PgPool,
+    range: &ReportRange,
+) -> Result<Vec<ReportPoint>, sqlx::Error> {
+    sqlx::query_as::<_, ReportPoint>(
+        r#"
+        WITH cohorts AS (
+            SELECT
+                date_trunc($1, u.created_at) AS cohort,
+                count(*) AS signed_up,
+                count(*) FILTER (
+                    WHERE EXISTS (
+                        SELECT 1
+                        FROM auth.login_history h
+                        WHERE h.user_id = u.id
+                          AND h.created_at >= date_trunc($1, u.created_at) + ('1 ' || $1)::INTERVAL
+                          AND h.created_at < $3
+                    )
+                ) AS returned
+            FROM auth.users u
+            WHERE u.created_at >= $2 AND u.created_at < $3
+            GROUP BY 1
+        )
+        SELECT cohort AT TIME ZONE 'UTC' AS time, series, value
+        FROM cohorts
+        CROSS JOIN LATERAL (
+            VALUES ('signed_up', signed_up), ('returned', returned)
+        ) AS counts (series, value)
+        ORDER BY 1, 2 DESC
+        "#,
+    )
+    .bind(range.bucket.as_str())
+    .bind(range.from.naive_utc())
+    .bind(range.to.naive_utc())
+    .fetch_all(pool)
+    .await
+}
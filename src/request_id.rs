@@ -0,0 +1,221 @@
+//! Request ids for tying a support ticket to the auth decisions and mutations of one request.
+//!
+//! `RequestIdLayer` takes the id from an incoming `X-Request-Id` header, or generates one, and
+//! runs the rest of the request inside a `request` tracing span carrying it. Rows written to
+//! `auth.log` while the request is handled record the id, error bodies from `RejectReason`
+//! include it as `request_id`, and the response echoes it back in `X-Request-Id`.
+//!
+//! ```ignore
+//! let app = Router::new()
+//!     .merge(subseq_auth::api::routes(store))
+//!     .layer(AuthLayer::new(app_state.clone()))
+//!     .layer(RequestIdLayer::new())
+//!     .with_state(app_state);
+//! ```
+
+use std::fmt;
+use std::task::{Context, Poll};
+
+use axum::body::Body;
+use axum::extract::{FromRequestParts, Request};
+use axum::http::header::{CONTENT_LENGTH, HeaderName};
+use axum::http::request::Parts;
+use axum::http::{HeaderMap, HeaderValue};
+use axum::response::Response;
+use futures_util::future::BoxFuture;
+use serde_json::Value;
+use tower::{Layer, Service};
+use tracing::Instrument;
+
+use crate::ids::new_uuid;
+use crate::prelude::ErrorCode;
+
+pub static REQUEST_ID_HEADER: HeaderName = HeaderName::from_static("x-request-id");
+
+/// Longest caller-supplied id that is kept; longer ones are replaced with a generated id.
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+/// Error bodies are small JSON documents; anything bigger is passed through untouched.
+const MAX_ERROR_BODY: usize = 64 * 1024;
+
+tokio::task_local! {
+    static CURRENT: RequestId;
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct RequestId(String);
+
+impl RequestId {
+    pub fn generate() -> Self {
+        Self(new_uuid().to_string())
+    }
+
+    /// Accept a caller-supplied id of printable ASCII without spaces, so it is safe to echo in
+    /// headers and logs.
+    pub fn parse(value: &str) -> Option<Self> {
+        let valid = !value.is_empty()
+            && value.len() <= MAX_REQUEST_ID_LEN
+            && value.bytes().all(|byte| byte.is_ascii_graphic());
+        valid.then(|| Self(value.to_string()))
+    }
+
+    fn from_headers(headers: &HeaderMap) -> Self {
+        headers
+            .get(&REQUEST_ID_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .and_then(Self::parse)
+            .unwrap_or_else(Self::generate)
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// Run `future` with this as the current request id.
+    pub async fn scope<F: Future>(self, future: F) -> F::Output {
+        CURRENT.scope(self, future).await
+    }
+}
+
+impl fmt::Display for RequestId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// Id of the request being handled by the current task, if it runs under `RequestIdLayer` or
+/// `RequestId::scope`.
+pub fn current_request_id() -> Option<String> {
+    CURRENT.try_with(|id| id.0.clone()).ok()
+}
+
+impl<S> FromRequestParts<S> for RequestId
+where
+    S: Send + Sync,
+{
+    type Rejection = std::convert::Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        Ok(parts
+            .extensions
+            .get::<RequestId>()
+            .cloned()
+            .unwrap_or_else(|| Self::from_headers(&parts.headers)))
+    }
+}
+
+/// Assign every request an id; see the module docs. Add it outside `AuthLayer` so
+/// authentication failures are covered too.
+#[derive(Debug, Clone, Default)]
+pub struct RequestIdLayer;
+
+impl RequestIdLayer {
+    pub fn new() -> Self {
+        Self
+    }
+}
+
+impl<Inner> Layer<Inner> for RequestIdLayer {
+    type Service = RequestIdService<Inner>;
+
+    fn layer(&self, inner: Inner) -> Self::Service {
+        RequestIdService { inner }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RequestIdService<Inner> {
+    inner: Inner,
+}
+
+impl<Inner> Service<Request> for RequestIdService<Inner>
+where
+    Inner: Service<Request, Response = Response> + Clone + Send + 'static,
+    Inner::Future: Send + 'static,
+{
+    type Response = Response;
+    type Error = Inner::Error;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: Request) -> Self::Future {
+        let clone = self.inner.clone();
+        // https://docs.rs/tower/latest/tower/trait.Service.html#be-careful-when-cloning-inner-services
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        let id = RequestId::from_headers(req.headers());
+        req.extensions_mut().insert(id.clone());
+        let span = tracing::info_span!(
+            "request",
+            request_id = %id,
+            method = %req.method(),
+            path = %req.uri().path(),
+        );
+        Box::pin(
+            id.clone()
+                .scope(async move {
+                    let response = inner.call(req).await?;
+                    Ok(tag_response(response, &id).await)
+                })
+                .instrument(span),
+        )
+    }
+}
+
+async fn tag_response(response: Response, id: &RequestId) -> Response {
+    let mut response = if response.extensions().get::<ErrorCode>().is_some() {
+        add_to_error_body(response, id).await
+    } else {
+        response
+    };
+    if let Ok(value) = HeaderValue::from_str(id.as_str()) {
+        response
+            .headers_mut()
+            .insert(REQUEST_ID_HEADER.clone(), value);
+    }
+    response
+}
+
+async fn add_to_error_body(response: Response, id: &RequestId) -> Response {
+    let (mut parts, body) = response.into_parts();
+    let bytes = match axum::body::to_bytes(body, MAX_ERROR_BODY).await {
+        Ok(bytes) => bytes,
+        Err(err) => {
+            tracing::warn!("Failed to read error body to add the request id: {}", err);
+            return Response::from_parts(parts, Body::empty());
+        }
+    };
+    let Ok(Value::Object(mut value)) = serde_json::from_slice::<Value>(&bytes) else {
+        return Response::from_parts(parts, Body::from(bytes));
+    };
+    value.insert("request_id".to_string(), Value::String(id.to_string()));
+    parts.headers.remove(CONTENT_LENGTH);
+    let body = serde_json::to_vec(&value).expect("valid json");
+    Response::from_parts(parts, Body::from(body))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn caller_ids_are_checked() {
+        assert_eq!(
+            RequestId::parse("req-42").map(|id| id.to_string()),
+            Some("req-42".to_string())
+        );
+        assert!(RequestId::parse("").is_none());
+        assert!(RequestId::parse("two words").is_none());
+        assert!(RequestId::parse(&"x".repeat(MAX_REQUEST_ID_LEN + 1)).is_none());
+    }
+
+    #[tokio::test]
+    async fn scope_sets_current_id() {
+        assert_eq!(current_request_id(), None);
+        let id = RequestId::parse("abc").unwrap();
+        let seen = id.scope(async { current_request_id() }).await;
+        assert_eq!(seen.as_deref(), Some("abc"));
+    }
+}
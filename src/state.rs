@@ -0,0 +1,215 @@
+//! A ready-made app state, for apps that have nothing of their own to add to it.
+//!
+//! ```ignore
+//! let state = AuthState::from_config(AuthConfig::from_env()?).await?;
+//! db::create_user_tables(&state.pool()).await?;
+//! let app = Router::new()
+//!     .merge(state.routes())
+//!     .route("/reports", get(list_reports))
+//!     .with_state(state);
+//! ```
+//!
+//! Apps with state of their own keep an `AuthState` in it and implement
+//! `FromRef<AppState> for AuthState`; `routes::<AppState>()` then mounts on their router. Apps
+//! that need their own policies or event hooks still implement `AuthApp` on their own type;
+//! `identity::IdentityValidator` covers the identity half of it.
+
+use std::sync::Arc;
+
+use anyhow::Context;
+use axum::Router;
+use axum::extract::FromRef;
+use openidconnect::ClaimsVerificationError;
+use openidconnect::core::{CoreIdToken, CoreIdTokenClaims};
+use sqlx::PgPool;
+use tower_sessions::MemoryStore;
+
+use crate::api::{AnnouncesUserEvents, AuthApp, HasPool, User, routes_from_ref};
+use crate::auth::AuthLayer;
+use crate::breaker::ProviderHealth;
+use crate::config::AuthConfig;
+use crate::db::connect;
+use crate::environment::set_environment;
+use crate::hooks::{Hooks, NoHooks};
+use crate::idempotency::IdempotencyLayer;
+use crate::identity::IdentityValidator;
+use crate::ids::set_uuid_version;
+use crate::keys::KeyRing;
+use crate::oidc::OidcToken;
+use crate::prelude::{GroupId, UserId, ValidatesIdentity};
+use crate::redact::set_redaction_mode;
+use crate::request_id::RequestIdLayer;
+use crate::suspension::RequireNotSuspendedLayer;
+
+/// Pool, identity validator, session store and signing keys built from an `AuthConfig`, with
+/// the default policies of every `AuthApp` hook. User events are only logged. Cheap to clone.
+#[derive(Clone)]
+pub struct AuthState {
+    config: Arc<AuthConfig>,
+    pool: Arc<PgPool>,
+    identity: IdentityValidator,
+    store: MemoryStore,
+    key_ring: Option<KeyRing>,
+    token_issuer: Option<String>,
+    hooks: Option<Arc<dyn Hooks>>,
+}
+
+impl AuthState {
+    /// Validate `config`, apply its process-wide settings, connect to the database and build the
+    /// identity validator. With the `jwt` identity mode its key ring also signs permission
+    /// tokens and is rotated by `/auth/admin/keys/rotate`.
+    ///
+    /// Migrations are not run; call `db::create_user_tables` when the app should apply them.
+    pub async fn from_config(config: AuthConfig) -> anyhow::Result<Self> {
+        config.validate()?;
+        set_redaction_mode(config.redaction);
+        set_uuid_version(config.uuid_version);
+        set_environment(config.environment.clone());
+        let pool = connect(config.database.db_config())
+            .await
+            .context("Failed to connect to the database")?;
+        let identity = IdentityValidator::from_config(&config).await?;
+        let key_ring = match &identity {
+            IdentityValidator::Jwt(jwt) => Some(jwt.key_ring().clone()),
+            _ => None,
+        };
+        Ok(Self {
+            config: Arc::new(config),
+            pool: Arc::new(pool),
+            identity,
+            store: MemoryStore::default(),
+            key_ring,
+            token_issuer: None,
+            hooks: None,
+        })
+    }
+
+    /// Sign permission tokens with `ring` and rotate it from `/auth/admin/keys/rotate`.
+    pub fn with_key_ring(mut self, ring: KeyRing) -> Self {
+        self.key_ring = Some(ring);
+        self
+    }
+
+    /// See `AuthApp::token_issuer`.
+    pub fn with_token_issuer<S: Into<String>>(mut self, issuer: S) -> Self {
+        self.token_issuer = Some(issuer.into());
+        self
+    }
+
+    /// See `AuthApp::hooks`.
+    pub fn with_hooks<H: Hooks + 'static>(mut self, hooks: H) -> Self {
+        self.hooks = Some(Arc::new(hooks));
+        self
+    }
+
+    pub fn config(&self) -> &AuthConfig {
+        &self.config
+    }
+
+    pub fn identity(&self) -> &IdentityValidator {
+        &self.identity
+    }
+
+    pub fn session_store(&self) -> &MemoryStore {
+        &self.store
+    }
+
+    /// The `/auth` routes with the configured session cookie and registration mode, behind the
+    /// layer that authenticates requests: `AuthLayer`, and the trusted proxy's layer in the
+    /// `trusted_header` identity mode. `RequireNotSuspendedLayer` refuses suspended users,
+    /// `IdempotencyLayer` replays retried POSTs and `RequestIdLayer` wraps them all. `O` is the
+    /// router's state: `AuthState` itself, or an app state it can be extracted from with `FromRef`.
+    pub fn routes<O>(&self) -> Router<O>
+    where
+        Self: FromRef<O>,
+        O: Clone + Send + Sync + 'static,
+    {
+        let routes = routes_from_ref::<Self, O>(
+            self.store.clone(),
+            &self.config.session,
+            self.config.registration,
+        )
+        .layer(IdempotencyLayer::new(self.clone()))
+        .layer(RequireNotSuspendedLayer::new(self.clone()));
+        let routes = match &self.identity {
+            IdentityValidator::TrustedHeader(trusted) => routes.layer(trusted.layer(self.clone())),
+            _ => routes,
+        };
+        routes
+            .layer(AuthLayer::new(self.clone()))
+            .layer(RequestIdLayer::new())
+    }
+}
+
+impl HasPool for AuthState {
+    fn pool(&self) -> Arc<PgPool> {
+        self.pool.clone()
+    }
+}
+
+impl ValidatesIdentity for AuthState {
+    fn validate_bearer(
+        &self,
+        token: &str,
+    ) -> Result<(CoreIdToken, CoreIdTokenClaims), ClaimsVerificationError> {
+        self.identity.validate_bearer(token)
+    }
+
+    fn validate_token(
+        &self,
+        token: &OidcToken,
+    ) -> Result<(CoreIdToken, CoreIdTokenClaims), ClaimsVerificationError> {
+        self.identity.validate_token(token)
+    }
+
+    fn refresh_token(
+        &self,
+        token: OidcToken,
+    ) -> impl std::future::Future<Output = anyhow::Result<OidcToken>> + Send {
+        self.identity.refresh_token(token)
+    }
+}
+
+impl AnnouncesUserEvents for AuthState {
+    fn announce_new_user(&self, user: &User) {
+        tracing::info!("New user {}", user.id);
+    }
+
+    fn announce_user_deactivation(&self, user_id: UserId) {
+        tracing::info!("Deactivated user {}", user_id);
+    }
+
+    fn announce_user_update(&self, user: &User) {
+        tracing::debug!("Updated user {}", user.id);
+    }
+
+    fn announce_user_group_join(&self, user_id: UserId, group_id: GroupId) {
+        tracing::debug!("User {} joined group {}", user_id, group_id);
+    }
+
+    fn announce_user_group_leave(&self, user_id: UserId, group_id: GroupId) {
+        tracing::debug!("User {} left group {}", user_id, group_id);
+    }
+}
+
+impl AuthApp for AuthState {
+    fn key_ring(&self) -> Option<&KeyRing> {
+        self.key_ring.as_ref()
+    }
+
+    fn identity_provider_health(&self) -> Vec<ProviderHealth> {
+        match &self.identity {
+            IdentityValidator::Oidc(idp) => vec![idp.health()],
+            IdentityValidator::SessionOnly(session) => vec![session.0.health()],
+            _ => Vec::new(),
+        }
+    }
+
+    fn token_issuer(&self) -> Option<&str> {
+        self.token_issuer.as_deref()
+    }
+
+    fn hooks(&self) -> &dyn Hooks {
+        self.hooks.as_deref().unwrap_or(&NoHooks)
+    }
+}
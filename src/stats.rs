@@ -0,0 +1,120 @@
+use chrono::{NaiveDate, NaiveDateTime};
+use serde::Serialize;
+use sqlx::{FromRow, PgPool};
+
+/// Days of history kept in `auth.daily_stats`.
+pub const MAX_STATS_DAYS: u32 = 365;
+
+/// Aggregate counts for an admin dashboard.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct AuthStats {
+    pub total_users: i64,
+    /// Users that are neither deactivated nor waiting for approval.
+    pub active_users: i64,
+    pub pending_users: i64,
+    /// Unrevoked, unexpired remember-me sessions. Browser sessions live in the app's session
+    /// store and are not counted.
+    pub active_sessions: i64,
+    /// Groups that are not deactivated.
+    pub groups: i64,
+    /// Role grants held directly by users.
+    pub user_grants: i64,
+    /// Role grants held by groups.
+    pub group_grants: i64,
+    /// Failed logins over the days in `daily`.
+    pub failed_logins: i64,
+    /// Oldest first, as of `daily_refreshed_at`.
+    pub daily: Vec<DailyStats>,
+    /// Last refresh of `auth.daily_stats`.
+    pub daily_refreshed_at: Option<NaiveDateTime>,
+}
+
+/// Activity on one UTC day.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, FromRow)]
+pub struct DailyStats {
+    pub day: NaiveDate,
+    pub signups: i64,
+    pub logins: i64,
+    pub failed_logins: i64,
+}
+
+#[derive(FromRow)]
+struct Totals {
+    total_users: i64,
+    active_users: i64,
+    pending_users: i64,
+    active_sessions: i64,
+    groups: i64,
+    user_grants: i64,
+    group_grants: i64,
+}
+
+/// Current totals, with the last `days` days of `auth.daily_stats` (at most `MAX_STATS_DAYS`).
+///
+/// Totals are counted live; the daily series is only as fresh as the last
+/// `refresh_daily_stats`.
+pub async fn stats(pool: &PgPool, days: u32) -> Result<AuthStats, sqlx::Error> {
+    let days = days.clamp(1, MAX_STATS_DAYS);
+    let totals = sqlx::query_as::<_, Totals>(
+        r#"
+        SELECT
+            (SELECT count(*) FROM auth.users) AS total_users,
+            (
+                SELECT count(*) FROM auth.users
+                WHERE active IS NOT FALSE AND NOT pending_approval
+            ) AS active_users,
+            (SELECT count(*) FROM auth.users WHERE pending_approval) AS pending_users,
+            (
+                SELECT count(*) FROM auth.remembered_sessions
+                WHERE revoked_at IS NULL AND expires_at > $1
+            ) AS active_sessions,
+            (SELECT count(*) FROM auth.groups WHERE active IS NOT FALSE) AS groups,
+            (SELECT count(*) FROM auth.user_roles) AS user_grants,
+            (SELECT count(*) FROM auth.group_roles) AS group_grants
+        "#,
+    )
+    .bind(chrono::Utc::now().naive_utc())
+    .fetch_one(pool)
+    .await?;
+
+    let daily_refreshed_at: Option<NaiveDateTime> =
+        sqlx::query_scalar("SELECT max(refreshed_at) FROM auth.daily_stats")
+            .fetch_one(pool)
+            .await?;
+    let daily = sqlx::query_as::<_, DailyStats>(
+        r#"
+        SELECT day, signups, logins, failed_logins
+        FROM (
+            SELECT day, signups, logins, failed_logins
+            FROM auth.daily_stats
+            ORDER BY day DESC
+            LIMIT $1
+        ) recent
+        ORDER BY day
+        "#,
+    )
+    .bind(i64::from(days))
+    .fetch_all(pool)
+    .await?;
+
+    Ok(AuthStats {
+        total_users: totals.total_users,
+        active_users: totals.active_users,
+        pending_users: totals.pending_users,
+        active_sessions: totals.active_sessions,
+        groups: totals.groups,
+        user_grants: totals.user_grants,
+        group_grants: totals.group_grants,
+        failed_logins: daily.iter().map(|day| day.failed_logins).sum(),
+        daily,
+        daily_refreshed_at,
+    })
+}
+
+/// Recompute `auth.daily_stats`. Readers keep seeing the previous contents meanwhile.
+pub async fn refresh_daily_stats(pool: &PgPool) -> Result<(), sqlx::Error> {
+    sqlx::query("REFRESH MATERIALIZED VIEW CONCURRENTLY auth.daily_stats")
+        .execute(pool)
+        .await?;
+    Ok(())
+}
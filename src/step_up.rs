@@ -0,0 +1,194 @@
+use std::time::Duration;
+
+use axum::extract::FromRequestParts;
+use axum::http::request::Parts;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tower_sessions::Session;
+
+use crate::prelude::{AuthRejectReason, AuthenticatedUser, RejectReason, UserId};
+
+/// Session key holding the last `record_reauthentication`.
+const REAUTHENTICATED_KEY: &str = "reauthenticated";
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Reauthentication {
+    user_id: UserId,
+    at: DateTime<Utc>,
+}
+
+/// Remember in the session that the user just confirmed their credentials, e.g. their password,
+/// without signing in again at the identity provider.
+pub async fn record_reauthentication(
+    session: &Session,
+    user_id: UserId,
+) -> Result<(), RejectReason> {
+    session
+        .insert(
+            REAUTHENTICATED_KEY,
+            Reauthentication {
+                user_id,
+                at: Utc::now(),
+            },
+        )
+        .await
+        .map_err(|_| RejectReason::Session)
+}
+
+/// When the user last authenticated: the identity provider's `auth_time`, or a later
+/// `record_reauthentication` in `session`.
+pub async fn last_authenticated_at(
+    auth_user: &AuthenticatedUser,
+    session: Option<&Session>,
+) -> Option<DateTime<Utc>> {
+    let reconfirmed = match session {
+        Some(session) => session
+            .get::<Reauthentication>(REAUTHENTICATED_KEY)
+            .await
+            .ok()
+            .flatten()
+            .filter(|reauth| reauth.user_id == auth_user.id())
+            .map(|reauth| reauth.at),
+        None => None,
+    };
+    auth_user.authenticated_at().max(reconfirmed)
+}
+
+/// Refuse with `reauth_required` unless the user authenticated within `max_age`. With
+/// `require_mfa`, only an identity provider sign-in reporting MFA counts; password
+/// reconfirmation does not.
+pub async fn check_fresh_auth(
+    auth_user: &AuthenticatedUser,
+    session: Option<&Session>,
+    max_age: Duration,
+    require_mfa: bool,
+) -> Result<(), RejectReason> {
+    let authenticated_at = if require_mfa {
+        auth_user.authenticated_at().filter(|_| auth_user.mfa())
+    } else {
+        last_authenticated_at(auth_user, session).await
+    };
+    let max_age_chrono = chrono::Duration::from_std(max_age).unwrap_or(chrono::Duration::MAX);
+    let fresh = authenticated_at.is_some_and(|at| {
+        Utc::now()
+            .checked_sub_signed(max_age_chrono)
+            .is_none_or(|cutoff| at >= cutoff)
+    });
+    if fresh {
+        Ok(())
+    } else {
+        tracing::info!(
+            "Refusing {}: last authenticated at {:?}",
+            auth_user.id(),
+            authenticated_at
+        );
+        Err(RejectReason::reauth_required(max_age, require_mfa))
+    }
+}
+
+/// The authenticated user, if they authenticated within the last `MAX_AGE_SECS` seconds, with
+/// MFA when `MFA` is set. Otherwise `403` with the `reauth_required` code; the client sends the
+/// user through `auth::reauthenticate` or `POST /auth/me/reauthenticate` and retries.
+///
+/// ```ignore
+/// async fn delete_account(RequireFreshAuth(user): RequireFreshAuth<300>) { .. }
+/// async fn rotate_keys(RequireFreshAuth(user): RequireFreshAuth<300, true>) { .. }
+/// ```
+#[derive(Debug, Clone)]
+pub struct RequireFreshAuth<const MAX_AGE_SECS: u64, const MFA: bool = false>(
+    pub AuthenticatedUser,
+);
+
+impl<S, const MAX_AGE_SECS: u64, const MFA: bool> FromRequestParts<S>
+    for RequireFreshAuth<MAX_AGE_SECS, MFA>
+where
+    S: Send + Sync,
+{
+    type Rejection = RejectReason;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let auth_user = parts
+            .extensions
+            .get::<AuthenticatedUser>()
+            .cloned()
+            .ok_or_else(|| RejectReason::auth(AuthRejectReason::no_session_token()))?;
+        let session = parts.extensions.get::<Session>().cloned();
+        check_fresh_auth(
+            &auth_user,
+            session.as_ref(),
+            Duration::from_secs(MAX_AGE_SECS),
+            MFA,
+        )
+        .await?;
+        Ok(Self(auth_user))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+    use std::time::Duration;
+
+    use base64::{Engine as _, engine::general_purpose};
+    use serde_json::json;
+
+    use super::check_fresh_auth;
+    use crate::prelude::{AuthenticatedUser, CoreIdToken, CoreIdTokenClaims};
+
+    async fn user(auth_time: i64, amr: &[&str]) -> AuthenticatedUser {
+        let payload = json!({
+            "iss": "https://issuer.example.com",
+            "sub": uuid::Uuid::new_v4().to_string(),
+            "aud": ["app"],
+            "iat": chrono::Utc::now().timestamp(),
+            "exp": chrono::Utc::now().timestamp() + 60,
+            "auth_time": auth_time,
+            "amr": amr,
+            "email": "user@example.com",
+        });
+        let encode = |value: &serde_json::Value| {
+            general_purpose::URL_SAFE_NO_PAD.encode(serde_json::to_vec(value).unwrap())
+        };
+        let token = format!(
+            "{}.{}.c2ln",
+            encode(&json!({"alg": "RS256"})),
+            encode(&payload)
+        );
+        let claims: CoreIdTokenClaims = serde_json::from_value(payload).unwrap();
+        AuthenticatedUser::from_claims(CoreIdToken::from_str(&token).unwrap(), claims)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn requires_recent_authentication() {
+        let now = chrono::Utc::now().timestamp();
+        let max_age = Duration::from_secs(300);
+
+        let recent = user(now - 60, &["pwd"]).await;
+        assert!(
+            check_fresh_auth(&recent, None, max_age, false)
+                .await
+                .is_ok()
+        );
+        assert!(
+            check_fresh_auth(&recent, None, max_age, true)
+                .await
+                .is_err()
+        );
+
+        let stale = user(now - 3600, &["pwd", "mfa"]).await;
+        assert!(
+            check_fresh_auth(&stale, None, max_age, false)
+                .await
+                .is_err()
+        );
+
+        let recent_mfa = user(now - 60, &["pwd", "mfa"]).await;
+        assert!(
+            check_fresh_auth(&recent_mfa, None, max_age, true)
+                .await
+                .is_ok()
+        );
+    }
+}
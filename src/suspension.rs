@@ -0,0 +1,370 @@
+//! Suspensions: bans that end at a set time or when lifted, kept apart from the `active` flag.
+//!
+//! A suspended user keeps their account, roles and memberships, but requests authenticated as
+//! them are refused by `RequireNotSuspendedLayer` with the `suspended` error, which says when the
+//! suspension ends. Lifting a suspension marks it lifted instead of deleting it, so
+//! `suspensions_for_user` is the full history.
+
+use chrono::NaiveDateTime;
+use serde::Serialize;
+use serde_json::json;
+use sqlx::{FromRow, PgPool};
+use uuid::Uuid;
+
+use crate::db::insert_audit_log;
+use crate::ids::new_uuid;
+use crate::remember::RememberedSession;
+use crate::user_id::UserId;
+
+macro_rules! suspension_columns {
+    () => {
+        "id, user_id, reason, note, starts_at, ends_at, imposed_by, created_at, lifted_at, \
+         lifted_by, lift_note"
+    };
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, FromRow)]
+pub struct Suspension {
+    pub id: Uuid,
+    pub user_id: UserId,
+    pub reason: String,
+    pub note: Option<String>,
+    pub starts_at: NaiveDateTime,
+    /// `None` until lifted.
+    pub ends_at: Option<NaiveDateTime>,
+    pub imposed_by: Option<UserId>,
+    pub created_at: NaiveDateTime,
+    pub lifted_at: Option<NaiveDateTime>,
+    pub lifted_by: Option<UserId>,
+    pub lift_note: Option<String>,
+}
+
+impl Suspension {
+    /// In force at `now`: started, not ended and not lifted.
+    pub fn in_force_at(&self, now: NaiveDateTime) -> bool {
+        self.lifted_at.is_none()
+            && self.starts_at <= now
+            && self.ends_at.is_none_or(|ends_at| ends_at > now)
+    }
+}
+
+/// A suspension to impose with `impose_suspension`.
+#[derive(Debug, Clone)]
+pub struct NewSuspension {
+    pub reason: String,
+    pub note: Option<String>,
+    /// Defaults to now.
+    pub starts_at: Option<NaiveDateTime>,
+    /// `None` suspends until lifted.
+    pub ends_at: Option<NaiveDateTime>,
+}
+
+impl NewSuspension {
+    pub fn new<S: Into<String>>(reason: S) -> Self {
+        Self {
+            reason: reason.into(),
+            note: None,
+            starts_at: None,
+            ends_at: None,
+        }
+    }
+
+    pub fn with_note<S: Into<String>>(mut self, note: S) -> Self {
+        self.note = Some(note.into());
+        self
+    }
+
+    pub fn starting_at(mut self, starts_at: NaiveDateTime) -> Self {
+        self.starts_at = Some(starts_at);
+        self
+    }
+
+    pub fn until(mut self, ends_at: NaiveDateTime) -> Self {
+        self.ends_at = Some(ends_at);
+        self
+    }
+}
+
+/// Suspend a user and log a `user_suspended` entry. A suspension already in force also revokes the
+/// user's remember-me tokens; one starting later keeps them from being used once it begins. Fails
+/// on the table's check constraint if `ends_at` is not after `starts_at`.
+pub async fn impose_suspension(
+    pool: &PgPool,
+    actor_user_id: UserId,
+    user_id: UserId,
+    suspension: &NewSuspension,
+) -> Result<Suspension, sqlx::Error> {
+    let now = chrono::Utc::now().naive_utc();
+    let mut tx = pool.begin().await?;
+    let row = sqlx::query_as::<_, Suspension>(concat!(
+        r#"
+        INSERT INTO auth.suspensions
+            (id, user_id, reason, note, starts_at, ends_at, imposed_by, created_at)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+        RETURNING "#,
+        suspension_columns!(),
+    ))
+    .bind(new_uuid())
+    .bind(user_id)
+    .bind(&suspension.reason)
+    .bind(&suspension.note)
+    .bind(suspension.starts_at.unwrap_or(now))
+    .bind(suspension.ends_at)
+    .bind(actor_user_id)
+    .bind(now)
+    .fetch_one(&mut *tx)
+    .await?;
+
+    insert_audit_log(
+        &mut *tx,
+        Some(actor_user_id),
+        json!({
+            "type": "user_suspended",
+            "user_id": user_id.to_string(),
+            "suspension_id": row.id.to_string(),
+            "reason": row.reason,
+            "starts_at": row.starts_at,
+            "ends_at": row.ends_at,
+        }),
+    )
+    .await?;
+    if row.in_force_at(now) {
+        RememberedSession::revoke_all_in(&mut tx, user_id).await?;
+    }
+    tx.commit().await?;
+    Ok(row)
+}
+
+/// Lift a suspension early and log a `user_unsuspended` entry. Returns the suspension, or `None`
+/// if it does not exist or was already lifted.
+pub async fn lift_suspension(
+    pool: &PgPool,
+    actor_user_id: UserId,
+    suspension_id: Uuid,
+    note: Option<&str>,
+) -> Result<Option<Suspension>, sqlx::Error> {
+    let now = chrono::Utc::now().naive_utc();
+    let mut tx = pool.begin().await?;
+    let Some(row) = sqlx::query_as::<_, Suspension>(concat!(
+        r#"
+        UPDATE auth.suspensions
+        SET lifted_at = $2,
+            lifted_by = $3,
+            lift_note = $4
+        WHERE id = $1
+          AND lifted_at IS NULL
+        RETURNING "#,
+        suspension_columns!(),
+    ))
+    .bind(suspension_id)
+    .bind(now)
+    .bind(actor_user_id)
+    .bind(note)
+    .fetch_optional(&mut *tx)
+    .await?
+    else {
+        return Ok(None);
+    };
+
+    insert_audit_log(
+        &mut *tx,
+        Some(actor_user_id),
+        json!({
+            "type": "user_unsuspended",
+            "user_id": row.user_id.to_string(),
+            "suspension_id": row.id.to_string(),
+            "note": note,
+        }),
+    )
+    .await?;
+    tx.commit().await?;
+    Ok(Some(row))
+}
+
+/// The suspension holding `user_id` back now, if any. With several in force, the one lasting
+/// longest; an indefinite one outlasts the rest.
+pub async fn active_suspension(
+    pool: &PgPool,
+    user_id: UserId,
+) -> Result<Option<Suspension>, sqlx::Error> {
+    sqlx::query_as::<_, Suspension>(concat!(
+        "SELECT ",
+        suspension_columns!(),
+        r#"
+        FROM auth.suspensions
+        WHERE user_id = $1
+          AND lifted_at IS NULL
+          AND starts_at <= $2
+          AND (ends_at IS NULL OR ends_at > $2)
+        ORDER BY ends_at DESC NULLS FIRST
+        LIMIT 1
+        "#,
+    ))
+    .bind(user_id)
+    .bind(chrono::Utc::now().naive_utc())
+    .fetch_optional(pool)
+    .await
+}
+
+/// Every suspension of `user_id`, including ended and lifted ones, newest first.
+pub async fn suspensions_for_user(
+    pool: &PgPool,
+    user_id: UserId,
+) -> Result<Vec<Suspension>, sqlx::Error> {
+    sqlx::query_as::<_, Suspension>(concat!(
+        "SELECT ",
+        suspension_columns!(),
+        r#"
+        FROM auth.suspensions
+        WHERE user_id = $1
+        ORDER BY starts_at DESC
+        "#,
+    ))
+    .bind(user_id)
+    .fetch_all(pool)
+    .await
+}
+
+#[cfg(feature = "api")]
+mod api_support {
+    use std::task::{Context, Poll};
+
+    use axum::extract::Request;
+    use axum::response::{IntoResponse, Response};
+    use futures_util::future::BoxFuture;
+    use tower::{Layer, Service};
+
+    use super::active_suspension;
+    use crate::api::HasPool;
+    use crate::prelude::{AuthenticatedUser, RejectReason};
+
+    /// Refuse requests authenticated as a suspended user with the `suspended` error, whose
+    /// details carry `until`, the end of the suspension (absent when it lasts until lifted).
+    ///
+    /// Goes inside `AuthLayer`, which identifies the user; unauthenticated requests pass. Checked
+    /// against `HasPool::pool` on every authenticated request, so a new suspension applies before
+    /// replicas catch up.
+    ///
+    /// ```ignore
+    /// let app = Router::new()
+    ///     .merge(subseq_auth::api::routes(store))
+    ///     .layer(RequireNotSuspendedLayer::new(app_state.clone()))
+    ///     .layer(AuthLayer::new(app_state.clone()))
+    ///     .with_state(app_state);
+    /// ```
+    #[derive(Clone)]
+    pub struct RequireNotSuspendedLayer<S> {
+        app: S,
+    }
+
+    impl<S> RequireNotSuspendedLayer<S>
+    where
+        S: HasPool,
+    {
+        pub fn new(app: S) -> Self {
+            Self { app }
+        }
+    }
+
+    impl<S, Inner> Layer<Inner> for RequireNotSuspendedLayer<S>
+    where
+        S: Clone,
+    {
+        type Service = RequireNotSuspended<S, Inner>;
+
+        fn layer(&self, inner: Inner) -> Self::Service {
+            RequireNotSuspended {
+                app: self.app.clone(),
+                inner,
+            }
+        }
+    }
+
+    #[derive(Clone)]
+    pub struct RequireNotSuspended<S, Inner> {
+        app: S,
+        inner: Inner,
+    }
+
+    impl<S, Inner, B> Service<Request<B>> for RequireNotSuspended<S, Inner>
+    where
+        S: HasPool + Clone + Send + Sync + 'static,
+        Inner: Service<Request<B>, Response = Response> + Clone + Send + 'static,
+        Inner::Future: Send + 'static,
+        B: Send + 'static,
+    {
+        type Response = Response;
+        type Error = Inner::Error;
+        type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+        fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            self.inner.poll_ready(cx)
+        }
+
+        fn call(&mut self, req: Request<B>) -> Self::Future {
+            let pool = self.app.pool();
+            let clone = self.inner.clone();
+            // https://docs.rs/tower/latest/tower/trait.Service.html#be-careful-when-cloning-inner-services
+            let mut inner = std::mem::replace(&mut self.inner, clone);
+            Box::pin(async move {
+                let Some(user_id) = req
+                    .extensions()
+                    .get::<AuthenticatedUser>()
+                    .map(|auth_user| auth_user.id())
+                else {
+                    return inner.call(req).await;
+                };
+                match active_suspension(&pool, user_id).await {
+                    Ok(None) => inner.call(req).await,
+                    Ok(Some(suspension)) => {
+                        tracing::info!(
+                            "Rejecting suspended user {} (suspension {})",
+                            user_id,
+                            suspension.id
+                        );
+                        Ok(RejectReason::suspended(suspension.ends_at).into_response())
+                    }
+                    Err(err) => {
+                        tracing::error!("Failed to check suspensions for {}: {}", user_id, err);
+                        Ok(RejectReason::database("Failed to reach database").into_response())
+                    }
+                }
+            })
+        }
+    }
+}
+
+#[cfg(feature = "api")]
+pub use api_support::{RequireNotSuspended, RequireNotSuspendedLayer};
+
+#[cfg(test)]
+mod tests {
+    use chrono::Duration;
+
+    use super::*;
+
+    #[test]
+    fn in_force_between_start_and_end_unless_lifted() {
+        let now = chrono::Utc::now().naive_utc();
+        let mut suspension = Suspension {
+            id: Uuid::new_v4(),
+            user_id: UserId(Uuid::new_v4()),
+            reason: "spam".to_string(),
+            note: None,
+            starts_at: now - Duration::hours(1),
+            ends_at: Some(now + Duration::hours(1)),
+            imposed_by: None,
+            created_at: now,
+            lifted_at: None,
+            lifted_by: None,
+            lift_note: None,
+        };
+        assert!(suspension.in_force_at(now));
+        assert!(!suspension.in_force_at(now + Duration::hours(2)));
+        assert!(!suspension.in_force_at(now - Duration::hours(2)));
+        suspension.ends_at = None;
+        assert!(suspension.in_force_at(now + Duration::days(365)));
+        suspension.lifted_at = Some(now);
+        assert!(!suspension.in_force_at(now));
+    }
+}
@@ -0,0 +1,904 @@
+//! Declarative role reconciliation: a manifest of bundles, group grants and static principals
+//! (service accounts, seed admins) that `apply` makes the database match, for deployments that
+//! bootstrap auth from configuration kept alongside the rest of their infrastructure.
+//!
+//! Only what the manifest names is managed. A listed bundle is created or replaced. A listed group
+//! is created or renamed, and its role grants become exactly the listed ones. A listed principal
+//! is created if missing and the manifest gives an email, and its direct role grants become
+//! exactly the listed ones plus those of its bundles. Grants a principal inherits from groups or
+//! an identity provider, group memberships, and anything the manifest does not mention are left
+//! alone.
+//!
+//! ```toml
+//! [[bundles]]
+//! name = "engineer"
+//! grants = [{ scope = "project", role_name = "writer" }]
+//!
+//! [[groups]]
+//! id = "0190c4a1-7b2e-7c3d-8e4f-5a6b7c8d9e0f"
+//! display_name = "Platform"
+//! grants = [{ scope = "project", scope_id = "infra", role_name = "admin" }]
+//!
+//! [[principals]]
+//! user_id = "0190c4a1-7b2e-7c3d-8e4f-000000000001"
+//! email = "root@example.com"
+//! grants = [{ scope = "global", scope_id = "global", role_name = "super_admin" }]
+//! bundles = [{ bundle = "engineer", scope_id = "infra" }]
+//! ```
+//!
+//! A run takes one transaction under an advisory lock and reports the drift it corrected; `plan`
+//! reports the same drift and rolls back. Changes are audited like the matching API calls, with no
+//! actor, followed by one `role_manifest_applied` entry.
+
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+use std::path::Path;
+
+use email_address::EmailAddress;
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use sqlx::{FromRow, PgConnection, PgPool};
+
+use crate::bundle::{BundleGrant, RoleBundle, write_bundle};
+use crate::db::{
+    GroupRow, RoleAssignmentTarget, RoleEffect, UserRow, insert_audit_log, insert_role_audit_log,
+};
+use crate::environment::validate_scope;
+use crate::group_id::GroupId;
+use crate::user_id::UserId;
+
+/// Serializes concurrent runs, e.g. several replicas applying the manifest at startup.
+const MANIFEST_LOCK: &str = "auth.role_manifest";
+
+/// The desired state. Every list may be omitted.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RoleManifest {
+    #[serde(default)]
+    pub bundles: Vec<ManifestBundle>,
+    #[serde(default)]
+    pub groups: Vec<ManifestGroup>,
+    #[serde(default)]
+    pub principals: Vec<ManifestPrincipal>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ManifestBundle {
+    pub name: String,
+    pub description: Option<String>,
+    #[serde(default)]
+    pub grants: Vec<BundleGrant>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ManifestGroup {
+    pub id: GroupId,
+    pub display_name: String,
+    #[serde(default)]
+    pub grants: Vec<ManifestGrant>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ManifestPrincipal {
+    pub user_id: UserId,
+    /// Create the user with this email if it does not exist. Without it a missing user is an
+    /// error.
+    pub email: Option<String>,
+    #[serde(default)]
+    pub grants: Vec<ManifestGrant>,
+    #[serde(default)]
+    pub bundles: Vec<ManifestBundleRef>,
+}
+
+/// A bundle's grants at `scope_id`. The bundle comes from the manifest or, failing that, the
+/// database.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ManifestBundleRef {
+    pub bundle: String,
+    pub scope_id: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ManifestGrant {
+    pub scope: String,
+    pub scope_id: String,
+    pub role_name: String,
+    #[serde(default)]
+    pub effect: RoleEffect,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ManifestFormat {
+    Toml,
+    Yaml,
+}
+
+impl ManifestFormat {
+    /// From the file extension: `toml`, `yaml` or `yml`.
+    pub fn from_path(path: &Path) -> Option<Self> {
+        match path.extension()?.to_str()? {
+            "toml" => Some(Self::Toml),
+            "yaml" | "yml" => Some(Self::Yaml),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ManifestError {
+    Read(String),
+    Parse(String),
+    Invalid(String),
+}
+
+impl fmt::Display for ManifestError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Read(msg) => write!(f, "Failed to read role manifest: {}", msg),
+            Self::Parse(msg) => write!(f, "Failed to parse role manifest: {}", msg),
+            Self::Invalid(msg) => write!(f, "Invalid role manifest: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for ManifestError {}
+
+type GrantKey = (String, String, String);
+
+impl RoleManifest {
+    pub fn parse(input: &str, format: ManifestFormat) -> Result<Self, ManifestError> {
+        match format {
+            ManifestFormat::Toml => {
+                toml::from_str(input).map_err(|err| ManifestError::Parse(err.to_string()))
+            }
+            ManifestFormat::Yaml => {
+                serde_yaml::from_str(input).map_err(|err| ManifestError::Parse(err.to_string()))
+            }
+        }
+    }
+
+    /// Read a manifest file, in the format given by its extension.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, ManifestError> {
+        let path = path.as_ref();
+        let format = ManifestFormat::from_path(path).ok_or_else(|| {
+            ManifestError::Read(format!(
+                "{} is not a .toml, .yaml or .yml file",
+                path.display()
+            ))
+        })?;
+        let input = std::fs::read_to_string(path)
+            .map_err(|err| ManifestError::Read(format!("{}: {}", path.display(), err)))?;
+        Self::parse(&input, format)
+    }
+
+    /// Check for blank names, duplicate entries and grants both allowed and denied. `apply` does
+    /// this first.
+    pub fn validate(&self) -> Result<(), ManifestError> {
+        let invalid = |msg: String| Err(ManifestError::Invalid(msg));
+        let mut bundles = HashSet::new();
+        for bundle in &self.bundles {
+            if bundle.name.trim().is_empty() {
+                return invalid("Bundle names must not be empty".to_string());
+            }
+            if !bundles.insert(bundle.name.as_str()) {
+                return invalid(format!("Bundle {} is listed twice", bundle.name));
+            }
+            if bundle
+                .grants
+                .iter()
+                .any(|grant| grant.scope.trim().is_empty() || grant.role_name.trim().is_empty())
+            {
+                return invalid(format!("Bundle {} has a blank grant", bundle.name));
+            }
+            for grant in &bundle.grants {
+                validate_scope(&grant.scope).map_err(|msg| {
+                    ManifestError::Invalid(format!(
+                        "Bundle {}: {}: {}",
+                        bundle.name, grant.scope, msg
+                    ))
+                })?;
+            }
+        }
+        let mut groups = HashSet::new();
+        for group in &self.groups {
+            if group.display_name.trim().is_empty() {
+                return invalid(format!("Group {} has no display name", group.id));
+            }
+            if !groups.insert(group.id) {
+                return invalid(format!("Group {} is listed twice", group.id));
+            }
+            grant_map(&group.grants)
+                .map_err(|msg| ManifestError::Invalid(format!("Group {}: {}", group.id, msg)))?;
+        }
+        let mut principals = HashSet::new();
+        for principal in &self.principals {
+            if !principals.insert(principal.user_id) {
+                return invalid(format!("Principal {} is listed twice", principal.user_id));
+            }
+            if let Some(email) = &principal.email
+                && !EmailAddress::is_valid(email)
+            {
+                return invalid(format!(
+                    "Principal {} has an invalid email",
+                    principal.user_id
+                ));
+            }
+            grant_map(&principal.grants).map_err(|msg| {
+                ManifestError::Invalid(format!("Principal {}: {}", principal.user_id, msg))
+            })?;
+            if principal
+                .bundles
+                .iter()
+                .any(|bundle| bundle.bundle.trim().is_empty() || bundle.scope_id.trim().is_empty())
+            {
+                return invalid(format!(
+                    "Principal {} has a blank bundle reference",
+                    principal.user_id
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Grants keyed by `(scope, scope_id, role_name)`, refusing blanks and conflicting effects.
+fn grant_map(grants: &[ManifestGrant]) -> Result<BTreeMap<GrantKey, RoleEffect>, String> {
+    let mut map = BTreeMap::new();
+    for grant in grants {
+        insert_grant(
+            &mut map,
+            (
+                grant.scope.clone(),
+                grant.scope_id.clone(),
+                grant.role_name.clone(),
+            ),
+            grant.effect,
+        )?;
+    }
+    Ok(map)
+}
+
+fn insert_grant(
+    map: &mut BTreeMap<GrantKey, RoleEffect>,
+    key: GrantKey,
+    effect: RoleEffect,
+) -> Result<(), String> {
+    if key.0.trim().is_empty() || key.1.trim().is_empty() || key.2.trim().is_empty() {
+        return Err("grants need a scope, scope id and role name".to_string());
+    }
+    validate_scope(&key.0).map_err(|msg| format!("{}: {}", key.0, msg))?;
+    match map.insert(key.clone(), effect) {
+        Some(other) if other != effect => Err(format!(
+            "{} is both allowed and denied in {}:{}",
+            key.2, key.0, key.1
+        )),
+        _ => Ok(()),
+    }
+}
+
+fn manifest_grant((scope, scope_id, role_name): GrantKey, effect: RoleEffect) -> ManifestGrant {
+    ManifestGrant {
+        scope,
+        scope_id,
+        role_name,
+        effect,
+    }
+}
+
+/// A difference between the manifest and the database, and the change that resolves it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Drift {
+    BundleCreated {
+        bundle: String,
+    },
+    /// The stored description or grants differed.
+    BundleUpdated {
+        bundle: String,
+    },
+    GroupCreated {
+        group_id: GroupId,
+    },
+    GroupRenamed {
+        group_id: GroupId,
+        from: String,
+        to: String,
+    },
+    UserCreated {
+        user_id: UserId,
+    },
+    GrantAdded {
+        #[serde(flatten)]
+        target: RoleAssignmentTarget,
+        #[serde(flatten)]
+        grant: ManifestGrant,
+    },
+    /// The grant existed with the other effect, or under a condition.
+    GrantChanged {
+        #[serde(flatten)]
+        target: RoleAssignmentTarget,
+        #[serde(flatten)]
+        grant: ManifestGrant,
+        from: RoleEffect,
+        conditional: bool,
+    },
+    /// A direct grant the manifest does not list.
+    GrantRevoked {
+        #[serde(flatten)]
+        target: RoleAssignmentTarget,
+        #[serde(flatten)]
+        grant: ManifestGrant,
+    },
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SyncReport {
+    /// Nothing was written; `drift` is what `apply` would change.
+    pub dry_run: bool,
+    pub drift: Vec<Drift>,
+}
+
+impl SyncReport {
+    pub fn in_sync(&self) -> bool {
+        self.drift.is_empty()
+    }
+}
+
+#[derive(Debug)]
+pub enum SyncError {
+    Manifest(ManifestError),
+    /// A principal references a bundle that is neither in the manifest nor stored.
+    UnknownBundle(String),
+    /// A principal does not exist and the manifest gives no email to create it with.
+    UnknownUser(UserId),
+    Database(sqlx::Error),
+}
+
+impl fmt::Display for SyncError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Manifest(err) => write!(f, "{}", err),
+            Self::UnknownBundle(name) => write!(f, "Unknown bundle: {}", name),
+            Self::UnknownUser(user_id) => {
+                write!(f, "User {} does not exist and has no email", user_id)
+            }
+            Self::Database(err) => write!(f, "Database error: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for SyncError {}
+
+impl From<ManifestError> for SyncError {
+    fn from(err: ManifestError) -> Self {
+        Self::Manifest(err)
+    }
+}
+
+impl From<sqlx::Error> for SyncError {
+    fn from(err: sqlx::Error) -> Self {
+        Self::Database(err)
+    }
+}
+
+/// Make the database match `desired` and report the drift that was corrected.
+///
+/// Principals are created without the app's `Hooks::before_user_create` and
+/// `Hooks::after_user_create`; an app that provisions per-user resources in them should do so for
+/// every `Drift::UserCreated` in the report.
+pub async fn apply(pool: &PgPool, desired: &RoleManifest) -> Result<SyncReport, SyncError> {
+    reconcile(pool, desired, false).await
+}
+
+/// Report what `apply` would change, without changing it.
+pub async fn plan(pool: &PgPool, desired: &RoleManifest) -> Result<SyncReport, SyncError> {
+    reconcile(pool, desired, true).await
+}
+
+async fn reconcile(
+    pool: &PgPool,
+    desired: &RoleManifest,
+    dry_run: bool,
+) -> Result<SyncReport, SyncError> {
+    desired.validate()?;
+    let mut tx = pool.begin().await?;
+    sqlx::query("SELECT pg_advisory_xact_lock(hashtext($1))")
+        .bind(MANIFEST_LOCK)
+        .execute(&mut *tx)
+        .await?;
+
+    let mut drift = Vec::new();
+    for bundle in &desired.bundles {
+        sync_bundle(&mut tx, bundle, &mut drift).await?;
+    }
+    for group in &desired.groups {
+        sync_group(&mut tx, group, &mut drift).await?;
+    }
+    for principal in &desired.principals {
+        sync_principal(&mut tx, principal, &mut drift).await?;
+    }
+
+    if dry_run {
+        tx.rollback().await?;
+    } else {
+        if !drift.is_empty() {
+            insert_audit_log(
+                &mut *tx,
+                None,
+                json!({
+                    "type": "role_manifest_applied",
+                    "drift": drift,
+                }),
+            )
+            .await?;
+        }
+        tx.commit().await?;
+        #[cfg(feature = "api")]
+        invalidate_role_snapshots(&drift);
+    }
+    if !drift.is_empty() {
+        tracing::info!(
+            "Role manifest drift: {} change(s){}",
+            drift.len(),
+            if dry_run { " (dry run)" } else { "" }
+        );
+    }
+    Ok(SyncReport { dry_run, drift })
+}
+
+/// Discard the role snapshots the committed `drift` made stale.
+#[cfg(feature = "api")]
+fn invalidate_role_snapshots(drift: &[Drift]) {
+    for change in drift {
+        let target = match change {
+            Drift::GrantAdded { target, .. }
+            | Drift::GrantChanged { target, .. }
+            | Drift::GrantRevoked { target, .. } => *target,
+            _ => continue,
+        };
+        match target {
+            RoleAssignmentTarget::User(user_id) => crate::guard::invalidate_role_snapshots(user_id),
+            RoleAssignmentTarget::Group(_) => {
+                crate::guard::invalidate_all_role_snapshots();
+                return;
+            }
+        }
+    }
+}
+
+async fn sync_bundle(
+    conn: &mut PgConnection,
+    bundle: &ManifestBundle,
+    drift: &mut Vec<Drift>,
+) -> Result<(), sqlx::Error> {
+    let mut grants = bundle.grants.clone();
+    grants.sort();
+    grants.dedup();
+    let stored: Option<Option<String>> =
+        sqlx::query_scalar("SELECT description FROM auth.role_bundles WHERE name = $1 FOR UPDATE")
+            .bind(&bundle.name)
+            .fetch_optional(&mut *conn)
+            .await?;
+    match stored {
+        None => drift.push(Drift::BundleCreated {
+            bundle: bundle.name.clone(),
+        }),
+        Some(description) => {
+            let stored_grants = sqlx::query_as::<_, BundleGrant>(
+                r#"
+                SELECT scope, role_name
+                FROM auth.role_bundle_grants
+                WHERE bundle_name = $1
+                ORDER BY scope ASC, role_name ASC
+                "#,
+            )
+            .bind(&bundle.name)
+            .fetch_all(&mut *conn)
+            .await?;
+            if description == bundle.description && stored_grants == grants {
+                return Ok(());
+            }
+            drift.push(Drift::BundleUpdated {
+                bundle: bundle.name.clone(),
+            });
+        }
+    }
+    write_bundle(
+        conn,
+        None,
+        &RoleBundle {
+            name: bundle.name.clone(),
+            description: bundle.description.clone(),
+            grants,
+        },
+    )
+    .await
+}
+
+async fn sync_group(
+    conn: &mut PgConnection,
+    group: &ManifestGroup,
+    drift: &mut Vec<Drift>,
+) -> Result<(), SyncError> {
+    let display_name: Option<String> =
+        sqlx::query_scalar("SELECT display_name FROM auth.groups WHERE id = $1 FOR UPDATE")
+            .bind(group.id)
+            .fetch_optional(&mut *conn)
+            .await?;
+    match display_name {
+        None => {
+            GroupRow::insert_in(conn, &GroupRow::new(group.id, None, &group.display_name)).await?;
+            drift.push(Drift::GroupCreated { group_id: group.id });
+        }
+        Some(display_name) if display_name != group.display_name => {
+            sqlx::query(
+                r#"
+                UPDATE auth.groups
+                SET display_name = $2,
+                    version = version + 1
+                WHERE id = $1
+                "#,
+            )
+            .bind(group.id)
+            .bind(&group.display_name)
+            .execute(&mut *conn)
+            .await?;
+            drift.push(Drift::GroupRenamed {
+                group_id: group.id,
+                from: display_name,
+                to: group.display_name.clone(),
+            });
+        }
+        Some(_) => {}
+    }
+    let desired = grant_map(&group.grants).map_err(ManifestError::Invalid)?;
+    sync_grants(conn, RoleAssignmentTarget::Group(group.id), desired, drift).await?;
+    Ok(())
+}
+
+async fn sync_principal(
+    conn: &mut PgConnection,
+    principal: &ManifestPrincipal,
+    drift: &mut Vec<Drift>,
+) -> Result<(), SyncError> {
+    let exists: bool = sqlx::query_scalar("SELECT EXISTS (SELECT 1 FROM auth.users WHERE id = $1)")
+        .bind(principal.user_id)
+        .fetch_one(&mut *conn)
+        .await?;
+    if !exists {
+        let Some(email) = &principal.email else {
+            return Err(SyncError::UnknownUser(principal.user_id));
+        };
+        UserRow::insert_in(
+            conn,
+            &UserRow::new(principal.user_id, None, email.clone(), None),
+        )
+        .await?;
+        drift.push(Drift::UserCreated {
+            user_id: principal.user_id,
+        });
+    }
+
+    let mut desired = grant_map(&principal.grants).map_err(ManifestError::Invalid)?;
+    for reference in &principal.bundles {
+        let exists: bool =
+            sqlx::query_scalar("SELECT EXISTS (SELECT 1 FROM auth.role_bundles WHERE name = $1)")
+                .bind(&reference.bundle)
+                .fetch_one(&mut *conn)
+                .await?;
+        if !exists {
+            return Err(SyncError::UnknownBundle(reference.bundle.clone()));
+        }
+        let grants = sqlx::query_as::<_, BundleGrant>(
+            r#"
+            SELECT scope, role_name
+            FROM auth.role_bundle_grants
+            WHERE bundle_name = $1
+            "#,
+        )
+        .bind(&reference.bundle)
+        .fetch_all(&mut *conn)
+        .await?;
+        for grant in grants {
+            insert_grant(
+                &mut desired,
+                (grant.scope, reference.scope_id.clone(), grant.role_name),
+                RoleEffect::Allow,
+            )
+            .map_err(|msg| {
+                ManifestError::Invalid(format!("Principal {}: {}", principal.user_id, msg))
+            })?;
+        }
+    }
+    sync_grants(
+        conn,
+        RoleAssignmentTarget::User(principal.user_id),
+        desired,
+        drift,
+    )
+    .await?;
+    Ok(())
+}
+
+#[derive(Debug, FromRow)]
+struct StoredGrant {
+    scope: String,
+    scope_id: String,
+    role_name: String,
+    effect: RoleEffect,
+    condition: Option<Value>,
+}
+
+/// Make the target's direct grants exactly `desired`, auditing each change.
+async fn sync_grants(
+    conn: &mut PgConnection,
+    target: RoleAssignmentTarget,
+    desired: BTreeMap<GrantKey, RoleEffect>,
+    drift: &mut Vec<Drift>,
+) -> Result<(), sqlx::Error> {
+    let stored = match target {
+        RoleAssignmentTarget::User(user_id) => {
+            sqlx::query_as::<_, StoredGrant>(
+                r#"
+                SELECT scope, scope_id, role_name, effect, condition
+                FROM auth.user_roles
+                WHERE user_id = $1
+                  AND source_group_id IS NULL
+                  AND source_provider IS NULL
+                FOR UPDATE
+                "#,
+            )
+            .bind(user_id)
+            .fetch_all(&mut *conn)
+            .await?
+        }
+        RoleAssignmentTarget::Group(group_id) => {
+            sqlx::query_as::<_, StoredGrant>(
+                r#"
+                SELECT scope, scope_id, role_name, effect, condition
+                FROM auth.group_roles
+                WHERE group_id = $1
+                FOR UPDATE
+                "#,
+            )
+            .bind(group_id)
+            .fetch_all(&mut *conn)
+            .await?
+        }
+    };
+    let mut stored: BTreeMap<GrantKey, (RoleEffect, bool)> = stored
+        .into_iter()
+        .map(|grant| {
+            (
+                (grant.scope, grant.scope_id, grant.role_name),
+                (grant.effect, grant.condition.is_some()),
+            )
+        })
+        .collect();
+
+    for (key, effect) in desired {
+        match stored.remove(&key) {
+            Some((from, false)) if from == effect => continue,
+            Some((from, conditional)) => drift.push(Drift::GrantChanged {
+                target,
+                grant: manifest_grant(key.clone(), effect),
+                from,
+                conditional,
+            }),
+            None => drift.push(Drift::GrantAdded {
+                target,
+                grant: manifest_grant(key.clone(), effect),
+            }),
+        }
+        let (scope, scope_id, role_name) = &key;
+        match target {
+            RoleAssignmentTarget::User(user_id) => {
+                sqlx::query(
+                    r#"
+                    INSERT INTO auth.user_roles (user_id, scope, scope_id, role_name, effect)
+                    VALUES ($1, $2, $3, $4, $5)
+                    ON CONFLICT (user_id, scope, scope_id, role_name) DO UPDATE
+                    SET effect = EXCLUDED.effect,
+                        condition = NULL,
+                        source_group_id = NULL,
+                        source_provider = NULL
+                    "#,
+                )
+                .bind(user_id)
+                .bind(scope)
+                .bind(scope_id)
+                .bind(role_name)
+                .bind(effect)
+                .execute(&mut *conn)
+                .await?;
+            }
+            RoleAssignmentTarget::Group(group_id) => {
+                sqlx::query(
+                    r#"
+                    INSERT INTO auth.group_roles (group_id, scope, scope_id, role_name, effect)
+                    VALUES ($1, $2, $3, $4, $5)
+                    ON CONFLICT (group_id, scope, scope_id, role_name) DO UPDATE
+                    SET effect = EXCLUDED.effect,
+                        condition = NULL
+                    "#,
+                )
+                .bind(group_id)
+                .bind(scope)
+                .bind(scope_id)
+                .bind(role_name)
+                .bind(effect)
+                .execute(&mut *conn)
+                .await?;
+            }
+        }
+        insert_role_audit_log(
+            conn,
+            None,
+            match effect {
+                RoleEffect::Allow => "role_grant",
+                RoleEffect::Deny => "role_deny",
+            },
+            target,
+            scope,
+            scope_id,
+            role_name,
+            None,
+        )
+        .await?;
+    }
+
+    for ((scope, scope_id, role_name), (effect, _)) in stored {
+        match target {
+            RoleAssignmentTarget::User(user_id) => {
+                sqlx::query(
+                    r#"
+                    DELETE FROM auth.user_roles
+                    WHERE user_id = $1
+                      AND scope = $2
+                      AND scope_id = $3
+                      AND role_name = $4
+                    "#,
+                )
+                .bind(user_id)
+                .bind(&scope)
+                .bind(&scope_id)
+                .bind(&role_name)
+                .execute(&mut *conn)
+                .await?;
+            }
+            RoleAssignmentTarget::Group(group_id) => {
+                sqlx::query(
+                    r#"
+                    DELETE FROM auth.group_roles
+                    WHERE group_id = $1
+                      AND scope = $2
+                      AND scope_id = $3
+                      AND role_name = $4
+                    "#,
+                )
+                .bind(group_id)
+                .bind(&scope)
+                .bind(&scope_id)
+                .bind(&role_name)
+                .execute(&mut *conn)
+                .await?;
+            }
+        }
+        insert_role_audit_log(
+            conn,
+            None,
+            "role_revoke",
+            target,
+            &scope,
+            &scope_id,
+            &role_name,
+            None,
+        )
+        .await?;
+        drift.push(Drift::GrantRevoked {
+            target,
+            grant: manifest_grant((scope, scope_id, role_name), effect),
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use uuid::Uuid;
+
+    use super::*;
+
+    const TOML: &str = r#"
+        [[bundles]]
+        name = "engineer"
+        grants = [{ scope = "project", role_name = "writer" }]
+
+        [[principals]]
+        user_id = "0190c4a1-7b2e-7c3d-8e4f-000000000001"
+        email = "root@example.com"
+        grants = [
+            { scope = "global", scope_id = "global", role_name = "super_admin" },
+            { scope = "project", scope_id = "legacy", role_name = "writer", effect = "deny" },
+        ]
+        bundles = [{ bundle = "engineer", scope_id = "infra" }]
+    "#;
+
+    const YAML: &str = r#"
+bundles:
+  - name: engineer
+    grants:
+      - { scope: project, role_name: writer }
+principals:
+  - user_id: 0190c4a1-7b2e-7c3d-8e4f-000000000001
+    email: root@example.com
+    grants:
+      - { scope: global, scope_id: global, role_name: super_admin }
+      - { scope: project, scope_id: legacy, role_name: writer, effect: deny }
+    bundles:
+      - { bundle: engineer, scope_id: infra }
+"#;
+
+    #[test]
+    fn toml_and_yaml_manifests_agree_and_validate() {
+        let manifest = RoleManifest::parse(TOML, ManifestFormat::Toml).unwrap();
+        assert_eq!(
+            RoleManifest::parse(YAML, ManifestFormat::Yaml).unwrap(),
+            manifest
+        );
+        assert_eq!(manifest.principals[0].grants[1].effect, RoleEffect::Deny);
+        assert!(manifest.validate().is_ok());
+        assert_eq!(
+            ManifestFormat::from_path(Path::new("auth/roles.yml")),
+            Some(ManifestFormat::Yaml)
+        );
+        assert!(matches!(
+            RoleManifest::parse("[[principals]]\nuser = 1", ManifestFormat::Toml),
+            Err(ManifestError::Parse(_))
+        ));
+
+        let mut conflicting = manifest.clone();
+        let mut grant = conflicting.principals[0].grants[1].clone();
+        grant.effect = RoleEffect::Allow;
+        conflicting.principals[0].grants.push(grant);
+        assert!(matches!(
+            conflicting.validate(),
+            Err(ManifestError::Invalid(msg)) if msg.contains("both allowed and denied")
+        ));
+        let mut pinned = manifest.clone();
+        pinned.principals[0].grants[0].scope = "global@prod".to_string();
+        assert!(pinned.validate().is_err());
+        let mut duplicated = manifest;
+        duplicated.principals.push(duplicated.principals[0].clone());
+        assert!(duplicated.validate().is_err());
+    }
+
+    #[test]
+    fn grant_drift_serializes_like_audit_entries() {
+        let group_id = GroupId(Uuid::nil());
+        let drift = Drift::GrantAdded {
+            target: RoleAssignmentTarget::Group(group_id),
+            grant: manifest_grant(
+                ("project".into(), "infra".into(), "admin".into()),
+                RoleEffect::Allow,
+            ),
+        };
+        assert_eq!(
+            serde_json::to_value(&drift).unwrap(),
+            json!({
+                "kind": "grant_added",
+                "target_type": "group",
+                "target_id": group_id,
+                "scope": "project",
+                "scope_id": "infra",
+                "role_name": "admin",
+                "effect": "allow",
+            })
+        );
+    }
+}
@@ -0,0 +1,415 @@
+//! Identity from headers set by an authenticating reverse proxy, e.g. oauth2-proxy or an API
+//! gateway, for deployments where the proxy owns the login UI.
+//!
+//! Headers are only believed from a trusted proxy: one that presents a shared secret, connects
+//! from an allowed address, or both when both are configured. The user is looked up by email and
+//! created on first sight under `AuthApp::provisioning_policy`, then handed on as an ordinary
+//! `AuthenticatedUser`, so the crate's routes and extractors work unchanged. Deleted users and
+//! users pending approval are refused as `api::self_handler` refuses them.
+
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use axum::extract::{ConnectInfo, Request};
+use axum::http::HeaderMap;
+use axum::http::header::HeaderName;
+use axum::http::request::Parts;
+use axum::response::{IntoResponse, Response};
+use email_address::EmailAddress;
+use futures_util::future::BoxFuture;
+use once_cell::sync::Lazy;
+use tower::{Layer, Service};
+
+use crate::api::{
+    AuthApp, User, accepted_username, deleted_account_rejection, pending_approval_rejection,
+};
+use crate::clients::{secret_hash, secret_matches};
+use crate::db::UserRow;
+use crate::hooks::NewUser;
+use crate::identity::LocalIssuer;
+use crate::ids::new_uuid;
+use crate::policy::{in_network, parse_cidr};
+use crate::prelude::{AuthenticatedUser, RejectReason, UserId};
+use crate::provisioning::{ProvisioningMode, provision_user};
+
+/// Email header oauth2-proxy sets with `--set-xauthrequest`.
+pub const DEFAULT_EMAIL_HEADER: &str = "x-auth-request-email";
+
+/// Username header oauth2-proxy sets with `--set-xauthrequest`.
+pub const DEFAULT_USERNAME_HEADER: &str = "x-auth-request-preferred-username";
+
+/// `iss` of the ID tokens behind proxied users' `AuthenticatedUser::authorization`. They are
+/// signed with a key that lives only as long as the process and never leave it.
+pub const TRUSTED_PROXY_ISSUER: &str = "urn:subseq-auth:trusted-proxy";
+
+static ISSUER: Lazy<LocalIssuer> = Lazy::new(|| LocalIssuer::new(TRUSTED_PROXY_ISSUER));
+
+/// Which headers carry the identity and how the proxy proves it set them.
+#[derive(Clone)]
+pub struct TrustedProxyConfig {
+    email_header: HeaderName,
+    username_header: Option<HeaderName>,
+    /// Header and SHA-256 of the secret it must carry.
+    secret: Option<(HeaderName, String)>,
+    allowed_peers: Vec<(IpAddr, u32)>,
+}
+
+impl fmt::Debug for TrustedProxyConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TrustedProxyConfig")
+            .field("email_header", &self.email_header)
+            .field("username_header", &self.username_header)
+            .field(
+                "secret_header",
+                &self.secret.as_ref().map(|(header, _)| header),
+            )
+            .field("allowed_peers", &self.allowed_peers)
+            .finish()
+    }
+}
+
+impl Default for TrustedProxyConfig {
+    fn default() -> Self {
+        Self {
+            email_header: HeaderName::from_static(DEFAULT_EMAIL_HEADER),
+            username_header: Some(HeaderName::from_static(DEFAULT_USERNAME_HEADER)),
+            secret: None,
+            allowed_peers: Vec::new(),
+        }
+    }
+}
+
+impl TrustedProxyConfig {
+    /// The oauth2-proxy headers, trusting no one until `with_shared_secret` or
+    /// `with_allowed_peers` is set.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_email_header(mut self, header: HeaderName) -> Self {
+        self.email_header = header;
+        self
+    }
+
+    /// `None` leaves new users without a username.
+    pub fn with_username_header(mut self, header: Option<HeaderName>) -> Self {
+        self.username_header = header;
+        self
+    }
+
+    /// Require `header` to carry `secret`, e.g. a value the gateway adds to every upstream
+    /// request.
+    pub fn with_shared_secret(mut self, header: HeaderName, secret: &str) -> Self {
+        self.secret = Some((header, secret_hash(secret)));
+        self
+    }
+
+    /// Require the connection to come from one of `cidrs`, e.g. `10.0.0.0/8` or a single
+    /// address. The router must be served with `into_make_service_with_connect_info`.
+    pub fn with_allowed_peers<I, S>(mut self, cidrs: I) -> Result<Self, String>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        for cidr in cidrs {
+            let cidr = cidr.as_ref();
+            let network = parse_cidr(cidr).ok_or_else(|| format!("Invalid CIDR: {}", cidr))?;
+            self.allowed_peers.push(network);
+        }
+        Ok(self)
+    }
+
+    /// Whether the request came through the proxy. Nothing is trusted until a secret or peer
+    /// list is configured.
+    pub fn is_trusted(&self, parts: &Parts) -> bool {
+        if self.secret.is_none() && self.allowed_peers.is_empty() {
+            return false;
+        }
+        if let Some((header, hash)) = &self.secret {
+            let presented = parts
+                .headers
+                .get(header)
+                .and_then(|value| value.to_str().ok());
+            if !presented.is_some_and(|secret| secret_matches(secret, hash)) {
+                return false;
+            }
+        }
+        if !self.allowed_peers.is_empty() {
+            let Some(ConnectInfo(peer)) = parts.extensions.get::<ConnectInfo<SocketAddr>>() else {
+                return false;
+            };
+            if !self
+                .allowed_peers
+                .iter()
+                .any(|(network, prefix)| in_network(*network, *prefix, peer.ip()))
+            {
+                return false;
+            }
+        }
+        true
+    }
+
+    fn header<'a>(headers: &'a HeaderMap, name: &HeaderName) -> Option<&'a str> {
+        headers
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .map(str::trim)
+            .filter(|value| !value.is_empty())
+    }
+}
+
+/// Authenticate requests from a trusted proxy by its identity headers, in place of
+/// `auth::AuthLayer` or behind it; requests it already authenticated are left alone. Requests
+/// from anyone else, or without the email header, pass through unauthenticated.
+///
+/// ```ignore
+/// let config = TrustedProxyConfig::new().with_allowed_peers(["10.0.0.0/8"])?;
+/// let app = Router::new()
+///     .merge(subseq_auth::api::routes(store))
+///     .layer(TrustedProxyLayer::new(state.clone(), config))
+///     .with_state(state);
+/// ```
+#[derive(Clone)]
+pub struct TrustedProxyLayer<S> {
+    app: S,
+    config: Arc<TrustedProxyConfig>,
+}
+
+impl<S> TrustedProxyLayer<S>
+where
+    S: AuthApp,
+{
+    pub fn new(app: S, config: TrustedProxyConfig) -> Self {
+        Self {
+            app,
+            config: Arc::new(config),
+        }
+    }
+}
+
+impl<S, Inner> Layer<Inner> for TrustedProxyLayer<S>
+where
+    S: Clone,
+{
+    type Service = TrustedProxy<S, Inner>;
+
+    fn layer(&self, inner: Inner) -> Self::Service {
+        TrustedProxy {
+            app: self.app.clone(),
+            config: self.config.clone(),
+            inner,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct TrustedProxy<S, Inner> {
+    app: S,
+    config: Arc<TrustedProxyConfig>,
+    inner: Inner,
+}
+
+impl<S, Inner, B> Service<Request<B>> for TrustedProxy<S, Inner>
+where
+    S: AuthApp + Clone + Send + Sync + 'static,
+    Inner: Service<Request<B>, Response = Response> + Clone + Send + 'static,
+    Inner::Future: Send + 'static,
+    B: Send + 'static,
+{
+    type Response = Response;
+    type Error = Inner::Error;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<B>) -> Self::Future {
+        let app = self.app.clone();
+        let config = self.config.clone();
+        let clone = self.inner.clone();
+        // https://docs.rs/tower/latest/tower/trait.Service.html#be-careful-when-cloning-inner-services
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        Box::pin(async move {
+            let (mut parts, body) = req.into_parts();
+            if parts.extensions.get::<AuthenticatedUser>().is_none() {
+                if config.is_trusted(&parts) {
+                    match proxy_user(&app, &config, &parts.headers).await {
+                        Ok(Some(auth_user)) => {
+                            parts.extensions.insert(auth_user);
+                        }
+                        Ok(None) => {}
+                        Err(reject) => return Ok(reject.into_response()),
+                    }
+                } else if parts.headers.contains_key(&config.email_header) {
+                    tracing::warn!(
+                        "Ignoring {} header from an untrusted peer",
+                        config.email_header
+                    );
+                }
+            }
+            inner.call(Request::from_parts(parts, body)).await
+        })
+    }
+}
+
+/// The user named by the proxy's headers, created if this is their first request.
+async fn proxy_user<S>(
+    app: &S,
+    config: &TrustedProxyConfig,
+    headers: &HeaderMap,
+) -> Result<Option<AuthenticatedUser>, RejectReason>
+where
+    S: AuthApp,
+{
+    let Some(email) = TrustedProxyConfig::header(headers, &config.email_header) else {
+        return Ok(None);
+    };
+    if !EmailAddress::is_valid(email) {
+        return Err(RejectReason::bad_request(
+            "Invalid email from trusted proxy",
+        ));
+    }
+    let username = config
+        .username_header
+        .as_ref()
+        .and_then(|header| TrustedProxyConfig::header(headers, header));
+    let pool = app.pool();
+    let existing =
+        UserRow::get_by_email_normalized_including_deleted(&pool, email, app.email_normalizer())
+            .await
+            .map_err(|_| RejectReason::database("Failed to reach database"))?;
+    let user_id = match existing {
+        Some(user) => user.id,
+        None => provision_proxy_user(app, email, username).await?,
+    };
+    if UserRow::is_deleted(&pool, user_id)
+        .await
+        .map_err(|_| RejectReason::database("Failed to reach database"))?
+    {
+        return Err(deleted_account_rejection());
+    }
+    if UserRow::is_pending_approval(&pool, user_id)
+        .await
+        .map_err(|_| RejectReason::database("Failed to reach database"))?
+    {
+        return Err(pending_approval_rejection());
+    }
+    proxy_identity(user_id, email, username)
+        .await
+        .map(Some)
+        .map_err(RejectReason::anyhow)
+}
+
+/// Create the user under `AuthApp::provisioning_policy`, as `api::self_handler` does for users
+/// first seen from the identity provider.
+async fn provision_proxy_user<S>(
+    app: &S,
+    email: &str,
+    username: Option<&str>,
+) -> Result<UserId, RejectReason>
+where
+    S: AuthApp,
+{
+    let policy = app.provisioning_policy();
+    if policy.mode() == ProvisioningMode::Disabled {
+        return Err(RejectReason::forbidden_detailed(
+            "provisioning_disabled",
+            "Accounts are not created on sign-in",
+            None,
+        ));
+    }
+    if let Err(err) = policy.check_email(email) {
+        tracing::info!("Not provisioning proxied user: {}", err);
+        return Err(RejectReason::email_rejected(&err));
+    }
+    if let Err(err) = app.email_domain_policy().validate(email).await {
+        tracing::info!("Rejecting proxied user: {}", err);
+        return Err(RejectReason::email_rejected(&err));
+    }
+
+    let pool = app.pool();
+    let user_id = UserId(new_uuid());
+    let username = accepted_username(app, &pool, user_id, username.map(str::to_string)).await?;
+    app.hooks()
+        .before_user_create(&NewUser {
+            id: user_id,
+            email,
+            username: username.as_deref(),
+            invited: false,
+        })
+        .await?;
+    let defaults = UserRow::new(user_id, username, email.to_string(), None)
+        .with_external_id(app.external_id_generator());
+    // Concurrent first requests resolve to the same row rather than racing on the insert.
+    let (user, created) =
+        UserRow::get_or_create_by_email_normalized(&pool, email, defaults, app.email_normalizer())
+            .await
+            .map_err(|_| RejectReason::database("Failed to reach database"))?;
+    if created {
+        provision_user(&pool, user.id, policy)
+            .await
+            .map_err(|_| RejectReason::database("Failed to reach database"))?;
+        tracing::info!("Provisioned user {} from trusted proxy", user.id);
+        let created = User::from(user.clone());
+        app.announce_new_user(&created);
+        app.hooks().after_user_create(&created).await;
+    }
+    Ok(user.id)
+}
+
+/// An `AuthenticatedUser` whose claims carry what the proxy vouched for.
+async fn proxy_identity(
+    user_id: UserId,
+    email: &str,
+    username: Option<&str>,
+) -> anyhow::Result<AuthenticatedUser> {
+    let (token, claims) = ISSUER.mint(user_id, email, username)?;
+    AuthenticatedUser::from_claims(token, claims).await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use axum::extract::ConnectInfo;
+    use axum::http::Request;
+    use axum::http::header::HeaderName;
+
+    use super::{TrustedProxyConfig, proxy_identity};
+    use crate::prelude::UserId;
+
+    #[tokio::test]
+    async fn trusts_configured_proxies_only() {
+        let secret = HeaderName::from_static("x-proxy-secret");
+        let config = TrustedProxyConfig::new()
+            .with_shared_secret(secret.clone(), "s3cret")
+            .with_allowed_peers(["10.0.0.0/8"])
+            .unwrap();
+        let parts = |secret_value: &str, peer: &str| {
+            let (mut parts, _) = Request::builder()
+                .header(&secret, secret_value)
+                .body(())
+                .unwrap()
+                .into_parts();
+            parts
+                .extensions
+                .insert(ConnectInfo(peer.parse::<SocketAddr>().unwrap()));
+            parts
+        };
+        assert!(config.is_trusted(&parts("s3cret", "10.1.2.3:443")));
+        assert!(!config.is_trusted(&parts("wrong", "10.1.2.3:443")));
+        assert!(!config.is_trusted(&parts("s3cret", "192.168.0.1:443")));
+        assert!(!TrustedProxyConfig::new().is_trusted(&parts("s3cret", "10.1.2.3:443")));
+
+        let user_id = UserId(uuid::Uuid::new_v4());
+        let auth_user = proxy_identity(user_id, "alice@example.com", Some("alice"))
+            .await
+            .unwrap();
+        assert_eq!(auth_user.id(), user_id);
+        assert_eq!(auth_user.email().as_deref(), Some("alice@example.com"));
+        assert_eq!(auth_user.username().as_deref(), Some("alice"));
+    }
+}
@@ -0,0 +1,183 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use sqlx::postgres::PgListener;
+use tokio::sync::broadcast;
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+
+use crate::db::GroupMembershipRow;
+use crate::group_id::GroupId;
+use crate::user_id::UserId;
+
+/// Channel the `auth.notify_user_update` triggers publish on.
+pub const USER_UPDATES_CHANNEL: &str = "auth_user_updates";
+
+/// Updates buffered per subscriber. A subscriber that falls further behind gets `Resync`.
+const SUBSCRIBER_CAPACITY: usize = 256;
+
+/// Wait before listening again after the listener connection fails.
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// What changed, i.e. which endpoint to fetch again.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UpdateKind {
+    /// `/auth/me/permissions` and `/auth/roles`.
+    Roles,
+    /// `/auth/me/groups`.
+    Groups,
+    /// `/auth/me`.
+    Profile,
+    /// Updates may have been missed, e.g. while the listener reconnected; fetch everything.
+    Resync,
+}
+
+impl UpdateKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            UpdateKind::Roles => "roles",
+            UpdateKind::Groups => "groups",
+            UpdateKind::Profile => "profile",
+            UpdateKind::Resync => "resync",
+        }
+    }
+}
+
+/// A change to one user, or with `group_id`, to every member of a group. `Resync` has neither.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UserUpdate {
+    pub kind: UpdateKind,
+    #[serde(default)]
+    pub user_id: Option<UserId>,
+    #[serde(default)]
+    pub group_id: Option<GroupId>,
+}
+
+impl UserUpdate {
+    pub fn resync() -> Self {
+        Self {
+            kind: UpdateKind::Resync,
+            user_id: None,
+            group_id: None,
+        }
+    }
+
+    /// Whether `user_id` should hear about this update. Group updates check membership, so a
+    /// user who just left the group still hears about the membership change itself, which
+    /// carries their `user_id`.
+    pub async fn concerns(&self, pool: &PgPool, user_id: UserId) -> Result<bool, sqlx::Error> {
+        match (self.user_id, self.group_id) {
+            (Some(target), _) => Ok(target == user_id),
+            (None, Some(group_id)) => GroupMembershipRow::is_member(pool, group_id, user_id).await,
+            (None, None) => Ok(true),
+        }
+    }
+}
+
+/// Cloneable handle to a background task that listens on `USER_UPDATES_CHANNEL` and fans the
+/// updates out to subscribers, e.g. `GET /auth/me/updates` streams. Run one per process; each
+/// holds one database connection for the listener.
+///
+/// ```ignore
+/// let (updates, task) = UserUpdates::spawn(pool.clone());
+/// // Return `Some(&self.updates)` from `AuthApp::user_updates`.
+/// ```
+#[derive(Clone)]
+pub struct UserUpdates {
+    tx: broadcast::Sender<UserUpdate>,
+}
+
+impl UserUpdates {
+    pub fn spawn(pool: Arc<PgPool>) -> (Self, JoinHandle<()>) {
+        Self::spawn_with_cancellation(pool, CancellationToken::new())
+    }
+
+    /// Like `spawn`, but the listener stops once `cancel` is cancelled.
+    pub fn spawn_with_cancellation(
+        pool: Arc<PgPool>,
+        cancel: CancellationToken,
+    ) -> (Self, JoinHandle<()>) {
+        let updates = Self::detached();
+        let handle = tokio::spawn(run_listener(pool, updates.tx.clone(), cancel));
+        (updates, handle)
+    }
+
+    /// A handle without a listener; only `publish` reaches subscribers. For tests and apps that
+    /// learn about changes some other way.
+    pub fn detached() -> Self {
+        let (tx, _) = broadcast::channel(SUBSCRIBER_CAPACITY);
+        Self { tx }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<UserUpdate> {
+        self.tx.subscribe()
+    }
+
+    /// Deliver `update` to this process's subscribers only.
+    pub fn publish(&self, update: UserUpdate) {
+        // No subscribers is not an error.
+        let _ = self.tx.send(update);
+    }
+}
+
+async fn run_listener(
+    pool: Arc<PgPool>,
+    tx: broadcast::Sender<UserUpdate>,
+    cancel: CancellationToken,
+) {
+    loop {
+        let listened = tokio::select! {
+            _ = cancel.cancelled() => return,
+            listened = listen(&pool, &tx) => listened,
+        };
+        if let Err(err) = listened {
+            tracing::error!("Listening for user updates failed: {}", err);
+        }
+        // Anything published while nobody listened is lost.
+        let _ = tx.send(UserUpdate::resync());
+        tokio::select! {
+            _ = cancel.cancelled() => return,
+            _ = tokio::time::sleep(RECONNECT_DELAY) => {}
+        }
+    }
+}
+
+async fn listen(pool: &PgPool, tx: &broadcast::Sender<UserUpdate>) -> Result<(), sqlx::Error> {
+    let mut listener = PgListener::connect_with(pool).await?;
+    listener.listen(USER_UPDATES_CHANNEL).await?;
+    tracing::info!("Listening for user updates on {}", USER_UPDATES_CHANNEL);
+    loop {
+        // `None` means the connection was lost; the next call reconnects and listens again.
+        let Some(notification) = listener.try_recv().await? else {
+            let _ = tx.send(UserUpdate::resync());
+            continue;
+        };
+        match serde_json::from_str::<UserUpdate>(notification.payload()) {
+            Ok(update) => {
+                let _ = tx.send(update);
+            }
+            Err(err) => {
+                tracing::warn!("Ignoring user update {:?}: {}", notification.payload(), err)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{UpdateKind, UserUpdate};
+
+    #[test]
+    fn parses_trigger_payloads() {
+        let user_id = uuid::Uuid::new_v4();
+        let update: UserUpdate =
+            serde_json::from_str(&format!(r#"{{"kind": "roles", "user_id": "{user_id}"}}"#))
+                .unwrap();
+        assert_eq!(update.kind, UpdateKind::Roles);
+        assert_eq!(update.user_id.map(|id| id.0), Some(user_id));
+        assert_eq!(update.group_id, None);
+    }
+}
@@ -1,12 +1,29 @@
 use std::fmt;
 use std::str;
 
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
+use crate::ids::{new_uuid, uuid_timestamp};
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize, Default)]
+#[cfg_attr(feature = "sqlx", derive(sqlx::Type), sqlx(transparent))]
 pub struct UserId(pub Uuid);
 
+impl UserId {
+    /// A new id of the process-wide `ids::uuid_version`.
+    pub fn new() -> Self {
+        UserId(new_uuid())
+    }
+
+    /// When the id was generated, if it is time-based (UUIDv7). For debugging; the user row's
+    /// `created_at` is authoritative.
+    pub fn created_at(&self) -> Option<DateTime<Utc>> {
+        uuid_timestamp(self.0)
+    }
+}
+
 impl fmt::Display for UserId {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "{}", self.0)
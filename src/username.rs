@@ -0,0 +1,251 @@
+use std::collections::HashSet;
+use std::fmt;
+
+use once_cell::sync::Lazy;
+use unicode_normalization::UnicodeNormalization;
+
+/// Look-alike characters folded to Latin when comparing usernames, paired index-by-index with
+/// `CONFUSABLE_TO`.
+///
+/// These must match the `translate()` tables in `auth.username_canonical`.
+pub const CONFUSABLE_FROM: &str = "авекмнорстухіјѕԁԛԝһӏαβεικνορτυχϲ01";
+pub const CONFUSABLE_TO: &str = "abekmhopctyxijsdqwhlabeikvoptuxcol";
+
+pub const DEFAULT_RESERVED_USERNAMES: &[&str] = &[
+    "admin",
+    "administrator",
+    "api",
+    "auth",
+    "help",
+    "me",
+    "moderator",
+    "null",
+    "official",
+    "root",
+    "security",
+    "staff",
+    "super_admin",
+    "support",
+    "system",
+    "undefined",
+];
+
+pub static DEFAULT_USERNAME_POLICY: Lazy<UsernamePolicy> = Lazy::new(UsernamePolicy::default);
+
+fn fold_confusable(c: char) -> char {
+    CONFUSABLE_FROM
+        .chars()
+        .position(|from| from == c)
+        .and_then(|index| CONFUSABLE_TO.chars().nth(index))
+        .unwrap_or(c)
+}
+
+/// Canonical form used to compare usernames: NFKC, lowercased, with look-alikes folded.
+///
+/// Mirrors `auth.username_canonical` so the result can be compared against the
+/// `username_canonical` column.
+pub fn canonical_username(username: &str) -> String {
+    username
+        .nfkc()
+        .collect::<String>()
+        .to_lowercase()
+        .chars()
+        .map(fold_confusable)
+        .collect()
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UsernamePolicyError {
+    Empty,
+    TooShort { min: usize },
+    TooLong { max: usize },
+    InvalidCharacter(char),
+    Reserved,
+}
+
+impl fmt::Display for UsernamePolicyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Empty => write!(f, "Username is required"),
+            Self::TooShort { min } => write!(f, "Username must be at least {} characters", min),
+            Self::TooLong { max } => write!(f, "Username must be at most {} characters", max),
+            Self::InvalidCharacter(c) => write!(f, "Username contains invalid character {:?}", c),
+            Self::Reserved => write!(f, "Username is reserved"),
+        }
+    }
+}
+
+impl std::error::Error for UsernamePolicyError {}
+
+/// Rules applied to usernames before a user record is created.
+///
+/// Uniqueness is enforced separately by the unique index on `auth.users.username_canonical`.
+#[derive(Debug, Clone)]
+pub struct UsernamePolicy {
+    reserved: HashSet<String>,
+    min_length: usize,
+    max_length: usize,
+    allowed_symbols: Vec<char>,
+}
+
+impl Default for UsernamePolicy {
+    fn default() -> Self {
+        Self {
+            reserved: DEFAULT_RESERVED_USERNAMES
+                .iter()
+                .map(|name| canonical_username(name))
+                .collect(),
+            min_length: 3,
+            max_length: 64,
+            // Identity providers commonly fall back to the email address as the username.
+            allowed_symbols: vec!['.', '_', '-', '+', '@'],
+        }
+    }
+}
+
+impl UsernamePolicy {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add names to the reserved list. Matching is done on the canonical form.
+    pub fn with_reserved<I, S>(mut self, names: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        self.reserved.extend(
+            names
+                .into_iter()
+                .map(|name| canonical_username(name.as_ref())),
+        );
+        self
+    }
+
+    pub fn with_length(mut self, min_length: usize, max_length: usize) -> Self {
+        self.min_length = min_length;
+        self.max_length = max_length;
+        self
+    }
+
+    /// Replace the non-alphanumeric characters usernames may contain.
+    pub fn with_allowed_symbols(mut self, symbols: &str) -> Self {
+        self.allowed_symbols = symbols.chars().collect();
+        self
+    }
+
+    /// Trim and NFKC-normalize a username. This is the form that gets stored.
+    pub fn normalize(&self, username: &str) -> String {
+        username.trim().nfkc().collect()
+    }
+
+    /// Whether the username, or the local part of an email-style username, is reserved.
+    pub fn is_reserved(&self, username: &str) -> bool {
+        let canonical = canonical_username(username);
+        if self.reserved.contains(&canonical) {
+            return true;
+        }
+        match canonical.split_once('@') {
+            Some((local, _)) => self.reserved.contains(local),
+            None => false,
+        }
+    }
+
+    /// Validate a username, returning its normalized form.
+    pub fn validate(&self, username: &str) -> Result<String, UsernamePolicyError> {
+        let normalized = self.normalize(username);
+        if normalized.is_empty() {
+            return Err(UsernamePolicyError::Empty);
+        }
+
+        let length = normalized.chars().count();
+        if length < self.min_length {
+            return Err(UsernamePolicyError::TooShort {
+                min: self.min_length,
+            });
+        }
+        if length > self.max_length {
+            return Err(UsernamePolicyError::TooLong {
+                max: self.max_length,
+            });
+        }
+
+        if let Some(invalid) = normalized
+            .chars()
+            .find(|c| !c.is_alphanumeric() && !self.allowed_symbols.contains(c))
+        {
+            return Err(UsernamePolicyError::InvalidCharacter(invalid));
+        }
+
+        if self.is_reserved(&normalized) {
+            return Err(UsernamePolicyError::Reserved);
+        }
+
+        Ok(normalized)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        CONFUSABLE_FROM, CONFUSABLE_TO, UsernamePolicy, UsernamePolicyError, canonical_username,
+    };
+
+    #[test]
+    fn confusable_tables_are_paired() {
+        assert_eq!(
+            CONFUSABLE_FROM.chars().count(),
+            CONFUSABLE_TO.chars().count()
+        );
+    }
+
+    #[test]
+    fn canonical_username_folds_case_width_and_lookalikes() {
+        assert_eq!(canonical_username("Alice"), "alice");
+        assert_eq!(canonical_username("ａｌｉｃｅ"), "alice");
+        // Cyrillic "а" and "о"
+        assert_eq!(canonical_username("\u{0430}dmin"), "admin");
+        assert_eq!(canonical_username("b\u{043e}b"), "bob");
+        assert_eq!(canonical_username("B0B1"), "bobl");
+    }
+
+    #[test]
+    fn validate_rejects_reserved_names_and_lookalikes() {
+        let policy = UsernamePolicy::default();
+        assert_eq!(policy.validate("admin"), Err(UsernamePolicyError::Reserved));
+        assert_eq!(policy.validate("ADMIN"), Err(UsernamePolicyError::Reserved));
+        assert_eq!(
+            policy.validate("\u{0430}dmin"),
+            Err(UsernamePolicyError::Reserved)
+        );
+        assert_eq!(
+            policy.validate("root@example.com"),
+            Err(UsernamePolicyError::Reserved)
+        );
+
+        let policy = UsernamePolicy::default().with_reserved(["billing"]);
+        assert_eq!(
+            policy.validate("Billing"),
+            Err(UsernamePolicyError::Reserved)
+        );
+    }
+
+    #[test]
+    fn validate_normalizes_and_checks_shape() {
+        let policy = UsernamePolicy::default();
+        assert_eq!(policy.validate("  ａｌｉｃｅ "), Ok("alice".to_string()));
+        assert_eq!(
+            policy.validate("alice@example.com"),
+            Ok("alice@example.com".to_string())
+        );
+        assert_eq!(policy.validate(""), Err(UsernamePolicyError::Empty));
+        assert_eq!(
+            policy.validate("al"),
+            Err(UsernamePolicyError::TooShort { min: 3 })
+        );
+        assert_eq!(
+            policy.validate("al ice"),
+            Err(UsernamePolicyError::InvalidCharacter(' '))
+        );
+    }
+}
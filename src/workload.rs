@@ -58,12 +58,12 @@ fn parse_bearer_token(authorization: &str) -> Result<&str, ClaimsVerificationErr
         ));
     }
 
-    if let Some(scheme) = scheme {
-        if !scheme.eq_ignore_ascii_case("Bearer") {
-            return Err(ClaimsVerificationError::Other(
-                "Invalid authorization scheme".to_string(),
-            ));
-        }
+    if let Some(scheme) = scheme
+        && !scheme.eq_ignore_ascii_case("Bearer")
+    {
+        return Err(ClaimsVerificationError::Other(
+            "Invalid authorization scheme".to_string(),
+        ));
     }
 
     Ok(token)
@@ -240,10 +240,9 @@ impl WorkloadJwtValidator {
             .lock()
             .map_err(|_| ClaimsVerificationError::Other("JWKS cache poisoned".to_string()))?
             .get(issuer)
+            && cached.fetched_at.elapsed() < self.cache_ttl
         {
-            if cached.fetched_at.elapsed() < self.cache_ttl {
-                return Ok(cached.jwks.clone());
-            }
+            return Ok(cached.jwks.clone());
         }
 
         self.refresh_jwks(issuer).await
@@ -322,12 +321,11 @@ impl WorkloadJwtValidator {
             .and_then(|v| v.as_str())
             .map(|v| v.trim())
             .filter(|v| !v.is_empty())
+            && token_use != "access"
         {
-            if token_use != "access" {
-                return Err(ClaimsVerificationError::Other(
-                    "Invalid token_use claim".to_string(),
-                ));
-            }
+            return Err(ClaimsVerificationError::Other(
+                "Invalid token_use claim".to_string(),
+            ));
         }
 
         let client_id = resolve_client_id(&data.claims).ok_or_else(|| {
@@ -0,0 +1,127 @@
+#![cfg(feature = "sqlx")]
+
+mod common;
+
+use std::sync::Arc;
+
+use chrono::{Duration, NaiveDate};
+use serde_json::json;
+use subseq_auth::audit::{AuditWriter, AuditWriterConfig};
+use subseq_auth::db::{LogRow, create_log_partition};
+
+use common::{TestDb, user};
+
+#[tokio::test]
+async fn user_events_are_filtered_by_time_range() {
+    let Some(db) = TestDb::create().await else {
+        return;
+    };
+    let pool = &db.pool;
+    let user_id = user(pool, "ada@example.com").await;
+    let march = NaiveDate::from_ymd_opt(2026, 3, 10)
+        .unwrap()
+        .and_hms_opt(12, 0, 0)
+        .unwrap();
+    create_log_partition(pool, march).await.unwrap();
+    for (offset, request_id) in [(0, "march"), (40, "april")] {
+        let mut row = LogRow::new(user_id, json!({"type": "login"}));
+        row.timestamp = march + Duration::days(offset);
+        row.request_id = Some(request_id.to_string());
+        LogRow::insert(pool, &row).await.unwrap();
+    }
+
+    let all = LogRow::events_for_user_in(pool, user_id, None, None)
+        .await
+        .unwrap();
+    assert_eq!(all.len(), 2);
+    let in_march = LogRow::events_for_user_in(
+        pool,
+        user_id,
+        Some((march - Duration::days(9), march + Duration::days(21))),
+        None,
+    )
+    .await
+    .unwrap();
+    assert_eq!(in_march.len(), 1);
+    assert_eq!(in_march[0].request_id.as_deref(), Some("march"));
+    let april =
+        LogRow::events_for_request_in(pool, "april", Some((march, march + Duration::days(60))))
+            .await
+            .unwrap();
+    assert_eq!(april.len(), 1);
+    assert!(
+        LogRow::events_for_request_in(pool, "april", Some((march, march + Duration::days(1))))
+            .await
+            .unwrap()
+            .is_empty()
+    );
+    let between = LogRow::events_between(pool, march, march + Duration::days(60), Some((1, 0)))
+        .await
+        .unwrap();
+    assert_eq!(between.len(), 1);
+    assert_eq!(between[0].request_id.as_deref(), Some("april"));
+
+    #[allow(deprecated)]
+    {
+        assert_eq!(
+            LogRow::events_for_user(pool, user_id, None)
+                .await
+                .unwrap()
+                .len(),
+            2
+        );
+    }
+
+    db.close().await;
+}
+
+#[tokio::test]
+async fn audit_writer_accepts_a_zero_flush_interval() {
+    let Some(db) = TestDb::create().await else {
+        return;
+    };
+    let pool = &db.pool;
+    let user_id = user(pool, "ada@example.com").await;
+    let config = AuditWriterConfig {
+        flush_interval: std::time::Duration::ZERO,
+        ..AuditWriterConfig::default()
+    };
+    let (audit, task) = AuditWriter::spawn(Arc::new(pool.clone()), config);
+    audit
+        .write(LogRow::new(user_id, json!({"type": "login"})))
+        .await
+        .unwrap();
+    audit.flush().await.unwrap();
+    assert_eq!(
+        LogRow::events_for_user_in(pool, user_id, None, None)
+            .await
+            .unwrap()
+            .len(),
+        1
+    );
+    audit.shutdown().await;
+    task.await.unwrap();
+
+    db.close().await;
+}
+
+#[tokio::test]
+async fn batches_beyond_the_bind_limit_are_written_whole() {
+    let Some(db) = TestDb::create().await else {
+        return;
+    };
+    let pool = &db.pool;
+    let user_id = user(pool, "ada@example.com").await;
+    let rows: Vec<LogRow> = (0..14_000)
+        .map(|_| LogRow::new(user_id, json!({"type": "login"})))
+        .collect();
+    LogRow::insert_batch(pool, &rows).await.unwrap();
+    let written: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM auth.log WHERE user_id = $1")
+        .bind(user_id)
+        .fetch_one(pool)
+        .await
+        .unwrap();
+    assert_eq!(written, 14_000);
+
+    db.close().await;
+}
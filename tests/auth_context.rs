@@ -0,0 +1,101 @@
+#![cfg(feature = "sqlx")]
+
+mod common;
+
+use subseq_auth::auth_context::AuthContext;
+use subseq_auth::db::{
+    AccessRoleRow, EffectiveRole, GROUP_MEMBER_ROLE, GroupMembershipRow, GroupRoleRow, GroupRow,
+    UserRoleRow, UserRow, effective_roles,
+};
+use subseq_auth::group_id::GroupId;
+use subseq_auth::user_id::UserId;
+use uuid::Uuid;
+
+use common::{TestDb, user};
+
+fn sorted(mut roles: Vec<EffectiveRole>) -> Vec<(String, String, String)> {
+    roles.sort_by(|a, b| {
+        (&a.scope, &a.scope_id, &a.role_name).cmp(&(&b.scope, &b.scope_id, &b.role_name))
+    });
+    roles
+        .into_iter()
+        .map(|role| (role.scope, role.scope_id, role.role_name))
+        .collect()
+}
+
+#[tokio::test]
+async fn context_matches_the_separate_lookups() {
+    let Some(db) = TestDb::create().await else {
+        return;
+    };
+    let pool = &db.pool;
+    let ada = user(pool, "ada@example.com").await;
+    let platform = GroupId(Uuid::new_v4());
+    let retired = GroupId(Uuid::new_v4());
+    for (group_id, display_name) in [(platform, "Platform"), (retired, "Retired")] {
+        GroupRow::insert(pool, &GroupRow::new(group_id, None, display_name))
+            .await
+            .unwrap();
+        GroupMembershipRow::add_member(
+            pool,
+            &GroupMembershipRow::new(group_id, ada, GROUP_MEMBER_ROLE),
+        )
+        .await
+        .unwrap();
+    }
+    GroupRow::deactivate(pool, retired).await.unwrap();
+    GroupRoleRow::allow(
+        pool,
+        &GroupRoleRow::new(platform, "project", "p1", "viewer"),
+    )
+    .await
+    .unwrap();
+    UserRoleRow::allow(pool, &UserRoleRow::new(ada, "project", "p2", "editor"))
+        .await
+        .unwrap();
+    UserRoleRow::deny(pool, &UserRoleRow::new(ada, "project", "p3", "editor"))
+        .await
+        .unwrap();
+    AccessRoleRow::allow(pool, &AccessRoleRow::new(ada, "support"))
+        .await
+        .unwrap();
+
+    let context = AuthContext::load(pool, ada).await.unwrap();
+    assert_eq!(context.user.as_ref().map(|user| user.id), Some(ada));
+    assert!(context.active);
+    assert!(!context.pending_approval);
+    let global_roles: Vec<String> = AccessRoleRow::roles(pool, ada)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|row| row.role_name)
+        .collect();
+    assert_eq!(context.global_roles, global_roles);
+    let groups: Vec<GroupId> = GroupMembershipRow::groups_for_user(pool, ada)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|group| group.id)
+        .collect();
+    assert_eq!(groups, vec![platform]);
+    assert_eq!(context.groups.len(), 1);
+    assert_eq!(context.groups[0].group.id, platform);
+    assert_eq!(context.groups[0].role_name, GROUP_MEMBER_ROLE);
+    assert_eq!(
+        sorted(context.roles),
+        sorted(effective_roles(pool, ada).await.unwrap())
+    );
+
+    // Deactivated and unknown users load as inactive.
+    UserRow::deactivate(pool, ada).await.unwrap();
+    assert!(!AuthContext::load(pool, ada).await.unwrap().active);
+    let unknown = AuthContext::load(pool, UserId(Uuid::new_v4()))
+        .await
+        .unwrap();
+    assert!(unknown.user.is_none());
+    assert!(!unknown.active);
+    assert!(unknown.roles.is_empty());
+    assert!(unknown.groups.is_empty());
+
+    db.close().await;
+}
@@ -0,0 +1,75 @@
+#![cfg(feature = "sqlx")]
+
+mod common;
+
+use std::sync::Arc;
+
+use futures_util::future::join_all;
+use subseq_auth::authz_loader::AuthzLoader;
+use subseq_auth::db::{AccessRoleRow, SUPER_ADMIN_ROLE, UserRoleRow, user_has_effective_access};
+use subseq_auth::user_id::UserId;
+use uuid::Uuid;
+
+use common::{TestDb, user};
+
+#[tokio::test]
+async fn batched_lookups_answer_as_the_direct_queries() {
+    let Some(db) = TestDb::create().await else {
+        return;
+    };
+    let pool = &db.pool;
+    let ada = user(pool, "ada@example.com").await;
+    let bob = user(pool, "bob@example.com").await;
+    let root = user(pool, "root@example.com").await;
+    let unknown = UserId(Uuid::new_v4());
+    UserRoleRow::allow(pool, &UserRoleRow::new(ada, "project", "p1", "editor"))
+        .await
+        .unwrap();
+    UserRoleRow::deny(pool, &UserRoleRow::new(bob, "project", "p1", "editor"))
+        .await
+        .unwrap();
+    AccessRoleRow::allow(pool, &AccessRoleRow::new(root, SUPER_ADMIN_ROLE))
+        .await
+        .unwrap();
+
+    let loader = AuthzLoader::new(Arc::new(pool.clone()));
+    let user_ids = [ada, bob, root, unknown, ada];
+    let checks = join_all(
+        user_ids
+            .iter()
+            .map(|user_id| loader.has_role(*user_id, "project", "p1", "editor")),
+    )
+    .await;
+    for (user_id, check) in user_ids.iter().zip(checks) {
+        let expected = user_has_effective_access(pool, *user_id, "project", "p1", "editor")
+            .await
+            .unwrap();
+        assert_eq!(check.unwrap(), expected, "{}", user_id);
+    }
+    let users = join_all(user_ids.iter().map(|user_id| loader.user(*user_id))).await;
+    let found: Vec<bool> = users
+        .into_iter()
+        .map(|user| user.unwrap().is_some())
+        .collect();
+    assert_eq!(found, vec![true, true, true, false, true]);
+
+    // Answers are kept for the loader's lifetime; a new loader sees the change.
+    UserRoleRow::revoke(pool, &UserRoleRow::new(ada, "project", "p1", "editor"))
+        .await
+        .unwrap();
+    assert!(
+        loader
+            .has_role(ada, "project", "p1", "editor")
+            .await
+            .unwrap()
+    );
+    let fresh = AuthzLoader::new(Arc::new(pool.clone()));
+    assert!(
+        !fresh
+            .has_role(ada, "project", "p1", "editor")
+            .await
+            .unwrap()
+    );
+
+    db.close().await;
+}
@@ -0,0 +1,94 @@
+//! A scratch database per test, created next to the one in `DATABASE_URL` and dropped afterwards.
+//! Without `DATABASE_URL` the database tests return early, so `cargo test` passes without
+//! Postgres:
+//!
+//! ```text
+//! DATABASE_URL=postgres://localhost/postgres cargo test
+//! ```
+
+#![allow(dead_code)]
+
+use std::str::FromStr;
+
+use sqlx::postgres::{PgConnectOptions, PgPoolOptions};
+use sqlx::{Connection, PgConnection, PgPool};
+use subseq_auth::db::{GroupRow, GroupVisibility, UserRow, create_user_tables};
+use subseq_auth::group_id::GroupId;
+use subseq_auth::user_id::UserId;
+use uuid::Uuid;
+
+pub struct TestDb {
+    pub pool: PgPool,
+    options: PgConnectOptions,
+    name: String,
+}
+
+impl TestDb {
+    /// A migrated scratch database, or `None` if `DATABASE_URL` is not set.
+    pub async fn create() -> Option<Self> {
+        let Ok(url) = std::env::var("DATABASE_URL") else {
+            eprintln!("Set DATABASE_URL to run the database tests");
+            return None;
+        };
+        let options = PgConnectOptions::from_str(&url).expect("DATABASE_URL is not a Postgres URL");
+        let name = format!("auth_test_{}", Uuid::new_v4().simple());
+        let mut admin = PgConnection::connect_with(&options)
+            .await
+            .expect("Failed to connect to DATABASE_URL");
+        sqlx::query(&format!("CREATE DATABASE {}", name))
+            .execute(&mut admin)
+            .await
+            .expect("Failed to create a scratch database");
+        admin.close().await.ok();
+
+        let pool = PgPoolOptions::new()
+            .max_connections(4)
+            .connect_with(options.clone().database(&name))
+            .await
+            .expect("Failed to connect to the scratch database");
+        create_user_tables(&pool)
+            .await
+            .expect("Failed to migrate the scratch database");
+        Some(Self {
+            pool,
+            options,
+            name,
+        })
+    }
+
+    /// Drop the scratch database.
+    pub async fn close(self) {
+        self.pool.close().await;
+        let mut admin = PgConnection::connect_with(&self.options)
+            .await
+            .expect("Failed to connect to DATABASE_URL");
+        sqlx::query(&format!(
+            "DROP DATABASE IF EXISTS {} WITH (FORCE)",
+            self.name
+        ))
+        .execute(&mut admin)
+        .await
+        .expect("Failed to drop the scratch database");
+    }
+}
+
+/// Insert a user with `email`.
+pub async fn user(pool: &PgPool, email: &str) -> UserId {
+    let user_id = UserId(Uuid::new_v4());
+    UserRow::insert(pool, &UserRow::new(user_id, None, email.to_string(), None))
+        .await
+        .unwrap();
+    user_id
+}
+
+/// Insert a group named `display_name` with `visibility`.
+pub async fn group(pool: &PgPool, display_name: &str, visibility: GroupVisibility) -> GroupId {
+    let group_id = GroupId(Uuid::new_v4());
+    GroupRow::insert(
+        pool,
+        &GroupRow::new(group_id, None, display_name).with_visibility(visibility),
+    )
+    .await
+    .unwrap();
+    group_id
+}
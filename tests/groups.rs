@@ -0,0 +1,326 @@
+#![cfg(feature = "sqlx")]
+
+mod common;
+
+use subseq_auth::archival::ArchivedFilter;
+use subseq_auth::db::{
+    GROUP_ADMIN_ROLE, GROUP_MEMBER_ROLE, GroupCapabilities, GroupDefaultRoleRow,
+    GroupJoinRequestRow, GroupMembershipRow, GroupRoleDefinitionRow, GroupRow, GroupVisibility,
+    JoinRequestStatus, UserRoleRow, group_capabilities, group_role_exists,
+};
+use subseq_auth::group_id::GroupId;
+use subseq_auth::user_id::UserId;
+
+use common::{TestDb, group, user};
+
+#[tokio::test]
+async fn join_requests_are_filed_for_visible_groups_and_decided_by_admins() {
+    let Some(db) = TestDb::create().await else {
+        return;
+    };
+    let pool = &db.pool;
+    let admin = user(pool, "admin@example.com").await;
+    let ada = user(pool, "ada@example.com").await;
+    let bob = user(pool, "bob@example.com").await;
+    let private = group(pool, "Payroll", GroupVisibility::Private).await;
+    let discoverable = group(pool, "Platform", GroupVisibility::Discoverable).await;
+    group(pool, "Book club", GroupVisibility::Open).await;
+
+    let listed = GroupRow::discoverable(pool, None, ArchivedFilter::Exclude, None)
+        .await
+        .unwrap();
+    assert_eq!(listed.len(), 2);
+    assert!(listed.iter().all(|group| group.id != private));
+    let searched = GroupRow::discoverable(pool, Some("PLAT"), ArchivedFilter::Exclude, None)
+        .await
+        .unwrap();
+    assert_eq!(searched.len(), 1);
+    assert_eq!(searched[0].id, discoverable);
+    assert!(
+        GroupRow::get_joinable(pool, private)
+            .await
+            .unwrap()
+            .is_none()
+    );
+    assert!(
+        GroupRow::get_joinable(pool, discoverable)
+            .await
+            .unwrap()
+            .is_some()
+    );
+
+    let first = GroupJoinRequestRow::request(pool, discoverable, ada, Some("hi"))
+        .await
+        .unwrap();
+    assert_eq!(first.status, JoinRequestStatus::Pending);
+    let again = GroupJoinRequestRow::request(pool, discoverable, ada, Some("hello?"))
+        .await
+        .unwrap();
+    assert_eq!(again.message.as_deref(), Some("hi"));
+    GroupJoinRequestRow::request(pool, discoverable, bob, None)
+        .await
+        .unwrap();
+    let pending = GroupJoinRequestRow::pending_for_group(pool, discoverable, None)
+        .await
+        .unwrap();
+    assert_eq!(pending.len(), 2);
+
+    assert!(
+        GroupJoinRequestRow::decide(pool, discoverable, ada, true, admin)
+            .await
+            .unwrap()
+    );
+    assert!(
+        !GroupJoinRequestRow::decide(pool, discoverable, ada, false, admin)
+            .await
+            .unwrap()
+    );
+    assert!(
+        GroupMembershipRow::is_member(pool, discoverable, ada)
+            .await
+            .unwrap()
+    );
+    assert!(
+        GroupJoinRequestRow::decide(pool, discoverable, bob, false, admin)
+            .await
+            .unwrap()
+    );
+    assert!(
+        !GroupMembershipRow::is_member(pool, discoverable, bob)
+            .await
+            .unwrap()
+    );
+    let rejected = GroupJoinRequestRow::get(pool, discoverable, bob)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(rejected.status, JoinRequestStatus::Rejected);
+    assert_eq!(rejected.decided_by, Some(admin.0));
+
+    // A decided request can be filed again.
+    let reopened = GroupJoinRequestRow::request(pool, discoverable, bob, Some("please"))
+        .await
+        .unwrap();
+    assert_eq!(reopened.status, JoinRequestStatus::Pending);
+    assert!(reopened.decided_by.is_none());
+
+    db.close().await;
+}
+
+#[tokio::test]
+async fn custom_group_roles_grant_their_capabilities() {
+    let Some(db) = TestDb::create().await else {
+        return;
+    };
+    let pool = &db.pool;
+    let ada = user(pool, "ada@example.com").await;
+    let bob = user(pool, "bob@example.com").await;
+    let group_id = group(pool, "Platform", GroupVisibility::Private).await;
+    let reviewer =
+        GroupCapabilities::from_names(["view_members", "approve_join_requests"]).unwrap();
+    assert_eq!(
+        GroupCapabilities::from_names(["view_members", "fly"]),
+        Err("fly".to_string())
+    );
+
+    assert!(!group_role_exists(pool, group_id, "reviewer").await.unwrap());
+    GroupRoleDefinitionRow::upsert(
+        pool,
+        &GroupRoleDefinitionRow::new(group_id, "reviewer", reviewer, None),
+    )
+    .await
+    .unwrap();
+    assert!(group_role_exists(pool, group_id, "reviewer").await.unwrap());
+    GroupMembershipRow::add_member(pool, &GroupMembershipRow::new(group_id, ada, "reviewer"))
+        .await
+        .unwrap();
+    GroupMembershipRow::add_member(
+        pool,
+        &GroupMembershipRow::new(group_id, bob, GROUP_MEMBER_ROLE),
+    )
+    .await
+    .unwrap();
+
+    let capabilities = group_capabilities(pool, group_id, ada).await.unwrap();
+    assert!(capabilities.contains(GroupCapabilities::APPROVE_JOIN_REQUESTS));
+    assert!(!capabilities.contains(GroupCapabilities::REMOVE_MEMBERS));
+    assert_eq!(
+        capabilities.names(),
+        vec!["view_members", "approve_join_requests"]
+    );
+    assert_eq!(
+        group_capabilities(pool, group_id, bob).await.unwrap(),
+        GroupCapabilities::VIEW_MEMBERS
+    );
+
+    // Redefining the role changes what its holders may do; a held role cannot be removed.
+    GroupRoleDefinitionRow::upsert(
+        pool,
+        &GroupRoleDefinitionRow::new(group_id, "reviewer", GroupCapabilities::VIEW_MEMBERS, None),
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        group_capabilities(pool, group_id, ada).await.unwrap(),
+        GroupCapabilities::VIEW_MEMBERS
+    );
+    assert!(
+        !GroupRoleDefinitionRow::delete_unused(pool, group_id, "reviewer")
+            .await
+            .unwrap()
+    );
+    GroupMembershipRow::remove_member(pool, group_id, ada)
+        .await
+        .unwrap();
+    assert!(
+        GroupRoleDefinitionRow::delete_unused(pool, group_id, "reviewer")
+            .await
+            .unwrap()
+    );
+    assert!(
+        GroupRoleDefinitionRow::for_group(pool, group_id)
+            .await
+            .unwrap()
+            .is_empty()
+    );
+
+    db.close().await;
+}
+
+#[tokio::test]
+async fn default_roles_follow_group_membership() {
+    let Some(db) = TestDb::create().await else {
+        return;
+    };
+    let pool = &db.pool;
+    let admin = user(pool, "admin@example.com").await;
+    let ada = user(pool, "ada@example.com").await;
+    let bob = user(pool, "bob@example.com").await;
+    let carol = user(pool, "carol@example.com").await;
+    let platform = group(pool, "Platform", GroupVisibility::Private).await;
+    let infra = group(pool, "Infra", GroupVisibility::Private).await;
+    let join = |group_id: GroupId, user_id: UserId| async move {
+        GroupMembershipRow::add_member(
+            pool,
+            &GroupMembershipRow::new(group_id, user_id, GROUP_MEMBER_ROLE),
+        )
+        .await
+        .unwrap();
+    };
+    let holds_editor = |user_id: UserId| async move {
+        UserRoleRow::roles_in_scope(pool, user_id, "project", "p1")
+            .await
+            .unwrap()
+            .iter()
+            .any(|role| role.role_name == "editor")
+    };
+    let editor = |group_id: GroupId| GroupDefaultRoleRow::new(group_id, "project", "p1", "editor");
+
+    // Existing members receive a default role when it is added, later members when they join.
+    join(platform, ada).await;
+    assert!(
+        GroupDefaultRoleRow::add(pool, admin, &editor(platform))
+            .await
+            .unwrap()
+    );
+    assert!(
+        !GroupDefaultRoleRow::add(pool, admin, &editor(platform))
+            .await
+            .unwrap()
+    );
+    GroupDefaultRoleRow::add(pool, admin, &editor(infra))
+        .await
+        .unwrap();
+    assert!(holds_editor(ada).await);
+    join(platform, bob).await;
+    join(infra, bob).await;
+    UserRoleRow::allow(pool, &UserRoleRow::new(carol, "project", "p1", "editor"))
+        .await
+        .unwrap();
+    join(platform, carol).await;
+    assert!(holds_editor(bob).await);
+
+    // Leaving revokes it unless the member holds it directly or through another group.
+    for user_id in [ada, bob, carol] {
+        GroupMembershipRow::remove_member(pool, platform, user_id)
+            .await
+            .unwrap();
+    }
+    assert!(!holds_editor(ada).await);
+    assert!(holds_editor(bob).await);
+    assert!(holds_editor(carol).await);
+
+    assert!(
+        GroupDefaultRoleRow::remove(pool, admin, &editor(infra))
+            .await
+            .unwrap()
+    );
+    assert!(!holds_editor(bob).await);
+    assert_eq!(
+        GroupDefaultRoleRow::for_group(pool, platform)
+            .await
+            .unwrap(),
+        vec![editor(platform)]
+    );
+
+    db.close().await;
+}
+
+#[tokio::test]
+async fn membership_checks_match_only_the_exact_member_and_role() {
+    let Some(db) = TestDb::create().await else {
+        return;
+    };
+    let pool = &db.pool;
+    let ada = user(pool, "ada@example.com").await;
+    let bob = user(pool, "bob@example.com").await;
+    let outsider = user(pool, "outsider@example.com").await;
+    let platform = group(pool, "Platform", GroupVisibility::Private).await;
+    let infra = group(pool, "Infra", GroupVisibility::Private).await;
+    GroupMembershipRow::add_member(
+        pool,
+        &GroupMembershipRow::new(platform, ada, GROUP_ADMIN_ROLE),
+    )
+    .await
+    .unwrap();
+    for group_id in [platform, infra] {
+        GroupMembershipRow::add_member(
+            pool,
+            &GroupMembershipRow::new(group_id, bob, GROUP_MEMBER_ROLE),
+        )
+        .await
+        .unwrap();
+    }
+
+    for (group_id, user_id, member) in [
+        (platform, ada, true),
+        (platform, bob, true),
+        (platform, outsider, false),
+        (infra, ada, false),
+        (infra, bob, true),
+    ] {
+        assert_eq!(
+            GroupMembershipRow::is_member(pool, group_id, user_id)
+                .await
+                .unwrap(),
+            member
+        );
+    }
+    assert!(
+        GroupMembershipRow::has_role(pool, platform, ada, GROUP_ADMIN_ROLE)
+            .await
+            .unwrap()
+    );
+    assert!(
+        !GroupMembershipRow::has_role(pool, platform, bob, GROUP_ADMIN_ROLE)
+            .await
+            .unwrap()
+    );
+    assert!(
+        !GroupMembershipRow::has_role(pool, infra, ada, GROUP_ADMIN_ROLE)
+            .await
+            .unwrap()
+    );
+
+    db.close().await;
+}
@@ -0,0 +1,197 @@
+#![cfg(feature = "sqlx")]
+
+mod common;
+
+use std::time::Duration;
+
+use subseq_auth::idempotency::{
+    Claim, DEFAULT_IDEMPOTENCY_RETENTION, StoredResponse, claim_key, complete_key, fingerprint,
+    release_key,
+};
+
+use common::{TestDb, user};
+
+#[tokio::test]
+async fn abandoned_claim_is_claimed_again_after_the_lease() {
+    let Some(db) = TestDb::create().await else {
+        return;
+    };
+    let pool = &db.pool;
+    let user_id = user(pool, "retry@example.com").await;
+    let fingerprint = fingerprint("POST", "/auth/invitations", b"{}");
+    let lease = Duration::from_secs(60);
+    let claim = || {
+        claim_key(
+            pool,
+            user_id,
+            "retry-1",
+            &fingerprint,
+            DEFAULT_IDEMPOTENCY_RETENTION,
+            lease,
+        )
+    };
+
+    assert!(matches!(claim().await.unwrap(), Claim::Claimed(_)));
+    assert_eq!(claim().await.unwrap(), Claim::InProgress);
+
+    // The first request never finished and its lease ran out.
+    sqlx::query("UPDATE auth.idempotency_keys SET created_at = created_at - INTERVAL '2 minutes'")
+        .execute(pool)
+        .await
+        .unwrap();
+    let stale: chrono::NaiveDateTime =
+        sqlx::query_scalar("SELECT created_at FROM auth.idempotency_keys")
+            .fetch_one(pool)
+            .await
+            .unwrap();
+    let Claim::Claimed(claimed_at) = claim().await.unwrap() else {
+        panic!("the abandoned key was not claimed again");
+    };
+
+    // The slow first request finishing late touches neither the new claim nor its response.
+    let stored = StoredResponse {
+        status_code: 201,
+        content_type: Some("application/json".to_string()),
+        location: Some("/auth/invitations/1".to_string()),
+        body: b"{}".to_vec(),
+    };
+    complete_key(pool, user_id, "retry-1", stale, &stored)
+        .await
+        .unwrap();
+    release_key(pool, user_id, "retry-1", stale).await.unwrap();
+    assert_eq!(claim().await.unwrap(), Claim::InProgress);
+
+    // A finished request is replayed for the whole retention window, lease or not.
+    complete_key(pool, user_id, "retry-1", claimed_at, &stored)
+        .await
+        .unwrap();
+    sqlx::query("UPDATE auth.idempotency_keys SET created_at = created_at - INTERVAL '2 minutes'")
+        .execute(pool)
+        .await
+        .unwrap();
+    assert_eq!(claim().await.unwrap(), Claim::Completed(stored));
+
+    db.close().await;
+}
+
+#[cfg(feature = "api")]
+mod layer {
+    use std::str::FromStr;
+    use std::sync::Arc;
+
+    use axum::body::{Body, to_bytes};
+    use axum::http::{Request, StatusCode};
+    use axum::routing::post;
+    use axum::{Extension, Router};
+    use base64::Engine;
+    use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+    use serde_json::{Value, json};
+    use sqlx::PgPool;
+    use subseq_auth::api::{HasPool, create_client_handler};
+    use subseq_auth::db::{AccessRoleRow, SUPER_ADMIN_ROLE};
+    use subseq_auth::idempotency::{IDEMPOTENCY_KEY_HEADER, IdempotencyLayer};
+    use subseq_auth::prelude::{AuthenticatedUser, CoreIdToken, CoreIdTokenClaims};
+    use subseq_auth::user_id::UserId;
+    use tower::ServiceExt;
+
+    use super::common::{TestDb, user};
+
+    #[derive(Clone)]
+    struct App {
+        pool: Arc<PgPool>,
+    }
+
+    impl HasPool for App {
+        fn pool(&self) -> Arc<PgPool> {
+            self.pool.clone()
+        }
+    }
+
+    /// An unsigned token for `user_id`; the layer under test never validates it.
+    async fn authenticated(user_id: UserId) -> AuthenticatedUser {
+        let now = chrono::Utc::now().timestamp();
+        let payload = json!({
+            "iss": "https://issuer.example.com",
+            "sub": user_id.to_string(),
+            "aud": ["app"],
+            "iat": now,
+            "exp": now + 60,
+            "email": "admin@example.com",
+        });
+        let encode = |value: &Value| URL_SAFE_NO_PAD.encode(serde_json::to_vec(value).unwrap());
+        let token = format!(
+            "{}.{}.c2ln",
+            encode(&json!({"alg": "RS256"})),
+            encode(&payload)
+        );
+        let claims: CoreIdTokenClaims = serde_json::from_value(payload).unwrap();
+        AuthenticatedUser::from_claims(CoreIdToken::from_str(&token).unwrap(), claims)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn issued_client_secret_is_not_stored_for_replay() {
+        let Some(db) = TestDb::create().await else {
+            return;
+        };
+        let pool = &db.pool;
+        let admin = user(pool, "admin@example.com").await;
+        AccessRoleRow::allow(pool, &AccessRoleRow::new(admin, SUPER_ADMIN_ROLE))
+            .await
+            .unwrap();
+        let app = App {
+            pool: Arc::new(pool.clone()),
+        };
+        let router = Router::new()
+            .route("/clients", post(create_client_handler::<App>))
+            .layer(IdempotencyLayer::new(app.clone()))
+            .layer(Extension(authenticated(admin).await))
+            .with_state(app);
+        let create = || {
+            Request::post("/clients")
+                .header(IDEMPOTENCY_KEY_HEADER, "create-billing")
+                .header("content-type", "application/json")
+                .body(Body::from(
+                    json!({"client_id": "billing", "name": "Billing", "confidential": true})
+                        .to_string(),
+                ))
+                .unwrap()
+        };
+
+        let response = router.clone().oneshot(create()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let created: Value = serde_json::from_slice(&body).unwrap();
+        let secret = created["client_secret"].as_str().unwrap().to_string();
+
+        let (withheld, stored): (bool, Option<Vec<u8>>) =
+            sqlx::query_as("SELECT withheld, body FROM auth.idempotency_keys")
+                .fetch_one(pool)
+                .await
+                .unwrap();
+        assert!(withheld);
+        assert!(stored.is_none());
+        let leaked: bool = sqlx::query_scalar(
+            "SELECT EXISTS (SELECT 1 FROM auth.idempotency_keys WHERE position($1 IN body) > 0)",
+        )
+        .bind(secret.as_bytes())
+        .fetch_one(pool)
+        .await
+        .unwrap();
+        assert!(!leaked);
+
+        // The retry neither registers a second client nor sees the secret again.
+        let retry = router.oneshot(create()).await.unwrap();
+        assert_eq!(retry.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        let body = to_bytes(retry.into_body(), usize::MAX).await.unwrap();
+        assert!(!String::from_utf8_lossy(&body).contains(&secret));
+        let clients: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM auth.clients")
+            .fetch_one(pool)
+            .await
+            .unwrap();
+        assert_eq!(clients, 1);
+
+        db.close().await;
+    }
+}
@@ -0,0 +1,82 @@
+#![cfg(all(feature = "import", feature = "api"))]
+
+mod common;
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use futures_util::future::BoxFuture;
+use subseq_auth::api::User;
+use subseq_auth::db::UserRow;
+use subseq_auth::hooks::{HookRejection, Hooks, NewUser};
+use subseq_auth::migrate::import::{ImportConflictKind, ImportFormat, ImportOptions, import};
+
+use common::TestDb;
+
+#[derive(Clone, Default)]
+struct Gate {
+    created: Arc<AtomicUsize>,
+}
+
+impl Hooks for Gate {
+    fn before_user_create<'a>(
+        &'a self,
+        user: &'a NewUser<'a>,
+    ) -> BoxFuture<'a, Result<(), HookRejection>> {
+        Box::pin(async move {
+            if user.email.ends_with("@blocked.example.com") {
+                return Err(HookRejection::new("domain_blocked", "Domain is blocked"));
+            }
+            Ok(())
+        })
+    }
+
+    fn after_user_create<'a>(&'a self, _user: &'a User) -> BoxFuture<'a, ()> {
+        Box::pin(async move {
+            self.created.fetch_add(1, Ordering::SeqCst);
+        })
+    }
+}
+
+const EXPORT: &str = "email,username,password_hash,groups,roles\n\
+                      ada@example.com,ada,,,\n\
+                      eve@blocked.example.com,eve,,,\n\
+                      bob@example.com,bob,,,\n";
+
+#[tokio::test]
+async fn import_runs_user_creation_hooks() {
+    let Some(db) = TestDb::create().await else {
+        return;
+    };
+    let pool = &db.pool;
+    let gate = Gate::default();
+
+    let dry_run = ImportOptions::new("dry")
+        .with_dry_run(true)
+        .with_hooks(gate.clone());
+    let report = import(pool, EXPORT.as_bytes(), ImportFormat::Csv, &dry_run)
+        .await
+        .unwrap();
+    assert_eq!(report.imported, 2);
+    assert_eq!(gate.created.load(Ordering::SeqCst), 0);
+
+    let options = ImportOptions::new("hooks")
+        .with_batch_size(2)
+        .with_hooks(gate.clone());
+    let report = import(pool, EXPORT.as_bytes(), ImportFormat::Csv, &options)
+        .await
+        .unwrap();
+    assert_eq!(report.imported, 2);
+    assert_eq!(report.conflicts.len(), 1);
+    assert_eq!(report.conflicts[0].record, 2);
+    assert_eq!(report.conflicts[0].kind, ImportConflictKind::Rejected);
+    assert_eq!(gate.created.load(Ordering::SeqCst), 2);
+    assert!(
+        UserRow::get_by_email(pool, "eve@blocked.example.com")
+            .await
+            .unwrap()
+            .is_none()
+    );
+
+    db.close().await;
+}
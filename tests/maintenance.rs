@@ -0,0 +1,63 @@
+#![cfg(feature = "sqlx")]
+
+mod common;
+
+use chrono::{Duration, NaiveDateTime, Utc};
+use sqlx::PgPool;
+use subseq_auth::remember::purge_remembered_sessions;
+use subseq_auth::user_id::UserId;
+use uuid::Uuid;
+
+use common::{TestDb, user};
+
+async fn remembered(
+    pool: &PgPool,
+    user_id: UserId,
+    expires_at: NaiveDateTime,
+    revoked_at: Option<NaiveDateTime>,
+) -> Uuid {
+    let id = Uuid::new_v4();
+    sqlx::query(
+        r#"
+        INSERT INTO auth.remembered_sessions
+            (id, user_id, secret_hash, token, expires_at, revoked_at)
+        VALUES ($1, $2, 'hash', 'token', $3, $4)
+        "#,
+    )
+    .bind(id)
+    .bind(user_id)
+    .bind(expires_at)
+    .bind(revoked_at)
+    .execute(pool)
+    .await
+    .unwrap();
+    id
+}
+
+#[tokio::test]
+async fn purge_removes_expired_and_revoked_remembered_sessions() {
+    let Some(db) = TestDb::create().await else {
+        return;
+    };
+    let pool = &db.pool;
+    let user_id = user(pool, "ada@example.com").await;
+    let now = Utc::now().naive_utc();
+    remembered(pool, user_id, now - Duration::days(1), None).await;
+    remembered(
+        pool,
+        user_id,
+        now + Duration::days(30),
+        Some(now - Duration::hours(1)),
+    )
+    .await;
+    let live = remembered(pool, user_id, now + Duration::days(30), None).await;
+
+    assert_eq!(purge_remembered_sessions(pool, now).await.unwrap(), 2);
+    let remaining: Vec<Uuid> = sqlx::query_scalar("SELECT id FROM auth.remembered_sessions")
+        .fetch_all(pool)
+        .await
+        .unwrap();
+    assert_eq!(remaining, vec![live]);
+
+    db.close().await;
+}
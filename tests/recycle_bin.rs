@@ -0,0 +1,372 @@
+#![cfg(feature = "sqlx")]
+
+mod common;
+
+use axum::Router;
+use axum::body::Body;
+use axum::http::Request;
+use axum::http::header::{COOKIE, SET_COOKIE};
+use axum::routing::get;
+use axum_extra::extract::CookieJar;
+use subseq_auth::auth::REMEMBER_COOKIE;
+use subseq_auth::db::{
+    GROUP_MEMBER_ROLE, GroupMembershipRow, GroupRoleRow, GroupRow, GroupVisibility, UserRow,
+    effective_roles,
+};
+use subseq_auth::prelude::{
+    ClaimsVerificationError, CoreIdToken, CoreIdTokenClaims, OidcToken, ValidatesIdentity,
+};
+use subseq_auth::recycle_bin::{
+    delete_group, delete_user, is_group_deleted, restore_group, restore_user,
+};
+use subseq_auth::remember::{
+    DEFAULT_REMEMBER_FOR, RememberMeLayer, RememberedSession, remember_session,
+};
+use subseq_auth::user_id::UserId;
+use tower::ServiceExt;
+use uuid::Uuid;
+
+use common::{TestDb, group, user};
+
+#[tokio::test]
+async fn deleted_group_is_hidden_and_frozen_until_restored() {
+    let Some(db) = TestDb::create().await else {
+        return;
+    };
+    let pool = &db.pool;
+    let admin = user(pool, "admin@example.com").await;
+    let member = user(pool, "member@example.com").await;
+    let newcomer = user(pool, "newcomer@example.com").await;
+    let group_id = group(pool, "Engineering", GroupVisibility::Private).await;
+    GroupMembershipRow::add_member(
+        pool,
+        &GroupMembershipRow::new(group_id, member, GROUP_MEMBER_ROLE),
+    )
+    .await
+    .unwrap();
+    GroupRoleRow::allow(
+        pool,
+        &GroupRoleRow::new(group_id, "project", "p1", "editor"),
+    )
+    .await
+    .unwrap();
+
+    assert!(delete_group(pool, admin, group_id).await.unwrap());
+    assert!(!delete_group(pool, admin, group_id).await.unwrap());
+    assert!(GroupRow::get(pool, group_id).await.unwrap().is_none());
+    assert!(effective_roles(pool, member).await.unwrap().is_empty());
+
+    let err = GroupMembershipRow::add_member(
+        pool,
+        &GroupMembershipRow::new(group_id, newcomer, GROUP_MEMBER_ROLE),
+    )
+    .await
+    .unwrap_err();
+    assert!(is_group_deleted(&err), "{}", err);
+    let err = GroupMembershipRow::remove_member(pool, group_id, member)
+        .await
+        .unwrap_err();
+    assert!(is_group_deleted(&err), "{}", err);
+    let err = GroupRoleRow::allow(
+        pool,
+        &GroupRoleRow::new(group_id, "project", "p2", "editor"),
+    )
+    .await
+    .unwrap_err();
+    assert!(is_group_deleted(&err), "{}", err);
+
+    assert!(restore_group(pool, admin, group_id).await.unwrap());
+    assert!(
+        GroupMembershipRow::is_member(pool, group_id, member)
+            .await
+            .unwrap()
+    );
+    let roles = effective_roles(pool, member).await.unwrap();
+    assert_eq!(roles.len(), 1);
+    assert_eq!(roles[0].role_name, "editor");
+    GroupMembershipRow::add_member(
+        pool,
+        &GroupMembershipRow::new(group_id, newcomer, GROUP_MEMBER_ROLE),
+    )
+    .await
+    .unwrap();
+
+    db.close().await;
+}
+
+#[tokio::test]
+async fn deleted_user_is_not_found_until_restored() {
+    let Some(db) = TestDb::create().await else {
+        return;
+    };
+    let pool = &db.pool;
+    let admin = user(pool, "admin@example.com").await;
+    let user_id = user(pool, "gone@example.com").await;
+
+    assert!(delete_user(pool, admin, user_id).await.unwrap());
+    assert!(UserRow::get(pool, user_id).await.unwrap().is_none());
+    assert!(
+        UserRow::get_by_email(pool, "gone@example.com")
+            .await
+            .unwrap()
+            .is_none()
+    );
+    assert!(UserRow::is_deleted(pool, user_id).await.unwrap());
+
+    // Signing in again finds the deleted account instead of creating a second one.
+    let defaults = UserRow::new(
+        UserId(Uuid::new_v4()),
+        None,
+        "gone@example.com".to_string(),
+        None,
+    );
+    let (found, created) = UserRow::get_or_create_by_email(pool, "gone@example.com", defaults)
+        .await
+        .unwrap();
+    assert_eq!(found.id, user_id);
+    assert!(!created);
+
+    assert!(restore_user(pool, admin, user_id).await.unwrap());
+    assert!(UserRow::get(pool, user_id).await.unwrap().is_some());
+    assert!(!UserRow::is_deleted(pool, user_id).await.unwrap());
+
+    db.close().await;
+}
+
+/// Never consulted: a deleted user is refused before any token reaches the provider.
+#[derive(Clone)]
+struct NoProvider;
+
+impl ValidatesIdentity for NoProvider {
+    fn validate_bearer(
+        &self,
+        _token: &str,
+    ) -> Result<(CoreIdToken, CoreIdTokenClaims), ClaimsVerificationError> {
+        unreachable!()
+    }
+
+    fn validate_token(
+        &self,
+        _token: &OidcToken,
+    ) -> Result<(CoreIdToken, CoreIdTokenClaims), ClaimsVerificationError> {
+        unreachable!()
+    }
+
+    async fn refresh_token(&self, _token: OidcToken) -> anyhow::Result<OidcToken> {
+        unreachable!()
+    }
+}
+
+#[tokio::test]
+async fn deleted_user_remember_me_token_is_rejected() {
+    let Some(db) = TestDb::create().await else {
+        return;
+    };
+    let pool = &db.pool;
+    let admin = user(pool, "admin@example.com").await;
+    let user_id = user(pool, "gone@example.com").await;
+    let token = OidcToken::from_bearer(concat!(
+        "eyJhbGciOiJSUzI1NiJ9.",
+        "eyJpc3MiOiJodHRwczovL2lkcC5leGFtcGxlLmNvbSIsInN1YiI6ImEiLCJhdWQiOiJhcHAiLCJleHAiOjIwMDAwMDAwMDAsImlhdCI6MTcwMDAwMDAwMH0",
+        ".c2ln:access:refresh:nonce"
+    ))
+    .unwrap();
+    let jar = remember_session(
+        pool,
+        CookieJar::new(),
+        user_id,
+        &token,
+        None,
+        None,
+        DEFAULT_REMEMBER_FOR,
+    )
+    .await
+    .unwrap();
+    let cookie = jar.get(REMEMBER_COOKIE).unwrap().value().to_string();
+
+    assert!(delete_user(pool, admin, user_id).await.unwrap());
+    assert!(
+        RememberedSession::list(pool, user_id)
+            .await
+            .unwrap()
+            .is_empty()
+    );
+
+    // A token left unrevoked, e.g. by a delete that predates revocation, is refused all the same.
+    sqlx::query("UPDATE auth.remembered_sessions SET revoked_at = NULL")
+        .execute(pool)
+        .await
+        .unwrap();
+    let router = Router::new()
+        .route("/", get(|| async {}))
+        .layer(RememberMeLayer::new(NoProvider, pool.clone()));
+    let response = router
+        .oneshot(
+            Request::get("/")
+                .header(COOKIE, format!("{}={}", REMEMBER_COOKIE, cookie))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    let set_cookie = response
+        .headers()
+        .get(SET_COOKIE)
+        .unwrap()
+        .to_str()
+        .unwrap();
+    assert!(
+        set_cookie.starts_with(&format!("{}=;", REMEMBER_COOKIE)),
+        "{}",
+        set_cookie
+    );
+
+    db.close().await;
+}
+
+#[cfg(feature = "api")]
+mod proxy {
+    use std::sync::Arc;
+
+    use axum::body::{Body, to_bytes};
+    use axum::http::{HeaderName, Request, StatusCode};
+    use axum::routing::get;
+    use axum::{Extension, Router};
+    use serde_json::Value;
+    use sqlx::PgPool;
+    use subseq_auth::api::{AnnouncesUserEvents, AuthApp, HasPool, User};
+    use subseq_auth::group_id::GroupId;
+    use subseq_auth::prelude::{
+        AuthenticatedUser, ClaimsVerificationError, CoreIdToken, CoreIdTokenClaims, OidcToken,
+        ValidatesIdentity,
+    };
+    use subseq_auth::recycle_bin::delete_user;
+    use subseq_auth::trusted_proxy::{DEFAULT_EMAIL_HEADER, TrustedProxyConfig, TrustedProxyLayer};
+    use subseq_auth::user_id::UserId;
+    use tower::ServiceExt;
+
+    use super::common::{TestDb, user};
+
+    #[derive(Clone)]
+    struct App {
+        pool: Arc<PgPool>,
+    }
+
+    impl HasPool for App {
+        fn pool(&self) -> Arc<PgPool> {
+            self.pool.clone()
+        }
+    }
+
+    impl ValidatesIdentity for App {
+        fn validate_bearer(
+            &self,
+            _token: &str,
+        ) -> Result<(CoreIdToken, CoreIdTokenClaims), ClaimsVerificationError> {
+            unreachable!()
+        }
+
+        fn validate_token(
+            &self,
+            _token: &OidcToken,
+        ) -> Result<(CoreIdToken, CoreIdTokenClaims), ClaimsVerificationError> {
+            unreachable!()
+        }
+
+        async fn refresh_token(&self, _token: OidcToken) -> anyhow::Result<OidcToken> {
+            unreachable!()
+        }
+    }
+
+    impl AnnouncesUserEvents for App {
+        fn announce_new_user(&self, _user: &User) {}
+        fn announce_user_deactivation(&self, _user_id: UserId) {}
+        fn announce_user_update(&self, _user: &User) {}
+        fn announce_user_group_join(&self, _user_id: UserId, _group_id: GroupId) {}
+        fn announce_user_group_leave(&self, _user_id: UserId, _group_id: GroupId) {}
+    }
+
+    impl AuthApp for App {}
+
+    #[tokio::test]
+    async fn deleted_user_behind_the_proxy_is_refused() {
+        let Some(db) = TestDb::create().await else {
+            return;
+        };
+        let pool = &db.pool;
+        let admin = user(pool, "admin@example.com").await;
+        let user_id = user(pool, "gone@example.com").await;
+        assert!(delete_user(pool, admin, user_id).await.unwrap());
+
+        let app = App {
+            pool: Arc::new(pool.clone()),
+        };
+        let secret = HeaderName::from_static("x-proxy-secret");
+        let config = TrustedProxyConfig::new().with_shared_secret(secret.clone(), "s3cret");
+        let router = Router::new()
+            .route(
+                "/",
+                get(
+                    |Extension(auth_user): Extension<AuthenticatedUser>| async move {
+                        auth_user.id().to_string()
+                    },
+                ),
+            )
+            .layer(TrustedProxyLayer::new(app, config));
+        let response = router
+            .oneshot(
+                Request::get("/")
+                    .header(secret, "s3cret")
+                    .header(DEFAULT_EMAIL_HEADER, "gone@example.com")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["error"]["code"], "account_deleted", "{}", body);
+
+        db.close().await;
+    }
+}
+
+#[cfg(feature = "hard-delete")]
+#[tokio::test]
+async fn purge_removes_only_rows_deleted_before_the_cutoff() {
+    use subseq_auth::group_id::GroupId;
+    use subseq_auth::recycle_bin::{deleted_groups, purge_deleted};
+
+    let Some(db) = TestDb::create().await else {
+        return;
+    };
+    let pool = &db.pool;
+    let admin = user(pool, "admin@example.com").await;
+    let old_user = user(pool, "old@example.com").await;
+    let old_group = group(pool, "Old", GroupVisibility::Private).await;
+    let recent_group = group(pool, "Recent", GroupVisibility::Private).await;
+    GroupMembershipRow::add_member(
+        pool,
+        &GroupMembershipRow::new(old_group, admin, GROUP_MEMBER_ROLE),
+    )
+    .await
+    .unwrap();
+
+    assert!(delete_user(pool, admin, old_user).await.unwrap());
+    assert!(delete_group(pool, admin, old_group).await.unwrap());
+    let cutoff = chrono::Utc::now().naive_utc();
+    assert!(delete_group(pool, admin, recent_group).await.unwrap());
+
+    assert_eq!(purge_deleted(pool, cutoff).await.unwrap(), 2);
+    assert!(!UserRow::is_deleted(pool, old_user).await.unwrap());
+    let remaining: Vec<GroupId> = deleted_groups(pool, None)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|group| group.id)
+        .collect();
+    assert_eq!(remaining, vec![recent_group]);
+
+    db.close().await;
+}
@@ -0,0 +1,260 @@
+#![cfg(feature = "sqlx")]
+
+mod common;
+
+use sqlx::PgPool;
+use subseq_auth::bundle::{
+    BundleGrant, RoleBundle, apply_bundle, delete_bundle, get_bundle, save_bundle,
+};
+use subseq_auth::db::{
+    GLOBAL_SCOPE_ID, GROUP_MEMBER_ROLE, GroupMembershipRow, GroupRoleRow, GroupVisibility,
+    RoleEffect, UserRoleRow, effective_roles, revoke_user_roles_report, roles_allow,
+    user_has_effective_role,
+};
+
+use common::{TestDb, group, user};
+
+async fn revocations_logged(pool: &PgPool) -> i64 {
+    sqlx::query_scalar("SELECT COUNT(*) FROM auth.log WHERE action->>'type' = 'role_revoke'")
+        .fetch_one(pool)
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+async fn bulk_revocation_is_narrowed_by_user_and_scope_and_audited() {
+    let Some(db) = TestDb::create().await else {
+        return;
+    };
+    let pool = &db.pool;
+    let ada = user(pool, "ada@example.com").await;
+    let bob = user(pool, "bob@example.com").await;
+    let group_id = group(pool, "Platform", GroupVisibility::Private).await;
+    for (user_id, scope_id, role_name) in [
+        (ada, "p1", "editor"),
+        (ada, "p1", "viewer"),
+        (ada, "p2", "editor"),
+        (bob, "p1", "editor"),
+    ] {
+        UserRoleRow::allow(
+            pool,
+            &UserRoleRow::new(user_id, "project", scope_id, role_name),
+        )
+        .await
+        .unwrap();
+    }
+    for scope_id in ["p1", "p2"] {
+        GroupRoleRow::allow(
+            pool,
+            &GroupRoleRow::new(group_id, "project", scope_id, "viewer"),
+        )
+        .await
+        .unwrap();
+    }
+
+    // Neither a user nor a scope revokes nothing rather than everything.
+    let report = revoke_user_roles_report(pool, None, None, None, false)
+        .await
+        .unwrap();
+    assert!(report.effects.is_empty());
+    let report = revoke_user_roles_report(pool, Some(bob), Some(ada), None, true)
+        .await
+        .unwrap();
+    assert_eq!(report.effects.len(), 3);
+    assert_eq!(UserRoleRow::roles(pool, ada).await.unwrap().len(), 3);
+
+    let revoked = UserRoleRow::revoke_all_in_scope(pool, ada, "project", "p1")
+        .await
+        .unwrap();
+    assert_eq!(revoked.len(), 2);
+    let remaining = UserRoleRow::roles(pool, ada).await.unwrap();
+    assert_eq!(remaining.len(), 1);
+    assert_eq!(remaining[0].scope_id, "p2");
+    let revoked = UserRoleRow::revoke_all_for_scope(pool, "project", "p1")
+        .await
+        .unwrap();
+    assert_eq!(revoked.len(), 1);
+    assert_eq!(revoked[0].user_id, bob);
+    assert_eq!(
+        UserRoleRow::revoke_all_for_user(pool, ada)
+            .await
+            .unwrap()
+            .len(),
+        1
+    );
+    assert!(UserRoleRow::roles(pool, ada).await.unwrap().is_empty());
+
+    assert_eq!(
+        GroupRoleRow::revoke_all_in_scope(pool, group_id, "project", "p1")
+            .await
+            .unwrap()
+            .len(),
+        1
+    );
+    assert_eq!(
+        GroupRoleRow::revoke_all_for_group(pool, group_id)
+            .await
+            .unwrap()
+            .len(),
+        1
+    );
+    assert!(
+        GroupRoleRow::revoke_all_for_scope(pool, "project", "p2")
+            .await
+            .unwrap()
+            .is_empty()
+    );
+    assert_eq!(revocations_logged(pool).await, 6);
+
+    db.close().await;
+}
+
+#[tokio::test]
+async fn a_deny_overrides_roles_inherited_from_a_group() {
+    let Some(db) = TestDb::create().await else {
+        return;
+    };
+    let pool = &db.pool;
+    let ada = user(pool, "ada@example.com").await;
+    let bob = user(pool, "bob@example.com").await;
+    let group_id = group(pool, "Platform", GroupVisibility::Private).await;
+    for member in [ada, bob] {
+        GroupMembershipRow::add_member(
+            pool,
+            &GroupMembershipRow::new(group_id, member, GROUP_MEMBER_ROLE),
+        )
+        .await
+        .unwrap();
+    }
+    for scope_id in ["p1", "p2"] {
+        GroupRoleRow::allow(
+            pool,
+            &GroupRoleRow::new(group_id, "project", scope_id, "editor"),
+        )
+        .await
+        .unwrap();
+    }
+    assert!(
+        user_has_effective_role(pool, ada, "project", "p1", "editor")
+            .await
+            .unwrap()
+    );
+
+    UserRoleRow::deny(pool, &UserRoleRow::new(ada, "project", "p1", "editor"))
+        .await
+        .unwrap();
+    assert!(
+        !user_has_effective_role(pool, ada, "project", "p1", "editor")
+            .await
+            .unwrap()
+    );
+    assert!(
+        user_has_effective_role(pool, ada, "project", "p2", "editor")
+            .await
+            .unwrap()
+    );
+    assert!(
+        user_has_effective_role(pool, bob, "project", "p1", "editor")
+            .await
+            .unwrap()
+    );
+    let roles = effective_roles(pool, ada).await.unwrap();
+    assert!(
+        roles
+            .iter()
+            .any(|role| role.scope_id == "p1" && role.effect == RoleEffect::Deny)
+    );
+    assert!(!roles_allow(&roles, "project", "p1", "editor", None));
+    assert!(roles_allow(&roles, "project", "p2", "editor", None));
+
+    // A deny at the global id blocks every scope id of the scope.
+    UserRoleRow::deny(
+        pool,
+        &UserRoleRow::new(bob, "project", GLOBAL_SCOPE_ID, "editor"),
+    )
+    .await
+    .unwrap();
+    assert!(
+        !user_has_effective_role(pool, bob, "project", "p2", "editor")
+            .await
+            .unwrap()
+    );
+
+    // Allowing the role again replaces the deny.
+    UserRoleRow::allow(pool, &UserRoleRow::new(ada, "project", "p1", "editor"))
+        .await
+        .unwrap();
+    assert!(
+        user_has_effective_role(pool, ada, "project", "p1", "editor")
+            .await
+            .unwrap()
+    );
+    let own = UserRoleRow::roles(pool, ada).await.unwrap();
+    assert_eq!(own.len(), 1);
+    assert_eq!(own[0].effect, RoleEffect::Allow);
+
+    db.close().await;
+}
+
+#[tokio::test]
+async fn applying_a_bundle_grants_only_the_missing_roles() {
+    let Some(db) = TestDb::create().await else {
+        return;
+    };
+    let pool = &db.pool;
+    let admin = user(pool, "admin@example.com").await;
+    let ada = user(pool, "ada@example.com").await;
+    let grant = |scope: &str, role_name: &str| BundleGrant {
+        scope: scope.to_string(),
+        role_name: role_name.to_string(),
+    };
+    let bundle = RoleBundle {
+        name: "engineer".to_string(),
+        description: Some("New engineers".to_string()),
+        grants: vec![grant("project", "editor"), grant("project", "viewer")],
+    };
+    save_bundle(pool, admin, &bundle).await.unwrap();
+    assert_eq!(
+        get_bundle(pool, "engineer").await.unwrap().as_ref(),
+        Some(&bundle)
+    );
+    UserRoleRow::deny(pool, &UserRoleRow::new(ada, "project", "p1", "viewer"))
+        .await
+        .unwrap();
+
+    let applied = apply_bundle(pool, admin, ada, "engineer", "p1")
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(applied.granted, vec![grant("project", "editor")]);
+    assert_eq!(applied.unchanged, vec![grant("project", "viewer")]);
+    assert!(
+        user_has_effective_role(pool, ada, "project", "p1", "editor")
+            .await
+            .unwrap()
+    );
+    assert!(
+        !user_has_effective_role(pool, ada, "project", "p1", "viewer")
+            .await
+            .unwrap()
+    );
+    let again = apply_bundle(pool, admin, ada, "engineer", "p1")
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(again.granted.is_empty());
+    assert_eq!(again.unchanged.len(), 2);
+
+    // Deleting the bundle keeps what it handed out.
+    assert!(delete_bundle(pool, admin, "engineer").await.unwrap());
+    assert!(!delete_bundle(pool, admin, "engineer").await.unwrap());
+    assert!(
+        apply_bundle(pool, admin, ada, "engineer", "p2")
+            .await
+            .unwrap()
+            .is_none()
+    );
+    assert_eq!(UserRoleRow::roles(pool, ada).await.unwrap().len(), 2);
+
+    db.close().await;
+}
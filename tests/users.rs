@@ -0,0 +1,311 @@
+#![cfg(feature = "sqlx")]
+
+mod common;
+
+use chrono::{Duration, Utc};
+use subseq_auth::db::{
+    LastLogin, LogRow, LoginHistoryRow, UserRow, deactivate_dormant, deactivate_dormant_report,
+    record_login, record_provider_login,
+};
+use subseq_auth::user_id::UserId;
+use uuid::Uuid;
+
+use common::{TestDb, user};
+
+#[tokio::test]
+async fn login_updates_last_login_and_appends_history() {
+    let Some(db) = TestDb::create().await else {
+        return;
+    };
+    let pool = &db.pool;
+    let user_id = user(pool, "ada@example.com").await;
+    let last = LastLogin::get(pool, user_id).await.unwrap().unwrap();
+    assert!(last.last_login_at.is_none());
+
+    assert!(
+        record_login(pool, user_id, Some("10.0.0.1"), Some("curl"), "password")
+            .await
+            .unwrap()
+    );
+    assert!(
+        record_provider_login(
+            pool,
+            user_id,
+            Some("https://idp.example.com"),
+            Some("10.0.0.2"),
+            None,
+            "oidc",
+        )
+        .await
+        .unwrap()
+    );
+
+    let last = LastLogin::get(pool, user_id).await.unwrap().unwrap();
+    assert!(last.last_login_at.is_some());
+    assert_eq!(last.last_login_ip.as_deref(), Some("10.0.0.2"));
+    let history = LoginHistoryRow::for_user(pool, user_id, None)
+        .await
+        .unwrap();
+    assert_eq!(history.len(), 2);
+    assert_eq!(history[0].method, "oidc");
+    assert_eq!(
+        history[0].provider.as_deref(),
+        Some("https://idp.example.com")
+    );
+    assert_eq!(history[1].ip.as_deref(), Some("10.0.0.1"));
+    let page = LoginHistoryRow::for_user(pool, user_id, Some((1, 1)))
+        .await
+        .unwrap();
+    assert_eq!(page.len(), 1);
+    assert_eq!(page[0].method, "password");
+
+    // Nothing is recorded for a user without an `auth.users` row.
+    let unknown = UserId(Uuid::new_v4());
+    assert!(
+        !record_login(pool, unknown, None, None, "password")
+            .await
+            .unwrap()
+    );
+    assert!(
+        LoginHistoryRow::for_user(pool, unknown, None)
+            .await
+            .unwrap()
+            .is_empty()
+    );
+
+    db.close().await;
+}
+
+#[tokio::test]
+async fn dormant_users_are_found_and_deactivated() {
+    let Some(db) = TestDb::create().await else {
+        return;
+    };
+    let pool = &db.pool;
+    let dormant = user(pool, "dormant@example.com").await;
+    let returning = user(pool, "returning@example.com").await;
+    user(pool, "new@example.com").await;
+    let long_ago = Utc::now().naive_utc() - Duration::days(200);
+    sqlx::query("UPDATE auth.users SET created_at = $2 WHERE id = ANY($1)")
+        .bind(vec![dormant.0, returning.0])
+        .bind(long_ago)
+        .execute(pool)
+        .await
+        .unwrap();
+    record_login(pool, returning, None, None, "password")
+        .await
+        .unwrap();
+    let cutoff = Utc::now().naive_utc() - Duration::days(90);
+
+    let inactive: Vec<UserId> = UserRow::inactive_since(pool, cutoff)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|user| user.id)
+        .collect();
+    assert_eq!(inactive, vec![dormant]);
+
+    let report = deactivate_dormant_report(pool, cutoff, true).await.unwrap();
+    assert!(report.dry_run);
+    assert_eq!(report.effects, vec![dormant]);
+    assert_eq!(
+        UserRow::inactive_since(pool, cutoff).await.unwrap().len(),
+        1
+    );
+
+    assert_eq!(
+        deactivate_dormant(pool, cutoff).await.unwrap(),
+        vec![dormant]
+    );
+    assert!(
+        UserRow::inactive_since(pool, cutoff)
+            .await
+            .unwrap()
+            .is_empty()
+    );
+    let active: bool = sqlx::query_scalar("SELECT active FROM auth.users WHERE id = $1")
+        .bind(dormant)
+        .fetch_one(pool)
+        .await
+        .unwrap();
+    assert!(!active);
+    let events = LogRow::events_for_user_in(pool, dormant, None, None)
+        .await
+        .unwrap();
+    assert_eq!(events.len(), 1);
+    assert_eq!(events[0].action["type"], "user_dormant_deactivated");
+    assert!(deactivate_dormant(pool, cutoff).await.unwrap().is_empty());
+
+    db.close().await;
+}
+
+#[cfg(feature = "hard-delete")]
+#[tokio::test]
+async fn delete_cascade_removes_user_data_and_anonymizes_the_log() {
+    use serde_json::json;
+    use subseq_auth::db::{GROUP_MEMBER_ROLE, GroupMembershipRow, GroupRow, UserRoleRow};
+    use subseq_auth::group_id::GroupId;
+
+    let Some(db) = TestDb::create().await else {
+        return;
+    };
+    let pool = &db.pool;
+    let admin = user(pool, "admin@example.com").await;
+    let ada = user(pool, "ada@example.com").await;
+    let group_id = GroupId(Uuid::new_v4());
+    GroupRow::insert(pool, &GroupRow::new(group_id, None, "Platform"))
+        .await
+        .unwrap();
+    GroupMembershipRow::add_member(
+        pool,
+        &GroupMembershipRow::new(group_id, ada, GROUP_MEMBER_ROLE),
+    )
+    .await
+    .unwrap();
+    UserRoleRow::allow(pool, &UserRoleRow::new(ada, "project", "p1", "editor"))
+        .await
+        .unwrap();
+    record_login(pool, ada, Some("10.0.0.1"), None, "password")
+        .await
+        .unwrap();
+    let own = LogRow::new(
+        ada,
+        json!({"type": "username_changed", "from": "ada", "to": "ada2", "email": "ada@example.com"}),
+    );
+    LogRow::insert(pool, &own).await.unwrap();
+    let mention = LogRow::new(
+        admin,
+        json!({"type": "user_suspended", "user_id": ada.to_string()}),
+    );
+    LogRow::insert(pool, &mention).await.unwrap();
+
+    assert!(UserRow::delete_cascade(pool, ada).await.unwrap());
+    assert!(!UserRow::delete_cascade(pool, ada).await.unwrap());
+    assert!(UserRow::get(pool, ada).await.unwrap().is_none());
+    assert!(UserRoleRow::roles(pool, ada).await.unwrap().is_empty());
+    assert!(
+        !GroupMembershipRow::is_member(pool, group_id, ada)
+            .await
+            .unwrap()
+    );
+    assert!(
+        LoginHistoryRow::for_user(pool, ada, None)
+            .await
+            .unwrap()
+            .is_empty()
+    );
+
+    let actions: Vec<serde_json::Value> = sqlx::query_scalar("SELECT action FROM auth.log")
+        .fetch_all(pool)
+        .await
+        .unwrap();
+    let logged = serde_json::Value::Array(actions).to_string();
+    assert!(!logged.contains(&ada.to_string()), "{}", logged);
+    assert!(!logged.contains("ada@example.com"), "{}", logged);
+    assert!(!logged.contains("ada2"), "{}", logged);
+    assert!(logged.contains("user_deleted"));
+    let suspended = LogRow::events_for_user_in(pool, admin, None, None)
+        .await
+        .unwrap();
+    assert_eq!(suspended.len(), 1);
+    assert_eq!(suspended[0].action["type"], "user_suspended");
+    let orphaned: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM auth.log WHERE user_id = $1")
+        .bind(ada)
+        .fetch_one(pool)
+        .await
+        .unwrap();
+    assert_eq!(orphaned, 0);
+
+    db.close().await;
+}
+
+#[cfg(feature = "password-hashing")]
+mod password {
+    use std::sync::Arc;
+
+    use axum::http::StatusCode;
+    use axum::response::IntoResponse;
+    use sqlx::PgPool;
+    use subseq_auth::api::{AnnouncesUserEvents, AuthApp, HasPool, User, set_user_password};
+    use subseq_auth::db::{UserPasswordRow, UserRow};
+    use subseq_auth::group_id::GroupId;
+    use subseq_auth::prelude::{
+        ClaimsVerificationError, CoreIdToken, CoreIdTokenClaims, OidcToken, ValidatesIdentity,
+    };
+    use subseq_auth::user_id::UserId;
+
+    use super::common::{TestDb, user};
+
+    #[derive(Clone)]
+    struct App {
+        pool: Arc<PgPool>,
+    }
+
+    impl HasPool for App {
+        fn pool(&self) -> Arc<PgPool> {
+            self.pool.clone()
+        }
+    }
+
+    impl ValidatesIdentity for App {
+        fn validate_bearer(
+            &self,
+            _token: &str,
+        ) -> Result<(CoreIdToken, CoreIdTokenClaims), ClaimsVerificationError> {
+            unreachable!()
+        }
+
+        fn validate_token(
+            &self,
+            _token: &OidcToken,
+        ) -> Result<(CoreIdToken, CoreIdTokenClaims), ClaimsVerificationError> {
+            unreachable!()
+        }
+
+        async fn refresh_token(&self, _token: OidcToken) -> anyhow::Result<OidcToken> {
+            unreachable!()
+        }
+    }
+
+    impl AnnouncesUserEvents for App {
+        fn announce_new_user(&self, _user: &User) {}
+        fn announce_user_deactivation(&self, _user_id: UserId) {}
+        fn announce_user_update(&self, _user: &User) {}
+        fn announce_user_group_join(&self, _user_id: UserId, _group_id: GroupId) {}
+        fn announce_user_group_leave(&self, _user_id: UserId, _group_id: GroupId) {}
+    }
+
+    impl AuthApp for App {}
+
+    #[tokio::test]
+    async fn new_passwords_must_pass_the_policy() {
+        let Some(db) = TestDb::create().await else {
+            return;
+        };
+        let pool = &db.pool;
+        let app = App {
+            pool: Arc::new(pool.clone()),
+        };
+        let user_id = user(pool, "ada@example.com").await;
+        let row = UserRow::get(pool, user_id).await.unwrap().unwrap();
+
+        let rejected = set_user_password(&app, &row, "ada@example.com")
+            .await
+            .unwrap_err();
+        assert_eq!(
+            rejected.into_response().status(),
+            StatusCode::UNPROCESSABLE_ENTITY
+        );
+        assert!(UserPasswordRow::get(pool, user_id).await.unwrap().is_none());
+
+        let password = "correct horse battery staple";
+        set_user_password(&app, &row, password).await.unwrap();
+        assert!(
+            UserPasswordRow::verify(pool, user_id, password, app.password_hasher())
+                .await
+                .unwrap()
+        );
+
+        db.close().await;
+    }
+}